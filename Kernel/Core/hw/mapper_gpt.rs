@@ -0,0 +1,378 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/hw/mapper_gpt.rs
+/// GUID Partition Table logical volume mapper
+use crate::prelude::*;
+use crate::lib::byteorder::{ByteOrder,LittleEndian};
+use crate::metadevs::storage;
+
+module_define!{MapperGPT, [Storage], init}
+
+static S_MAPPER: Mapper = Mapper;
+
+fn init()
+{
+	storage::register_mapper(&S_MAPPER);
+}
+
+/// MBR partition type used by the protective entry
+const PROTECTIVE_SYSTEM_ID: u8 = 0xEE;
+/// Minimum size of the header (as of revision 1.0)
+const HEADER_MIN_SIZE: usize = 92;
+/// Minimum size of a single partition entry
+const ENTRY_MIN_SIZE: usize = 128;
+/// Upper limit on the size of the partition entry array (the spec minimum is 16KiB)
+const ENTRY_ARRAY_MAX_SIZE: usize = 1024*1024;
+/// Number of UTF-16 code units in a partition name
+const ENTRY_NAME_LEN: usize = 36;
+
+struct Mapper;
+
+#[derive(Debug)]
+struct Header
+{
+	my_lba: u64,
+	alternate_lba: u64,
+	first_usable_lba: u64,
+	last_usable_lba: u64,
+	entries_lba: u64,
+	num_entries: u32,
+	entry_size: u32,
+	entries_crc32: u32,
+}
+
+struct Entry
+{
+	type_guid: Guid,
+	unique_guid: Guid,
+	first_lba: u64,
+	/// Inclusive
+	last_lba: u64,
+	name: String,
+}
+
+#[derive(PartialEq)]
+struct Guid([u8; 16]);
+
+impl storage::Mapper for Mapper
+{
+	fn name(&self) -> &str { "gpt" }
+
+	fn handles_pv(&self, pv: &dyn storage::PhysicalVolume) -> Result<usize,storage::IoError> {
+		if !has_protective_mbr(pv)? {
+			return Ok(0);
+		}
+
+		match load_table(pv)?
+		{
+		Some(_) => Ok(2),
+		None => {
+			log_notice!("PV '{}' has a protective MBR but no valid GPT", pv.name());
+			Ok(0)
+			},
+		}
+	}
+
+	fn enum_volumes(&self, pv: &dyn storage::PhysicalVolume, new_volume_cb: &mut dyn FnMut(String, u64, u64)) -> Result<(),storage::IoError>
+	{
+		let (hdr, entries) = match load_table(pv)?
+			{
+			Some(v) => v,
+			None => return Err( storage::IoError::InvalidParameter ),
+			};
+
+		let in_range = entries.chunks(hdr.entry_size as usize)
+			.filter_map(|d| Entry::read(d))
+			.filter(|e| {
+				if e.first_lba > e.last_lba || e.first_lba < hdr.first_usable_lba || e.last_lba > hdr.last_usable_lba {
+					log_warning!("GPT partition {} on '{}' out of range ({:#x}--{:#x} not within {:#x}--{:#x})",
+						e.unique_guid, pv.name(), e.first_lba, e.last_lba, hdr.first_usable_lba, hdr.last_usable_lba);
+					false
+				}
+				else {
+					true
+				}
+				});
+		// Overlapping partitions would let writes to one corrupt the other, so only the first (in table order) is used
+		let mut entries: Vec<Entry> = Vec::new();
+		for e in in_range
+		{
+			if let Some(o) = entries.iter().find(|o| o.first_lba <= e.last_lba && e.first_lba <= o.last_lba) {
+				log_warning!("GPT partition {} on '{}' overlaps {} ({:#x}--{:#x} and {:#x}--{:#x}), ignoring",
+					e.unique_guid, pv.name(), o.unique_guid, e.first_lba, e.last_lba, o.first_lba, o.last_lba);
+			}
+			else {
+				entries.push(e);
+			}
+		}
+
+		for e in &entries
+		{
+			log_debug!("{:?}", e);
+			// Name using the label if it's usable (and unique on this disk), otherwise the partition's GUID
+			let label_usable = e.name != ""
+				&& e.name.chars().all(|c| !c.is_control() && c != '/')
+				&& entries.iter().filter(|o| o.name == e.name).count() == 1
+				;
+			let name = if label_usable {
+					format!("{}:{}", pv.name(), e.name)
+				}
+				else {
+					format!("{}:{}", pv.name(), e.unique_guid)
+				};
+			new_volume_cb( name, e.first_lba, e.last_lba - e.first_lba + 1 );
+		}
+
+		Ok( () )
+	}
+}
+
+/// Read a number of blocks from the volume, handling short reads
+fn read_blocks(pv: &dyn storage::PhysicalVolume, mut blockidx: u64, mut dst: &mut [u8]) -> Result<(),storage::IoError>
+{
+	let bs = pv.blocksize();
+	assert!(dst.len() % bs == 0);
+	while dst.len() > 0
+	{
+		let count = dst.len() / bs;
+		let n = crate::futures::block_on( pv.read(0, blockidx, count, dst) )?;
+		if n == 0 {
+			return Err( storage::IoError::Unknown("Zero-length read") );
+		}
+		dst = &mut {dst}[n * bs..];
+		blockidx += n as u64;
+	}
+	Ok( () )
+}
+
+/// Check for a MBR containing a protective (0xEE) entry
+fn has_protective_mbr(pv: &dyn storage::PhysicalVolume) -> Result<bool,storage::IoError>
+{
+	let mut block = vec![0u8; pv.blocksize()];
+	read_blocks(pv, 0, &mut block)?;
+	if !(block[510] == 0x55 && block[511] == 0xAA) {
+		return Ok(false);
+	}
+	Ok( (0 .. 4).any(|i| block[0x1BE + i*16 + 4] == PROTECTIVE_SYSTEM_ID) )
+}
+
+/// Load the header and entry array, falling back to the backup if the primary is corrupt
+fn load_table(pv: &dyn storage::PhysicalVolume) -> Result<Option<(Header, Vec<u8>)>,storage::IoError>
+{
+	let primary = load_table_at(pv, 1)?;
+	if let Some((ref hdr, _)) = primary {
+		if let Some(c) = pv.capacity() {
+			if hdr.alternate_lba != c - 1 {
+				log_notice!("PV '{}' GPT backup header at {:#x} is not at the end of the disk ({:#x})", pv.name(), hdr.alternate_lba, c - 1);
+			}
+		}
+		return Ok(primary);
+	}
+
+	// The backup header lives in the last block of the disk (if the size is known)
+	let last_lba = match pv.capacity()
+		{
+		Some(c) if c > 1 => c - 1,
+		_ => return Ok(None),
+		};
+	let backup = load_table_at(pv, last_lba)?;
+	if backup.is_some() {
+		log_warning!("PV '{}' primary GPT header is invalid, using backup at LBA {:#x}", pv.name(), last_lba);
+	}
+	Ok(backup)
+}
+
+/// Load and validate the header at `lba` and the entry array it references
+fn load_table_at(pv: &dyn storage::PhysicalVolume, lba: u64) -> Result<Option<(Header, Vec<u8>)>,storage::IoError>
+{
+	let bs = pv.blocksize();
+	let mut block = vec![0u8; bs];
+	read_blocks(pv, lba, &mut block)?;
+	let hdr = match Header::read(&block, lba)
+		{
+		Some(v) => v,
+		None => return Ok(None),
+		};
+	log_debug!("PV '{}' GPT header @{:#x} {:?}", pv.name(), lba, hdr);
+
+	let array_len = hdr.num_entries as usize * hdr.entry_size as usize;
+	let array_blocks = crate::lib::num::div_up(array_len, bs) as u64;
+	let array_end = hdr.entries_lba.saturating_add(array_blocks);
+	if let Some(c) = pv.capacity() {
+		if hdr.last_usable_lba >= c || array_end > c {
+			log_warning!("PV '{}' GPT header @{:#x} references blocks past the end of the disk ({:#x}--{:#x}, entries {:#x}+{}, capacity {:#x})",
+				pv.name(), lba, hdr.first_usable_lba, hdr.last_usable_lba, hdr.entries_lba, array_blocks, c);
+			return Ok(None);
+		}
+	}
+	// The entry array must be outside of the usable area (and not overlap the MBR or this header)
+	let array_overlaps = |first: u64, last: u64| hdr.entries_lba <= last && first < array_end;
+	if hdr.entries_lba == 0 || array_overlaps(lba, lba) || array_overlaps(hdr.first_usable_lba, hdr.last_usable_lba) {
+		log_warning!("PV '{}' GPT entry array @{:#x}+{} overlaps the header or usable area ({:#x}--{:#x})",
+			pv.name(), hdr.entries_lba, array_blocks, hdr.first_usable_lba, hdr.last_usable_lba);
+		return Ok(None);
+	}
+
+	let mut entries = vec![0u8; array_blocks as usize * bs];
+	if let Err(e) = read_blocks(pv, hdr.entries_lba, &mut entries) {
+		log_warning!("PV '{}' GPT entry array @{:#x} can't be read: {:?}", pv.name(), hdr.entries_lba, e);
+		return Ok(None);
+	}
+	entries.truncate(array_len);
+
+	let crc = crc32(&entries);
+	if crc != hdr.entries_crc32 {
+		log_warning!("PV '{}' GPT entry array @{:#x} CRC mismatch ({:08x} != {:08x})",
+			pv.name(), hdr.entries_lba, crc, hdr.entries_crc32);
+		return Ok(None);
+	}
+
+	Ok( Some( (hdr, entries) ) )
+}
+
+impl Header
+{
+	fn read(data: &[u8], lba: u64) -> Option<Header>
+	{
+		if &data[0..8] != b"EFI PART" {
+			return None;
+		}
+		let revision = LittleEndian::read_u32(&data[8..]);
+		if revision >> 16 != 1 {
+			log_warning!("Unknown GPT revision {:#x}", revision);
+			return None;
+		}
+		let header_size = LittleEndian::read_u32(&data[12..]) as usize;
+		if header_size < HEADER_MIN_SIZE || header_size > data.len() {
+			log_warning!("Invalid GPT header size {}", header_size);
+			return None;
+		}
+
+		// The header CRC is calculated with the CRC field zeroed
+		let header_crc32 = LittleEndian::read_u32(&data[16..]);
+		let crc = {
+			let mut hdr_data = data[..header_size].to_vec();
+			hdr_data[16..20].copy_from_slice(&[0; 4]);
+			crc32(&hdr_data)
+			};
+		if crc != header_crc32 {
+			log_warning!("GPT header @{:#x} CRC mismatch ({:08x} != {:08x})", lba, crc, header_crc32);
+			return None;
+		}
+
+		let rv = Header {
+			my_lba: LittleEndian::read_u64(&data[24..]),
+			alternate_lba: LittleEndian::read_u64(&data[32..]),
+			first_usable_lba: LittleEndian::read_u64(&data[40..]),
+			last_usable_lba: LittleEndian::read_u64(&data[48..]),
+			//disk_guid: &data[56..72],
+			entries_lba: LittleEndian::read_u64(&data[72..]),
+			num_entries: LittleEndian::read_u32(&data[80..]),
+			entry_size: LittleEndian::read_u32(&data[84..]),
+			entries_crc32: LittleEndian::read_u32(&data[88..]),
+			};
+
+		if rv.my_lba != lba {
+			log_warning!("GPT header @{:#x} claims to be at {:#x}", lba, rv.my_lba);
+			return None;
+		}
+		if (rv.entry_size as usize) < ENTRY_MIN_SIZE || rv.entry_size % 8 != 0 {
+			log_warning!("Invalid GPT entry size {}", rv.entry_size);
+			return None;
+		}
+		if rv.num_entries as usize * rv.entry_size as usize > ENTRY_ARRAY_MAX_SIZE {
+			log_warning!("GPT entry array too large ({} * {})", rv.num_entries, rv.entry_size);
+			return None;
+		}
+		if rv.first_usable_lba > rv.last_usable_lba {
+			log_warning!("GPT usable range is empty ({:#x}--{:#x})", rv.first_usable_lba, rv.last_usable_lba);
+			return None;
+		}
+		Some(rv)
+	}
+}
+
+impl Entry
+{
+	fn read(data: &[u8]) -> Option<Entry>
+	{
+		assert!(data.len() >= ENTRY_MIN_SIZE);
+		let type_guid = Guid::from_slice(&data[0..16]);
+		if type_guid.is_nil() {
+			return None;
+		}
+
+		let name_units = (0 .. ENTRY_NAME_LEN)
+			.map(|i| LittleEndian::read_u16(&data[56 + i*2..]))
+			.take_while(|&v| v != 0)
+			;
+		let name = ::core::char::decode_utf16(name_units)
+			.map(|r| r.unwrap_or(::core::char::REPLACEMENT_CHARACTER))
+			.collect();
+
+		Some(Entry {
+			type_guid: type_guid,
+			unique_guid: Guid::from_slice(&data[16..32]),
+			first_lba: LittleEndian::read_u64(&data[32..]),
+			last_lba: LittleEndian::read_u64(&data[40..]),
+			//attributes: LittleEndian::read_u64(&data[48..]),
+			name: name,
+			})
+	}
+}
+impl ::core::fmt::Debug for Entry
+{
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "Entry {{ type: {}, id: {}, lba: {:#x}--{:#x}, name: {:?} }}",
+			self.type_guid, self.unique_guid, self.first_lba, self.last_lba, self.name)
+	}
+}
+
+impl Guid
+{
+	fn from_slice(data: &[u8]) -> Guid {
+		let mut rv = [0; 16];
+		rv.copy_from_slice(&data[..16]);
+		Guid(rv)
+	}
+	fn is_nil(&self) -> bool {
+		self.0.iter().all(|&v| v == 0)
+	}
+}
+impl ::core::fmt::Display for Guid
+{
+	/// Standard textual form, first three fields are stored little-endian
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		let d = &self.0;
+		write!(f, "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+			LittleEndian::read_u32(&d[0..]), LittleEndian::read_u16(&d[4..]), LittleEndian::read_u16(&d[6..]),
+			d[8], d[9],
+			d[10], d[11], d[12], d[13], d[14], d[15]
+			)
+	}
+}
+
+/// CRC-32 (IEEE 802.3, reflected) as used by the GPT header and entry array
+fn crc32(data: &[u8]) -> u32
+{
+	static TABLE: [u32; 256] = {
+		let mut t = [0; 256];
+		let mut i = 0;
+		while i < 256
+		{
+			let mut v = i as u32;
+			let mut j = 0;
+			while j < 8
+			{
+				v = if v & 1 != 0 { 0xEDB88320 ^ (v >> 1) } else { v >> 1 };
+				j += 1;
+			}
+			t[i] = v;
+			i += 1;
+		}
+		t
+		};
+	!data.iter().fold(!0u32, |crc, &b| TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
@@ -18,6 +18,9 @@ fn init()
 
 struct Mapper;
 
+/// Upper limit on the number of EBRs followed in an extended partition (loop protection)
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
 #[derive(Debug)]
 struct Entry
 {
@@ -32,17 +35,15 @@ impl storage::Mapper for Mapper
 	fn name(&self) -> &str { "mbr" }
 
 	fn handles_pv(&self, pv: &dyn storage::PhysicalVolume) -> Result<usize,storage::IoError> {
-		if pv.blocksize() != 512 {
-			log_log!("Support non 512 byte sectors in MBR mapper (got {} for {})", pv.blocksize(), pv.name());
+		if pv.blocksize() < 512 {
+			log_log!("MBR mapper can't handle {} byte sectors (on {})", pv.blocksize(), pv.name());
 			return Ok(0);
 		}
 		
-		// SAFE: Plain old data
-		let mut block: [u8; 512] = unsafe { ::core::mem::zeroed() };
-		crate::futures::block_on( pv.read(0, 0, 1, &mut block) )?;
+		let block = read_sector(pv, 0)?;
 		
 		log_debug!("PV '{}' boot sig {:02x} {:02x}", pv.name(), block[0x1FE], block[0x1FF]);
-		if block[0x1FE] == 0x55 && block[0x1FE+1] == 0xAA {
+		if has_signature(&block) {
 			Ok(1)
 		}
 		else {
@@ -52,28 +53,28 @@ impl storage::Mapper for Mapper
 	
 	fn enum_volumes(&self, pv: &dyn crate::metadevs::storage::PhysicalVolume, new_volume_cb: &mut dyn FnMut(String, u64, u64)) -> Result<(),storage::IoError>
 	{
-		if !(pv.blocksize() == 512) {
+		if pv.blocksize() < 512 {
 			return Err( storage::IoError::InvalidParameter );
 		}
 		
-		// SAFE: Plain old data
-		let mut block: [u8; 512] = unsafe { ::core::mem::zeroed() };
-		crate::futures::block_on( pv.read(0, 0, 1, &mut block) )?;
-		if !(block[510] == 0x55 && block[511] == 0xAA) {
+		let block = read_sector(pv, 0)?;
+		if !has_signature(&block) {
 			return Err( storage::IoError::InvalidParameter );
 		}
 		
 		// the "unique ID" (according to the osdev.org wiki) might just be the tail of the MBR code
 		//let uid = &block[0x1b4 .. 0x1be];
 		
+		// Logical partitions are numbered after the four primary slots, in chain order
+		let mut next_logical = 4;
 		for i in 0 .. 4 {
 			let ofs = 0x1BE + i*16;
 			
 			if let Some(info) = Entry::read( &block[ofs .. ofs + 16] )
 			{
 				log_debug!("{:?}", info);
-				if info.system_id == 0x5 || info.system_id == 0xF {
-					todo!("Extended partition");
+				if info.is_extended() {
+					enum_logical(pv, &info, &mut next_logical, new_volume_cb)?;
 				}
 				else {
 					new_volume_cb( format!("{}p{}", pv.name(), i), info.lba_start, info.lba_count );
@@ -85,8 +86,88 @@ impl storage::Mapper for Mapper
 	}
 }
 
+/// Read a single sector from the volume
+fn read_sector(pv: &dyn storage::PhysicalVolume, lba: u64) -> Result<Vec<u8>,storage::IoError>
+{
+	let mut block = vec![0u8; pv.blocksize()];
+	crate::futures::block_on( pv.read(0, lba, 1, &mut block) )?;
+	Ok(block)
+}
+/// Check for the 0x55AA boot signature
+fn has_signature(block: &[u8]) -> bool
+{
+	block[0x1FE] == 0x55 && block[0x1FF] == 0xAA
+}
+
+/// Walk the EBR chain within an extended partition, emitting each logical partition
+///
+/// In each EBR the first entry is the logical partition (relative to the EBR), and the second
+/// links to the next EBR (relative to the start of the extended partition).
+fn enum_logical(pv: &dyn storage::PhysicalVolume, ext: &Entry, next_idx: &mut usize, new_volume_cb: &mut dyn FnMut(String, u64, u64)) -> Result<(),storage::IoError>
+{
+	let ext_end = ext.lba_start + ext.lba_count;
+	let mut visited = Vec::new();
+	let mut ebr_lba = ext.lba_start;
+	loop
+	{
+		if visited.contains(&ebr_lba) {
+			log_warning!("EBR chain on '{}' loops back to {:#x}", pv.name(), ebr_lba);
+			break ;
+		}
+		if visited.len() >= MAX_LOGICAL_PARTITIONS {
+			log_warning!("EBR chain on '{}' too long, stopping after {} entries", pv.name(), visited.len());
+			break ;
+		}
+		visited.push(ebr_lba);
+		
+		let block = read_sector(pv, ebr_lba)?;
+		if !has_signature(&block) {
+			log_warning!("EBR at {:#x} on '{}' has no boot signature", ebr_lba, pv.name());
+			break ;
+		}
+		
+		if let Some(info) = Entry::read( &block[0x1BE .. 0x1BE + 16] )
+		{
+			log_debug!("EBR {:#x}: {:?}", ebr_lba, info);
+			let start = ebr_lba + info.lba_start;
+			if info.lba_start == 0 || start + info.lba_count > ext_end {
+				log_warning!("Logical partition {:#x}+{:#x} on '{}' is outside the extended partition ({:#x}--{:#x})",
+					start, info.lba_count, pv.name(), ext.lba_start, ext_end);
+			}
+			else {
+				new_volume_cb( format!("{}p{}", pv.name(), *next_idx), start, info.lba_count );
+			}
+		}
+		// Number by chain position, so a bad/empty entry doesn't renumber the following partitions
+		*next_idx += 1;
+		
+		match Entry::read( &block[0x1CE .. 0x1CE + 16] )
+		{
+		Some(ref link) if link.is_extended() => {
+			let next = ext.lba_start + link.lba_start;
+			if link.lba_start == 0 || next >= ext_end {
+				log_warning!("EBR link to {:#x} on '{}' is outside the extended partition", next, pv.name());
+				break ;
+			}
+			ebr_lba = next;
+			},
+		_ => break,
+		}
+	}
+	Ok( () )
+}
+
 impl Entry
 {
+	/// Extended partition types (CHS, LBA, and Linux)
+	fn is_extended(&self) -> bool {
+		match self.system_id
+		{
+		0x05 | 0x0F | 0x85 => true,
+		_ => false,
+		}
+	}
+	
 	fn read(data: &[u8]) -> Option<Entry>
 	{
 		assert!(data.len() >= 16);
@@ -7,6 +7,7 @@
 pub mod bus_pci;
 
 pub mod mapper_mbr;
+pub mod mapper_gpt;
 
 // vim: ft=rust
 
@@ -107,7 +107,14 @@ pub trait PhysicalVolume: Send + 'static
 	///
 	/// Erases (requests the underlying storage forget about) `count` blocks starting at `blockidx`.
 	/// This is functionally equivalent to the SSD "TRIM" command.
+	///
+	/// This is advisory, devices without support for discarding can do nothing.
 	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> AsyncIoResult<'a,()>;
+	/// Flush the device's write cache
+	///
+	/// Once complete, all previously completed writes are on stable storage (i.e. this acts as a
+	/// write barrier).
+	fn flush<'a>(&'a self) -> AsyncIoResult<'a,()>;
 }
 
 /// Registration for a physical volume handling driver
@@ -521,6 +528,48 @@ impl VolumeHandle
 		}
 		Ok( () )
 	}
+	
+	/// Inform the underlying storage that a series of blocks are no longer in use (e.g. TRIM)
+	///
+	/// The contents of the blocks are undefined afterwards.
+	pub async fn discard_blocks(&self, idx: u64, count: usize) -> Result<(),IoError>
+	{
+		log_trace!("VolumeHandle::discard_blocks(idx={}, count={})", idx, count);
+		let mut rem = count;
+		let mut blk = 0;
+		while rem > 0
+		{
+			let (pv, ofs, count) = match self.get_phys_block(idx + blk as u64, rem) {
+				Some(v) => v,
+				None => {
+					log_warning!("VolumeHandle::discard_blocks - Block id {} is invalid", idx + blk as u64);
+					return Err( IoError::BadAddr )
+					},
+				};
+			log_trace!("- PV{} {} + {}", pv, ofs, count);
+			assert!(count <= rem);
+			S_PHYSICAL_VOLUMES.lock().get(&pv).unwrap().dev.wipe(ofs, count).await?;
+			blk += count;
+			rem -= count;
+		}
+		Ok( () )
+	}
+	
+	/// Flush the write caches of all devices backing this volume
+	///
+	/// Writes completed before this call are on stable storage once it returns.
+	pub async fn flush(&self) -> Result<(),IoError>
+	{
+		log_trace!("VolumeHandle::flush()");
+		let mut pvs: Vec<usize> = self.handle.regions.iter().map(|r| r.volume).collect();
+		pvs.sort();
+		pvs.dedup();
+		for pv in pvs
+		{
+			S_PHYSICAL_VOLUMES.lock().get(&pv).unwrap().dev.flush().await?;
+		}
+		Ok( () )
+	}
 }
 
 impl PhysicalVolumeInfo
@@ -673,6 +722,9 @@ mod null_volume
 		fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> super::AsyncIoResult<'a,()> {
 			Box::pin(async { Ok(()) })
 		}
+		fn flush<'a>(&'a self) -> super::AsyncIoResult<'a,()> {
+			Box::pin(async { Ok(()) })
+		}
 	}
 }
 
@@ -51,6 +51,8 @@ struct CachedBlock
 	reference_count: AtomicUsize,
 	last_access: ::kernel::time::CacheTimer,
 	is_dirty: AtomicBool,
+	/// Write-back is deferred until released (see `CachedVolume::edit_held`)
+	is_held: AtomicBool,
 
 	mapping: RwLock<Option<::kernel::memory::page_cache::CachedPage>>,
 }
@@ -258,6 +260,61 @@ impl CachedVolume
 
 		Ok( rv )
 	}
+	/// Edit a cached block, but don't write it back until `release` is called
+	///
+	/// Used for write-ahead logging, where the new contents must not reach the disk before the log does.
+	pub async fn edit_held<F: FnOnce(&mut [u8])->R,R>(&self, block: u64, count: usize, f: F) -> Result<R, IoError>
+	{
+		let cached_block = self.get_block_meta(block).await?;
+		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
+
+		if (block - cached_block.index()) as usize + count > self.blocks_per_page() as usize {
+			return Err(IoError::InvalidParameter);
+		}
+
+		cached_block.0.is_held.store(true, Ordering::Relaxed);
+		Ok(cached_block.edit(|block_data| {
+			f( &mut block_data[blk_ofs ..][ .. count * self.block_size()] )
+			}))
+	}
+	/// Allow a block edited with `edit_held` to be written back, and write it
+	pub async fn release(&self, block: u64) -> Result<(), IoError>
+	{
+		if let Some(cached_block) = self.get_block_meta_opt(block) {
+			cached_block.0.is_held.store(false, Ordering::Relaxed);
+			cached_block.0.flush(&self.vh).await?;
+		}
+		Ok( () )
+	}
+
+	/// Write all modified cached blocks for this volume back to the disk, and flush the device's write cache
+	pub async fn flush(&self) -> Result<(), IoError>
+	{
+		// Collect the dirty blocks first, so the cache lock isn't held while writing
+		let dirty: Vec<MetaBlockHandle> = {
+			let lh = S_BLOCK_CACHE.lock();
+			lh.map.iter()
+				.filter(|(k,ent)| k.0 == self.vh.idx() && ent.is_dirty.load(Ordering::Relaxed))
+				.map(|(_,ent)| {
+					let handle = ent.borrow();
+					// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists.
+					unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(handle) }
+					})
+				.collect()
+			};
+		for ent in &dirty
+		{
+			ent.0.flush(&self.vh).await?;
+		}
+		self.vh.flush().await
+	}
+	/// Write barrier: ensure that all completed writes are on stable storage before any later writes
+	///
+	/// NOTE: Doesn't write back modified cached blocks (use `flush` or `release` for that)
+	pub async fn barrier(&self) -> Result<(), IoError>
+	{
+		self.vh.flush().await
+	}
 }
 
 fn map_cached_frame(frame: &::kernel::memory::phys::FrameHandle) -> ::kernel::memory::page_cache::CachedPage
@@ -286,6 +343,7 @@ impl CachedBlock
 
 			last_access: Default::default(),
 			is_dirty: AtomicBool::new(false),
+			is_held: AtomicBool::new(false),
 			mapping: RwLock::new(Some(mapping)),
 			})
 	}
@@ -294,6 +352,10 @@ impl CachedBlock
 	async fn flush(&self, vol: &VolumeHandle) -> Result<(), IoError>
 	{
 		let lh = self.mapping.read();
+		if self.is_held.load(Ordering::Relaxed) {
+			// Still dirty, will be written when released
+			return Ok( () );
+		}
 		if self.is_dirty.swap(false, Ordering::Acquire)
 		{
 			vol.write_blocks(self.index, lh.as_ref().expect("CachedBlock::flush - None mapping").data()).await?;
@@ -0,0 +1,366 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/cluster.rs
+//! Cluster heap management (FAT chains, allocation bitmap, and stream IO)
+use kernel::prelude::*;
+use ::vfs;
+use crate::on_disk::{FAT_EOC,FAT_BAD};
+
+const ERROR_SHORTCHAIN: vfs::Error = vfs::Error::Unknown("Cluster chain terminated early");
+
+/// Location of a stream's data in the cluster heap
+#[derive(Copy,Clone,Debug,Default)]
+pub struct Stream
+{
+	/// First cluster (zero if nothing is allocated)
+	pub first_cluster: u32,
+	/// Clusters are consecutive, and the FAT isn't used (`NoFatChain`)
+	pub contiguous: bool,
+	/// Bytes that have been written, anything past this (and before `size`) reads as zero
+	pub valid_size: u64,
+	pub size: u64,
+}
+impl Stream
+{
+	pub fn cluster_count(&self, cluster_size: usize) -> u32 {
+		((self.size + cluster_size as u64 - 1) / cluster_size as u64) as u32
+	}
+}
+
+/// In-memory copy of the allocation bitmap
+pub struct Bitmap
+{
+	/// Clusters holding the on-disk bitmap
+	clusters: Vec<u32>,
+	data: Vec<u8>,
+	/// Index to start the next search from
+	next_free: usize,
+}
+impl Bitmap
+{
+	pub fn new(clusters: Vec<u32>, data: Vec<u8>) -> Bitmap {
+		Bitmap { clusters, data, next_free: 0 }
+	}
+	pub fn free_count(&self, cluster_count: usize) -> usize {
+		(0 .. cluster_count).filter(|&i| self.data[i / 8] & 1 << (i % 8) == 0).count()
+	}
+}
+
+/// FAT and bitmap methods
+impl super::FilesystemInner
+{
+	/// Check that a stream read from disk only references clusters within the heap
+	pub fn check_stream(&self, s: &Stream) -> vfs::Result<()> {
+		if s.first_cluster == 0 {
+			return Ok( () );
+		}
+		if s.first_cluster < 2 || s.first_cluster - 2 >= self.cluster_count {
+			log_error!("Stream first cluster {} out of range", s.first_cluster);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		if s.contiguous {
+			// NOTE: Computed in 64 bits, a corrupted size can overflow `cluster_count`
+			let count = (s.size + self.cluster_size as u64 - 1) / self.cluster_size as u64;
+			if (s.first_cluster - 2) as u64 + count > self.cluster_count as u64 {
+				log_error!("Contiguous stream {}+{} runs past the end of the heap", s.first_cluster, count);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+		}
+		Ok( () )
+	}
+
+	/// Volume block for the start of a cluster
+	pub fn cluster_block(&self, cluster: u32) -> u64 {
+		assert!(cluster >= 2 && cluster - 2 < self.cluster_count, "Cluster {} out of range", cluster);
+		self.heap_block + (cluster - 2) as u64 * self.blocks_per_cluster as u64
+	}
+
+	fn get_fat(&self, cluster: u32) -> vfs::Result<u32> {
+		let ofs = cluster as u64 * 4;
+		let bs = self.vh.block_size() as u64;
+		let mut buf = [0; 4];
+		::kernel::futures::block_on( self.vh.read_inner(self.fat_block + ofs / bs, (ofs % bs) as usize, &mut buf) )?;
+		Ok( u32::from_le_bytes(buf) )
+	}
+	fn set_fat(&self, cluster: u32, value: u32) -> vfs::Result<()> {
+		let ofs = cluster as u64 * 4;
+		let bs = self.vh.block_size() as u64;
+		::kernel::futures::block_on( self.vh.edit(self.fat_block + ofs / bs, 1, |d| {
+			d[(ofs % bs) as usize..][..4].copy_from_slice(&value.to_le_bytes());
+			}) )?;
+		Ok( () )
+	}
+	/// Obtain the next cluster in a FAT chain
+	pub fn next_cluster(&self, cluster: u32) -> vfs::Result<Option<u32>> {
+		match self.get_fat(cluster)?
+		{
+		FAT_EOC => Ok(None),
+		FAT_BAD => {
+			log_error!("Bad cluster in a chain (after {})", cluster);
+			Err(vfs::Error::InconsistentFilesystem)
+			},
+		v if v >= 2 && v - 2 < self.cluster_count => Ok(Some(v)),
+		v => {
+			log_error!("Invalid FAT entry {:#x} for cluster {}", v, cluster);
+			Err(vfs::Error::InconsistentFilesystem)
+			},
+		}
+	}
+
+	/// Call `cb` for each cluster in a stream (starting at cluster `skip`), until it returns `false`
+	pub fn walk_clusters(&self, s: &Stream, skip: u32, mut cb: impl FnMut(u32)->vfs::Result<bool>) -> vfs::Result<()> {
+		if s.first_cluster == 0 {
+			return Ok( () );
+		}
+		if s.contiguous {
+			let end = s.first_cluster as u64 + s.cluster_count(self.cluster_size) as u64;
+			for c in s.first_cluster as u64 + skip as u64 .. end {
+				if !cb(c as u32)? {
+					break ;
+				}
+			}
+		}
+		else {
+			let mut cur = Some(s.first_cluster);
+			for _ in 0 .. skip {
+				cur = match cur
+					{
+					Some(c) => self.next_cluster(c)?,
+					None => return Ok( () ),
+					};
+			}
+			while let Some(c) = cur {
+				if !cb(c)? {
+					break ;
+				}
+				cur = self.next_cluster(c)?;
+			}
+		}
+		Ok( () )
+	}
+	/// Count the clusters in a FAT chain (used for the root directory, which has no recorded size)
+	pub fn chain_length(&self, first_cluster: u32) -> vfs::Result<u32> {
+		let s = Stream { first_cluster, contiguous: false, valid_size: 0, size: 0 };
+		let mut rv = 0;
+		self.walk_clusters(&s, 0, |_| { rv += 1; Ok(rv <= self.cluster_count) })?;
+		if rv > self.cluster_count {
+			log_error!("Loop in cluster chain starting at {}", first_cluster);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		Ok(rv)
+	}
+
+	/// Allocate a single cluster, searching from `near`
+	fn alloc_cluster(&self, near: u32) -> vfs::Result<Option<u32>> {
+		let mut bitmap = self.bitmap.lock();
+		let count = self.cluster_count as usize;
+		let start = if near >= 2 && ((near - 2) as usize) < count { (near - 2) as usize } else { bitmap.next_free };
+		let found = (start .. count).chain(0 .. start)
+			.find(|&i| bitmap.data[i / 8] & 1 << (i % 8) == 0);
+		let Some(idx) = found else { return Ok(None); };
+		bitmap.data[idx / 8] |= 1 << (idx % 8);
+		bitmap.next_free = idx + 1;
+		self.write_bitmap_byte(&bitmap, idx / 8)?;
+		Ok( Some(idx as u32 + 2) )
+	}
+	fn free_cluster(&self, cluster: u32) -> vfs::Result<()> {
+		let mut bitmap = self.bitmap.lock();
+		let idx = (cluster - 2) as usize;
+		if bitmap.data[idx / 8] & 1 << (idx % 8) == 0 {
+			log_warning!("Freeing cluster {} which was already free", cluster);
+		}
+		bitmap.data[idx / 8] &= !(1 << (idx % 8));
+		if idx < bitmap.next_free {
+			bitmap.next_free = idx;
+		}
+		self.write_bitmap_byte(&bitmap, idx / 8)
+	}
+	fn write_bitmap_byte(&self, bitmap: &Bitmap, byte: usize) -> vfs::Result<()> {
+		let bs = self.vh.block_size();
+		let cluster = bitmap.clusters[byte / self.cluster_size];
+		let ofs = byte % self.cluster_size;
+		let val = bitmap.data[byte];
+		::kernel::futures::block_on( self.vh.edit(self.cluster_block(cluster) + (ofs / bs) as u64, 1, |d| d[ofs % bs] = val) )?;
+		Ok( () )
+	}
+
+	/// Obtain the last cluster of a stream, given its cluster count
+	fn last_cluster(&self, s: &Stream, count: u32) -> vfs::Result<u32> {
+		assert!(count > 0);
+		if s.contiguous {
+			Ok(s.first_cluster + count - 1)
+		}
+		else {
+			let mut rv = None;
+			self.walk_clusters(s, count - 1, |c| { rv = Some(c); Ok(false) })?;
+			rv.ok_or(ERROR_SHORTCHAIN)
+		}
+	}
+
+	/// Change the number of clusters allocated to a stream (`size` is updated by the caller)
+	pub fn resize_stream(&self, s: &mut Stream, have: u32, want: u32) -> vfs::Result<()> {
+		if want > have {
+			let mut last = if have == 0 { None } else { Some(self.last_cluster(s, have)?) };
+			for added in 0 .. want - have
+			{
+				let near = last.map(|c| c + 1).unwrap_or(0);
+				let c = match self.alloc_cluster(near)?
+					{
+					Some(c) => c,
+					None => {
+						// Release what was allocated, so the stream is unchanged
+						self.resize_stream(s, have + added, have)?;
+						return Err(vfs::Error::OutOfSpace);
+						},
+					};
+				match last
+				{
+				None => {
+					s.first_cluster = c;
+					s.contiguous = true;
+					},
+				Some(l) => {
+					if s.contiguous && c != l + 1 {
+						// No longer contiguous, so a FAT chain is needed for the existing clusters
+						for i in 0 .. have + added - 1 {
+							self.set_fat(s.first_cluster + i, s.first_cluster + i + 1)?;
+						}
+						s.contiguous = false;
+					}
+					if !s.contiguous {
+						self.set_fat(l, c)?;
+					}
+					},
+				}
+				if !s.contiguous {
+					self.set_fat(c, FAT_EOC)?;
+				}
+				last = Some(c);
+			}
+		}
+		else if want < have {
+			// NOTE: Doesn't use `walk_clusters` for contiguous streams, as `size` might not match `have`
+			let to_free: Vec<u32> = if s.contiguous {
+					(s.first_cluster + want .. s.first_cluster + have).collect()
+				}
+				else {
+					let mut rv = Vec::with_capacity((have - want) as usize);
+					self.walk_clusters(s, want, |c| { rv.push(c); Ok(true) })?;
+					rv
+				};
+			if want == 0 {
+				s.first_cluster = 0;
+				s.contiguous = false;
+			}
+			else if !s.contiguous {
+				let l = self.last_cluster(s, want)?;
+				self.set_fat(l, FAT_EOC)?;
+			}
+			for c in to_free {
+				self.free_cluster(c)?;
+			}
+		}
+		Ok( () )
+	}
+}
+
+/// Stream data IO
+impl super::FilesystemInner
+{
+	/// Read from within the allocated clusters of a stream
+	pub fn read_stream(&self, s: &Stream, ofs: u64, buf: &mut [u8]) -> vfs::Result<()> {
+		let cs = self.cluster_size as u64;
+		let mut pos = 0;
+		let mut cluster_ofs = (ofs % cs) as usize;
+		self.walk_clusters(s, (ofs / cs) as u32, |c| {
+			let len = usize::min(self.cluster_size - cluster_ofs, buf.len() - pos);
+			self.read_in_cluster(c, cluster_ofs, &mut buf[pos..][..len])?;
+			pos += len;
+			cluster_ofs = 0;
+			Ok(pos < buf.len())
+			})?;
+		if pos < buf.len() {
+			return Err(ERROR_SHORTCHAIN);
+		}
+		Ok( () )
+	}
+	/// Write to within the allocated clusters of a stream
+	pub fn write_stream(&self, s: &Stream, ofs: u64, buf: &[u8]) -> vfs::Result<()> {
+		let cs = self.cluster_size as u64;
+		let mut pos = 0;
+		let mut cluster_ofs = (ofs % cs) as usize;
+		self.walk_clusters(s, (ofs / cs) as u32, |c| {
+			let len = usize::min(self.cluster_size - cluster_ofs, buf.len() - pos);
+			self.write_in_cluster(c, cluster_ofs, &buf[pos..][..len])?;
+			pos += len;
+			cluster_ofs = 0;
+			Ok(pos < buf.len())
+			})?;
+		if pos < buf.len() {
+			return Err(ERROR_SHORTCHAIN);
+		}
+		Ok( () )
+	}
+	/// Fill a range of a stream's allocated clusters with zeroes
+	pub fn zero_stream(&self, s: &Stream, mut ofs: u64, end: u64) -> vfs::Result<()> {
+		let zeroes = vec![0u8; self.cluster_size];
+		while ofs < end {
+			let len = u64::min(end - ofs, self.cluster_size as u64 - ofs % self.cluster_size as u64) as usize;
+			self.write_stream(s, ofs, &zeroes[..len])?;
+			ofs += len as u64;
+		}
+		Ok( () )
+	}
+
+	fn read_in_cluster(&self, cluster: u32, ofs: usize, buf: &mut [u8]) -> vfs::Result<()> {
+		let bs = self.vh.block_size();
+		let mut block = self.cluster_block(cluster) + (ofs / bs) as u64;
+		let mut block_ofs = ofs % bs;
+		let mut buf = buf;
+		while buf.len() > 0
+		{
+			if block_ofs == 0 && buf.len() >= bs {
+				let count = buf.len() / bs;
+				let (a, b) = buf.split_at_mut(count * bs);
+				::kernel::futures::block_on( self.vh.read_blocks(block, a) )?;
+				buf = b;
+				block += count as u64;
+			}
+			else {
+				let len = usize::min(bs - block_ofs, buf.len());
+				let (a, b) = buf.split_at_mut(len);
+				::kernel::futures::block_on( self.vh.read_inner(block, block_ofs, a) )?;
+				buf = b;
+				block += 1;
+				block_ofs = 0;
+			}
+		}
+		Ok( () )
+	}
+	fn write_in_cluster(&self, cluster: u32, ofs: usize, buf: &[u8]) -> vfs::Result<()> {
+		let bs = self.vh.block_size();
+		let mut block = self.cluster_block(cluster) + (ofs / bs) as u64;
+		let mut block_ofs = ofs % bs;
+		let mut buf = buf;
+		while buf.len() > 0
+		{
+			if block_ofs == 0 && buf.len() >= bs {
+				let count = buf.len() / bs;
+				let (a, b) = buf.split_at(count * bs);
+				::kernel::futures::block_on( self.vh.write_blocks(block, a) )?;
+				buf = b;
+				block += count as u64;
+			}
+			else {
+				let len = usize::min(bs - block_ofs, buf.len());
+				let (a, b) = buf.split_at(len);
+				::kernel::futures::block_on( self.vh.edit(block, 1, |d| d[block_ofs..][..len].copy_from_slice(a)) )?;
+				buf = b;
+				block += 1;
+				block_ofs = 0;
+			}
+		}
+		Ok( () )
+	}
+}
@@ -0,0 +1,412 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/dir.rs
+//! Directory IO
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::lib::byte_str::ByteStr;
+use ::vfs::{self, node};
+use utf16::Str16;
+use crate::on_disk::{self, EntrySet, ENTRY_SIZE};
+use crate::cluster::Stream;
+use crate::{FilesystemInner,EntryPos,NodePos};
+
+/// Directory methods
+impl FilesystemInner
+{
+	/// Obtain the data stream for the directory starting at `cluster`
+	///
+	/// Sub-directories are located using the position of their entry set, which is known for any open directory.
+	pub fn dir_stream(&self, cluster: u32) -> vfs::Result<Stream> {
+		if cluster == self.root_cluster {
+			// The root has no stream extension, so is always a FAT chain
+			let size = self.chain_length(cluster)? as u64 * self.cluster_size as u64;
+			Ok(Stream {
+				first_cluster: cluster,
+				contiguous: false,
+				valid_size: size,
+				size: size,
+				})
+		}
+		else {
+			let pos = self.dir_pos(cluster)?;
+			Ok( self.read_entry_set(pos)?.stream() )
+		}
+	}
+	/// Position of the entry set for an open directory
+	fn dir_pos(&self, cluster: u32) -> vfs::Result<EntryPos> {
+		let p = self.dirs.lock().get(&cluster).cloned();
+		match p.and_then(|p| { let v = *p.lock(); v })
+		{
+		Some(v) => Ok(v),
+		None => {
+			log_error!("dir_pos({}): Directory isn't open", cluster);
+			Err(vfs::Error::NotFound)
+			},
+		}
+	}
+
+	/// Read the entire contents of a directory
+	pub fn read_dir_data(&self, s: &Stream) -> vfs::Result<Vec<u8>> {
+		let mut rv = vec![0u8; s.size as usize];
+		self.read_stream(s, 0, &mut rv)?;
+		Ok(rv)
+	}
+
+	/// Read the entry set at the given position
+	pub fn read_entry_set(&self, pos: EntryPos) -> vfs::Result<EntrySet> {
+		let s = self.dir_stream(pos.dir)?;
+		let ofs = pos.index as u64 * ENTRY_SIZE as u64;
+		if ofs + ENTRY_SIZE as u64 > s.size {
+			return Err(vfs::Error::NotFound);
+		}
+		let mut first = [0; ENTRY_SIZE];
+		self.read_stream(&s, ofs, &mut first)?;
+		if first[0] != on_disk::ENTRY_FILE {
+			// Stale inode number
+			return Err(vfs::Error::NotFound);
+		}
+		let mut raw = vec![0u8; (1 + first[1] as usize) * ENTRY_SIZE];
+		if ofs + raw.len() as u64 > s.size {
+			log_error!("Entry set at {:?} runs past the end of the directory", pos);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		self.read_stream(&s, ofs, &mut raw)?;
+		let set = EntrySet::parse(&raw).map_err(|e| {
+			log_error!("Bad entry set at {:?}: {}", pos, e);
+			vfs::Error::InconsistentFilesystem
+			})?;
+		self.check_stream(&set.stream())?;
+		Ok(set)
+	}
+	/// Write back an entry set
+	pub fn write_entry_set(&self, pos: EntryPos, set: &EntrySet) -> vfs::Result<()> {
+		let s = self.dir_stream(pos.dir)?;
+		self.write_stream(&s, pos.index as u64 * ENTRY_SIZE as u64, set.raw())
+	}
+	/// Mark a range of entries as unused
+	fn free_entries(&self, s: &Stream, index: u32, count: usize) -> vfs::Result<()> {
+		let ofs = index as u64 * ENTRY_SIZE as u64;
+		let mut raw = vec![0u8; count * ENTRY_SIZE];
+		self.read_stream(s, ofs, &mut raw)?;
+		for ent in raw.chunks_mut(ENTRY_SIZE) {
+			ent[0] &= !on_disk::ENTRY_INUSE;
+		}
+		self.write_stream(s, ofs, &raw)
+	}
+
+	/// Find space for `count` entries in a directory, extending it if required
+	fn alloc_entries(&self, dir_cluster: u32, s: &mut Stream, data: &[u8], count: usize) -> vfs::Result<u32> {
+		let mut run = 0;
+		for (i,ent) in data.chunks(ENTRY_SIZE).enumerate()
+		{
+			if ent[0] & on_disk::ENTRY_INUSE == 0 {
+				run += 1;
+				if run == count {
+					return Ok( (i + 1 - count) as u32 );
+				}
+			}
+			else {
+				run = 0;
+			}
+		}
+
+		// Not enough space, add enough clusters to fit the new entries after the last in-use one
+		let cur_count = data.len() / ENTRY_SIZE;
+		let need_bytes = (count - run) * ENTRY_SIZE;
+		let new_clusters = (need_bytes + self.cluster_size - 1) / self.cluster_size;
+		log_debug!("alloc_entries: Extending directory {} by {} clusters", dir_cluster, new_clusters);
+		let have = s.cluster_count(self.cluster_size);
+		self.resize_stream(s, have, have + new_clusters as u32)?;
+		let old_size = s.size;
+		s.size = (have as u64 + new_clusters as u64) * self.cluster_size as u64;
+		s.valid_size = s.size;
+		// New space must be zeroed (so it's all end-of-directory markers)
+		self.zero_stream(s, old_size, s.size)?;
+		if dir_cluster != self.root_cluster {
+			// Record the new size in the directory's own entry
+			let pos = self.dir_pos(dir_cluster)?;
+			let mut set = self.read_entry_set(pos)?;
+			set.set_stream(s);
+			self.write_entry_set(pos, &set)?;
+		}
+		Ok( (cur_count - run) as u32 )
+	}
+
+	/// Locate an entry set by name
+	fn find_by_name(&self, data: &[u8], name: &[u16]) -> Option<(u32, EntrySet)> {
+		let hash = self.upcase.name_hash(name);
+		for_each_set(self, data, 0, |i, set| {
+			if set.name_hash() == hash && self.upcase.names_equal(&set.name, name) {
+				Some( (i, set) )
+			}
+			else {
+				None
+			}
+			})
+	}
+}
+
+/// Iterate the entry sets in a directory's data (from entry `start`), stopping when `cb` returns `Some`
+fn for_each_set<T>(fs: &FilesystemInner, data: &[u8], start: usize, mut cb: impl FnMut(u32, EntrySet)->Option<T>) -> Option<T> {
+	let count = data.len() / ENTRY_SIZE;
+	let mut i = start;
+	while i < count
+	{
+		let ent = &data[i*ENTRY_SIZE..][..ENTRY_SIZE];
+		match ent[0]
+		{
+		on_disk::ENTRY_END => break,
+		on_disk::ENTRY_FILE => {
+			let n = 1 + ent[1] as usize;
+			if i + n <= count {
+				match EntrySet::parse(&data[i*ENTRY_SIZE..][..n*ENTRY_SIZE])
+				{
+				Ok(ref set) if fs.check_stream(&set.stream()).is_err() => log_notice!("Skipping entry set at {} with a bad stream", i),
+				Ok(set) => {
+					if let Some(rv) = cb(i as u32, set) {
+						return Some(rv);
+					}
+					i += n;
+					continue ;
+					},
+				Err(e) => log_notice!("Skipping bad entry set at {}: {}", i, e),
+				}
+			}
+			else {
+				log_notice!("Skipping truncated entry set at {}", i);
+			}
+			},
+		_ => {},
+		}
+		i += 1;
+	}
+	None
+}
+
+/// Convert (and check) a name for use on disk
+fn name_to_utf16(name: &ByteStr) -> vfs::Result<Vec<u16>> {
+	let rv: Vec<u16> = ::utf16::wtf8_to_utf16(name.as_bytes()).collect();
+	if rv.len() == 0 || rv.len() > on_disk::MAX_NAME_LEN {
+		return Err(vfs::Error::InvalidParameter);
+	}
+	if rv.iter().any(|&c| c < 0x20 || b"\"*/:<>?\\|".iter().any(|&b| b as u16 == c)) {
+		return Err(vfs::Error::InvalidParameter);
+	}
+	Ok(rv)
+}
+
+/// Get metadata from a file's entry set
+pub fn metadata(set: &EntrySet) -> node::Metadata {
+	let (created, modified, accessed) = set.times();
+	let mode = 0o755;
+	node::Metadata {
+		size: set.stream().size,
+		link_count: 1,
+		mode: if set.attributes() & on_disk::ATTR_READONLY != 0 { mode & !0o222 } else { mode },
+		created,
+		modified,
+		accessed,
+		..Default::default()
+		}
+}
+
+pub struct DirNode
+{
+	fs: ArefBorrow<FilesystemInner>,
+	/// First cluster of the directory's data
+	cluster: u32,
+	/// Location of this directory's entry set (`None` for the root)
+	pos: Option<NodePos>,
+}
+impl DirNode
+{
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, cluster: u32, pos: Option<EntryPos>) -> Box<DirNode> {
+		Box::new(DirNode {
+			pos: pos.map(|p| NodePos::new(&fs, p, Some(cluster))),
+			fs,
+			cluster,
+			})
+	}
+}
+impl node::NodeBase for DirNode {
+	fn get_id(&self) -> node::InodeId {
+		match self.pos
+		{
+		Some(ref p) => p.id(),
+		None => crate::ROOT_INODE,
+		}
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		let _lh = self.fs.lock.read();
+		match self.pos
+		{
+		Some(ref p) => Ok( metadata(&self.fs.read_entry_set(p.get()?)?) ),
+		None => Ok(node::Metadata {
+			size: self.fs.dir_stream(self.cluster)?.size,
+			link_count: 1,
+			mode: 0o755,
+			..Default::default()
+			}),
+		}
+	}
+}
+impl node::Dir for DirNode {
+	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId> {
+		log_trace!("DirNode::lookup({:?})", name);
+		let name = name_to_utf16(name)?;
+		let _lh = self.fs.lock.read();
+		let data = self.fs.read_dir_data(&self.fs.dir_stream(self.cluster)?)?;
+		match self.fs.find_by_name(&data, &name)
+		{
+		Some((index, _)) => Ok( EntryPos { dir: self.cluster, index }.to_id() ),
+		None => Err(vfs::Error::NotFound),
+		}
+	}
+	fn read(&self, ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		log_trace!("DirNode::read(ofs={})", ofs);
+		let _lh = self.fs.lock.read();
+		let data = self.fs.read_dir_data(&self.fs.dir_stream(self.cluster)?)?;
+		let mut next_ofs = ofs;
+		for_each_set(&self.fs, &data, ofs, |index, set| {
+			next_ofs = index as usize + set.entry_count();
+			let id = EntryPos { dir: self.cluster, index }.to_id();
+			let cont = match Str16::new(&set.name)
+				{
+				Some(n) => callback(id, &mut n.wtf8()),
+				None => {
+					// Unpaired surrogates, replace them so the name can be converted
+					let name: Vec<u16> = set.name.iter().map(|&c| if 0xD800 <= c && c <= 0xDFFF { 0xFFFD } else { c }).collect();
+					let rv = callback(id, &mut Str16::new(&name).unwrap().wtf8());
+					rv
+					},
+				};
+			if cont { None } else { Some( () ) }
+			});
+		Ok( next_ofs )
+	}
+	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> node::Result<node::InodeId> {
+		log_trace!("DirNode::create({:?}, {:?})", name, nodetype);
+		let name = name_to_utf16(name)?;
+		let attributes = match nodetype
+			{
+			node::NodeType::File => on_disk::ATTR_ARCHIVE,
+			node::NodeType::Dir => on_disk::ATTR_DIRECTORY,
+			node::NodeType::Symlink(_) => return Err(vfs::Error::Unknown("exFAT doesn't support symbolic links")),
+			};
+
+		let _lh = self.fs.lock.write();
+		let mut dir_stream = self.fs.dir_stream(self.cluster)?;
+		let data = self.fs.read_dir_data(&dir_stream)?;
+		if self.fs.find_by_name(&data, &name).is_some() {
+			return Err(vfs::Error::AlreadyExists);
+		}
+
+		// Directories always have at least one cluster (zeroed, so it starts with an end marker)
+		let mut stream = Stream::default();
+		if attributes & on_disk::ATTR_DIRECTORY != 0 {
+			self.fs.resize_stream(&mut stream, 0, 1)?;
+			stream.size = self.fs.cluster_size as u64;
+			stream.valid_size = stream.size;
+			self.fs.zero_stream(&stream, 0, stream.size)?;
+		}
+		let set = EntrySet::new(&name, self.fs.upcase.name_hash(&name), attributes, &stream);
+		let index = match self.fs.alloc_entries(self.cluster, &mut dir_stream, &data, set.entry_count())
+			{
+			Ok(v) => v,
+			Err(e) => {
+				let have = stream.cluster_count(self.fs.cluster_size);
+				self.fs.resize_stream(&mut stream, have, 0)?;
+				return Err(e);
+				},
+			};
+		let pos = EntryPos { dir: self.cluster, index };
+		self.fs.write_entry_set(pos, &set)?;
+		Ok( pos.to_id() )
+	}
+	fn link(&self, _name: &ByteStr, _inode: &dyn node::NodeBase) -> node::Result<()> {
+		Err(vfs::Error::Unknown("exFAT doesn't support hard links"))
+	}
+	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
+		log_trace!("DirNode::unlink({:?})", name);
+		let name = name_to_utf16(name)?;
+		let _lh = self.fs.lock.write();
+		let dir_stream = self.fs.dir_stream(self.cluster)?;
+		let data = self.fs.read_dir_data(&dir_stream)?;
+		let (index, set) = self.fs.find_by_name(&data, &name).ok_or(vfs::Error::NotFound)?;
+		let mut stream = set.stream();
+		if set.is_dir() {
+			let contents = self.fs.read_dir_data(&stream)?;
+			if contents.chunks(ENTRY_SIZE).take_while(|e| e[0] != on_disk::ENTRY_END).any(|e| e[0] & on_disk::ENTRY_INUSE != 0) {
+				return Err(vfs::Error::DirectoryNotEmpty);
+			}
+		}
+
+		// Remove the entries first, so a failure while freeing clusters only leaks them
+		self.fs.free_entries(&dir_stream, index, set.entry_count())?;
+		self.fs.entry_removed(EntryPos { dir: self.cluster, index }, if set.is_dir() { Some(stream.first_cluster) } else { None });
+		let have = stream.cluster_count(self.fs.cluster_size);
+		self.fs.resize_stream(&mut stream, have, 0)?;
+		Ok( () )
+	}
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn node::Dir, new_name: &ByteStr) -> node::Result<()> {
+		log_trace!("DirNode::rename({:?}, {:?})", old_name, new_name);
+		let new_dir = match new_dir.get_any().downcast_ref::<DirNode>()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		let old_name = name_to_utf16(old_name)?;
+		let new_name = name_to_utf16(new_name)?;
+		let same_dir = new_dir.cluster == self.cluster;
+
+		let _lh = self.fs.lock.write();
+		let src_data = self.fs.read_dir_data(&self.fs.dir_stream(self.cluster)?)?;
+		let (old_index, set) = self.fs.find_by_name(&src_data, &old_name).ok_or(vfs::Error::NotFound)?;
+		let mut dst_stream = self.fs.dir_stream(new_dir.cluster)?;
+		let dst_data = if same_dir { src_data } else { self.fs.read_dir_data(&dst_stream)? };
+		match self.fs.find_by_name(&dst_data, &new_name)
+		{
+		// Case-only rename of the same entry
+		Some((i, _)) if same_dir && i == old_index => {},
+		Some(_) => return Err(vfs::Error::AlreadyExists),
+		None => {},
+		}
+		if set.is_dir() {
+			// Don't allow moving a directory into itself
+			let moved = set.stream().first_cluster;
+			let mut c = new_dir.cluster;
+			while c != self.fs.root_cluster {
+				if c == moved {
+					return Err(vfs::Error::InvalidParameter);
+				}
+				c = self.fs.dir_pos(c)?.dir;
+			}
+		}
+
+		let new_set = set.renamed(&new_name, self.fs.upcase.name_hash(&new_name));
+		let old_pos = EntryPos { dir: self.cluster, index: old_index };
+		if same_dir && new_set.entry_count() <= set.entry_count() {
+			// Fits where the old set was, so rewrite in-place
+			self.fs.write_entry_set(old_pos, &new_set)?;
+			if new_set.entry_count() < set.entry_count() {
+				let s = self.fs.dir_stream(self.cluster)?;
+				self.fs.free_entries(&s, old_index + new_set.entry_count() as u32, set.entry_count() - new_set.entry_count())?;
+			}
+		}
+		else {
+			let index = self.fs.alloc_entries(new_dir.cluster, &mut dst_stream, &dst_data, new_set.entry_count())?;
+			let new_pos = EntryPos { dir: new_dir.cluster, index };
+			self.fs.write_entry_set(new_pos, &new_set)?;
+			// NOTE: Re-fetch the stream, in case the above extended this directory
+			let s = self.fs.dir_stream(self.cluster)?;
+			self.fs.free_entries(&s, old_index, set.entry_count())?;
+			self.fs.entry_moved(old_pos, new_pos);
+		}
+		Ok( () )
+	}
+}
@@ -0,0 +1,139 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/file.rs
+//! File IO
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use ::vfs::{self, node};
+use crate::{FilesystemInner,EntryPos,NodePos};
+
+pub struct FileNode
+{
+	fs: ArefBorrow<FilesystemInner>,
+	pos: NodePos,
+}
+
+impl FileNode
+{
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, pos: EntryPos) -> Box<FileNode> {
+		Box::new(FileNode {
+			pos: NodePos::new(&fs, pos, None),
+			fs,
+			})
+	}
+
+	/// Change the allocated size of a file (`valid_size` is left to the caller)
+	fn resize(&self, s: &mut crate::cluster::Stream, newsize: u64) -> vfs::Result<()> {
+		let cs = self.fs.cluster_size as u64;
+		let have = s.cluster_count(self.fs.cluster_size);
+		let want = (newsize + cs - 1) / cs;
+		if want > u32::MAX as u64 {
+			return Err(vfs::Error::OutOfSpace);
+		}
+		self.fs.resize_stream(s, have, want as u32)?;
+		s.size = newsize;
+		Ok( () )
+	}
+}
+impl node::NodeBase for FileNode {
+	fn get_id(&self) -> node::InodeId {
+		self.pos.id()
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		let _lh = self.fs.lock.read();
+		Ok( super::dir::metadata(&self.fs.read_entry_set(self.pos.get()?)?) )
+	}
+}
+impl node::File for FileNode {
+	fn size(&self) -> u64 {
+		let _lh = self.fs.lock.read();
+		match self.pos.get().and_then(|p| self.fs.read_entry_set(p))
+		{
+		Ok(set) => set.stream().size,
+		Err(e) => {
+			log_error!("FileNode::size: Can't read entry set - {:?}", e);
+			0
+			},
+		}
+	}
+	fn truncate(&self, newsize: u64) -> node::Result<u64> {
+		log_trace!("FileNode::truncate({:#x})", newsize);
+		let _lh = self.fs.lock.write();
+		let pos = self.pos.get()?;
+		let mut set = self.fs.read_entry_set(pos)?;
+		let mut s = set.stream();
+		if newsize != s.size {
+			self.resize(&mut s, newsize)?;
+			// NOTE: When growing, the new space is past `valid_size` so reads as zero without being written
+			s.valid_size = u64::min(s.valid_size, newsize);
+			set.set_stream(&s);
+			self.fs.write_entry_set(pos, &set)?;
+		}
+		Ok( newsize )
+	}
+	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
+		log_trace!("FileNode::clear({:#x}+{:#x})", ofs, size);
+		let _lh = self.fs.lock.write();
+		let s = self.fs.read_entry_set(self.pos.get()?)?.stream();
+		if ofs > s.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		// Only the valid portion needs to be written, the rest already reads as zero
+		let end = u64::min(ofs.saturating_add(size), s.valid_size);
+		if ofs < end {
+			self.fs.zero_stream(&s, ofs, end)?;
+		}
+		Ok( () )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		log_trace!("FileNode::read(@{:#x} len={:?})", ofs, buf.len());
+		let _lh = self.fs.lock.read();
+		let s = self.fs.read_entry_set(self.pos.get()?)?.stream();
+		if ofs > s.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let len = u64::min(buf.len() as u64, s.size - ofs) as usize;
+		let buf = &mut buf[..len];
+
+		// Data past `valid_size` hasn't been written yet, so is zero
+		let valid_len = if ofs >= s.valid_size { 0 } else { u64::min(len as u64, s.valid_size - ofs) as usize };
+		let (data, zeroes) = buf.split_at_mut(valid_len);
+		self.fs.read_stream(&s, ofs, data)?;
+		for b in zeroes {
+			*b = 0;
+		}
+		Ok( len )
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
+		log_trace!("FileNode::write(@{:#x} len={:?})", ofs, buf.len());
+		let _lh = self.fs.lock.write();
+		let pos = self.pos.get()?;
+		let mut set = self.fs.read_entry_set(pos)?;
+		let mut s = set.stream();
+		if ofs > s.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let end = ofs + buf.len() as u64;
+		if end > s.size {
+			// Allocate first, and record it (so a failed write doesn't leak clusters)
+			self.resize(&mut s, end)?;
+			set.set_stream(&s);
+			self.fs.write_entry_set(pos, &set)?;
+		}
+		if ofs > s.valid_size {
+			// Anything between the old valid length and this write has never been written
+			self.fs.zero_stream(&s, s.valid_size, ofs)?;
+		}
+		self.fs.write_stream(&s, ofs, buf)?;
+		if end > s.valid_size {
+			s.valid_size = end;
+			set.set_stream(&s);
+			self.fs.write_entry_set(pos, &set)?;
+		}
+		Ok( buf.len() )
+	}
+}
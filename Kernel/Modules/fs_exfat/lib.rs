@@ -0,0 +1,370 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/lib.rs
+//! exFAT Filesystem driver
+#![no_std]
+
+#[macro_use] extern crate kernel;
+use kernel::prelude::*;
+
+use kernel::metadevs::storage::{VolumeHandle,SizePrinter};
+use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
+use kernel::lib::mem::Arc;
+use kernel::lib::collections::VecMap;
+use kernel::sync::{Mutex,RwLock};
+use ::vfs::{self, mount, node};
+
+extern crate utf16;
+extern crate block_cache;
+
+module_define!{FS_EXFAT, [VFS], init}
+
+/// on-disk structures
+mod on_disk;
+/// FAT, allocation bitmap, and cluster IO
+mod cluster;
+/// Up-case table
+mod upcase;
+/// Directory IO
+mod dir;
+/// File IO
+mod file;
+
+/// The root directory has no entry set, so gets a reserved inode number
+const ROOT_INODE: node::InodeId = 0;
+
+/// Driver strucutre
+struct Driver;
+
+struct Filesystem
+{
+	inner: ArefInner<FilesystemInner>
+}
+impl ::core::ops::Deref for Filesystem {
+	type Target = FilesystemInner;
+	fn deref(&self) -> &FilesystemInner { &self.inner }
+}
+
+pub struct FilesystemInner
+{
+	vh: ::block_cache::CachedVolume,
+
+	/// Cluster size in bytes
+	cluster_size: usize,
+	/// Cluster size in volume blocks (NOTE: All on-disk sector values are converted to volume blocks)
+	blocks_per_cluster: usize,
+	/// First block of the active FAT
+	fat_block: u64,
+	/// First block of the cluster heap (cluster #2)
+	heap_block: u64,
+	cluster_count: u32,
+	root_cluster: u32,
+
+	bitmap: Mutex<cluster::Bitmap>,
+	upcase: upcase::UpcaseTable,
+
+	/// Protects directory contents and cluster allocations (held for read by lookups/reads)
+	lock: RwLock<()>,
+	/// Entry positions of nodes in use (so `rename` and `unlink` can update them)
+	nodes: Mutex<VecMap<node::InodeId, SharedPos>>,
+	/// Entry positions of directories in use, by their first cluster
+	dirs: Mutex<VecMap<u32, SharedPos>>,
+}
+
+/// Location of a file's entry set: The parent directory's first cluster, and the entry index
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct EntryPos
+{
+	pub dir: u32,
+	pub index: u32,
+}
+impl EntryPos
+{
+	pub fn to_id(&self) -> node::InodeId {
+		(self.dir as u64) << 32 | self.index as u64
+	}
+	pub fn from_id(id: node::InodeId) -> EntryPos {
+		EntryPos {
+			dir: (id >> 32) as u32,
+			index: id as u32,
+			}
+	}
+}
+/// Entry position shared between all nodes for a file (`None` once unlinked)
+type SharedPos = Arc<Mutex<Option<EntryPos>>>;
+
+/// A node's handle to its shared entry position
+pub struct NodePos
+{
+	fs: ArefBorrow<FilesystemInner>,
+	pos: SharedPos,
+	/// First cluster, for directories
+	dir_cluster: Option<u32>,
+	/// Inode number when opened (reported once the file is deleted)
+	orig_id: node::InodeId,
+}
+impl NodePos
+{
+	pub fn new(fs: &ArefBorrow<FilesystemInner>, pos: EntryPos, dir_cluster: Option<u32>) -> NodePos {
+		let mut nodes = fs.nodes.lock();
+		let mut dirs = fs.dirs.lock();
+		let p = nodes.entry(pos.to_id()).or_insert_with(|| Arc::new(Mutex::new(Some(pos)))).clone();
+		if let Some(c) = dir_cluster {
+			dirs.insert(c, p.clone());
+		}
+		NodePos {
+			fs: fs.clone(),
+			pos: p,
+			dir_cluster,
+			orig_id: pos.to_id(),
+			}
+	}
+	/// Current position, or `NotFound` if the file has been deleted
+	pub fn get(&self) -> vfs::Result<EntryPos> {
+		self.pos.lock().ok_or(vfs::Error::NotFound)
+	}
+	/// Current inode number (changes if the file is moved)
+	pub fn id(&self) -> node::InodeId {
+		self.pos.lock().map(|p| p.to_id()).unwrap_or(self.orig_id)
+	}
+}
+impl ::core::ops::Drop for NodePos
+{
+	fn drop(&mut self) {
+		let mut nodes = self.fs.nodes.lock();
+		let mut dirs = self.fs.dirs.lock();
+		let key = self.pos.lock().map(|p| p.to_id());
+		let in_nodes = key.map_or(false, |k| nodes.get(&k).map_or(false, |v| Arc::ptr_eq(v, &self.pos)));
+		let in_dirs = self.dir_cluster.map_or(false, |c| dirs.get(&c).map_or(false, |v| Arc::ptr_eq(v, &self.pos)));
+		// If this is the last handle, remove from the maps
+		if Arc::strong_count(&self.pos) == 1 + in_nodes as usize + in_dirs as usize {
+			if in_nodes {
+				nodes.remove(&key.unwrap());
+			}
+			if in_dirs {
+				dirs.remove(&self.dir_cluster.unwrap());
+			}
+		}
+	}
+}
+
+
+static S_DRIVER: Driver = Driver;
+
+fn init()
+{
+	let h = mount::DriverRegistration::new("exfat", &S_DRIVER);
+	// TODO: Remember the registration for unloading
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, vol: &VolumeHandle) -> ::vfs::Result<usize> {
+		let bs = {
+			let mut bs = vec![0u8; vol.block_size().max(512)];
+			::kernel::futures::block_on( vol.read_blocks(0, &mut bs) )?;
+			on_disk::BootSect::read(&bs[..512])
+			};
+		if bs.is_exfat() {
+			Ok(1)
+		}
+		else {
+			Ok(0)
+		}
+	}
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, _options: &mount::MountOptions) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
+		let vol = ::block_cache::CachedVolume::new(vol);
+
+		// Read the bootsector
+		let bs = {
+			let blk = ::kernel::futures::block_on(vol.get_block(0))?;
+			on_disk::BootSect::read(&blk.data()[..512])
+			};
+		if !bs.is_exfat() {
+			return Err(vfs::Error::TypeMismatch);
+		}
+		if !(9 <= bs.bytes_per_sector_shift && bs.bytes_per_sector_shift <= 12) || bs.bytes_per_sector_shift + bs.sectors_per_cluster_shift > 25 {
+			log_error!("Invalid exFAT bootsector: sector shift {}, cluster shift {}", bs.bytes_per_sector_shift, bs.sectors_per_cluster_shift);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		if bs.fat_count == 0 || bs.fat_count > 2 {
+			log_error!("Invalid exFAT bootsector: {} FATs", bs.fat_count);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		if bs.root_cluster < 2 || bs.root_cluster - 2 >= bs.cluster_count {
+			log_error!("Invalid exFAT bootsector: root cluster {} out of range", bs.root_cluster);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+
+		let bps = 1u64 << bs.bytes_per_sector_shift;
+		let cluster_size = (bps as usize) << bs.sectors_per_cluster_shift;
+		log_debug!("exFAT rev {:#x}, {} clusters of {}b, Size {}", bs.revision, bs.cluster_count, cluster_size,
+			SizePrinter(bs.volume_length * bps));
+
+		// Convert sector values into volume blocks (which might be a different size)
+		let vol_bs = vol.block_size() as u64;
+		let to_blocks = |sectors: u64| -> vfs::Result<u64> {
+			if (sectors * bps) % vol_bs != 0 {
+				log_error!("exFAT layout isn't aligned to the volume's {} byte blocks ({} sectors of {} bytes)", vol_bs, sectors, bps);
+				Err(vfs::Error::Unknown("exFAT sector size incompatible with volume"))
+			}
+			else {
+				Ok(sectors * bps / vol_bs)
+			}
+			};
+		// TexFAT volumes have two FATs (and bitmaps), the flag selects which is in use
+		let active_fat = if bs.fat_count == 2 && bs.volume_flags & on_disk::VOLFLAG_ACTIVE_FAT != 0 { 1 } else { 0 };
+		let mut inner = FilesystemInner {
+			cluster_size: cluster_size,
+			blocks_per_cluster: to_blocks(1 << bs.sectors_per_cluster_shift)? as usize,
+			fat_block: to_blocks(bs.fat_offset as u64 + active_fat * bs.fat_length as u64)?,
+			heap_block: to_blocks(bs.cluster_heap_offset as u64)?,
+			cluster_count: bs.cluster_count,
+			root_cluster: bs.root_cluster,
+
+			bitmap: Mutex::new(cluster::Bitmap::new(Vec::new(), Vec::new())),
+			upcase: upcase::UpcaseTable::ascii(),
+
+			lock: RwLock::new(()),
+			nodes: Default::default(),
+			dirs: Default::default(),
+
+			vh: vol,
+			};
+		inner.load_root_metadata(active_fat as u8)?;
+
+		Ok(Box::new(Filesystem {
+			// SAFE: Saving to a Box, so won't move
+			inner: unsafe { ArefInner::new(inner) },
+			}))
+	}
+}
+
+impl FilesystemInner
+{
+	/// Locate and load the allocation bitmap and up-case table from the root directory
+	fn load_root_metadata(&mut self, active_fat: u8) -> vfs::Result<()> {
+		let root = self.dir_stream(self.root_cluster)?;
+		let data = self.read_dir_data(&root)?;
+		let mut bitmap = None;
+		let mut upcase = None;
+		for ent in data.chunks(on_disk::ENTRY_SIZE)
+		{
+			match ent[0]
+			{
+			on_disk::ENTRY_END => break,
+			// Bit 0 of the flags selects which FAT this bitmap is for
+			on_disk::ENTRY_BITMAP if ent[1] & 1 == active_fat => {
+				bitmap = Some(cluster::Stream {
+					first_cluster: on_disk::get_u32(ent, 20),
+					contiguous: false,
+					valid_size: on_disk::get_u64(ent, 24),
+					size: on_disk::get_u64(ent, 24),
+					});
+				},
+			on_disk::ENTRY_UPCASE => {
+				upcase = Some((on_disk::get_u32(ent, 4), cluster::Stream {
+					first_cluster: on_disk::get_u32(ent, 20),
+					contiguous: false,
+					valid_size: on_disk::get_u64(ent, 24),
+					size: on_disk::get_u64(ent, 24),
+					}));
+				},
+			_ => {},
+			}
+		}
+
+		let Some(bitmap) = bitmap else {
+			log_error!("exFAT root directory has no allocation bitmap");
+			return Err(vfs::Error::InconsistentFilesystem);
+			};
+		self.check_stream(&bitmap)?;
+		let bitmap_bytes = (self.cluster_count as usize + 7) / 8;
+		if (bitmap.size as usize) < bitmap_bytes {
+			log_error!("exFAT allocation bitmap too small ({} < {})", bitmap.size, bitmap_bytes);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let mut data = vec![0u8; bitmap_bytes];
+		self.read_stream(&bitmap, 0, &mut data)?;
+		let mut clusters = Vec::new();
+		self.walk_clusters(&bitmap, 0, |c| { clusters.push(c); Ok(true) })?;
+		let bitmap = cluster::Bitmap::new(clusters, data);
+		log_debug!("{} of {} clusters free", bitmap.free_count(self.cluster_count as usize), self.cluster_count);
+		self.bitmap = Mutex::new(bitmap);
+
+		match upcase
+		{
+		Some((checksum, s)) => {
+			self.check_stream(&s)?;
+			let mut raw = vec![0u8; s.size as usize];
+			self.read_stream(&s, 0, &mut raw)?;
+			if on_disk::upcase_checksum(&raw) != checksum {
+				log_warning!("exFAT up-case table checksum mismatch");
+			}
+			self.upcase = upcase::UpcaseTable::from_raw(&raw);
+			},
+		None => log_warning!("exFAT root directory has no up-case table, using ASCII"),
+		}
+		Ok( () )
+	}
+
+	/// Update the position of any open nodes after an entry set moves
+	fn entry_moved(&self, old: EntryPos, new: EntryPos) {
+		let mut nodes = self.nodes.lock();
+		if let Some(p) = nodes.remove(&old.to_id()) {
+			*p.lock() = Some(new);
+			nodes.insert(new.to_id(), p);
+		}
+	}
+	/// Mark any open nodes for an entry set as deleted
+	fn entry_removed(&self, pos: EntryPos, dir_cluster: Option<u32>) {
+		let mut nodes = self.nodes.lock();
+		let mut dirs = self.dirs.lock();
+		if let Some(p) = nodes.remove(&pos.to_id()) {
+			*p.lock() = None;
+		}
+		if let Some(c) = dir_cluster {
+			dirs.remove(&c);
+		}
+	}
+}
+
+impl mount::Filesystem for Filesystem
+{
+	fn root_inode(&self) -> node::InodeId {
+		ROOT_INODE
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		if id == ROOT_INODE {
+			return Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), self.root_cluster, None)));
+		}
+		let pos = EntryPos::from_id(id);
+		let set = {
+			let _lh = self.lock.read();
+			match self.read_entry_set(pos)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("get_node_by_inode({:#x}): Can't read entry set - {:?}", id, e);
+				return None;
+				},
+			}
+			};
+		if set.is_dir() {
+			Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), set.stream().first_cluster, Some(pos))))
+		}
+		else {
+			Some(node::Node::File(file::FileNode::new_boxed(self.inner.borrow(), pos)))
+		}
+	}
+	fn flush(&self) -> ::vfs::Result<()> {
+		::kernel::futures::block_on(self.vh.flush())?;
+		Ok( () )
+	}
+	fn remount(&self, _options: &mount::MountOptions) -> ::vfs::Result<()> {
+		// No driver-specific options
+		Ok( () )
+	}
+}
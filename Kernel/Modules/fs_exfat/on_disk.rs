@@ -0,0 +1,344 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/on_disk.rs
+//! On-Disk structures and flags
+#[allow(unused_imports)]
+use kernel::prelude::*;
+use ::vfs::node;
+use crate::cluster::Stream;
+
+/// Size of a directory entry
+pub const ENTRY_SIZE: usize = 32;
+
+// Directory entry types
+pub const ENTRY_END    : u8 = 0x00;	// End of directory marker
+pub const ENTRY_INUSE  : u8 = 0x80;	// Set for live entries, cleared when deleted
+pub const ENTRY_BITMAP : u8 = 0x81;	// Allocation bitmap
+pub const ENTRY_UPCASE : u8 = 0x82;	// Up-case table
+#[allow(dead_code)]
+pub const ENTRY_LABEL  : u8 = 0x83;	// Volume label
+pub const ENTRY_FILE   : u8 = 0x85;	// File (first entry of a set)
+pub const ENTRY_STREAM : u8 = 0xC0;	// Stream extension
+pub const ENTRY_NAME   : u8 = 0xC1;	// File name fragment
+
+pub const ATTR_READONLY : u16 = 0x01;
+#[allow(dead_code)]
+pub const ATTR_HIDDEN   : u16 = 0x02;
+#[allow(dead_code)]
+pub const ATTR_SYSTEM   : u16 = 0x04;
+pub const ATTR_DIRECTORY: u16 = 0x10;
+pub const ATTR_ARCHIVE  : u16 = 0x20;
+
+pub const STREAM_ALLOC_POSSIBLE: u8 = 0x01;
+pub const STREAM_NO_FAT_CHAIN  : u8 = 0x02;
+
+/// UTF-16 code units stored in each name entry
+pub const NAME_CHARS_PER_ENTRY: usize = 15;
+pub const MAX_NAME_LEN: usize = 255;
+
+/// FAT value for the end of a cluster chain
+pub const FAT_EOC: u32 = 0xFFFF_FFFF;
+/// FAT value for a bad cluster
+pub const FAT_BAD: u32 = 0xFFFF_FFF7;
+
+pub const VOLFLAG_ACTIVE_FAT: u16 = 0x0001;
+
+/// 1980-01-01 00:00:00, the earliest representable timestamp
+// TODO: Fill with the current time
+pub const TIMESTAMP_EPOCH: u32 = (1 << 21) | (1 << 16);
+
+fn read_u8(s: &mut &[u8]) -> u8 {
+	use kernel::lib::byteorder::ReadBytesExt;
+	s.read_u8().unwrap()
+}
+fn read_u16(s: &mut &[u8]) -> u16 {
+	use kernel::lib::byteorder::{ReadBytesExt,LittleEndian};
+	s.read_u16::<LittleEndian>().unwrap()
+}
+fn read_u32(s: &mut &[u8]) -> u32 {
+	use kernel::lib::byteorder::{ReadBytesExt,LittleEndian};
+	s.read_u32::<LittleEndian>().unwrap()
+}
+fn read_u64(s: &mut &[u8]) -> u64 {
+	use kernel::lib::byteorder::{ReadBytesExt,LittleEndian};
+	s.read_u64::<LittleEndian>().unwrap()
+}
+fn read_arr<T: AsMut<[u8]>>(s: &mut &[u8]) -> T {
+	use kernel::lib::io::Read;
+	// (mostly) SAFE: 'T' should be POD... but can't enforce that easily
+	let mut v: T = unsafe { ::core::mem::zeroed() };
+	s.read(v.as_mut()).unwrap();
+	v
+}
+
+pub fn get_u16(d: &[u8], ofs: usize) -> u16 {
+	u16::from_le_bytes([d[ofs], d[ofs+1]])
+}
+pub fn get_u32(d: &[u8], ofs: usize) -> u32 {
+	u32::from_le_bytes([d[ofs], d[ofs+1], d[ofs+2], d[ofs+3]])
+}
+pub fn get_u64(d: &[u8], ofs: usize) -> u64 {
+	get_u32(d, ofs) as u64 | (get_u32(d, ofs+4) as u64) << 32
+}
+fn set_u16(d: &mut [u8], ofs: usize, v: u16) {
+	d[ofs..][..2].copy_from_slice(&v.to_le_bytes());
+}
+fn set_u32(d: &mut [u8], ofs: usize, v: u32) {
+	d[ofs..][..4].copy_from_slice(&v.to_le_bytes());
+}
+fn set_u64(d: &mut [u8], ofs: usize, v: u64) {
+	d[ofs..][..8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Main boot sector
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct BootSect
+{
+	pub jump: [u8; 3],
+	pub fs_name: [u8; 8],
+	pub partition_offset: u64,
+	pub volume_length: u64,
+	/// Sector offset of the first FAT
+	pub fat_offset: u32,
+	/// Length of each FAT in sectors
+	pub fat_length: u32,
+	/// Sector offset of the cluster heap (cluster 2)
+	pub cluster_heap_offset: u32,
+	pub cluster_count: u32,
+	pub root_cluster: u32,
+	pub serial: u32,
+	pub revision: u16,
+	pub volume_flags: u16,
+	pub bytes_per_sector_shift: u8,
+	pub sectors_per_cluster_shift: u8,
+	pub fat_count: u8,
+	pub drive_select: u8,
+	pub percent_in_use: u8,
+	pub signature: u16,
+}
+impl BootSect
+{
+	pub fn read(buf: &[u8]) -> BootSect {
+		assert!(buf.len() >= 512);
+		let s = &mut &buf[..];
+		let jump = read_arr(s);
+		let fs_name = read_arr(s);
+		*s = &buf[64..];
+		BootSect {
+			jump,
+			fs_name,
+			partition_offset: read_u64(s),
+			volume_length: read_u64(s),
+			fat_offset: read_u32(s),
+			fat_length: read_u32(s),
+			cluster_heap_offset: read_u32(s),
+			cluster_count: read_u32(s),
+			root_cluster: read_u32(s),
+			serial: read_u32(s),
+			revision: read_u16(s),
+			volume_flags: read_u16(s),
+			bytes_per_sector_shift: read_u8(s),
+			sectors_per_cluster_shift: read_u8(s),
+			fat_count: read_u8(s),
+			drive_select: read_u8(s),
+			percent_in_use: read_u8(s),
+			signature: get_u16(buf, 510),
+			}
+	}
+
+	pub fn is_exfat(&self) -> bool {
+		&self.fs_name == b"EXFAT   " && self.signature == 0xAA55
+	}
+}
+
+/// Checksum over a file's directory entry set (skips the checksum field itself)
+pub fn entry_set_checksum(raw: &[u8]) -> u16 {
+	let mut sum: u16 = 0;
+	for (i,&b) in raw.iter().enumerate() {
+		if i == 2 || i == 3 {
+			continue ;
+		}
+		sum = sum.rotate_right(1).wrapping_add(b as u16);
+	}
+	sum
+}
+/// Hash of an (up-cased) file name, stored in the stream extension entry
+pub fn name_hash(upcased_name: impl Iterator<Item=u16>) -> u16 {
+	let mut hash: u16 = 0;
+	for c in upcased_name {
+		for b in c.to_le_bytes().iter() {
+			hash = hash.rotate_right(1).wrapping_add(*b as u16);
+		}
+	}
+	hash
+}
+/// Checksum of the (compressed) up-case table
+pub fn upcase_checksum(raw: &[u8]) -> u32 {
+	raw.iter().fold(0u32, |sum, &b| sum.rotate_right(1).wrapping_add(b as u32))
+}
+
+/// Convert an on-disk timestamp (local time, with optional UTC offset) into a VFS timestamp
+pub fn decode_timestamp(ts: u32, ms10: u8, utc_offset: u8) -> node::Timestamp {
+	if ts == 0 {
+		return 0;
+	}
+	let rv = node::timestamp_from_date(
+		1980 + (ts >> 25) as i64, (ts >> 21) & 0xF, (ts >> 16) & 0x1F,
+		(ts >> 11) & 0x1F, (ts >> 5) & 0x3F, (ts & 0x1F) * 2
+		);
+	let rv = rv + ms10 as i64 / 100;
+	if utc_offset & 0x80 != 0 {
+		// Signed 7-bit count of 15 minute intervals
+		let ofs_quarters = ((utc_offset << 1) as i8 >> 1) as i64;
+		rv - ofs_quarters * 15 * 60
+	}
+	else {
+		rv
+	}
+}
+
+/// A file's directory entry set (File, Stream Extension, and File Name entries)
+pub struct EntrySet
+{
+	raw: Vec<u8>,
+	pub name: Vec<u16>,
+}
+impl EntrySet
+{
+	/// Parse an entry set (must be exactly the length given by the file entry's secondary count)
+	pub fn parse(raw: &[u8]) -> Result<EntrySet, &'static str> {
+		assert!(raw.len() % ENTRY_SIZE == 0);
+		assert_eq!(raw[0], ENTRY_FILE);
+		let count = raw.len() / ENTRY_SIZE;
+		if count < 3 {
+			return Err("Entry set too short");
+		}
+		if entry_set_checksum(raw) != get_u16(raw, 2) {
+			return Err("Bad entry set checksum");
+		}
+		let stream = &raw[ENTRY_SIZE..][..ENTRY_SIZE];
+		if stream[0] != ENTRY_STREAM {
+			return Err("Second entry not a stream extension");
+		}
+		let name_len = stream[3] as usize;
+		let name_ents = (name_len + NAME_CHARS_PER_ENTRY - 1) / NAME_CHARS_PER_ENTRY;
+		if name_len == 0 || 2 + name_ents > count {
+			return Err("Bad name length");
+		}
+		let mut name = Vec::with_capacity(name_len);
+		for ent in raw[2*ENTRY_SIZE..].chunks(ENTRY_SIZE).take(name_ents) {
+			if ent[0] != ENTRY_NAME {
+				return Err("Missing file name entry");
+			}
+			for i in 0 .. NAME_CHARS_PER_ENTRY {
+				if name.len() < name_len {
+					name.push( get_u16(ent, 2 + i*2) );
+				}
+			}
+		}
+		// NOTE: Any further (vendor) secondary entries are kept in `raw`, but otherwise ignored
+		Ok(EntrySet {
+			raw: raw.to_vec(),
+			name,
+			})
+	}
+
+	/// Construct a new entry set
+	pub fn new(name: &[u16], name_hash: u16, attributes: u16, stream: &Stream) -> EntrySet {
+		assert!(name.len() > 0 && name.len() <= MAX_NAME_LEN);
+		let name_ents = (name.len() + NAME_CHARS_PER_ENTRY - 1) / NAME_CHARS_PER_ENTRY;
+		let mut raw = vec![0u8; (2 + name_ents) * ENTRY_SIZE];
+		{
+			let file = &mut raw[..ENTRY_SIZE];
+			file[0] = ENTRY_FILE;
+			file[1] = (1 + name_ents) as u8;
+			set_u16(file, 4, attributes);
+			set_u32(file, 8, TIMESTAMP_EPOCH);	// Created
+			set_u32(file, 12, TIMESTAMP_EPOCH);	// Modified
+			set_u32(file, 16, TIMESTAMP_EPOCH);	// Accessed
+		}
+		{
+			let ent = &mut raw[ENTRY_SIZE..][..ENTRY_SIZE];
+			ent[0] = ENTRY_STREAM;
+			ent[3] = name.len() as u8;
+			set_u16(ent, 4, name_hash);
+		}
+		let mut rv = EntrySet { raw, name: Vec::new() };
+		rv.set_name_entries(name);
+		rv.set_stream(stream);
+		rv
+	}
+
+	/// Create a copy of this set with a different name (keeps attributes, times, and data)
+	pub fn renamed(&self, name: &[u16], name_hash: u16) -> EntrySet {
+		let mut rv = EntrySet::new(name, name_hash, self.attributes(), &self.stream());
+		rv.raw[4..ENTRY_SIZE].copy_from_slice(&self.raw[4..ENTRY_SIZE]);
+		rv.update_checksum();
+		rv
+	}
+
+	fn set_name_entries(&mut self, name: &[u16]) {
+		for (ent, chunk) in Iterator::zip( self.raw[2*ENTRY_SIZE..].chunks_mut(ENTRY_SIZE), name.chunks(NAME_CHARS_PER_ENTRY) ) {
+			ent[0] = ENTRY_NAME;
+			for (i,&c) in chunk.iter().enumerate() {
+				set_u16(ent, 2 + i*2, c);
+			}
+		}
+		self.name = name.to_vec();
+	}
+	fn update_checksum(&mut self) {
+		let sum = entry_set_checksum(&self.raw);
+		set_u16(&mut self.raw, 2, sum);
+	}
+
+	/// Raw entries (with an up-to-date checksum)
+	pub fn raw(&self) -> &[u8] {
+		&self.raw
+	}
+	/// Number of directory entries used by this set
+	pub fn entry_count(&self) -> usize {
+		self.raw.len() / ENTRY_SIZE
+	}
+
+	pub fn attributes(&self) -> u16 {
+		get_u16(&self.raw, 4)
+	}
+	pub fn is_dir(&self) -> bool {
+		self.attributes() & ATTR_DIRECTORY != 0
+	}
+	pub fn name_hash(&self) -> u16 {
+		get_u16(&self.raw, ENTRY_SIZE + 4)
+	}
+
+	/// Creation, modification, and access times
+	pub fn times(&self) -> (node::Timestamp, node::Timestamp, node::Timestamp) {
+		let f = &self.raw[..ENTRY_SIZE];
+		(
+			decode_timestamp(get_u32(f, 8), f[20], f[22]),
+			decode_timestamp(get_u32(f, 12), f[21], f[23]),
+			decode_timestamp(get_u32(f, 16), 0, f[24]),
+		)
+	}
+
+	pub fn stream(&self) -> Stream {
+		let ent = &self.raw[ENTRY_SIZE..][..ENTRY_SIZE];
+		Stream {
+			first_cluster: get_u32(ent, 20),
+			contiguous: ent[1] & STREAM_NO_FAT_CHAIN != 0,
+			valid_size: get_u64(ent, 8),
+			size: get_u64(ent, 24),
+			}
+	}
+	pub fn set_stream(&mut self, s: &Stream) {
+		{
+			let ent = &mut self.raw[ENTRY_SIZE..][..ENTRY_SIZE];
+			ent[1] = STREAM_ALLOC_POSSIBLE | if s.contiguous && s.first_cluster != 0 { STREAM_NO_FAT_CHAIN } else { 0 };
+			set_u64(ent, 8, s.valid_size);
+			set_u32(ent, 20, s.first_cluster);
+			set_u64(ent, 24, s.size);
+		}
+		self.update_checksum();
+	}
+}
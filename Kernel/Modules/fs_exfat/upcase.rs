@@ -0,0 +1,58 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/upcase.rs
+//! Up-case table (used for case-insensitive name comparisons and name hashes)
+use kernel::prelude::*;
+
+pub struct UpcaseTable
+{
+	table: Vec<u16>,
+}
+impl UpcaseTable
+{
+	/// Table used until the on-disk one is loaded (and if it's missing), only maps ASCII
+	pub fn ascii() -> UpcaseTable {
+		UpcaseTable {
+			table: (0 .. 128).map(|c: u16| if b'a' as u16 <= c && c <= b'z' as u16 { c - 0x20 } else { c }).collect(),
+		}
+	}
+
+	/// Decode the on-disk table
+	///
+	/// The table is a list of mappings starting at U+0000, where `0xFFFF, n` is a run of `n` identity mappings.
+	pub fn from_raw(raw: &[u8]) -> UpcaseTable {
+		let mut table = Vec::with_capacity(0x1_0000);
+		let mut it = raw.chunks(2).filter(|c| c.len() == 2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+		while let Some(v) = it.next()
+		{
+			if v == 0xFFFF {
+				let Some(count) = it.next() else { break ; };
+				for _ in 0 .. count {
+					let c = table.len();
+					if c >= 0x1_0000 {
+						break ;
+					}
+					table.push(c as u16);
+				}
+			}
+			else if table.len() < 0x1_0000 {
+				table.push(v);
+			}
+		}
+		UpcaseTable { table }
+	}
+
+	pub fn upcase(&self, c: u16) -> u16 {
+		self.table.get(c as usize).copied().unwrap_or(c)
+	}
+
+	/// Compare two names, ignoring case
+	pub fn names_equal(&self, a: &[u16], b: &[u16]) -> bool {
+		a.len() == b.len() && Iterator::zip(a.iter(), b.iter()).all(|(&a, &b)| self.upcase(a) == self.upcase(b))
+	}
+	/// Hash of a name, as stored in the stream extension
+	pub fn name_hash(&self, name: &[u16]) -> u16 {
+		crate::on_disk::name_hash( name.iter().map(|&c| self.upcase(c)) )
+	}
+}
@@ -0,0 +1,31 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/csum.rs
+//! Metadata checksums (FEAT_RO_COMPAT_METADATA_CSUM, and jbd2 CSUM_V2/V3)
+//!
+//! Both ext4 and jbd2 use CRC-32C (Castagnoli) without the final inversion, chaining the result of one call into
+//! the next as the seed.
+
+/// Update a CRC-32C with `data`
+pub fn crc32c(crc: u32, data: &[u8]) -> u32
+{
+	static TABLE: [u32; 256] = {
+		let mut t = [0; 256];
+		let mut i = 0;
+		while i < 256
+		{
+			let mut v = i as u32;
+			let mut j = 0;
+			while j < 8
+			{
+				v = if v & 1 != 0 { 0x82F63B78 ^ (v >> 1) } else { v >> 1 };
+				j += 1;
+			}
+			t[i] = v;
+			i += 1;
+		}
+		t
+		};
+	data.iter().fold(crc, |crc, &b| TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
@@ -3,8 +3,10 @@
 //
 // Modules/fs_extN/dir.rs
 //! Directory handling
+use kernel::prelude::*;
 use kernel::lib::byte_str::ByteStr;
 use vfs;
+use crate::inodes::{InodeHandleTrait,InodeHandleWrite};
 
 
 pub struct Dir
@@ -23,191 +25,425 @@ impl Dir
 	}
 
 
-	/// Returns (block_index, offset)
-	fn find_name(&self, name: &ByteStr) -> vfs::node::Result<(usize, usize, vfs::node::InodeId)>
+	/// Returns (block_index, inode)
+	fn find_name(&self, name: &ByteStr) -> vfs::node::Result<(u32, u32)>
 	{
-		log_debug!("find_name({:?})", name);
 		let inode = self.inode.lock_read();
-		// TODO: Use the B+ trees if present
+		Self::find_name_locked(&inode, name)
+	}
+	fn find_name_locked(inode: &dyn InodeHandleTrait, name: &ByteStr) -> vfs::node::Result<(u32, u32)>
+	{
+		log_debug!("find_name({:?})", name);
+		let fs = inode.fs();
 
-		// Linear search
-		for (blk_index, vol_blk) in inode.blocks().enumerate()
+		if is_indexed(inode)
 		{
-			log_trace!("find_name: Block {} (vol_blk={})", blk_index, vol_blk);
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
+			if let Some(mut path) = ::htree::Path::probe(inode, name.as_bytes())?
 			{
-				if ent.d_rec_len == 0 {
-					log_error!("find_name: Found d_rec_len=0");
-					return Err( vfs::Error::InconsistentFilesystem );
-				}
-				else if ent.d_inode == 0 {
-					log_debug!("find_name: Found d_inode=0");
-					return Err(vfs::Error::NotFound);
-				}
-				else if &ent.d_name == name.as_bytes()
+				let mut blk_index = path.leaf();
+				loop
 				{
-					return Ok( (blk_index, offset, ent.d_inode as vfs::node::InodeId) );
-				}
-				else {
-					offset += ent.u32_len() * 4;
+					let blk_data = fs.get_block(inode.get_block_addr(blk_index)?)?;
+					if let Some( (_, ino) ) = find_in_block(blk_data.bytes(), name.as_bytes())? {
+						return Ok( (blk_index, ino) );
+					}
+					// Hash collisions can span into the next leaf
+					blk_index = match path.next_leaf(inode)?
+						{
+						Some(v) => v,
+						None => return Err(vfs::Error::NotFound),
+						};
 				}
 			}
+			log_notice!("find_name: Index unusable, falling back to a linear search");
+		}
+
+		// Linear search
+		for (blk_index, vol_blk) in inode.blocks().enumerate()
+		{
+			log_trace!("find_name: Block {} (vol_blk={})", blk_index, vol_blk);
+			let blk_data = fs.get_block(vol_blk)?;
+			if let Some( (_, ino) ) = find_in_block(blk_data.bytes(), name.as_bytes())? {
+				return Ok( (blk_index as u32, ino) );
+			}
 		}
 		Err(vfs::Error::NotFound)
 	}
 
-	/// Defragment a directory block, and return the offset and length of the final free entry
-	fn defragment_block(fs: &crate::instance::InstanceInner, block: u32) -> vfs::node::Result<(usize, u16)> {
-		fs.edit_block(block, |blk_data: &mut [u8]| {
-			// SAFE: Alignment checked, range valid
-			let blk_data: &mut [u32] = unsafe {
-				assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-				::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
+	/// Add an entry to the directory, checking for duplicates
+	fn add_dir_ent(&self, name: &ByteStr, inode: u32) -> Result<(), vfs::Error>
+	{
+		if !(name.len() <= 255) {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let fs = &*self.inode.fs;
+		let d_type = if fs.has_feature_incompat(::ondisk::FEAT_INCOMPAT_FILETYPE) {
+				fs.with_inode(inode, |ino| Ok( file_type(ino.lock_read().i_mode_fmt()) ))?
+			}
+			else {
+				::ondisk::FT_UNKNOWN
+			};
+		let _lh_write = self.inode.lock_dir();
+		let mut ih = self.inode.lock_write();
+
+		match Self::find_name_locked(&ih, name)
+		{
+		Ok(_) => return Err(vfs::Error::AlreadyExists),
+		Err(vfs::Error::NotFound) => {},
+		Err(e) => return Err(e),
+		}
+		let ent = NewEnt { inode, d_type, name: name.as_bytes() };
+
+		if is_indexed(&ih)
+		{
+			if let Some(path) = ::htree::Path::probe(&ih, name.as_bytes())? {
+				return Self::add_dir_ent_indexed(&mut ih, path, &ent);
+			}
+			// The index can't be maintained, so remove it (the leaves are still valid directory blocks)
+			log_warning!("add_dir_ent: Index unusable, clearing EXT4_INDEX_FL");
+			ih.clear_i_flags(::ondisk::EXT4_INDEX_FL);
+		}
+
+		// Linear: Find a block with enough free space
+		let need = dirent_len(name.len());
+		for vol_blk in ih.blocks()
+		{
+			let (slot, free) = {
+				let blk_data = fs.get_block(vol_blk)?;
+				let blk_data = leaf_ents(&ih, blk_data.bytes());
+				(find_space(blk_data, need)?, free_space(blk_data)?)
 				};
-			let mut write_u32s = 0;
-			let mut read_u32s = 0;
-			while read_u32s < blk_data.len() {
-				if read_u32s + 1 == blk_data.len() {
-					return Err(vfs::Error::InconsistentFilesystem);
-				}
-				let rec_len = (u32::from_le(blk_data[read_u32s+1]) & 0xFFFF) as u16;
-				let rec_len_u32s = ((rec_len + 4-1) / 4) as usize;
-				if read_u32s + rec_len_u32s > blk_data.len() {
-					return Err(vfs::Error::InconsistentFilesystem);
-				}
-				if blk_data[read_u32s] == 0 {
-					// Entry is free, don't update the write position
-				}
-				else {
-					if read_u32s == write_u32s {
-						// Positions are equal, no change
-					}
-					else {
-						// Copy entry into the new location
-						blk_data.copy_within(read_u32s .. read_u32s + rec_len_u32s, write_u32s);
-					}
-					write_u32s += rec_len_u32s;
-				}
-				read_u32s += rec_len_u32s;
+			if let Some(ofs) = slot {
+				log_debug!("add_dir_ent: Slot found: vol_blk {} ofs {}", vol_blk, ofs);
+				return edit_leaf(&ih, vol_blk, |blk_data| insert_at(blk_data, ofs, &ent));
 			}
-			let tail_len = read_u32s - write_u32s;
-			if tail_len > 0 {
-				blk_data[write_u32s + 0] = 0;
-				blk_data[write_u32s + 1] = (tail_len as u32 * 4).to_le();
+			if free >= need {
+				// Enough space, but fragmented. Rewrite the block with the entries packed
+				log_debug!("add_dir_ent: Compacting vol_blk {}", vol_blk);
+				return edit_leaf(&ih, vol_blk, |blk_data| {
+					let mut ents = read_entries(blk_data)?;
+					ents.push( (ent.inode, ent.d_type, ent.name.to_vec()) );
+					fill_block(blk_data, ents.iter());
+					Ok( () )
+					});
 			}
-			Ok( (write_u32s * 4, (tail_len * 4) as u16) )
+		}
+
+		// No space, expand the directory
+		let (_, vol_blk) = append_block(&mut ih)?;
+		edit_leaf(&ih, vol_blk, |blk_data| {
+			fill_block(blk_data, ::core::iter::once( &(ent.inode, ent.d_type, ent.name.to_vec()) ));
+			Ok( () )
 			})
 	}
 
-	/// Locate a entry in the directory, also checking for duplicates
-	fn find_free(&self, name: &ByteStr) -> vfs::node::Result<(u32, usize)>
+	/// Insert into a directory with an index, splitting the leaf if it's full
+	fn add_dir_ent_indexed(ih: &mut InodeHandleWrite, mut path: ::htree::Path, ent: &NewEnt) -> vfs::node::Result<()>
 	{
-		let inode = self.inode.lock_read();
-		// Linear search
-		// TODO: ext3 and later use B+ trees (so can exit the search early, as the lookup can be faster)
-		// - These are a ReadOnly feature, so we shouldn't be here if those are present
+		let fs = ih.fs();
+		let need = dirent_len(ent.name.len());
+		let leaf_vol = ih.get_block_addr(path.leaf())?;
+		if let Some(ofs) = find_space(leaf_ents(&*ih, fs.get_block(leaf_vol)?.bytes()), need)? {
+			return edit_leaf(&*ih, leaf_vol, |blk_data| insert_at(blk_data, ofs, ent));
+		}
 
-		let mut rv: Option< ((u32, usize), u16) > = None;
-		let mut total_free = 0;
-		for (blk_index, vol_blk) in inode.blocks().enumerate()
+		// Split the leaf: sort the entries by hash, and move the top half (by size) into a new block
+		path.make_room(ih)?;
+		let mut ents: Vec<_> = read_entries(fs.get_block(leaf_vol)?.bytes())?
+			.into_iter()
+			.map(|e| (path.hash_name(&e.2), e))
+			.collect();
+		if ents.len() < 2 {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		ents.sort_by_key(|e| e.0);
+		let mut size = 0;
+		let mut split = ents.len();
+		while split > 1
 		{
-			let mut block_free = 0;
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
+			let len = dirent_len( (ents[split-1].1).2.len() );
+			if size + len / 2 > fs.fs_block_size / 2 {
+				break;
+			}
+			size += len;
+			split -= 1;
+		}
+		let split_hash = (ents[split].0).0;
+		// If a hash value is present in both halves, flag the new leaf as a continuation
+		let continued = if (ents[split-1].0).0 == split_hash { 1 } else { 0 };
+		log_debug!("add_dir_ent_indexed: Splitting leaf {} at {}/{} (hash {:#x}{})",
+			path.leaf(), split, ents.len(), split_hash, if continued != 0 { "+" } else { "" });
+
+		let (new_blk, new_vol) = append_block(ih)?;
+		edit_leaf(&*ih, leaf_vol, |blk_data| { fill_block(blk_data, ents[..split].iter().map(|e| &e.1)); Ok( () ) })?;
+		edit_leaf(&*ih, new_vol , |blk_data| { fill_block(blk_data, ents[split..].iter().map(|e| &e.1)); Ok( () ) })?;
+		path.insert_leaf(split_hash | continued, new_blk);
+		path.write(&*ih)?;
+
+		// And insert into the half that now covers the name's hash
+		let vol_blk = if path.hash() >= split_hash { new_vol } else { leaf_vol };
+		let Some(ofs) = find_space(leaf_ents(&*ih, fs.get_block(vol_blk)?.bytes()), need)? else {
+			log_error!("add_dir_ent_indexed: No space after splitting leaf");
+			return Err(vfs::Error::InconsistentFilesystem);
+		};
+		edit_leaf(&*ih, vol_blk, |blk_data| insert_at(blk_data, ofs, ent))
+	}
+
+	/// Fill a newly allocated directory with its `.` and `..` entries
+	fn init_dir(&self, ino_id: u32) -> vfs::node::Result<()>
+	{
+		let fs = &*self.inode.fs;
+		let d_type = if fs.has_feature_incompat(::ondisk::FEAT_INCOMPAT_FILETYPE) { ::ondisk::FT_DIR } else { ::ondisk::FT_UNKNOWN };
+		let ents = [
+			(ino_id, d_type, b".".to_vec()),
+			(self.inode.get_id() as u32, d_type, b"..".to_vec()),
+			];
+		fs.with_inode(ino_id, |ino| {
 			{
-				if ent.d_rec_len == 0 {
-					return Err( vfs::Error::InconsistentFilesystem );
-				}
-				
-				if ent.d_inode == 0 {
-					if &ent.d_name == name.as_bytes() {
-						return Err( vfs::Error::AlreadyExists );
-					}
-				}
-				else {
-					if (ent.d_rec_len - 8) >= name.len() as u16 {
-						// Free entry with sufficient space!
-						rv = Some(match rv
-							{
-							Some( (v, len) ) if len < ent.d_rec_len => (v, len),
-							_ => ( (blk_index as u32, offset), ent.d_rec_len ),
-							});
-					}
-					block_free += ent.u32_len() * 4;
-				}
-				offset += ent.u32_len() * 4;
+				let mut ih = ino.lock_write();
+				let (_, vol_blk) = append_block(&mut ih)?;
+				edit_leaf(&ih, vol_blk, |blk_data| { fill_block(blk_data, ents.iter()); Ok( () ) })?;
 			}
+			// The `.` entry
+			ino.inc_link_count()
+			})
+	}
 
-			// If there wasn't a good space found yet, but this block has enough space on its own - then defragment and return
-			if rv.is_none() && block_free - 8 >= name.len() {
-				// Defragment the block, there's enough space for this name but not in a single contigous chunk.
-				drop(blk_data);
-				let (offset,rec_len) = Self::defragment_block(&self.inode.fs, vol_blk)?;
-				if rec_len - 8 >= name.len() as u16 {
-					rv = Some(( (blk_index as u32, offset), rec_len));
-				}
-				else {
-					// BUG? It was calculated that there was enough space, but defragmentation didn't provide enough
-				}
+	/// Remove an entry from the directory, returning the inode it referred to
+	fn remove_dir_ent(&self, name: &ByteStr) -> Result<u32, vfs::Error>
+	{
+		let _lh_write = self.inode.lock_dir();
+		let inode = self.inode.lock_read();
+		let (blk, _) = try!(Self::find_name_locked(&inode, name));
+
+		let vol_blk = try!( inode.get_block_addr(blk) );
+		edit_leaf(&inode, vol_blk, |blk_data| {
+			let Some( (ofs, ino) ) = find_in_block(blk_data, name.as_bytes())? else {
+				return Err(vfs::Error::InconsistentFilesystem);
+			};
+			// Merge into the previous entry (or mark as unused if first in the block)
+			let mut prev = None;
+			let mut cur = 0;
+			while cur < ofs {
+				prev = Some(cur);
+				cur += dirent_hdr(blk_data, cur)?.1;
+			}
+			let rec_len = dirent_hdr(blk_data, ofs)?.1;
+			match prev
+			{
+			Some(p) => {
+				let prev_len = dirent_hdr(blk_data, p)?.1;
+				blk_data[p+4..][..2].copy_from_slice( &((prev_len + rec_len) as u16).to_le_bytes() );
+				},
+			None => blk_data[ofs..][..4].copy_from_slice( &0u32.to_le_bytes() ),
 			}
+			Ok( ino )
+			})
+	}
+}
 
-			total_free += block_free - 8;	// Calcualte total free space, accounting for the required dirent header
-		}
-		if let Some( (rv, _) ) = rv {
-			return Ok(rv);
-		}
+/// Append a new (uninitialised) block to a directory, returning (block_index, vol_block)
+pub fn append_block(ih: &mut InodeHandleWrite) -> vfs::node::Result<(u32, u64)>
+{
+	let fs_block_size = ih.fs().fs_block_size as u64;
+	let blk_index = (ih.i_size() / fs_block_size) as u32;
+	ih.ensure_blocks_allocated(blk_index, 1)?;
+	ih.set_i_size( (blk_index as u64 + 1) * fs_block_size )?;
+	Ok( (blk_index, ih.get_block_addr(blk_index)?) )
+}
 
-		if total_free >= name.len() {
-			// There is _maybe_ enough space for this name, but not in a contigious block
-			// - "maybe" because entries cannot span block boundaries, so there might be a few small free slots at the
-			//   end of blocks.
-			// Defragment blocks in overlapping pairs until there's enough free space
+/// Entry space in a directory leaf block (excluding the checksum tail, with metadata checksums)
+fn leaf_ents<'b>(inode: &dyn InodeHandleTrait, blk_data: &'b [u8]) -> &'b [u8]
+{
+	if inode.csum_seed().is_some() {
+		&blk_data[..blk_data.len() - ::ondisk::DIRENT_TAIL_SIZE]
+	}
+	else {
+		blk_data
+	}
+}
+/// Edit the entries in a directory leaf block, updating the checksum tail (if metadata checksums are enabled)
+fn edit_leaf<R>(inode: &dyn InodeHandleTrait, vol_blk: u64, f: impl FnOnce(&mut [u8])->vfs::node::Result<R>) -> vfs::node::Result<R>
+{
+	let csum_seed = inode.csum_seed();
+	inode.fs().edit_block(vol_blk, |blk_data| {
+		let Some(seed) = csum_seed else {
+			return f(blk_data);
+		};
+		let (ents, tail) = blk_data.split_at_mut(blk_data.len() - ::ondisk::DIRENT_TAIL_SIZE);
+		let rv = f(ents)?;
+		// The tail looks like an unused entry to code that doesn't know about checksums
+		write_dirent(tail, 0, ::ondisk::DIRENT_TAIL_SIZE, &[], ::ondisk::DIRENT_TAIL_FT);
+		let csum = ::csum::crc32c(seed, ents);
+		tail[8..12].copy_from_slice( &csum.to_le_bytes() );
+		Ok(rv)
+		})
+}
+
+/// Check that a directory only contains `.` and `..`
+fn is_empty_dir(inode: &dyn InodeHandleTrait) -> vfs::node::Result<bool>
+{
+	for vol_blk in inode.blocks()
+	{
+		let blk_data = inode.fs().get_block(vol_blk)?;
+		if read_entries(blk_data.bytes())?.iter().any(|&(_, _, ref name)| &name[..] != b"." && &name[..] != b"..") {
+			return Ok(false);
 		}
+	}
+	Ok(true)
+}
 
-		todo!("Dir::find_free - expand directory");
+/// Directory uses a hashed index
+fn is_indexed(inode: &dyn InodeHandleTrait) -> bool
+{
+	inode.i_flags() & ::ondisk::EXT4_INDEX_FL != 0 && inode.fs().has_feature_compat(::ondisk::FEAT_COMPAT_DIR_INDEX)
+}
+
+/// Directory entry type for an inode format
+fn file_type(i_mode_fmt: u16) -> u8
+{
+	match i_mode_fmt
+	{
+	::ondisk::S_IFREG => ::ondisk::FT_REG_FILE,
+	::ondisk::S_IFDIR => ::ondisk::FT_DIR,
+	::ondisk::S_IFCHR => ::ondisk::FT_CHRDEV,
+	::ondisk::S_IFBLK => ::ondisk::FT_BLKDEV,
+	::ondisk::S_IFIFO => ::ondisk::FT_FIFO,
+	::ondisk::S_IFSOCK => ::ondisk::FT_SOCK,
+	::ondisk::S_IFLNK => ::ondisk::FT_SYMLINK,
+	_ => ::ondisk::FT_UNKNOWN,
 	}
+}
 
-	/// Add an entry to the directory, checking for duplicates
-	fn add_dir_ent(&self, name: &ByteStr, inode: u32/*, d_type: u8*/) -> Result<(), vfs::Error>
+/// An entry being added to a directory
+struct NewEnt<'a>
+{
+	inode: u32,
+	d_type: u8,
+	name: &'a [u8],
+}
+
+/// Space used by a directory entry with a name of the given length
+fn dirent_len(name_len: usize) -> usize
+{
+	(::ondisk::DIRENT_MIN_SIZE + name_len + 3) & !3
+}
+/// Read and check the header of the entry at `ofs`, returns (inode, rec_len, name_len)
+fn dirent_hdr(blk_data: &[u8], ofs: usize) -> vfs::node::Result<(u32, usize, usize)>
+{
+	if ofs + ::ondisk::DIRENT_MIN_SIZE > blk_data.len() {
+		return Err(vfs::Error::InconsistentFilesystem);
+	}
+	let inode = u32::from_le_bytes([blk_data[ofs], blk_data[ofs+1], blk_data[ofs+2], blk_data[ofs+3]]);
+	let rec_len = u16::from_le_bytes([blk_data[ofs+4], blk_data[ofs+5]]) as usize;
+	let name_len = blk_data[ofs+6] as usize;
+	if rec_len < ::ondisk::DIRENT_MIN_SIZE || rec_len % 4 != 0 || ofs + rec_len > blk_data.len() || ::ondisk::DIRENT_MIN_SIZE + name_len > rec_len {
+		log_error!("Corrupted directory entry at +{}: rec_len={} name_len={}", ofs, rec_len, name_len);
+		return Err(vfs::Error::InconsistentFilesystem);
+	}
+	Ok( (inode, rec_len, name_len) )
+}
+/// Locate a (used) entry in a directory block, returns (offset, inode)
+fn find_in_block(blk_data: &[u8], name: &[u8]) -> vfs::node::Result<Option<(usize, u32)>>
+{
+	let mut ofs = 0;
+	while ofs < blk_data.len()
 	{
-		if !(name.len() <= 255) {
-			return Err(vfs::Error::InvalidParameter);
+		let (inode, rec_len, name_len) = dirent_hdr(blk_data, ofs)?;
+		if inode != 0 && &blk_data[ofs + 8..][..name_len] == name {
+			return Ok(Some( (ofs, inode) ));
 		}
-		let _lh_write = self.inode.lock_dir();
-
-		// 1. Find a suitable slot
-		let (blk, ofs) = try!(self.find_free(name));
-		log_debug!("add_dir_ent: Slot found: blk {blk} ofs {ofs}");
-		// 2. Fill said slot
-		let vol_blk = try!( self.inode.lock_read().blocks_from(blk as u32).next_or_err() );
-		self.inode.fs.edit_block(vol_blk, |blk_data| {
-			// SAFE: Alignment checked, range valid
-			let blk_data: &mut [u32] = unsafe {
-				assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-				::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
-				};
-			match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
-			{
-			None => return Err(vfs::Error::InconsistentFilesystem),
-			Some(ent) => {
-				ent.d_name_len = name.len() as u8;
-				ent.d_inode = inode as u32;
-				//ent.d_type = d_type;
-				},
-			}
-			// Re-get the entry using the new name length, and store the new name in it
-			let ent = ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..]).unwrap();
-			ent.d_name.clone_from_slice( name.as_ref() );
-			// If the entry has plenty of free space (record length is more than 16 bytes longer than needed), then split it
-			// - A dirent has 8 bytes of overhead (u32 inode, u16 len, u8 namelen, u8 type)
-			// TODO: ^
-			Ok( () )
-			})
+		ofs += rec_len;
+	}
+	Ok(None)
+}
+/// Find an entry with at least `need` bytes of unused space
+fn find_space(blk_data: &[u8], need: usize) -> vfs::node::Result<Option<usize>>
+{
+	let mut ofs = 0;
+	while ofs < blk_data.len()
+	{
+		let (inode, rec_len, name_len) = dirent_hdr(blk_data, ofs)?;
+		let used = if inode == 0 { 0 } else { dirent_len(name_len) };
+		if rec_len - used >= need {
+			return Ok(Some(ofs));
+		}
+		ofs += rec_len;
+	}
+	Ok(None)
+}
+/// Total unused space in a directory block
+fn free_space(blk_data: &[u8]) -> vfs::node::Result<usize>
+{
+	let mut rv = 0;
+	let mut ofs = 0;
+	while ofs < blk_data.len()
+	{
+		let (inode, rec_len, name_len) = dirent_hdr(blk_data, ofs)?;
+		rv += rec_len - if inode == 0 { 0 } else { dirent_len(name_len) };
+		ofs += rec_len;
+	}
+	Ok(rv)
+}
+/// Insert a new entry into the space at the end of the entry at `ofs` (found using `find_space`)
+fn insert_at(blk_data: &mut [u8], ofs: usize, ent: &NewEnt) -> vfs::node::Result<()>
+{
+	let (inode, rec_len, name_len) = dirent_hdr(blk_data, ofs)?;
+	if inode == 0 {
+		write_dirent(&mut blk_data[ofs..], ent.inode, rec_len, ent.name, ent.d_type);
+	}
+	else {
+		let used = dirent_len(name_len);
+		blk_data[ofs+4..][..2].copy_from_slice( &(used as u16).to_le_bytes() );
+		write_dirent(&mut blk_data[ofs + used..], ent.inode, rec_len - used, ent.name, ent.d_type);
+	}
+	Ok( () )
+}
+fn write_dirent(buf: &mut [u8], inode: u32, rec_len: usize, name: &[u8], d_type: u8)
+{
+	buf[0..4].copy_from_slice( &inode.to_le_bytes() );
+	buf[4..6].copy_from_slice( &(rec_len as u16).to_le_bytes() );
+	buf[6] = name.len() as u8;
+	buf[7] = d_type;
+	buf[8..][..name.len()].copy_from_slice(name);
+}
+/// Get all used entries in a block as (inode, d_type, name)
+fn read_entries(blk_data: &[u8]) -> vfs::node::Result<Vec<(u32, u8, Vec<u8>)>>
+{
+	let mut rv = Vec::new();
+	let mut ofs = 0;
+	while ofs < blk_data.len()
+	{
+		let (inode, rec_len, name_len) = dirent_hdr(blk_data, ofs)?;
+		if inode != 0 {
+			rv.push( (inode, blk_data[ofs+7], blk_data[ofs+8..][..name_len].to_vec()) );
+		}
+		ofs += rec_len;
+	}
+	Ok(rv)
+}
+/// Overwrite a block with the given entries (packed, with the last entry covering the rest of the block)
+fn fill_block<'a>(blk_data: &mut [u8], ents: impl Iterator<Item=&'a (u32, u8, Vec<u8>)>)
+{
+	let mut ofs = 0;
+	let mut last = None;
+	for &(inode, d_type, ref name) in ents
+	{
+		let len = dirent_len(name.len());
+		write_dirent(&mut blk_data[ofs..], inode, len, name, d_type);
+		last = Some(ofs);
+		ofs += len;
+	}
+	match last
+	{
+	Some(last) => {
+		let rec_len = blk_data.len() - last;
+		blk_data[last+4..][..2].copy_from_slice( &(rec_len as u16).to_le_bytes() );
+		},
+	None => write_dirent(blk_data, 0, blk_data.len(), &[], 0),
+	}
+	for b in blk_data[ofs..].iter_mut() {
+		*b = 0;
 	}
 }
 
@@ -219,6 +455,9 @@ impl vfs::node::NodeBase for Dir
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> vfs::Result<vfs::node::Metadata> {
+		Ok( self.inode.get_metadata() )
+	}
 }
 impl vfs::node::Dir for Dir
 {
@@ -228,8 +467,8 @@ impl vfs::node::Dir for Dir
 			Err(vfs::Error::NotFound)
 		}
 		else {
-			let (_, _, rv) = try!(self.find_name(name));
-			Ok( rv )
+			let (_, rv) = try!(self.find_name(name));
+			Ok( rv as vfs::node::InodeId )
 		}
 	}
 	fn read(&self, start_ofs: usize, callback: &mut vfs::node::ReadDirCallback) -> vfs::Result<usize>
@@ -309,17 +548,44 @@ impl vfs::node::Dir for Dir
 		}
 		else
 		{
-			let ino_id = try!( self.inode.fs.allocate_inode(self.inode.get_id() as u32, nodetype) );
+			let is_dir = matches!(nodetype, vfs::node::NodeType::Dir);
+			let _txn = self.inode.fs.start_transaction();
+			if is_dir {
+				// The new directory's `..` (checked first, as it can fail if there are too many links)
+				try!(self.inode.inc_link_count());
+			}
+			let ino_id = match self.inode.fs.allocate_inode(self.inode.get_id() as u32, nodetype)
+				{
+				Ok(v) => v,
+				Err(e) => {
+					if is_dir {
+						self.inode.dec_link_count();
+					}
+					return Err(e);
+					},
+				};
 
-			match self.add_dir_ent(name, ino_id)
+			let rv = if is_dir { self.init_dir(ino_id) } else { Ok( () ) };
+			match rv.and_then(|_| self.add_dir_ent(name, ino_id))
 			{
 			Ok(()) => {
 				log_debug!("create: {:?} = Inode{}", name, ino_id);
 				Ok(ino_id as vfs::node::InodeId)
 				},
 			Err(e) => {
-				// Call with_inode to force the inode to be deallocated
-				let _ = self.inode.fs.with_inode(ino_id, |_| Ok(()));
+				// Drop the initial link(s), so the inode is deallocated
+				let _ = self.inode.fs.with_inode(ino_id, |ino| {
+					if is_dir {
+						ino.clear_link_count();
+					}
+					else {
+						ino.dec_link_count();
+					}
+					Ok( () )
+					});
+				if is_dir {
+					self.inode.dec_link_count();
+				}
 				Err(e)
 				},
 			}
@@ -342,14 +608,20 @@ impl vfs::node::Dir for Dir
 		{
 			// TODO: How can I be sure that the passed inode number is valid? (or that it stays valid)
 			let inode = node.get_id();
-			try!(self.add_dir_ent(name, inode as u32));
-			// 3. Update inode's link count
-			try!(self.inode.fs.with_inode(inode as u32, |ino| {
-				ino.inc_link_count();
-				Ok( () )
-				}));
-			
-			todo!("link(name={:?}, inode={:?})", name, inode);
+			let _txn = self.inode.fs.start_transaction();
+			// Update the inode's link count first, as it can fail if there are too many links
+			try!(self.inode.fs.with_inode(inode as u32, |ino| ino.inc_link_count()));
+			match self.add_dir_ent(name, inode as u32)
+			{
+			Ok(()) => Ok( () ),
+			Err(e) => {
+				let _ = self.inode.fs.with_inode(inode as u32, |ino| {
+					ino.dec_link_count();
+					Ok( () )
+					});
+				Err(e)
+				},
+			}
 		}
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::node::Result<()> {
@@ -363,32 +635,106 @@ impl vfs::node::Dir for Dir
 		}
 		else
 		{
-			let (blk, ofs, _) = try!(self.find_name(name));
-
-			let inode = self.inode.lock_read();
-			let vol_blk = try!( inode.blocks_from(blk as u32).next_or_err() );
-
-			self.inode.fs.edit_block(vol_blk, |blk_data| {
-				// SAFE: Alignment checked, range valid
-				let blk_data: &mut [u32] = unsafe {
-					assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-					::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
-					};
-				match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
-				{
-				None => return Err(vfs::Error::InconsistentFilesystem),
-				Some(ent) => {
-					// Clear name length
-					ent.d_name_len = 0;
-					// Decrement inode's reference count
-					try!(self.inode.fs.with_inode(ent.d_inode, |ino| {
-						ino.dec_link_count();
-						Ok( () )
-						}));
-					Ok( () )
-					},
+			let _txn = self.inode.fs.start_transaction();
+			let (_, inode) = try!(self.find_name(name));
+			let is_dir = try!(self.inode.fs.with_inode(inode, |ino| {
+				let ih = ino.lock_read();
+				if ih.i_mode_fmt() != ::ondisk::S_IFDIR {
+					Ok(false)
+				}
+				else if is_empty_dir(&ih)? {
+					Ok(true)
+				}
+				else {
+					Err(vfs::Error::DirectoryNotEmpty)
+				}
+				}));
+			try!(self.remove_dir_ent(name));
+			// Decrement inode's reference count (the inode is freed once it's no longer in use)
+			try!(self.inode.fs.with_inode(inode, |ino| {
+				if is_dir {
+					// Also drops the directory's `.` link to itself
+					ino.clear_link_count();
+				}
+				else {
+					ino.dec_link_count();
 				}
-				})
+				Ok( () )
+				}));
+			if is_dir {
+				// and the `..` link to this directory
+				self.inode.dec_link_count();
+			}
+			Ok( () )
+		}
+	}
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn vfs::node::Dir, new_name: &ByteStr) -> vfs::node::Result<()> {
+		let new_dir = match new_dir.get_any().downcast_ref::<Dir>()
+			{
+			Some(v) => v,
+			None => return Err( vfs::Error::CrossFilesystem ),
+			};
+		if self.inode.fs.is_readonly()
+		{
+			Err( vfs::Error::ReadOnlyFilesystem )
+		}
+		else if old_name == "" || new_name == ""
+		{
+			Err( vfs::Error::InvalidParameter )
+		}
+		else
+		{
+			let _txn = self.inode.fs.start_transaction();
+			let (_, inode) = try!(self.find_name(old_name));
+			let is_dir = try!(self.inode.fs.with_inode(inode, |ino| Ok( ino.lock_read().i_mode_fmt() == ::ondisk::S_IFDIR )));
+			// Add the new name before removing the old, so the node is never unreachable
+			try!(new_dir.add_dir_ent(new_name, inode));
+			try!(self.remove_dir_ent(old_name));
+
+			// A directory moved to a new parent needs its `..` entry (and the parents' link counts) updated
+			if is_dir && new_dir.inode.get_id() != self.inode.get_id()
+			{
+				let new_parent = new_dir.inode.get_id() as u32;
+				let set_parent = |blk_data: &mut [u8]| -> vfs::node::Result<()> {
+					// SAFE: Alignment checked, range valid
+					let blk_data: &mut [u32] = unsafe {
+						assert!(&blk_data[0] as *const _ as usize % 4 == 0);
+						::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
+						};
+					let dot_len = match ::ondisk::DirEnt::new(blk_data)
+						{
+						Some(ent) if &ent.d_name == b"." => ent.u32_len(),
+						_ => return Err(vfs::Error::InconsistentFilesystem),
+						};
+					match ::ondisk::DirEnt::new_mut(&mut blk_data[dot_len ..])
+					{
+					Some(ent) if &ent.d_name == b".." => {
+						ent.d_inode = new_parent;
+						Ok( () )
+						},
+					_ => Err(vfs::Error::InconsistentFilesystem),
+					}
+					};
+				try!(self.inode.fs.with_inode(inode, |ino| {
+					let ih = ino.lock_read();
+					let vol_blk = ih.blocks().next_or_err()?;
+					if is_indexed(&ih) {
+						// Block zero is the index root, which has its own checksum
+						let csum_seed = ih.csum_seed();
+						ih.fs().edit_block(vol_blk, |blk_data| {
+							set_parent(blk_data)?;
+							::htree::update_root_csum(csum_seed, blk_data);
+							Ok( () )
+							})
+					}
+					else {
+						edit_leaf(&ih, vol_blk, &set_parent)
+					}
+					}));
+				self.inode.dec_link_count();
+				try!(new_dir.inode.inc_link_count());
+			}
+			Ok( () )
 		}
 	}
 }
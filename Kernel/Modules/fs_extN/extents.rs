@@ -0,0 +1,429 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/extents.rs
+//! ext4 extent trees (FEAT_INCOMPAT_EXTENTS)
+//!
+//! The root of the tree lives in the inode's `i_block` array (a header and up to four entries), with further
+//! levels stored in full filesystem blocks.
+use kernel::prelude::*;
+use kernel::lib::byteorder::EncodedLE;
+use crate::ondisk::{ExtentHeader,Extent,ExtentIdx};
+use crate::ondisk::{EXTENT_MAGIC,EXTENT_ENTRY_SIZE,EXTENT_INIT_MAX_LEN};
+use crate::instance::InstanceInner;
+
+/// Size of the in-inode tree root, in bytes
+const ROOT_SIZE: usize = 15 * 4;
+/// Sanity limit on the depth of a tree (ext4 itself uses 5)
+const MAX_DEPTH: u16 = 5;
+
+/// Result of looking up a logical block
+pub enum Mapping
+{
+	/// `count` blocks are mapped starting at physical block `start`
+	Mapped { start: u64, count: u32, unwritten: bool },
+	/// `count` blocks aren't backed by storage
+	Hole { count: u32 },
+}
+
+/// An entry in an extent tree node (either a leaf extent, or an index)
+#[derive(Copy,Clone)]
+struct Entry
+{
+	/// First logical block covered
+	block: u32,
+	/// Leaf: first physical block, Index: location of the child node
+	phys: u64,
+	/// Leaf only: Raw `ee_len` value
+	len: u16,
+}
+impl Entry
+{
+	fn is_unwritten(&self) -> bool {
+		self.len > EXTENT_INIT_MAX_LEN
+	}
+	fn count(&self) -> u32 {
+		if self.is_unwritten() {
+			(self.len - EXTENT_INIT_MAX_LEN) as u32
+		}
+		else {
+			self.len as u32
+		}
+	}
+}
+
+/// Decoded extent tree node
+struct Node
+{
+	depth: u16,
+	max: u16,
+	generation: u32,
+	entries: Vec<Entry>,
+}
+impl Node
+{
+	fn decode(buf: &[u8]) -> vfs::node::Result<Node> {
+		let mut r = buf;
+		let hdr = ExtentHeader::decode(&mut r).map_err(|_| vfs::Error::InconsistentFilesystem)?;
+		if hdr.eh_magic != EXTENT_MAGIC {
+			log_error!("Bad extent header magic {:#x}", hdr.eh_magic);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		if hdr.eh_entries > hdr.eh_max || EXTENT_ENTRY_SIZE * (1 + hdr.eh_max as usize) > buf.len() || hdr.eh_depth > MAX_DEPTH {
+			log_error!("Malformed extent header {:?}", hdr);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let mut entries = Vec::with_capacity(hdr.eh_entries as usize + 1);
+		for _ in 0 .. hdr.eh_entries
+		{
+			entries.push(if hdr.eh_depth == 0 {
+					let e = Extent::decode(&mut r).map_err(|_| vfs::Error::InconsistentFilesystem)?;
+					Entry { block: e.ee_block, phys: e.ee_start_lo as u64 | (e.ee_start_hi as u64) << 32, len: e.ee_len }
+				}
+				else {
+					let e = ExtentIdx::decode(&mut r).map_err(|_| vfs::Error::InconsistentFilesystem)?;
+					Entry { block: e.ei_block, phys: e.ei_leaf_lo as u64 | (e.ei_leaf_hi as u64) << 32, len: 0 }
+				});
+		}
+		Ok(Node {
+			depth: hdr.eh_depth,
+			max: hdr.eh_max,
+			generation: hdr.eh_generation,
+			entries: entries,
+			})
+	}
+	fn encode(&self, buf: &mut [u8]) {
+		assert!(self.entries.len() <= self.max as usize);
+		let mut w = &mut buf[..];
+		let hdr = ExtentHeader {
+			eh_magic: EXTENT_MAGIC,
+			eh_entries: self.entries.len() as u16,
+			eh_max: self.max,
+			eh_depth: self.depth,
+			eh_generation: self.generation,
+			};
+		hdr.encode(&mut w).unwrap();
+		for e in &self.entries
+		{
+			if self.depth == 0 {
+				Extent { ee_block: e.block, ee_len: e.len, ee_start_hi: (e.phys >> 32) as u16, ee_start_lo: e.phys as u32 }.encode(&mut w).unwrap();
+			}
+			else {
+				ExtentIdx { ei_block: e.block, ei_leaf_lo: e.phys as u32, ei_leaf_hi: (e.phys >> 32) as u16, _ei_unused: 0 }.encode(&mut w).unwrap();
+			}
+		}
+		// Clear the unused space (so stale entries aren't left around)
+		for b in w.iter_mut() {
+			*b = 0;
+		}
+	}
+
+	/// Index of the last entry that starts at or before `block`
+	fn find(&self, block: u32) -> Option<usize> {
+		match self.entries.iter().position(|e| e.block > block)
+		{
+		Some(0) => None,
+		Some(i) => Some(i - 1),
+		None if self.entries.is_empty() => None,
+		None => Some(self.entries.len() - 1),
+		}
+	}
+
+	fn read(fs: &InstanceInner, block: u64) -> vfs::node::Result<Node> {
+		let blk = fs.get_block(block)?;
+		Node::decode(blk.bytes())
+	}
+	/// Write to a tree block, including the checksum tail if `csum_seed` is set (see `InstanceInner::inode_csum_seed`)
+	fn write(&self, fs: &InstanceInner, block: u64, csum_seed: Option<u32>) -> vfs::node::Result<()> {
+		fs.edit_block(block, |data| {
+			self.encode(data);
+			// The tail follows the last possible entry
+			let tail = EXTENT_ENTRY_SIZE * (1 + self.max as usize);
+			if let (Some(seed), true) = (csum_seed, tail + 4 <= data.len()) {
+				let csum = ::csum::crc32c(seed, &data[..tail]);
+				data[tail..][..4].copy_from_slice( &csum.to_le_bytes() );
+			}
+			Ok( () )
+			})
+	}
+}
+
+fn root_to_bytes(i_block: &[u32; 15]) -> [u8; ROOT_SIZE] {
+	let mut rv = [0; ROOT_SIZE];
+	for (d,s) in Iterator::zip( rv.chunks_mut(4), i_block.iter() ) {
+		d.copy_from_slice(&s.to_le_bytes());
+	}
+	rv
+}
+fn root_from_bytes(i_block: &mut [u32; 15], buf: &[u8; ROOT_SIZE]) {
+	for (d,s) in Iterator::zip( i_block.iter_mut(), buf.chunks(4) ) {
+		*d = u32::from_le_bytes([s[0], s[1], s[2], s[3]]);
+	}
+}
+
+/// Number of entries that fit in a tree block (leaving space for the checksum tail)
+fn block_capacity(fs: &InstanceInner) -> u16 {
+	((fs.fs_block_size - EXTENT_ENTRY_SIZE) / EXTENT_ENTRY_SIZE) as u16
+}
+
+/// Initialise an empty extent tree in an inode's `i_block`
+pub fn init_root(i_block: &mut [u32; 15])
+{
+	let mut buf = [0; ROOT_SIZE];
+	Node { depth: 0, max: (ROOT_SIZE / EXTENT_ENTRY_SIZE - 1) as u16, generation: 0, entries: Vec::new() }.encode(&mut buf);
+	root_from_bytes(i_block, &buf);
+}
+
+/// Locate the physical position of logical block `block`
+pub fn lookup(fs: &InstanceInner, i_block: &[u32; 15], block: u32) -> vfs::node::Result<Mapping>
+{
+	let mut node = Node::decode(&root_to_bytes(i_block))?;
+	// Exclusive upper bound on the logical blocks covered by `node`
+	let mut limit = 1u64 << 32;
+	loop
+	{
+		let idx = match node.find(block)
+			{
+			Some(i) => i,
+			None => {
+				let end = node.entries.first().map(|e| e.block as u64).unwrap_or(limit);
+				return Ok(Mapping::Hole { count: (end.min(limit) - block as u64).min(u32::MAX as u64) as u32 });
+				},
+			};
+		let ent = node.entries[idx];
+		let next = node.entries.get(idx+1).map(|e| e.block as u64).unwrap_or(limit).min(limit);
+		if node.depth == 0
+		{
+			let ofs = block - ent.block;
+			return Ok(if ofs < ent.count() {
+					Mapping::Mapped { start: ent.phys + ofs as u64, count: ent.count() - ofs, unwritten: ent.is_unwritten() }
+				}
+				else {
+					Mapping::Hole { count: (next - block as u64).min(u32::MAX as u64) as u32 }
+				});
+		}
+		let child = Node::read(fs, ent.phys)?;
+		if child.depth + 1 != node.depth {
+			log_error!("Extent tree depth mismatch ({} under {})", child.depth, node.depth);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		limit = next;
+		node = child;
+	}
+}
+
+/// State used when inserting into a tree
+struct Inserter<'a>
+{
+	fs: &'a InstanceInner,
+	inode_num: u32,
+	csum_seed: Option<u32>,
+	/// Number of tree blocks allocated (for `i_blocks` accounting)
+	new_blocks: u32,
+}
+impl<'a> Inserter<'a>
+{
+	fn alloc_node(&mut self, near: u64) -> vfs::node::Result<u64> {
+		let rv = self.fs.allocate_data_block(self.inode_num, near)?;
+		self.new_blocks += 1;
+		Ok(rv)
+	}
+
+	/// Insert `new` into the subtree rooted at `node`, leaving `node` over capacity by one if it filled
+	fn insert(&mut self, node: &mut Node, new: Entry) -> vfs::node::Result<()>
+	{
+		if node.depth == 0
+		{
+			let pos = node.find(new.block).map(|i| i+1).unwrap_or(0);
+			if pos > 0 {
+				let prev = &mut node.entries[pos-1];
+				if prev.block + prev.count() > new.block {
+					log_error!("Extent insert overlaps existing extent ({}+{} vs {})", prev.block, prev.count(), new.block);
+					return Err(vfs::Error::InconsistentFilesystem);
+				}
+				// Merge with the previous extent if contiguous on both sides
+				if !prev.is_unwritten() && prev.block + prev.count() == new.block && prev.phys + prev.count() as u64 == new.phys
+					&& prev.len as u32 + new.len as u32 <= EXTENT_INIT_MAX_LEN as u32
+				{
+					prev.len += new.len;
+					return Ok( () );
+				}
+			}
+			node.entries.insert(pos, new);
+			Ok( () )
+		}
+		else
+		{
+			let pos = match node.find(new.block)
+				{
+				Some(i) => i,
+				None if node.entries.is_empty() => return Err(vfs::Error::InconsistentFilesystem),
+				None => {
+					// New entry is before the start of this node, extend the first child to cover it
+					node.entries[0].block = new.block;
+					0
+					},
+				};
+			let child_blk = node.entries[pos].phys;
+			let mut child = Node::read(self.fs, child_blk)?;
+			self.insert(&mut child, new)?;
+			if child.entries.len() > child.max as usize
+			{
+				// Split the child, moving the upper half into a new block
+				let split_at = child.entries.len() / 2;
+				let sibling = Node {
+					depth: child.depth,
+					max: block_capacity(self.fs),
+					generation: child.generation,
+					entries: child.entries.split_off(split_at),
+					};
+				let sibling_blk = self.alloc_node(child_blk)?;
+				sibling.write(self.fs, sibling_blk, self.csum_seed)?;
+				node.entries.insert(pos+1, Entry { block: sibling.entries[0].block, phys: sibling_blk, len: 0 });
+			}
+			child.write(self.fs, child_blk, self.csum_seed)
+		}
+	}
+}
+
+/// Add a mapping for `count` blocks starting at logical block `block` (which must not already be mapped)
+///
+/// Returns the number of tree blocks allocated
+pub fn insert(fs: &InstanceInner, inode_num: u32, csum_seed: Option<u32>, i_block: &mut [u32; 15], block: u32, phys: u64, count: u16) -> vfs::node::Result<u32>
+{
+	assert!(count > 0 && count <= EXTENT_INIT_MAX_LEN);
+	let mut root = Node::decode(&root_to_bytes(i_block))?;
+	let mut ins = Inserter { fs, inode_num, csum_seed, new_blocks: 0 };
+	ins.insert(&mut root, Entry { block: block, phys: phys, len: count })?;
+	if root.entries.len() > root.max as usize
+	{
+		// The root is full, move its contents into a new block and increase the depth
+		let child = Node {
+			depth: root.depth,
+			max: block_capacity(fs),
+			generation: root.generation,
+			entries: ::core::mem::replace(&mut root.entries, Vec::new()),
+			};
+		let child_blk = ins.alloc_node(phys)?;
+		child.write(fs, child_blk, csum_seed)?;
+		log_debug!("Extent tree for I{} grown to depth {}", inode_num, root.depth+1);
+		root.depth += 1;
+		root.entries.push(Entry { block: child.entries[0].block, phys: child_blk, len: 0 });
+	}
+	let mut buf = [0; ROOT_SIZE];
+	root.encode(&mut buf);
+	root_from_bytes(i_block, &buf);
+	Ok(ins.new_blocks)
+}
+
+/// Zero the unwritten extent containing `block` and mark it as written
+pub fn mark_written(fs: &InstanceInner, csum_seed: Option<u32>, i_block: &mut [u32; 15], block: u32) -> vfs::node::Result<()>
+{
+	// TODO: Split the extent instead of initialising the entire thing.
+	fn mark(fs: &InstanceInner, node: &mut Node, block: u32) -> vfs::node::Result<Option<u64>> {
+		let idx = match node.find(block)
+			{
+			Some(i) => i,
+			None => return Err(vfs::Error::InconsistentFilesystem),
+			};
+		if node.depth == 0 {
+			let e = &mut node.entries[idx];
+			if e.is_unwritten() && block - e.block < e.count() {
+				let zeroes = vec![0; fs.fs_block_size];
+				for b in e.phys .. e.phys + e.count() as u64 {
+					fs.write_blocks(b, &zeroes)?;
+				}
+				e.len -= EXTENT_INIT_MAX_LEN;
+			}
+			Ok(None)
+		}
+		else {
+			Ok(Some(node.entries[idx].phys))
+		}
+	}
+	let mut root = Node::decode(&root_to_bytes(i_block))?;
+	let mut next = mark(fs, &mut root, block)?;
+	if next.is_none() {
+		let mut buf = [0; ROOT_SIZE];
+		root.encode(&mut buf);
+		root_from_bytes(i_block, &buf);
+	}
+	while let Some(blk) = next
+	{
+		let mut node = Node::read(fs, blk)?;
+		next = mark(fs, &mut node, block)?;
+		if next.is_none() {
+			node.write(fs, blk, csum_seed)?;
+		}
+	}
+	Ok( () )
+}
+
+/// Remove all mappings at or after logical block `first`, freeing the data and any emptied tree blocks
+///
+/// Returns the number of blocks freed (for `i_blocks` accounting)
+pub fn truncate(fs: &InstanceInner, csum_seed: Option<u32>, i_block: &mut [u32; 15], first: u32) -> vfs::node::Result<u64>
+{
+	fn trunc(fs: &InstanceInner, csum_seed: Option<u32>, node: &mut Node, first: u32) -> vfs::node::Result<u64> {
+		let mut freed = 0;
+		if node.depth == 0
+		{
+			while let Some(e) = node.entries.last_mut()
+			{
+				if e.block >= first {
+					fs.free_blocks(e.phys, e.count() as u64)?;
+					freed += e.count() as u64;
+					node.entries.pop();
+				}
+				else {
+					let keep = e.count().min(first - e.block);
+					if keep < e.count() {
+						fs.free_blocks(e.phys + keep as u64, (e.count() - keep) as u64)?;
+						freed += (e.count() - keep) as u64;
+						e.len = if e.is_unwritten() { keep as u16 + EXTENT_INIT_MAX_LEN } else { keep as u16 };
+					}
+					break;
+				}
+			}
+		}
+		else
+		{
+			// Only the last child starting before `first` can be partially kept
+			let mut i = node.entries.len();
+			while i > 0
+			{
+				i -= 1;
+				let ent = node.entries[i];
+				let mut child = Node::read(fs, ent.phys)?;
+				if child.depth + 1 != node.depth {
+					log_error!("Extent tree depth mismatch ({} under {})", child.depth, node.depth);
+					return Err(vfs::Error::InconsistentFilesystem);
+				}
+				freed += trunc(fs, csum_seed, &mut child, first)?;
+				if child.entries.is_empty() {
+					fs.free_blocks(ent.phys, 1)?;
+					freed += 1;
+					node.entries.remove(i);
+				}
+				else {
+					child.write(fs, ent.phys, csum_seed)?;
+				}
+				if ent.block < first {
+					break;
+				}
+			}
+		}
+		Ok(freed)
+	}
+
+	let mut root = Node::decode(&root_to_bytes(i_block))?;
+	let freed = trunc(fs, csum_seed, &mut root, first)?;
+	if root.entries.is_empty() && root.depth > 0 {
+		// Nothing left below the root, collapse back to an empty leaf
+		root.depth = 0;
+	}
+	let mut buf = [0; ROOT_SIZE];
+	root.encode(&mut buf);
+	root_from_bytes(i_block, &buf);
+	Ok(freed)
+}
@@ -29,6 +29,9 @@ impl vfs::node::NodeBase for File
 	fn get_any(&self) -> &dyn (::core::any::Any) {
 		self
 	}
+	fn get_metadata(&self) -> vfs::Result<vfs::node::Metadata> {
+		Ok( self.inode.get_metadata() )
+	}
 }
 impl vfs::node::File for File
 {
@@ -56,6 +59,12 @@ impl vfs::node::File for File
 		iter_blocks_range(&inode, ofs, buf.len(), &mut |block_range, data_range| {
 			match block_range
 			{
+			// Block zero indicates a sparse region (or an unwritten extent)
+			BlockRef::Sub(0, _) | BlockRef::Range(0, _) => {
+				for b in &mut buf[data_range] {
+					*b = 0;
+				}
+				},
 			BlockRef::Sub(blkid, sub_range) => {
 				let blk_data = try!(self.inode.fs.get_block_uncached(blkid));
 				buf[data_range].copy_from_slice(&blk_data[sub_range]);
@@ -69,28 +78,32 @@ impl vfs::node::File for File
 	}
 
 	fn truncate(&self, new_size: u64) -> vfs::node::Result<u64> {
+		if self.inode.fs.is_readonly() {
+			return Err( vfs::Error::ReadOnlyFilesystem );
+		}
+		let _txn = self.inode.fs.start_transaction();
 		let mut inode = self.inode.lock_write();
 		let old_size = inode.i_size();
-		if new_size == 0
-		{
-			todo!("truncate - 0");
-		}
-		else if new_size == old_size
+		let fs_block_size = self.inode.fs.fs_block_size as u64;
+		if new_size == old_size
 		{
 			Ok( new_size )
 		}
 		else if new_size < old_size
 		{
-			todo!("truncate - shrink");
+			// Clear the rest of the new final block, so a later extension reads as zero
+			let tail = new_size % fs_block_size;
+			if tail != 0 {
+				zero_range(&inode, new_size, fs_block_size - tail)?;
+			}
+			inode.free_blocks_from( ::kernel::lib::num::div_up(new_size, fs_block_size) as u32 )?;
+			inode.set_i_size(new_size)?;
+			Ok( new_size )
 		}
 		else {
-			ensure_blocks_present(&self.inode.fs, &mut inode, new_size)?;
+			ensure_blocks_present(&self.inode.fs, &mut inode, old_size, new_size - old_size)?;
 			inode.set_i_size(new_size)?;
-			// TODO: Risky cast? If truncating to a very large size
-			iter_blocks_range(&inode, old_size, (new_size - old_size) as usize, &mut |_block_range, _data_range| {
-				// TODO: Zero the blocks?
-				Ok( () )
-				})?;
+			zero_range(&inode, old_size, new_size - old_size)?;
 			Ok( new_size )
 		}
 	}
@@ -105,10 +118,8 @@ impl vfs::node::File for File
 			Err( vfs::Error::InvalidParameter )
 		}
 		else {
-			// 1. Leading partial
-			// 2. Inner
-			// 3. Trailing partial
-			todo!("clear");
+			let _txn = self.inode.fs.start_transaction();
+			zero_range(&inode, ofs, size)
 		}
 	}
 	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
@@ -121,6 +132,8 @@ impl vfs::node::File for File
 		else if ofs == size
 		{
 			drop(inode);
+			// All metadata changes (and the inode write-back) are committed together
+			let _txn = self.inode.fs.start_transaction();
 			let mut inode = self.inode.lock_write();
 			if ofs == inode.i_size() {
 			}
@@ -129,7 +142,7 @@ impl vfs::node::File for File
 			}
 			let new_size = ofs + buf.len() as u64;
 			// Ensure that there are blocks allocated
-			ensure_blocks_present(&self.inode.fs, &mut inode, new_size)?;
+			ensure_blocks_present(&self.inode.fs, &mut inode, ofs, buf.len() as u64)?;
 			// Extend the size
 			inode.set_i_size(new_size)?;
 			// Write data
@@ -141,6 +154,11 @@ impl vfs::node::File for File
 			Err( vfs::Error::InvalidParameter )
 		}
 		else {
+			drop(inode);
+			let _txn = self.inode.fs.start_transaction();
+			let mut inode = self.inode.lock_write();
+			// Fill any sparse regions being written to
+			ensure_blocks_present(&self.inode.fs, &mut inode, ofs, buf.len() as u64)?;
 			// NOTE: In this section, we're free to read-modify-write blocks without fear, as the VFS itself handles
 			//       the file "borrow checking". A file race is the userland's problem (if a SharedRW handle is used)
 			write_inner(&inode, ofs, buf)
@@ -159,16 +177,45 @@ fn write_inner(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, buf: &[u8]
 				})?;
 			},
 		BlockRef::Range(blkid, _count) => {
-			inode.fs().write_blocks(blkid, &buf[data_range])?;
+			// NOTE: Cache-aware, as the blocks may have previously held (cached) metadata
+			inode.fs().write_blocks_cached(blkid, &buf[data_range])?;
 			},
 		}
 		Ok( () )
 		})
 }
 
+/// Overwrite part of a file with zeroes (sparse regions are left as-is)
+fn zero_range(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, len: u64) -> vfs::Result<()> {
+	let fs = inode.fs();
+	let zeroes = vec![0; fs.fs_block_size];
+	// TODO: Risky cast? If zeroing a very large range
+	iter_blocks_range(inode, ofs, len as usize, &mut |block_range, _data_range| {
+		match block_range
+		{
+		BlockRef::Sub(0, _) | BlockRef::Range(0, _) => {},
+		BlockRef::Sub(blkid, sub_range) => {
+			fs.edit_block(blkid, |data| {
+				for b in &mut data[sub_range] {
+					*b = 0;
+				}
+				Ok( () )
+				})?;
+			},
+		BlockRef::Range(blkid, count) => {
+			for i in 0 .. count as u64 {
+				fs.write_blocks_cached(blkid + i, &zeroes)?;
+			}
+			},
+		}
+		Ok( () )
+		})?;
+	Ok( () )
+}
+
 enum BlockRef {
-	Sub(u32, ::core::ops::Range<usize>),
-	Range(u32, u32),
+	Sub(u64, ::core::ops::Range<usize>),
+	Range(u64, u32),
 }
 fn iter_blocks_range(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, len: usize, cb: &mut dyn FnMut(BlockRef, ::core::ops::Range<usize>)->vfs::Result<()>) -> vfs::Result<usize>
 {
@@ -202,15 +249,16 @@ fn iter_blocks_range(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, len:
 	{
 		let b = blocks.next_or_err()?;
 		log_trace!("iter_blocks_range: Suffix B{} 0+{}", b, trailing_bytes);
-		cb( BlockRef::Sub(b, 0..trailing_bytes), written..written+len )?;
+		cb( BlockRef::Sub(b, 0..trailing_bytes), written..written+trailing_bytes )?;
 		written += trailing_bytes;
 	}
 	Ok( written )
 }
 
-fn ensure_blocks_present(fs: &super::instance::InstanceInner, lh: &mut super::inodes::InodeHandleWrite, size: u64) -> vfs::Result<()> {
-	let nblocks = ::kernel::lib::num::div_up(size, fs.fs_block_size as u64);
+fn ensure_blocks_present(fs: &super::instance::InstanceInner, lh: &mut super::inodes::InodeHandleWrite, ofs: u64, len: u64) -> vfs::Result<()> {
+	let first_block = ofs / fs.fs_block_size as u64;
+	let end_block = ::kernel::lib::num::div_up(ofs + len, fs.fs_block_size as u64);
 
-	lh.ensure_blocks_allocated(0, nblocks as u32)
+	lh.ensure_blocks_allocated(first_block as u32, (end_block - first_block) as u32)
 }
 
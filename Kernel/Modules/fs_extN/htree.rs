@@ -0,0 +1,483 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/htree.rs
+//! Hashed directory indexes (HTree)
+//!
+//! An indexed directory stores a `dx_root` in its first block (hidden in the `..` entry's record), mapping name hashes
+//! to the directory blocks (leaves) holding the entries. Larger directories have a level of `dx_node` blocks between
+//! the root and the leaves, which look like a single empty entry to code that doesn't understand the index.
+use kernel::prelude::*;
+use crate::inodes::{InodeHandleTrait,InodeHandleWrite};
+use crate::ondisk::{DX_HASH_LEGACY,DX_HASH_HALF_MD4,DX_HASH_TEA,DX_HASH_LEGACY_UNSIGNED,DX_HASH_HALF_MD4_UNSIGNED,DX_HASH_TEA_UNSIGNED};
+
+/// Offset of `dx_root_info` in the root block (after the `.` and `..` entry headers)
+const DX_ROOT_INFO_OFS: usize = 24;
+/// Offset of the entries in a `dx_node` (after the fake directory entry)
+const DX_NODE_ENTRIES_OFS: usize = 8;
+/// Size of a `dx_entry`
+const DX_ENTRY_SIZE: usize = 8;
+/// Size of the `dx_tail` following the entries (with metadata checksums)
+const DX_TAIL_SIZE: usize = 8;
+/// Maximum value of `indirect_levels` (without FEAT_INCOMPAT_LARGEDIR)
+const DX_MAX_INDIRECT_LEVELS: u8 = 1;
+
+/// Compute the (major, minor) hash of a name
+pub fn dx_hash(name: &[u8], version: u8, seed: &[u32; 4]) -> (u32, u32)
+{
+	let mut buf = if seed.iter().any(|&v| v != 0) {
+			*seed
+		}
+		else {
+			[0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476]
+		};
+	let (hash, minor_hash) = match version
+		{
+		DX_HASH_LEGACY => (legacy_hash(name, true), 0),
+		DX_HASH_LEGACY_UNSIGNED => (legacy_hash(name, false), 0),
+		DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+			let mut input = [0; 8];
+			for ofs in (0 .. name.len()).step_by(32) {
+				str2hashbuf(&name[ofs..], version == DX_HASH_HALF_MD4, &mut input);
+				half_md4_transform(&mut buf, &input);
+			}
+			(buf[1], buf[2])
+			},
+		DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+			let mut input = [0; 4];
+			for ofs in (0 .. name.len()).step_by(16) {
+				str2hashbuf(&name[ofs..], version == DX_HASH_TEA, &mut input);
+				tea_transform(&mut buf, &input);
+			}
+			(buf[0], buf[1])
+			},
+		_ => panic!("dx_hash: Unknown hash version {}", version),
+		};
+	// The bottom bit is used to flag hash collisions that span leaves, and the top value is reserved for EOF
+	let hash = match hash & !1
+		{
+		0xFFFF_FFFE => 0xFFFF_FFFC,
+		v => v,
+		};
+	(hash, minor_hash)
+}
+
+/// The original (r5-like) hash
+fn legacy_hash(name: &[u8], signed: bool) -> u32
+{
+	let (mut hash0, mut hash1) = (0x12a3fe2d_u32, 0x37abe8f9_u32);
+	for &c in name
+	{
+		let c = if signed { c as i8 as i32 } else { c as i32 };
+		let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7152373) as u32);
+		if hash & 0x8000_0000 != 0 {
+			hash = hash.wrapping_sub(0x7FFF_FFFF);
+		}
+		hash1 = hash0;
+		hash0 = hash;
+	}
+	hash0 << 1
+}
+
+/// Pack (the start of) a name into words, padded with a value derived from the remaining length
+fn str2hashbuf(msg: &[u8], signed: bool, out: &mut [u32])
+{
+	let len = msg.len() as u32;
+	let pad = (len | len << 8) | (len | len << 8) << 16;
+	let mut val = pad;
+	let mut n = 0;
+	for (i, &c) in msg.iter().take(out.len() * 4).enumerate()
+	{
+		let c = if signed { c as i8 as i32 as u32 } else { c as u32 };
+		val = c.wrapping_add(val << 8);
+		if i % 4 == 3 {
+			out[n] = val;
+			n += 1;
+			val = pad;
+		}
+	}
+	if n < out.len() {
+		out[n] = val;
+		n += 1;
+	}
+	for v in out[n..].iter_mut() {
+		*v = pad;
+	}
+}
+
+/// MD4 with only half of the rounds
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8])
+{
+	const K1: u32 = 0;
+	const K2: u32 = 0o13240474631;
+	const K3: u32 = 0o15666365641;
+	fn f(x: u32, y: u32, z: u32) -> u32 { z ^ (x & (y ^ z)) }
+	fn g(x: u32, y: u32, z: u32) -> u32 { (x & y).wrapping_add((x ^ y) & z) }
+	fn h(x: u32, y: u32, z: u32) -> u32 { x ^ y ^ z }
+	fn round(f: fn(u32,u32,u32)->u32, a: &mut u32, b: u32, c: u32, d: u32, x: u32, s: u32) {
+		*a = a.wrapping_add(f(b, c, d)).wrapping_add(x).rotate_left(s);
+	}
+
+	let [mut a, mut b, mut c, mut d] = *buf;
+	round(f, &mut a, b, c, d, input[0].wrapping_add(K1),  3);
+	round(f, &mut d, a, b, c, input[1].wrapping_add(K1),  7);
+	round(f, &mut c, d, a, b, input[2].wrapping_add(K1), 11);
+	round(f, &mut b, c, d, a, input[3].wrapping_add(K1), 19);
+	round(f, &mut a, b, c, d, input[4].wrapping_add(K1),  3);
+	round(f, &mut d, a, b, c, input[5].wrapping_add(K1),  7);
+	round(f, &mut c, d, a, b, input[6].wrapping_add(K1), 11);
+	round(f, &mut b, c, d, a, input[7].wrapping_add(K1), 19);
+
+	round(g, &mut a, b, c, d, input[1].wrapping_add(K2),  3);
+	round(g, &mut d, a, b, c, input[3].wrapping_add(K2),  5);
+	round(g, &mut c, d, a, b, input[5].wrapping_add(K2),  9);
+	round(g, &mut b, c, d, a, input[7].wrapping_add(K2), 13);
+	round(g, &mut a, b, c, d, input[0].wrapping_add(K2),  3);
+	round(g, &mut d, a, b, c, input[2].wrapping_add(K2),  5);
+	round(g, &mut c, d, a, b, input[4].wrapping_add(K2),  9);
+	round(g, &mut b, c, d, a, input[6].wrapping_add(K2), 13);
+
+	round(h, &mut a, b, c, d, input[3].wrapping_add(K3),  3);
+	round(h, &mut d, a, b, c, input[7].wrapping_add(K3),  9);
+	round(h, &mut c, d, a, b, input[2].wrapping_add(K3), 11);
+	round(h, &mut b, c, d, a, input[6].wrapping_add(K3), 15);
+	round(h, &mut a, b, c, d, input[1].wrapping_add(K3),  3);
+	round(h, &mut d, a, b, c, input[5].wrapping_add(K3),  9);
+	round(h, &mut c, d, a, b, input[0].wrapping_add(K3), 11);
+	round(h, &mut b, c, d, a, input[4].wrapping_add(K3), 15);
+
+	buf[0] = buf[0].wrapping_add(a);
+	buf[1] = buf[1].wrapping_add(b);
+	buf[2] = buf[2].wrapping_add(c);
+	buf[3] = buf[3].wrapping_add(d);
+}
+
+/// Tiny Encryption Algorithm, 16 rounds
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4])
+{
+	const DELTA: u32 = 0x9E3779B9;
+	let [a, b, c, d] = *input;
+	let (mut b0, mut b1) = (buf[0], buf[1]);
+	let mut sum = 0u32;
+	for _ in 0 .. 16
+	{
+		sum = sum.wrapping_add(DELTA);
+		b0 = b0.wrapping_add( (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b) );
+		b1 = b1.wrapping_add( (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d) );
+	}
+	buf[0] = buf[0].wrapping_add(b0);
+	buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn read_u16(d: &[u8], ofs: usize) -> u16 {
+	u16::from_le_bytes([d[ofs], d[ofs+1]])
+}
+fn read_u32(d: &[u8], ofs: usize) -> u32 {
+	u32::from_le_bytes([d[ofs], d[ofs+1], d[ofs+2], d[ofs+3]])
+}
+
+/// One index block on the path from the root to a leaf
+struct Frame
+{
+	/// Directory block index
+	blk: u32,
+	limit: usize,
+	/// (hash, directory block) pairs, the first hash is implicitly zero
+	entries: Vec<(u32, u32)>,
+	/// Entry that was followed
+	pos: usize,
+	dirty: bool,
+}
+impl Frame
+{
+	/// Locate the last entry with a hash not greater than `hash`
+	fn search(&self, hash: u32) -> usize {
+		self.entries[1..].partition_point(|e| e.0 <= hash)
+	}
+	fn is_full(&self) -> bool {
+		self.entries.len() >= self.limit
+	}
+}
+
+/// Path through the index to the leaf that should contain a name
+pub struct Path
+{
+	hash_version: u8,
+	seed: [u32; 4],
+	/// Hash of the name being looked up
+	hash: u32,
+	/// Offset of the entries in the root block (depends on `dx_root_info.info_length`)
+	root_entries_ofs: usize,
+	/// Number of levels below the root
+	indirect_levels: u8,
+	frames: Vec<Frame>,
+}
+
+impl Path
+{
+	/// Walk the index of a directory (with EXT4_INDEX_FL) to find the leaf for `name`
+	///
+	/// Returns `None` if the index is unusable (unknown hash, or corrupted), in which case the directory should be
+	/// treated as linear.
+	pub fn probe(ih: &dyn InodeHandleTrait, name: &[u8]) -> vfs::Result<Option<Path>>
+	{
+		let fs = ih.fs();
+		let (hash_version, info_length, indirect_levels, flags) = {
+			let blk = fs.get_block(ih.get_block_addr(0)?)?;
+			let d = blk.bytes();
+			if read_u16(d, 12+4) as usize != fs.fs_block_size - 12 || read_u32(d, DX_ROOT_INFO_OFS) != 0 {
+				log_warning!("{}: Bad HTree root (..={}, reserved={:#x})", fs.vol.name(), read_u16(d, 12+4), read_u32(d, DX_ROOT_INFO_OFS));
+				return Ok(None);
+			}
+			(d[DX_ROOT_INFO_OFS+4], d[DX_ROOT_INFO_OFS+5] as usize, d[DX_ROOT_INFO_OFS+6], d[DX_ROOT_INFO_OFS+7])
+			};
+		if hash_version > DX_HASH_TEA {
+			log_warning!("{}: Unsupported HTree hash version {}", fs.vol.name(), hash_version);
+			return Ok(None);
+		}
+		if info_length < 8 || DX_ROOT_INFO_OFS + info_length + DX_ENTRY_SIZE > fs.fs_block_size || indirect_levels > DX_MAX_INDIRECT_LEVELS || flags & 1 != 0 {
+			log_warning!("{}: Bad HTree root info (len={}, levels={}, flags={:#x})", fs.vol.name(), info_length, indirect_levels, flags);
+			return Ok(None);
+		}
+
+		let hash_version = if fs.dir_hash_unsigned() { hash_version + 3 } else { hash_version };
+		let seed = fs.dir_hash_seed();
+		let (hash, _) = dx_hash(name, hash_version, &seed);
+		let mut rv = Path {
+			hash_version: hash_version,
+			seed: seed,
+			hash: hash,
+			root_entries_ofs: DX_ROOT_INFO_OFS + info_length,
+			indirect_levels: indirect_levels,
+			frames: Vec::with_capacity(indirect_levels as usize + 1),
+			};
+		let mut blk = 0;
+		for level in 0 ..= indirect_levels as usize
+		{
+			let mut frame = match rv.load_frame(ih, blk, level == 0)
+				{
+				Ok(v) => v,
+				Err(vfs::Error::InconsistentFilesystem) => return Ok(None),
+				Err(e) => return Err(e),
+				};
+			frame.pos = frame.search(hash);
+			blk = frame.entries[frame.pos].1;
+			rv.frames.push(frame);
+		}
+		log_trace!("probe: hash={:#x} leaf={}", hash, blk);
+		Ok(Some(rv))
+	}
+
+	fn load_frame(&self, ih: &dyn InodeHandleTrait, blk: u32, is_root: bool) -> vfs::Result<Frame>
+	{
+		let fs = ih.fs();
+		let ofs = if is_root { self.root_entries_ofs } else { DX_NODE_ENTRIES_OFS };
+		let vol_blk = ih.get_block_addr(blk)?;
+		if vol_blk == 0 {
+			log_warning!("{}: HTree block {} is sparse", fs.vol.name(), blk);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let data = fs.get_block(vol_blk)?;
+		let d = data.bytes();
+		let limit = read_u16(d, ofs) as usize;
+		let count = read_u16(d, ofs+2) as usize;
+		if count == 0 || count > limit || ofs + limit * DX_ENTRY_SIZE > d.len() {
+			log_warning!("{}: Bad HTree block {} (count={}, limit={})", fs.vol.name(), blk, count, limit);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let entries = (0 .. count)
+			.map(|i| {
+				let e = ofs + i * DX_ENTRY_SIZE;
+				(if i == 0 { 0 } else { read_u32(d, e) }, read_u32(d, e + 4) & 0x0FFF_FFFF)
+				})
+			.collect();
+		Ok(Frame { blk, limit, entries, pos: 0, dirty: false })
+	}
+
+	/// Hash of the name that was looked up
+	pub fn hash(&self) -> u32 {
+		self.hash
+	}
+	/// Hash of another name, using this directory's hash algorithm
+	pub fn hash_name(&self, name: &[u8]) -> (u32, u32) {
+		dx_hash(name, self.hash_version, &self.seed)
+	}
+	/// Leaf block containing the looked up hash
+	pub fn leaf(&self) -> u32 {
+		let f = self.frames.last().unwrap();
+		f.entries[f.pos].1
+	}
+
+	/// Advance to the next leaf, if it can contain entries with the same hash (a collision that spans a split)
+	pub fn next_leaf(&mut self, ih: &dyn InodeHandleTrait) -> vfs::Result<Option<u32>>
+	{
+		// Find the lowest level that has a next entry
+		let Some(k) = self.frames.iter().rposition(|f| f.pos + 1 < f.entries.len()) else {
+			return Ok(None);
+		};
+		let f = &mut self.frames[k];
+		f.pos += 1;
+		if f.entries[f.pos].0 & !1 != self.hash {
+			return Ok(None);
+		}
+		// Reload the path below that level
+		for j in k+1 .. self.frames.len()
+		{
+			let blk = { let p = &self.frames[j-1]; p.entries[p.pos].1 };
+			self.frames[j] = self.load_frame(ih, blk, false)?;
+		}
+		Ok(Some(self.leaf()))
+	}
+
+	/// Ensure that the bottom index block can take another entry, splitting index blocks or adding a level if needed
+	pub fn make_room(&mut self, ih: &mut InodeHandleWrite) -> vfs::Result<()>
+	{
+		let fs = ih.fs();
+		let tail_size = if ih.csum_seed().is_some() { DX_TAIL_SIZE } else { 0 };
+		let node_limit = (fs.fs_block_size - DX_NODE_ENTRIES_OFS - tail_size) / DX_ENTRY_SIZE;
+		// Find the lowest level that isn't full
+		let mut k = self.frames.len();
+		while k > 0 && self.frames[k-1].is_full() {
+			k -= 1;
+		}
+		if k == self.frames.len() {
+			return Ok( () );
+		}
+		if k == 0
+		{
+			// Everything is full, move the root's entries into a new node (which has more room than the root)
+			if self.indirect_levels >= DX_MAX_INDIRECT_LEVELS {
+				log_warning!("{}: Directory index full", fs.vol.name());
+				return Err(vfs::Error::OutOfSpace);
+			}
+			let (blk, _) = crate::dir::append_block(ih)?;
+			let root = &mut self.frames[0];
+			let node = Frame {
+				blk: blk,
+				limit: node_limit,
+				entries: ::core::mem::replace(&mut root.entries, vec![ (0, blk) ]),
+				pos: root.pos,
+				dirty: true,
+				};
+			root.pos = 0;
+			root.dirty = true;
+			self.indirect_levels += 1;
+			self.frames.insert(1, node);
+			k = 2;
+		}
+		// Split full nodes from the top down, so there's always room in the parent
+		for j in k .. self.frames.len()
+		{
+			let (blk, _) = crate::dir::append_block(ih)?;
+			let half = self.frames[j].entries.len() / 2;
+			let mut new = Frame {
+				blk: blk,
+				limit: node_limit,
+				entries: self.frames[j].entries.split_off(half),
+				pos: 0,
+				dirty: true,
+				};
+			self.frames[j].dirty = true;
+			let parent = &mut self.frames[j-1];
+			parent.entries.insert(parent.pos + 1, (new.entries[0].0, blk));
+			parent.dirty = true;
+			// Keep the half that the path goes through, and write the other
+			if self.frames[j].pos >= half {
+				new.pos = self.frames[j].pos - half;
+				self.frames[j-1].pos += 1;
+				::core::mem::swap(&mut self.frames[j], &mut new);
+			}
+			self.write_frame(&*ih, &new, false)?;
+		}
+		Ok( () )
+	}
+
+	/// Add a new leaf (after the current one) covering hashes from `hash` (with the bottom bit set for a continuation)
+	pub fn insert_leaf(&mut self, hash: u32, blk: u32)
+	{
+		let f = self.frames.last_mut().unwrap();
+		assert!( !f.is_full() );
+		f.entries.insert(f.pos + 1, (hash, blk));
+		f.dirty = true;
+	}
+
+	/// Write modified index blocks back to the disk
+	pub fn write(&mut self, ih: &dyn InodeHandleTrait) -> vfs::Result<()>
+	{
+		for (i, f) in self.frames.iter().enumerate()
+		{
+			if f.dirty {
+				self.write_frame(ih, f, i == 0)?;
+			}
+		}
+		for f in self.frames.iter_mut() {
+			f.dirty = false;
+		}
+		Ok( () )
+	}
+	fn write_frame(&self, ih: &dyn InodeHandleTrait, f: &Frame, is_root: bool) -> vfs::Result<()>
+	{
+		let fs = ih.fs();
+		let vol_blk = ih.get_block_addr(f.blk)?;
+		let csum_seed = ih.csum_seed();
+		fs.edit_block(vol_blk, |d| {
+			let ofs = if is_root {
+					d[DX_ROOT_INFO_OFS+6] = self.indirect_levels;
+					self.root_entries_ofs
+				}
+				else {
+					// Fake (empty) directory entry covering the whole block
+					for b in d.iter_mut() {
+						*b = 0;
+					}
+					let len = d.len() as u16;
+					d[4..6].copy_from_slice( &len.to_le_bytes() );
+					DX_NODE_ENTRIES_OFS
+				};
+			d[ofs+0..][..2].copy_from_slice( &(f.limit as u16).to_le_bytes() );
+			d[ofs+2..][..2].copy_from_slice( &(f.entries.len() as u16).to_le_bytes() );
+			for (i, &(hash, blk)) in f.entries.iter().enumerate()
+			{
+				let e = ofs + i * DX_ENTRY_SIZE;
+				if i > 0 {
+					d[e..][..4].copy_from_slice( &hash.to_le_bytes() );
+				}
+				d[e+4..][..4].copy_from_slice( &blk.to_le_bytes() );
+			}
+			if let Some(seed) = csum_seed {
+				set_dx_csum(seed, d, ofs);
+			}
+			Ok( () )
+			})
+	}
+}
+
+/// Update the checksum of an index root, after its `.` or `..` entries have been changed
+pub fn update_root_csum(csum_seed: Option<u32>, d: &mut [u8])
+{
+	if let Some(seed) = csum_seed {
+		let ofs = DX_ROOT_INFO_OFS + d[DX_ROOT_INFO_OFS+5] as usize;
+		set_dx_csum(seed, d, ofs);
+	}
+}
+/// Set the checksum in the `dx_tail` of an index block with entries at `ofs`
+///
+/// The checksum covers the block up to the last used entry, then the tail (with the checksum field zeroed).
+fn set_dx_csum(seed: u32, d: &mut [u8], ofs: usize)
+{
+	if ofs + 4 > d.len() {
+		return ;
+	}
+	let limit = read_u16(d, ofs) as usize;
+	let count = read_u16(d, ofs+2) as usize;
+	let tail = ofs + limit * DX_ENTRY_SIZE;
+	if count > limit || tail + DX_TAIL_SIZE > d.len() {
+		// No room for a tail (the index was created without checksums)
+		log_warning!("HTree block has no space for a checksum (limit={})", limit);
+		return ;
+	}
+	let csum = ::csum::crc32c(seed, &d[..ofs + count * DX_ENTRY_SIZE]);
+	let csum = ::csum::crc32c(csum, &d[tail..][..4]);
+	let csum = ::csum::crc32c(csum, &[0; 4]);
+	d[tail+4..][..4].copy_from_slice( &csum.to_le_bytes() );
+}
@@ -35,6 +35,14 @@ impl Drop for Inode
 {
 	fn drop(&mut self)
 	{
+		let (links, mode) = { let od = self.on_disk.read(); (od.i_links_count, od.i_mode) };
+		if links == 0 && mode != 0 && !self.fs.is_readonly() {
+			// Last reference to an unlinked inode, release it
+			if let Err(e) = self.release() {
+				log_error!("Inode::drop - Failed to release I{}: {:?}", self.inode_idx, e);
+			}
+			return ;
+		}
 		if self.is_dirty.load(Ordering::Relaxed) {
 			log_warning!("Inode::drop - Dirty node being dropped, writing back and ignoring errors");
 			let _ = self.flush();
@@ -54,17 +62,83 @@ impl Inode
 		Ok( () )
 	}
 
+	/// Free all blocks and the inode itself
+	fn release(&self) -> vfs::Result<()>
+	{
+		log_debug!("Releasing I{}", self.inode_idx);
+		let _txn = self.fs.start_transaction();
+		let is_dir = {
+			let mut lh = self.lock_write();
+			lh.free_blocks_from(0)?;
+			// `free_inode` clears the on-disk inode, so don't write it back
+			self.is_dirty.store(false, Ordering::Relaxed);
+			lh.i_mode_fmt() == ::ondisk::S_IFDIR
+			};
+		self.fs.free_inode(self.inode_idx, is_dir)
+	}
+
+	/// Remove a name referring to this inode (it's freed when the last reference is dropped)
+	///
+	/// For directories this is a sub-directory's `..` link, which never takes the count below 2 (or changes a count
+	/// of 1, see `inc_link_count`). Use `clear_link_count` when removing the directory itself.
 	pub fn dec_link_count(&self) {
-		todo!("Inode::dec_link_count");
+		let mut lh = self.lock_write();
+		let is_dir = lh.lock.i_mode_fmt() == ::ondisk::S_IFDIR;
+		match lh.lock.i_links_count
+		{
+		0 => log_warning!("Inode::dec_link_count - I{} already has no links", self.inode_idx),
+		1 ..= 2 if is_dir => {},
+		v => {
+			lh.lock.i_links_count = v - 1;
+			self.is_dirty.store(true, Ordering::Relaxed);
+			},
+		}
+	}
+	/// Add a name referring to this inode
+	///
+	/// With FEAT_RO_COMPAT_DIR_NLINK, a directory with too many sub-directories to count has a link count of 1 (which
+	/// then stays at 1)
+	pub fn inc_link_count(&self) -> vfs::Result<()> {
+		let mut lh = self.lock_write();
+		let uncounted = lh.lock.i_mode_fmt() == ::ondisk::S_IFDIR && self.fs.has_feature_ro_compat(::ondisk::FEAT_RO_COMPAT_DIR_NLINK);
+		lh.lock.i_links_count = match lh.lock.i_links_count
+			{
+			1 if uncounted => 1,
+			v if v < ::ondisk::EXT4_LINK_MAX => v + 1,
+			_ if uncounted => 1,
+			_ => return Err(vfs::Error::Unknown("Too many links")),
+			};
+		self.is_dirty.store(true, Ordering::Relaxed);
+		Ok( () )
 	}
-	pub fn inc_link_count(&self) {
-		todo!("Inode::inc_link_count");
+	/// Remove all names referring to this inode (used when a directory is unlinked)
+	pub fn clear_link_count(&self) {
+		let mut lh = self.lock_write();
+		lh.lock.i_links_count = 0;
+		self.is_dirty.store(true, Ordering::Relaxed);
 	}
 
 	/// Obtain the inode ID
 	pub fn get_id(&self) -> vfs::node::InodeId {
 		self.inode_idx as vfs::node::InodeId
 	}
+	/// Obtain the VFS metadata for this inode
+	pub fn get_metadata(&self) -> vfs::node::Metadata {
+		let od = self.on_disk.read();
+		// Linux stores the high 16 bits of the uid/gid in `osd2`
+		let (uid_high, gid_high) = (od._osd2[1] & 0xFFFF, od._osd2[1] >> 16);
+		vfs::node::Metadata {
+			size: od.i_size(&self.fs),
+			link_count: od.i_links_count as u32,
+			mode: od.i_mode & !::ondisk::S_IFMT,
+			owner: od.i_uid as u32 | uid_high << 16,
+			group: od.i_gid as u32 | gid_high << 16,
+			// NOTE: `i_ctime` is the inode change time, creation time is `i_crtime` in the (unloaded) extra fields
+			created: 0,
+			modified: od.i_mtime as vfs::node::Timestamp,
+			accessed: od.i_atime as vfs::node::Timestamp,
+			}
+	}
 	/// Obtain the node contents consistency lock (used for directories)
 	pub fn lock_dir(&self) -> ::kernel::sync::mutex::HeldMutex<'_, ()> {
 		self.dir_lock.lock()
@@ -145,10 +219,18 @@ common_methods! {
 	pub fn i_size(&self) -> u64 {
 		self.lock.i_size(&self.parent.fs)
 	}
-	pub fn get_extent_from_block(&self, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)> {
+	pub fn i_flags(&self) -> u32 {
+		self.lock.i_flags
+	}
+	/// Seed for the checksums of this inode's blocks (None if metadata checksums are disabled)
+	pub fn csum_seed(&self) -> Option<u32> {
+		self.parent.fs.inode_csum_seed(self.parent.inode_idx, self.lock.i_version)
+	}
+	/// Get a run of contiguous blocks starting at `block_idx`, a zero address indicates a sparse run
+	pub fn get_extent_from_block(&self, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u64, u32)> {
 		self.lock.get_extent_from_block(&self.parent.fs, block_idx, max_blocks)
 	}
-	pub fn get_block_addr(&self, block_idx: u32) -> vfs::node::Result<u32> {
+	pub fn get_block_addr(&self, block_idx: u32) -> vfs::node::Result<u64> {
 		self.lock.get_block_addr(&self.parent.fs, block_idx)
 	}
 	/// The maximum number of blocks needed to contain the file size
@@ -172,10 +254,46 @@ common_methods! {
 }
 impl<'a> InodeHandleWrite<'a> {
 	pub fn set_i_size(&mut self, new_size: u64) -> vfs::node::Result<()> {
+		self.parent.is_dirty.store(true, Ordering::Relaxed);
 		self.lock.set_i_size(&self.parent.fs, new_size)
 	}
+	/// Release all blocks from `block_idx` onwards (the size isn't changed)
+	pub fn free_blocks_from(&mut self, block_idx: u32) -> vfs::node::Result<()> {
+		// Fast symlinks store the target in `i_block`
+		if self.lock.i_mode_fmt() == ::ondisk::S_IFLNK && self.i_size() < 60 {
+			return Ok( () );
+		}
+		self.parent.is_dirty.store(true, Ordering::Relaxed);
+		let freed = self.lock.free_blocks_from(&self.parent.fs, self.parent.inode_idx, block_idx)?;
+		self.lock.sub_i_blocks(&self.parent.fs, freed);
+		Ok( () )
+	}
+	pub fn clear_i_flags(&mut self, flags: u32) {
+		self.parent.is_dirty.store(true, Ordering::Relaxed);
+		self.lock.i_flags &= !flags;
+	}
 	pub fn ensure_blocks_allocated(&mut self, block_idx: u32, num_blocks: u32) -> vfs::node::Result<()> {
-		self.lock.ensure_blocks_allocated(&self.parent.fs, self.parent.inode_idx, block_idx, num_blocks)
+		self.parent.is_dirty.store(true, Ordering::Relaxed);
+		if self.lock.i_flags & crate::ondisk::EXT4_EXTENTS_FL != 0 {
+			self.lock.ensure_extents_allocated(&self.parent.fs, self.parent.inode_idx, block_idx, num_blocks)
+		}
+		else {
+			self.lock.ensure_blocks_allocated(&self.parent.fs, self.parent.inode_idx, block_idx, num_blocks)
+		}
+	}
+}
+impl<'a> Drop for InodeHandleWrite<'a>
+{
+	fn drop(&mut self)
+	{
+		// Write changes through to the (cached) inode table
+		if self.parent.is_dirty.swap(false, Ordering::Relaxed)
+		{
+			if let Err(e) = self.parent.fs.write_inode(self.parent.inode_idx, &self.lock) {
+				log_error!("Failed to write back inode I{}: {:?}", self.parent.inode_idx, e);
+				self.parent.is_dirty.store(true, Ordering::Relaxed);
+			}
+		}
 	}
 }
 
@@ -211,7 +329,7 @@ impl crate::ondisk::Inode
 		Ok( () )
 	}
 
-	fn max_blocks(&self, fs: &InstanceInner) -> u32 {
+	pub fn max_blocks(&self, fs: &InstanceInner) -> u32 {
 		let n_blocks = (self.i_size(fs) + fs.fs_block_size as u64 - 1) / fs.fs_block_size as u64;
 		if n_blocks > ::core::u32::MAX as u64 {
 			::core::u32::MAX
@@ -254,7 +372,21 @@ impl crate::ondisk::Inode
 			panic!("");
 		}
 	}
-	fn get_extent_from_block(&self, fs: &InstanceInner, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)>
+	pub fn get_extent_from_block(&self, fs: &InstanceInner, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u64, u32)>
+	{
+		if self.i_flags & crate::ondisk::EXT4_EXTENTS_FL != 0 {
+			return Ok(match ::extents::lookup(fs, &self.i_block, block_idx)?
+				{
+				// Unwritten extents read as zero, so are reported as sparse
+				::extents::Mapping::Mapped { unwritten: true, count, .. } => (0, count.min(max_blocks)),
+				::extents::Mapping::Mapped { start, count, .. } => (start, count.min(max_blocks)),
+				::extents::Mapping::Hole { count } => (0, count.min(max_blocks)),
+				});
+		}
+		let (fs_start, num) = self.get_indirect_extent_from_block(fs, block_idx, max_blocks)?;
+		Ok( (fs_start as u64, num) )
+	}
+	fn get_indirect_extent_from_block(&self, fs: &InstanceInner, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)>
 	{
 		match Self::get_block_addr_extent(fs, block_idx, max_blocks)
 		{
@@ -269,7 +401,7 @@ impl crate::ondisk::Inode
 			},
 		(BlockAddrs::Single { idx }, max_blocks) => {
 			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
-			let si_block = try!( fs.get_block( self.i_block[SI_BLOCK] ) );
+			let si_block = try!( fs.get_block( self.i_block[SI_BLOCK] as u64 ) );
 			
 			let fs_start = si_block[idx];
 			for num in 1 .. max_blocks {
@@ -280,8 +412,8 @@ impl crate::ondisk::Inode
 			Ok( (fs_start, max_blocks) )
 			},
 		(BlockAddrs::Double { blk, idx }, max_blocks) => {
-			let di_block = try!( fs.get_block( self.i_block[DI_BLOCK] ) );
-			let di_block = try!( fs.get_block( di_block[blk] ) );
+			let di_block = try!( fs.get_block( self.i_block[DI_BLOCK] as u64 ) );
+			let di_block = try!( fs.get_block( di_block[blk] as u64 ) );
 
 			let fs_start = di_block[idx as usize];
 			for num in 1 .. max_blocks {
@@ -293,9 +425,9 @@ impl crate::ondisk::Inode
 			},
 		(BlockAddrs::Triple { blk_o, blk_i, idx }, max_blocks) => {
 			// Triple-indirect block
-			let ti_block = try!( fs.get_block( self.i_block[TI_BLOCK] ) );
-			let ti_block = try!( fs.get_block( ti_block[blk_o] ) );
-			let ti_block = try!( fs.get_block( ti_block[blk_i] ) );
+			let ti_block = try!( fs.get_block( self.i_block[TI_BLOCK] as u64 ) );
+			let ti_block = try!( fs.get_block( ti_block[blk_o] as u64 ) );
+			let ti_block = try!( fs.get_block( ti_block[blk_i] as u64 ) );
 
 
 			let fs_start = ti_block[idx as usize];
@@ -309,28 +441,31 @@ impl crate::ondisk::Inode
 		}
 	}
 
-	pub fn get_block_addr(&self, fs: &InstanceInner, block_idx: u32) -> vfs::node::Result<u32>
+	pub fn get_block_addr(&self, fs: &InstanceInner, block_idx: u32) -> vfs::node::Result<u64>
 	{
+		if self.i_flags & crate::ondisk::EXT4_EXTENTS_FL != 0 {
+			return Ok(self.get_extent_from_block(fs, block_idx, 1)?.0);
+		}
 		match Self::get_block_addr_extent(fs, block_idx, 1).0
 		{
 		BlockAddrs::Direct { direct_idx: idx } => {
-			Ok( self.i_block[idx] )
+			Ok( self.i_block[idx] as u64 )
 			},
 		BlockAddrs::Single { idx } => {
 			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
-			let si_block = try!( fs.get_block( self.i_block[SI_BLOCK] ) );
-			Ok( si_block[ idx] )
+			let si_block = try!( fs.get_block( self.i_block[SI_BLOCK] as u64 ) );
+			Ok( si_block[ idx] as u64 )
 			},
 		BlockAddrs::Double { blk, idx } => {
-			let di_block = try!( fs.get_block( self.i_block[DI_BLOCK] ) );
-			let di_block = try!( fs.get_block( di_block[blk] ) );
-			Ok( di_block[idx] )
+			let di_block = try!( fs.get_block( self.i_block[DI_BLOCK] as u64 ) );
+			let di_block = try!( fs.get_block( di_block[blk] as u64 ) );
+			Ok( di_block[idx] as u64 )
 			},
 		BlockAddrs::Triple { blk_o, blk_i, idx } => {
-			let ti_block = try!( fs.get_block( self.i_block[TI_BLOCK] ) );
-			let ti_block = try!( fs.get_block( ti_block[blk_o] ) );
-			let ti_block = try!( fs.get_block( ti_block[blk_i] ) );
-			Ok( ti_block[idx] )
+			let ti_block = try!( fs.get_block( self.i_block[TI_BLOCK] as u64 ) );
+			let ti_block = try!( fs.get_block( ti_block[blk_o] as u64 ) );
+			let ti_block = try!( fs.get_block( ti_block[blk_i] as u64 ) );
+			Ok( ti_block[idx] as u64 )
 			},
 		}
 	}
@@ -348,19 +483,247 @@ impl crate::ondisk::Inode
 			match addrs
 			{
 			BlockAddrs::Direct { direct_idx } => {
-				for s in &mut self.i_block[direct_idx..][.. span_count as usize] {
-					if *s == 0 {
-						*s = fs.allocate_data_block(inode_num, prev_block)?;
+				for i in direct_idx .. direct_idx + span_count as usize {
+					if self.i_block[i] == 0 {
+						// NOTE: Without FEAT_INCOMPAT_64BIT (required for blocks above 2^32) extents are used
+						self.i_block[i] = fs.allocate_data_block(inode_num, prev_block)? as u32;
+						self.add_i_blocks(fs, 1);
 					}
-					prev_block = *s;
+					prev_block = self.i_block[i] as u64;
 				}
 				},
-			// TODO: Indirect requires editing the result of `get_block`
-			_ => todo!("ensure_blocks_allocated - indirect"), 
+			BlockAddrs::Single { idx } => {
+				let si_block = self.ensure_indirect_root(fs, inode_num, SI_BLOCK, prev_block)?;
+				prev_block = self.ensure_indirect_run(fs, inode_num, si_block, idx, span_count as usize, prev_block)?;
+				},
+			BlockAddrs::Double { blk, idx } => {
+				let di_block = self.ensure_indirect_root(fs, inode_num, DI_BLOCK, prev_block)?;
+				let di_block = self.ensure_indirect_entry(fs, inode_num, di_block, blk, prev_block)?;
+				prev_block = self.ensure_indirect_run(fs, inode_num, di_block, idx, span_count as usize, prev_block)?;
+				},
+			BlockAddrs::Triple { blk_o, blk_i, idx } => {
+				let ti_block = self.ensure_indirect_root(fs, inode_num, TI_BLOCK, prev_block)?;
+				let ti_block = self.ensure_indirect_entry(fs, inode_num, ti_block, blk_o, prev_block)?;
+				let ti_block = self.ensure_indirect_entry(fs, inode_num, ti_block, blk_i, prev_block)?;
+				prev_block = self.ensure_indirect_run(fs, inode_num, ti_block, idx, span_count as usize, prev_block)?;
+				},
 			}
 		}
 		Ok( () )
 	}
+	/// Allocate a zeroed indirect block
+	fn allocate_indirect(&mut self, fs: &InstanceInner, inode_num: u32, prev_block: u64) -> vfs::node::Result<u64> {
+		let rv = fs.allocate_data_block(inode_num, prev_block)?;
+		self.add_i_blocks(fs, 1);
+		fs.edit_block(rv, |blk_data| {
+			for b in blk_data.iter_mut() {
+				*b = 0;
+			}
+			Ok( () )
+			})?;
+		Ok(rv)
+	}
+	/// Get the indirect block referenced by `i_block[slot]`, allocating it if needed
+	fn ensure_indirect_root(&mut self, fs: &InstanceInner, inode_num: u32, slot: usize, prev_block: u64) -> vfs::node::Result<u64> {
+		if self.i_block[slot] == 0 {
+			self.i_block[slot] = self.allocate_indirect(fs, inode_num, prev_block)? as u32;
+		}
+		Ok( self.i_block[slot] as u64 )
+	}
+	/// Get the indirect block referenced by entry `idx` of indirect block `blk`, allocating it if needed
+	fn ensure_indirect_entry(&mut self, fs: &InstanceInner, inode_num: u32, blk: u64, idx: usize, prev_block: u64) -> vfs::node::Result<u64> {
+		let cur = fs.get_block(blk)?[idx];
+		if cur != 0 {
+			return Ok(cur as u64);
+		}
+		let rv = self.allocate_indirect(fs, inode_num, prev_block)?;
+		fs.edit_block(blk, |blk_data| {
+			blk_data[idx*4..][..4].copy_from_slice( &(rv as u32).to_le_bytes() );
+			Ok( () )
+			})?;
+		Ok(rv)
+	}
+	/// Allocate data blocks for entries `idx .. idx+count` of indirect block `blk`, returning the last block
+	fn ensure_indirect_run(&mut self, fs: &InstanceInner, inode_num: u32, blk: u64, idx: usize, count: usize, mut prev_block: u64) -> vfs::node::Result<u64> {
+		let cur = fs.get_block(blk)?[idx..][..count].to_vec();
+		for (i, &v) in Iterator::zip(idx .., cur.iter())
+		{
+			if v == 0 {
+				let new_block = fs.allocate_data_block(inode_num, prev_block)?;
+				self.add_i_blocks(fs, 1);
+				fs.edit_block(blk, |blk_data| {
+					blk_data[i*4..][..4].copy_from_slice( &(new_block as u32).to_le_bytes() );
+					Ok( () )
+					})?;
+				prev_block = new_block;
+			}
+			else {
+				prev_block = v as u64;
+			}
+		}
+		Ok(prev_block)
+	}
+
+	/// Free all blocks from logical block `first` onwards, returning the number of blocks freed (including tree/indirect blocks)
+	fn free_blocks_from(&mut self, fs: &InstanceInner, inode_num: u32, first: u32) -> vfs::node::Result<u64> {
+		if self.i_flags & crate::ondisk::EXT4_EXTENTS_FL != 0 {
+			return ::extents::truncate(fs, fs.inode_csum_seed(inode_num, self.i_version), &mut self.i_block, first);
+		}
+		let mut freed = 0;
+		for i in (first as usize).min(SI_BLOCK) .. SI_BLOCK {
+			if self.i_block[i] != 0 {
+				fs.free_blocks(self.i_block[i] as u64, 1)?;
+				self.i_block[i] = 0;
+				freed += 1;
+			}
+		}
+		let u32_per_fs_block = (fs.fs_block_size / ::core::mem::size_of::<u32>()) as u64;
+		let mut base = SI_BLOCK as u64;
+		for &(depth, slot) in &[(1, SI_BLOCK), (2, DI_BLOCK), (3, TI_BLOCK)]
+		{
+			let span = u32_per_fs_block.pow(depth);
+			if self.i_block[slot] != 0 && (first as u64) < base + span {
+				let rel = (first as u64).saturating_sub(base);
+				freed += Self::free_indirect(fs, self.i_block[slot], depth, rel)?;
+				if rel == 0 {
+					self.i_block[slot] = 0;
+				}
+			}
+			base += span;
+		}
+		Ok(freed)
+	}
+	/// Free the blocks referenced by indirect block `blk` (`depth` levels above the data) from relative block `first`
+	///
+	/// The indirect block itself is freed if `first` is zero
+	fn free_indirect(fs: &InstanceInner, blk: u32, depth: u32, first: u64) -> vfs::node::Result<u64> {
+		let u32_per_fs_block = (fs.fs_block_size / ::core::mem::size_of::<u32>()) as u64;
+		let span = u32_per_fs_block.pow(depth - 1);
+		let entries = fs.get_block(blk as u64)?.to_vec();
+		let first_ent = (first / span) as usize;
+		let mut freed = 0;
+		// Data blocks are freed in contiguous runs
+		let mut run: Option<(u64, u64)> = None;
+		for (i, &v) in entries.iter().enumerate().skip(first_ent)
+		{
+			if v == 0 {
+				continue ;
+			}
+			if depth > 1 {
+				freed += Self::free_indirect(fs, v, depth - 1, first.saturating_sub(i as u64 * span))?;
+				continue ;
+			}
+			run = match run
+				{
+				Some((s, c)) if s + c == v as u64 => Some((s, c + 1)),
+				Some((s, c)) => {
+					fs.free_blocks(s, c)?;
+					Some((v as u64, 1))
+					},
+				None => Some((v as u64, 1)),
+				};
+			freed += 1;
+		}
+		if let Some((s, c)) = run {
+			fs.free_blocks(s, c)?;
+		}
+		if first == 0 {
+			fs.free_blocks(blk as u64, 1)?;
+			freed += 1;
+		}
+		else {
+			// Clear the entries that were entirely freed
+			let first_clear = ::kernel::lib::num::div_up(first, span) as usize;
+			fs.edit_block(blk as u64, |blk_data| {
+				for b in blk_data[first_clear*4 ..].iter_mut() {
+					*b = 0;
+				}
+				Ok( () )
+				})?;
+		}
+		Ok(freed)
+	}
+
+	/// Extent-tree version of `ensure_blocks_allocated`
+	fn ensure_extents_allocated(&mut self, fs: &InstanceInner, inode_num: u32, mut block_idx: u32, count: u32) -> vfs::node::Result<()> {
+		let end = block_idx + count;
+		let csum_seed = fs.inode_csum_seed(inode_num, self.i_version);
+		let mut prev_block = 0;
+		while block_idx < end
+		{
+			match ::extents::lookup(fs, &self.i_block, block_idx)?
+			{
+			::extents::Mapping::Mapped { start, count, unwritten } => {
+				if unwritten {
+					// Writing into a preallocated extent, it has to be zeroed first
+					::extents::mark_written(fs, csum_seed, &mut self.i_block, block_idx)?;
+				}
+				let n = count.min(end - block_idx);
+				prev_block = start + n as u64 - 1;
+				block_idx += n;
+				},
+			::extents::Mapping::Hole { count } => {
+				let n = count.min(end - block_idx);
+				// Allocate blocks one at a time, and insert contiguous runs
+				let mut run: Option<(u32, u64, u16)> = None;
+				for i in block_idx .. block_idx + n
+				{
+					let b = fs.allocate_data_block(inode_num, prev_block)?;
+					self.add_i_blocks(fs, 1);
+					prev_block = b;
+					run = match run
+						{
+						Some((l, p, c)) if p + c as u64 == b && c < ::ondisk::EXTENT_INIT_MAX_LEN => Some((l, p, c+1)),
+						Some((l, p, c)) => {
+							let new_tree_blocks = ::extents::insert(fs, inode_num, csum_seed, &mut self.i_block, l, p, c)?;
+							self.add_i_blocks(fs, new_tree_blocks as u64);
+							Some((i, b, 1))
+							},
+						None => Some((i, b, 1)),
+						};
+				}
+				if let Some((l, p, c)) = run {
+					let new_tree_blocks = ::extents::insert(fs, inode_num, csum_seed, &mut self.i_block, l, p, c)?;
+					self.add_i_blocks(fs, new_tree_blocks as u64);
+				}
+				block_idx += n;
+				},
+			}
+		}
+		Ok( () )
+	}
+
+	/// Account for `count` newly allocated filesystem blocks in `i_blocks`
+	fn add_i_blocks(&mut self, fs: &InstanceInner, count: u64) {
+		let (cur, units) = self.i_blocks_units(fs, count);
+		self.set_i_blocks(fs, cur + units);
+	}
+	/// Account for `count` released filesystem blocks in `i_blocks`
+	fn sub_i_blocks(&mut self, fs: &InstanceInner, count: u64) {
+		let (cur, units) = self.i_blocks_units(fs, count);
+		if units > cur {
+			log_warning!("sub_i_blocks: i_blocks underflowed ({} < {})", cur, units);
+		}
+		self.set_i_blocks(fs, cur.saturating_sub(units));
+	}
+	/// Returns the current `i_blocks` value, and `count` converted into the same units
+	fn i_blocks_units(&self, fs: &InstanceInner, count: u64) -> (u64, u64) {
+		let huge = fs.has_feature_ro_compat(crate::ondisk::FEAT_RO_COMPAT_HUGE_FILE);
+		let cur = self.i_blocks as u64 | if huge { ((self._osd2[0] & 0xFFFF) as u64) << 32 } else { 0 };
+		// `i_blocks` is in 512 byte units, unless the inode is flagged as huge
+		let units = if huge && self.i_flags & crate::ondisk::EXT4_HUGE_FILE_FL != 0 { count } else { count * (fs.fs_block_size / 512) as u64 };
+		(cur, units)
+	}
+	fn set_i_blocks(&mut self, fs: &InstanceInner, new: u64) {
+		let huge = fs.has_feature_ro_compat(crate::ondisk::FEAT_RO_COMPAT_HUGE_FILE);
+		self.i_blocks = new as u32;
+		if huge {
+			self._osd2[0] = (self._osd2[0] & !0xFFFF) | ((new >> 32) & 0xFFFF) as u32;
+		}
+		else if new > u32::MAX as u64 {
+			log_warning!("set_i_blocks: i_blocks overflowed");
+		}
+	}
 }
 
 /// Iterator over block numbers owned by an inode
@@ -372,11 +735,11 @@ pub struct Blocks<'a>
 }
 impl<'a> Blocks<'a>
 {
-	pub fn next_or_err(&mut self) -> ::vfs::Result<u32> {
+	pub fn next_or_err(&mut self) -> ::vfs::Result<u64> {
 		self.next().ok_or( ::vfs::Error::Unknown("Unexpected end of block list") )
 	}
 
-	pub fn next_extent_or_err(&mut self, max: u32) -> ::vfs::Result<(u32, u32)> {
+	pub fn next_extent_or_err(&mut self, max: u32) -> ::vfs::Result<(u64, u32)> {
 		let max_blocks = self.ondisk.max_blocks(self.fs);
 		if self.inner_idx >= max_blocks {
 			Err( ::vfs::Error::Unknown("Unexpected end of block list") )
@@ -392,8 +755,8 @@ impl<'a> Blocks<'a>
 }
 impl<'a> Iterator for Blocks<'a>
 {
-	type Item = u32;
-	fn next(&mut self) -> Option<u32>
+	type Item = u64;
+	fn next(&mut self) -> Option<u64>
 	{
 		let max_blocks = self.ondisk.max_blocks(self.fs);
 		if self.inner_idx >= max_blocks {
@@ -7,19 +7,31 @@ use kernel::prelude::*;
 use ::vfs::{self, node};
 use kernel::metadevs::storage::VolumeHandle;
 use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
+use core::sync::atomic::{AtomicBool,Ordering};
 
 pub struct Instance(ArefInner<InstanceInner>);
 pub type InstancePtr = ArefBorrow<InstanceInner>;
 
 pub struct InstanceInner
 {
-	is_readonly: bool,
+	/// Volume uses features that can only be read
+	features_readonly: bool,
+	/// Either `features_readonly` or mounted with `ro`
+	is_readonly: AtomicBool,
 	pub vol: ::block_cache::CachedVolume,
 	superblock: ::kernel::sync::RwLock<crate::ondisk::Superblock>,
 	pub fs_block_size: usize,
 
 	mount_handle: vfs::mount::SelfHandle,
 	group_descriptors: ::kernel::sync::RwLock< Vec<::ondisk::GroupDesc> >,
+	/// Byte offset of the group descriptor table within the volume
+	group_desc_ofs: u64,
+	/// Size of a single group descriptor (32, or larger with FEAT_INCOMPAT_64BIT)
+	group_desc_size: usize,
+	/// Journal (if FEAT_COMPAT_HAS_JOURNAL is set)
+	journal: Option<::journal::Journal>,
+	/// Seed for metadata checksums (if FEAT_RO_COMPAT_METADATA_CSUM is set)
+	csum_seed: Option<u32>,
 }
 
 pub enum FeatureState
@@ -52,6 +64,11 @@ impl Instance
 				log_warning!("Volume `{}` uses incompatible read-write features (unsupported bits {:#x})", vol_name, unsupported_rdo);
 				FeatureState::ReadOnly( unsupported_rdo )
 			}
+			else if sb.has_feature_ro_compat(::ondisk::FEAT_RO_COMPAT_METADATA_CSUM) && sb.ext.s_checksum_type != ::ondisk::EXT4_CRC32C_CHKSUM {
+				// Checksums can't be generated
+				log_warning!("Volume `{}` uses an unknown metadata checksum type {}", vol_name, sb.ext.s_checksum_type);
+				FeatureState::ReadOnly( ::ondisk::FEAT_RO_COMPAT_METADATA_CSUM )
+			}
 			else if unsupported_opt != 0 {
 				// Can read and write, but may confuse other systems
 				log_warning!("Volume `{}` uses incompatible optional features (unsupported bits {:#x})", vol_name, unsupported_opt);
@@ -64,30 +81,18 @@ impl Instance
 		}
 	}
 
-	pub fn new_boxed(vol: VolumeHandle, mount_handle: vfs::mount::SelfHandle) -> vfs::Result<Box<Instance>>
+	pub fn new_boxed(vol: VolumeHandle, mount_handle: vfs::mount::SelfHandle, options: &vfs::mount::MountOptions) -> vfs::Result<Box<Instance>>
 	{
 		let vol_bs = vol.block_size();
+		let vol = ::block_cache::CachedVolume::new(vol);
 
-		// The superblock exists at offset 1024 in the volume, no matter the on-disk block size
-		let superblock_idx = (1024 / vol_bs) as u64;
-		let superblock_ofs = (1024 % vol_bs) as usize;
-
-		let (superblock, first_block) = {
-			let mut first_block: Vec<u8> = vec![0; ::core::cmp::max(1024, vol_bs)];
-			::kernel::futures::block_on(vol.read_blocks(superblock_idx, &mut first_block[..]))?;
-			assert!(superblock_ofs % 4 == 0);
-			(
-				::ondisk::Superblock::from_slice(&first_block[superblock_ofs ..][..1024]),
-				first_block,
-				)
-			};
-
+		let superblock = Self::read_superblock(&vol)?;
 		if superblock.data.s_magic != 0xEF53 {
 			return Err(vfs::Error::TypeMismatch);
 		}
 		log_debug!("superblock = {:x?}", superblock);
 
-		let is_readonly = match Self::check_features(vol.name(), &superblock)
+		let features_readonly = match Self::check_features(vol.name(), &superblock)
 			{
 			FeatureState::Incompatible(_) => return Err(vfs::Error::TypeMismatch),
 			FeatureState::ReadOnly(_) => true,
@@ -104,104 +109,113 @@ impl Instance
 			log_warning!("ExtN TODO: Handle filesystem block size smaller than disk block size?");
 			return Err(vfs::Error::InconsistentFilesystem);
 		}
-		let num_groups = ::kernel::lib::num::div_up(superblock.data.s_blocks_count, superblock.data.s_blocks_per_group);
 
 		// Read group descriptor table
-		// - This resides in the first FS block after the superblock
-		let group_descs = {
-			const GROUP_DESC_SIZE: usize = ::core::mem::size_of::<::ondisk::GroupDesc>();
-			if GROUP_DESC_SIZE != superblock.s_group_desc_size() {
-				return Err(vfs::Error::Unknown("Superblock size mismatch vs expected"));
-			}
-
-			let groups_per_vol_block = vol_bs / GROUP_DESC_SIZE;
-			// Group descriptors are in the first filesystem block after the superblock
-			// - So either immediately right after the superblock, or the second block (whichever is larger)
-			let byte_offset = usize::max(2*1024, fs_block_size);
-			log_trace!("Group Descs: {} groups @ byte {}, {} per volume block (vol_bs={})",
-				num_groups, byte_offset, groups_per_vol_block, vol_bs);
-
-			let mut gds: Vec<::ondisk::GroupDesc> = (0..num_groups).map(|_| Default::default()).collect();
-
-			// The superblock is 1024 bytes at offset 1024
-			// - If the volume block size is 2K or larger, then there are some group descriptors in the first block
-			let (n_skip, mut vol_block) = if vol_bs > byte_offset {
-					// Volume block size is larger than the offset
-					// - This means that at least 2048 bytes of the group descriptors are in the same block as the superblock
-					let n_shared = (vol_bs - byte_offset) / GROUP_DESC_SIZE;
-
-					let mut src = &first_block[byte_offset..];
-					let count = ::core::cmp::min(n_shared, gds.len());
-					assert_eq!(src.len(), count * GROUP_DESC_SIZE);
-					for s in &mut gds[..count] {
-						*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-						log_debug!("GROUP DESC: {:?}", s);
-					}
-					(count, 1)
-				}
-				else {
-					// Volume BS <= superblock
-					// - Offset of byte 2048 in the disk
-					(0, (byte_offset / vol_bs) as u64)
-				};
-
-			// Determine how many descriptors are in the subsequent volume blocks
-			let rem_count = gds.len() - n_skip;
-			let tail_count = rem_count % groups_per_vol_block;
-			let body_count = rem_count - tail_count;
-			log_trace!("vol_block={} n_skip={} => rem_count={} (tail_count={}, body_count={})",
-				vol_block, n_skip, rem_count,  tail_count, body_count);
-
-			let mut buf: Vec<u8> = vec![0; vol_bs];
-			if body_count > 0
-			{
-				for gds in gds[n_skip..][..body_count].chunks_mut(groups_per_vol_block) {
-					::kernel::futures::block_on(vol.read_blocks(vol_block, &mut buf))?;
-					let mut src = &buf[..];
-					for s in gds {
-						*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-						log_debug!("GROUP DESC: {:?}", s);
-					}
-					vol_block += 1;
-				}
-			}
+		// - This resides in the first FS block after the superblock (which is either block 0 or 1)
+		let group_desc_size = superblock.s_group_desc_size();
+		if group_desc_size % 32 != 0 || vol_bs % group_desc_size != 0 {
+			log_warning!("{}: Unsupported group descriptor size {}", vol.name(), group_desc_size);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let group_desc_ofs = (superblock.data.s_first_data_block as u64 + 1) * fs_block_size as u64;
+		let group_descs = Self::read_group_descs(&vol, &superblock, group_desc_ofs)?;
 
-			if tail_count > 0
-			{
-				let ofs = n_skip + body_count;
-				// Read a single volume block into a buffer, then populate from that
-				::kernel::futures::block_on(vol.read_blocks(vol_block, &mut buf))?;
-				let n_bytes = (gds.len() - ofs) * GROUP_DESC_SIZE;
-				let mut src = &buf[..n_bytes];
-				for s in &mut gds[ofs..] {
-					*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-					log_debug!("GROUP DESC: {:?}", s);
+		let csum_seed = if superblock.has_feature_ro_compat(::ondisk::FEAT_RO_COMPAT_METADATA_CSUM) {
+				Some(if superblock.has_feature_incompat(::ondisk::FEAT_INCOMPAT_CSUM_SEED) {
+					superblock.ext.s_checksum_seed
 				}
+				else {
+					::csum::crc32c(!0, &superblock.ext.s_uuid)
+				})
 			}
-
-			gds
+			else {
+				None
 			};
 
-
-		for (i, gd) in group_descs.iter().enumerate()
-		{
-			log_debug!("{}: Group #{}: {:?}", vol.name(), i, gd);
-		}
-
-		let inner = InstanceInner {
-			is_readonly: is_readonly,
+		let mut inner = InstanceInner {
+			features_readonly: features_readonly,
+			is_readonly: AtomicBool::new(features_readonly || options.readonly),
 			fs_block_size: fs_block_size,
 			superblock: ::kernel::sync::RwLock::new(superblock),
 			group_descriptors: ::kernel::sync::RwLock::new(group_descs),
+			group_desc_ofs: group_desc_ofs,
+			group_desc_size: group_desc_size,
 			mount_handle: mount_handle,
-			vol: ::block_cache::CachedVolume::new(vol),
+			vol: vol,
+			journal: None,
+			csum_seed: csum_seed,
 			};
 
+		// Replay the journal (even if mounting read-only, as the metadata can't be trusted otherwise)
+		if inner.superblock.read().ext.s_feature_compat & ::ondisk::FEAT_COMPAT_HAS_JOURNAL != 0
+		{
+			let journal_inum = inner.superblock.read().ext.s_journal_inum;
+			let journal = ::journal::Journal::load(&inner, journal_inum)?;
+			if journal.replay(&inner)? {
+				let superblock = Self::read_superblock(&inner.vol)?;
+				*inner.group_descriptors.write() = Self::read_group_descs(&inner.vol, &superblock, group_desc_ofs)?;
+				*inner.superblock.write() = superblock;
+			}
+			if !journal.is_writable() {
+				inner.features_readonly = true;
+				inner.is_readonly.store(true, Ordering::Relaxed);
+			}
+			inner.journal = Some(journal);
+		}
+		inner.set_needs_recovery( inner.journal.is_some() && !inner.is_readonly() )?;
+
 		// SAFE: Boxed instantly
 		unsafe {
 			Ok(Box::new(Instance(ArefInner::new( inner ))))
 		}
 	}
+
+	fn read_superblock(vol: &::block_cache::CachedVolume) -> vfs::Result<::ondisk::Superblock>
+	{
+		let vol_bs = vol.block_size();
+		// The superblock exists at offset 1024 in the volume, no matter the on-disk block size
+		let superblock_idx = (1024 / vol_bs) as u64;
+		let superblock_ofs = (1024 % vol_bs) as usize;
+
+		let mut first_block: Vec<u8> = vec![0; ::core::cmp::max(1024, vol_bs)];
+		::kernel::futures::block_on(vol.read_blocks(superblock_idx, &mut first_block[..]))?;
+		assert!(superblock_ofs % 4 == 0);
+		let raw = &first_block[superblock_ofs ..][..1024];
+		let rv = ::ondisk::Superblock::from_slice(raw);
+		if rv.data.s_magic == 0xEF53 && rv.has_feature_ro_compat(::ondisk::FEAT_RO_COMPAT_METADATA_CSUM) {
+			let csum = ::csum::crc32c(!0, &raw[..::ondisk::S_CHECKSUM_OFS]);
+			if csum != rv.s_checksum {
+				log_error!("{}: Superblock checksum mismatch ({:#x} != {:#x})", vol.name(), csum, rv.s_checksum);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+		}
+		Ok( rv )
+	}
+
+	fn read_group_descs(vol: &::block_cache::CachedVolume, superblock: &::ondisk::Superblock, group_desc_ofs: u64) -> vfs::Result<Vec<::ondisk::GroupDesc>>
+	{
+		let vol_bs = vol.block_size();
+		let first_data_block = superblock.data.s_first_data_block as u64;
+		let num_groups = ::kernel::lib::num::div_up(superblock.s_blocks_count() - first_data_block, superblock.data.s_blocks_per_group as u64) as usize;
+		let group_desc_size = superblock.s_group_desc_size();
+
+		let table_len = num_groups * group_desc_size;
+		let (first_vol_block, skip) = (group_desc_ofs / vol_bs as u64, (group_desc_ofs % vol_bs as u64) as usize);
+		log_trace!("Group Descs: {} groups @ byte {} ({} bytes each, vol_bs={})",
+			num_groups, group_desc_ofs, group_desc_size, vol_bs);
+
+		let mut buf: Vec<u8> = vec![0; ::kernel::lib::num::div_up(skip + table_len, vol_bs) * vol_bs];
+		::kernel::futures::block_on(vol.read_blocks(first_vol_block, &mut buf))?;
+		let rv = buf[skip..][..table_len].chunks(group_desc_size)
+			.map(|d| ::ondisk::GroupDesc::from_slice(d))
+			.collect::<Vec<_>>();
+
+		for (i, gd) in rv.iter().enumerate()
+		{
+			log_debug!("{}: Group #{}: {:?}", vol.name(), i, gd);
+		}
+		Ok(rv)
+	}
 }
 
 impl vfs::mount::Filesystem for Instance
@@ -234,13 +248,28 @@ impl vfs::mount::Filesystem for Instance
 			},
 		}
 	}
+	fn flush(&self) -> vfs::Result<()> {
+		::kernel::futures::block_on(self.0.vol.flush())?;
+		// Everything is on disk, so the journal doesn't need to be checked if we don't come back
+		self.0.set_needs_recovery(false)?;
+		Ok( () )
+	}
+	fn remount(&self, options: &vfs::mount::MountOptions) -> vfs::Result<()> {
+		if !options.readonly && self.0.features_readonly {
+			log_notice!("{}: Can't remount read-write, volume uses unsupported features", self.0.vol.name());
+			return Err(vfs::Error::ReadOnlyFilesystem);
+		}
+		self.0.is_readonly.store(self.0.features_readonly || options.readonly, Ordering::Relaxed);
+		self.0.set_needs_recovery( self.0.journal.is_some() && !self.0.is_readonly() )?;
+		Ok( () )
+	}
 }
 
 impl InstanceInner
 {
 	pub fn is_readonly(&self) -> bool
 	{
-		self.is_readonly
+		self.is_readonly.load(Ordering::Relaxed)
 	}
 }
 
@@ -260,11 +289,19 @@ impl<'a> ::core::ops::Deref for Block<'a>
 		}
 	}
 }
+impl<'a> Block<'a>
+{
+	/// Obtain the block contents as bytes
+	pub fn bytes(&self) -> &[u8] {
+		let &Block(ref handle, ofs, size) = self;
+		&handle.data()[ofs as usize..][..size as usize]
+	}
+}
 
 impl InstanceInner
 {
 	/// Obtain a block (using the block cache)
-	pub fn get_block(&self, block: u32) -> vfs::node::Result<Block>
+	pub fn get_block(&self, block: u64) -> vfs::node::Result<Block>
 	{
 		if self.fs_block_size > ::kernel::PAGE_SIZE {
 			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
@@ -272,7 +309,7 @@ impl InstanceInner
 			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
 		}
 		log_trace!("get_block({})", block);
-		let sector = block as u64 * self.vol_blocks_per_fs_block();
+		let sector = block * self.vol_blocks_per_fs_block();
 
 		let ch = ::kernel::futures::block_on(self.vol.get_block(sector))?;
 		let ofs = (sector - ch.index()) as usize * self.vol.block_size();
@@ -280,7 +317,7 @@ impl InstanceInner
 	}
 
 	/// Edit a block in the cache using the provided closure
-	pub fn edit_block<F,R>(&self, block: u32, f: F) -> vfs::node::Result<R>
+	pub fn edit_block<F,R>(&self, block: u64, f: F) -> vfs::node::Result<R>
 	where
 		F: FnOnce(&mut [u8]) -> vfs::node::Result<R>
 	{
@@ -290,27 +327,27 @@ impl InstanceInner
 			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
 		}
 		log_trace!("get_block({})", block);
-		let sector = block as u64 * self.vol_blocks_per_fs_block();
+		let sector = block * self.vol_blocks_per_fs_block();
 
-		::kernel::futures::block_on(self.vol.edit(sector, self.vol_blocks_per_fs_block() as usize, |data| {
+		self.edit_meta(sector, self.vol_blocks_per_fs_block() as usize, |data| {
 			f(data)
-			}))?
+			})?
 	}
 
 	#[cfg(false_)]	// TODO
 	/// Read from within a block
-	pub fn read_blocks_inner<F,R>(&self, first_block: u32, ofs: usize, len: usize, f: F) -> vfs::node::Result<R>
+	pub fn read_blocks_inner<F,R>(&self, first_block: u64, ofs: usize, len: usize, f: F) -> vfs::node::Result<R>
 	where
 		F: FnOnce(&[u8]) -> vfs::node::Result<R>
 	{
-		let sector = first_block as u64 * self.vol_blocks_per_fs_block();
+		let sector = first_block * self.vol_blocks_per_fs_block();
 		todo!("");
 	}
 	/// Obtain a block (uncached)
 	///
 	/// This is the more expensive version of `get_block`, which doesn't directly touch the block cache.
 	/// It's used to handle partial file reads (which should be cached by higher layers)
-	pub fn get_block_uncached(&self, block: u32) -> vfs::node::Result<Box<[u8]>>
+	pub fn get_block_uncached(&self, block: u64) -> vfs::node::Result<Box<[u8]>>
 	{
 		log_trace!("get_block_uncached({})", block);
 		let mut rv = vec![0; self.fs_block_size].into_boxed_slice();
@@ -319,60 +356,65 @@ impl InstanceInner
 	}
 
 	/// Read a sequence of blocks into a user-provided buffer
-	pub fn read_blocks(&self, first_block: u32, data: &mut [u8]) -> vfs::node::Result<()>
+	pub fn read_blocks(&self, first_block: u64, data: &mut [u8]) -> vfs::node::Result<()>
+	{
+		::kernel::futures::block_on( self.vol.read_blocks_uncached( first_block * self.vol_blocks_per_fs_block(), data) )?;
+		Ok( () )
+	}
+
+	/// Write a sequence of blocks, updating any cached copies (used for journal replay, and file data)
+	pub fn write_blocks_cached(&self, first_block: u64, data: &[u8]) -> vfs::node::Result<()>
+	{
+		::kernel::futures::block_on( self.vol.write_blocks( first_block * self.vol_blocks_per_fs_block(), data) )?;
+		Ok( () )
+	}
+	/// Allow a block held by a journal transaction to be written back to the disk
+	pub fn release_block(&self, block: u64) -> vfs::node::Result<()>
+	{
+		::kernel::futures::block_on( self.vol.release( block * self.vol_blocks_per_fs_block() ) )?;
+		Ok( () )
+	}
+	/// Wait until all previous writes are on stable storage (used to order journal updates)
+	pub fn barrier(&self) -> vfs::node::Result<()>
 	{
-		::kernel::futures::block_on( self.vol.read_blocks_uncached( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
+		::kernel::futures::block_on( self.vol.barrier() )?;
 		Ok( () )
 	}
 
 	/// Write a sequence of blocks from a user-provided buffer
-	pub fn write_blocks(&self, first_block: u32, data: &[u8]) -> vfs::node::Result<()>
+	pub fn write_blocks(&self, first_block: u64, data: &[u8]) -> vfs::node::Result<()>
 	{
 		// TODO: Requires maybe interfacing with the cache used by get_block?
-		::kernel::futures::block_on( self.vol.write_blocks_uncached( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
+		::kernel::futures::block_on( self.vol.write_blocks_uncached( first_block * self.vol_blocks_per_fs_block(), data) )?;
 		Ok( () )
 	}
 }
 
 impl InstanceInner
 {
-	fn get_block_grp_id(&self, block_idx: u32) -> (u32, u32) {
-		let s_blocks_per_group = self.superblock.read().data.s_blocks_per_group;
-		(block_idx / s_blocks_per_group, block_idx % s_blocks_per_group)
+	/// Returns (grp_idx, inner_idx)
+	fn get_block_grp_id(&self, block_idx: u64) -> (u32, u32) {
+		let sb = self.superblock.read();
+		let rel = block_idx - sb.data.s_first_data_block as u64;
+		let s_blocks_per_group = sb.data.s_blocks_per_group as u64;
+		((rel / s_blocks_per_group) as u32, (rel % s_blocks_per_group) as u32)
 	}
 	/// Allocate a new data block
-	pub fn allocate_data_block(&self, inode_num: u32, prev_block: u32) -> vfs::node::Result<u32> {
+	pub fn allocate_data_block(&self, inode_num: u32, prev_block: u64) -> vfs::node::Result<u64> {
 		log_debug!("allocate_data_block(inode_num=I{}, prev_block=B{})", inode_num, prev_block);
 		let has_blocks = self.edit_superblock(|sb| {
-			if sb.has_feature_incompat(crate::ondisk::FEAT_INCOMPAT_64BIT) {
-				if sb.data.s_free_blocks_count == 0 && sb.ext.s_free_blocks_count_hi == 0 {
-					false
-				}
-				else {
-					let (new, overflow) = sb.data.s_free_blocks_count.overflowing_sub(1);
-					if overflow {
-						sb.ext.s_free_blocks_count_hi -= 1;
-					}
-					sb.data.s_free_blocks_count = new;
-					true
-				}
-			}
-			else {
-				if sb.data.s_free_blocks_count == 0 {
-					false
-				}
-				else {
-					sb.data.s_free_blocks_count -= 1;
-					true
-				}
+			match sb.s_free_blocks_count()
+			{
+			0 => false,
+			v => { sb.set_s_free_blocks_count(v - 1); true },
 			}
 			})?;
 		if !has_blocks {
 			return Err(vfs::Error::OutOfSpace);
 		}
+		let inode_bg = self.get_inode_grp_id(inode_num).0;
 		if prev_block != 0 {
 			let (block_bg, _) = self.get_block_grp_id(prev_block);
-			let inode_bg = self.get_inode_grp_id(inode_num).0;
 			// 1. Check witin the same BG (telling it the previous block, so it can pick one near that)
 			if let Some(rv) = self.allocate_block_in_group(block_bg, prev_block)? {
 				return Ok(rv);
@@ -385,18 +427,23 @@ impl InstanceInner
 			}
 		}
 		else {
-			let inode_bg = self.get_inode_grp_id(inode_num).0;
 			if let Some(rv) = self.allocate_block_in_group(inode_bg, 0)? {
 				return Ok(rv);
 			}
 		}
-		// Fallback: Otherwise, first available within the inodes BG
-		// Or, from a random block group
-		todo!("allocate_data_block(inode={}, prev_block={})", inode_num, prev_block);
+		// Fallback: Search the remaining groups, starting after the inode's group
+		// - With FEAT_INCOMPAT_FLEX_BG the first group of a flex group can be entirely metadata
+		let num_groups = self.group_descriptors.read().len() as u32;
+		for ofs in 1 .. num_groups {
+			if let Some(rv) = self.allocate_block_in_group((inode_bg + ofs) % num_groups, 0)? {
+				return Ok(rv);
+			}
+		}
+		log_error!("allocate_data_block: Superblock said that there were free blocks, but no group had any");
+		Err(vfs::Error::InconsistentFilesystem)
 	}
 
-	fn allocate_block_in_group(&self, group: u32, prev_block: u32) -> vfs::node::Result<Option<u32>> {
-		let first_bmp_block = self.group_descriptors.read()[group as usize].bg_block_bitmap;
+	fn allocate_block_in_group(&self, group: u32, prev_block: u64) -> vfs::node::Result<Option<u64>> {
 		// Prefer allocating within a few blocks of the previous (ideally right after) - if non zero
 		if prev_block != 0 {
 			let next_block = prev_block + 1;
@@ -408,7 +455,7 @@ impl InstanceInner
 				let bmp_block = bmp_byte / self.fs_block_size as u32;
 				let bmp_byte = bmp_byte % self.fs_block_size as u32;
 				// If it is, then set it and decrement the (non-zero) free block count
-				if self.edit_block(first_bmp_block + bmp_block, |blk_data| {
+				if self.edit_bitmap(group, Bitmap::Block, bmp_block as u64, |blk_data| {
 					if blk_data[bmp_byte as usize] & bmp_mask == 0 {
 						blk_data[bmp_byte as usize] |= bmp_mask;
 						Ok(true)
@@ -417,7 +464,7 @@ impl InstanceInner
 						Ok(false)
 					}
 					})? {
-					if !self.edit_block_group_header(block_bg, |bg| if bg.bg_free_blocks_count == 0 { false } else { bg.bg_free_blocks_count -= 1; true })? {
+					if !self.edit_block_group_header(block_bg, |bg| match bg.free_blocks_count() { 0 => false, v => { bg.set_free_blocks_count(v - 1); true } })? {
 						return Err(vfs::Error::InconsistentFilesystem);
 					}
 					log_debug!("allocate_block_in_group(): return next B{}", next_block);
@@ -427,19 +474,22 @@ impl InstanceInner
 		}
 
 		// Decrement the block count, and then find an entry
-		if !self.edit_block_group_header(group, |bg| if bg.bg_free_inodes_count == 0 { false } else { bg.bg_free_blocks_count -= 1; true })?
+		if !self.edit_block_group_header(group, |bg| match bg.free_blocks_count() { 0 => false, v => { bg.set_free_blocks_count(v - 1); true } })?
 		{
 			return Ok(None);
 		}
 
 		// Iterate the bitmap
 		let blocks_per_bmpblock = self.fs_block_size * 8;
-		let s_blocks_per_group = self.superblock.read().data.s_blocks_per_group;
+		let (s_blocks_per_group, group_base) = {
+			let sb = self.superblock.read();
+			(sb.data.s_blocks_per_group, sb.data.s_first_data_block as u64 + group as u64 * sb.data.s_blocks_per_group as u64)
+			};
 		for base in (0 .. s_blocks_per_group).step_by(blocks_per_bmpblock) {
 			// Number of inodes in this bitmap block (might be fewer, if the group size is small)
 			let n_blocks = (s_blocks_per_group - base).min(blocks_per_bmpblock as u32);
 			let n_bytes = ::kernel::lib::num::div_up(n_blocks, 8) as usize;
-			let rv = self.edit_block(first_bmp_block + base / blocks_per_bmpblock as u32, |blk_data| {
+			let rv = self.edit_bitmap(group, Bitmap::Block, (base / blocks_per_bmpblock as u32) as u64, |blk_data| {
 				Ok(match blk_data[..n_bytes].iter().position(|&v| v != !0)
 				{
 				None => None,
@@ -455,7 +505,7 @@ impl InstanceInner
 				if rel_block_id >= n_blocks {
 					break
 				}
-				let rv = group * s_blocks_per_group + base + rel_block_id;
+				let rv = group_base + base as u64 + rel_block_id as u64;
 				log_debug!("allocate_block_in_bg({}) Allocate B{}", group, rv);
 				return Ok(Some(rv));
 			}
@@ -463,34 +513,165 @@ impl InstanceInner
 		log_error!("allocate_block_in_group: Descriptor said that there were free blocks, but bitmap was full.");
 		Err(vfs::Error::InconsistentFilesystem)
 	}
+
+	/// Release a run of `count` blocks starting at `first`
+	pub fn free_blocks(&self, mut first: u64, mut count: u64) -> vfs::node::Result<()> {
+		log_debug!("free_blocks(B{}+{})", first, count);
+		let blocks_per_bmpblock = self.fs_block_size as u32 * 8;
+		let s_blocks_per_group = self.superblock.read().data.s_blocks_per_group;
+		while count > 0
+		{
+			// Handle one group at a time
+			let (group, sub) = self.get_block_grp_id(first);
+			let n = count.min( (s_blocks_per_group - sub) as u64 ) as u32;
+			let mut n_freed = 0;
+			let mut bit = sub;
+			while bit < sub + n
+			{
+				let bmp_base = bit / blocks_per_bmpblock * blocks_per_bmpblock;
+				let end = (sub + n).min(bmp_base + blocks_per_bmpblock);
+				n_freed += self.edit_bitmap(group, Bitmap::Block, (bit / blocks_per_bmpblock) as u64, |blk_data| {
+					let mut n_freed = 0;
+					for b in bit .. end {
+						let (byte, mask) = (((b - bmp_base) / 8) as usize, 1 << (b % 8));
+						if blk_data[byte] & mask == 0 {
+							log_warning!("free_blocks: B{} is already free", first + (b - sub) as u64);
+						}
+						else {
+							blk_data[byte] &= !mask;
+							n_freed += 1;
+						}
+					}
+					Ok(n_freed)
+					})?;
+				bit = end;
+			}
+			self.edit_block_group_header(group, |bg| bg.set_free_blocks_count(bg.free_blocks_count() + n_freed))?;
+			self.edit_superblock(|sb| sb.set_s_free_blocks_count(sb.s_free_blocks_count() + n_freed as u64))?;
+			first += n as u64;
+			count -= n as u64;
+		}
+		Ok( () )
+	}
+}
+
+/// Handle to the running journal transaction, commits when the last handle is dropped
+pub struct TransactionHandle<'a>(&'a InstanceInner);
+impl<'a> ::core::ops::Drop for TransactionHandle<'a>
+{
+	fn drop(&mut self)
+	{
+		if let Some(ref j) = self.0.journal
+		{
+			if let Err(e) = j.stop(self.0)
+			{
+				// The on-disk state may not match the cache anymore, stop any further modifications
+				log_error!("{}: Journal commit failed: {:?}, remounting read-only", self.0.vol.name(), e);
+				self.0.is_readonly.store(true, Ordering::Relaxed);
+			}
+		}
+	}
 }
 
+/// Journalling
 impl InstanceInner
 {
-	fn edit_superblock<R>(&self, cb: impl FnOnce(&mut crate::ondisk::Superblock)->R) -> vfs::node::Result<R> {
+	/// Start (or join) a transaction, all metadata changes made before the handle is dropped are committed together
+	pub fn start_transaction(&self) -> TransactionHandle
+	{
+		if let Some(ref j) = self.journal {
+			j.start();
+		}
+		TransactionHandle(self)
+	}
+
+	/// Edit metadata in the block cache, adding it to the running transaction if journalled
+	fn edit_meta<F: FnOnce(&mut [u8])->R, R>(&self, vol_block: u64, count: usize, f: F) -> vfs::node::Result<R>
+	{
+		match self.journal
+		{
+		Some(ref j) => {
+			let _txn = self.start_transaction();
+			let rv = ::kernel::futures::block_on(self.vol.edit_held(vol_block, count, f))?;
+			let vpb = self.vol_blocks_per_fs_block();
+			for fs_block in vol_block / vpb ..= (vol_block + count as u64 - 1) / vpb {
+				j.add_block(fs_block);
+			}
+			Ok(rv)
+			},
+		None => Ok( ::kernel::futures::block_on(self.vol.edit(vol_block, count, f))? ),
+		}
+	}
+
+	/// Set/clear the "needs recovery" flag, which indicates that the journal might not be empty
+	///
+	/// Written directly (not through the journal), and only changed while no transactions are running.
+	fn set_needs_recovery(&self, needed: bool) -> vfs::node::Result<()>
+	{
 		let mut lh = self.superblock.write();
-		let rv = cb(&mut lh);
+		if lh.has_feature_incompat(::ondisk::FEAT_INCOMPAT_RECOVER) == needed {
+			return Ok( () );
+		}
+		if needed {
+			lh.ext.s_feature_incompat |= ::ondisk::FEAT_INCOMPAT_RECOVER;
+		}
+		else {
+			lh.ext.s_feature_incompat &= !::ondisk::FEAT_INCOMPAT_RECOVER;
+		}
+		let (vol_block, count, ofs) = self.superblock_pos();
+		::kernel::futures::block_on(self.vol.edit(vol_block, count, |data| {
+			self.write_superblock(&mut lh, &mut data[ofs..][..1024]);
+			}))?;
+		Ok( () )
+	}
+}
+
+impl InstanceInner
+{
+	/// Returns the location of the superblock as (vol_block, vol_block_count, byte_ofs)
+	fn superblock_pos(&self) -> (u64, usize, usize) {
 		if self.vol.block_size() > 1024 {
-			::kernel::futures::block_on(self.vol.edit(0, 1, |data| {
-				let data = &mut data[1024..][..1024];
-				lh.write_to_slice(data);
-				}))?;
+			(0, 1, 1024)
 		}
 		else {
-			::kernel::futures::block_on(self.vol.edit(1024 / self.vol.block_size() as u64, 1024 / self.vol.block_size(), |data| {
-				lh.write_to_slice(data);
-				}))?;
+			(1024 / self.vol.block_size() as u64, 1024 / self.vol.block_size(), 0)
 		}
+	}
+	fn edit_superblock<R>(&self, cb: impl FnOnce(&mut crate::ondisk::Superblock)->R) -> vfs::node::Result<R> {
+		let _txn = self.start_transaction();
+		let mut lh = self.superblock.write();
+		let rv = cb(&mut lh);
+		let (vol_block, count, ofs) = self.superblock_pos();
+		self.edit_meta(vol_block, count, |data| {
+			self.write_superblock(&mut lh, &mut data[ofs..][..1024]);
+			})?;
 		Ok(rv)
 	}
+	/// Encode the superblock into `buf`, updating the checksum
+	fn write_superblock(&self, sb: &mut ::ondisk::Superblock, buf: &mut [u8]) {
+		sb.write_to_slice(buf);
+		if self.csum_seed.is_some() {
+			sb.s_checksum = ::csum::crc32c(!0, &buf[..::ondisk::S_CHECKSUM_OFS]);
+			buf[::ondisk::S_CHECKSUM_OFS..][..4].copy_from_slice( &sb.s_checksum.to_le_bytes() );
+		}
+	}
 	fn edit_block_group_header<R>(&self, idx: u32, cb: impl FnOnce(&mut crate::ondisk::GroupDesc)->R) -> vfs::node::Result<R> {
+		let _txn = self.start_transaction();
 		let mut lh = self.group_descriptors.write();
 		let rv = cb(&mut lh[idx as usize]);
-		let ofs = 1024 + idx as usize * ::core::mem::size_of::<::ondisk::GroupDesc>();
-		::kernel::futures::block_on(self.vol.edit( (ofs / self.vol.block_size()) as u64, 1, |data| {
-			let buf = &mut data[ofs % self.vol.block_size()..][..::core::mem::size_of::<::ondisk::GroupDesc>()];
+		let ofs = self.group_desc_ofs + idx as u64 * self.group_desc_size as u64;
+		let vol_bs = self.vol.block_size() as u64;
+		self.edit_meta( ofs / vol_bs, 1, |data| {
+			let buf = &mut data[(ofs % vol_bs) as usize..][..self.group_desc_size];
 			lh[idx as usize].write_to_slice(buf);
-			}))?;
+			if let Some(seed) = self.csum_seed {
+				// Covers the group number and the descriptor (with the checksum field zeroed)
+				buf[::ondisk::GROUP_DESC_CSUM_OFS..][..2].copy_from_slice(&[0; 2]);
+				let csum = ::csum::crc32c(::csum::crc32c(seed, &idx.to_le_bytes()), buf) as u16;
+				buf[::ondisk::GROUP_DESC_CSUM_OFS..][..2].copy_from_slice( &csum.to_le_bytes() );
+				lh[idx as usize].lo.bg_checksum = csum;
+			}
+			})?;
 		Ok(rv)
 	}
 }
@@ -510,7 +691,7 @@ impl InstanceInner
 	fn get_inode_pos(&self, inode_num: u32) -> (u64, usize) {
 		let (group, ofs) = self.get_inode_grp_id(inode_num);
 
-		let base_blk_id = self.group_descriptors.read()[group as usize].bg_inode_table as u64 * self.vol_blocks_per_fs_block();
+		let base_blk_id = self.group_descriptors.read()[group as usize].inode_table() * self.vol_blocks_per_fs_block();
 		assert!(base_blk_id != 0);
 		let ofs_bytes = (ofs as usize) * self.superblock.read().s_inode_size();
 		let (sub_blk_id, sub_blk_ofs) = (ofs_bytes / self.vol.block_size(), ofs_bytes % self.vol.block_size());
@@ -538,7 +719,13 @@ impl InstanceInner
 	/// Allocate a new inode number, possibly in the same block group as `parent_inode_num`.
 	pub fn allocate_inode(&self, parent_inode_num: u32, nodetype: vfs::node::NodeType) -> vfs::node::Result< u32 >
 	{
-		// TODO: Update the superblock
+		let i_mode = match nodetype
+			{
+			vfs::node::NodeType::File => ::ondisk::S_IFREG | 0o644,
+			vfs::node::NodeType::Dir => ::ondisk::S_IFDIR | 0o755,
+			// TODO: Symlink nodes (fast symlinks store the target in `i_block`)
+			vfs::node::NodeType::Symlink(_) => return Err(vfs::Error::Unknown("extN symbolic links aren't supported")),
+			};
 		let has_inodes = self.edit_superblock(|sb| {
 			if sb.data.s_free_inodes_count == 0 {
 				false
@@ -555,40 +742,61 @@ impl InstanceInner
 		assert!(parent_inode_num != 0);	// Has to be a parent - root exists
 		let (grp, _idx) = self.get_inode_grp_id(parent_inode_num);
 
-		let rv = if let Some(rv) = self.allocate_inode_in_bg(grp, nodetype)? {
-				rv
+		// Prefer the parent's group, then search the rest
+		let num_groups = self.group_descriptors.read().len() as u32;
+		let mut rv = None;
+		for ofs in 0 .. num_groups {
+			rv = self.allocate_inode_in_bg((grp + ofs) % num_groups, nodetype)?;
+			if rv.is_some() {
+				break;
 			}
-			else {
-				todo!("InstanceInner::allocate_inode - Search for any BG");
-			};
+		}
+		let Some(rv) = rv else {
+			log_error!("allocate_inode: Superblock said that there were free inodes, but no group had any");
+			return Err(vfs::Error::InconsistentFilesystem);
+		};
 
-		self.write_inode(rv, &crate::ondisk::Inode {
-			i_mode: match nodetype
-				{
-				vfs::node::NodeType::File => ::ondisk::S_IFREG,
-				_ => todo!(""),
-				},
+		// New nodes are owned by the creating process
+		let creds = vfs::Credentials::current();
+		let mut osd2 = [0; 3];
+		osd2[1] = (creds.uid() >> 16) | (creds.gid() >> 16) << 16;
+		let mut inode = crate::ondisk::Inode {
+			i_mode: i_mode,
+			i_uid: creds.uid() as u16,
+			i_gid: creds.gid() as u16,
+			// The caller is expected to add the first name
+			i_links_count: 1,
+			_osd2: osd2,
 			..Default::default()
-			})?;
+			};
+		if self.has_feature_incompat(crate::ondisk::FEAT_INCOMPAT_EXTENTS) {
+			inode.i_flags |= crate::ondisk::EXT4_EXTENTS_FL;
+			crate::extents::init_root(&mut inode.i_block);
+		}
+		self.write_new_inode(rv, &inode)?;
 
 		Ok(rv)
 	}
-	fn allocate_inode_in_bg(&self, grp: u32, _nodetype: vfs::node::NodeType) -> vfs::node::Result< Option<u32> > {
+	fn allocate_inode_in_bg(&self, grp: u32, nodetype: vfs::node::NodeType) -> vfs::node::Result< Option<u32> > {
 		// NOTE: Check with read-only first, and only read-modify-write if the read-only check passed
-		if self.group_descriptors.read()[grp as usize].bg_free_inodes_count == 0 {
+		if self.group_descriptors.read()[grp as usize].free_inodes_count() == 0 {
 			return Ok(None);
 		}
-		let Some(first_bmp_block) = self.edit_block_group_header(grp, |gd|
-			if gd.bg_free_inodes_count == 0 {
-				// This should only be hit if there is a race
-				None
-			}
-			else {
-				gd.bg_free_inodes_count -= 1;
-				Some(gd.bg_inode_bitmap)
-			})? else {
+		if !self.edit_block_group_header(grp, |gd|
+			match gd.free_inodes_count()
+			{
+			// This should only be hit if there is a race
+			0 => false,
+			v => {
+				gd.set_free_inodes_count(v - 1);
+				if let vfs::node::NodeType::Dir = nodetype {
+					gd.set_used_dirs_count(gd.used_dirs_count() + 1);
+				}
+				true
+				},
+			})? {
 			return Ok(None);
-		};
+		}
 
 		// Start the bitmap check
 		let inodes_per_block = self.fs_block_size * 8;
@@ -597,7 +805,7 @@ impl InstanceInner
 			// Number of inodes in this bitmap block (might be fewer, if the group size is small)
 			let n_inodes = (s_inodes_per_group - base).min(inodes_per_block as u32);
 			let n_bytes = ::kernel::lib::num::div_up(n_inodes, 8) as usize;
-			let rv = self.edit_block(first_bmp_block + base / inodes_per_block as u32, |blk_data| {
+			let rv = self.edit_bitmap(grp, Bitmap::Inode, (base / inodes_per_block as u32) as u64, |blk_data| {
 				Ok(match blk_data[..n_bytes].iter().position(|&v| v != !0)
 				{
 				None => None,
@@ -614,6 +822,13 @@ impl InstanceInner
 				if rel_inode_id >= n_inodes {
 					break
 				}
+				if self.csum_seed.is_some() {
+					// fsck assumes that inodes in the unused tail of the table are free (and maybe not zeroed)
+					let idx = base + rel_inode_id;
+					self.edit_block_group_header(grp, |gd| if idx >= s_inodes_per_group.saturating_sub(gd.itable_unused()) {
+						gd.set_itable_unused(s_inodes_per_group - idx - 1);
+						})?;
+				}
 				let rv = 1 + grp * s_inodes_per_group + base + rel_inode_id;
 				log_debug!("allocate_inode_in_bg({}) Allocate I{}", grp, rv);
 				assert!(rv > 2);
@@ -623,6 +838,33 @@ impl InstanceInner
 		log_error!("allocate_inode_in_bg: Descriptor said that there were free inodes, but bitmap was full.");
 		Err(vfs::Error::InconsistentFilesystem)
 	}
+	/// Release an inode number (the inode's blocks must have already been freed)
+	pub fn free_inode(&self, inode_num: u32, is_dir: bool) -> vfs::node::Result<()>
+	{
+		log_debug!("free_inode(I{}, is_dir={})", inode_num, is_dir);
+		let (grp, idx) = self.get_inode_grp_id(inode_num);
+		let inodes_per_block = self.fs_block_size as u32 * 8;
+		let was_used = self.edit_bitmap(grp, Bitmap::Inode, (idx / inodes_per_block) as u64, |blk_data| {
+			let (byte, mask) = (((idx % inodes_per_block) / 8) as usize, 1 << (idx % 8));
+			let rv = blk_data[byte] & mask != 0;
+			blk_data[byte] &= !mask;
+			Ok(rv)
+			})?;
+		if !was_used {
+			log_warning!("free_inode: I{} is already free", inode_num);
+			return Ok( () );
+		}
+		// Clear the on-disk inode (a zero mode marks it as unused for fsck)
+		self.write_new_inode(inode_num, &Default::default())?;
+		self.edit_block_group_header(grp, |gd| {
+			gd.set_free_inodes_count(gd.free_inodes_count() + 1);
+			if is_dir {
+				gd.set_used_dirs_count(gd.used_dirs_count().saturating_sub(1));
+			}
+			})?;
+		self.edit_superblock(|sb| sb.data.s_free_inodes_count += 1)?;
+		Ok( () )
+	}
 
 	/// Read an inode descriptor from the disk
 	pub fn read_inode(&self, inode_num: u32) -> vfs::Result< ::ondisk::Inode >
@@ -645,19 +887,190 @@ impl InstanceInner
 		log_trace!("- rv={:?}", rv);
 		Ok( rv )
 	}
+	/// Write a freshly allocated inode, clearing (and initialising) the extra fields
+	fn write_new_inode(&self, inode_num: u32, inode_data: &::ondisk::Inode) -> vfs::Result< () >
+	{
+		let (vol_block, blk_ofs) = self.get_inode_pos(inode_num);
+
+		let s_inode_size = self.superblock.read().s_inode_size();
+		let base_size = ::core::mem::size_of::<crate::ondisk::Inode>();
+		let extra_size = ::core::mem::size_of::<crate::ondisk::InodeExtra>();
+		self.edit_meta(vol_block, 1, |data| {
+			let slot = &mut data[blk_ofs..][..s_inode_size];
+			for b in slot.iter_mut() {
+				*b = 0;
+			}
+			let mut slice = &mut slot[..];
+			let _ = ::kernel::lib::byteorder::EncodedLE::encode(inode_data, &mut slice);
+			// Larger inodes record how many of the extra fields are valid
+			if s_inode_size >= base_size + extra_size {
+				let _ = ::kernel::lib::byteorder::EncodedLE::encode(&crate::ondisk::InodeExtra {
+					i_extra_size: extra_size as u16,
+					i_checksum_hi: 0,
+					i_ctime_extra: 0,
+					i_mtime_extra: 0,
+					i_atime_extra: 0,
+					i_crtime: 0,
+					i_crtime_extra: 0,
+					i_version_hi: 0,
+					i_projid: 0,
+					}, &mut slice);
+			}
+			self.set_inode_csum(inode_num, slot);
+			})?;
+
+		Ok( () )
+	}
 	/// Write an inode descriptor back to the disk
 	pub fn write_inode(&self, inode_num: u32, inode_data: &::ondisk::Inode) -> vfs::Result< () >
 	{
 		let (vol_block, blk_ofs) = self.get_inode_pos(inode_num);
 
 		let s_inode_size = self.superblock.read().s_inode_size();
-		::kernel::futures::block_on(self.vol.edit(vol_block, 1, |data| {
-			let mut slice = &mut data[blk_ofs..][..s_inode_size];
-			let _ = ::kernel::lib::byteorder::EncodedLE::encode(inode_data, &mut slice);
-			}))?;
+		self.edit_meta(vol_block, 1, |data| {
+			let slot = &mut data[blk_ofs..][..s_inode_size];
+			let _ = ::kernel::lib::byteorder::EncodedLE::encode(inode_data, &mut &mut slot[..]);
+			self.set_inode_csum(inode_num, slot);
+			})?;
 
 		Ok( () )
 	}
+	/// Update the checksum of an encoded inode (no-op without FEAT_RO_COMPAT_METADATA_CSUM)
+	fn set_inode_csum(&self, inode_num: u32, slot: &mut [u8])
+	{
+		use ::ondisk::{INODE_CSUM_LO_OFS, INODE_CSUM_HI_OFS, INODE_EXTRA_ISIZE_OFS, INODE_GENERATION_OFS};
+		let g = &slot[INODE_GENERATION_OFS..][..4];
+		let Some(seed) = self.inode_csum_seed(inode_num, u32::from_le_bytes([g[0], g[1], g[2], g[3]])) else {
+			return ;
+		};
+		// The high half is only stored if the extra fields cover it
+		let has_hi = slot.len() >= INODE_CSUM_HI_OFS + 2
+			&& 128 + u16::from_le_bytes([slot[INODE_EXTRA_ISIZE_OFS], slot[INODE_EXTRA_ISIZE_OFS+1]]) as usize >= INODE_CSUM_HI_OFS + 2;
+		slot[INODE_CSUM_LO_OFS..][..2].copy_from_slice(&[0; 2]);
+		if has_hi {
+			slot[INODE_CSUM_HI_OFS..][..2].copy_from_slice(&[0; 2]);
+		}
+		let csum = ::csum::crc32c(seed, slot);
+		slot[INODE_CSUM_LO_OFS..][..2].copy_from_slice( &(csum as u16).to_le_bytes() );
+		if has_hi {
+			slot[INODE_CSUM_HI_OFS..][..2].copy_from_slice( &((csum >> 16) as u16).to_le_bytes() );
+		}
+	}
+}
+
+#[derive(Copy,Clone)]
+enum Bitmap
+{
+	Block,
+	Inode,
+}
+
+/// Block/inode bitmaps
+impl InstanceInner
+{
+	/// Edit block `ofs` of a group's block or inode bitmap
+	///
+	/// With metadata checksums this also initialises a bitmap flagged as uninitialised, and updates the bitmap's
+	/// checksum in the group descriptor.
+	fn edit_bitmap<R>(&self, group: u32, which: Bitmap, ofs: u64, f: impl FnOnce(&mut [u8])->vfs::node::Result<R>) -> vfs::node::Result<R>
+	{
+		let (first_block, uninit_flag) = {
+			let gd = &self.group_descriptors.read()[group as usize];
+			match which
+			{
+			Bitmap::Block => (gd.block_bitmap(), gd.lo.bg_flags & ::ondisk::BG_BLOCK_UNINIT),
+			Bitmap::Inode => (gd.inode_bitmap(), gd.lo.bg_flags & ::ondisk::BG_INODE_UNINIT),
+			}
+			};
+		let Some(seed) = self.csum_seed else {
+			return self.edit_block(first_block + ofs, f);
+		};
+		let csum_len = {
+			let sb = self.superblock.read();
+			match which
+			{
+			Bitmap::Block => sb.data.s_blocks_per_group as usize / 8,
+			Bitmap::Inode => sb.s_inodes_per_group() as usize / 8,
+			}
+			};
+		let (rv, csum) = self.edit_block(first_block + ofs, |data| {
+			if uninit_flag != 0 {
+				self.init_bitmap(group, which, data);
+			}
+			let rv = f(data)?;
+			// Only the first block of the bitmap is covered by the checksum
+			let csum = if ofs == 0 { Some(::csum::crc32c(seed, &data[..csum_len.min(data.len())])) } else { None };
+			Ok( (rv, csum) )
+			})?;
+		self.edit_block_group_header(group, |gd| {
+			gd.lo.bg_flags &= !uninit_flag;
+			match (which, csum)
+			{
+			(_, None) => {},
+			(Bitmap::Block, Some(csum)) => gd.set_block_bitmap_csum(csum),
+			(Bitmap::Inode, Some(csum)) => gd.set_inode_bitmap_csum(csum),
+			}
+			})?;
+		Ok(rv)
+	}
+
+	/// Fill the initial contents of a bitmap flagged as uninitialised (BG_BLOCK_UNINIT/BG_INODE_UNINIT)
+	fn init_bitmap(&self, group: u32, which: Bitmap, data: &mut [u8])
+	{
+		fn set(data: &mut [u8], bit: u64) {
+			data[(bit / 8) as usize] |= 1 << (bit % 8);
+		}
+		for b in data.iter_mut() {
+			*b = 0;
+		}
+		let sb = self.superblock.read();
+		let n_valid = match which
+			{
+			Bitmap::Inode => sb.s_inodes_per_group() as u64,
+			Bitmap::Block => {
+				let group_base = sb.data.s_first_data_block as u64 + group as u64 * sb.data.s_blocks_per_group as u64;
+				let n_blocks = (sb.s_blocks_count() - group_base).min(sb.data.s_blocks_per_group as u64);
+				let gds = self.group_descriptors.read();
+				// Superblock and descriptor table backups
+				if Self::group_has_super(&sb, group) {
+					let gdt_blocks = ::kernel::lib::num::div_up(gds.len() * self.group_desc_size, self.fs_block_size) as u64;
+					for b in 0 .. 1 + gdt_blocks + sb.ext.s_reserved_gdt_blocks as u64 {
+						set(data, b);
+					}
+				}
+				// The group's own bitmaps and inode table (which can be in another group with FEAT_INCOMPAT_FLEX_BG)
+				let gd = &gds[group as usize];
+				let itable_blocks = ::kernel::lib::num::div_up(sb.s_inodes_per_group() as usize * sb.s_inode_size(), self.fs_block_size) as u64;
+				let itable = gd.inode_table() .. gd.inode_table() + itable_blocks;
+				for b in [gd.block_bitmap(), gd.inode_bitmap()].iter().cloned().chain(itable) {
+					if group_base <= b && b < group_base + n_blocks {
+						set(data, b - group_base);
+					}
+				}
+				n_blocks
+				},
+			};
+		// Bits past the end of the group are always set
+		for bit in n_valid .. data.len() as u64 * 8 {
+			set(data, bit);
+		}
+	}
+
+	/// Check if a group holds a backup of the superblock (and group descriptors)
+	fn group_has_super(sb: &::ondisk::Superblock, group: u32) -> bool
+	{
+		if group <= 1 || !sb.has_feature_ro_compat(::ondisk::FEAT_RO_COMPAT_SPARSE_SUPER) {
+			return true;
+		}
+		// With sparse superblocks, only powers of 3, 5, and 7 have backups
+		[3u64, 5, 7].iter().any(|&base| {
+			let mut v = base;
+			while v < group as u64 {
+				v *= base;
+			}
+			v == group as u64
+			})
+	}
 }
 
 /// Superblock parameters
@@ -674,6 +1087,22 @@ impl InstanceInner
 	pub fn has_feature_ro_compat(&self, feat: u32) -> bool {
 		self.superblock.read().has_feature_ro_compat(feat)
 	}
+	pub fn has_feature_compat(&self, feat: u32) -> bool {
+		self.superblock.read().has_feature_compat(feat)
+	}
+
+	/// Seed for directory index hashes
+	pub fn dir_hash_seed(&self) -> [u32; 4] {
+		self.superblock.read().ext.s_hash_seed
+	}
+	/// Directory index hashes treat names as unsigned
+	pub fn dir_hash_unsigned(&self) -> bool {
+		self.superblock.read().ext.s_flags & crate::ondisk::EXT2_FLAGS_UNSIGNED_HASH != 0
+	}
+	/// Seed for the checksums of an inode and the blocks it owns (None if metadata checksums are disabled)
+	pub fn inode_csum_seed(&self, inode_num: u32, generation: u32) -> Option<u32> {
+		self.csum_seed.map(|seed| ::csum::crc32c(::csum::crc32c(seed, &inode_num.to_le_bytes()), &generation.to_le_bytes()))
+	}
 }
 
 
@@ -0,0 +1,514 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/journal.rs
+//! ext3/4 journal (jbd2) replay and transactions
+//!
+//! Transactions are committed synchronously: the modified metadata blocks are held in the block cache, copied into
+//! the log (followed by a commit block), then written in place before the log is marked as empty again.
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use kernel::lib::byteorder::{EncodedBE,ReadBytesExt,BigEndian};
+use crate::ondisk::{JournalHeader,JournalSuperblock};
+use crate::ondisk::{JBD2_MAGIC, JBD2_DESCRIPTOR_BLOCK,JBD2_COMMIT_BLOCK,JBD2_REVOKE_BLOCK,JBD2_SUPERBLOCK_V1,JBD2_SUPERBLOCK_V2};
+use crate::instance::InstanceInner;
+
+/// Incompatible journal features understood when reading
+const SUPPORTED_REPLAY_FEATURES: u32 = 0
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_REVOKE
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V2
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3
+	;
+/// Incompatible journal features that can be generated
+const SUPPORTED_WRITE_FEATURES: u32 = 0
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_REVOKE
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V2
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3
+	;
+
+pub struct Journal
+{
+	/// Location of the journal: (first journal block, first filesystem block, count)
+	extents: Vec<(u32, u64, u32)>,
+	/// Size of a descriptor block tag (not including the optional UUID)
+	tag_size: usize,
+	/// Size of the checksum tail on descriptor/revoke blocks
+	tail_size: usize,
+	/// Seed for checksums (with CSUM_V2/V3)
+	csum_seed: Option<u32>,
+	/// Transactions can be written
+	writable: bool,
+
+	state: Mutex<State>,
+}
+struct State
+{
+	/// Raw copy of journal block 0
+	sb_raw: Vec<u8>,
+	sb: JournalSuperblock,
+	/// Number of open `TransactionHandle`s
+	users: usize,
+	/// Filesystem blocks modified by the running transaction
+	blocks: Vec<u64>,
+}
+
+/// An item found while walking the log
+enum Record
+{
+	/// Block `fs_block` was logged in journal block `jblock` (`csum` is only 16 bits with CSUM_V2)
+	Tag { seq: u32, fs_block: u64, jblock: u32, flags: u32, csum: u32 },
+	/// Earlier copies of `fs_block` must not be replayed
+	Revoke { seq: u32, fs_block: u64 },
+}
+
+/// Wrapping comparison of transaction IDs (`a` after or equal to `b`)
+fn tid_geq(a: u32, b: u32) -> bool {
+	a.wrapping_sub(b) as i32 >= 0
+}
+
+impl Journal
+{
+	/// Locate and validate the journal stored in `inode_num`
+	pub fn load(fs: &InstanceInner, inode_num: u32) -> vfs::Result<Journal>
+	{
+		let inode = fs.read_inode(inode_num)?;
+		let n_blocks = inode.max_blocks(fs);
+		let mut extents = Vec::new();
+		let mut idx = 0;
+		while idx < n_blocks
+		{
+			let (start, count) = inode.get_extent_from_block(fs, idx, n_blocks - idx)?;
+			if start == 0 {
+				log_error!("{}: Journal inode I{} is sparse", fs.vol.name(), inode_num);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			extents.push( (idx, start, count) );
+			idx += count;
+		}
+		if extents.is_empty() {
+			log_error!("{}: Journal inode I{} is empty", fs.vol.name(), inode_num);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+
+		let mut sb_raw = vec![0; fs.fs_block_size];
+		fs.read_blocks(extents[0].1, &mut sb_raw)?;
+		let sb = JournalSuperblock::from_slice(&sb_raw);
+		log_debug!("Journal superblock = {:?}", sb);
+		if sb.s_header.h_magic != JBD2_MAGIC {
+			log_error!("{}: Bad journal magic {:#x}", fs.vol.name(), sb.s_header.h_magic);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let (compat, incompat) = match sb.s_header.h_blocktype
+			{
+			JBD2_SUPERBLOCK_V1 => (0, 0),
+			JBD2_SUPERBLOCK_V2 => (sb.s_feature_compat, sb.s_feature_incompat),
+			v => {
+				log_error!("{}: Unknown journal superblock type {}", fs.vol.name(), v);
+				return Err(vfs::Error::InconsistentFilesystem);
+				},
+			};
+		if sb.s_blocksize as usize != fs.fs_block_size || sb.s_maxlen > n_blocks || sb.s_first == 0 || sb.s_first >= sb.s_maxlen {
+			log_error!("{}: Journal geometry invalid (bs={}, maxlen={}/{}, first={})",
+				fs.vol.name(), sb.s_blocksize, sb.s_maxlen, n_blocks, sb.s_first);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		if incompat & !SUPPORTED_REPLAY_FEATURES != 0 {
+			log_warning!("{}: Journal uses unsupported features ({:#x})", fs.vol.name(), incompat & !SUPPORTED_REPLAY_FEATURES);
+			return Err(vfs::Error::TypeMismatch);
+		}
+		let has_csum = incompat & (::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V2 | ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3) != 0;
+		let writable = incompat & !SUPPORTED_WRITE_FEATURES == 0 && compat & ::ondisk::JBD2_FEATURE_COMPAT_CHECKSUM == 0
+			&& (!has_csum || sb.s_checksum_type == ::ondisk::JBD2_CRC32C_CHKSUM);
+		if !writable {
+			log_notice!("{}: Journal uses unsupported checksums, can't write transactions", fs.vol.name());
+		}
+		let csum_seed = if has_csum { Some(::csum::crc32c(!0, &sb.s_uuid)) } else { None };
+
+		let has_64bit = incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT != 0;
+		let (tag_size, tail_size) = if incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3 != 0 {
+				(16, 4)
+			}
+			else if incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V2 != 0 {
+				(if has_64bit { 12 } else { 8 } + 2, 4)
+			}
+			else {
+				(if has_64bit { 12 } else { 8 }, 0)
+			};
+
+		Ok(Journal {
+			extents: extents,
+			tag_size: tag_size,
+			tail_size: tail_size,
+			csum_seed: csum_seed,
+			writable: writable,
+			state: Mutex::new(State {
+				sb_raw: sb_raw,
+				sb: sb,
+				users: 0,
+				blocks: Vec::new(),
+				}),
+			})
+	}
+
+	/// Returns true if new transactions can be written
+	pub fn is_writable(&self) -> bool {
+		self.writable
+	}
+
+	/// Get the filesystem block backing journal block `jblock`
+	fn map(&self, jblock: u32) -> vfs::Result<u64> {
+		match self.extents.iter().find(|&&(first, _, count)| first <= jblock && jblock - first < count)
+		{
+		Some(&(first, start, _)) => Ok(start + (jblock - first) as u64),
+		None => Err(vfs::Error::InconsistentFilesystem),
+		}
+	}
+	fn read_jblock(&self, fs: &InstanceInner, jblock: u32, buf: &mut [u8]) -> vfs::Result<()> {
+		fs.read_blocks(self.map(jblock)?, buf)
+	}
+	fn write_jblock(&self, fs: &InstanceInner, jblock: u32, buf: &[u8]) -> vfs::Result<()> {
+		fs.write_blocks(self.map(jblock)?, buf)
+	}
+	fn write_sb(&self, fs: &InstanceInner, st: &mut State) -> vfs::Result<()> {
+		st.sb.write_to_slice(&mut st.sb_raw);
+		if self.csum_seed.is_some() {
+			// Covers the entire 1024 byte superblock (with the checksum field zeroed)
+			let ofs = ::ondisk::JBD2_SB_CSUM_OFS;
+			st.sb_raw[ofs..][..4].copy_from_slice(&[0; 4]);
+			let csum = ::csum::crc32c(!0, &st.sb_raw[..1024]);
+			st.sb_raw[ofs..][..4].copy_from_slice(&csum.to_be_bytes());
+		}
+		self.write_jblock(fs, 0, &st.sb_raw)
+	}
+	/// Check the big-endian checksum at `ofs` in a log block (computed with that field zeroed)
+	fn check_block_csum(&self, buf: &mut [u8], ofs: usize) -> bool {
+		let seed = match self.csum_seed
+			{
+			Some(v) => v,
+			None => return true,
+			};
+		let mut stored = [0; 4];
+		stored.copy_from_slice(&buf[ofs..][..4]);
+		buf[ofs..][..4].copy_from_slice(&[0; 4]);
+		let csum = ::csum::crc32c(seed, buf);
+		buf[ofs..][..4].copy_from_slice(&stored);
+		csum.to_be_bytes() == stored
+	}
+	fn has_64bit(sb: &JournalSuperblock) -> bool {
+		sb.s_header.h_blocktype == JBD2_SUPERBLOCK_V2 && sb.s_feature_incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT != 0
+	}
+}
+
+/// Recovery
+impl Journal
+{
+	/// Replay committed transactions left in the log, returns true if anything was written
+	pub fn replay(&self, fs: &InstanceInner) -> vfs::Result<bool>
+	{
+		let mut st = self.state.lock();
+		if st.sb.s_start == 0 {
+			return Ok(false);
+		}
+		if st.sb.s_header.h_blocktype == JBD2_SUPERBLOCK_V2 && st.sb.s_feature_incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_FAST_COMMIT != 0 {
+			log_error!("{}: Journal needs recovery, but fast-commit replay isn't supported", fs.vol.name());
+			return Err(vfs::Error::Unknown("extN fast-commit replay unsupported"));
+		}
+
+		// 1. Find the end of the log (the first transaction without a commit block)
+		let end_seq = self.walk(fs, &st.sb, None, &mut |_| Ok( () ))?;
+		log_notice!("{}: Replaying journal transactions {} to {}", fs.vol.name(), st.sb.s_sequence, end_seq.wrapping_sub(1));
+
+		// 2. Collect revoked blocks, keeping the newest revoking transaction
+		let mut revoked = ::kernel::lib::VecMap::<u64, u32>::new();
+		self.walk(fs, &st.sb, Some(end_seq), &mut |r| {
+			if let Record::Revoke { seq, fs_block } = r {
+				let e = revoked.entry(fs_block).or_insert(seq);
+				if tid_geq(seq, *e) {
+					*e = seq;
+				}
+			}
+			Ok( () )
+			})?;
+
+		// 3. Write logged blocks back to their home locations
+		let mut buf = vec![0; fs.fs_block_size];
+		let mut n_written = 0;
+		let mut n_bad = 0;
+		self.walk(fs, &st.sb, Some(end_seq), &mut |r| {
+			if let Record::Tag { seq, fs_block, jblock, flags, csum } = r {
+				match revoked.get(&fs_block)
+				{
+				Some(&rseq) if tid_geq(rseq, seq) => {},
+				_ => {
+					self.read_jblock(fs, jblock, &mut buf)?;
+					if let Some(seed) = self.csum_seed {
+						let calc = ::csum::crc32c(::csum::crc32c(seed, &seq.to_be_bytes()), &buf);
+						let ok = if self.tag_size == 16 { calc == csum } else { calc as u16 == csum as u16 };
+						if !ok {
+							log_error!("{}: Journal block {} (T{}, home {}) has a bad checksum, not replaying",
+								fs.vol.name(), jblock, seq, fs_block);
+							n_bad += 1;
+							return Ok( () );
+						}
+					}
+					if flags & ::ondisk::JBD2_FLAG_ESCAPE != 0 {
+						buf[..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+					}
+					fs.write_blocks_cached(fs_block, &buf)?;
+					n_written += 1;
+					},
+				}
+			}
+			Ok( () )
+			})?;
+		log_notice!("{}: Journal replay wrote {} blocks ({} skipped due to bad checksums)", fs.vol.name(), n_written, n_bad);
+
+		// 4. The replayed blocks must be on disk before the log is discarded
+		fs.barrier()?;
+
+		// 5. The log is now empty
+		st.sb.s_sequence = end_seq;
+		st.sb.s_start = 0;
+		self.write_sb(fs, &mut st)?;
+		Ok(n_written > 0)
+	}
+
+	/// Walk the log from `s_start`, stopping at `end_seq` (or the first incomplete transaction)
+	///
+	/// A descriptor, revoke, or commit block with a bad checksum is treated as the end of the log.
+	///
+	/// Returns the ID of the first transaction not fully seen
+	fn walk(&self, fs: &InstanceInner, sb: &JournalSuperblock, end_seq: Option<u32>, cb: &mut dyn FnMut(Record)->vfs::Result<()>) -> vfs::Result<u32>
+	{
+		let next = |b: u32| if b + 1 >= sb.s_maxlen { sb.s_first } else { b + 1 };
+		let has_64bit = Self::has_64bit(sb);
+		let mut buf = vec![0; fs.fs_block_size];
+		let mut seq = sb.s_sequence;
+		let mut pos = sb.s_start;
+		// Limit the number of blocks visited, in case the log is corrupted into a loop
+		let mut remaining = sb.s_maxlen;
+		while remaining > 0 && Some(seq) != end_seq
+		{
+			remaining -= 1;
+			self.read_jblock(fs, pos, &mut buf)?;
+			let hdr = JournalHeader::from_slice(&buf);
+			if hdr.h_magic != JBD2_MAGIC || hdr.h_sequence != seq {
+				break;
+			}
+			if self.tail_size > 0 && (hdr.h_blocktype == JBD2_DESCRIPTOR_BLOCK || hdr.h_blocktype == JBD2_REVOKE_BLOCK) {
+				let tail = buf.len() - self.tail_size;
+				if !self.check_block_csum(&mut buf, tail) {
+					log_warning!("{}: Journal block {} (T{}) has a bad checksum, ending replay", fs.vol.name(), pos, seq);
+					break;
+				}
+			}
+			match hdr.h_blocktype
+			{
+			JBD2_DESCRIPTOR_BLOCK => {
+				let mut data_pos = next(pos);
+				let mut ofs = 12;
+				while ofs + self.tag_size <= buf.len() - self.tail_size
+				{
+					let mut r = &buf[ofs..][..self.tag_size];
+					let (lo, flags, hi, csum);
+					if self.tag_size == 16 {
+						lo = r.read_u32::<BigEndian>().unwrap();
+						flags = r.read_u32::<BigEndian>().unwrap();
+						hi = r.read_u32::<BigEndian>().unwrap();
+						csum = r.read_u32::<BigEndian>().unwrap();
+					}
+					else {
+						lo = r.read_u32::<BigEndian>().unwrap();
+						csum = r.read_u16::<BigEndian>().unwrap() as u32;
+						flags = r.read_u16::<BigEndian>().unwrap() as u32;
+						hi = if has_64bit { r.read_u32::<BigEndian>().unwrap() } else { 0 };
+					}
+					ofs += self.tag_size;
+					if flags & ::ondisk::JBD2_FLAG_SAME_UUID == 0 {
+						ofs += 16;
+					}
+					if end_seq.is_some() {
+						cb(Record::Tag { seq, fs_block: lo as u64 | (hi as u64) << 32, jblock: data_pos, flags, csum })?;
+					}
+					data_pos = next(data_pos);
+					remaining = remaining.saturating_sub(1);
+					if flags & ::ondisk::JBD2_FLAG_LAST_TAG != 0 {
+						break;
+					}
+				}
+				pos = data_pos;
+				},
+			JBD2_COMMIT_BLOCK => {
+				if !self.check_block_csum(&mut buf, ::ondisk::JBD2_COMMIT_CSUM_OFS) {
+					log_warning!("{}: Journal commit block {} (T{}) has a bad checksum, ending replay", fs.vol.name(), pos, seq);
+					break;
+				}
+				seq = seq.wrapping_add(1);
+				pos = next(pos);
+				},
+			JBD2_REVOKE_BLOCK => {
+				let mut r = &buf[12..];
+				let count = r.read_u32::<BigEndian>().unwrap() as usize;
+				let count = count.min(buf.len() - self.tail_size);
+				let mut r = &buf[16..count.max(16)];
+				while r.len() > 0
+				{
+					let fs_block = if has_64bit { r.read_u64::<BigEndian>() } else { r.read_u32::<BigEndian>().map(|v| v as u64) };
+					let fs_block = fs_block.map_err(|_| vfs::Error::InconsistentFilesystem)?;
+					if end_seq.is_some() {
+						cb(Record::Revoke { seq, fs_block })?;
+					}
+				}
+				pos = next(pos);
+				},
+			_ => break,
+			}
+		}
+		Ok(seq)
+	}
+}
+
+/// Transactions
+impl Journal
+{
+	/// Start (or join) the running transaction
+	pub fn start(&self) {
+		self.state.lock().users += 1;
+	}
+	/// Record that `fs_block` has been modified (and is held in the cache) as part of the running transaction
+	pub fn add_block(&self, fs_block: u64) {
+		let mut st = self.state.lock();
+		assert!(st.users > 0, "Journal::add_block with no open transaction");
+		if !st.blocks.contains(&fs_block) {
+			st.blocks.push(fs_block);
+		}
+	}
+	/// Leave the running transaction, committing it if this was the last user
+	pub fn stop(&self, fs: &InstanceInner) -> vfs::Result<()> {
+		let mut st = self.state.lock();
+		st.users -= 1;
+		if st.users > 0 || st.blocks.is_empty() {
+			return Ok( () );
+		}
+		let blocks = ::core::mem::replace(&mut st.blocks, Vec::new());
+		let rv = self.commit(fs, &mut st, &blocks);
+		if rv.is_err() {
+			// Let the blocks be written anyway, the log can't be trusted now
+			for &b in &blocks {
+				let _ = fs.release_block(b);
+			}
+		}
+		rv
+	}
+
+	fn commit(&self, fs: &InstanceInner, st: &mut State, blocks: &[u64]) -> vfs::Result<()>
+	{
+		assert!(self.writable);
+		let bs = fs.fs_block_size;
+		// Tags per descriptor, assuming that all need a UUID
+		let tags_per_desc = (bs - 12 - self.tail_size) / (self.tag_size + 16);
+		let log_len = (st.sb.s_maxlen - st.sb.s_first) as usize;
+		let max_per_txn = (log_len - 2) * tags_per_desc / (tags_per_desc + 1);
+		if blocks.len() > max_per_txn {
+			// Splitting it would make the update non-atomic, so refuse to commit
+			log_error!("{}: Transaction of {} blocks is larger than the journal (max {})", fs.vol.name(), blocks.len(), max_per_txn);
+			return Err(vfs::Error::Unknown("extN transaction larger than the journal"));
+		}
+		let seq = st.sb.s_sequence;
+		let has_64bit = Self::has_64bit(&st.sb);
+		log_trace!("commit: T{} {} blocks", seq, blocks.len());
+
+		// The log is always empty between transactions, so start at the beginning
+		let mut pos = st.sb.s_first;
+		for group in blocks.chunks(tags_per_desc)
+		{
+			let mut desc = vec![0; bs];
+			JournalHeader { h_magic: JBD2_MAGIC, h_blocktype: JBD2_DESCRIPTOR_BLOCK, h_sequence: seq }.write_to_slice(&mut desc);
+			let mut ofs = 12;
+			for (i, &fs_block) in group.iter().enumerate()
+			{
+				let mut data = fs.get_block(fs_block)?.bytes().to_vec();
+				let mut flags = 0;
+				if i > 0 {
+					flags |= ::ondisk::JBD2_FLAG_SAME_UUID;
+				}
+				if i == group.len() - 1 {
+					flags |= ::ondisk::JBD2_FLAG_LAST_TAG;
+				}
+				if data[..4] == JBD2_MAGIC.to_be_bytes() {
+					flags |= ::ondisk::JBD2_FLAG_ESCAPE;
+					data[..4].copy_from_slice(&[0; 4]);
+				}
+
+				// Tag checksums cover the sequence number and the block as written to the log
+				let csum = match self.csum_seed
+					{
+					Some(seed) => ::csum::crc32c(::csum::crc32c(seed, &seq.to_be_bytes()), &data),
+					None => 0,
+					};
+				let mut w = &mut desc[ofs..][..self.tag_size];
+				if self.tag_size == 16 {
+					// CSUM_V3
+					(fs_block as u32).encode(&mut w).unwrap();
+					flags.encode(&mut w).unwrap();
+					((fs_block >> 32) as u32).encode(&mut w).unwrap();
+					csum.encode(&mut w).unwrap();
+				}
+				else {
+					(fs_block as u32).encode(&mut w).unwrap();
+					(csum as u16).encode(&mut w).unwrap();
+					(flags as u16).encode(&mut w).unwrap();
+					if has_64bit {
+						((fs_block >> 32) as u32).encode(&mut w).unwrap();
+					}
+				}
+				ofs += self.tag_size;
+				if i == 0 {
+					desc[ofs..][..16].copy_from_slice(&st.sb.s_uuid);
+					ofs += 16;
+				}
+
+				self.write_jblock(fs, pos + 1 + i as u32, &data)?;
+			}
+			if let Some(seed) = self.csum_seed {
+				let tail = bs - self.tail_size;
+				let csum = ::csum::crc32c(seed, &desc);
+				desc[tail..].copy_from_slice(&csum.to_be_bytes());
+			}
+			self.write_jblock(fs, pos, &desc)?;
+			pos += 1 + group.len() as u32;
+		}
+		let mut commit = vec![0; bs];
+		JournalHeader { h_magic: JBD2_MAGIC, h_blocktype: JBD2_COMMIT_BLOCK, h_sequence: seq }.write_to_slice(&mut commit);
+		if let Some(seed) = self.csum_seed {
+			// `h_chksum[0]`, with the checksum type and size left as zero
+			let csum = ::csum::crc32c(seed, &commit);
+			commit[::ondisk::JBD2_COMMIT_CSUM_OFS..][..4].copy_from_slice(&csum.to_be_bytes());
+		}
+		self.write_jblock(fs, pos, &commit)?;
+		// The log must be on disk before it's marked as live
+		fs.barrier()?;
+
+		// The transaction is durable once the log start is recorded
+		st.sb.s_start = st.sb.s_first;
+		self.write_sb(fs, st)?;
+		// - And that must be on disk before any of the home locations are touched
+		fs.barrier()?;
+
+		// Checkpoint: write the blocks to their home locations
+		for &b in blocks {
+			fs.release_block(b)?;
+		}
+		// Checkpointed blocks have to hit the disk before the log is discarded
+		fs.barrier()?;
+
+		// And mark the log as empty again
+		st.sb.s_start = 0;
+		st.sb.s_sequence = seq.wrapping_add(1);
+		self.write_sb(fs, st)?;
+		Ok( () )
+	}
+}
@@ -20,7 +20,11 @@ extern crate block_cache;
 module_define!{FS_EXTN, [VFS], init}
 
 mod ondisk;
+mod csum;
 mod inodes;
+mod extents;
+mod journal;
+mod htree;
 
 mod dir;
 mod file;
@@ -35,15 +39,26 @@ fn init()
 const SUPPORTED_OPT_FEATURES: u32 = 0
 	| ::ondisk::FEAT_COMPAT_EXT_ATTR	// Extended attributes
 	| ::ondisk::FEAT_COMPAT_RESIZE_INODE	// Extra space was allocated for resizing the filesystem
+	| ::ondisk::FEAT_COMPAT_HAS_JOURNAL	// Metadata updates are journalled (jbd2)
+	| ::ondisk::FEAT_COMPAT_DIR_INDEX	// Large directories can have a hashed index (HTree)
 	;
 /// Read-only features: Missing features stop write support
 const SUPPORTED_RDO_FEATURES: u32 = 0
 	| ::ondisk::FEAT_RO_COMPAT_SPARSE_SUPER	// Enables storing SB backups at group 0, 3^n, 5^n, and 7^n
 	| ::ondisk::FEAT_RO_COMPAT_LARGE_FILE	// 64-bit file sizes (in a separate inode field)
+	| ::ondisk::FEAT_RO_COMPAT_HUGE_FILE	// 48-bit `i_blocks`, optionally in filesystem blocks
+	| ::ondisk::FEAT_RO_COMPAT_EXTRA_ISIZE	// Inodes have at least `s_min_extra_isize` bytes of extra fields
+	| ::ondisk::FEAT_RO_COMPAT_DIR_NLINK	// Directories with too many subdirectories have a link count of 1
+	| ::ondisk::FEAT_RO_COMPAT_METADATA_CSUM	// Metadata blocks, inodes and group descriptors are checksummed (crc32c)
 	;
 /// Required Features: Missing features prevent mounting
 const SUPPORTED_REQ_FEATURES: u32 = 0
 	| ::ondisk::FEAT_INCOMPAT_FILETYPE	// DirEnt.d_name_len restricted to 1 byte and extra byte used for file type
+	| ::ondisk::FEAT_INCOMPAT_RECOVER	// Journal needs to be replayed (done at mount)
+	| ::ondisk::FEAT_INCOMPAT_EXTENTS	// Inodes can use an extent tree instead of indirect blocks
+	| ::ondisk::FEAT_INCOMPAT_64BIT	// 64-bit block numbers and larger group descriptors
+	| ::ondisk::FEAT_INCOMPAT_FLEX_BG	// Group metadata can be located outside of the group
+	| ::ondisk::FEAT_INCOMPAT_CSUM_SEED	// Metadata checksum seed is stored in the superblock
 	;
 
 static S_DRIVER: Driver = Driver;
@@ -80,8 +95,8 @@ impl vfs::mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
-		Ok( try!(instance::Instance::new_boxed(vol, mounthandle)) )
+	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle, options: &vfs::mount::MountOptions) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
+		Ok( try!(instance::Instance::new_boxed(vol, mounthandle, options)) )
 	}
 }
 
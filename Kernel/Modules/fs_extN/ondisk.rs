@@ -44,6 +44,9 @@ impl Superblock
 		}
 	}
 
+	pub fn has_feature_compat(&self, feat: u32) -> bool {
+		self.data.s_rev_level > 0 && self.ext.s_feature_compat & feat != 0
+	}
 	pub fn has_feature_incompat(&self, feat: u32) -> bool {
 		self.data.s_rev_level > 0 && self.ext.s_feature_incompat & feat != 0
 	}
@@ -52,15 +55,44 @@ impl Superblock
 	}
 
 	pub fn s_group_desc_size(&self) -> usize {
-		//if self.has_feature_incompat(FEAT_INCOMPAT_64BIT) {
-		//	self.ext.s_group_desc_size as usize
-		//}
-		//else {
+		if self.has_feature_incompat(FEAT_INCOMPAT_64BIT) && self.ext.s_desc_size >= 32 {
+			self.ext.s_desc_size as usize
+		}
+		else {
 			32
-		//}
+		}
+	}
+
+	/// Total number of blocks (including the high word if FEAT_INCOMPAT_64BIT is set)
+	pub fn s_blocks_count(&self) -> u64 {
+		if self.has_feature_incompat(FEAT_INCOMPAT_64BIT) {
+			self.data.s_blocks_count as u64 | (self.ext.s_blocks_count_hi as u64) << 32
+		}
+		else {
+			self.data.s_blocks_count as u64
+		}
+	}
+	/// Number of free blocks (including the high word if FEAT_INCOMPAT_64BIT is set)
+	pub fn s_free_blocks_count(&self) -> u64 {
+		if self.has_feature_incompat(FEAT_INCOMPAT_64BIT) {
+			self.data.s_free_blocks_count as u64 | (self.ext.s_free_blocks_count_hi as u64) << 32
+		}
+		else {
+			self.data.s_free_blocks_count as u64
+		}
+	}
+	pub fn set_s_free_blocks_count(&mut self, v: u64) {
+		if self.has_feature_incompat(FEAT_INCOMPAT_64BIT) {
+			self.ext.s_free_blocks_count_hi = (v >> 32) as u32;
+		}
+		self.data.s_free_blocks_count = v as u32;
 	}
 }
 def_from_slice!{ Superblock }
+/// Offset of `s_checksum` (covering everything before it)
+pub const S_CHECKSUM_OFS: usize = 0x3FC;
+/// Metadata checksum algorithm (`s_checksum_type`)
+pub const EXT4_CRC32C_CHKSUM: u8 = 1;
 //#[allow(dead_code)]
 //// SAFE: Never called, and does POD transmutes
 //fn _sb_size() { unsafe {
@@ -325,11 +357,35 @@ pub const S_IROTH: u16 =  0o004;	// Global Read
 pub const S_IWOTH: u16 =  0o002;	// Global Write
 pub const S_IXOTH: u16 =  0o001;	// Global Execute
 
-pub const EXT4_INDEX_FL: u16 = 0x1000;	// i_flags: Directory uses a hashed btree
-
+/// Maximum link count, directories past this use 1 (with FEAT_RO_COMPAT_DIR_NLINK)
+pub const EXT4_LINK_MAX: u16 = 65000;
+
+/// Offset of `l_i_checksum_lo` (in `osd2`)
+pub const INODE_CSUM_LO_OFS: usize = 0x7C;
+/// Offset of `i_extra_isize`
+pub const INODE_EXTRA_ISIZE_OFS: usize = 0x80;
+/// Offset of `i_checksum_hi` (only present if `i_extra_isize` covers it)
+pub const INODE_CSUM_HI_OFS: usize = 0x82;
+/// Offset of `i_generation` (`i_version` above)
+pub const INODE_GENERATION_OFS: usize = 0x64;
+
+pub const EXT4_INDEX_FL: u32 = 0x1000;	// i_flags: Directory uses a hashed btree
+pub const EXT4_HUGE_FILE_FL: u32 = 0x40000;	// i_flags: `i_blocks` is in filesystem blocks (not 512 byte sectors)
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;	// i_flags: `i_block` contains an extent tree
+
+/// bg_flags: Inode bitmap and table aren't initialised (with metadata checksums)
+pub const BG_INODE_UNINIT: u16 = 0x1;
+/// bg_flags: Block bitmap isn't initialised, only the group's own metadata is in use (with metadata checksums)
+pub const BG_BLOCK_UNINIT: u16 = 0x2;
+/// bg_flags: Inode table has been zeroed
+pub const BG_INODE_ZEROED: u16 = 0x4;
+/// Offset of `bg_checksum` within a group descriptor
+pub const GROUP_DESC_CSUM_OFS: usize = 0x1E;
+
+/// Block group descriptor, the first 32 bytes are always present
 #[repr(C)]
 #[derive(Default,::kernel_derives::EncodedLE)]
-pub struct GroupDesc
+pub struct GroupDescLo
 {
 	pub bg_block_bitmap: u32,	// Blocks bitmap block
 	pub bg_inode_bitmap: u32,	// Inodes bitmap block
@@ -337,19 +393,159 @@ pub struct GroupDesc
 	pub bg_free_blocks_count: u16,	// Free blocks count
 	pub bg_free_inodes_count: u16,	// Free inodes count
 	pub bg_used_dirs_count: u16,	// Directories count
-	pub bg_pad: u16,	// Padding
-	pub bg_reserved: [u32; 3],	// Reserved
+	pub bg_flags: u16,	// Flags (BG_*)
+	pub bg_exclude_bitmap: u32,	// Snapshot exclusion bitmap
+	pub bg_block_bitmap_csum: u16,
+	pub bg_inode_bitmap_csum: u16,
+	pub bg_itable_unused: u16,	// Unused inodes count
+	pub bg_checksum: u16,
+}
+/// [FEAT_INCOMPAT_64BIT] High halves of the block group descriptor fields
+#[repr(C)]
+#[derive(Default,::kernel_derives::EncodedLE)]
+pub struct GroupDescHi
+{
+	pub bg_block_bitmap_hi: u32,
+	pub bg_inode_bitmap_hi: u32,
+	pub bg_inode_table_hi: u32,
+	pub bg_free_blocks_count_hi: u16,
+	pub bg_free_inodes_count_hi: u16,
+	pub bg_used_dirs_count_hi: u16,
+	pub bg_itable_unused_hi: u16,
+	pub bg_exclude_bitmap_hi: u32,
+	pub bg_block_bitmap_csum_hi: u16,
+	pub bg_inode_bitmap_csum_hi: u16,
+	pub _bg_reserved: u32,
+}
+/// Block group descriptor (32 or 64+ bytes on disk, depending on `s_desc_size`)
+#[derive(Default)]
+pub struct GroupDesc
+{
+	pub lo: GroupDescLo,
+	pub hi: GroupDescHi,
+}
+impl GroupDesc
+{
+	/// Decode from a slice of `s_group_desc_size` bytes
+	pub fn from_slice(mut r: &[u8]) -> Self {
+		let lo = ::kernel::lib::byteorder::EncodedLE::decode(&mut r).unwrap();
+		let hi = if r.len() >= 32 { ::kernel::lib::byteorder::EncodedLE::decode(&mut r).unwrap() } else { Default::default() };
+		GroupDesc { lo, hi }
+	}
+	/// Encode into a slice of `s_group_desc_size` bytes (only writing the high half if there's space)
+	pub fn write_to_slice(&self, mut r: &mut [u8]) {
+		::kernel::lib::byteorder::EncodedLE::encode(&self.lo, &mut r).unwrap();
+		if r.len() >= 32 {
+			::kernel::lib::byteorder::EncodedLE::encode(&self.hi, &mut r).unwrap();
+		}
+	}
+
+	pub fn block_bitmap(&self) -> u64 {
+		self.lo.bg_block_bitmap as u64 | (self.hi.bg_block_bitmap_hi as u64) << 32
+	}
+	pub fn inode_bitmap(&self) -> u64 {
+		self.lo.bg_inode_bitmap as u64 | (self.hi.bg_inode_bitmap_hi as u64) << 32
+	}
+	pub fn inode_table(&self) -> u64 {
+		self.lo.bg_inode_table as u64 | (self.hi.bg_inode_table_hi as u64) << 32
+	}
+	pub fn free_blocks_count(&self) -> u32 {
+		self.lo.bg_free_blocks_count as u32 | (self.hi.bg_free_blocks_count_hi as u32) << 16
+	}
+	pub fn set_free_blocks_count(&mut self, v: u32) {
+		self.lo.bg_free_blocks_count = v as u16;
+		self.hi.bg_free_blocks_count_hi = (v >> 16) as u16;
+	}
+	pub fn free_inodes_count(&self) -> u32 {
+		self.lo.bg_free_inodes_count as u32 | (self.hi.bg_free_inodes_count_hi as u32) << 16
+	}
+	pub fn set_free_inodes_count(&mut self, v: u32) {
+		self.lo.bg_free_inodes_count = v as u16;
+		self.hi.bg_free_inodes_count_hi = (v >> 16) as u16;
+	}
+	pub fn used_dirs_count(&self) -> u32 {
+		self.lo.bg_used_dirs_count as u32 | (self.hi.bg_used_dirs_count_hi as u32) << 16
+	}
+	pub fn set_used_dirs_count(&mut self, v: u32) {
+		self.lo.bg_used_dirs_count = v as u16;
+		self.hi.bg_used_dirs_count_hi = (v >> 16) as u16;
+	}
+	pub fn itable_unused(&self) -> u32 {
+		self.lo.bg_itable_unused as u32 | (self.hi.bg_itable_unused_hi as u32) << 16
+	}
+	pub fn set_itable_unused(&mut self, v: u32) {
+		self.lo.bg_itable_unused = v as u16;
+		self.hi.bg_itable_unused_hi = (v >> 16) as u16;
+	}
+	/// NOTE: The high half is only stored if the descriptor is large enough
+	pub fn set_block_bitmap_csum(&mut self, v: u32) {
+		self.lo.bg_block_bitmap_csum = v as u16;
+		self.hi.bg_block_bitmap_csum_hi = (v >> 16) as u16;
+	}
+	pub fn set_inode_bitmap_csum(&mut self, v: u32) {
+		self.lo.bg_inode_bitmap_csum = v as u16;
+		self.hi.bg_inode_bitmap_csum_hi = (v >> 16) as u16;
+	}
 }
-def_from_slice!{ GroupDesc }
 impl_fmt! {
 	Debug(self, f) for GroupDesc {
-		write!(f, "GroupDesc {{ addrs: (block_bm: {}, inode_bm: {}, inodes: {}), counts: (free_blk: {}, free_inodes: {}, used_dirs: {}) }}",
-			self.bg_block_bitmap, self.bg_inode_bitmap, self.bg_inode_table,
-			self.bg_free_blocks_count, self.bg_free_inodes_count, self.bg_used_dirs_count
+		write!(f, "GroupDesc {{ addrs: (block_bm: {}, inode_bm: {}, inodes: {}), counts: (free_blk: {}, free_inodes: {}, used_dirs: {}), flags: {:#x} }}",
+			self.block_bitmap(), self.inode_bitmap(), self.inode_table(),
+			self.free_blocks_count(), self.free_inodes_count(), self.used_dirs_count(),
+			self.lo.bg_flags
 			)
 	}
 }
 
+/// Header at the start of each extent tree node (in `i_block`, or a tree block)
+#[repr(C)]
+#[derive(Debug,Default,::kernel_derives::EncodedLE)]
+pub struct ExtentHeader
+{
+	/// Magic number (EXTENT_MAGIC)
+	pub eh_magic: u16,
+	/// Number of valid entries following the header
+	pub eh_entries: u16,
+	/// Capacity of this node
+	pub eh_max: u16,
+	/// Depth of the tree below this node (zero for leaves)
+	pub eh_depth: u16,
+	pub eh_generation: u32,
+}
+def_from_slice!{ ExtentHeader }
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+/// Size of an extent header, leaf entry, and index entry
+pub const EXTENT_ENTRY_SIZE: usize = 12;
+
+/// Extent tree leaf entry
+#[repr(C)]
+#[derive(Debug,Default,::kernel_derives::EncodedLE)]
+pub struct Extent
+{
+	/// First logical block covered
+	pub ee_block: u32,
+	/// Number of blocks covered (values above EXTENT_INIT_MAX_LEN indicate an unwritten extent)
+	pub ee_len: u16,
+	pub ee_start_hi: u16,
+	pub ee_start_lo: u32,
+}
+def_from_slice!{ Extent }
+/// Maximum length of an initialised extent
+pub const EXTENT_INIT_MAX_LEN: u16 = 1 << 15;
+
+/// Extent tree index (internal node) entry
+#[repr(C)]
+#[derive(Debug,Default,::kernel_derives::EncodedLE)]
+pub struct ExtentIdx
+{
+	/// First logical block covered by the child node
+	pub ei_block: u32,
+	pub ei_leaf_lo: u32,
+	pub ei_leaf_hi: u16,
+	pub _ei_unused: u16,
+}
+def_from_slice!{ ExtentIdx }
+
 
 
 #[repr(C)]
@@ -369,6 +565,35 @@ pub struct DirEnt
 	pub d_name: [u8],	// EXT2_NAME_LEN+1
 }
 pub const DIRENT_MIN_SIZE: usize = 8;
+/// [FEAT_RO_COMPAT_METADATA_CSUM] Size of the checksum tail at the end of leaf directory blocks
+///
+/// The tail looks like an unused entry (inode 0, rec_len 12, name_len 0) with a file type of `DIRENT_TAIL_FT`.
+pub const DIRENT_TAIL_SIZE: usize = 12;
+pub const DIRENT_TAIL_FT: u8 = 0xDE;
+
+/// d_type values (with FEAT_INCOMPAT_FILETYPE)
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHRDEV: u8 = 3;
+pub const FT_BLKDEV: u8 = 4;
+pub const FT_FIFO: u8 = 5;
+pub const FT_SOCK: u8 = 6;
+pub const FT_SYMLINK: u8 = 7;
+
+/// s_flags: Directory hashes were calculated using signed chars
+pub const EXT2_FLAGS_SIGNED_HASH: u32 = 0x1;
+/// s_flags: Directory hashes were calculated using unsigned chars
+pub const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+/// HTree hash algorithms (`dx_root_info.hash_version`)
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+/// Unsigned variants, not stored on disk (selected by EXT2_FLAGS_UNSIGNED_HASH)
+pub const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+pub const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+pub const DX_HASH_TEA_UNSIGNED: u8 = 5;
 
 impl DirEnt
 {
@@ -427,3 +652,91 @@ impl_fmt! {
 			)
 	}
 }
+
+
+// --------------------------------------------------------------------
+// Journal (jbd2) structures - NOTE: All big-endian
+// --------------------------------------------------------------------
+macro_rules! def_from_slice_be {
+	($t:ty) => {
+		impl $t {
+			pub fn from_slice(mut r: &[u8]) -> Self {
+				::kernel::lib::byteorder::EncodedBE::decode(&mut r).unwrap()
+			}
+			pub fn write_to_slice(&self, mut r: &mut [u8]) {
+				::kernel::lib::byteorder::EncodedBE::encode(self, &mut r).unwrap()
+			}
+		}
+	};
+}
+
+pub const JBD2_MAGIC: u32 = 0xC03B3998;
+
+pub const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+pub const JBD2_COMMIT_BLOCK: u32 = 2;
+pub const JBD2_SUPERBLOCK_V1: u32 = 3;
+pub const JBD2_SUPERBLOCK_V2: u32 = 4;
+pub const JBD2_REVOKE_BLOCK: u32 = 5;
+
+/// Header present at the start of every journal metadata block
+#[derive(Debug,Default,::kernel_derives::EncodedBE)]
+pub struct JournalHeader
+{
+	pub h_magic: u32,
+	pub h_blocktype: u32,
+	/// Transaction ID
+	pub h_sequence: u32,
+}
+def_from_slice_be!{ JournalHeader }
+
+/// Journal superblock (only the fields used, the remainder of the block is left untouched)
+#[derive(Debug,::kernel_derives::EncodedBE)]
+pub struct JournalSuperblock
+{
+	pub s_header: JournalHeader,
+	/// Journal block size (should match the filesystem)
+	pub s_blocksize: u32,
+	/// Total number of blocks in the journal
+	pub s_maxlen: u32,
+	/// First block of log information
+	pub s_first: u32,
+	/// First expected transaction ID
+	pub s_sequence: u32,
+	/// Block of the first transaction in the log, zero if the log is empty
+	pub s_start: u32,
+	pub s_errno: i32,
+	// Valid for V2 only
+	pub s_feature_compat: u32,
+	pub s_feature_incompat: u32,
+	pub s_feature_ro_compat: u32,
+	pub s_uuid: [u8; 16],
+	pub s_nr_users: u32,
+	pub s_dynsuper: u32,
+	pub s_max_transaction: u32,
+	pub s_max_trans_data: u32,
+	pub s_checksum_type: u8,
+	pub _s_padding2: [u8; 3],
+	/// [JBD2_FEATURE_INCOMPAT_FAST_COMMIT] Number of fast commit blocks
+	pub s_num_fc_blks: u32,
+}
+def_from_slice_be!{ JournalSuperblock }
+
+pub const JBD2_FEATURE_COMPAT_CHECKSUM: u32 = 1 << 0;
+pub const JBD2_FEATURE_INCOMPAT_REVOKE: u32 = 1 << 0;	// Revoke blocks are present
+pub const JBD2_FEATURE_INCOMPAT_64BIT: u32 = 1 << 1;	// Tags contain the high 32 bits of the block number
+pub const JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT: u32 = 1 << 2;
+pub const JBD2_FEATURE_INCOMPAT_CSUM_V2: u32 = 1 << 3;	// Tags contain a 16-bit checksum
+pub const JBD2_FEATURE_INCOMPAT_CSUM_V3: u32 = 1 << 4;	// Tags are 16 bytes, with a 32-bit checksum
+pub const JBD2_FEATURE_INCOMPAT_FAST_COMMIT: u32 = 1 << 5;
+
+pub const JBD2_FLAG_ESCAPE: u32 = 1;	// Data block had the journal magic in the first four bytes (replaced by zero)
+pub const JBD2_FLAG_SAME_UUID: u32 = 2;	// Tag isn't followed by a 16 byte UUID
+pub const JBD2_FLAG_DELETED: u32 = 4;
+pub const JBD2_FLAG_LAST_TAG: u32 = 8;	// Last tag in this descriptor block
+
+/// Checksum algorithm (`s_checksum_type`) for CSUM_V2/V3
+pub const JBD2_CRC32C_CHKSUM: u8 = 4;
+/// Offset of `s_checksum` in the journal superblock (covering the first 1024 bytes)
+pub const JBD2_SB_CSUM_OFS: usize = 0xFC;
+/// Offset of `h_chksum[0]` in a commit block
+pub const JBD2_COMMIT_CSUM_OFS: usize = 0x10;
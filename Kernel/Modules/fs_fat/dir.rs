@@ -43,6 +43,16 @@ impl node::NodeBase for DirNode {
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		// Sub-directories start with a "." entry pointing at themselves, the root has no entry
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.read();
+		Ok(match self.find_ent_by_cluster(self.start_cluster)?
+		{
+		Some(e) => e.metadata(),
+		None => metadata_for_attributes(on_disk::ATTR_DIRECTORY),
+		})
+	}
 }
 
 #[derive(Debug)]
@@ -162,6 +172,23 @@ pub fn update_file_size(fs: &FilesystemInner, file_cluster: ClusterNum, new_size
 	Err(vfs::Error::Unknown("FAT: update_file_size didn't find entry"))
 }
 
+/// Obtain the metadata for an open file (from its directory entry)
+pub fn file_metadata(fs: &ArefBorrow<FilesystemInner>, file_cluster: ClusterNum) -> Result<node::Metadata, ::vfs::Error> {
+	let dir_cluster = {
+		let lh_files = fs.open_files.read();
+		let file_info = lh_files.get(&file_cluster).ok_or(vfs::Error::Unknown("FAT: file_metadata called with file not recorded open"))?;
+		file_info.dir_cluster
+		};
+	let dir = DirNode::new(fs.reborrow(), dir_cluster);
+	let dir_info = fs.get_dir_info(dir_cluster);
+	let _lh_dir = dir_info.info.lock.read();
+	match dir.find_ent_by_cluster(file_cluster)?
+	{
+	Some(e) => Ok(e.metadata()),
+	None => Err(vfs::Error::Unknown("FAT: file_metadata didn't find entry")),
+	}
+}
+
 fn dir_clusters(fs: &super::FilesystemInner, start_cluster: ClusterNum) -> ClusterList<'_> {
 	let is_fixed_root = !is!(fs.ty, super::Size::Fat32) && start_cluster == fs.root_first_cluster;
 	if is_fixed_root {
@@ -328,6 +355,12 @@ impl DirEnt {
 				cluster: ClusterNum::new( (ent.cluster as u32) | (ent.cluster_hi as u32) << 16 ).unwrap_or( ClusterNum::new(0xFF_FFFF).unwrap() ),
 				size: ent.size,
 				attributes: ent.attribs,
+				creation_ds: ent.creation_ds,
+				creation_time: ent.creation_time,
+				creation_date: ent.creation_date,
+				accessed_date: ent.accessed_date,
+				modified_time: ent.modified_time,
+				modified_date: ent.modified_date,
 				})
 		}
 	}
@@ -345,12 +378,12 @@ impl DirEnt {
 				size: v.size,
 				cluster: v.cluster.get() as u16,
 				cluster_hi: (v.cluster.get() >> 16) as u16,
-				creation_ds: 0,
-				creation_date: 0,
-				creation_time: 0,
-				accessed_date: 0,
-				modified_date: 0,
-				modified_time: 0,
+				creation_ds: v.creation_ds,
+				creation_date: v.creation_date,
+				creation_time: v.creation_time,
+				accessed_date: v.accessed_date,
+				modified_date: v.modified_date,
+				modified_time: v.modified_time,
 				}.write(&mut dst);
 			},
 		DirEnt::Long(e) => {
@@ -376,9 +409,13 @@ struct DirEntShort {
 	cluster: ClusterNum,
 	size: u32,
 	attributes: u8,
-	//creation_time: ::kernel::time::Timestamp,
-	//modified_time: ::kernel::time::Timestamp,
-	//accessed_time: ::kernel::time::Timestamp,
+	/// Raw on-disk timestamps, kept so they're preserved when the entry is re-written
+	creation_ds: u8,
+	creation_time: u16,
+	creation_date: u16,
+	accessed_date: u16,
+	modified_time: u16,
+	modified_date: u16,
 }
 impl_fmt! {
 	Debug(self,f) for DirEntShort {
@@ -456,6 +493,35 @@ impl DirEntShort {
 	fn inode(&self, parent_dir: ClusterNum) -> node::InodeId {
 		super::InodeRef::new(self.cluster, parent_dir).to_id()
 	}
+	fn metadata(&self) -> node::Metadata {
+		// FAT stores local time with no zone, so these are treated as UTC
+		fn decode(date: u16, time: u16) -> node::Timestamp {
+			if date == 0 {
+				return 0;
+			}
+			node::timestamp_from_date(
+				1980 + (date >> 9) as i64, ((date >> 5) & 0xF) as u32, (date & 0x1F) as u32,
+				(time >> 11) as u32, ((time >> 5) & 0x3F) as u32, (time & 0x1F) as u32 * 2,
+				)
+		}
+		let mut rv = metadata_for_attributes(self.attributes);
+		rv.size = self.size as u64;
+		rv.created = decode(self.creation_date, self.creation_time) + self.creation_ds as i64 / 100;
+		rv.modified = decode(self.modified_date, self.modified_time);
+		rv.accessed = decode(self.accessed_date, 0);
+		rv
+	}
+}
+/// Metadata derived from just the attributes (FAT has no ownership, and only a read-only flag)
+///
+/// There's no execute flag either, so everything is reported as executable
+fn metadata_for_attributes(attributes: u8) -> node::Metadata {
+	let mode = 0o755;
+	node::Metadata {
+		link_count: 1,
+		mode: if attributes & on_disk::ATTR_READONLY != 0 { mode & !0o222 } else { mode },
+		..Default::default()
+		}
 }
 
 /// Decoded long file name
@@ -836,11 +902,17 @@ impl node::Dir for DirNode {
 		}
 		Ok( super::InodeRef::new(new_cluster, self.start_cluster).to_id() )
 	}
-	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> node::Result<()> {
-		todo!("DirNode::link('{:?}', {:#x})", name, node.get_id());
+	fn link(&self, _name: &ByteStr, _node: &dyn node::NodeBase) -> node::Result<()> {
+		// A FAT directory entry is the file, so there can only ever be one name
+		Err(vfs::Error::Unknown("FAT doesn't support hard links"))
 	}
 	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
-		todo!("DirNode::unlink('{:?}')", name);
+		log_notice!("DirNode::unlink('{:?}'): TODO", name);
+		Err(vfs::Error::Unknown("FAT: unlink not implemented"))
+	}
+	fn rename(&self, old_name: &ByteStr, _new_dir: &dyn node::Dir, new_name: &ByteStr) -> node::Result<()> {
+		log_notice!("DirNode::rename('{:?}', '{:?}'): TODO", old_name, new_name);
+		Err(vfs::Error::Unknown("FAT: rename not implemented"))
 	}
 }
 
@@ -864,6 +936,13 @@ impl CreateDirents {
 				node::NodeType::Dir => on_disk::ATTR_DIRECTORY,
 				node::NodeType::Symlink(_) => todo!("Symlink"),
 				},
+			// TODO: Fill with the current time
+			creation_ds: 0,
+			creation_time: 0,
+			creation_date: 0,
+			accessed_date: 0,
+			modified_time: 0,
+			modified_date: 0,
 			};
 		let short_name_checksum = short_ent.get_encoded_name().1.iter().copied().fold(0, |sum, b| {
 			u8::wrapping_add((sum >> 1) + (sum << 7), b)
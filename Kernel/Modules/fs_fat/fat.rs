@@ -172,25 +172,26 @@ impl super::FilesystemInner
 	fn get_fat_addr(&self, cluster: u32) -> (u64, usize, usize, u32) {
 		// - Determine what sector contains the requested FAT entry
 		let bs = self.vh.block_size();
-		let cps;
-		let (ofs, ent_len) = match self.ty
+		let (byte_ofs, ent_len, cps) = match self.ty
 			{
-			Size::Fat12 => {
-				cps = bs as u32 / 3 * 2;	// 2 per 3 bytes
-				( (cluster % cps) / 2 * 3, 3 )
-				},
-			Size::Fat16 => {
-				cps = bs as u32 / 2;
-				( (cluster % cps) * 2, 2, )
-				},
-			Size::Fat32 => {
-				cps = bs as u32 / 4;
-				( (cluster % cps) * 4, 4, )
-				},
+			// NOTE: FAT12 entry pairs don't align with sectors, so an entry can span two sectors
+			Size::Fat12 => ( cluster as u64 / 2 * 3, 3, bs as u32 / 3 * 2 ),	// 2 per 3 bytes
+			Size::Fat16 => ( cluster as u64 * 2, 2, bs as u32 / 2 ),
+			Size::Fat32 => ( cluster as u64 * 4, 4, bs as u32 / 4 ),
 			};
-		let sector_idx = self.first_fat_sector as u64 + (cluster / cps) as u64;
+		let sector_idx = self.first_fat_sector as u64 + byte_ofs / bs as u64;
+		let ofs = (byte_ofs % bs as u64) as usize;
 		//log_trace!("get_fat_addr({}): S {} ofs={} ent_len={} cps={}", cluster, sector_idx, ofs, ent_len, cps);
-		(sector_idx, ofs as usize, ent_len, cps)
+		(sector_idx, ofs, ent_len, cps)
+	}
+	/// Read raw bytes from the FAT, spilling into the next sector if required (for FAT12)
+	fn read_fat_bytes(&self, sector_idx: u64, ofs: usize, buf: &mut [u8]) -> Result<(), storage::IoError> {
+		let len1 = buf.len().min(self.vh.block_size() - ofs);
+		::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf[..len1]) )?;
+		if len1 < buf.len() {
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx + 1, 0, &mut buf[len1..]) )?;
+		}
+		Ok( () )
 	}
 
 	/// Read a FAT entry
@@ -199,7 +200,7 @@ impl super::FilesystemInner
 
 		// - Read entry from the FAT
 		let mut buf = [0; 4];
-		::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf[..ent_len]) )?;
+		self.read_fat_bytes(sector_idx, ofs, &mut buf[..ent_len])?;
 		let mut buf = &buf[..ent_len];
 
 		// - Extract the entry
@@ -215,6 +216,25 @@ impl super::FilesystemInner
 	fn set_fat_entry(&self, cluster: ClusterNum, exp_prev: FatEntry, new: FatEntry) -> Result< (), storage::IoError > {
 		let (sector_idx, ofs, ent_len, _cps) = self.get_fat_addr(cluster.get());
 
+		let bs = self.vh.block_size();
+		if ofs + ent_len > bs {
+			// A FAT12 entry pair spanning two sectors, update each half separately
+			let mut buf = [0; 3];
+			self.read_fat_bytes(sector_idx, ofs, &mut buf)?;
+			let val = (&buf[..]).read_uint::<LittleEndian>(3).unwrap() as u32;
+			let is_second = cluster.get() % 2 == 1;
+			let cur = FatEntry::from_fat12_outer(val, is_second);
+			if cur != exp_prev {
+				log_error!("FAT Check failure: {} expected {:?} got {:?}", cluster, exp_prev, cur);
+				return Err(storage::IoError::Unknown("FAT: Internal assertion failure"));
+			}
+			write_u24_le(&mut buf, new.to_fat12_outer(val, is_second));
+			let len1 = bs - ofs;
+			::kernel::futures::block_on(self.vh.edit(sector_idx, 1, |data| data[ofs..].copy_from_slice(&buf[..len1])))?;
+			::kernel::futures::block_on(self.vh.edit(sector_idx + 1, 1, |data| data[..3 - len1].copy_from_slice(&buf[len1..])))?;
+			return Ok( () );
+		}
+
 		// Use `block_cache`'s read/write locks
 		let changed = ::kernel::futures::block_on(self.vh.edit(sector_idx, 1, |buf| {
 			let buf = &mut buf[ofs..][..ent_len];
@@ -285,19 +305,21 @@ impl super::FilesystemInner
 		assert!(base % cps == 0);
 		// Clamp the end to CPS (should be already), and to the last cluster in the volume (may not be)
 		let end = end.min(cps).min(self.cluster_count as u32 - base);
+		if let Size::Fat12 = self.ty {
+			// FAT12 entries don't line up with sectors (and FAT12 volumes are small), so check each entry individually
+			for sub_idx in start.max(2 - base.min(2)) .. end {
+				let cluster = ClusterNum::new(base + sub_idx).unwrap();
+				if let FatEntry::Unallocated = self.get_fat_entry(cluster)? {
+					self.set_fat_entry(cluster, FatEntry::Unallocated, FatEntry::EndOfChain)?;
+					return Ok(Some(cluster));
+				}
+			}
+			return Ok(None);
+		}
 		::kernel::futures::block_on( self.vh.edit(sector_idx, 1, |data| {
 			match self.ty
 			{
-			Size::Fat12 => {
-				for sub_idx in start .. end {
-					let buf = &mut data[sub_idx as usize / 2 * 3..][..3];
-					let val = {&buf[..]}.read_uint::<LittleEndian>(3).unwrap() as u32;
-					if let FatEntry::Unallocated = FatEntry::from_fat12_outer(val, sub_idx % 2 == 1) {
-						write_u24_le(buf, FatEntry::EndOfChain.to_fat12_outer(val, sub_idx % 2 == 1));
-						return Some(ClusterNum::new(base + sub_idx).unwrap());
-					}
-				}
-				},
+			Size::Fat12 => unreachable!(),
 			Size::Fat16 => {
 				for sub_idx in start .. end {
 					let buf = &mut data[sub_idx as usize * 2..][..2];
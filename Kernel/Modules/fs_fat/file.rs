@@ -39,6 +39,9 @@ impl node::NodeBase for FileNode {
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		super::dir::file_metadata(&self.fs, self.first_cluster)
+	}
 }
 impl node::File for FileNode {
 	fn size(&self) -> u64 {
@@ -64,6 +64,7 @@ pub struct FilesystemInner
 	vh: ::block_cache::CachedVolume,
 	ty: Size,
 	
+	/// Cluster size in volume blocks (NOTE: All "sector" values are in volume blocks, not FAT sectors)
 	spc: usize,
 	cluster_size: usize,
 	/// Total number of data clusters
@@ -93,23 +94,24 @@ impl mount::Driver for Driver
 {
 	fn detect(&self, vol: &VolumeHandle) -> ::vfs::Result<usize> {
 		let bs = {
-			let mut bs = [0u8; 512];
+			// NOTE: The volume's block size might be larger than the boot sector
+			let mut bs = vec![0u8; vol.block_size().max(512)];
 			::kernel::futures::block_on( vol.read_blocks(0, &mut bs) )?;
-			on_disk::BootSect::read(&bs)
+			on_disk::BootSect::read(&bs[..512])
 			};
 		
 		let bps = bs.common().bps;
 		let spc = bs.common().spc;
 		let media_desc = bs.common().media_descriptor;
 		
-		if bps == 0 || spc == 0 || media_desc < 0xf0 {
+		if !(bps.is_power_of_two() && 512 <= bps && bps <= 4096) || spc == 0 || media_desc < 0xf0 {
 			Ok(0)
 		}
 		else {
 			Ok(1)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, _options: &mount::MountOptions) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
 		let vol = ::block_cache::CachedVolume::new(vol);
 
 		// Read the bootsector
@@ -118,8 +120,9 @@ impl mount::Driver for Driver
 			on_disk::BootSect::read(&mut &blk.data()[..512])
 			};
 		let bs_c = bs.common();
-		if bs_c.bps != 512 {
-			return Err(vfs::Error::Unknown("TODO: non 512-byte sector FAT"))
+		if !(bs_c.bps.is_power_of_two() && 512 <= bs_c.bps && bs_c.bps <= 4096) {
+			log_error!("Invalid FAT bootsector: bytes per sector {}", bs_c.bps);
+			return Err(vfs::Error::InconsistentFilesystem);
 		}
 		if bs_c.fat_count == 0 {
 			return Err(vfs::Error::Unknown("FAT Count is 0"));
@@ -163,6 +166,23 @@ impl mount::Driver for Driver
 			};
 		log_debug!("{:?} {} sectors, Size {}", fat_type, total_sectors,
 			SizePrinter((total_sectors*bs_c.bps as usize) as u64));
+
+		// Everything past this point addresses the volume in its own blocks, which might not match the FAT sector size
+		// - Larger sectors are just several blocks, smaller sectors only work if the layout lines up with blocks
+		let vol_bs = vol.block_size();
+		let to_blocks = |sectors: usize| -> vfs::Result<usize> {
+			if (sectors * bps) % vol_bs != 0 {
+				log_error!("FAT layout isn't aligned to the volume's {} byte blocks ({} sectors of {} bytes)", vol_bs, sectors, bps);
+				Err(vfs::Error::Unknown("FAT sector size incompatible with volume"))
+			}
+			else {
+				Ok(sectors * bps / vol_bs)
+			}
+			};
+		let spc = to_blocks(spc)?;
+		let first_fat_sector = to_blocks(bs_c.reserved_sect_count as usize)?;
+		let first_data_sector = to_blocks(first_data_sector)?;
+		let root_dir_sectors = to_blocks(root_dir_sectors)?;
 		
 		Ok(Box::new(Filesystem {
 			// SAFE: Saving to a Box, so won't move
@@ -171,7 +191,7 @@ impl mount::Driver for Driver
 				spc: spc,
 				cluster_size: spc * vol.block_size(),
 				cluster_count: cluster_count,
-				first_fat_sector: bs_c.reserved_sect_count as usize,
+				first_fat_sector: first_fat_sector,
 				first_data_sector: first_data_sector,
 				root_first_cluster: match fat_type {
 					Size::Fat32 => ClusterNum::new(bs.info32().unwrap().root_cluster)
@@ -264,4 +284,12 @@ impl mount::Filesystem for Filesystem
 			Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), r.first_cluster)))
 		}
 	}
+	fn flush(&self) -> ::vfs::Result<()> {
+		::kernel::futures::block_on(self.vh.flush())?;
+		Ok( () )
+	}
+	fn remount(&self, _options: &mount::MountOptions) -> ::vfs::Result<()> {
+		// No driver-specific options
+		Ok( () )
+	}
 }
@@ -10,7 +10,7 @@ use vfs::{mount, node};
 use kernel::metadevs::storage::{self,VolumeHandle};
 use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
 use kernel::lib::byteorder::{ByteOrder,LittleEndian};
-use kernel::lib::byte_str::ByteStr;
+use kernel::lib::byte_str::{ByteStr,ByteString};
 
 #[macro_use]
 extern crate kernel;
@@ -22,6 +22,18 @@ module_define!{FS_ISO9660, [VFS], init}
 
 //mod ondisk;
 
+/// Directory record flag: Entry is a directory
+const FLAG_DIRECTORY: u8 = 1 << 1;
+/// Directory record flag: Associated file (e.g. a resource fork), not listed
+const FLAG_ASSOCIATED: u8 = 1 << 2;
+/// Directory record flag: File continues in the next record
+const FLAG_MULTI_EXTENT: u8 = 1 << 7;
+
+/// Maximum number of volume descriptors searched before giving up
+const MAX_VOLUME_DESCRIPTORS: usize = 64;
+/// Maximum number of SUSP continuation areas followed for a single record
+const MAX_CONTINUATION_AREAS: usize = 16;
+
 struct Driver;
 static S_DRIVER: Driver = Driver;
 
@@ -38,7 +50,12 @@ struct InstanceInner
 	root_lba: u32,
 	root_size: u32,
 
+	/// Names are UCS-2 (the root is from a Joliet supplementary volume descriptor)
+	joliet: bool,
+	/// SUSP (and Rock Ridge) is in use, with this many bytes skipped at the start of each system use area
 	susp_len_skip: Option<u8>,
+	/// Metadata from the root directory's "." entry
+	root_metadata: node::Metadata,
 }
 
 fn init()
@@ -64,69 +81,117 @@ impl mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, options: &mount::MountOptions) -> vfs::Result<Box<dyn mount::Filesystem>> {
 		// For this to work properly, the block size must evenly divide 2048
 		if 2048 % vol.block_size() != 0 {
 			return Err( vfs::Error::Unknown("Can't mount ISO9660 with sector size not a factor of 2048"/*, vol.block_size()*/) );
 		}
 		let scale = 2048 / vol.block_size();
-		
-		// Search the start of the disk for the primary volume descriptor
-		// - TODO: Limit the number of sectors searched.
+
+		// Search the start of the disk for the primary volume descriptor, and a Joliet supplementary descriptor
+		let mut pvd = None;
+		let mut joliet_root = None;
 		let mut block = vec![0u8; 2048];
-		for sector in 16 .. 
+		for sector in 16 .. 16 + MAX_VOLUME_DESCRIPTORS
 		{
 			::kernel::futures::block_on(vol.read_blocks((sector*scale) as u64, &mut block))?;
 			if &block[1..6] != b"CD001" {
 				return Err( vfs::Error::Unknown("Invalid volume descriptor present") );
 			}
 			else if block[0] == 255 {
-				return Err( vfs::Error::Unknown("Can't find ISO9660 primary volume descriptor") );
+				break ;
 			}
 			else if block[0] == 0x01 {
-				// Found it!
-				break ;
+				if pvd.is_none() {
+					pvd = Some(block.clone());
+				}
+			}
+			else if block[0] == 0x02 {
+				// Supplementary descriptor, Joliet is indicated by the UCS-2 escape sequences (levels 1-3)
+				match &block[88..91]
+				{
+				b"%/@" | b"%/C" | b"%/E" => {
+					if joliet_root.is_none() {
+						joliet_root = Some( (LittleEndian::read_u32(&block[156+ 2..]), LittleEndian::read_u32(&block[156+10..])) );
+					}
+					},
+				_ => {},
+				}
 			}
 			else {
 				// Try the next one
 			}
 		}
+		let block = match pvd
+			{
+			Some(v) => v,
+			None => return Err( vfs::Error::Unknown("Can't find ISO9660 primary volume descriptor") ),
+			};
 		//::kernel::logging::hex_dump("ISO966 PVD", &block);
-		
+
 		// Obtain the logical block size (different from medium sector size)
-		let lb_size = LittleEndian::read_u16(&block[128..]);
+		let lb_size = LittleEndian::read_u16(&block[128..]) as usize;
+		if !lb_size.is_power_of_two() || lb_size < 512 || lb_size > 2048 || lb_size % vol.block_size() != 0 {
+			log_error!("Unsupported logical block size {} (sector size {})", lb_size, vol.block_size());
+			return Err( vfs::Error::Unknown("ISO9660 logical block size unsupported") );
+		}
 		// Extract the root directory entry
 		// - We want the LBA and byte length
 		let root_lba  = LittleEndian::read_u32(&block[156+ 2..]);
 		let root_size = LittleEndian::read_u32(&block[156+10..]);
-		
+
 		log_debug!("lb_size = {}, root = {:#x} + {:#x} bytes", lb_size, root_lba, root_size);
-	
-	
+
+
 		let mut inner = InstanceInner {
 			vh: ::block_cache::CachedVolume::new(vol),
-			lb_size: lb_size as usize,
+			lb_size: lb_size,
 			root_lba: root_lba,
 			root_size: root_size,
+			joliet: false,
 			susp_len_skip: None,
+			root_metadata: Default::default(),
 			};
 
 		// Determine if SUSP is in use (used for RockRidge extensions)
-		inner.susp_len_skip = {
-			let mut it = DirSector::new(&inner, ::kernel::futures::block_on(inner.get_sector(root_lba))?, 0 );
-			let first_ent = match it.next()?
-				{
-				None => return Err(vfs::Error::InconsistentFilesystem),
-				Some(v) => v,
-				};
-			if first_ent.sys_use.len() >= 6 && &first_ent.sys_use[..6] == b"SP\x07\x01\xBE\xEF" {
-				Some(first_ent.sys_use[6])
+		let susp_len_skip = {
+			let sector = ::kernel::futures::block_on(inner.get_sector(root_lba))?;
+			let len = sector[0] as usize;
+			if len < 34 || len > sector.len() {
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			let su = record_system_use(&sector[..len]);
+			if su.len() >= 7 && &su[..6] == b"SP\x07\x01\xBE\xEF" {
+				Some(su[6])
 			}
 			else {
 				None
 			}
 			};
-		
+
+		// Pick the directory hierarchy to use
+		// - Rock Ridge is preferred as it also carries permissions and symlinks, then Joliet for long names
+		if susp_len_skip.is_some() && !options.has("norock") {
+			log_notice!("Using Rock Ridge names");
+			inner.susp_len_skip = susp_len_skip;
+		}
+		else if let Some((lba, size)) = joliet_root.filter(|_| !options.has("nojoliet")) {
+			log_notice!("Using Joliet names");
+			inner.root_lba = lba;
+			inner.root_size = size;
+			inner.joliet = true;
+		}
+
+		// Read the "." entry now that the hierarchy is known, to get the root's metadata
+		inner.root_metadata = {
+			let mut it = DirIter::new(&inner, inner.root_lba, inner.root_size, 0);
+			match it.next()?
+			{
+			None => return Err(vfs::Error::InconsistentFilesystem),
+			Some((_, v)) => v.metadata,
+			}
+			};
+
 		// SAFE: Stored in a box, and not moved out.
 		Ok( Box::new( Instance(unsafe { ArefInner::new( inner ) }) ) )
 	}
@@ -139,43 +204,52 @@ impl mount::Filesystem for Instance
 	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		if id == 0 {
-			Some(Dir::new_node(self.0.borrow(), self.root_lba, self.root_size) )
+			Some(Dir::new_node(self.0.borrow(), id, self.root_lba, self.root_size, self.root_metadata.clone()) )
 		}
 		else {
-			// Look up (or read) parent directory to obtain the info
+			// Inode numbers are the byte address of the directory record
 			let (sector, ofs) = ::kernel::lib::num::div_rem(id as u64, self.lb_size as u64);
-			let blk = match ::kernel::futures::block_on(self.get_sector(sector as u32))
-				{
-				Ok(v) => v,
-				Err(_) => return None,
-				};
-			let mut it = DirSector::new(&self.0, blk, ofs as usize);
+			// - The size only matters if the record is multi-extent, and they don't cross the end of the directory
+			let mut it = DirIter::new(&self.0, sector as u32, u32::MAX, ofs as usize);
 			let ent = match it.next()
 				{
-				Ok(Some(v)) => v,
+				Ok(Some((_, v))) => v,
 				Ok(None) => return None,
 				Err(_) => return None,
 				};
-			if ent.name.len() == 0 {
+			if ent.is_self_or_parent || ent.flags & FLAG_ASSOCIATED != 0 {
 				None
 			}
-			else {
-				if ent.flags & (1 << 7) != 0 {
-					// Multi-extent file!
-					None
-				}
-				else if ent.flags & (1 << 1) != 0 {
-					Some(Dir::new_node(self.0.borrow(), ent.start, ent.size))
-				}
-				else if ent.flags & 0x64 != 0 {
-					None
-				}
-				else {
-					Some(File::new_node(self.0.borrow(), ent.start, ent.size))
+			else if let Some(target) = ent.symlink {
+				Some(Symlink::new_node(id, target, ent.metadata))
+			}
+			else if let Some(lba) = ent.child_link {
+				// Rock Ridge relocated directory, the real directory's "." entry has its size
+				let mut it = DirIter::new(&self.0, lba, self.lb_size as u32, 0);
+				match it.next()
+				{
+				Ok(Some((_, dot))) => Some(Dir::new_node(self.0.borrow(), id, dot.start, dot.size as u32, ent.metadata)),
+				_ => None,
 				}
 			}
+			else if ent.flags & FLAG_DIRECTORY != 0 {
+				Some(Dir::new_node(self.0.borrow(), id, ent.start, ent.size as u32, ent.metadata))
+			}
+			else if ent.flags & 0x60 != 0 {
+				None
+			}
+			else {
+				Some(File::new_node(self.0.borrow(), id, ent.extents, ent.size, ent.metadata))
+			}
 		}
 	}
+	fn flush(&self) -> vfs::Result<()> {
+		// Read-only, nothing to write back
+		Ok( () )
+	}
+	fn remount(&self, _options: &mount::MountOptions) -> vfs::Result<()> {
+		Ok( () )
+	}
 }
 struct Sector<'a>(::block_cache::BlockHandleRead<'a>,u16,u16);
 impl<'a> ::core::ops::Deref for Sector<'a> {
@@ -194,15 +268,68 @@ impl InstanceInner
 		let hwsector = sector as usize * hwsects_per_lb;
 		self.vh.read_blocks( hwsector as u64, buf).await?;
 		Ok( () )
-		
+
 	}
-	/// Read a metadata sector via a cache
+	/// Read a metadata sector (logical block) via a cache
 	async fn get_sector(&self, sector: u32) -> Result<Sector<'_>, storage::IoError> {
 		assert!(sector > 0);
-		
-		let blk = self.vh.get_block(sector as u64).await?;
-		let ofs = (sector as u64 - blk.index()) as usize * self.vh.block_size();
-		Ok( Sector(blk, ofs as u16, self.vh.block_size() as u16) )
+
+		// - Logical blocks are at most 2048 bytes and aligned, so they never span cache blocks
+		let hwsects_per_lb = self.lb_size / self.vh.block_size();
+		let hwsector = sector as u64 * hwsects_per_lb as u64;
+		let blk = self.vh.get_block(hwsector).await?;
+		let ofs = (hwsector - blk.index()) as usize * self.vh.block_size();
+		Ok( Sector(blk, ofs as u16, self.lb_size as u16) )
+	}
+
+	/// Read `buf.len()` bytes from `ofs` bytes into the extent starting at `lba`
+	fn read_data(&self, lba: u32, ofs: u64, mut buf: &mut [u8]) -> node::Result<()> {
+		let (sector, ofs) = ::kernel::lib::num::div_rem(ofs, self.lb_size as u64);
+		let mut sector = lba + sector as u32;
+		let ofs = ofs as usize;
+
+		// 1. Leading (or only) partial sector
+		if buf.len() > 0 && (ofs > 0 || buf.len() < self.lb_size) {
+			log_trace!("reading partial at {} (ofs={})", sector, ofs);
+			let mut tmp = vec![0u8; self.lb_size];
+			::kernel::futures::block_on(self.read_sector(sector, &mut tmp))?;
+			let len = ::core::cmp::min(buf.len(), self.lb_size - ofs);
+			::kernel::lib::split_off_front_mut(&mut buf, len).unwrap().clone_from_slice(&tmp[ofs..][..len]);
+			sector += 1;
+		}
+
+		// 2. Inner
+		if buf.len() >= self.lb_size {
+			let sector_count = buf.len() / self.lb_size;
+			log_trace!("reading {} sectors worth of data at {}", sector_count, sector);
+			let bytes = sector_count * self.lb_size;
+			let dst = ::kernel::lib::split_off_front_mut(&mut buf, bytes).unwrap();
+			::kernel::futures::block_on(self.read_sector(sector, dst))?;
+			sector += sector_count as u32;
+		}
+
+		// 3. Trailing
+		if buf.len() > 0 {
+			log_trace!("reading {} bytes trailing at {}", buf.len(), sector);
+			let mut tmp = vec![0; self.lb_size];
+			::kernel::futures::block_on(self.read_sector(sector, &mut tmp))?;
+			let len = buf.len();
+			buf.clone_from_slice(&tmp[..len]);
+		}
+		Ok( () )
+	}
+
+	/// Read a SUSP continuation area
+	fn read_continuation(&self, lba: u32, ofs: u32, len: u32) -> node::Result<Vec<u8>> {
+		let sector = ::kernel::futures::block_on(self.get_sector(lba))?;
+		match sector.get(ofs as usize ..).and_then(|v| v.get(.. len as usize))
+		{
+		Some(v) => Ok(v.to_vec()),
+		None => {
+			log_warning!("SUSP continuation area {:#x}+{}+{} out of bounds", lba, ofs, len);
+			Err(vfs::Error::InconsistentFilesystem)
+			},
+		}
 	}
 }
 
@@ -210,32 +337,40 @@ impl InstanceInner
 struct File
 {
 	fs: ArefBorrow<InstanceInner>,
-	first_lba: u32,
-	size: u32,
+	inode: node::InodeId,
+	/// Extents making up the file (starting LBA and byte length)
+	extents: Vec<(u32,u32)>,
+	size: u64,
+	metadata: node::Metadata,
 }
 impl File
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, inode: node::InodeId, extents: Vec<(u32,u32)>, size: u64, metadata: node::Metadata) -> node::Node {
 		node::Node::File( Box::new( File {
 			fs: fs,
-			first_lba: first_lba,
+			inode: inode,
+			extents: extents,
 			size: size,
+			metadata: metadata,
 			} ) )
 	}
 }
 impl node::NodeBase for File
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("File::get_id")
+		self.inode
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.metadata.clone() )
+	}
 }
 impl node::File for File
 {
 	fn size(&self) -> u64 {
-		self.size as u64
+		self.size
 	}
 	fn truncate(&self, _newsize: u64) -> node::Result<u64> {
 		Err(vfs::Error::ReadOnlyFilesystem)
@@ -244,46 +379,28 @@ impl node::File for File
 		Err(vfs::Error::ReadOnlyFilesystem)
 	}
 	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
-		if ofs > self.size as u64 {
+		if ofs > self.size {
 			Err(vfs::Error::InvalidParameter)
 		}
 		else {
-			let len = ::core::cmp::min( buf.len(),  (self.size as u64 - ofs) as usize );
-			
-			let (sector, ofs) = ::kernel::lib::num::div_rem(ofs, self.fs.lb_size as u64);
-			let mut sector = sector as u32;
-			let ofs = ofs as usize;
-
-			let mut read = 0;
-			// 1. Leading
-			if ofs > 0 {
-				log_trace!("ofs {} > 0, reading partial at {}", ofs, sector);
-				let mut tmp = vec![0u8; self.fs.lb_size];
-				::kernel::futures::block_on(self.fs.read_sector(self.first_lba + sector, &mut tmp))?;
-
-				assert!(ofs < self.fs.lb_size);
-				sector += 1;
-				buf[..len].clone_from_slice(&tmp[ofs..]);
-				read = len;
-			}
-
-			// 2. Inner
-			if len - read >= self.fs.lb_size {
-				let sector_count = (len - read) / self.fs.lb_size;
-				log_trace!("reading {} sectors worth of data at {} (len = {}, read = {})", sector_count, sector, len, read);
-				let bytes = sector_count * self.fs.lb_size;
-				::kernel::futures::block_on(self.fs.read_sector(self.first_lba + sector, &mut buf[read..][..bytes]))?;
-				sector += sector_count as u32;
-				read += bytes;
-			}
-
-			// 3. Trailing
-			if read < len {
-				log_trace!("reading {} bytes trailing at {}", len - read, sector);
-				let mut tmp = vec![0; self.fs.lb_size];
-				::kernel::futures::block_on(self.fs.read_sector(self.first_lba + sector, &mut tmp))?;
+			let len = ::core::cmp::min( buf.len() as u64, self.size - ofs ) as usize;
+			let mut buf = &mut buf[..len];
 
-				buf[read..].clone_from_slice(&tmp);
+			// Read from each extent that overlaps the requested range
+			let mut extent_base = 0;
+			for &(lba, extent_len) in &self.extents
+			{
+				if buf.len() == 0 {
+					break ;
+				}
+				let extent_end = extent_base + extent_len as u64;
+				let pos = ofs + (len - buf.len()) as u64;
+				if pos < extent_end {
+					let bytes = ::core::cmp::min(buf.len() as u64, extent_end - pos) as usize;
+					let dst = ::kernel::lib::split_off_front_mut(&mut buf, bytes).unwrap();
+					self.fs.read_data(lba, pos - extent_base, dst)?;
+				}
+				extent_base = extent_end;
 			}
 
 			Ok( len )
@@ -299,43 +416,54 @@ impl node::File for File
 struct Dir
 {
 	fs: ArefBorrow<InstanceInner>,
+	inode: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	metadata: node::Metadata,
 }
 impl Dir
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, inode: node::InodeId, first_lba: u32, size: u32, metadata: node::Metadata) -> node::Node {
 		node::Node::Dir( Box::new( Dir {
 			fs: fs,
+			inode: inode,
 			first_lba: first_lba,
 			size: size,
+			metadata: metadata,
 			} ) )
 	}
 }
 impl node::NodeBase for Dir
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("Dir::get_id")
+		self.inode
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.metadata.clone() )
+	}
 }
 impl node::Dir for Dir
 {
 	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId>
 	{
-		for sector in 0 .. ::kernel::lib::num::div_up(self.size, self.fs.lb_size as u32)
+		let mut it = DirIter::new(&self.fs, self.first_lba, self.size, 0);
+		while let Some((inode, ent)) = it.next()?
 		{
-			let mut it = DirSector::new(&self.fs, ::kernel::futures::block_on(self.fs.get_sector(self.first_lba + sector))?, 0); 
-
-			while let Some(ent) = it.next()?
-			{
-				if ent.name == name.as_bytes()
-				{
-					let inode = (self.first_lba + sector) as u64 * self.fs.lb_size as u64 + ent.this_ofs as u64;
-					return Ok( inode );
+			if !ent.is_visible() {
+				continue ;
+			}
+			// Plain ISO9660 names are upper-case only, so compare those without case
+			let is_match = if ent.iso_name {
+					ent.name.eq_ignore_ascii_case(name.as_bytes())
 				}
+				else {
+					ent.name == name.as_bytes()
+				};
+			if is_match {
+				return Ok( inode );
 			}
 		}
 
@@ -343,35 +471,21 @@ impl node::Dir for Dir
 	}
 	fn read(&self, ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize>
 	{
-		let max_sectors = ::kernel::lib::num::div_up(self.size, self.fs.lb_size as u32);
-		let end_ofs = self.size as usize % self.fs.lb_size;
-
-		let (sector, mut ofs) = (ofs / self.fs.lb_size, ofs % self.fs.lb_size);
-		
-		for sector in sector as u32 .. max_sectors
+		let mut it = DirIter::new(&self.fs, self.first_lba, self.size, ofs);
+		while let Some((inode, ent)) = it.next()?
 		{
-			let mut it = DirSector::new(&self.fs, ::kernel::futures::block_on(self.fs.get_sector(self.first_lba + sector))?,  ofs );
-			ofs = 0;
-
-			while let Some(ent) = it.next()?
+			if ent.is_visible()
 			{
-				if ent.name.len() > 0 && ent.name != b"\0" && ent.name != b"\x01"
-				{
-					log_debug!("ent = {:?}", ent);
-					let inode = (self.first_lba + sector) as u64 * self.fs.lb_size as u64 + ent.this_ofs as u64;
-					if ! callback(inode, &mut ent.name.iter().cloned()) {
-						return Ok( sector as usize * self.fs.lb_size + ent.next_ofs );
-					}
-				}
-				if ent.next_ofs == end_ofs {
-					break;
+				log_debug!("ent = {:?}", ent);
+				if ! callback(inode, &mut ent.name.iter().cloned()) {
+					return Ok( it.ofs );
 				}
 			}
 		}
-		
-		Ok( max_sectors as usize * self.fs.lb_size )
+
+		Ok( self.size as usize )
 	}
-	
+
 	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> node::Result<node::InodeId> {
 		// ISO9660 is readonly
 		Err( vfs::Error::ReadOnlyFilesystem )
@@ -384,114 +498,369 @@ impl node::Dir for Dir
 		// ISO9660 is readonly
 		Err( vfs::Error::ReadOnlyFilesystem )
 	}
+	fn rename(&self, _old_name: &ByteStr, _new_dir: &dyn node::Dir, _new_name: &ByteStr) -> node::Result<()> {
+		// ISO9660 is readonly
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
 }
 
-
-#[derive(Default)]
-struct DirEnt<'a>
+// --------------------------------------------------------------------
+/// Rock Ridge symbolic link
+struct Symlink
+{
+	inode: node::InodeId,
+	target: Vec<u8>,
+	metadata: node::Metadata,
+}
+impl Symlink
+{
+	fn new_node(inode: node::InodeId, target: Vec<u8>, metadata: node::Metadata) -> node::Node {
+		node::Node::Symlink( Box::new( Symlink {
+			inode: inode,
+			target: target,
+			metadata: metadata,
+			} ) )
+	}
+}
+impl node::NodeBase for Symlink
+{
+	fn get_id(&self) -> node::InodeId {
+		self.inode
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.metadata.clone() )
+	}
+}
+impl node::Symlink for Symlink
 {
-	this_ofs: usize,
-	next_ofs: usize,
+	fn read(&self) -> ByteString {
+		ByteString::from( ByteStr::new(&self.target) )
+	}
+}
 
+
+// --------------------------------------------------------------------
+struct DirEnt
+{
 	flags: u8,
 	start: u32,
-	size: u32,
-	name: &'a [u8],
-	sys_use: &'a [u8],
+	/// Total size (of all extents)
+	size: u64,
+	/// Extents of the file, only more than one if the file is multi-extent
+	extents: Vec<(u32,u32)>,
+	name: Vec<u8>,
+	/// `name` is a plain ISO9660 identifier
+	iso_name: bool,
+	/// This is the "." or ".." entry
+	is_self_or_parent: bool,
+
+	/// Rock Ridge symbolic link target
+	symlink: Option<Vec<u8>>,
+	/// Rock Ridge relocated directory location
+	child_link: Option<u32>,
+	/// Rock Ridge: This is a relocated directory, so shouldn't be visible here
+	relocated: bool,
+	metadata: node::Metadata,
 }
-impl<'a> ::core::fmt::Debug for DirEnt<'a> {
+impl ::core::fmt::Debug for DirEnt {
 	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
 		write!(f, "DirEnt {{ start: {:#x}, size: {:#x}, name: {:?} }}",
-			self.start, self.size, ByteStr::new(self.name)
+			self.start, self.size, ByteStr::new(&self.name)
 			)
 	}
 }
 
-impl<'a> DirEnt<'a>
+impl DirEnt
 {
+	/// Decode a 7-byte directory entry recording date
+	fn decode_date(d: &[u8]) -> node::Timestamp {
+		if d[..6].iter().all(|&v| v == 0) {
+			return 0;
+		}
+		// Offset from GMT in 15 minute intervals
+		let gmt_offset = d[6] as i8 as i64 * 15 * 60;
+		node::timestamp_from_date(1900 + d[0] as i64, d[1] as u32, d[2] as u32, d[3] as u32, d[4] as u32, d[5] as u32) - gmt_offset
+	}
+	/// Decode a 17-byte (ASCII digits) volume descriptor style date
+	fn decode_date_long(d: &[u8]) -> node::Timestamp {
+		fn digits(d: &[u8]) -> u32 {
+			d.iter().fold(0, |acc, &c| acc * 10 + (c.wrapping_sub(b'0') % 10) as u32)
+		}
+		if d[..16].iter().all(|&v| v == b'0' || v == 0) {
+			return 0;
+		}
+		let gmt_offset = d[16] as i8 as i64 * 15 * 60;
+		node::timestamp_from_date(digits(&d[0..4]) as i64, digits(&d[4..6]), digits(&d[6..8]), digits(&d[8..10]), digits(&d[10..12]), digits(&d[12..14])) - gmt_offset
+	}
+
+	/// Should this entry be listed/looked up
+	fn is_visible(&self) -> bool {
+		!self.is_self_or_parent && !self.relocated && self.flags & FLAG_ASSOCIATED == 0
+	}
+
+	/// Parse a directory record
+	fn parse(fs: &InstanceInner, ent: &[u8]) -> node::Result<DirEnt> {
+		let len = ent.len();
+		let namelen = ent[32] as usize;
+		if 33 + namelen > len {
+			log_warning!("Name overruns end of entry");
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let raw_name = &ent[33..][..namelen];
+
+		let recorded = DirEnt::decode_date(&ent[18..25]);
+		let start = LittleEndian::read_u32(&ent[2..]);
+		let size = LittleEndian::read_u32(&ent[10..]);
+		let mut rv = DirEnt {
+			flags: ent[25],
+			start: start,
+			size: size as u64,
+			extents: vec![ (start, size) ],
+			name: Vec::new(),
+			iso_name: false,
+			is_self_or_parent: raw_name == b"\0" || raw_name == b"\x01",
+			symlink: None,
+			child_link: None,
+			relocated: false,
+			metadata: node::Metadata {
+				size: size as u64,
+				link_count: 1,
+				// No execute flag without Rock Ridge, so treat all files as executable
+				mode: 0o555,
+				created: recorded,
+				modified: recorded,
+				accessed: recorded,
+				..Default::default()
+				},
+			};
+
+		if let Some(skip) = fs.susp_len_skip {
+			let su = record_system_use(ent);
+			let skip = skip as usize;
+			if su.len() < skip {
+				log_warning!("System use area smaller than SUSP skip value");
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			rv.apply_susp(fs, &su[skip..])?;
+		}
+
+		if rv.is_self_or_parent {
+			rv.name = raw_name.to_vec();
+		}
+		else if rv.name.len() > 0 {
+			// Name was provided by Rock Ridge, use as-is
+		}
+		else if fs.joliet {
+			// UCS-2 (big endian), converted to UTF-8
+			let units = raw_name.chunks(2).filter(|v| v.len() == 2).map(|v| u16::from_be_bytes([v[0], v[1]]));
+			for c in ::core::char::decode_utf16(units) {
+				let c = c.unwrap_or(::core::char::REPLACEMENT_CHARACTER);
+				let mut buf = [0; 4];
+				rv.name.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+			}
+			rv.name = strip_version(&rv.name).to_vec();
+		}
+		else {
+			rv.name = strip_version(raw_name).to_vec();
+			rv.iso_name = true;
+		}
+		Ok(rv)
+	}
+
+	/// Apply SUSP/Rock Ridge entries (following continuation areas)
+	fn apply_susp(&mut self, fs: &InstanceInner, su: &[u8]) -> node::Result<()> {
+		let mut cont_area;
+		let mut area = su;
+		// Set if the last symlink component was flagged to continue into the next
+		let mut sl_continue = false;
+		for _ in 0 .. MAX_CONTINUATION_AREAS
+		{
+			let mut next_area = None;
+			for item in SuspIterator(area)
+			{
+				//log_trace!("item={:?}", item);
+				match item
+				{
+				SuspItem::ContinuationEntry(lba, ofs, len) => next_area = Some( (lba, ofs, len) ),
+				// NOTE: Flag 0x1 means the name continues in the next NM, 0x2/0x4 are for "." and ".."
+				SuspItem::AlternateName(flags, part) => if flags & 0x6 == 0 {
+					self.name.extend_from_slice(part);
+					},
+				SuspItem::PosixMode { mode, n_links, uid, gid, .. } => {
+					self.metadata.mode = (mode & 0o7777) as u16;
+					self.metadata.link_count = n_links;
+					self.metadata.owner = uid;
+					self.metadata.group = gid;
+					},
+				SuspItem::Timestamps { flags, mut data } => {
+					// Timestamps are present in the order of the flag bits, either in short or long form
+					let ts_len = if flags & 0x80 != 0 { 17 } else { 7 };
+					for bit in 0 .. 7
+					{
+						if flags & (1 << bit) == 0 {
+							continue ;
+						}
+						let Some(d) = ::kernel::lib::split_off_front(&mut data, ts_len) else { break ; };
+						let ts = if ts_len == 17 { DirEnt::decode_date_long(d) } else { DirEnt::decode_date(d) };
+						match bit
+						{
+						0 => self.metadata.created = ts,
+						1 => self.metadata.modified = ts,
+						2 => self.metadata.accessed = ts,
+						_ => {},
+						}
+					}
+					},
+				SuspItem::Symlink(_flags, mut components) => {
+					let target = self.symlink.get_or_insert_with(Vec::new);
+					while components.len() >= 2
+					{
+						let cflags = components[0];
+						let clen = components[1] as usize;
+						let Some(content) = components.get(2..2+clen) else { break ; };
+						components = &components[2+clen..];
+
+						if !sl_continue && target.len() > 0 && target.last() != Some(&b'/') {
+							target.push(b'/');
+						}
+						if cflags & 0x08 != 0 {
+							// Root
+							target.push(b'/');
+						}
+						else if cflags & 0x04 != 0 {
+							target.extend_from_slice(b"..");
+						}
+						else if cflags & 0x02 != 0 {
+							target.push(b'.');
+						}
+						else {
+							target.extend_from_slice(content);
+						}
+						sl_continue = cflags & 0x01 != 0;
+					}
+					},
+				SuspItem::ChildLink(lba) => self.child_link = Some(lba),
+				SuspItem::Relocated => self.relocated = true,
+				_ => {},
+				}
+			}
+
+			let Some((lba, ofs, len)) = next_area else {
+				return Ok( () );
+				};
+			cont_area = fs.read_continuation(lba, ofs, len)?;
+			area = &cont_area;
+		}
+		log_warning!("Too many SUSP continuation areas, ignoring the rest");
+		Ok( () )
+	}
+}
+
+/// Get the system use area of a directory record
+fn record_system_use(ent: &[u8]) -> &[u8] {
+	let namelen = ent[32] as usize;
+	// There's a padding byte after even length names
+	let ofs = 33 + namelen + (1 - namelen % 2);
+	ent.get(ofs ..).unwrap_or(&[])
+}
+/// Strip the version number (and trailing dot of an extension-less name) from an identifier
+fn strip_version(name: &[u8]) -> &[u8] {
+	let name = match name.iter().position(|&c| c == b';')
+		{
+		Some(p) => &name[..p],
+		None => name,
+		};
+	match name.split_last()
+	{
+	Some((b'.', rest)) if rest.len() > 0 => rest,
+	_ => name,
+	}
 }
 
-struct DirSector<'a> {
+/// Iterator over the records in a directory
+struct DirIter<'a> {
 	fs: &'a InstanceInner,
-	data: Sector<'a>,
-	ofs: usize
+	first_lba: u32,
+	size: usize,
+	/// Byte offset of the next record in the directory
+	ofs: usize,
 }
 
-impl<'a> DirSector<'a>
+impl<'a> DirIter<'a>
 {
-	pub fn new<'b>(fs: &'b InstanceInner, data: Sector<'b>, start_ofs: usize) -> DirSector<'b>
+	pub fn new<'b>(fs: &'b InstanceInner, first_lba: u32, size: u32, start_ofs: usize) -> DirIter<'b>
 	{
-		DirSector {
+		DirIter {
 			fs: fs,
-			data: data,
+			first_lba: first_lba,
+			size: size as usize,
 			ofs: start_ofs,
 		}
 	}
-	pub fn next(&mut self) -> node::Result<Option<DirEnt>> {
-		let cur_ofs = self.ofs;
 
-		if self.ofs == self.data.len() {
-			Ok(None)
+	/// Get the next entry (combining the records of multi-extent files), along with its inode number
+	pub fn next(&mut self) -> node::Result<Option<(node::InodeId, DirEnt)>> {
+		let (inode, mut ent) = match self.next_record()?
+			{
+			None => return Ok(None),
+			Some(v) => v,
+			};
+		// Multi-extent files are a sequence of records, with all but the last flagged
+		let mut flags = ent.flags;
+		while flags & FLAG_MULTI_EXTENT != 0
+		{
+			let cont = match self.next_record()?
+				{
+				None => {
+					log_warning!("Consistency error in filesystem (multi-extent file missing final record)");
+					return Err(vfs::Error::InconsistentFilesystem);
+					},
+				Some((_, v)) => v,
+				};
+			ent.extents.push( (cont.start, cont.size as u32) );
+			ent.size += cont.size;
+			flags = cont.flags;
 		}
-		else {
-			let len = self.data[self.ofs] as usize;
-			if len == 0 {
-				self.ofs += 1;
-				Ok(Some(DirEnt {
-					this_ofs: cur_ofs,
-					next_ofs: self.ofs,
-					..Default::default()
-					}))
-			}
-			else if len < 33 {
-				log_warning!("Consistency error in filesystem (dir entry length {} < 33)", len);
-				return Err(vfs::Error::InconsistentFilesystem);
-			}
-			else if self.ofs + len > self.data.len() {
-				log_warning!("Consistency error in filesystem (dir entry spans sectors)");
-				return Err(vfs::Error::InconsistentFilesystem);
-			}
-			else {
-				let ent = &self.data[self.ofs ..][.. len];
-				self.ofs += len;
-				
-				let namelen = ent[32] as usize;
-				if 33 + namelen > len {
-					log_warning!("Name overruns end of entry");
+		ent.metadata.size = ent.size;
+		Ok(Some( (inode, ent) ))
+	}
+
+	fn next_record(&mut self) -> node::Result<Option<(node::InodeId, DirEnt)>> {
+		let lb_size = self.fs.lb_size;
+		while self.ofs < self.size
+		{
+			let (sector, ofs) = (self.ofs / lb_size, self.ofs % lb_size);
+			let lba = self.first_lba + sector as u32;
+			// Copy the record out, so the sector isn't held while reading continuation areas
+			let mut rec = [0u8; 255];
+			let len = {
+				let data = ::kernel::futures::block_on(self.fs.get_sector(lba))?;
+				let len = data[ofs] as usize;
+				if len == 0 {
+					// Records don't span sectors, so the rest of this sector is padding
+					self.ofs = (sector + 1) * lb_size;
+					continue ;
+				}
+				else if len < 34 {
+					log_warning!("Consistency error in filesystem (dir entry length {} < 34)", len);
 					return Err(vfs::Error::InconsistentFilesystem);
 				}
-				let su = &ent[33 + namelen ..];
-
-				let mut name = &ent[33..][..namelen];
-
-				if let Some(skip) = self.fs.susp_len_skip {
-					let skip = skip as usize;
-					if su.len() < skip {
-						log_warning!("System use area smaller than SUSP skip value");
-						return Err(vfs::Error::InconsistentFilesystem);
-					}
-
-					for ent in SuspIterator(&su[skip..])
-					{
-						//log_trace!("ent={:?}", ent);
-						// TODO: Need to handle this _FAR_ better
-						if let SuspItem::AlternateName(0, new_name) = ent {
-							name = new_name;
-						}
-					}
+				else if ofs + len > data.len() {
+					log_warning!("Consistency error in filesystem (dir entry spans sectors)");
+					return Err(vfs::Error::InconsistentFilesystem);
 				}
-
-				Ok(Some(DirEnt {
-					this_ofs: cur_ofs,
-					next_ofs: self.ofs,
-					flags: ent[25],
-					start: LittleEndian::read_u32(&ent[2..]),
-					size: LittleEndian::read_u32(&ent[10..]),
-					name: name,
-					sys_use: su,
-					}))
-			}
+				rec[..len].copy_from_slice(&data[ofs..][..len]);
+				len
+				};
+			self.ofs += len;
+			let ent = DirEnt::parse(self.fs, &rec[..len])?;
+			let inode = lba as u64 * lb_size as u64 + ofs as u64;
+			return Ok(Some( (inode, ent) ));
 		}
+		Ok(None)
 	}
 }
 
@@ -506,7 +875,7 @@ enum SuspItem<'a>
 	Pad(&'a [u8]),
 	Identifer,
 	//End,
-	
+
 	// RockRidge
 	RockRidge(u8),
 	PosixMode {
@@ -521,6 +890,10 @@ enum SuspItem<'a>
 		flags: u8,
 		data: &'a [u8],
 		},
+	Symlink(u8, &'a [u8]),
+	ChildLink(u32),
+	ParentLink(u32),
+	Relocated,
 
 	Unknown([u8; 2], u8, &'a[u8]),
 }
@@ -549,8 +922,8 @@ impl<'a> Iterator for SuspIterator<'a>
 			let data = &self.0[4..len];
 
 			self.0 = &self.0[len..];
-			
-			log_debug!("tag = {}{} - data={} [{:?}]", tag[0] as char, tag[1] as char, len-4, data);
+
+			log_trace!("tag = {}{} - data={} [{:?}]", tag[0] as char, tag[1] as char, len-4, data);
 			Some(match &tag[..]
 				{
 				b"ST" => return None,	// Terminated
@@ -585,11 +958,23 @@ impl<'a> Iterator for SuspIterator<'a>
 						data: &data[1..],
 						}
 					},
-				//b"SL" => SuspItem::Symlink(),
+				b"SL" => {
+					if data.len() < 1 { return None; }
+					SuspItem::Symlink(data[0], &data[1..])
+					},
 				b"NM" => {
 					if data.len() < 1 { return None; }
 					SuspItem::AlternateName(data[0], &data[1..])
 					},
+				b"CL" => {
+					if data.len() < 8 { return None; }
+					SuspItem::ChildLink(LittleEndian::read_u32(&data[0..]))
+					},
+				b"PL" => {
+					if data.len() < 8 { return None; }
+					SuspItem::ParentLink(LittleEndian::read_u32(&data[0..]))
+					},
+				b"RE" => SuspItem::Relocated,
 				_ => SuspItem::Unknown(tag, ver, data),
 				})
 		}
@@ -1,3 +1,4 @@
+use ::kernel::prelude::*;
 
 use ::kernel::lib::byte_str::ByteStr;
 
@@ -31,7 +32,8 @@ impl Dir
 			return None;
 			};
 		let Some(resident) = attr.inner().as_resident() else {
-			log_error!("TODO: $I30 IndexRoot not resident?");
+			// The index root is always resident, larger indexes use $INDEX_ALLOCATION
+			log_error!("$I30 IndexRoot not resident");
 			return None;
 			};
 		let data = resident.data();
@@ -41,6 +43,37 @@ impl Dir
 			};
 		Some(rv)
 	}
+
+	/// Read and check an index block from $INDEX_ALLOCATION
+	fn read_index_block<'a>(&self, i30_root: &crate::ondisk::Attrib_IndexRoot, vcn: u64, buf: &'a mut [u8]) -> Result<&'a crate::ondisk::Attrib_IndexBlockHeader, ::vfs::Error> {
+		let i30_alloc = self.i30_allocation.as_ref().ok_or(::vfs::Error::InconsistentFilesystem)?;
+		// Index VCNs are in clusters, unless the index block is smaller than a cluster (then they're in 512 byte units)
+		let block_size = i30_root.index_block_size() as u64;
+		let vcn_size = if block_size >= self.instance.cluster_size_bytes() as u64 { self.instance.cluster_size_bytes() as u64 } else { 512 };
+		let l = ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, i30_alloc, vcn * vcn_size, buf))?;
+		if l != buf.len() {
+			log_error!("Index block VCN {} out of range (read {:#x} bytes)", vcn, l);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		let rv = get_index_block(&self.instance, buf)?;
+		if rv.this_vcn() != vcn {
+			log_error!("Index block VCN mismatch: {} != exp {}", rv.this_vcn(), vcn);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		Ok(rv)
+	}
+
+	/// Load the $I30 bitmap, indicating which index blocks are in use
+	fn get_index_bitmap(&self) -> Result<Vec<u8>, ::vfs::Error> {
+		let Some(attr) = self.instance.get_attr_inner(&self.mft_ent, crate::ondisk::FileAttr::Bitmap, crate::ondisk::ATTRNAME_INDEXNAME, 0) else {
+			log_error!("$I30 Bitmap missing");
+			return Err(::vfs::Error::InconsistentFilesystem);
+			};
+		let mut rv = vec![0; self.instance.attr_size(&self.mft_ent, &attr) as usize];
+		let l = ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, &attr, 0, &mut rv))?;
+		rv.truncate(l);
+		Ok(rv)
+	}
 }
 impl ::vfs::node::NodeBase for Dir
 {
@@ -50,6 +83,9 @@ impl ::vfs::node::NodeBase for Dir
 	fn get_any(&self) -> &(dyn ::core::any::Any + 'static) {
 		self
 	}
+	fn get_metadata(&self) -> Result<::vfs::node::Metadata, ::vfs::Error> {
+		self.instance.get_metadata(&self.mft_ent)
+	}
 }
 impl ::vfs::node::Dir for Dir
 {
@@ -94,18 +130,14 @@ impl ::vfs::node::Dir for Dir
 			Err(Some(v)) => v,
 			};
 		if i30_root.index_header().flags() & 0x1 == 0 {
-			// Explicit error? The root had a chain VCN, but no allocation was expected present
+			log_error!("$I30 IndexRoot has a child VCN, but isn't flagged as having children");
+			return Err(::vfs::Error::InconsistentFilesystem);
 		}
-		let i30_alloc = self.i30_allocation.as_ref().ok_or(::vfs::Error::InconsistentFilesystem)?;
 		let mut buf = vec![ 0; i30_root.index_block_size() as usize];
-		loop
+		// Limit the search depth, so a corrupted index can't cause an infinite loop
+		for _ in 0 .. 32
 		{
-			let l = ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, i30_alloc, vcn * buf.len() as u64, &mut buf))?;
-			if l == 0 {
-				// Inconsistent? Off the end
-				return Err(::vfs::Error::NotFound);
-			}
-			let block_hdr = get_index_block(&self.instance, &mut buf[..l])?;
+			let block_hdr = self.read_index_block(i30_root, vcn, &mut buf)?;
 			vcn = match btree_search(block_hdr.index_header(), cmp)
 				{
 				Ok(e) => return Ok(e.mft_reference_num()),
@@ -113,81 +145,44 @@ impl ::vfs::node::Dir for Dir
 				Err(Some(v)) => v,
 				};
 		}
+		log_error!("$I30 index too deep");
+		Err(::vfs::Error::InconsistentFilesystem)
 	}
 	fn read(&self, ofs: usize, cb: &mut ::vfs::node::ReadDirCallback) -> Result<usize, ::vfs::Error> {
 		let mft_ent = self.mft_ent.read();
 		let i30_root = self.get_root(&mft_ent).ok_or(::vfs::Error::InconsistentFilesystem)?;
 		// Iterate index entries
-		// - Start with the information in the root (which should be resident)
-		
-		fn iterate_index<'a>(hdr: &'a crate::ondisk::Attrib_IndexHeader, pos: &mut usize) -> Option<&'a crate::ondisk::Attrib_IndexEntry> {
-			let mut data = hdr.entries_slice();
-			while data.len() > 0
-			{
-				let Some(ent) = crate::ondisk::Attrib_IndexEntry::from_slice(data) else {
-					break
-					};
-				//log_debug!("iterate_index: MFTRef={:#x} flags={:#x} data={:02x?}", ent.mft_reference(), ent.index_flags(), ent.data());
-				// Returns `none` if this is the last entry - the last entry has no data
-				let Some(next) = ent.next() else {
-					break;
-					};
-				if *pos == 0 {
-					return Some(ent);
-				}
-				*pos -= 1;
-				data = next;
-			}
-			None
-		}
-
+		// - Start with the information in the root (which should be resident), then each in-use index block
 		// TODO: Have `ofs` be a byte offset (or something that doesn't require linear iteration on each run)
-		let mut pos = ofs;
-		let mut rv = ofs;
-		{
-			while let Some(v) = iterate_index(i30_root.index_header(), &mut pos) {
-				let a = crate::ondisk::Attrib_Filename::from_slice(v.data()).ok_or(::vfs::Error::InconsistentFilesystem)?;
-				log_debug!("Dir::read: Found (root) {:?}", a.filename());
-				rv += 1;
-				pos = rv;
-				if ! cb(v.mft_reference_num(), &mut a.filename().wtf8()) {
-					return Ok(rv);
-				}
-			}
+		let mut pos = 0;
+		if ! emit_entries(i30_root.index_header(), ofs, &mut pos, cb)? {
+			return Ok(pos);
 		}
 
 		// If this flag is set, the index doesn't fit in the root
 		if i30_root.index_header().flags() & 0x1 != 0
 		{
 			let i30_alloc = self.i30_allocation.as_ref().ok_or(::vfs::Error::InconsistentFilesystem)?;
+			let bitmap = self.get_index_bitmap()?;
 
-			let mut buf = vec![ 0; i30_root.index_block_size() as usize];
-			let mut ipos = pos;
-			for read_ofs in (0 ..).step_by(buf.len())
+			let block_size = i30_root.index_block_size() as usize;
+			let vcns_per_block = if block_size >= self.instance.cluster_size_bytes() { block_size / self.instance.cluster_size_bytes() } else { block_size / 512 };
+			let n_blocks = self.instance.attr_size(&self.mft_ent, i30_alloc) / block_size as u64;
+			let mut buf = vec![ 0; block_size ];
+			for block_idx in 0 .. n_blocks as usize
 			{
-				let l = ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, i30_alloc, read_ofs, &mut buf))?;
-				if l == 0 {
-					break;
+				// Blocks not marked in the bitmap are free, and can contain stale entries
+				if bitmap.get(block_idx / 8).map(|v| v & (1 << (block_idx % 8)) != 0) != Some(true) {
+					continue ;
 				}
-				//log_debug!("Alloc block @{:#x}: {:?}", read_ofs, ::kernel::logging::HexDump(&buf[..128]));
-				let block_hdr = get_index_block(&self.instance, &mut buf[..l])?;
-				let index_hdr = block_hdr.index_header();
-
-				while let Some(v) = iterate_index(index_hdr, &mut pos) {
-					//log_debug!("Indexed attribute: {:?}", ::kernel::logging::HexDump(v.data()));
-					let a = crate::ondisk::Attrib_Filename::from_slice(v.data()).ok_or(::vfs::Error::InconsistentFilesystem)?;
-					log_debug!("Dir::read: Found {:?}", a.filename());
-					rv += 1;
-					ipos += 1;
-					pos = ipos;
-					if ! cb(v.mft_reference_num(), &mut a.filename().wtf8()) {
-						return Ok(rv);
-					}
+				let block_hdr = self.read_index_block(i30_root, (block_idx * vcns_per_block) as u64, &mut buf)?;
+				if ! emit_entries(block_hdr.index_header(), ofs, &mut pos, cb)? {
+					return Ok(pos);
 				}
 			}
 		}
 
-		Ok(rv)
+		Ok(pos)
 	}
 	fn create(&self, _name: &ByteStr, _node_type: ::vfs::node::NodeType<'_>) -> Result<u64, ::vfs::Error> {
 		Err(::vfs::Error::ReadOnlyFilesystem)
@@ -198,6 +193,43 @@ impl ::vfs::node::Dir for Dir
 	fn unlink(&self, _name: &ByteStr) -> Result<(), ::vfs::Error> {
 		Err(::vfs::Error::ReadOnlyFilesystem)
 	}
+	fn rename(&self, _old_name: &ByteStr, _new_dir: &dyn ::vfs::node::Dir, _new_name: &ByteStr) -> Result<(), ::vfs::Error> {
+		Err(::vfs::Error::ReadOnlyFilesystem)
+	}
+}
+
+/// Iterate the entries in an index node (excluding the terminating entry, which has no data)
+fn iter_index(hdr: &crate::ondisk::Attrib_IndexHeader) -> impl Iterator<Item=&crate::ondisk::Attrib_IndexEntry> {
+	let mut data = hdr.entries_slice();
+	::core::iter::from_fn(move || {
+		let ent = crate::ondisk::Attrib_IndexEntry::from_slice(data)?;
+		// Returns `none` if this is the last entry
+		data = ent.next()?;
+		Some(ent)
+	})
+}
+
+/// Pass the entries from an index node to a `read` callback, skipping until `pos` reaches `skip`
+///
+/// Returns `false` if the callback requested a stop
+fn emit_entries(hdr: &crate::ondisk::Attrib_IndexHeader, skip: usize, pos: &mut usize, cb: &mut ::vfs::node::ReadDirCallback) -> Result<bool, ::vfs::Error> {
+	for ent in iter_index(hdr)
+	{
+		*pos += 1;
+		if *pos <= skip {
+			continue ;
+		}
+		let a = crate::ondisk::Attrib_Filename::from_slice(ent.data()).ok_or(::vfs::Error::InconsistentFilesystem)?;
+		// DOS names are an alias of a long name, so don't list them twice
+		if a.is_dos_name() {
+			continue ;
+		}
+		log_debug!("Dir::read: Found {:?}", a.filename());
+		if ! cb(ent.mft_reference_num(), &mut a.filename().wtf8()) {
+			return Ok(false);
+		}
+	}
+	Ok(true)
 }
 
 fn get_index_block<'a>(instance: &super::instance::Instance, buf: &'a mut [u8]) -> Result<&'a crate::ondisk::Attrib_IndexBlockHeader, ::vfs::Error>
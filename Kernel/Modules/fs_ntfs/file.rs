@@ -7,14 +7,23 @@ pub struct File {
 	mft_idx: u64,
 	mft_ent: super::instance::CachedMft,
 
-	attr_data: Option<super::ondisk::AttrHandle>,
+	/// The $DATA attribute (and the MFT entry holding it, which may be an extension entry)
+	attr_data: Option<(super::instance::CachedMft, super::ondisk::AttrHandle)>,
 }
 
 impl File
 {
 	pub fn new(instance: super::instance::InstanceRef, mft_idx: u64, mft_ent: super::instance::CachedMft) -> Self {
+		let attr_data = match ::kernel::futures::block_on(instance.get_attr_from(&mft_ent, crate::ondisk::FileAttr::Data, crate::ondisk::ATTRNAME_DATA, 0))
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Unable to locate $DATA for MFT entry {}: {:?}", mft_idx, e);
+				None
+				},
+			};
 		File {
-			attr_data: instance.get_attr_inner(&mft_ent, crate::ondisk::FileAttr::Data, crate::ondisk::ATTRNAME_DATA, 0),
+			attr_data,
 			instance,
 			mft_idx,
 			mft_ent,
@@ -30,14 +39,19 @@ impl ::vfs::node::NodeBase for File
 	fn get_any(&self) -> &(dyn ::core::any::Any + 'static) {
 		self
 	}
+	fn get_metadata(&self) -> Result<::vfs::node::Metadata, ::vfs::Error> {
+		let mut rv = self.instance.get_metadata(&self.mft_ent)?;
+		rv.size = ::vfs::node::File::size(self);
+		Ok(rv)
+	}
 }
 impl ::vfs::node::File for File
 {
 	fn size(&self) -> u64 {
-		let Some(ref attr_data) = self.attr_data else {
+		let Some((ref data_ent, ref attr_data)) = self.attr_data else {
 			return 0;
 			};
-		self.instance.attr_size(&self.mft_ent, attr_data)
+		self.instance.attr_size(data_ent, attr_data)
 	}
 	fn truncate(&self, _new_size: u64) -> Result<u64, ::vfs::Error> {
 		Err(::vfs::Error::ReadOnlyFilesystem)
@@ -46,10 +60,10 @@ impl ::vfs::node::File for File
 		Err(::vfs::Error::ReadOnlyFilesystem)
 	}
 	fn read(&self, ofs: u64, dst: &mut [u8]) -> Result<usize, ::vfs::Error> {
-		let Some(ref attr_data) = self.attr_data else {
+		let Some((ref data_ent, ref attr_data)) = self.attr_data else {
 			return Ok(0);
 			};
-		Ok( ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, attr_data, ofs, dst))? )
+		Ok( ::kernel::futures::block_on(self.instance.attr_read(data_ent, attr_data, ofs, dst))? )
 	}
 	fn write(&self, _ofs: u64, _src: &[u8]) -> Result<usize, ::vfs::Error> {
 		Err(::vfs::Error::ReadOnlyFilesystem)
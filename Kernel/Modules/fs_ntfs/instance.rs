@@ -67,8 +67,12 @@ impl Instance
 					log_warning!("$UpCase not large enough - Read {:#x} bytes, expected {:#x}",
 						len, upcase_table.len() * 2);
 				}
-				for e in upcase_table.iter_mut() {
-					*e = e.to_le();
+				for e in upcase_table[..len / 2].iter_mut() {
+					*e = u16::from_le(*e);
+				}
+				// Anything not covered by the table maps to itself
+				for (i,e) in upcase_table.iter_mut().enumerate().skip(len / 2) {
+					*e = i as u16;
 				}
 				upcase_table
 				};
@@ -78,62 +82,56 @@ impl Instance
 		Ok(unsafe { Box::new(InstanceWrapper(aref::ArefInner::new(instance))) })
 	}
 
-	fn cluster_size_bytes(&self) -> usize {
+	pub fn cluster_size_bytes(&self) -> usize {
 		self.cluster_size_blocks * self.vol.block_size()
 	}
 
 	/// Apply `update_sequence` fixups to a loaded metadata block
 	///
-	/// All metadata blocks (e.g. MFT entries, or index blocks) have an "update sequence" that catches sectors (volume
-	/// blocks) that didn't get written to disk correctly. Within the first sector there's a sequence number that's
-	/// incremented on every change to the block and a copy of the original/correct last two bytes of each sector.
+	/// All metadata blocks (e.g. MFT entries, or index blocks) have an "update sequence" that catches sectors
+	/// that didn't get written to disk correctly. Within the block header there's a sequence number that's
+	/// incremented on every change to the block and a copy of the original/correct last two bytes of each 512 byte
+	/// stride (this is always 512, no matter the volume's sector size).
 	///
-	/// This function takes that update sequence information, checks that the last word of each non-first sector matches
+	/// This function takes that update sequence information, checks that the last word of each stride matches
 	/// the expectation and then restores the original value.
 	///
-	/// `get_usa` is a function that gets an `UpdateSequence` from the passed first sector
+	/// `get_usa` is a function that gets an `UpdateSequence` from the passed block
 	pub fn apply_sequence_fixups(&self, buf: &mut [u8], get_usa: &dyn Fn(&[u8])->Option<&crate::ondisk::UpdateSequence>) -> Result<(),::vfs::Error> {
-		let block_size = self.vol.block_size();
-		if buf.len() >= block_size
-		{
-			assert!(buf.len() % block_size == 0, "apply_sequence_fixups: Passed a buffer not a multiple of volume blocks long"); 
-			let (buf1, buf2) = buf.split_at_mut(block_size);
-			let usa = (get_usa)(buf1).ok_or(::vfs::Error::InconsistentFilesystem)?;
-			let exp_val = usa.sequence_number();
-			let mut usa_it = usa.array();
-			let s0_last_word = usa_it.next().ok_or(::vfs::Error::InconsistentFilesystem)?;
-			//log_debug!("apply_sequence_fixups: {} sectors, seq={}", 1+buf2.len()/block_size, exp_val);
-
-			fn apply_fixup(sector_idx: usize, sector: &mut [u8], last_word: u16, exp_val: u16) -> Result<(),::vfs::Error> {
-				let block_size = sector.len();
-				let slot = &mut sector[ block_size - 2 ..];
-				let cur_val = u16::from_le_bytes([slot[0], slot[1]]);
-				//log_debug!("apply_sequence_fixups: +{}: 0x{:04x} -> 0x{:04x}", 1+sector_idx, cur_val, last_word);
-				if cur_val != exp_val {
-					log_error!("apply_sequence_fixups: Sequence number mismatch in sector +{}: 0x{:04x} != exp 0x{:04x}",
-						1+sector_idx, cur_val, exp_val
-						);
-					return Err(::vfs::Error::InconsistentFilesystem);
-				}
-				slot.copy_from_slice(&last_word.to_le_bytes());
-				Ok( () )
-			}
-
-			for (rel_sector_idx, (sector, last_word)) in Iterator::zip( buf2.chunks_mut(block_size), usa_it ).enumerate()
-			{
-				apply_fixup(1+rel_sector_idx, sector, last_word, exp_val)?;
-			}
-
-			apply_fixup(0, &mut buf[..block_size], s0_last_word, exp_val)?;
+		const STRIDE: usize = ondisk::UPDATE_SEQUENCE_STRIDE;
+		if buf.len() % STRIDE != 0 {
+			log_error!("apply_sequence_fixups: Block length {:#x} isn't a multiple of {:#x}", buf.len(), STRIDE);
+			return Err(::vfs::Error::InconsistentFilesystem);
 		}
-		else
+		// Take a copy of the array, as it's within the buffer being fixed
+		let (exp_val, saved_words) = {
+			let usa = (get_usa)(buf).ok_or(::vfs::Error::InconsistentFilesystem)?;
+			(usa.sequence_number(), usa.array().collect::<Vec<u16>>())
+			};
+		if saved_words.len() < buf.len() / STRIDE {
+			log_error!("apply_sequence_fixups: Update sequence too short - {} entries for {} sectors",
+				saved_words.len(), buf.len() / STRIDE);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		//log_debug!("apply_sequence_fixups: {} sectors, seq={}", buf.len() / STRIDE, exp_val);
+
+		for (sector_idx, (sector, last_word)) in Iterator::zip( buf.chunks_mut(STRIDE), saved_words.into_iter() ).enumerate()
 		{
-			// Call the getter, just so the error is triggered if needed
-			(get_usa)(buf).ok_or(::vfs::Error::InconsistentFilesystem)?;
+			let slot = &mut sector[STRIDE - 2 ..];
+			let cur_val = u16::from_le_bytes([slot[0], slot[1]]);
+			//log_debug!("apply_sequence_fixups: +{}: 0x{:04x} -> 0x{:04x}", sector_idx, cur_val, last_word);
+			if cur_val != exp_val {
+				log_error!("apply_sequence_fixups: Sequence number mismatch in sector +{}: 0x{:04x} != exp 0x{:04x}",
+					sector_idx, cur_val, exp_val
+					);
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+			slot.copy_from_slice(&last_word.to_le_bytes());
 		}
 		Ok( () )
 	}
 
+	/// Compare two UCS-2 code units as NTFS does for filenames (using the `$UpCase` table)
 	pub fn compare_ucs2_nocase(&self, a: u16, b: u16) -> ::core::cmp::Ordering {
 		// Look up $UpCase
 		if self.upcase_table.len() == 0x1_0000 {
@@ -153,8 +151,8 @@ impl Instance
 			::core::cmp::Ord::cmp(&a, &b)
 		}
 	}
+	/// Compare two UCS-2 strings ignoring case - this matches the collation order of filename indexes
 	pub fn compare_ucs2_nocase_iter(&self, a: &mut dyn Iterator<Item=u16>, b: &mut dyn Iterator<Item=u16>) -> ::core::cmp::Ordering {
-		// TODO: Case insensitive UCS-2 comparison! - use the `$UpCase` special file to obtain the sorting rule to use
 		use ::core::cmp::Ordering;
 		loop {
 			match (a.next(), b.next())
@@ -193,6 +191,13 @@ impl ::vfs::mount::Filesystem for InstanceWrapper
 			Some(::vfs::node::Node::File(Box::new(super::file::File::new(self.0.borrow(), inode_id, ent))))
 		}
 	}
+	fn flush(&self) -> ::vfs::Result<()> {
+		// Read-only, nothing to write back
+		Ok( () )
+	}
+	fn remount(&self, _options: &::vfs::mount::MountOptions) -> ::vfs::Result<()> {
+		Ok( () )
+	}
 }
 
 /**
@@ -276,8 +281,76 @@ impl Instance
 	pub async fn get_attr(&self, entry: MftEntryIdx, attr_id: ondisk::FileAttr, name: &str, index: usize) -> ::vfs::Result<Option<(CachedMft, ondisk::AttrHandle)>> {
 		// Get the MFT entry
 		let e = self.get_mft_entry(entry).await?;
-		let rv = self.get_attr_inner(&e, attr_id, name, index);
-		Ok(rv.map(|a| (e, a)))
+		self.get_attr_from(&e, attr_id, name, index).await
+	}
+	/// Get a handle to an attribute of a loaded MFT entry, which might be held in an extension entry
+	pub async fn get_attr_from(&self, mft_ent: &CachedMft, attr_id: ondisk::FileAttr, name: &str, index: usize) -> ::vfs::Result<Option<(CachedMft, ondisk::AttrHandle)>> {
+		if let Some(a) = self.get_attr_inner(mft_ent, attr_id, name, index) {
+			return Ok(Some( (mft_ent.clone(), a) ));
+		}
+		// Not in this entry, so check the $ATTRIBUTE_LIST
+		let Some(list) = self.get_attribute_list_dyn(mft_ent).await? else {
+			return Ok(None);
+			};
+		// NOTE: The list includes attributes in the base entry, so the index is across all entries
+		let Some(ent) = ondisk::iter_attribute_list(&list)
+			.filter(|e| e.ty() == attr_id as u32 && e.name() == name && e.starting_vcn() == 0)
+			.nth(index)
+			else {
+				return Ok(None);
+			};
+		Ok(Some( self.get_listed_attr(ent).await? ))
+	}
+	/// Locate the extent of an attribute (by type and name) that holds the given VCN, using the `$ATTRIBUTE_LIST`
+	async fn get_attr_extent(&self, mft_ent: &CachedMft, attr_id: u32, name: &[u16], vcn: u64) -> ::vfs::Result<Option<(CachedMft, ondisk::AttrHandle)>> {
+		let Some(list) = self.get_attribute_list_dyn(mft_ent).await? else {
+			return Ok(None);
+			};
+		// Entries are sorted by type, name, then starting VCN - so the last one starting at or before `vcn` holds it
+		let Some(ent) = ondisk::iter_attribute_list(&list)
+			.filter(|e| e.ty() == attr_id && e.name().iter_units().eq(name.iter().copied()))
+			.take_while(|e| e.starting_vcn() <= vcn)
+			.last()
+			else {
+				return Ok(None);
+			};
+		Ok(Some( self.get_listed_attr(ent).await? ))
+	}
+	/// Get the attribute referenced by an `$ATTRIBUTE_LIST` entry
+	async fn get_listed_attr(&self, ent: &ondisk::Attrib_AttributeListEntry) -> ::vfs::Result<(CachedMft, ondisk::AttrHandle)> {
+		let ext_ent = self.get_mft_entry(ent.mft_entry()).await?;
+		let rv = {
+			let lh = ext_ent.inner.read();
+			let a = lh.iter_attributes().find(|a| a.ty() == ent.ty() && a.attribute_id() == ent.attribute_id());
+			a.map(|a| lh.attr_handle(a))
+			};
+		match rv
+		{
+		Some(a) => Ok( (ext_ent, a) ),
+		None => {
+			log_error!("Attribute {} #{} missing from MFT entry {}", ondisk::FileAttr::fmt_val(ent.ty()), ent.attribute_id(), ent.mft_entry());
+			Err(::vfs::Error::InconsistentFilesystem)
+			},
+		}
+	}
+	/// Load the `$ATTRIBUTE_LIST` (if present) of the base entry of `mft_ent` (this is boxed, as it recurses via `attr_read`)
+	fn get_attribute_list_dyn<'a>(&'a self, mft_ent: &'a CachedMft) -> ::core::pin::Pin<Box< dyn ::core::future::Future<Output=::vfs::Result<Option<Vec<u8>>>> + 'a >> {
+		Box::pin(self.get_attribute_list(mft_ent))
+	}
+	async fn get_attribute_list(&self, mft_ent: &CachedMft) -> ::vfs::Result<Option<Vec<u8>>> {
+		let base_idx = mft_ent.inner.read().base_entry();
+		let base = match base_idx
+			{
+			Some(idx) => self.get_mft_entry(idx).await?,
+			None => mft_ent.clone(),
+			};
+		let Some(attr) = self.get_attr_inner(&base, ondisk::FileAttr::AttributeList, "", 0) else {
+			return Ok(None);
+			};
+		let mut rv = vec![0; self.attr_size(&base, &attr) as usize];
+		let l = self.attr_read(&base, &attr, 0, &mut rv).await?;
+		rv.truncate(l);
+		Ok(Some(rv))
 	}
 
 	/// Get a hanle to an attribute within a MFT entry
@@ -307,14 +380,42 @@ impl Instance
 		}
 	}
 
+	/// Obtain VFS metadata (times, link count) from a MFT entry's $STANDARD_INFORMATION
+	pub fn get_metadata(&self, mft_ent: &CachedMft) -> ::vfs::Result<::vfs::node::Metadata> {
+		let attr = self.get_attr_inner(mft_ent, ondisk::FileAttr::StandardInformation, "", 0);
+		let mft_ent = mft_ent.inner.read();
+		let mut rv = ::vfs::node::Metadata {
+			link_count: mft_ent.hard_link_count() as u32,
+			// NTFS permissions are ACLs, so only report a basic mode (files are assumed executable)
+			mode: 0o755,
+			..Default::default()
+			};
+		let Some(attr) = attr else {
+			log_warning!("$STANDARD_INFORMATION missing");
+			return Ok(rv);
+			};
+		let data = mft_ent.get_attr(&attr)
+			.and_then(|a| a.inner().as_resident())
+			.ok_or(::vfs::Error::InconsistentFilesystem)?
+			.data();
+		let si = ondisk::Attrib_StandardInformation::from_slice(data).ok_or(::vfs::Error::InconsistentFilesystem)?;
+		if si.file_attributes() & 0x1 != 0 {
+			rv.mode &= !0o222;
+		}
+		rv.created = ondisk::filetime_to_unix(si.creation_time());
+		rv.modified = ondisk::filetime_to_unix(si.last_data_mod_time());
+		rv.accessed = ondisk::filetime_to_unix(si.last_access_time());
+		Ok(rv)
+	}
+
 	/// Read data out of an attribute (resident or non-resident)
 	pub async fn attr_read(&self, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, ofs: u64, mut dst: &mut [u8]) -> ::vfs::Result<usize> {
 		if dst.len() == 0 {
 			return Ok(0);
 		}
 
-		let mft_ent = mft_ent.inner.read();
-		let a = mft_ent.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+		let mft_ent_lh = mft_ent.inner.read();
+		let a = mft_ent_lh.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
 		match a.inner()
 		{
 		ondisk::MftAttribData::Resident(r) => {
@@ -345,186 +446,244 @@ impl Instance
 			if space < dst.len() as u64 {
 				dst = &mut dst[..space as usize];
 			}
+			let rv = dst.len();
 
-			if r.starting_vcn() != 0 {
-				todo!("attr_read: TODO - Handle sparse files (starting_vcn = {})", r.starting_vcn());
-				// For this, inject a run filled with zeroes?
+			// Anything past the initialised size hasn't been written, so reads as zero
+			let init_space = r.initiated_size().saturating_sub(ofs);
+			if init_space < dst.len() as u64 {
+				let (data, uninit) = dst.split_at_mut(init_space as usize);
+				uninit.fill(0);
+				dst = data;
 			}
 
-			let mut cur_vcn = ofs / (self.cluster_size_bytes() as u64);
-			let mut cur_ofs = ofs as usize % self.cluster_size_bytes();
-
-			let mut runs = CompressionRuns::new(r.data_runs(), 1 << r.compression_unit_size()).peekable();
-			// Seek to the run containing the first cluster
-			let mut runbase_vcn = 0;
-			while let Some(r) = runs.peek() {
-				if runbase_vcn + r.cluster_count() > cur_vcn {
-					break;
+			// The attribute can be split into extents held in other MFT entries (listed in the base entry's
+			// $ATTRIBUTE_LIST), so read from this extent and then from the extents that follow it
+			let cluster_size = self.cluster_size_bytes() as u64;
+			let mut ofs = ofs;
+			if r.starting_vcn() <= ofs / cluster_size && ofs / cluster_size <= r.last_vcn() {
+				let l = self.attr_read_extent(a.is_compressed(), r, ofs, dst).await?;
+				ofs += l as u64;
+				dst = &mut dst[l..];
+			}
+			if dst.len() > 0 {
+				let ty = a.ty();
+				let name: Vec<u16> = a.name().iter_units().collect();
+				let is_compressed = a.is_compressed();
+				while dst.len() > 0
+				{
+					let vcn = ofs / cluster_size;
+					let Some( (ext_ent, ext_attr) ) = self.get_attr_extent(mft_ent, ty, &name, vcn).await? else {
+						log_error!("attr_read: No extent of attribute {} covering VCN {}", ondisk::FileAttr::fmt_val(ty), vcn);
+						return Err(::vfs::Error::InconsistentFilesystem);
+						};
+					let ext_lh = ext_ent.inner.read();
+					let ext_r = match ext_lh.get_attr(&ext_attr).map(|a| a.inner())
+						{
+						Some(ondisk::MftAttribData::Nonresident(ext_r)) => ext_r,
+						_ => return Err(::vfs::Error::InconsistentFilesystem),
+						};
+					let l = self.attr_read_extent(is_compressed, ext_r, ofs, dst).await?;
+					if l == 0 {
+						log_error!("attr_read: Extent VCN {}--{} didn't cover VCN {}", ext_r.starting_vcn(), ext_r.last_vcn(), vcn);
+						return Err(::vfs::Error::InconsistentFilesystem);
+					}
+					ofs += l as u64;
+					dst = &mut dst[l..];
 				}
-				runbase_vcn += r.cluster_count();
-				runs.next();
 			}
-			let rv = dst.len();
-			// Keep consuming runs until the destination is empty
-			while dst.len() > 0
-			{
-				let Some(cur_run) = runs.next() else {
-					// Filled with zeroes? Or invalid parameter?
-					todo!("Handle reading past the end of the populated runs");
-					};
+			Ok(rv)
+			},
+		}
+	}
+	/// Read data from a single extent (the runs in one MFT entry) of a non-resident attribute
+	///
+	/// Returns the number of bytes read, which is short if the data continues in another extent
+	async fn attr_read_extent(&self, is_compressed: bool, r: &ondisk::MftAttrHeader_NonResident, ofs: u64, mut dst: &mut [u8]) -> ::vfs::Result<usize> {
+		let mut cur_vcn = ofs / (self.cluster_size_bytes() as u64);
+		let mut cur_ofs = ofs as usize % self.cluster_size_bytes();
+		let mut runbase_vcn = r.starting_vcn();
+		assert!(runbase_vcn <= cur_vcn);
+		let len = dst.len();
+
+		// Sparse (but not compressed) attributes can still have a compression unit size, and uncompressed
+		// data followed by a sparse run would look like a compressed unit - so only use it if compressed.
+		let compression_unit = if is_compressed { 1 << r.compression_unit_size() } else { 1 };
+		let mut runs = CompressionRuns::new(r.data_runs(), compression_unit).peekable();
+		// Seek to the run containing the first cluster
+		while let Some(r) = runs.peek() {
+			if runbase_vcn + r.cluster_count() > cur_vcn {
+				break;
+			}
+			runbase_vcn += r.cluster_count();
+			runs.next();
+		}
+		// Keep consuming runs until the destination is empty
+		while dst.len() > 0
+		{
+			let Some(cur_run) = runs.next() else {
+				if cur_vcn > r.last_vcn() {
+					// The rest of the data is in another extent
+					break;
+				}
+				// Past the end of the populated runs (but within the file's size) - this space isn't allocated
+				dst.fill(0);
+				return Ok(len);
+				};
 
-				match cur_run
+			match cur_run
+			{
+			CompressionRun::Sparse(run_cluster_count) => {
+				log_debug!("Sparse +{}", run_cluster_count);
+				// VCN within the run
+				let rel_vcn = cur_vcn - runbase_vcn;
+				// Number of clusters available in the run
+				let cluster_count = run_cluster_count - rel_vcn;
+				// Number of bytes we can read in this loop
+				let len = usize::min(dst.len(), (cluster_count as usize) * self.cluster_size_bytes() - cur_ofs);
+				let buf = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
+
+				buf.fill(0);
+
+				runbase_vcn += run_cluster_count;
+				cur_vcn += cluster_count;
+				cur_ofs = 0;
+				},
+			CompressionRun::Raw(crun_cluster_count, iter) => {
+				log_debug!("Raw +{}", crun_cluster_count);
+				let mut iter = iter.peekable();
+				let mut irunbase_vcn = runbase_vcn;
+				while let Some(r) = iter.peek() {
+					if irunbase_vcn + r.cluster_count > cur_vcn {
+						break;
+					}
+					irunbase_vcn += r.cluster_count;
+					iter.next();
+				}
+				while let Some(cur_run) = iter.next()
 				{
-				CompressionRun::Sparse(run_cluster_count) => {
-					log_debug!("Sparse +{}", run_cluster_count);
+					let run_lcn = cur_run.lcn.expect("CompressionRun::Raw with sparse run");
+
+					if dst.len() == 0 {
+						break;
+					}
+
 					// VCN within the run
-					let rel_vcn = cur_vcn - runbase_vcn;
+					let rel_vcn = cur_vcn - irunbase_vcn;
 					// Number of clusters available in the run
-					let cluster_count = run_cluster_count - rel_vcn;
+					let cluster_count = cur_run.cluster_count - rel_vcn;
 					// Number of bytes we can read in this loop
 					let len = usize::min(dst.len(), (cluster_count as usize) * self.cluster_size_bytes() - cur_ofs);
 					let buf = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
 
-					buf.fill(0);
+					let lcn = run_lcn + rel_vcn;
+					let block = lcn * self.cluster_size_blocks as u64 + (cur_ofs / self.vol.block_size()) as u64;
+					let block_ofs = cur_ofs % self.vol.block_size();
+					if block_ofs != 0 || buf.len() % self.vol.block_size() != 0 {
+						self.vol.read_inner(block, block_ofs, buf).await?;
+					}
+					else {
+						self.vol.read_blocks(block, buf).await?;
+					}
 
-					runbase_vcn += run_cluster_count;
+					irunbase_vcn += cur_run.cluster_count;
 					cur_vcn += cluster_count;
 					cur_ofs = 0;
-					},
-				CompressionRun::Raw(crun_cluster_count, iter) => {
-					log_debug!("Raw +{}", crun_cluster_count);
-					let mut iter = iter.peekable();
-					let mut irunbase_vcn = runbase_vcn;
-					while let Some(r) = iter.peek() {
-						if irunbase_vcn + r.cluster_count > cur_vcn {
-							break;
-						}
-						irunbase_vcn += r.cluster_count;
-						iter.next();
-					}
-					while let Some(cur_run) = iter.next()
+				}
+				runbase_vcn += crun_cluster_count;
+				},
+			CompressionRun::Compressed(uncompressed_count, compressed_count, iter) => {
+				log_debug!("Compressed +{}", compressed_count);
+				// Iterate compressed blocks, and decompress into the target buffer (or a bounce buffer - if incomplete)
+				// - Load the entire compression unit? (Or, stream in pairs of 8K chunks)
+				let mut buf = vec![ 0u8; compressed_count as usize * self.cluster_size_bytes() ];
+				{
+					let mut dst = &mut buf[..];
+					for cur_run in iter
 					{
-						let run_lcn = cur_run.lcn.expect("CompressionRun::Raw with sparse run");
-
-						if dst.len() == 0 {
-							break;
-						}
-
-						// VCN within the run
-						let rel_vcn = cur_vcn - irunbase_vcn;
-						// Number of clusters available in the run
-						let cluster_count = cur_run.cluster_count - rel_vcn;
-						// Number of bytes we can read in this loop
-						let len = usize::min(dst.len(), (cluster_count as usize) * self.cluster_size_bytes() - cur_ofs);
+						let len = usize::min( dst.len(), cur_run.cluster_count as usize * self.cluster_size_bytes() );
 						let buf = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
-
-						let lcn = run_lcn + rel_vcn;
-						let block = lcn * self.cluster_size_blocks as u64 + (cur_ofs / self.vol.block_size()) as u64;
-						let block_ofs = cur_ofs % self.vol.block_size();
-						if block_ofs != 0 || buf.len() % self.vol.block_size() != 0 {
-							self.vol.read_inner(block, block_ofs, buf).await?;
-						}
-						else {
-							self.vol.read_blocks(block, buf).await?;
-						}
-
-						irunbase_vcn += cur_run.cluster_count;
-						cur_vcn += cluster_count;
-						cur_ofs = 0;
+						let lcn = cur_run.lcn.expect("CompressionRun::Compressed with a sparse run");
+						let block = lcn * self.cluster_size_blocks as u64;
+						self.vol.read_blocks(block, buf).await?;
 					}
-					runbase_vcn += crun_cluster_count;
-					},
-				CompressionRun::Compressed(uncompressed_count, compressed_count, iter) => {
-					log_debug!("Compressed +{}", compressed_count);
-					// Iterate compressed blocks, and decompress into the target buffer (or a bounce buffer - if incomplete)
-					// - Load the entire compression unit? (Or, stream in pairs of 8K chunks)
-					let mut buf = vec![ 0u8; compressed_count as usize * self.cluster_size_bytes() ];
-					{
-						let mut dst = &mut buf[..];
-						for cur_run in iter
-						{
-							let len = usize::min( dst.len(), cur_run.cluster_count as usize * self.cluster_size_bytes() );
-							let buf = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
-							let lcn = cur_run.lcn.expect("CompressionRun::Compressed with a sparse run");
-							let block = lcn * self.cluster_size_blocks as u64;
-							self.vol.read_blocks(block, buf).await?;
-						}
-					}
-					if false
-					{
-						let mut decomp = crate::compression::Decompressor::new(&buf);
-						while let Some(len) = decomp.get_block(None) {
-							log_debug!("get_block length: {}", len);
-						}
+				}
+				if false
+				{
+					let mut decomp = crate::compression::Decompressor::new(&buf);
+					while let Some(len) = decomp.get_block(None) {
+						log_debug!("get_block length: {}", len);
 					}
+				}
 
-					// - Iterate through compressed blocks (4K) skipping until target data
-					let mut decomp = crate::compression::Decompressor::new(&buf);
-					let rel_vcn = cur_vcn - runbase_vcn;
-					let byte_ofs = rel_vcn as usize * self.cluster_size_bytes() + cur_ofs;
-
-					// Ensure that `dst` here just contains the buffer to be used for this compression unit
-					// - That avoids accidentally over-reading (which could happen if there are extra compressed bytes)
-					let mut dst = {
-						let maxlen = uncompressed_count as usize * self.cluster_size_bytes() - byte_ofs;
-						let len = usize::min(dst.len(), maxlen);
-						::kernel::lib::split_off_front_mut(&mut dst, len).unwrap()
-						};
+				// - Iterate through compressed blocks (4K) skipping until target data
+				let mut decomp = crate::compression::Decompressor::new(&buf);
+				let rel_vcn = cur_vcn - runbase_vcn;
+				let byte_ofs = rel_vcn as usize * self.cluster_size_bytes() + cur_ofs;
+
+				// Ensure that `dst` here just contains the buffer to be used for this compression unit
+				// - That avoids accidentally over-reading (which could happen if there are extra compressed bytes)
+				let mut dst = {
+					let maxlen = uncompressed_count as usize * self.cluster_size_bytes() - byte_ofs;
+					let len = usize::min(dst.len(), maxlen);
+					::kernel::lib::split_off_front_mut(&mut dst, len).unwrap()
+					};
 
-					const BLOCK_SIZE: usize = 0x1000;
-					// Consume complete blocks until the offset is reached
-					for _ in 0 .. byte_ofs / BLOCK_SIZE
+				const BLOCK_SIZE: usize = 0x1000;
+				// Consume complete blocks until the offset is reached
+				for _ in 0 .. byte_ofs / BLOCK_SIZE
+				{
+					match decomp.get_block(None)
 					{
-						match decomp.get_block(None)
-						{
-						Some(BLOCK_SIZE) => {},
-						v => {
-							log_error!("Inconsistent filesystem: Encountered end of compresed data while seeking to byte_ofs={}: {:?}", byte_ofs, v);
-							return Err(::vfs::Error::InconsistentFilesystem)?
-							},
-						}
+					Some(BLOCK_SIZE) => {},
+					v => {
+						log_error!("Inconsistent filesystem: Encountered end of compresed data while seeking to byte_ofs={}: {:?}", byte_ofs, v);
+						return Err(::vfs::Error::InconsistentFilesystem)?
+						},
 					}
+				}
 
-					// If the read position ends up within a compressed block - read into a temporary buffer
-					// and then read from part of that buffer
-					if byte_ofs % BLOCK_SIZE != 0
-					{
-						let byte_ofs = byte_ofs % BLOCK_SIZE;
-						let mut uc_block = vec![0u8; BLOCK_SIZE];
-						let Some(len) = decomp.get_block(Some(&mut uc_block)) else {
-							log_error!("Inconsistent filesystem: Encountered end of compresed data while seeking to byte_ofs={}: partial block", byte_ofs);
-							return Err(::vfs::Error::InconsistentFilesystem);
-							};
-						assert!(len <= BLOCK_SIZE, "{} > {}", len, BLOCK_SIZE);
-						// This can be a partial block, if it's the final block in the file
-						if len < byte_ofs {
-							// Inconsistent: The offset is already clamped to within the valid initialised range of the file, so this shouldn't happen
-							log_error!("Inconsistent filesystem: Partial compressed chunk of {} bytes, byte_ofs={}", len, byte_ofs);
-							return Err(::vfs::Error::InconsistentFilesystem);
-						}
-
-						let len = usize::min(len - byte_ofs, dst.len());
-						let b = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
-						b.copy_from_slice(&uc_block[byte_ofs..][..len]);
+				// If the read position ends up within a compressed block - read into a temporary buffer
+				// and then read from part of that buffer
+				if byte_ofs % BLOCK_SIZE != 0
+				{
+					let byte_ofs = byte_ofs % BLOCK_SIZE;
+					let mut uc_block = vec![0u8; BLOCK_SIZE];
+					let Some(len) = decomp.get_block(Some(&mut uc_block)) else {
+						log_error!("Inconsistent filesystem: Encountered end of compresed data while seeking to byte_ofs={}: partial block", byte_ofs);
+						return Err(::vfs::Error::InconsistentFilesystem);
+						};
+					assert!(len <= BLOCK_SIZE, "{} > {}", len, BLOCK_SIZE);
+					// This can be a partial block, if it's the final block in the file
+					if len < byte_ofs {
+						// Inconsistent: The offset is already clamped to within the valid initialised range of the file, so this shouldn't happen
+						log_error!("Inconsistent filesystem: Partial compressed chunk of {} bytes, byte_ofs={}", len, byte_ofs);
+						return Err(::vfs::Error::InconsistentFilesystem);
 					}
 
-					// Consume entire (or leading partial) compression chunks
-					while dst.len() > 0
-					{
-						let Some(len) = decomp.get_block(Some(dst)) else {
-							log_error!("Inconsistent filesystem: Unexpected end of compressed data");
-							return Err(::vfs::Error::InconsistentFilesystem);
-							};
-						let len = usize::min(len, dst.len());
-						::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
-					}
-					},
+					let len = usize::min(len - byte_ofs, dst.len());
+					let b = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
+					b.copy_from_slice(&uc_block[byte_ofs..][..len]);
 				}
-			}
 
-			Ok(rv)
-			},
+				// Consume entire (or leading partial) compression chunks
+				while dst.len() > 0
+				{
+					let Some(len) = decomp.get_block(Some(dst)) else {
+						log_error!("Inconsistent filesystem: Unexpected end of compressed data");
+						return Err(::vfs::Error::InconsistentFilesystem);
+						};
+					let len = usize::min(len, dst.len());
+					::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
+				}
+
+				runbase_vcn += uncompressed_count;
+				cur_vcn = runbase_vcn;
+				cur_ofs = 0;
+				},
+			}
 		}
+
+		Ok(len - dst.len())
 	}
 }
 
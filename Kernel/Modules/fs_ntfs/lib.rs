@@ -46,7 +46,7 @@ impl vfs::mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, mount_handle: vfs::mount::SelfHandle) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, mount_handle: vfs::mount::SelfHandle, _options: &vfs::mount::MountOptions) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
 		let bs = {
 			let mut block = vec![0; ::core::cmp::max(512, vol.block_size() as usize)];
 			::kernel::futures::block_on(vol.read_blocks(0, &mut block[..]))?;
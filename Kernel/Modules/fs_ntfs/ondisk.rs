@@ -79,6 +79,7 @@ pub const ATTRNAME_INDEXNAME: &'static str = "$I30";	// Index over attribute 0x3
 #[derive(Copy,Clone)]
 pub enum FileAttr {
 	StandardInformation = 0x10,
+	AttributeList = 0x20,
 	FileName = 0x30,
 	Data = 0x80,
 	IndexRoot = 0x90,
@@ -93,6 +94,7 @@ impl FileAttr {
 				match self.0
 				{
 				0x10 => f.write_str("StandardInformation"),
+				0x20 => f.write_str("AttributeList"),
 				0x30 => f.write_str("FileName"),
 				0x80 => f.write_str("Data"),
 				0x90 => f.write_str("IndexRoot"),
@@ -106,7 +108,10 @@ impl FileAttr {
 	}
 }
 
-// TODO: Use the update sequence to fix loaded items (contains the actual values of each sector's last two bytes)
+/// Spacing of the words protected by an update sequence (independent of the volume's sector size)
+pub const UPDATE_SEQUENCE_STRIDE: usize = 512;
+
+/// Update sequence: a sequence number followed by the original values of each 512 byte stride's last two bytes
 pub struct UpdateSequence([u8]);
 impl UpdateSequence {
 	pub fn new_borrowed(v: &[u8]) -> Option<&Self> {
@@ -151,9 +156,11 @@ pub struct MftEntry([u8]);
 delegate!{ MftEntry -> MftEntryHeader =>
 	first_attrib_ofs: u16,
 	flags: u16,
+	pub hard_link_count: u16,
 
 	update_sequence_ofs: u16,
 	update_sequence_size: u16,
+	reference: u64,
 }
 impl MftEntry {
 	pub fn new_borrowed(v: &[u8]) -> Option<&Self> {
@@ -175,6 +182,14 @@ impl MftEntry {
 	pub fn flags_isdir(&self) -> bool {
 		self.flags() & 0x2 != 0
 	}
+	/// The base entry, if this is an extension entry (holding attributes that didn't fit in the base)
+	pub fn base_entry(&self) -> Option<MftEntryIdx> {
+		match self.reference() & ((1<<48)-1)
+		{
+		0 => None,
+		v => Some(MftEntryIdx(v as u32)),
+		}
+	}
 
 	/// Iterate attributes
 	pub fn iter_attributes(&self) -> impl Iterator<Item=&'_ MftAttrib> {
@@ -282,6 +297,11 @@ impl MftAttrib {
 		&self.0[ Self::size_of() .. ]
 	}
 
+	/// Attribute data is stored compressed (in units of `compression_unit_size`)
+	pub fn is_compressed(&self) -> bool {
+		self.flags() & 0x0001 != 0
+	}
+
 	pub fn inner(&self) -> MftAttribData<'_> {
 		if self.nonresident_flag() != 0 {
 			MftAttribData::Nonresident(MftAttrHeader_NonResident::from_slice(self.raw_data()).unwrap())
@@ -314,7 +334,7 @@ delegate!{ MftAttrHeader_NonResident =>
 	pub allocated_size: u64,
 	/// Size of the user-facing data (bytes)
 	pub real_size: u64,
-	/// Size of data on-disk (taking into account compression)
+	/// Size of the initialised data (bytes), anything past this reads as zero
 	pub initiated_size: u64,
 }
 impl MftAttrHeader_NonResident {
@@ -533,7 +553,6 @@ impl Attrib_IndexBlockHeader {
 	pub fn index_header(&self) -> &Attrib_IndexHeader {
 		Attrib_IndexHeader::from_slice(self.index_header_bytes()).unwrap()
 	}
-	// TODO: the update sequence handles spotting badly-written sectors
 	pub fn update_sequence(&self) -> &UpdateSequence {
 		UpdateSequence::from_subslice(&self.0, self.update_sequence_ofs(), self.update_sequence_size()).unwrap()
 	}
@@ -610,6 +629,85 @@ delegate! { Attrib_IndexEntry =>
 }
 
 
+/// Entry in an `$ATTRIBUTE_LIST`
+pub struct Attrib_AttributeListEntry([u8]);
+impl Attrib_AttributeListEntry {
+	fn size_of() -> usize {
+		// NOTE: Not the structure's size, that has trailing padding
+		0x1A
+	}
+	pub fn from_slice(v: &[u8]) -> Option<&Self> {
+		if v.len() < Self::size_of() {
+			log_debug!("Attrib_AttributeListEntry::from_slice: Too small, {} < {}", v.len(), Self::size_of());
+			return None;
+		}
+		// SAFE: Same repr
+		let rv: &Self = unsafe { ::core::mem::transmute(v) };
+		if (rv.entry_size() as usize) < Self::size_of() || rv.entry_size() as usize > v.len() {
+			return None;
+		}
+		if rv.name_ofs() as usize + rv.name_length() as usize * 2 > rv.entry_size() as usize {
+			return None;
+		}
+		Some(rv)
+	}
+	pub fn name(&self) -> &Utf16Le {
+		Utf16Le::new(&self.0[self.name_ofs() as usize..][..self.name_length() as usize * 2])
+	}
+	pub fn mft_entry(&self) -> MftEntryIdx {
+		MftEntryIdx( (self.mft_reference() & ((1<<48)-1)) as u32 )
+	}
+	/// Remainder of the list after this entry
+	pub fn next(&self) -> &[u8] {
+		&self.0[self.entry_size() as usize..]
+	}
+}
+delegate! { Attrib_AttributeListEntry =>
+	pub ty: u32,
+	entry_size: u16,
+	name_length: u8,
+	name_ofs: u8,
+	pub starting_vcn: u64,
+	mft_reference: u64,
+	pub attribute_id: u16,
+}
+/// Iterate the entries of an `$ATTRIBUTE_LIST` attribute's data
+pub fn iter_attribute_list(mut data: &[u8]) -> impl Iterator<Item=&Attrib_AttributeListEntry> {
+	::core::iter::from_fn(move || {
+		let rv = Attrib_AttributeListEntry::from_slice(data)?;
+		data = rv.next();
+		Some(rv)
+		})
+}
+
+pub struct Attrib_StandardInformation([u8]);
+impl Attrib_StandardInformation {
+	pub fn from_slice(v: &[u8]) -> Option<&Self> {
+		if v.len() < ::core::mem::size_of::<raw::Attrib_StandardInformation>() {
+			return None;
+		}
+		// SAFE: Same repr
+		Some(unsafe { ::core::mem::transmute(v) })
+	}
+}
+delegate!{ Attrib_StandardInformation =>
+	pub creation_time: i64,
+	pub last_data_mod_time: i64,
+	pub last_mft_mod_time: i64,
+	pub last_access_time: i64,
+	/// DOS-style attribute flags (0x1 = Read-only)
+	pub file_attributes: u32,
+}
+/// Convert a NTFS timestamp (100ns units since 1601) into a unix timestamp
+pub fn filetime_to_unix(v: i64) -> i64 {
+	if v == 0 {
+		0
+	}
+	else {
+		v.div_euclid(10_000_000) - 11_644_473_600
+	}
+}
+
 pub struct Attrib_Filename([u8]);
 impl Attrib_Filename {
 	fn size_of() -> usize {
@@ -635,7 +733,12 @@ impl Attrib_Filename {
 		let d = &self.0[Self::size_of()..][..self.filename_length() as usize * 2];
 		Utf16Le::new(d)
 	}
+	/// This is a DOS (8.3) name, there will also be a separate long name for the same file
+	pub fn is_dos_name(&self) -> bool {
+		self.filename_namespace() == 2
+	}
 }
 delegate!{ Attrib_Filename =>
 	filename_length: u8,
+	filename_namespace: u8,
 }
@@ -174,7 +174,7 @@ pub struct MftAttrHeader_NonResident {
 	allocated_size: u64,
 	/// User-facing byte count
 	real_size: u64,
-	/// Size of the data, after compression
+	/// Size of the initialised data, past this reads as zero
 	initiated_size: u64,
 	// name: [u16],
 }
@@ -182,6 +182,20 @@ pub struct MftAttrHeader_NonResident {
 type Filetime = i64;
 #[derive(::kernel_derives::FieldsLE)]
 #[repr(C)]
+pub struct Attrib_StandardInformation {
+	/// Time the file was created
+	creation_time: Filetime,
+	/// Last change time for the data
+	last_data_mod_time: Filetime,
+	/// Last change time for the MFT entry
+	last_mft_mod_time: Filetime,
+	/// Last Access Time (unreliable on most systems)
+	last_access_time: Filetime,
+	/// DOS-style file attribute flags
+	file_attributes: u32,
+}
+#[derive(::kernel_derives::FieldsLE)]
+#[repr(C)]
 pub struct Attrib_Filename {
 	/// Parent directory MFT entry
 	parent_directory: u64,
@@ -260,6 +274,25 @@ pub struct Attrib_IndexBlockHeader {
 }
 
 
+/// Entry in an `$ATTRIBUTE_LIST`, giving the MFT entry that holds an attribute (or one extent of it)
+#[derive(::kernel_derives::FieldsLE)]
+#[repr(C)]
+pub struct Attrib_AttributeListEntry {
+	/// Attribute type
+	ty: u32,
+	/// Size of this entry (including the name and padding)
+	entry_size: u16,
+	name_length: u8,
+	name_ofs: u8,
+	/// First VCN of this extent (zero for resident attributes)
+	starting_vcn: u64,
+	/// MFT entry holding the attribute
+	mft_reference: u64,
+	/// Attribute ID within that MFT entry
+	attribute_id: u16,
+	//name: [u16],
+}
+
 #[derive(::kernel_derives::FieldsLE)]
 #[repr(C)]
 pub struct Attrib_IndexEntry {
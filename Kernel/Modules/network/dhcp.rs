@@ -0,0 +1,637 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/dhcp.rs
+//! DHCPv4 client (RFC 2131)
+//!
+//! A client is started for every registered NIC. If the NIC is given a static address before the initial
+//! delay expires, the client stays idle.
+use kernel::lib::Vec;
+use kernel::lib::mem::Arc;
+use kernel::sync::Mutex;
+use core::sync::atomic::{AtomicBool,Ordering};
+use crate::nic::{MacAddr,PacketReader,SparsePacket};
+use crate::ipv4::Address;
+
+const PORT_SERVER: u16 = 67;
+const PORT_CLIENT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+/// Ask the server to broadcast replies (we can't receive unicast before having an address)
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Length of the fixed BOOTP fields (including the magic cookie)
+const FIXED_LEN: usize = 240;
+/// Minimum BOOTP message size (some relays/servers drop anything smaller)
+const MIN_MESSAGE_LEN: usize = 300;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_REQUESTED_ADDR: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Delay before the first DISCOVER (RFC 2131 suggests a random 1-10 seconds)
+const INITIAL_DELAY_MS: u64 = 1000;
+/// Retransmission timeout for DISCOVER/REQUEST, doubled on each attempt
+const RETRANSMIT_MIN_MS: u64 = 4_000;
+const RETRANSMIT_MAX_MS: u64 = 64_000;
+/// Minimum interval between REQUESTs when renewing/rebinding
+const RENEW_RETRANSMIT_MIN_MS: u64 = 60_000;
+/// Lease time sent by the server to indicate an infinite lease
+const LEASE_INFINITE: u32 = 0xFFFF_FFFF;
+
+static CLIENTS: Mutex<Vec<Arc<Client>>> = Mutex::new(Vec::new());
+
+/// Handle to a running client, stops the client (and removes the leased address) when dropped
+pub struct ClientHandle
+{
+	client: Arc<Client>,
+	thread: ::kernel::threads::WorkerThread,
+}
+
+struct Client
+{
+	mac: MacAddr,
+	stop_flag: AtomicBool,
+	/// Poked when the state changes (or the client should stop)
+	cv: ::kernel::futures::Condvar,
+	state: Mutex<ClientState>,
+}
+
+#[derive(Copy,Clone,Debug)]
+enum State
+{
+	/// Waiting to start discovery
+	Init,
+	/// DISCOVER sent, waiting for an offer
+	Selecting,
+	/// REQUEST sent for an offer, waiting for an acknowledgement
+	Requesting { offer: Address, server: Address },
+	/// Holding a lease
+	Bound,
+	/// T1 has passed, unicasting REQUESTs to the leasing server
+	Renewing,
+	/// T2 has passed, broadcasting REQUESTs to any server
+	Rebinding,
+	/// The interface was statically configured
+	Disabled,
+}
+
+#[derive(Copy,Clone,Debug)]
+struct Lease
+{
+	address: Address,
+	mask: u8,
+	router: Option<Address>,
+	server: Address,
+	/// Times (in ticks) to start renewing, rebinding, and when the lease expires
+	t1: u64,
+	t2: u64,
+	expiry: u64,
+}
+
+struct ClientState
+{
+	state: State,
+	xid: u32,
+	/// Time (in ticks) that the current exchange started, used for the `secs` field
+	start_time: u64,
+	/// Time (in ticks) of the next timer action
+	next_event: u64,
+	retransmit_ms: u64,
+	lease: Option<Lease>,
+}
+
+/// A message to be sent once the client state is unlocked
+struct Transmit
+{
+	msg: Vec<u8>,
+	source: Address,
+	/// Unicast destination, `None` for a broadcast
+	dest: Option<Address>,
+}
+
+/// Start a DHCP client on the given NIC
+pub fn start(mac: MacAddr) -> ClientHandle
+{
+	let now = ::kernel::time::ticks();
+	let client = Arc::new(Client {
+		mac: mac,
+		stop_flag: AtomicBool::new(false),
+		cv: ::kernel::futures::Condvar::new(),
+		state: Mutex::new(ClientState {
+			state: State::Init,
+			// No RNG available, so mix the MAC with the current time
+			xid: u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]) ^ (now as u32),
+			start_time: now,
+			next_event: now + INITIAL_DELAY_MS,
+			retransmit_ms: RETRANSMIT_MIN_MS,
+			lease: None,
+			}),
+		});
+	CLIENTS.lock().push(client.clone());
+	let thread = {
+		let client = client.clone();
+		::kernel::threads::WorkerThread::new("DHCP Client", move || worker(&client))
+		};
+	ClientHandle {
+		client: client,
+		thread: thread,
+		}
+}
+impl ::core::ops::Drop for ClientHandle
+{
+	fn drop(&mut self)
+	{
+		self.client.stop_flag.store(true, Ordering::SeqCst);
+		self.client.cv.wake_all();
+		self.thread.wait().expect("Couldn't wait for DHCP worker to terminate");
+		CLIENTS.lock().retain(|c| !Arc::ptr_eq(c, &self.client));
+		if let Some(lease) = self.client.state.lock().lease.take()
+		{
+			lease.uninstall(self.client.mac);
+		}
+	}
+}
+
+fn worker(client: &Client)
+{
+	while !client.stop_flag.load(Ordering::SeqCst)
+	{
+		let key = client.cv.get_key();
+		let now = ::kernel::time::ticks();
+		let (wakeup, tx) = client.state.lock().run_tasks(client.mac, now);
+		if let Some(tx) = tx {
+			tx.send(client.mac);
+		}
+		match wakeup
+		{
+		Some(t) if t <= now => {},
+		Some(t) => ::kernel::futures::block_on(::kernel::futures::join_one(
+			client.cv.wait(key),
+			::kernel::futures::msleep( (t - now) as usize )
+			)),
+		None => ::kernel::futures::block_on(client.cv.wait(key)),
+		}
+	}
+}
+
+/// Handle an incoming UDP datagram (called by the IPv4 layer before interface matching)
+///
+/// Returns `true` if the datagram was for a DHCP client
+pub(crate) fn handle_rx(source_mac: MacAddr, src: Address, dest: Address, mut pkt: PacketReader) -> bool
+{
+	let hdr: [u8; 8] = match pkt.read_bytes([0; 8])
+		{
+		Ok(v) => v,
+		Err(_) => return false,
+		};
+	let src_port = u16::from_be_bytes([hdr[0], hdr[1]]);
+	let dst_port = u16::from_be_bytes([hdr[2], hdr[3]]);
+	let length = u16::from_be_bytes([hdr[4], hdr[5]]) as usize;
+	if src_port != PORT_SERVER || dst_port != PORT_CLIENT {
+		return false;
+	}
+	if length < 8 || length - 8 > pkt.remain() {
+		return false;
+	}
+	let mut data = vec![0; length - 8];
+	if pkt.read(&mut data).is_err() {
+		return false;
+	}
+	let msg = match Message::parse(&data)
+		{
+		Some(v) => v,
+		None => {
+			log_debug!("Malformed DHCP message from {:?}", src);
+			return false;
+			},
+		};
+	let client = match CLIENTS.lock().iter().find(|c| c.mac == msg.chaddr)
+		{
+		Some(v) => v.clone(),
+		None => return false,
+		};
+
+	if hdr[6] != 0 || hdr[7] != 0
+	{
+		let sum = crate::udp::calculate_checksum(crate::Address::Ipv4(src), crate::Address::Ipv4(dest), &hdr, &data);
+		if sum != 0 {
+			log_error!("Incorrect checksum on DHCP message: 0x{:04x} != 0", sum);
+			return true;
+		}
+	}
+	if !src.is_zero() {
+		crate::arp::snoop_v4(source_mac, src);
+	}
+
+	let tx = client.state.lock().handle_message(client.mac, &msg, ::kernel::time::ticks());
+	if let Some(tx) = tx {
+		tx.send(client.mac);
+	}
+	client.cv.wake_all();
+	true
+}
+
+impl ClientState
+{
+	/// Handle timer actions, returning the time of the next action and a message to send
+	fn run_tasks(&mut self, mac: MacAddr, now: u64) -> (Option<u64>, Option<Transmit>)
+	{
+		if let State::Disabled = self.state {
+			return (None, None);
+		}
+		if now < self.next_event {
+			// An infinite lease has no timers
+			return (if self.next_event == !0 { None } else { Some(self.next_event) }, None);
+		}
+
+		let tx = match self.state
+			{
+			State::Disabled => None,
+			State::Init => {
+				if self.lease.is_none() && crate::ipv4::has_interface(mac)
+				{
+					log_notice!("{:x?} has a static address, not starting DHCP", mac);
+					self.state = State::Disabled;
+					return (None, None);
+				}
+				self.new_exchange(now);
+				self.state = State::Selecting;
+				self.retransmit_ms = RETRANSMIT_MIN_MS;
+				self.next_event = now + self.retransmit_ms;
+				log_debug!("{:x?} DHCPDISCOVER xid={:#x}", mac, self.xid);
+				Some(self.make_transmit(mac, DHCPDISCOVER, now))
+				},
+			State::Selecting => {
+				self.retransmit_ms = ::core::cmp::min(self.retransmit_ms * 2, RETRANSMIT_MAX_MS);
+				self.next_event = now + self.retransmit_ms;
+				Some(self.make_transmit(mac, DHCPDISCOVER, now))
+				},
+			State::Requesting { .. } => {
+				if self.retransmit_ms >= RETRANSMIT_MAX_MS
+				{
+					log_notice!("{:x?} No DHCPACK received, restarting discovery", mac);
+					self.state = State::Init;
+					self.next_event = now;
+					None
+				}
+				else
+				{
+					self.retransmit_ms *= 2;
+					self.next_event = now + self.retransmit_ms;
+					Some(self.make_transmit(mac, DHCPREQUEST, now))
+				}
+				},
+			State::Bound => {
+				let lease = self.lease.expect("Bound without a lease");
+				log_debug!("{:x?} Renewing lease on {:?}", mac, lease.address);
+				self.new_exchange(now);
+				self.state = State::Renewing;
+				self.next_event = Self::renew_retransmit_time(now, lease.t2);
+				Some(self.make_transmit(mac, DHCPREQUEST, now))
+				},
+			State::Renewing => {
+				let lease = self.lease.expect("Renewing without a lease");
+				if now >= lease.t2
+				{
+					log_debug!("{:x?} Rebinding lease on {:?}", mac, lease.address);
+					self.state = State::Rebinding;
+					self.next_event = Self::renew_retransmit_time(now, lease.expiry);
+				}
+				else
+				{
+					self.next_event = Self::renew_retransmit_time(now, lease.t2);
+				}
+				Some(self.make_transmit(mac, DHCPREQUEST, now))
+				},
+			State::Rebinding => {
+				let lease = self.lease.expect("Rebinding without a lease");
+				if now >= lease.expiry
+				{
+					log_notice!("{:x?} Lease on {:?} expired", mac, lease.address);
+					self.lease = None;
+					lease.uninstall(mac);
+					self.state = State::Init;
+					self.next_event = now;
+					None
+				}
+				else
+				{
+					self.next_event = Self::renew_retransmit_time(now, lease.expiry);
+					Some(self.make_transmit(mac, DHCPREQUEST, now))
+				}
+				},
+			};
+		(Some(self.next_event), tx)
+	}
+
+	/// Handle a reply from a server, returning a message to send in response
+	fn handle_message(&mut self, mac: MacAddr, msg: &Message, now: u64) -> Option<Transmit>
+	{
+		if msg.op != OP_REPLY || msg.xid != self.xid {
+			log_debug!("{:x?} Ignoring DHCP message (op={} xid={:#x})", mac, msg.op, msg.xid);
+			return None;
+		}
+		log_debug!("{:x?} DHCP message type {} in {:?}: {:?}", mac, msg.msg_type, self.state, msg);
+		match (self.state, msg.msg_type)
+		{
+		(State::Selecting, DHCPOFFER) => {
+			// Take the first offer
+			let server = msg.server_id?;
+			self.state = State::Requesting { offer: msg.yiaddr, server: server };
+			self.retransmit_ms = RETRANSMIT_MIN_MS;
+			self.next_event = now + self.retransmit_ms;
+			Some(self.make_transmit(mac, DHCPREQUEST, now))
+			},
+		(State::Requesting { offer, .. }, DHCPACK) if msg.yiaddr == offer => {
+			self.bind(mac, msg, now);
+			None
+			},
+		(State::Renewing, DHCPACK)
+		| (State::Rebinding, DHCPACK) => {
+			self.bind(mac, msg, now);
+			None
+			},
+		(State::Requesting { .. }, DHCPNAK)
+		| (State::Renewing, DHCPNAK)
+		| (State::Rebinding, DHCPNAK) => {
+			log_notice!("{:x?} DHCPNAK from {:?}, restarting", mac, msg.server_id);
+			if let Some(lease) = self.lease.take() {
+				lease.uninstall(mac);
+			}
+			self.state = State::Init;
+			self.next_event = now;
+			None
+			},
+		_ => None,
+		}
+	}
+
+	/// Accept the lease in an ACK (installing the address if it changed)
+	fn bind(&mut self, mac: MacAddr, msg: &Message, now: u64)
+	{
+		let lease_time = msg.lease_time.unwrap_or(LEASE_INFINITE);
+		let (t1, t2, expiry) = if lease_time == LEASE_INFINITE {
+				(!0, !0, !0)
+			}
+			else {
+				let t1 = msg.renewal_time.unwrap_or(lease_time / 2);
+				let t2 = msg.rebinding_time.unwrap_or( (lease_time as u64 * 7 / 8) as u32 );
+				(now + t1 as u64 * 1000, now + t2 as u64 * 1000, now + lease_time as u64 * 1000)
+			};
+		let server = match (msg.server_id, self.state)
+			{
+			(Some(v), _) => v,
+			(None, State::Requesting { server, .. }) => server,
+			(None, _) => self.lease.map(|l| l.server).unwrap_or(Address::zero()),
+			};
+		let lease = Lease {
+			address: msg.yiaddr,
+			mask: match msg.subnet_mask
+				{
+				Some(m) => m.as_u32().leading_ones() as u8,
+				None => classful_mask(msg.yiaddr),
+				},
+			router: msg.router,
+			server: server,
+			t1: t1,
+			t2: t2,
+			expiry: expiry,
+			};
+		log_notice!("{:x?} Bound to {:?}/{} (router {:?}, lease {}s)", mac, lease.address, lease.mask, lease.router, lease_time);
+		match self.lease
+		{
+		Some(old) if old.address == lease.address && old.mask == lease.mask && old.router == lease.router => {},
+		Some(old) => {
+			old.uninstall(mac);
+			lease.install(mac);
+			},
+		None => lease.install(mac),
+		}
+		self.lease = Some(lease);
+		self.state = State::Bound;
+		self.next_event = t1;
+	}
+
+	fn new_exchange(&mut self, now: u64)
+	{
+		self.xid = self.xid.wrapping_add(1);
+		self.start_time = now;
+	}
+
+	/// Renew/rebind retransmissions wait half of the remaining time (with a lower limit)
+	fn renew_retransmit_time(now: u64, limit: u64) -> u64
+	{
+		let delay = ::core::cmp::max( limit.saturating_sub(now) / 2, RENEW_RETRANSMIT_MIN_MS );
+		::core::cmp::min(now + delay, limit)
+	}
+
+	fn make_transmit(&self, mac: MacAddr, msg_type: u8, now: u64) -> Transmit
+	{
+		// The client address is only filled once the address is usable
+		let (ciaddr, dest) = match (self.state, self.lease)
+			{
+			(State::Renewing, Some(l)) => (l.address, Some(l.server)),
+			(State::Rebinding, Some(l)) => (l.address, None),
+			_ => (Address::zero(), None),
+			};
+		let flags = if ciaddr.is_zero() { FLAG_BROADCAST } else { 0 };
+		let secs = ::core::cmp::min( (now - self.start_time) / 1000, 0xFFFF ) as u16;
+
+		let mut msg = Vec::with_capacity(MIN_MESSAGE_LEN);
+		msg.extend_from_slice(&[OP_REQUEST, HTYPE_ETHERNET, 6, 0]);
+		msg.extend_from_slice(&self.xid.to_be_bytes());
+		msg.extend_from_slice(&secs.to_be_bytes());
+		msg.extend_from_slice(&flags.to_be_bytes());
+		msg.extend_from_slice(&ciaddr.0);
+		// yiaddr, siaddr, giaddr
+		msg.extend_from_slice(&[0; 4*3]);
+		// chaddr (padded to 16 bytes), then the unused sname and file fields
+		msg.extend_from_slice(&mac);
+		msg.resize(FIXED_LEN - 4, 0);
+		msg.extend_from_slice(&MAGIC_COOKIE);
+
+		msg.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, msg_type]);
+		if let State::Requesting { offer, server } = self.state
+		{
+			msg.extend_from_slice(&[OPT_REQUESTED_ADDR, 4]);
+			msg.extend_from_slice(&offer.0);
+			msg.extend_from_slice(&[OPT_SERVER_ID, 4]);
+			msg.extend_from_slice(&server.0);
+		}
+		msg.extend_from_slice(&[OPT_PARAMETER_LIST, 5, OPT_SUBNET_MASK, OPT_ROUTER, OPT_LEASE_TIME, OPT_RENEWAL_TIME, OPT_REBINDING_TIME]);
+		msg.push(OPT_END);
+		if msg.len() < MIN_MESSAGE_LEN {
+			msg.resize(MIN_MESSAGE_LEN, OPT_PAD);
+		}
+
+		Transmit {
+			msg: msg,
+			source: ciaddr,
+			dest: dest,
+			}
+	}
+}
+
+impl Lease
+{
+	fn install(&self, mac: MacAddr)
+	{
+		crate::ipv4::add_interface(mac, self.address, self.mask);
+		if let Some(router) = self.router
+		{
+			let r = crate::ipv4::routes::add(crate::ipv4::routes::Route {
+				network: Address::zero(),
+				mask: 0,
+				gateway: router,
+				source: self.address,
+				metric: 0,
+				});
+			if let Err(e) = r {
+				log_warning!("{:x?} Unable to add default route via {:?}: {:?}", mac, router, e);
+			}
+		}
+	}
+	fn uninstall(&self, mac: MacAddr)
+	{
+		// NOTE: Removing the address also removes the routes using it
+		let _ = crate::ipv4::del_interface(mac, self.address);
+	}
+}
+
+impl Transmit
+{
+	fn send(&self, mac: MacAddr)
+	{
+		let dest = self.dest.unwrap_or(Address::broadcast());
+		let len = (8 + self.msg.len()) as u16;
+		let mut hdr = [
+			(PORT_CLIENT >> 8) as u8, PORT_CLIENT as u8,
+			(PORT_SERVER >> 8) as u8, PORT_SERVER as u8,
+			(len >> 8) as u8, len as u8,
+			0, 0,
+			];
+		let sum = match crate::udp::calculate_checksum(crate::Address::Ipv4(self.source), crate::Address::Ipv4(dest), &hdr, &self.msg)
+			{
+			// A calculated checksum of zero is sent as all ones (zero means no checksum)
+			0 => 0xFFFF,
+			v => v,
+			};
+		hdr[6] = (sum >> 8) as u8;
+		hdr[7] = (sum >> 0) as u8;
+		let data_pkt = SparsePacket::new_root(&self.msg);
+		let pkt = SparsePacket::new_chained(&hdr, &data_pkt);
+		match self.dest
+		{
+		Some(_) => ::kernel::futures::block_on(crate::ipv4::send_packet(self.source, dest, crate::udp::IPV4_PROTO_UDP, pkt)),
+		None => crate::ipv4::send_raw(mac, [0xFF; 6], self.source, dest, crate::udp::IPV4_PROTO_UDP, pkt),
+		}
+	}
+}
+
+/// Default mask for when the server doesn't provide one
+fn classful_mask(addr: Address) -> u8
+{
+	match addr.0[0]
+	{
+	0 ..= 127 => 8,
+	128 ..= 191 => 16,
+	_ => 24,
+	}
+}
+
+#[derive(Debug)]
+struct Message
+{
+	op: u8,
+	xid: u32,
+	yiaddr: Address,
+	chaddr: MacAddr,
+
+	msg_type: u8,
+	server_id: Option<Address>,
+	subnet_mask: Option<Address>,
+	router: Option<Address>,
+	lease_time: Option<u32>,
+	renewal_time: Option<u32>,
+	rebinding_time: Option<u32>,
+}
+impl Message
+{
+	// NOTE: Option overloading (using the sname/file fields for options) isn't supported
+	fn parse(data: &[u8]) -> Option<Message>
+	{
+		if data.len() < FIXED_LEN || data[236..240] != MAGIC_COOKIE {
+			return None;
+		}
+		if data[1] != HTYPE_ETHERNET || data[2] != 6 {
+			return None;
+		}
+		let mut chaddr = [0; 6];
+		chaddr.copy_from_slice(&data[28..34]);
+		let mut rv = Message {
+			op: data[0],
+			xid: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+			yiaddr: Address([data[16], data[17], data[18], data[19]]),
+			chaddr: chaddr,
+			msg_type: 0,
+			server_id: None,
+			subnet_mask: None,
+			router: None,
+			lease_time: None,
+			renewal_time: None,
+			rebinding_time: None,
+			};
+
+		let mut opts = &data[FIXED_LEN..];
+		loop
+		{
+			match opts
+			{
+			[] | [OPT_END, ..] => break,
+			[OPT_PAD, rest @ ..] => opts = rest,
+			[ty, len, rest @ ..] => {
+				let len = *len as usize;
+				if rest.len() < len {
+					return None;
+				}
+				let (val, rest) = rest.split_at(len);
+				match (*ty, val)
+				{
+				(OPT_MESSAGE_TYPE, &[v]) => rv.msg_type = v,
+				(OPT_SERVER_ID, &[a,b,c,d]) => rv.server_id = Some(Address([a,b,c,d])),
+				(OPT_SUBNET_MASK, &[a,b,c,d]) => rv.subnet_mask = Some(Address([a,b,c,d])),
+				// Multiple routers can be listed, in order of preference
+				(OPT_ROUTER, &[a,b,c,d, ..]) => rv.router = Some(Address([a,b,c,d])),
+				(OPT_LEASE_TIME, &[a,b,c,d]) => rv.lease_time = Some(u32::from_be_bytes([a,b,c,d])),
+				(OPT_RENEWAL_TIME, &[a,b,c,d]) => rv.renewal_time = Some(u32::from_be_bytes([a,b,c,d])),
+				(OPT_REBINDING_TIME, &[a,b,c,d]) => rv.rebinding_time = Some(u32::from_be_bytes([a,b,c,d])),
+				_ => {},
+				}
+				opts = rest;
+				},
+			[_] => return None,
+			}
+		}
+		if rv.msg_type == 0 {
+			return None;
+		}
+		Some(rv)
+	}
+}
@@ -0,0 +1,218 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/icmp.rs
+//! Internet Control Message Protocol (IPv4)
+use kernel::lib::Vec;
+use crate::nic::{SparsePacket,PacketReader};
+use crate::ipv4::RxResult;
+use kernel::futures::block_on;
+
+const IPV4_PROTO_ICMP: u8 = 1;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DEST_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const TYPE_TIME_EXCEEDED: u8 = 11;
+
+// Codes for `TYPE_DEST_UNREACHABLE`
+pub const UNREACHABLE_NET: u8 = 0;
+pub const UNREACHABLE_HOST: u8 = 1;
+pub const UNREACHABLE_PROTOCOL: u8 = 2;
+pub const UNREACHABLE_PORT: u8 = 3;
+pub const UNREACHABLE_FRAG_NEEDED: u8 = 4;
+
+/// Number of bytes of the original datagram (after the IP header) quoted in an error
+const QUOTE_DATA_LEN: usize = 8;
+
+pub fn init()
+{
+	crate::ipv4::register_handler(IPV4_PROTO_ICMP, rx_handler_v4).unwrap();
+}
+
+/// An error reported by a remote host (or router) against a packet we sent
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ErrorKind
+{
+	NetUnreachable,
+	HostUnreachable,
+	ProtocolUnreachable,
+	PortUnreachable,
+	/// The packet was too large, and had "Don't Fragment" set
+	FragmentationNeeded,
+	/// TTL ran out in transit
+	TimeExceeded,
+	/// Any other destination unreachable code
+	Other(u8),
+}
+impl ErrorKind
+{
+	/// Hard errors indicate that the remote end isn't going to accept the traffic (RFC 1122 4.2.3.9)
+	pub fn is_hard(&self) -> bool
+	{
+		match *self
+		{
+		ErrorKind::ProtocolUnreachable
+		| ErrorKind::PortUnreachable => true,
+		_ => false,
+		}
+	}
+}
+
+fn rx_handler_v4(int: &crate::ipv4::Interface, src_addr: crate::ipv4::Address, mut pkt: PacketReader) -> RxResult
+{
+	// Validate the checksum over the entire message
+	{
+		let mut pkt = pkt.clone();
+		let psum_whole = !crate::ipv4::calculate_checksum( (0 .. pkt.remain() / 2).map(|_| pkt.read_u16n().unwrap()) );
+		// Final byte is decoded as if there was a zero after it (so as 0x??00)
+		let psum_partial = if pkt.remain() > 0 { (pkt.read_u8().unwrap() as u16) << 8 } else { 0 };
+		let sum = crate::ipv4::calculate_checksum([psum_whole, psum_partial].iter().copied());
+		if sum != 0 {
+			log_error!("Incorrect checksum: 0x{:04x} != 0", sum);
+			return RxResult::Handled;
+		}
+	}
+
+	let hdr = match PktHeader::read(&mut pkt)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_error!("Undersized packet: Ran out of data reading header");
+			return RxResult::Handled;
+			},
+		};
+	log_debug!("ICMP {:?} -> {:?}: {:?}", src_addr, int.addr(), hdr);
+	match hdr.ty
+	{
+	TYPE_ECHO_REQUEST => {
+		// Reply with the same identifier, sequence number and data
+		let mut data = vec![0; pkt.remain()];
+		if pkt.read(&mut data).is_err() {
+			return RxResult::Handled;
+		}
+		send_message_v4(int.addr(), src_addr, TYPE_ECHO_REPLY, 0, hdr.rest, &data);
+		},
+	TYPE_ECHO_REPLY => {
+		// TODO: Pass to a user-facing ping interface
+		log_debug!("Echo reply from {:?}", src_addr);
+		},
+	TYPE_DEST_UNREACHABLE => {
+		let kind = match hdr.code
+			{
+			UNREACHABLE_NET => ErrorKind::NetUnreachable,
+			UNREACHABLE_HOST => ErrorKind::HostUnreachable,
+			UNREACHABLE_PROTOCOL => ErrorKind::ProtocolUnreachable,
+			UNREACHABLE_PORT => ErrorKind::PortUnreachable,
+			UNREACHABLE_FRAG_NEEDED => ErrorKind::FragmentationNeeded,
+			_ => ErrorKind::Other(hdr.code),
+			};
+		handle_error_v4(kind, pkt);
+		},
+	TYPE_TIME_EXCEEDED => {
+		handle_error_v4(ErrorKind::TimeExceeded, pkt);
+		},
+	_ => {
+		log_debug!("Unhandled ICMP type {} (code {})", hdr.ty, hdr.code);
+		},
+	}
+	RxResult::Handled
+}
+
+/// Decode the quoted datagram from an error message, and pass the error to the protocol that sent it
+fn handle_error_v4(kind: ErrorKind, mut pkt: PacketReader)
+{
+	let (proto, src, dst, src_port, dst_port) = match read_quoted_v4(&mut pkt)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_warning!("ICMP error {:?} with a malformed quoted datagram", kind);
+			return ;
+			},
+		};
+	log_debug!("ICMP error {:?} for proto {} {:?}:{} -> {:?}:{}", kind, proto, src, src_port, dst, dst_port);
+	// NOTE: The quoted datagram was sent by us, so the source is the local end
+	let local = (crate::Address::Ipv4(src), src_port);
+	let remote = (crate::Address::Ipv4(dst), dst_port);
+	match proto
+	{
+	crate::tcp::IPV4_PROTO_TCP => crate::tcp::handle_icmp_error(local, remote, kind),
+	crate::udp::IPV4_PROTO_UDP => crate::udp::handle_icmp_error(local, remote, kind),
+	_ => {},
+	}
+}
+
+/// Read the protocol, addresses and ports from the datagram quoted in an error message
+fn read_quoted_v4(pkt: &mut PacketReader) -> Result<(u8, crate::ipv4::Address, crate::ipv4::Address, u16, u16), ()>
+{
+	let hdr: [u8; 20] = pkt.read_bytes([0; 20])?;
+	if hdr[0] >> 4 != 4 {
+		return Err( () );
+	}
+	let hdr_len = (hdr[0] & 0xF) as usize * 4;
+	for _ in 20 .. hdr_len {
+		pkt.read_u8()?;
+	}
+	let proto = hdr[9];
+	let src = crate::ipv4::Address([hdr[12], hdr[13], hdr[14], hdr[15]]);
+	let dst = crate::ipv4::Address([hdr[16], hdr[17], hdr[18], hdr[19]]);
+	// Only the ports are needed from the original data (TCP and UDP both start with them)
+	let src_port = pkt.read_u16n()?;
+	let dst_port = pkt.read_u16n()?;
+	Ok( (proto, src, dst, src_port, dst_port) )
+}
+
+/// Send a destination unreachable message in response to the packet in `ip_pkt` (positioned at the start of the IP header)
+pub fn send_unreachable_v4(local: crate::ipv4::Address, remote: crate::ipv4::Address, code: u8, mut ip_pkt: PacketReader)
+{
+	// Quote the IP header and the first few bytes of the datagram
+	let hdr_len = match ip_pkt.clone().read_u8()
+		{
+		Ok(v) => (v & 0xF) as usize * 4,
+		Err(_) => return,
+		};
+	let mut quote: Vec<u8> = vec![0; ::core::cmp::min(hdr_len + QUOTE_DATA_LEN, ip_pkt.remain())];
+	if ip_pkt.read(&mut quote).is_err() {
+		return ;
+	}
+	log_debug!("Sending unreachable ({}) to {:?}", code, remote);
+	send_message_v4(local, remote, TYPE_DEST_UNREACHABLE, code, [0; 4], &quote);
+}
+
+fn send_message_v4(local: crate::ipv4::Address, remote: crate::ipv4::Address, ty: u8, code: u8, rest: [u8; 4], data: &[u8])
+{
+	let mut hdr = [ty, code, 0, 0, rest[0], rest[1], rest[2], rest[3]];
+	let sum_header = crate::ipv4::calculate_checksum( hdr.chunks(2).map(|v| (v[0] as u16) << 8 | v[1] as u16) );
+	// Final byte is summed as if there was a zero after it (so as 0x??00)
+	let sum_data = crate::ipv4::calculate_checksum( data.chunks(2).map(|v| (v[0] as u16) << 8 | v.get(1).copied().unwrap_or(0) as u16) );
+	let sum = crate::ipv4::calculate_checksum([ !sum_header, !sum_data ].iter().copied());
+	hdr[2] = (sum >> 8) as u8;
+	hdr[3] = (sum >> 0) as u8;
+
+	let data_pkt = SparsePacket::new_root(data);
+	let hdr_pkt = SparsePacket::new_chained(&hdr, &data_pkt);
+	// TODO: Queue a packet instead of blocking here
+	block_on(crate::ipv4::send_packet(local, remote, IPV4_PROTO_ICMP, hdr_pkt));
+}
+
+#[derive(Debug)]
+struct PktHeader
+{
+	ty: u8,
+	code: u8,
+	checksum: u16,
+	/// Type-specific data (e.g. identifier and sequence number for echo)
+	rest: [u8; 4],
+}
+impl PktHeader
+{
+	fn read(reader: &mut PacketReader) -> Result<Self, ()>
+	{
+		Ok(PktHeader {
+			ty: reader.read_u8()?,
+			code: reader.read_u8()?,
+			checksum: reader.read_u16n()?,
+			rest: reader.read_bytes([0; 4])?,
+			})
+	}
+}
@@ -0,0 +1,212 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/icmpv6.rs
+//! Internet Control Message Protocol (IPv6)
+use kernel::lib::Vec;
+use crate::nic::{SparsePacket,PacketReader};
+use crate::ipv4::RxResult;
+use crate::ipv6::Address;
+use crate::icmp::ErrorKind;
+use kernel::futures::block_on;
+
+pub(crate) const IPV6_NEXT_HEADER_ICMPV6: u8 = 58;
+
+const TYPE_DEST_UNREACHABLE: u8 = 1;
+const TYPE_PACKET_TOO_BIG: u8 = 2;
+const TYPE_TIME_EXCEEDED: u8 = 3;
+const TYPE_PARAMETER_PROBLEM: u8 = 4;
+const TYPE_ECHO_REQUEST: u8 = 128;
+const TYPE_ECHO_REPLY: u8 = 129;
+pub(crate) const TYPE_NEIGHBOUR_SOLICITATION: u8 = 135;
+pub(crate) const TYPE_NEIGHBOUR_ADVERTISEMENT: u8 = 136;
+
+// Codes for `TYPE_DEST_UNREACHABLE`
+pub const UNREACHABLE_NO_ROUTE: u8 = 0;
+pub const UNREACHABLE_ADDRESS: u8 = 3;
+pub const UNREACHABLE_PORT: u8 = 4;
+// Codes for `TYPE_PARAMETER_PROBLEM`
+pub const PARAMETER_PROBLEM_NEXT_HEADER: u8 = 1;
+
+/// Error messages quote as much of the original packet as will fit in the minimum IPv6 MTU
+const MAX_QUOTE_LEN: usize = 1280 - 40 - 8;
+
+pub fn init()
+{
+	crate::ipv6::register_handler(IPV6_NEXT_HEADER_ICMPV6, rx_handler).unwrap();
+}
+
+fn rx_handler(int: &crate::ipv6::Interface, src_addr: Address, dest_addr: Address, hop_limit: u8, mut pkt: PacketReader) -> RxResult
+{
+	// Validate the checksum over the pseudo-header and entire message
+	{
+		let mut pkt = pkt.clone();
+		let sum_pseudo = crate::ipv6::pseudo_header_checksum(&src_addr, &dest_addr, IPV6_NEXT_HEADER_ICMPV6, pkt.remain());
+		let psum_whole = !crate::ipv4::calculate_checksum( (0 .. pkt.remain() / 2).map(|_| pkt.read_u16n().unwrap()) );
+		// Final byte is decoded as if there was a zero after it (so as 0x??00)
+		let psum_partial = if pkt.remain() > 0 { (pkt.read_u8().unwrap() as u16) << 8 } else { 0 };
+		let sum = crate::ipv4::calculate_checksum([!sum_pseudo, psum_whole, psum_partial].iter().copied());
+		if sum != 0 {
+			log_error!("Incorrect checksum: 0x{:04x} != 0", sum);
+			return RxResult::Handled;
+		}
+	}
+
+	let hdr = match PktHeader::read(&mut pkt)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_error!("Undersized packet: Ran out of data reading header");
+			return RxResult::Handled;
+			},
+		};
+	log_debug!("ICMPv6 {:?} -> {:?}: {:?}", src_addr, dest_addr, hdr);
+	match hdr.ty
+	{
+	TYPE_ECHO_REQUEST => {
+		// Reply with the same identifier, sequence number and data
+		let mut data = vec![0; pkt.remain()];
+		if pkt.read(&mut data).is_err() {
+			return RxResult::Handled;
+		}
+		send_message(int.addr(), src_addr, TYPE_ECHO_REPLY, 0, hdr.rest, &data);
+		},
+	TYPE_ECHO_REPLY => {
+		// TODO: Pass to a user-facing ping interface
+		log_debug!("Echo reply from {:?}", src_addr);
+		},
+	TYPE_NEIGHBOUR_SOLICITATION => crate::ndp::handle_solicitation(int, src_addr, hop_limit, pkt),
+	TYPE_NEIGHBOUR_ADVERTISEMENT => crate::ndp::handle_advertisement(hop_limit, hdr.rest, pkt),
+	TYPE_DEST_UNREACHABLE => {
+		let kind = match hdr.code
+			{
+			UNREACHABLE_NO_ROUTE => ErrorKind::NetUnreachable,
+			UNREACHABLE_ADDRESS => ErrorKind::HostUnreachable,
+			UNREACHABLE_PORT => ErrorKind::PortUnreachable,
+			_ => ErrorKind::Other(hdr.code),
+			};
+		handle_error(kind, pkt);
+		},
+	TYPE_PACKET_TOO_BIG => {
+		handle_error(ErrorKind::FragmentationNeeded, pkt);
+		},
+	TYPE_TIME_EXCEEDED => {
+		handle_error(ErrorKind::TimeExceeded, pkt);
+		},
+	TYPE_PARAMETER_PROBLEM if hdr.code == PARAMETER_PROBLEM_NEXT_HEADER => {
+		handle_error(ErrorKind::ProtocolUnreachable, pkt);
+		},
+	_ => {
+		log_debug!("Unhandled ICMPv6 type {} (code {})", hdr.ty, hdr.code);
+		},
+	}
+	RxResult::Handled
+}
+
+/// Decode the quoted packet from an error message, and pass the error to the protocol that sent it
+fn handle_error(kind: ErrorKind, mut pkt: PacketReader)
+{
+	let (next_header, src, dst, src_port, dst_port) = match read_quoted(&mut pkt)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_warning!("ICMPv6 error {:?} with a malformed quoted packet", kind);
+			return ;
+			},
+		};
+	log_debug!("ICMPv6 error {:?} for next header {} {:?}:{} -> {:?}:{}", kind, next_header, src, src_port, dst, dst_port);
+	// NOTE: The quoted packet was sent by us, so the source is the local end
+	let local = (crate::Address::Ipv6(src), src_port);
+	let remote = (crate::Address::Ipv6(dst), dst_port);
+	match next_header
+	{
+	crate::tcp::IPV6_PROTO_TCP => crate::tcp::handle_icmp_error(local, remote, kind),
+	crate::udp::IPV6_PROTO_UDP => crate::udp::handle_icmp_error(local, remote, kind),
+	_ => {},
+	}
+}
+
+/// Read the next header, addresses and ports from the packet quoted in an error message
+// NOTE: We never send extension headers, so the upper-layer header directly follows the IPv6 header
+fn read_quoted(pkt: &mut PacketReader) -> Result<(u8, Address, Address, u16, u16), ()>
+{
+	let hdr: [u8; 40] = pkt.read_bytes([0; 40])?;
+	if hdr[0] >> 4 != 6 {
+		return Err( () );
+	}
+	let next_header = hdr[6];
+	let mut src = [0; 16];
+	src.copy_from_slice(&hdr[8..24]);
+	let mut dst = [0; 16];
+	dst.copy_from_slice(&hdr[24..40]);
+	// Only the ports are needed from the original data (TCP and UDP both start with them)
+	let src_port = pkt.read_u16n()?;
+	let dst_port = pkt.read_u16n()?;
+	Ok( (next_header, Address(src), Address(dst), src_port, dst_port) )
+}
+
+/// Send a destination unreachable message in response to the packet in `ip_pkt` (positioned at the start of the IP header)
+pub fn send_unreachable(local: Address, remote: Address, code: u8, ip_pkt: PacketReader)
+{
+	log_debug!("Sending unreachable ({}) to {:?}", code, remote);
+	send_error(local, remote, TYPE_DEST_UNREACHABLE, code, [0; 4], ip_pkt);
+}
+/// Send a parameter problem message, `pointer` is the offset of the problematic field in `ip_pkt`
+pub fn send_parameter_problem(local: Address, remote: Address, code: u8, pointer: u32, ip_pkt: PacketReader)
+{
+	log_debug!("Sending parameter problem ({} @{}) to {:?}", code, pointer, remote);
+	send_error(local, remote, TYPE_PARAMETER_PROBLEM, code, pointer.to_be_bytes(), ip_pkt);
+}
+fn send_error(local: Address, remote: Address, ty: u8, code: u8, rest: [u8; 4], mut ip_pkt: PacketReader)
+{
+	let mut quote: Vec<u8> = vec![0; ::core::cmp::min(MAX_QUOTE_LEN, ip_pkt.remain())];
+	if ip_pkt.read(&mut quote).is_err() {
+		return ;
+	}
+	send_message(local, remote, ty, code, rest, &quote);
+}
+
+/// Build an ICMPv6 header (with checksum) for a message
+pub(crate) fn make_header(local: &Address, remote: &Address, ty: u8, code: u8, rest: [u8; 4], data: &[u8]) -> [u8; 8]
+{
+	let mut hdr = [ty, code, 0, 0, rest[0], rest[1], rest[2], rest[3]];
+	let sum_pseudo = crate::ipv6::pseudo_header_checksum(local, remote, IPV6_NEXT_HEADER_ICMPV6, hdr.len() + data.len());
+	let sum_header = crate::ipv4::calculate_checksum( hdr.chunks(2).map(|v| (v[0] as u16) << 8 | v[1] as u16) );
+	// Final byte is summed as if there was a zero after it (so as 0x??00)
+	let sum_data = crate::ipv4::calculate_checksum( data.chunks(2).map(|v| (v[0] as u16) << 8 | v.get(1).copied().unwrap_or(0) as u16) );
+	let sum = crate::ipv4::calculate_checksum([ !sum_pseudo, !sum_header, !sum_data ].iter().copied());
+	hdr[2] = (sum >> 8) as u8;
+	hdr[3] = (sum >> 0) as u8;
+	hdr
+}
+
+pub(crate) fn send_message(local: Address, remote: Address, ty: u8, code: u8, rest: [u8; 4], data: &[u8])
+{
+	let hdr = make_header(&local, &remote, ty, code, rest, data);
+	let data_pkt = SparsePacket::new_root(data);
+	let hdr_pkt = SparsePacket::new_chained(&hdr, &data_pkt);
+	// TODO: Queue a packet instead of blocking here
+	block_on(crate::ipv6::send_packet(local, remote, IPV6_NEXT_HEADER_ICMPV6, hdr_pkt));
+}
+
+#[derive(Debug)]
+struct PktHeader
+{
+	ty: u8,
+	code: u8,
+	checksum: u16,
+	/// Type-specific data (e.g. identifier and sequence number for echo)
+	rest: [u8; 4],
+}
+impl PktHeader
+{
+	fn read(reader: &mut PacketReader) -> Result<Self, ()>
+	{
+		Ok(PktHeader {
+			ty: reader.read_u8()?,
+			code: reader.read_u8()?,
+			checksum: reader.read_u16n()?,
+			rest: reader.read_bytes([0; 4])?,
+			})
+	}
+}
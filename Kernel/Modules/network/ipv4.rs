@@ -7,10 +7,20 @@ use kernel::lib::Vec;
 use kernel::sync::RwLock;
 use crate::nic::MacAddr;
 use crate::nic::PacketReader;
+use core::sync::atomic::{AtomicU16,Ordering};
+
+mod reassembly;
+pub mod routes;
+
+/// Largest packet (including the IP header) that can be sent without fragmentation
+// TODO: Get this from the interface
+const MTU: usize = 1500;
 
 // List of protocol numbers and handlers
 static PROTOCOLS: RwLock<Vec<(u8, ProtoHandler)>> = RwLock::new(Vec::new());
 static INTERFACES: RwLock<Vec<Interface>> = RwLock::new(Vec::new());
+/// Source of the identification field for outgoing packets
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(1);
 
 // NOTE: uses mac address to identify interface
 pub fn add_interface(local_mac: [u8; 6], addr: Address, mask_bits: u8)
@@ -30,9 +40,49 @@ pub fn add_interface(local_mac: [u8; 6], addr: Address, mask_bits: u8)
 		address: addr,
 		mask: mask_bits,
 		});
+	drop(lh);
+
+	// Directly attached network
+	let _ = routes::add(routes::Route {
+		network: addr.mask(mask_bits),
+		mask: mask_bits,
+		gateway: Address::zero(),
+		source: addr,
+		metric: 0,
+		});
+}
+
+/// Remove an address from an interface (along with any routes using it)
+pub fn del_interface(local_mac: [u8; 6], addr: Address) -> Result<(), ()>
+{
+	let mut lh = INTERFACES.write();
+	match lh.iter().position(|i| i.local_mac == local_mac && i.address == addr)
+	{
+	Some(i) => { lh.remove(i); },
+	None => return Err( () ),
+	}
+	drop(lh);
+
+	routes::remove_for_source(addr);
+	Ok( () )
+}
+
+/// Check if the given NIC has any IPv4 addresses
+pub fn has_interface(local_mac: [u8; 6]) -> bool
+{
+	INTERFACES.read().iter().any(|i| i.local_mac == local_mac)
+}
+
+/// Result of passing a packet to a protocol handler
+pub enum RxResult
+{
+	/// The packet was consumed (or silently dropped)
+	Handled,
+	/// Nothing was listening on the destination port, an ICMP port unreachable should be sent
+	PortUnreachable,
 }
 
-pub fn register_handler(proto: u8, handler: fn(&Interface, Address, PacketReader)) -> Result<(), ()>
+pub fn register_handler(proto: u8, handler: fn(&Interface, Address, PacketReader) -> RxResult) -> Result<(), ()>
 {
 	let mut lh = PROTOCOLS.write();
 	for &(p, _) in lh.iter()
@@ -93,19 +143,22 @@ pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, sourc
 		}
 	}
 	
-	// Check for IP-level fragmentation
-	if hdr.get_has_more_fragments() || hdr.get_fragment_ofs() != 0 {
-		// TODO: Handle fragmented packets
-		log_error!("TODO: Handle fragmented packets");
-		return Ok( () );
-	}
-	
 	// Sanity check that we have enough bytes for the body.
 	if reader.remain() < hdr.total_length as usize - hdr_len {
 		log_warning!("Undersized packet: {} bytes after header, body length is {}", reader.remain(), hdr.total_length as usize - hdr_len);
 		return Err( () );
 	}
+	// - Exclude any link-layer padding from the body
+	reader.truncate(hdr.total_length as usize - hdr_len);
 
+
+	// DHCP replies can arrive before the interface has an address (and are usually broadcast)
+	if hdr.protocol == crate::udp::IPV4_PROTO_UDP && !hdr.get_has_more_fragments() && hdr.get_fragment_ofs() == 0
+	{
+		if crate::dhcp::handle_rx(source_mac, hdr.source, hdr.destination, reader.clone()) {
+			return Ok( () );
+		}
+	}
 	
 	// Check destination IP against known interfaces.
 	// - Could also be doing routing.
@@ -119,18 +172,26 @@ pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, sourc
 			// TODO: Check if the source address is from the same subnet, and only cache in ARP if it is
 			crate::arp::snoop_v4(source_mac, hdr.source);
 
-			// Figure out which sub-protocol to send this packet to
-			// - Should there be alternate handlers for 
-			for &(id,ref handler) in PROTOCOLS.read().iter()
+			// Check for IP-level fragmentation
+			if hdr.get_has_more_fragments() || hdr.get_fragment_ofs() != 0
 			{
-				if id == hdr.protocol
+				if let Some(pkt) = reassembly::push_fragment(&hdr, pre_header_reader, reader)
 				{
-					handler.dispatch(interface, hdr.source, hdr.destination, reader);
-					return Ok( () );
+					let handle = crate::nic::PacketHandle::new(pkt).ok().unwrap();
+					log_debug!("Reassembled {} byte packet from {:?}", handle.len(), hdr.source);
+					let pre_header_reader = PacketReader::new(&handle);
+					let mut reader = pre_header_reader.clone();
+					// NOTE: The header is from the first fragment, which could have a different length to this one
+					let first_hdr_len = (reader.clone().read_u8()? & 0xF) as usize * 4;
+					for _ in 0 .. first_hdr_len {
+						reader.read_u8()?;
+					}
+					dispatch_local(interface, &hdr, pre_header_reader, reader);
 				}
+				return Ok( () );
 			}
-			log_debug!("Unknown protocol {}", hdr.protocol);
-			// No handler, but the interface is known
+
+			dispatch_local(interface, &hdr, pre_header_reader, reader);
 			return Ok( () );
 		}
 	}
@@ -144,6 +205,28 @@ pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, sourc
 	Ok( () )
 }
 
+/// Pass a packet for a local interface to the handler for its protocol
+fn dispatch_local(interface: &Interface, hdr: &Ipv4Header, pre_header_reader: PacketReader, reader: PacketReader)
+{
+	// Figure out which sub-protocol to send this packet to
+	// - Should there be alternate handlers for 
+	let res = PROTOCOLS.read().iter()
+		.find(|&&(id,_)| id == hdr.protocol)
+		.map(|&(_,ref handler)| handler.dispatch(interface, hdr.source, hdr.destination, reader));
+	match res
+	{
+	Some(RxResult::Handled) => {},
+	Some(RxResult::PortUnreachable) => {
+		crate::icmp::send_unreachable_v4(interface.address, hdr.source, crate::icmp::UNREACHABLE_PORT, pre_header_reader);
+		},
+	None => {
+		log_debug!("Unknown protocol {}", hdr.protocol);
+		// No handler, but the interface is known
+		crate::icmp::send_unreachable_v4(interface.address, hdr.source, crate::icmp::UNREACHABLE_PROTOCOL, pre_header_reader);
+		},
+	}
+}
+
 // Calculate a checksum of a sequence of NATIVE ENDIAN (not network) 16-bit words
 pub fn calculate_checksum(words: impl Iterator<Item=u16>) -> u16
 {
@@ -159,18 +242,13 @@ pub fn calculate_checksum(words: impl Iterator<Item=u16>) -> u16
 	!sum as u16
 }
 
-// 
+/// Find the local address, interface, and next hop to use when sending to `dest`
 pub fn route_lookup(source: Address, dest: Address) -> Option<(Address, MacAddr, Address)>
 {
-	for interface in INTERFACES.read().iter()
-	{
-		// On-link?
-		if (source.is_zero() || interface.address == source) && interface.address.mask(interface.mask) == dest.mask(interface.mask)
-		{
-			return Some( (interface.address, interface.local_mac, dest) );
-		}
-	}
-	None
+	let route = routes::lookup(source, dest)?;
+	let local_mac = INTERFACES.read().iter().find(|i| i.address == route.source)?.local_mac;
+	let next_hop = if route.gateway.is_zero() { dest } else { route.gateway };
+	Some( (route.source, local_mac, next_hop) )
 }
 
 pub async fn send_packet(source: Address, dest: Address, proto: u8, pkt: crate::nic::SparsePacket<'_>)
@@ -199,7 +277,51 @@ pub async fn send_packet(source: Address, dest: Address, proto: u8, pkt: crate::
 		ver_and_len: 0x40 | 20/4,
 		diff_services: 0,
 		total_length: (20 + pkt.total_len()) as u16,
-		identification: 0,
+		identification: NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+		flags: 0,
+		frag_ofs_high: 0,
+		ttl: 255,
+		protocol: proto,
+		hdr_checksum: 0,
+		source: source,
+		destination: dest,
+		};
+	if 20 + pkt.total_len() <= MTU
+	{
+		hdr.set_checksum();
+		let hdr_bytes = hdr.encode();
+		crate::nic::send_from(interface_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &pkt));
+	}
+	else
+	{
+		// Fragment, each fragment (other than the last) must have a multiple of 8 bytes of data
+		let data: Vec<u8> = pkt.into_iter().flat_map(|v| v.iter().copied()).collect();
+		let max_frag_len = (MTU - 20) & !7;
+		log_debug!("send_packet: Fragmenting {} bytes into {} byte chunks", data.len(), max_frag_len);
+		for (i,frag) in data.chunks(max_frag_len).enumerate()
+		{
+			let ofs = i * max_frag_len;
+			hdr.total_length = (20 + frag.len()) as u16;
+			hdr.set_fragment_ofs(ofs / 8);
+			hdr.set_has_more_fragments(ofs + frag.len() < data.len());
+			hdr.set_checksum();
+			let hdr_bytes = hdr.encode();
+			let data_pkt = crate::nic::SparsePacket::new_root(frag);
+			crate::nic::send_from(interface_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &data_pkt));
+		}
+	}
+}
+
+/// Send a packet directly out of an interface (bypassing routing and ARP)
+///
+/// Used by DHCP, which has to send before the interface has an address
+pub(crate) fn send_raw(interface_mac: MacAddr, dest_mac: MacAddr, source: Address, dest: Address, proto: u8, pkt: crate::nic::SparsePacket<'_>)
+{
+	let mut hdr = Ipv4Header {
+		ver_and_len: 0x40 | 20/4,
+		diff_services: 0,
+		total_length: (20 + pkt.total_len()) as u16,
+		identification: NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
 		flags: 0,
 		frag_ofs_high: 0,
 		ttl: 255,
@@ -273,19 +395,31 @@ impl Ipv4Header
 	fn get_has_more_fragments(&self) -> bool {
 		self.flags & 1 << 5 != 0
 	}
-	//fn set_has_more_fragments(&mut self) {
-	//	self.flags |= 1 << 5;
-	//}
+	fn set_has_more_fragments(&mut self, v: bool) {
+		if v {
+			self.flags |= 1 << 5;
+		}
+		else {
+			self.flags &= !(1 << 5);
+		}
+	}
 
+	/// Fragment offset in units of 8 bytes
 	fn get_fragment_ofs(&self) -> usize {
-		((self.frag_ofs_high as usize) << 5) | (self.flags & 0x1F) as usize
+		// The high 5 bits are in the bottom of the flags byte
+		((self.flags & 0x1F) as usize) << 8 | (self.frag_ofs_high as usize)
+	}
+	fn set_fragment_ofs(&mut self, ofs: usize) {
+		assert!(ofs < 1 << 13);
+		self.flags = (self.flags & !0x1F) | (ofs >> 8) as u8;
+		self.frag_ofs_high = ofs as u8;
 	}
 }
 
 enum ProtoHandler
 {
 	/// Direct in-kernel handling (e.g. TCP)
-	DirectKernel(fn(&Interface, Address, PacketReader)),
+	DirectKernel(fn(&Interface, Address, PacketReader) -> RxResult),
 	/// Indirect user handling (pushes onto a buffer for the user to read from)
 	// Ooh, another use for stack_dst, a DST queue!
 	#[allow(dead_code)]
@@ -293,7 +427,7 @@ enum ProtoHandler
 }
 impl ProtoHandler
 {
-	fn dispatch(&self, i: &Interface, src: Address, _dest: Address, r: PacketReader)
+	fn dispatch(&self, i: &Interface, src: Address, _dest: Address, r: PacketReader) -> RxResult
 	{
 		match *self
 		{
@@ -332,30 +466,22 @@ impl Address
 		| (self.0[2] as u32) << 8
 		| (self.0[3] as u32) << 0
 	}
+	pub fn from_u32(v: u32) -> Self {
+		Address([ (v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, (v >> 0) as u8 ])
+	}
+	/// Clear all but the first `bits` bits of the address
 	pub fn mask(&self, bits: u8) -> Address {
-		let mask = (1 << (bits % 8)) - 1;
-		if bits < 8 {
-			Address([ self.0[0] & mask, 0, 0, 0 ])
-		}
-		else if bits < 16 {
-			Address([ self.0[0], self.0[1] & mask, 0, 0 ])
-		}
-		else if bits < 24 {
-			Address([ self.0[0], self.0[1], self.0[2] & mask, 0 ])
-		}
-		else if bits < 32 {
-			Address([ self.0[0], self.0[1], self.0[2], self.0[3] & mask ])
-		}
-		else if bits == 32 {
-			Address(self.0)
-		}
-		else {
-			unreachable!()
-		}
+		assert!(bits <= 32);
+		let mask = if bits == 0 { 0 } else { !0u32 << (32 - bits) };
+		Address::from_u32(self.as_u32() & mask)
 	}
 	pub fn is_zero(&self) -> bool {
 		self.0 == [0,0,0,0]
 	}
+	/// The limited broadcast address (255.255.255.255)
+	pub fn broadcast() -> Self {
+		Address([0xFF,0xFF,0xFF,0xFF])
+	}
 }
 pub struct Interface
 {
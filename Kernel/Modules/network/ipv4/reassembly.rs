@@ -0,0 +1,220 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ipv4/reassembly.rs
+//! IPv4 fragment reassembly
+use kernel::lib::Vec;
+use kernel::sync::Mutex;
+use crate::nic::PacketReader;
+use super::{Address,Ipv4Header};
+
+/// Time after the first fragment is seen that an incomplete packet is discarded
+const REASSEMBLY_TIMEOUT_MS: u64 = 30*1000;
+/// Maximum number of packets being reassembled at one time
+const MAX_BUFFERS: usize = 16;
+/// Maximum number of bytes held across all reassembly buffers
+const MAX_TOTAL_BYTES: usize = 256*1024;
+/// Largest possible datagram (limited by the 16-bit total length field)
+const MAX_DATAGRAM_LEN: usize = 0xFFFF;
+
+static BUFFERS: Mutex<Vec<Buffer>> = Mutex::new(Vec::new());
+
+#[derive(PartialEq)]
+struct Key
+{
+	source: Address,
+	destination: Address,
+	protocol: u8,
+	identification: u16,
+}
+
+struct Buffer
+{
+	key: Key,
+	expiry: ::kernel::time::TickCount,
+	/// Raw IP header from the first fragment (once it's been received)
+	header: Option<Vec<u8>>,
+	/// Payload data (grows as fragments are received)
+	data: Vec<u8>,
+	/// Sorted and non-overlapping list of received payload ranges
+	ranges: Vec<(usize,usize)>,
+	/// Total payload length, known once the final fragment is received
+	total_len: Option<usize>,
+}
+impl Buffer
+{
+	fn add_range(&mut self, start: usize, end: usize)
+	{
+		self.ranges.push( (start, end) );
+		self.ranges.sort();
+		// Merge overlapping/adjacent ranges
+		let mut out: Vec<(usize,usize)> = Vec::with_capacity(self.ranges.len());
+		for &(s,e) in self.ranges.iter()
+		{
+			match out.last_mut()
+			{
+			Some(last) if s <= last.1 => last.1 = ::core::cmp::max(last.1, e),
+			_ => out.push( (s,e) ),
+			}
+		}
+		self.ranges = out;
+	}
+	fn is_complete(&self) -> bool
+	{
+		match self.total_len
+		{
+		Some(l) => self.header.is_some() && self.ranges.len() == 1 && self.ranges[0] == (0, l),
+		None => false,
+		}
+	}
+}
+
+/// A fully reassembled packet (header of the first fragment, followed by the complete payload)
+pub struct Reassembled(Vec<u8>);
+impl crate::nic::RxPacket for Reassembled
+{
+	fn len(&self) -> usize {
+		self.0.len()
+	}
+	fn num_regions(&self) -> usize {
+		1
+	}
+	fn get_region(&self, idx: usize) -> &[u8] {
+		assert!(idx == 0);
+		&self.0
+	}
+	fn get_slice(&self, range: ::core::ops::Range<usize>) -> Option<&[u8]> {
+		self.0.get(range)
+	}
+}
+
+/// Add a fragment to the reassembly buffers, returning the complete packet if this was the last missing piece
+///
+/// `ip_pkt` is positioned at the start of the IP header, and `body` just after it
+pub(super) fn push_fragment(hdr: &Ipv4Header, mut ip_pkt: PacketReader, mut body: PacketReader) -> Option<Reassembled>
+{
+	let now = ::kernel::time::ticks();
+	let key = Key {
+		source: hdr.source,
+		destination: hdr.destination,
+		protocol: hdr.protocol,
+		identification: hdr.identification,
+		};
+	let ofs = hdr.get_fragment_ofs() * 8;
+	let len = body.remain();
+	let end = ofs + len;
+	let hdr_len = hdr.get_header_length();
+	if hdr_len + end > MAX_DATAGRAM_LEN {
+		log_warning!("Fragment past end of maximum datagram size ({}+{}+{} > {})", hdr_len, ofs, len, MAX_DATAGRAM_LEN);
+		return None;
+	}
+	if hdr.get_has_more_fragments() && len % 8 != 0 {
+		log_warning!("Non-final fragment with a length ({}) that isn't a multiple of 8", len);
+		return None;
+	}
+
+	let mut lh = BUFFERS.lock();
+
+	// Discard timed out buffers
+	lh.retain(|b| {
+		if b.expiry <= now {
+			log_notice!("Reassembly of {:?}->{:?} #{} timed out", b.key.source, b.key.destination, b.key.identification);
+			false
+		}
+		else {
+			true
+		}
+		});
+
+	let mut idx = match lh.iter().position(|b| b.key == key)
+		{
+		Some(i) => i,
+		None => {
+			// Enforce the buffer count limit, evicting the oldest in-progress packet
+			if lh.len() >= MAX_BUFFERS {
+				log_notice!("Too many packets being reassembled, dropping oldest");
+				lh.remove(0);
+			}
+			lh.push(Buffer {
+				key: key,
+				expiry: now + REASSEMBLY_TIMEOUT_MS,
+				header: None,
+				data: Vec::new(),
+				ranges: Vec::new(),
+				total_len: None,
+				});
+			lh.len() - 1
+			},
+		};
+
+	// Enforce the memory limit (evicting other buffers if needed)
+	let new_size = ::core::cmp::max(lh[idx].data.len(), end);
+	loop
+	{
+		let total: usize = lh.iter().enumerate().map(|(i,b)| if i == idx { new_size } else { b.data.len() }).sum();
+		if total <= MAX_TOTAL_BYTES {
+			break;
+		}
+		match (0 .. lh.len()).find(|&i| i != idx)
+		{
+		Some(i) => {
+			log_notice!("Reassembly memory limit reached, dropping oldest");
+			lh.remove(i);
+			if i < idx {
+				idx -= 1;
+			}
+			},
+		None => {
+			log_notice!("Reassembly memory limit reached by a single packet, dropping");
+			lh.remove(idx);
+			return None;
+			},
+		}
+	}
+
+	let buf = &mut lh[idx];
+	if !hdr.get_has_more_fragments()
+	{
+		match buf.total_len
+		{
+		Some(l) if l != end => {
+			log_warning!("Conflicting final fragments ({} != {}), dropping packet", l, end);
+			lh.remove(idx);
+			return None;
+			},
+		_ => buf.total_len = Some(end),
+		}
+	}
+	if buf.total_len.map(|l| end > l).unwrap_or(false) {
+		log_warning!("Fragment extends past the end of the packet, dropping packet");
+		lh.remove(idx);
+		return None;
+	}
+	if ofs == 0 && buf.header.is_none()
+	{
+		let mut h = vec![0; hdr_len];
+		if ip_pkt.read(&mut h).is_err() {
+			return None;
+		}
+		buf.header = Some(h);
+	}
+	if buf.data.len() < end {
+		buf.data.resize(end, 0);
+	}
+	if len > 0 && body.read(&mut buf.data[ofs .. end]).is_err() {
+		return None;
+	}
+	buf.add_range(ofs, end);
+
+	if buf.is_complete()
+	{
+		let buf = lh.remove(idx);
+		let mut rv = buf.header.unwrap();
+		rv.extend_from_slice(&buf.data);
+		Some( Reassembled(rv) )
+	}
+	else
+	{
+		None
+	}
+}
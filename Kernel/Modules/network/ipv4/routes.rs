@@ -0,0 +1,125 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ipv4/routes.rs
+//! IPv4 routing table
+use kernel::lib::Vec;
+use kernel::sync::RwLock;
+use super::Address;
+
+static ROUTES: RwLock<Vec<Route>> = RwLock::new(Vec::new());
+
+/// An entry in the routing table
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Route
+{
+	/// Destination network (with the bits past `mask` cleared)
+	pub network: Address,
+	/// Prefix length of the network
+	pub mask: u8,
+	/// Next hop, zero if the network is directly attached
+	pub gateway: Address,
+	/// Local address (and hence interface) used to send packets on this route
+	pub source: Address,
+	/// Cost of the route, lower metrics are preferred when prefix lengths are equal
+	pub metric: u16,
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	/// The mask was longer than 32 bits, or the network had bits set past the mask
+	InvalidMask,
+	/// The source address isn't assigned to an interface (or the gateway isn't reachable from any interface)
+	UnknownInterface,
+	/// An identical route already exists
+	Exists,
+	/// No matching route to remove
+	NotFound,
+}
+
+/// Add a route to the table
+///
+/// If `source` is zero, it's selected from the interface that can reach `gateway` (or `network` for a direct route)
+pub fn add(mut route: Route) -> Result<(), Error>
+{
+	if route.mask > 32 || route.network.mask(route.mask) != route.network {
+		return Err(Error::InvalidMask);
+	}
+	{
+		let interfaces = super::INTERFACES.read();
+		if route.source.is_zero()
+		{
+			let target = if route.gateway.is_zero() { route.network } else { route.gateway };
+			route.source = match interfaces.iter().find(|i| i.address.mask(i.mask) == target.mask(i.mask))
+				{
+				Some(i) => i.address,
+				None => return Err(Error::UnknownInterface),
+				};
+		}
+		else if !interfaces.iter().any(|i| i.address == route.source)
+		{
+			return Err(Error::UnknownInterface);
+		}
+	}
+
+	let mut lh = ROUTES.write();
+	if lh.iter().any(|r| *r == route) {
+		return Err(Error::Exists);
+	}
+	log_notice!("Add route {:?}/{} via {:?} from {:?} (metric {})", route.network, route.mask, route.gateway, route.source, route.metric);
+	lh.push(route);
+	Ok( () )
+}
+
+/// Remove the first route matching the given network, mask and gateway
+pub fn remove(network: Address, mask: u8, gateway: Address) -> Result<Route, Error>
+{
+	let mut lh = ROUTES.write();
+	match lh.iter().position(|r| r.network == network && r.mask == mask && r.gateway == gateway)
+	{
+	Some(i) => {
+		let rv = lh.remove(i);
+		log_notice!("Remove route {:?}/{} via {:?}", rv.network, rv.mask, rv.gateway);
+		Ok(rv)
+		},
+	None => Err(Error::NotFound),
+	}
+}
+
+/// Remove all routes that send from the given address (e.g. when the address is removed)
+pub fn remove_for_source(source: Address)
+{
+	let mut lh = ROUTES.write();
+	lh.retain(|r| r.source != source);
+}
+
+/// Get a route by index (for enumerating the table)
+pub fn get(index: usize) -> Option<Route>
+{
+	ROUTES.read().get(index).copied()
+}
+
+/// Find the best route to `dest` (optionally restricted to routes from `source`)
+///
+/// The longest matching prefix wins, then the lowest metric.
+pub fn lookup(source: Address, dest: Address) -> Option<Route>
+{
+	let lh = ROUTES.read();
+	let mut best: Option<&Route> = None;
+	for r in lh.iter()
+	{
+		if !source.is_zero() && r.source != source {
+			continue ;
+		}
+		if dest.mask(r.mask) != r.network {
+			continue ;
+		}
+		best = match best
+			{
+			Some(b) if b.mask > r.mask || (b.mask == r.mask && b.metric <= r.metric) => Some(b),
+			_ => Some(r),
+			};
+	}
+	best.copied()
+}
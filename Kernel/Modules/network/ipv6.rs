@@ -0,0 +1,445 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ipv6.rs
+//! IPv6 (Layer 3)
+use kernel::lib::Vec;
+use kernel::sync::RwLock;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::nic::{MacAddr,PacketReader,SparsePacket};
+use crate::ipv4::RxResult;
+
+/// Largest packet (including the IP header) that can be sent
+// TODO: Get this from the interface (and support sending fragments)
+const MTU: usize = 1500;
+/// Hop limit used for all outgoing packets (NDP requires 255)
+const HOP_LIMIT: u8 = 255;
+
+const NEXT_HEADER_HOP_BY_HOP: u8 = 0;
+const NEXT_HEADER_ROUTING: u8 = 43;
+const NEXT_HEADER_FRAGMENT: u8 = 44;
+const NEXT_HEADER_DEST_OPTS: u8 = 60;
+
+// List of next header values and handlers
+static PROTOCOLS: RwLock<Vec<(u8, ProtoHandler)>> = RwLock::new(Vec::new());
+static INTERFACES: RwLock<Vec<Interface>> = RwLock::new(Vec::new());
+
+/// Number of received fragments dropped (reassembly isn't supported)
+static RX_FRAGMENTS_DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Number of outgoing packets dropped for exceeding the MTU (fragmentation isn't supported)
+static TX_OVERSIZE_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+// NOTE: uses mac address to identify interface
+pub fn add_interface(local_mac: [u8; 6], addr: Address, mask_bits: u8)
+{
+	let mut lh = INTERFACES.write();
+	for interface in lh.iter()
+	{
+		if interface.address == addr
+		{
+			return ;
+		}
+	}
+
+	log_notice!("IPv6 address {:?}/{} on {:x?}", addr, mask_bits, local_mac);
+	lh.push(Interface {
+		local_mac: local_mac,
+		address: addr,
+		mask: mask_bits,
+		});
+}
+/// Add the auto-configured link-local address (`fe80::/64`, derived from the MAC) for an interface
+pub fn add_link_local(local_mac: [u8; 6])
+{
+	// TODO: Duplicate address detection
+	add_interface(local_mac, Address::link_local_from_mac(local_mac), 64);
+}
+/// Check if the given address is assigned to the interface with the specified MAC
+pub(crate) fn is_local_on(local_mac: MacAddr, addr: &Address) -> bool
+{
+	INTERFACES.read().iter().any(|i| i.local_mac == local_mac && i.address == *addr)
+}
+
+pub fn register_handler(next_header: u8, handler: fn(&Interface, Address, Address, u8, PacketReader) -> RxResult) -> Result<(), ()>
+{
+	let mut lh = PROTOCOLS.write();
+	for &(p, _) in lh.iter()
+	{
+		if p == next_header {
+			return Err( () );
+		}
+	}
+	lh.push( (next_header, ProtoHandler::DirectKernel(handler),) );
+	Ok( () )
+}
+pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, local_mac: MacAddr, _source_mac: [u8; 6], mut reader: PacketReader) -> Result<(), ()>
+{
+	let pre_header_reader = reader.clone();
+	let hdr = match Ipv6Header::read(&mut reader)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_warning!("Undersized packet: Ran out of data reading header");
+			return Err( () );
+			},
+		};
+
+	if hdr.ver_tc_flow >> 28 != 6 {
+		log_warning!("Malformed packet: version isn't 6 - ver_tc_flow={:08x}", hdr.ver_tc_flow);
+		return Err( () );
+	}
+	if reader.remain() < hdr.payload_length as usize {
+		log_warning!("Undersized packet: {} bytes after header, payload length is {}", reader.remain(), hdr.payload_length);
+		return Err( () );
+	}
+	// - Exclude any link-layer padding from the body
+	reader.truncate(hdr.payload_length as usize);
+
+	// Skip over extension headers
+	let mut next_header = hdr.next_header;
+	// Offset of the current next header field (reported in parameter problem messages)
+	let mut next_header_ofs = 6;
+	loop
+	{
+		match next_header
+		{
+		NEXT_HEADER_HOP_BY_HOP | NEXT_HEADER_ROUTING | NEXT_HEADER_DEST_OPTS => {
+			next_header_ofs = pre_header_reader.remain() - reader.remain();
+			next_header = reader.read_u8()?;
+			// Length is in units of 8 bytes, not including the first 8
+			let len = reader.read_u8()? as usize * 8 + 6;
+			for _ in 0 .. len {
+				reader.read_u8()?;
+			}
+			},
+		NEXT_HEADER_FRAGMENT => {
+			let count = RX_FRAGMENTS_DROPPED.fetch_add(1, Ordering::Relaxed) + 1;
+			log_notice!("TODO: IPv6 fragment reassembly, dropping packet from {:?} ({} dropped)", hdr.source, count);
+			return Ok( () );
+			},
+		_ => break,
+		}
+	}
+
+	// Check destination IP against the addresses on the interface the packet arrived on
+	for interface in INTERFACES.read().iter().filter(|i| i.local_mac == local_mac)
+	{
+		if interface.address == hdr.destination
+			|| hdr.destination == Address::ALL_NODES
+			|| hdr.destination == interface.address.solicited_node()
+		{
+			dispatch_local(interface, &hdr, next_header, next_header_ofs, pre_header_reader, reader);
+			return Ok( () );
+		}
+	}
+	//else
+	{
+		// Routing.
+		// For now, just drop it
+		log_debug!("TODO: Packet didn't match any interfaces (A={:?}), try routing?", hdr.destination);
+	}
+
+	Ok( () )
+}
+
+/// Pass a packet for a local interface to the handler for its next header
+fn dispatch_local(interface: &Interface, hdr: &Ipv6Header, next_header: u8, next_header_ofs: usize, pre_header_reader: PacketReader, reader: PacketReader)
+{
+	let res = PROTOCOLS.read().iter()
+		.find(|&&(id,_)| id == next_header)
+		.map(|&(_,ref handler)| handler.dispatch(interface, hdr.source, hdr.destination, hdr.hop_limit, reader));
+	// NOTE: Errors are never sent in response to multicast packets
+	let can_send_error = !hdr.destination.is_multicast() && !hdr.source.is_zero();
+	match res
+	{
+	Some(RxResult::Handled) => {},
+	Some(RxResult::PortUnreachable) => {
+		if can_send_error {
+			crate::icmpv6::send_unreachable(interface.address, hdr.source, crate::icmpv6::UNREACHABLE_PORT, pre_header_reader);
+		}
+		},
+	None => {
+		log_debug!("Unknown next header {}", next_header);
+		if can_send_error {
+			crate::icmpv6::send_parameter_problem(interface.address, hdr.source, crate::icmpv6::PARAMETER_PROBLEM_NEXT_HEADER, next_header_ofs as u32, pre_header_reader);
+		}
+		},
+	}
+}
+
+/// Checksum of the pseudo-header used by upper-layer protocols
+pub fn pseudo_header_checksum(source: &Address, destination: &Address, next_header: u8, len: usize) -> u16
+{
+	let s = source.words();
+	let d = destination.words();
+	crate::ipv4::calculate_checksum(
+		s.iter().copied()
+		.chain(d.iter().copied())
+		.chain([ (len >> 16) as u16, len as u16, 0, next_header as u16 ].iter().copied())
+		)
+}
+
+/// Determine the local address, interface and next hop for a destination
+pub fn route_lookup(source: Address, dest: Address) -> Option<(Address, MacAddr, Address)>
+{
+	for interface in INTERFACES.read().iter()
+	{
+		if !(source.is_zero() || interface.address == source) {
+			continue ;
+		}
+		// On-link? (multicast is always sent directly)
+		if dest.is_multicast() || interface.address.mask(interface.mask) == dest.mask(interface.mask)
+		{
+			return Some( (interface.address, interface.local_mac, dest) );
+		}
+	}
+	None
+}
+
+pub async fn send_packet(source: Address, dest: Address, next_header: u8, pkt: SparsePacket<'_>)
+{
+	log_trace!("send_packet({:?} -> {:?} {})", source, dest, next_header);
+	// 1. Look up routing table for destination IP and interface
+	let (local_addr, interface_mac, next_hop) = match route_lookup(source, dest)
+		{
+		Some(v) => v,
+		None => {
+			log_notice!("Unable to send to {:?}: No route", dest);
+			return	// TODO: Error - No route to host
+			},
+		};
+	if 40 + pkt.total_len() > MTU {
+		let count = TX_OVERSIZE_DROPPED.fetch_add(1, Ordering::Relaxed) + 1;
+		log_notice!("Unable to send to {:?}: {} bytes exceeds the MTU ({} dropped)", dest, pkt.total_len(), count);
+		return	// TODO: Send fragments
+	}
+	// 2. Neighbour discovery
+	let dest_mac = if next_hop.is_multicast() {
+			next_hop.multicast_mac()
+		}
+		else {
+			match crate::ndp::lookup(interface_mac, local_addr, next_hop).await
+			{
+			Some(v) => v,
+			None => {
+				log_notice!("Unable to send to {:?}: No neighbour", dest);
+				return
+				},	// TODO: Error - No route to host
+			}
+		};
+	// 3. Send
+	send_raw(interface_mac, dest_mac, local_addr, dest, next_header, pkt);
+}
+
+/// Send a packet directly to the given MAC address (bypassing routing and neighbour discovery)
+pub(crate) fn send_raw(interface_mac: MacAddr, dest_mac: MacAddr, source: Address, dest: Address, next_header: u8, pkt: SparsePacket<'_>)
+{
+	let hdr = Ipv6Header {
+		ver_tc_flow: 6 << 28,
+		payload_length: pkt.total_len() as u16,
+		next_header: next_header,
+		hop_limit: HOP_LIMIT,
+		source: source,
+		destination: dest,
+		};
+	let hdr_bytes = hdr.encode();
+	crate::nic::send_from(interface_mac, dest_mac, 0x86DD, SparsePacket::new_chained(&hdr_bytes, &pkt));
+}
+
+struct Ipv6Header
+{
+	/// Version (4 bits), traffic class (8 bits), and flow label (20 bits)
+	ver_tc_flow: u32,
+	payload_length: u16,
+	next_header: u8,
+	hop_limit: u8,
+	source: Address,
+	destination: Address,
+}
+impl Ipv6Header
+{
+	fn encode(&self) -> [u8; 40] {
+		let mut rv = [0; 40];
+		rv[0] = (self.ver_tc_flow >> 24) as u8;
+		rv[1] = (self.ver_tc_flow >> 16) as u8;
+		rv[2] = (self.ver_tc_flow >> 8) as u8;
+		rv[3] = (self.ver_tc_flow >> 0) as u8;
+		rv[4] = (self.payload_length >> 8) as u8;
+		rv[5] = (self.payload_length >> 0) as u8;
+		rv[6] = self.next_header;
+		rv[7] = self.hop_limit;
+		rv[8..][..16].copy_from_slice(&self.source.0);
+		rv[24..][..16].copy_from_slice(&self.destination.0);
+		rv
+	}
+	fn read(reader: &mut PacketReader) -> Result<Self, ()>
+	{
+		Ok(Ipv6Header {
+			ver_tc_flow: reader.read_u32n()?,
+			payload_length: reader.read_u16n()?,
+			next_header: reader.read_u8()?,
+			hop_limit: reader.read_u8()?,
+			source: Address(reader.read_bytes([0; 16])?),
+			destination: Address(reader.read_bytes([0; 16])?),
+			})
+	}
+}
+
+enum ProtoHandler
+{
+	/// Direct in-kernel handling (e.g. TCP)
+	DirectKernel(fn(&Interface, Address, Address, u8, PacketReader) -> RxResult),
+}
+impl ProtoHandler
+{
+	fn dispatch(&self, i: &Interface, src: Address, dest: Address, hop_limit: u8, r: PacketReader) -> RxResult
+	{
+		match *self
+		{
+		ProtoHandler::DirectKernel(fcn) => fcn(i, src, dest, hop_limit, r),
+		}
+	}
+}
+
+#[derive(Copy,Clone,Default,PartialEq,PartialOrd,Eq,Ord)]
+pub struct Address(pub [u8; 16]);
+impl ::core::fmt::Display for Address
+{
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result
+	{
+		let w = self.words();
+		// Find the longest run of zero words (at least two long) to replace with `::`
+		let mut best = (0, 0);
+		let mut i = 0;
+		while i < 8
+		{
+			if w[i] == 0 {
+				let start = i;
+				while i < 8 && w[i] == 0 {
+					i += 1;
+				}
+				if i - start > best.1 {
+					best = (start, i - start);
+				}
+			}
+			else {
+				i += 1;
+			}
+		}
+
+		if best.1 < 2 {
+			for (i,v) in w.iter().enumerate() {
+				if i > 0 {
+					f.write_str(":")?;
+				}
+				write!(f, "{:x}", v)?;
+			}
+		}
+		else {
+			for i in 0 .. best.0 {
+				if i > 0 {
+					f.write_str(":")?;
+				}
+				write!(f, "{:x}", w[i])?;
+			}
+			f.write_str("::")?;
+			for i in best.0 + best.1 .. 8 {
+				if i > best.0 + best.1 {
+					f.write_str(":")?;
+				}
+				write!(f, "{:x}", w[i])?;
+			}
+		}
+		Ok( () )
+	}
+}
+impl ::core::fmt::Debug for Address {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		::core::fmt::Display::fmt(self, f)
+	}
+}
+impl Address
+{
+	/// `ff02::1` - All nodes on the link
+	pub const ALL_NODES: Address = Address([0xff,0x02, 0,0, 0,0, 0,0, 0,0, 0,0, 0,0, 0,1]);
+
+	pub fn zero() -> Self {
+		Address([0; 16])
+	}
+	pub fn from_words(w: [u16; 8]) -> Self {
+		let mut rv = [0; 16];
+		for i in 0 .. 8 {
+			rv[i*2+0] = (w[i] >> 8) as u8;
+			rv[i*2+1] = (w[i] >> 0) as u8;
+		}
+		Address(rv)
+	}
+	/// Big endian 16-bit words
+	pub fn words(&self) -> [u16; 8] {
+		let mut rv = [0; 8];
+		for i in 0 .. 8 {
+			rv[i] = (self.0[i*2] as u16) << 8 | (self.0[i*2+1] as u16);
+		}
+		rv
+	}
+	/// Link-local address using the modified EUI-64 interface identifier derived from a MAC address
+	pub fn link_local_from_mac(mac: MacAddr) -> Self {
+		Address([
+			0xfe,0x80, 0,0, 0,0, 0,0,
+			mac[0] ^ 0x02, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5],
+			])
+	}
+	/// Solicited-node multicast address (`ff02::1:ffXX:XXXX`) for this address
+	pub fn solicited_node(&self) -> Self {
+		Address([
+			0xff,0x02, 0,0, 0,0, 0,0, 0,0, 0,1, 0xff, self.0[13], self.0[14], self.0[15],
+			])
+	}
+	/// Ethernet address used for a multicast address (`33:33` followed by the low 32 bits)
+	pub fn multicast_mac(&self) -> MacAddr {
+		[0x33, 0x33, self.0[12], self.0[13], self.0[14], self.0[15]]
+	}
+
+	pub fn mask(&self, bits: u8) -> Address {
+		assert!(bits <= 128);
+		let mut rv = self.0;
+		for (i,b) in rv.iter_mut().enumerate()
+		{
+			let ofs = i * 8;
+			if ofs + 8 <= bits as usize {
+				// Fully within the prefix
+			}
+			else if ofs < bits as usize {
+				*b &= !(0xFF >> (bits as usize - ofs));
+			}
+			else {
+				*b = 0;
+			}
+		}
+		Address(rv)
+	}
+	pub fn is_zero(&self) -> bool {
+		self.0 == [0; 16]
+	}
+	pub fn is_multicast(&self) -> bool {
+		self.0[0] == 0xff
+	}
+	pub fn is_link_local(&self) -> bool {
+		self.0[0] == 0xfe && self.0[1] & 0xC0 == 0x80
+	}
+}
+pub struct Interface
+{
+	local_mac: [u8; 6],
+	address: Address,
+	mask: u8,
+}
+impl Interface
+{
+	pub fn addr(&self) -> Address {
+		self.address
+	}
+	pub fn local_mac(&self) -> MacAddr {
+		self.local_mac
+	}
+}
@@ -17,26 +17,59 @@ module_define!{Network, [], init}
 
 pub mod nic;
 pub mod tcp;
+pub mod udp;
 pub mod arp;
 pub mod ipv4;
-//pub mod ipv6;
+pub mod icmp;
+pub mod ipv6;
+pub mod ndp;
+pub mod icmpv6;
+pub mod dhcp;
 
 fn init()
 {
+	crate::icmp::init();
+	crate::icmpv6::init();
 	crate::tcp::init();
+	crate::udp::init();
 }
 
 #[derive(Copy,Clone,PartialOrd,PartialEq,Ord,Eq,Debug)]
 pub enum Address
 {
 	Ipv4(crate::ipv4::Address),
+	Ipv6(crate::ipv6::Address),
 }
 impl Address
 {
 	fn unwrap_ipv4(&self) -> crate::ipv4::Address {
 		match self {
 		&Address::Ipv4(v) => v,
+		_ => panic!("Expected an IPv4 address, got {:?}", self),
 		}
 	}
+	fn unwrap_ipv6(&self) -> crate::ipv6::Address {
+		match self {
+		&Address::Ipv6(v) => v,
+		_ => panic!("Expected an IPv6 address, got {:?}", self),
+		}
+	}
+}
+
+/// Checksum of the pseudo-header used by TCP and UDP (both addresses must be of the same family)
+pub(crate) fn pseudo_header_checksum(src: &Address, dst: &Address, proto: u8, len: usize) -> u16
+{
+	match (src, dst)
+	{
+	(Address::Ipv4(s), Address::Ipv4(d)) =>
+		crate::ipv4::calculate_checksum([
+			// Big endian stores MSB first, so write the high word first
+			(s.as_u32() >> 16) as u16, (s.as_u32() >> 0) as u16,
+			(d.as_u32() >> 16) as u16, (d.as_u32() >> 0) as u16,
+			proto as u16, len as u16,
+			].iter().copied()),
+	(Address::Ipv6(s), Address::Ipv6(d)) => crate::ipv6::pseudo_header_checksum(s, d, proto, len),
+	_ => panic!("Address family mismatch: {:?} and {:?}", src, dst),
+	}
 }
 
@@ -0,0 +1,187 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ndp.rs
+//! "Neighbour Discovery Protocol" (IPv6's replacement for ARP)
+use kernel::sync::RwLock;
+use kernel::lib::VecMap;
+use crate::nic::{MacAddr,PacketReader,SparsePacket};
+use crate::ipv6::Address;
+
+static CACHE: RwLock<VecMap<Address, MacAddr>> = RwLock::new(VecMap::new());
+static SLEEPERS: ::kernel::futures::Condvar = ::kernel::futures::Condvar::new();
+
+const OPT_SOURCE_LINK_ADDR: u8 = 1;
+const OPT_TARGET_LINK_ADDR: u8 = 2;
+
+/// Hop limit that all received NDP messages must have (anything else has been forwarded by a router)
+const REQUIRED_HOP_LIMIT: u8 = 255;
+
+// Flags in the first byte of an advertisement
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+
+/// Read the target address and the link-layer address option (of the given type) from a solicitation/advertisement
+fn read_message(pkt: &mut PacketReader, opt_ty: u8) -> Result<(Address, Option<MacAddr>), ()>
+{
+	let target = Address(pkt.read_bytes([0; 16])?);
+	let mut mac = None;
+	while pkt.remain() >= 2
+	{
+		let ty = pkt.read_u8()?;
+		// Length is in units of 8 bytes (including the type and length), zero is invalid
+		let len = pkt.read_u8()? as usize * 8;
+		if len == 0 {
+			return Err( () );
+		}
+		if ty == opt_ty && len == 8 {
+			mac = Some(pkt.read_bytes([0; 6])?);
+		}
+		else {
+			for _ in 2 .. len {
+				pkt.read_u8()?;
+			}
+		}
+	}
+	Ok( (target, mac) )
+}
+
+/// Handle an incoming neighbour solicitation, replying if the target is one of the receiving interface's addresses
+pub(crate) fn handle_solicitation(int: &crate::ipv6::Interface, src_addr: Address, hop_limit: u8, mut pkt: PacketReader)
+{
+	if hop_limit != REQUIRED_HOP_LIMIT {
+		log_notice!("Dropping neighbour solicitation from {:?} with hop limit {}", src_addr, hop_limit);
+		return ;
+	}
+	let (target, src_mac) = match read_message(&mut pkt, OPT_SOURCE_LINK_ADDR)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_warning!("Malformed neighbour solicitation from {:?}", src_addr);
+			return ;
+			},
+		};
+	log_debug!("NDP Solicitation from {:?} ({:x?}) for {:?}", src_addr, src_mac, target);
+	// An unspecified source is duplicate address detection, so don't cache it
+	if !src_addr.is_zero() {
+		if let Some(mac) = src_mac {
+			snoop(mac, src_addr);
+		}
+	}
+
+	if !crate::ipv6::is_local_on(int.local_mac(), &target) {
+		return ;
+	}
+	let target_mac = int.local_mac();
+	let opt = [
+		OPT_TARGET_LINK_ADDR, 1,
+		target_mac[0], target_mac[1], target_mac[2], target_mac[3], target_mac[4], target_mac[5],
+		];
+	let mut data = [0; 16 + 8];
+	data[..16].copy_from_slice(&target.0);
+	data[16..].copy_from_slice(&opt);
+	if src_addr.is_zero()
+	{
+		// Reply to all nodes (the sender doesn't have an address yet)
+		let dest = Address::ALL_NODES;
+		let hdr = crate::icmpv6::make_header(&target, &dest, crate::icmpv6::TYPE_NEIGHBOUR_ADVERTISEMENT, 0, [FLAG_OVERRIDE,0,0,0], &data);
+		let data_pkt = SparsePacket::new_root(&data);
+		crate::ipv6::send_raw(int.local_mac(), dest.multicast_mac(), target, dest, crate::icmpv6::IPV6_NEXT_HEADER_ICMPV6, SparsePacket::new_chained(&hdr, &data_pkt));
+	}
+	else
+	{
+		crate::icmpv6::send_message(target, src_addr, crate::icmpv6::TYPE_NEIGHBOUR_ADVERTISEMENT, 0, [FLAG_SOLICITED|FLAG_OVERRIDE,0,0,0], &data);
+	}
+}
+
+/// Handle an incoming neighbour advertisement
+pub(crate) fn handle_advertisement(hop_limit: u8, flags: [u8; 4], mut pkt: PacketReader)
+{
+	if hop_limit != REQUIRED_HOP_LIMIT {
+		log_notice!("Dropping neighbour advertisement with hop limit {}", hop_limit);
+		return ;
+	}
+	let (target, target_mac) = match read_message(&mut pkt, OPT_TARGET_LINK_ADDR)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_warning!("Malformed neighbour advertisement");
+			return ;
+			},
+		};
+	log_debug!("NDP Advertisement for {:?} = {:x?} (flags {:#x})", target, target_mac, flags[0]);
+	if let Some(mac) = target_mac
+	{
+		// RFC 4861 7.2.5: An existing entry is only replaced if the override flag is set
+		// - There's no INCOMPLETE state in the cache, so a missing entry is always filled
+		if flags[0] & FLAG_OVERRIDE == 0 {
+			let mut lh = CACHE.write();
+			if lh.get(&target).is_none()
+			{
+				lh.insert(target, mac);
+				SLEEPERS.wake_all();
+			}
+		}
+		else {
+			snoop(mac, target);
+		}
+	}
+}
+
+/// Inform the NDP layer of an observed mapping
+pub fn snoop(mac: MacAddr, ip: Address)
+{
+	let mut lh = CACHE.write();
+	if lh.get(&ip) != Some(&mac)
+	{
+		log_debug!("NDP snoop: {:?} = {:x?}", ip, mac);
+		lh.insert(ip, mac);
+		SLEEPERS.wake_all();
+	}
+}
+
+/// Acquire a MAC address for the given IP
+pub async fn lookup(interface_mac: MacAddr, source: Address, addr: Address) -> Option<MacAddr>
+{
+	if let Some(v) = CACHE.read().get(&addr)
+	{
+		return Some(*v);
+	}
+	log_debug!("Sending neighbour solicitation for {} from {:?}", addr, source);
+	// - Send a solicitation to the target's solicited-node multicast address
+	let dest = addr.solicited_node();
+	let mut data = [0; 16 + 8];
+	data[..16].copy_from_slice(&addr.0);
+	data[16..].copy_from_slice(&[
+		OPT_SOURCE_LINK_ADDR, 1,
+		interface_mac[0], interface_mac[1], interface_mac[2], interface_mac[3], interface_mac[4], interface_mac[5],
+		]);
+	let hdr = crate::icmpv6::make_header(&source, &dest, crate::icmpv6::TYPE_NEIGHBOUR_SOLICITATION, 0, [0; 4], &data);
+	{
+		let data_pkt = SparsePacket::new_root(&data);
+		crate::ipv6::send_raw(interface_mac, dest.multicast_mac(), source, dest, crate::icmpv6::IPV6_NEXT_HEADER_ICMPV6, SparsePacket::new_chained(&hdr, &data_pkt));
+	}
+
+	// - Wait until the cache has the requested host in it (with timeout)
+	const TIMEOUT_MS: u64 = 1000;
+	let timeout_time = ::kernel::time::ticks() + TIMEOUT_MS;
+	loop
+	{
+		// Get condvar key, then check if the IP is present, THEN wait until the key changes
+		let key = SLEEPERS.get_key();
+		if let Some(v) = CACHE.read().get(&addr)
+		{
+			return Some(*v);
+		}
+		// Sleep up to the timeout.
+		let sleep_duration = match timeout_time.checked_sub(::kernel::time::ticks())
+			{
+			None => return None,
+			Some(v) => v,
+			};
+		::kernel::futures::join_one(
+			SLEEPERS.wait(key),
+			::kernel::futures::msleep(sleep_duration as usize)
+			).await;
+	}
+}
@@ -98,16 +98,25 @@ pub trait RxPacket
 pub struct PacketReader<'a> {
 	pkt: &'a PacketHandle<'a>,
 	ofs: usize,
+	/// End of the readable region (can be less than the packet length, e.g. to exclude link-layer padding)
+	end: usize,
 }
 impl<'a> PacketReader<'a> {
-	fn new(pkt: &'a PacketHandle<'a>) -> PacketReader<'a> {
+	pub(crate) fn new(pkt: &'a PacketHandle<'a>) -> PacketReader<'a> {
 		PacketReader {
 			pkt: pkt,
 			ofs: 0,
+			end: pkt.len(),
 			}
 	}
 	pub fn remain(&self) -> usize {
-		self.pkt.len() - self.ofs
+		self.end - self.ofs
+	}
+	/// Restrict the reader to at most `len` more bytes
+	pub fn truncate(&mut self, len: usize) {
+		if len < self.remain() {
+			self.end = self.ofs + len;
+		}
 	}
 	pub fn read(&mut self, dst: &mut [u8]) -> Result<usize, ()> {
 		// TODO: Should this be cached?
@@ -120,14 +129,17 @@ impl<'a> PacketReader<'a> {
 				return Err( () );
 			}
 		}
+		if self.ofs >= self.end && dst.len() > 0 {
+			return Err( () );
+		}
 
 		let mut wofs = 0;
-		while wofs < dst.len() && self.ofs + wofs < self.pkt.len()
+		while wofs < dst.len() && self.ofs + wofs < self.end
 		{
 			let rgn = self.pkt.get_region(r);
 			let alen = rgn.len() - ofs;
 			let rlen = dst.len() - wofs;
-			let len = ::core::cmp::min(alen, rlen);
+			let len = ::core::cmp::min(::core::cmp::min(alen, rlen), self.end - (self.ofs + wofs));
 
 			dst[wofs..][..len].copy_from_slice( &rgn[ofs..][..len] );
 			
@@ -157,7 +169,7 @@ impl<'a> PacketReader<'a> {
 	pub fn read_u32n(&mut self) -> Result<u32, ()> {
 		let mut b = [0,0,0,0];
 		self.read(&mut b)?;
-		Ok( (b[0] as u32) << 24 + (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32) )
+		Ok( (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32) )
 	}
 }
 
@@ -211,9 +223,10 @@ pub fn send_from(local_addr: MacAddr, dest_addr: MacAddr, ether_ty: u16, pkt: Sp
 	}
 	if let Some(i) = int
 	{
+		// Ethernet II: Destination, then source
 		let buf = [
-			local_addr[0], local_addr[1], local_addr[2], local_addr[3], local_addr[4], local_addr[5],
 			dest_addr[0], dest_addr[1], dest_addr[2], dest_addr[3], dest_addr[4], dest_addr[5],
+			local_addr[0], local_addr[1], local_addr[2], local_addr[3], local_addr[4], local_addr[5],
 			(ether_ty >> 8) as u8, ether_ty as u8,
 			];
 		i.base_interface.tx_raw(SparsePacket::new_chained(&buf, &pkt));
@@ -226,6 +239,8 @@ pub struct Registration<T> {
 	pd: ::core::marker::PhantomData<T>,
 	index: usize,
 	ptr: Aref<T>,
+	// NOTE: Dropped after the NIC is removed from the list (the client could be sending)
+	_dhcp: crate::dhcp::ClientHandle,
 }
 impl<T> Drop for Registration<T> {
 	fn drop(&mut self) {
@@ -307,11 +322,15 @@ pub fn register<T: Interface>(mac_addr: [u8; 6], int: T) -> Registration<T> {
 		return list.len() - 1;
 	}
 	let idx = insert_opt(&mut INTERFACES_LIST.lock(), reg);
+
+	// Every IPv6-capable interface gets a link-local address
+	crate::ipv6::add_link_local(mac_addr);
 	
 	Registration {
 		pd: ::core::marker::PhantomData,
 		index: idx,
 		ptr: int_ptr,
+		_dhcp: crate::dhcp::start(mac_addr),
 		}
 }
 
@@ -338,12 +357,12 @@ fn rx_thread(int_data: &InterfaceData)
 				}
 				let mut r = PacketReader::new(&pkt);
 				// 2. Hand off to sub-modules depending on the EtherTy field
-				let src_mac = {
+				let _dst_mac = {
 					let mut b = [0; 6];
 					r.read(&mut b).unwrap();
 					b
 					};
-				let _dst_mac = {
+				let src_mac = {
 					let mut b = [0; 6];
 					r.read(&mut b).unwrap();
 					b
@@ -362,6 +381,13 @@ fn rx_thread(int_data: &InterfaceData)
 				0x0806 => {
 					crate::arp::handle_packet(&*int_data.base_interface, src_mac, r);
 					},
+				0x86DD => match crate::ipv6::handle_rx_ethernet(&*int_data.base_interface, int_data.addr, src_mac, r)
+					{
+					Ok( () ) => {},
+					Err(e) => {
+						log_warning!("TODO: Unable to hanle IPv6 packet - {:?}", e);
+						},
+					}
 				v @ _ => {
 					log_warning!("TODO: Handle packet with EtherTy={:#x}", v);
 					},
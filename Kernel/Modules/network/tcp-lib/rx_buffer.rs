@@ -98,6 +98,47 @@ impl RxBuffer
 		}
 		len as usize
 	}
+	/// Current buffer size
+	pub fn size(&self) -> usize
+	{
+		self.size
+	}
+	/// Find populated ranges (start and end offsets) within the given range of offsets
+	///
+	/// Returns the number of entries written to `out`
+	pub fn valid_ranges(&self, range: ::core::ops::Range<usize>, out: &mut [(usize, usize)]) -> usize
+	{
+		let mut count = 0;
+		let mut cur_start = None;
+		for ofs in range.start .. ::core::cmp::min(range.end, self.size)
+		{
+			match (self.is_valid(ofs), cur_start)
+			{
+			(true, None) => cur_start = Some(ofs),
+			(false, Some(start)) => {
+				if count == out.len() {
+					return count;
+				}
+				out[count] = (start, ofs);
+				count += 1;
+				cur_start = None;
+				},
+			_ => {},
+			}
+		}
+		if let Some(start) = cur_start {
+			if count < out.len() {
+				out[count] = (start, ::core::cmp::min(range.end, self.size));
+				count += 1;
+			}
+		}
+		count
+	}
+	fn is_valid(&self, offset: usize) -> bool
+	{
+		let ofs = (self.read_pos + offset) % self.size;
+		self.data[self.size..][ofs / 8] & 1 << (ofs % 8) != 0
+	}
 	/// Resize the buffer
 	pub fn resize(&mut self, new_size: usize) {
 		self.compact();
@@ -242,3 +283,22 @@ fn wrapping()
 	{ let mut b = [0; 12]; buf.take(&mut b); assert_eq!(b, [0xFF; 12]); }
 }
 
+#[test]
+// Find the populated ranges after a gap
+fn sparse_ranges()
+{
+	let mut buf = RxBuffer::new(32);
+	buf.insert(0, b"ab").expect("Insert 1");
+	buf.insert(4, b"cd").expect("Insert 2");
+	buf.insert(10, b"efg").expect("Insert 3");
+	let mut ranges = [(0,0); 4];
+	assert_eq!(buf.valid_ranges(2 .. 16, &mut ranges), 2);
+	assert_eq!(&ranges[..2], &[(4,6), (10,13)]);
+	// Output is limited to the provided space
+	assert_eq!(buf.valid_ranges(0 .. 16, &mut ranges[..1]), 1);
+	assert_eq!(ranges[0], (0,2));
+	// Offsets are relative to the read position
+	{ let mut b = [0; 2]; buf.take(&mut b); }
+	assert_eq!(buf.valid_ranges(0 .. 16, &mut ranges), 2);
+	assert_eq!(&ranges[..2], &[(2,4), (8,11)]);
+}
@@ -11,7 +11,8 @@ use crate::nic::SparsePacket;
 use crate::Address;
 use kernel::futures::block_on;
 
-const IPV4_PROTO_TCP: u8 = 6;
+pub(crate) const IPV4_PROTO_TCP: u8 = 6;
+pub(crate) const IPV6_PROTO_TCP: u8 = 6;
 
 
 #[path="tcp-lib/"]
@@ -21,8 +22,23 @@ mod lib {
 }
 
 mod connection;
+mod congestion;
+mod options;
 use self::connection::Connection;
 
+/// Largest segment sent or accepted (leaves space for IPv6 headers within a 1500 byte MTU)
+const LOCAL_MSS: usize = 1400;
+/// Segment size used if the peer doesn't send an MSS option (RFC 9293 3.7.1)
+const DEFAULT_MSS: usize = 536;
+/// Window scale offered in SYNs (allows windows up to 2MiB)
+const LOCAL_WINDOW_SCALE: u8 = 5;
+
+/// Current value of the timestamp option clock (milliseconds)
+fn timestamp_now() -> u32
+{
+	::kernel::time::ticks() as u32
+}
+
 fn earliest_timestamp(dst: &mut Option<::kernel::time::TickCount>, src: Option<::kernel::time::TickCount>) {
 	match src
 	{
@@ -38,8 +54,9 @@ fn earliest_timestamp(dst: &mut Option<::kernel::time::TickCount>, src: Option<:
 pub fn init()
 {
 	crate::ipv4::register_handler(IPV4_PROTO_TCP, rx_handler_v4).unwrap();
+	crate::ipv6::register_handler(IPV6_PROTO_TCP, rx_handler_v6).unwrap();
 
-	// TODO: Spawn a worker that waits on all the TX timers and handles sending packets
+	// Worker that handles the retransmit timers and sending packets
 	::core::mem::forget(::kernel::threads::WorkerThread::new("TCP Worker", || {
 		// Check/advance all connections, also getting the timeout for the sleep
 		loop
@@ -55,7 +72,7 @@ pub fn init()
 			if let Some(wakeup_time) = wakeup_time {
 				::kernel::futures::block_on(::kernel::futures::join_one(
 					WORKER_CV.wait(key),
-					::kernel::futures::msleep( (wakeup_time - ::kernel::time::ticks()) as usize )
+					::kernel::futures::msleep( wakeup_time.saturating_sub(::kernel::time::ticks()) as usize )
 					));
 			}
 			else {
@@ -72,13 +89,13 @@ static WORKER_CV: ::kernel::futures::Condvar = ::kernel::futures::Condvar::new()
 
 static S_PORTS: Mutex<PortPool> = Mutex::new(PortPool::new());
 
-/// Find the local source address for the given remote address
-// TODO: Shouldn't this get an interface handle instead?
+/// Find the local source address for the given remote address (selected by the routing table)
 fn get_outbound_ip_for(addr: &Address) -> Option<Address>
 {
 	match addr
 	{
 	Address::Ipv4(addr) => crate::ipv4::route_lookup(crate::ipv4::Address::zero(), *addr).map(|(laddr, _, _)| Address::Ipv4(laddr)),
+	Address::Ipv6(addr) => crate::ipv6::route_lookup(crate::ipv6::Address::zero(), *addr).map(|(laddr, _, _)| Address::Ipv6(laddr)),
 	}
 }
 /// Allocate a port for the given local address
@@ -92,9 +109,19 @@ fn release_port(_addr: &Address, idx: u16)
 	S_PORTS.lock().release(idx)
 }
 
-fn rx_handler_v4(int: &crate::ipv4::Interface, src_addr: crate::ipv4::Address, pkt: crate::nic::PacketReader)
+fn rx_handler_v4(int: &crate::ipv4::Interface, src_addr: crate::ipv4::Address, pkt: crate::nic::PacketReader) -> crate::ipv4::RxResult
 {
-	rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt)
+	rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt);
+	// NOTE: Closed ports are reported using RST, not ICMP
+	crate::ipv4::RxResult::Handled
+}
+fn rx_handler_v6(_int: &crate::ipv6::Interface, src_addr: crate::ipv6::Address, dest_addr: crate::ipv6::Address, _hop_limit: u8, pkt: crate::nic::PacketReader) -> crate::ipv4::RxResult
+{
+	if dest_addr.is_multicast() {
+		return crate::ipv4::RxResult::Handled;
+	}
+	rx_handler(Address::Ipv6(src_addr), Address::Ipv6(dest_addr), pkt);
+	crate::ipv4::RxResult::Handled
 }
 fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::PacketReader)
 {
@@ -109,7 +136,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 		};
 	log_debug!("hdr = {:?}", hdr);
 	let hdr_len = hdr.get_header_size();
-	if hdr_len > pre_header_reader.remain() {
+	if hdr_len < 5*4 || hdr_len > pre_header_reader.remain() {
 		log_error!("Undersized or invalid packet: Header length is {} but packet length is {}", hdr_len, pre_header_reader.remain());
 		return ;
 	}
@@ -120,16 +147,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 
 		let packet_len = pre_header_reader.remain();
 		// Pseudo header for checksum
-		let sum_pseudo = match (src_addr,dest_addr)
-			{
-			(Address::Ipv4(s), Address::Ipv4(d)) =>
-				calculate_checksum([
-					// Big endian stores MSB first, so write the high word first
-					(s.as_u32() >> 16) as u16, (s.as_u32() >> 0) as u16,
-					(d.as_u32() >> 16) as u16, (d.as_u32() >> 0) as u16,
-					IPV4_PROTO_TCP as u16, packet_len as u16,
-					].iter().copied()),
-			};
+		let sum_pseudo = crate::pseudo_header_checksum(&src_addr, &dest_addr, IPV4_PROTO_TCP, packet_len);
 		let sum_header = hdr.checksum();
 		let sum_options_and_data = {
 			let mut pkt = pkt.clone();
@@ -146,12 +164,11 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 	}
 
 	// Options
+	let opts = options::Options::read(&mut pkt.clone(), hdr_len - 5*4);
+	log_debug!("opts = {:?}", opts);
 	while pkt.remain() > pre_header_reader.remain() - hdr_len
 	{
-		match pkt.read_u8().unwrap()
-		{
-		_ => {},
-		}
+		pkt.read_u8().unwrap();
 	}
 	
 	let get_server = ||->Option<_> {
@@ -162,7 +179,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 	// Search for active connections with this quad
 	if let Some(c) = CONNECTIONS.get(&quad)
 	{
-		c.lock().handle(&quad, &hdr, pkt);
+		c.lock().handle(&quad, &hdr, &opts, pkt);
 	}
 	// Search for proto-connections
 	// - Proto-connections are lighter weight than full-blown connections, reducing the impact of a SYN flood
@@ -173,17 +190,25 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 			// Check the SEQ/ACK numbers, and create the actual connection
 			if hdr.sequence_number == c.seen_seq + 1 && hdr.acknowledgement_number == c.sent_seq
 			{
-				// Make the full connection struct
-				match CONNECTIONS.insert(quad, Mutex::new(Connection::new_inbound(&hdr)))
+				if let Some(server) = get_server()
+				{
+					// Make the full connection struct
+					match CONNECTIONS.insert(quad, Mutex::new(Connection::new_inbound(&hdr, &opts, c.options)))
+					{
+					Ok(()) => {
+						log_debug!("Final ACK of a handshake: {:?}", quad);
+						// Add the connection onto the server's accept queue
+						server.accept_queue.push(quad).expect("Acceped connection with full accept queue");
+						server.waiters.wake_all();
+						},
+					Err(_) => log_warning!("Conflicting connection?"),	// TODO: What do to if there's a second connection for the quad?
+					}
+				}
+				else
 				{
-				Ok(()) => {
-					log_debug!("Final ACK of a handshake: {:?}", quad);
-					// Add the connection onto the server's accept queue
-					let server = get_server().expect("Can't find server for proto connection");
-					server.accept_queue.push(quad).expect("Acceped connection with full accept queue");
-					// TODO: Signal a waiter too
-					},
-				Err(_) => log_warning!("Conflicting connection?"),	// TODO: What do to if there's a second connection for the quad?
+					// The server was closed during the handshake
+					log_debug!("Final ACK of a handshake with no server: {:?}", quad);
+					block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[], &[]));
 				}
 			}
 			else
@@ -199,7 +224,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 		else {
 			// No proto connection - RST?
 			log_debug!("Unexpected ACK: {:?}", quad);
-			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[]));
+			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[], &[]));
 		}
 	}
 	// If none found, look for servers on the destination (if SYN)
@@ -213,13 +238,20 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 				// Reject if no space
 				// - Send a RST
 				// TODO: Queue a packet instead of blocking here
-				block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[]));
+				block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[], &[]));
 			}
 			else {
 				log_debug!("Start of incoming handshake: {:?}", quad);
 				// - Add the quad as a proto-connection and send the SYN-ACK
-				let pc = ProtoConnection::new(hdr.sequence_number);
-				block_on(quad.send_packet(pc.sent_seq, pc.seen_seq, FLAG_SYN|FLAG_ACK, hdr.window_size, &[], &[]));
+				let pc = ProtoConnection::new(hdr.sequence_number, &opts);
+				// Only reply with the options that the peer offered
+				let syn_options = options::OptionsBuf::syn(
+					LOCAL_MSS as u16,
+					if pc.options.recv_window_scale > 0 { Some(LOCAL_WINDOW_SCALE) } else { None },
+					pc.options.sack,
+					opts.timestamp.map(|ts| options::Timestamp { value: timestamp_now(), echo: ts.value }),
+					);
+				block_on(quad.send_packet(pc.sent_seq, pc.seen_seq, FLAG_SYN|FLAG_ACK, connection::DEF_RX_WINDOW_SIZE as u16, syn_options.as_bytes(), &[], &[]));
 				let _ = PROTO_CONNECTIONS.replace(quad, pc);	// Insert without replacing
 			}
 		}
@@ -227,12 +259,26 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 		{
 			// Send a RST
 			log_debug!("SYN to closed port: {:?}", quad);
-			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST|(!hdr.flags & FLAG_ACK), 0, &[], &[]));
+			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST|(!hdr.flags & FLAG_ACK), 0, &[], &[], &[]));
 		}
 	}
 	// Otherwise, drop
 }
 
+/// Handle an ICMP error against a segment sent from the given local address
+pub(crate) fn handle_icmp_error(local: (Address, u16), remote: (Address, u16), kind: crate::icmp::ErrorKind)
+{
+	let quad = Quad::new(local.0, local.1, remote.0, remote.1);
+	if let Some(c) = CONNECTIONS.get(&quad)
+	{
+		c.lock().handle_icmp_error(&quad, kind);
+	}
+	else
+	{
+		log_debug!("ICMP error {:?} for unknown connection {:?}", kind, quad);
+	}
+}
+
 #[derive(Copy,Clone,PartialEq,PartialOrd,Eq,Ord)]
 struct ListenPair(Option<Address>, u16);
 impl ::core::fmt::Debug for ListenPair
@@ -280,13 +326,11 @@ impl Quad
 			local_addr, local_port, remote_addr, remote_port
 			}
 	}
-	async fn send_packet(&self, seq: u32, ack: u32, flags: u8, window_size: u16, data1: &[u8], data2: &[u8])
+	async fn send_packet(&self, seq: u32, ack: u32, flags: u8, window_size: u16, options_bytes: &[u8], data1: &[u8], data2: &[u8])
 	{
 		// Make a header
-		// TODO: Any options required?
-		let options_bytes = &[];
 		let opts_len_rounded = ((options_bytes.len() + 3) / 4) * 4;
-		let hdr = PktHeader {
+		let mut hdr = PktHeader {
 			source_port: self.local_port,
 			dest_port: self.remote_port,
 			sequence_number: seq,
@@ -296,8 +340,17 @@ impl Quad
 			window_size: window_size,
 			checksum: 0,	// To be filled afterwards
 			urgent_pointer: 0,
-			}.as_bytes();
+			};
 		// Calculate checksum
+		{
+			let pad = &[0; 3][.. opts_len_rounded - options_bytes.len()];
+			let tcp_len = 20 + opts_len_rounded + data1.len() + data2.len();
+			// NOTE: TCP has the same protocol number on IPv4 and IPv6
+			let sum_pseudo = crate::pseudo_header_checksum(&self.local_addr, &self.remote_addr, IPV4_PROTO_TCP, tcp_len);
+			let sum_rest = sum_bytes(options_bytes.iter().chain(pad).chain(data1).chain(data2));
+			hdr.checksum = crate::ipv4::calculate_checksum([ !sum_pseudo, !hdr.checksum(), !sum_rest ].iter().copied());
+		}
+		let hdr = hdr.as_bytes();
 
 		// Create sparse packet chain
 		let data_pkt = SparsePacket::new_root(data2);
@@ -311,10 +364,22 @@ impl Quad
 		match self.local_addr
 		{
 		Address::Ipv4(a) => crate::ipv4::send_packet(a, self.remote_addr.unwrap_ipv4(), IPV4_PROTO_TCP, hdr_pkt).await,
+		Address::Ipv6(a) => crate::ipv6::send_packet(a, self.remote_addr.unwrap_ipv6(), IPV6_PROTO_TCP, hdr_pkt).await,
 		}
 	}
 }
 
+/// Sum a byte sequence as big-endian 16-bit words (an odd final byte is padded with zero)
+fn sum_bytes<'a>(bytes: impl Iterator<Item=&'a u8>) -> u16
+{
+	let mut it = bytes.copied();
+	crate::ipv4::calculate_checksum(::core::iter::from_fn(move || {
+		let hi = it.next()?;
+		let lo = it.next().unwrap_or(0);
+		Some( (hi as u16) << 8 | lo as u16 )
+		}))
+}
+
 #[derive(Debug)]
 struct PktHeader
 {
@@ -416,14 +481,17 @@ struct ProtoConnection
 {
 	seen_seq: u32,
 	sent_seq: u32,
+	/// Options agreed from the SYN
+	options: options::Negotiated,
 }
 impl ProtoConnection
 {
-	fn new(seen_seq: u32) -> ProtoConnection
+	fn new(seen_seq: u32, syn_options: &options::Options) -> ProtoConnection
 	{
 		ProtoConnection {
 			seen_seq: seen_seq,
 			sent_seq: 1,	// TODO: Random
+			options: options::Negotiated::from_syn(syn_options, LOCAL_MSS, LOCAL_WINDOW_SCALE),
 			}
 	}
 }
@@ -434,6 +502,8 @@ struct Server
 	accept_space: AtomicUsize,
 	// Established connections waiting for the user to accept
 	accept_queue: AtomicRingBuf<Quad>,
+	// Userland waiters for a new connection
+	waiters: ::kernel::user_async::Queue,
 }
 
 #[derive(Debug)]
@@ -446,12 +516,19 @@ pub enum ListenError
 pub struct ServerHandle(ListenPair);
 impl ServerHandle
 {
+	/// Listen on the given port on all local addresses
 	pub fn listen(port: u16) -> Result<ServerHandle,ListenError>
 	{
-		let p = ListenPair::any(port);
+		Self::listen_on(None, port)
+	}
+	/// Listen on the given port, optionally restricted to a single local address
+	pub fn listen_on(addr: Option<Address>, port: u16) -> Result<ServerHandle,ListenError>
+	{
+		let p = ListenPair(addr, port);
 		SERVERS.insert(p, Server {
 			accept_space: AtomicUsize::new(10),
 			accept_queue: AtomicRingBuf::new(10),
+			waiters: ::kernel::user_async::Queue::new(),
 			}).map_err(|_| ListenError::SocketInUse)?;
 		Ok( ServerHandle(p) )
 	}
@@ -461,10 +538,41 @@ impl ServerHandle
 	{
 		let s = SERVERS.get(&self.0).expect("Server entry missing while handle still exists");
 		let rv_quad = s.accept_queue.pop()?;
+		// Free up the slot used by this connection
+		s.accept_space.fetch_add(1, Ordering::SeqCst);
 		Some( ConnectionHandle(rv_quad) )
 	}
 
-	//pub fn wait_accept(&mut self)
+	/// Register a sleep object to be woken when a new connection is ready
+	pub fn bind_wait_accept(&self, obj: &mut ::kernel::threads::SleepObject)
+	{
+		let s = SERVERS.get(&self.0).expect("Server entry missing while handle still exists");
+		s.waiters.wait_upon(obj);
+		if !s.accept_queue.is_empty() {
+			obj.signal();
+		}
+	}
+	/// Unregister a sleep object, returning `true` if there's a connection waiting
+	pub fn clear_wait_accept(&self, obj: &mut ::kernel::threads::SleepObject) -> bool
+	{
+		let s = SERVERS.get(&self.0).expect("Server entry missing while handle still exists");
+		s.waiters.clear_wait(obj);
+		!s.accept_queue.is_empty()
+	}
+}
+impl ::core::ops::Drop for ServerHandle
+{
+	fn drop(&mut self)
+	{
+		// Established-but-unaccepted connections are dropped (and closed) along with the server
+		if let Some(s) = SERVERS.take(&self.0)
+		{
+			while let Some(quad) = s.accept_queue.pop()
+			{
+				::core::mem::drop( ConnectionHandle(quad) );
+			}
+		}
+	}
 }
 
 /// Handle to an open (or partially-open) connection
@@ -472,7 +580,7 @@ impl ServerHandle
 /// Can be directly constructed (for an outgoing/client connection), or returned from a server
 pub struct ConnectionHandle(Quad);
 
-#[derive(Debug)]
+#[derive(Copy,Clone,Debug)]
 pub enum ConnError
 {
 	NoRoute,
@@ -481,6 +589,8 @@ pub enum ConnError
 	RemoteClosed,
 	RemoteReset,
 	NoPortAvailable,
+	/// Retransmissions went unacknowledged for too long
+	TimedOut,
 }
 
 impl ConnectionHandle
@@ -506,8 +616,21 @@ impl ConnectionHandle
 		// 4. Send the opening SYN (by creating the outbound connection structure)
 		let conn = Connection::new_outbound(&quad, 0x10000u32);
 		CONNECTIONS.insert(quad, Mutex::new(conn)).map_err(|_| ()).expect("Our unqiue port wasn't unique");
+		// Wake the worker so it picks up the SYN's retransmit timer
+		WORKER_CV.wake_one();
 		Ok( ConnectionHandle(quad) )
 	}
+	/// Local address and port of this connection
+	pub fn local_addr(&self) -> (Address, u16)
+	{
+		(self.0.local_addr, self.0.local_port)
+	}
+	/// Remote address and port of this connection
+	pub fn remote_addr(&self) -> (Address, u16)
+	{
+		(self.0.remote_addr, self.0.remote_port)
+	}
+
 	pub fn send_data(&self, buf: &[u8]) -> Result<usize, ConnError>
 	{
 		match CONNECTIONS.get(&self.0)
@@ -525,9 +648,27 @@ impl ConnectionHandle
 		Some(v) => v.lock().recv_data(&self.0, buf),
 		}
 	}
-	//pub fn wait_recv()
 
-	pub fn close(&mut self) -> Result<(), ConnError>
+	/// Register a sleep object to be woken when data (or a close/reset) is available
+	pub fn bind_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject)
+	{
+		match CONNECTIONS.get(&self.0)
+		{
+		None => panic!("Connection {:?} removed before handle dropped", self.0),
+		Some(v) => v.lock().bind_wait_recv(obj),
+		}
+	}
+	/// Unregister a sleep object, returning `true` if a `recv_data` call would not block
+	pub fn clear_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject) -> bool
+	{
+		match CONNECTIONS.get(&self.0)
+		{
+		None => panic!("Connection {:?} removed before handle dropped", self.0),
+		Some(v) => v.lock().clear_wait_recv(obj),
+		}
+	}
+
+	pub fn close(&self) -> Result<(), ConnError>
 	{
 		match CONNECTIONS.get(&self.0)
 		{
@@ -540,7 +681,11 @@ impl ::core::ops::Drop for ConnectionHandle
 {
 	fn drop(&mut self)
 	{
-		// Mark the connection to close
+		// Mark the connection to close (ignoring errors, the connection may already be closed)
+		if let Some(v) = CONNECTIONS.get(&self.0)
+		{
+			let _ = v.lock().close(&self.0);
+		}
 	}
 }
 
@@ -0,0 +1,173 @@
+//! TCP congestion control
+//!
+//! The connection logic detects ACKs, duplicate ACKs and timeouts, and asks the algorithm how much data can be in flight.
+use kernel::lib::mem::Box;
+
+/// Available congestion control algorithms
+#[derive(Copy,Clone,Debug)]
+#[allow(dead_code)]
+pub enum Algorithm
+{
+	/// RFC 5681 slow start/congestion avoidance, with RFC 6582 fast recovery
+	NewReno,
+}
+/// Algorithm used for new connections
+pub const DEFAULT_ALGORITHM: Algorithm = Algorithm::NewReno;
+
+impl Algorithm
+{
+	pub fn create(&self, mss: usize) -> Box<dyn CongestionControl>
+	{
+		match self
+		{
+		Algorithm::NewReno => Box::new(NewReno::new(mss)),
+		}
+	}
+}
+
+/// Interface to a congestion control algorithm
+pub trait CongestionControl: Send
+{
+	/// Maximum number of bytes that can be un-acknowledged
+	fn window(&self) -> usize;
+	/// `acked` new bytes were acknowledged (up to sequence number `ack`)
+	///
+	/// Returns `true` if the first un-acknowledged segment should be retransmitted
+	fn on_ack(&mut self, ack: u32, acked: usize) -> bool;
+	/// A duplicate ACK was received, `snd_max` is the highest sequence number sent and `in_flight` the number of
+	/// un-acknowledged bytes
+	///
+	/// Returns `true` if the first un-acknowledged segment should be retransmitted
+	fn on_duplicate_ack(&mut self, snd_max: u32, in_flight: usize) -> bool;
+	/// The retransmit timer expired with `in_flight` bytes un-acknowledged
+	fn on_timeout(&mut self, in_flight: usize);
+	/// Returns `true` if recovering from a loss detected by duplicate ACKs (other holes reported by SACK can be re-sent)
+	fn in_recovery(&self) -> bool;
+}
+
+/// Number of duplicate ACKs that trigger a fast retransmit
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// NewReno (RFC 5681 and RFC 6582)
+struct NewReno
+{
+	mss: usize,
+	/// Congestion window
+	cwnd: usize,
+	/// Slow start threshold
+	ssthresh: usize,
+	/// Number of consecutive duplicate ACKs
+	dup_acks: u32,
+	/// When in fast recovery, the highest sequence number sent when the loss was detected
+	recover: Option<u32>,
+}
+impl NewReno
+{
+	fn new(mss: usize) -> Self
+	{
+		NewReno {
+			mss: mss,
+			cwnd: Self::initial_window(mss),
+			ssthresh: usize::MAX,
+			dup_acks: 0,
+			recover: None,
+		}
+	}
+
+	/// Initial window (RFC 5681 3.1)
+	fn initial_window(mss: usize) -> usize
+	{
+		if mss > 2190 {
+			2 * mss
+		}
+		else if mss > 1095 {
+			3 * mss
+		}
+		else {
+			4 * mss
+		}
+	}
+
+	/// Reduce the slow start threshold after a loss
+	fn reduce_ssthresh(&mut self, in_flight: usize)
+	{
+		self.ssthresh = ::core::cmp::max(in_flight / 2, 2 * self.mss);
+	}
+}
+impl CongestionControl for NewReno
+{
+	fn window(&self) -> usize
+	{
+		self.cwnd
+	}
+
+	fn on_ack(&mut self, ack: u32, acked: usize) -> bool
+	{
+		self.dup_acks = 0;
+		match self.recover
+		{
+		Some(recover) if ack.wrapping_sub(recover) as i32 >= 0 => {
+			// Full ACK: Deflate the window and leave fast recovery
+			log_trace!("NewReno: Full ACK, leaving fast recovery (cwnd={})", self.ssthresh);
+			self.cwnd = self.ssthresh;
+			self.recover = None;
+			false
+			},
+		Some(_) => {
+			// Partial ACK: The next segment was also lost, retransmit it and partially deflate the window
+			log_trace!("NewReno: Partial ACK of {} bytes", acked);
+			self.cwnd = self.cwnd.saturating_sub(acked) + self.mss;
+			true
+			},
+		None => {
+			if self.cwnd < self.ssthresh {
+				// Slow start
+				self.cwnd += ::core::cmp::min(acked, self.mss);
+			}
+			else {
+				// Congestion avoidance, approximately one MSS per RTT
+				self.cwnd += ::core::cmp::max(self.mss * self.mss / self.cwnd, 1);
+			}
+			false
+			},
+		}
+	}
+
+	fn on_duplicate_ack(&mut self, snd_max: u32, in_flight: usize) -> bool
+	{
+		if self.recover.is_some()
+		{
+			// Each duplicate ACK means a segment has left the network, inflate the window to match
+			self.cwnd += self.mss;
+			return false;
+		}
+		self.dup_acks += 1;
+		if self.dup_acks == DUP_ACK_THRESHOLD
+		{
+			self.reduce_ssthresh(in_flight);
+			self.cwnd = self.ssthresh + DUP_ACK_THRESHOLD as usize * self.mss;
+			self.recover = Some(snd_max);
+			log_trace!("NewReno: Fast retransmit, ssthresh={} cwnd={}", self.ssthresh, self.cwnd);
+			true
+		}
+		else
+		{
+			false
+		}
+	}
+
+	fn on_timeout(&mut self, in_flight: usize)
+	{
+		self.reduce_ssthresh(in_flight);
+		// Loss window (RFC 5681 3.1)
+		self.cwnd = self.mss;
+		self.dup_acks = 0;
+		self.recover = None;
+		log_trace!("NewReno: Timeout, ssthresh={}", self.ssthresh);
+	}
+
+	fn in_recovery(&self) -> bool
+	{
+		self.recover.is_some()
+	}
+}
@@ -5,14 +5,29 @@ use super::lib::rx_buffer::RxBuffer;
 use super::{Quad,WORKER_CV};
 use super::ConnError;
 use super::{FLAG_SYN,FLAG_ACK,FLAG_PSH,FLAG_RST,FLAG_FIN};
-
-const DEF_TX_WINDOW_SIZE: u32 = 0x1000;
-const DEF_RX_WINDOW_SIZE: u32 = 0x4000;	// 16KiB
+use super::congestion::CongestionControl;
+use super::options::{Negotiated,Options,OptionsBuf,Timestamp};
+use super::{LOCAL_MSS,LOCAL_WINDOW_SCALE};
+use kernel::lib::mem::Box;
+
+const DEF_TX_WINDOW_SIZE: u32 = 0x4000;	// 16KiB
+pub(super) const DEF_RX_WINDOW_SIZE: u32 = 0x4000;	// 16KiB
+/// Initial receive window if window scaling is in use
+const SCALED_RX_WINDOW_SIZE: u32 = 0x40000;	// 256KiB
 const MAX_WINDOW_SIZE: u32 = 0x100000;	// 4MiB
-/// Base timeout between attempting to send a packet and the first retransmit attempt
-const RETRANSMIT_TIMEOUT_MS: usize = 200;
-/// Maximum segment size (i.e. the largest amount of data in a single IP frame)
-const MSS: usize = 1400;
+/// Retransmit timeout used before the first RTT measurement (RFC 6298 2.1)
+const INITIAL_RTO_MS: u32 = 1000;
+/// Lower bound on the retransmit timeout
+// NOTE: RFC 6298 suggests 1s, but 200ms (as used by most other stacks) is more useful on a LAN
+const MIN_RTO_MS: u32 = 200;
+/// Upper bound on the retransmit timeout (after backoff)
+const MAX_RTO_MS: u32 = 60_000;
+/// Clock granularity (`G` in RFC 6298)
+const CLOCK_GRANULARITY_MS: u32 = 1;
+/// A connection is aborted if retransmissions go unacknowledged for this long (RFC 1122 4.2.3.5 "R2")
+const CONNECTION_TIMEOUT_MS: u64 = 100_000;
+/// Maximum number of SACK blocks sent in a segment
+const MAX_TX_SACK_BLOCKS: usize = 3;
 
 pub struct Connection
 {
@@ -26,10 +41,22 @@ pub struct Connection
 	rx_buffer: RxBuffer,
 	/// Sequence number of the first byte in the RX buffer
 	rx_buffer_seq: u32,
+	/// Sequence number after the highest received byte (above `next_rx_seq` if there's out-of-order data)
+	rx_highest_seq: u32,
 
 	rx_window_size_max: u32,
 	rx_window_size: u32,
 
+	/// Userland waiters for received data (or a state change)
+	rx_waiters: ::kernel::user_async::Queue,
+	/// Reason for a `ForceClose` (if not a plain reset)
+	abort_error: Option<ConnError>,
+
+	/// Options agreed in the handshake
+	options: Negotiated,
+	/// Most recent timestamp received from the peer (echoed in our timestamps)
+	ts_recent: u32,
+
 	tx_state: ConnectionTxState,
 }
 struct ConnectionTxState {
@@ -37,39 +64,202 @@ struct ConnectionTxState {
 	buffer: RingBuf<u8>,
 	/// Sequence number of the next byte to be sent
 	next_tx_seq: u32,
+	/// Highest sequence number sent so far (can be above `next_tx_seq` after a timeout)
+	max_tx_seq: u32,
 	
 	/// Number of bytes that have been sent, but not ACKed
 	sent_bytes: usize,
+	/// Largest amount of data sent in a segment (negotiated MSS, less the space used by options)
+	mss: usize,
 
 	/// Last received TX window size
 	max_tx_window_size: u32,
-	/// Current TX window size (can be reduced if packet loss is seen)
-	cur_tx_window_size: u32,
+	/// Congestion control algorithm (limits the amount of un-acknowledged data)
+	cc: Box<dyn CongestionControl>,
 
 	// TODO: Pending flags?
 
 	// -- Timers and state for transmit
 	/// Timer use to ensure that we get ACKs in a suitable time.
 	retransmit_timer: ::kernel::time::Timer,
+	/// Round-trip estimation and the current retransmit timeout
+	rto: RtoState,
 	/// Flag that forces a TX on the next opportunity (e.g. the buffer has a packet worth of data, or a flush was requested)
 	force_tx: bool,
+	/// Re-send the first un-acknowledged segment on the next opportunity (duplicate or partial ACK)
+	fast_retransmit: bool,
+	/// SACK blocks from the most recent ACK
+	peer_sack: super::options::SackBlocks,
+	/// End of the last hole re-sent during fast recovery
+	recovery_next_seq: u32,
 	/// Send an ACK in the next opportunity
 	pending_ack: bool,
 }
 impl ConnectionTxState {
-	fn new(tx_seq: u32, init_window_size: u32) -> Self {
+	fn new(tx_seq: u32, init_window_size: u32, options: &Negotiated) -> Self {
+		let mss = Self::mss_for(options);
 		ConnectionTxState {
 			buffer: RingBuf::new(DEF_TX_WINDOW_SIZE as usize),
 			next_tx_seq: tx_seq,
+			max_tx_seq: tx_seq,
 
 			sent_bytes: 0,
+			mss: mss,
 			max_tx_window_size: init_window_size,
-			cur_tx_window_size: init_window_size,
+			cc: super::congestion::DEFAULT_ALGORITHM.create(mss),
 			retransmit_timer: ::kernel::time::Timer::new(),
+			rto: RtoState::new(),
 			force_tx: false,
+			fast_retransmit: false,
+			peer_sack: Default::default(),
+			recovery_next_seq: tx_seq,
 			pending_ack: false,
 		}
 	}
+
+	/// Data segment size for the given options (every segment carries a timestamp if they're enabled)
+	fn mss_for(options: &Negotiated) -> usize
+	{
+		options.send_mss - if options.timestamps { super::options::TIMESTAMP_LEN } else { 0 }
+	}
+	/// Update the segment size once the handshake completes (restarting congestion control)
+	fn set_options(&mut self, options: &Negotiated)
+	{
+		self.mss = Self::mss_for(options);
+		self.cc = super::congestion::DEFAULT_ALGORITHM.create(self.mss);
+	}
+
+	/// (Re)start the retransmit timer with the current timeout
+	fn arm_retransmit(&mut self)
+	{
+		self.retransmit_timer.reset(self.rto.timeout as u64);
+		// The worker could be sleeping without a deadline
+		WORKER_CV.wake_one();
+	}
+
+	/// Sequence number of the first un-acknowledged byte (`buffer[0]`)
+	fn first_unacked_seq(&self) -> u32
+	{
+		self.next_tx_seq.wrapping_sub(self.sent_bytes as u32)
+	}
+	/// Number of bytes sent but not yet acknowledged (including any that will be re-sent after a timeout)
+	fn bytes_in_flight(&self) -> usize
+	{
+		self.max_tx_seq.wrapping_sub(self.first_unacked_seq()) as usize
+	}
+
+	/// Find the next range of sent data not covered by the peer's SACK blocks (but below data that is covered)
+	fn next_sack_hole(&self) -> Option<(u32, usize)>
+	{
+		fn seq_lt(a: u32, b: u32) -> bool { (a.wrapping_sub(b) as i32) < 0 }
+		let snd_una = self.first_unacked_seq();
+		let mut start = if seq_lt(self.recovery_next_seq, snd_una) { snd_una } else { self.recovery_next_seq };
+		// Skip over SACKed data
+		while let Some(b) = self.peer_sack.iter().find(|b| !seq_lt(start, b.0) && seq_lt(start, b.1)) {
+			start = b.1;
+		}
+		// The hole ends at the next SACKed block
+		let end = self.peer_sack.iter()
+			.filter(|b| seq_lt(start, b.0))
+			.map(|b| b.0)
+			.min_by_key(|v| v.wrapping_sub(start))?;
+		let end = if seq_lt(self.next_tx_seq, end) { self.next_tx_seq } else { end };
+		if seq_lt(start, end) {
+			Some( (start, end.wrapping_sub(start) as usize) )
+		}
+		else {
+			None
+		}
+	}
+}
+
+/// Retransmit timeout calculation (RFC 6298)
+struct RtoState
+{
+	/// Smoothed round-trip time, `None` until the first measurement
+	srtt: Option<u32>,
+	/// Round-trip time variation
+	rttvar: u32,
+	/// Current retransmit timeout (including any backoff)
+	timeout: u32,
+	/// End sequence number and send time of the segment being timed
+	timed_segment: Option<(u32, ::kernel::time::TickCount)>,
+	/// Time of the first retransmission since the last new ACK
+	retransmit_start: Option<::kernel::time::TickCount>,
+}
+impl RtoState
+{
+	fn new() -> Self {
+		RtoState {
+			srtt: None,
+			rttvar: 0,
+			timeout: INITIAL_RTO_MS,
+			timed_segment: None,
+			retransmit_start: None,
+		}
+	}
+
+	/// Start timing a segment (if one isn't already being timed)
+	fn start_timing(&mut self, seq_end: u32)
+	{
+		if self.timed_segment.is_none() {
+			self.timed_segment = Some( (seq_end, ::kernel::time::ticks()) );
+		}
+	}
+
+	/// Handle an ACK that acknowledges new data, with the echoed timestamp (if timestamps are in use)
+	fn on_ack(&mut self, ack: u32, ts_echo: Option<u32>)
+	{
+		self.retransmit_start = None;
+		if let Some(echo) = ts_echo
+		{
+			// Timestamps allow every ACK to be measured, even for retransmitted data (RFC 7323 4.1)
+			self.timed_segment = None;
+			self.add_sample( super::timestamp_now().wrapping_sub(echo) );
+		}
+		else if let Some( (seq_end, sent_time) ) = self.timed_segment
+		{
+			if ack.wrapping_sub(seq_end) as i32 >= 0 {
+				self.timed_segment = None;
+				self.add_sample( (::kernel::time::ticks() - sent_time) as u32 );
+			}
+		}
+	}
+
+	/// Stop timing the current segment (it's been re-sent, so an ACK could be for either copy)
+	fn cancel_timing(&mut self)
+	{
+		self.timed_segment = None;
+	}
+
+	fn add_sample(&mut self, rtt: u32)
+	{
+		let srtt = match self.srtt
+			{
+			None => {
+				self.rttvar = rtt / 2;
+				rtt
+				},
+			Some(srtt) => {
+				self.rttvar = (3 * self.rttvar + (srtt as i32 - rtt as i32).abs() as u32) / 4;
+				(7 * srtt + rtt) / 8
+				},
+			};
+		self.srtt = Some(srtt);
+		self.timeout = (srtt + ::core::cmp::max(CLOCK_GRANULARITY_MS, 4 * self.rttvar)).max(MIN_RTO_MS).min(MAX_RTO_MS);
+		log_trace!("RTT sample {}ms: SRTT={} RTTVAR={} RTO={}", rtt, srtt, self.rttvar, self.timeout);
+	}
+
+	/// Back off the timer after a retransmit, returning `false` if the connection has timed out
+	fn on_retransmit(&mut self) -> bool
+	{
+		let now = ::kernel::time::ticks();
+		// Karn's algorithm: Retransmitted segments can't be used to measure the RTT
+		self.timed_segment = None;
+		self.timeout = ::core::cmp::min(self.timeout * 2, MAX_RTO_MS);
+		let start = *self.retransmit_start.get_or_insert(now);
+		now - start < CONNECTION_TIMEOUT_MS
+	}
 }
 #[derive(Copy,Clone,Debug,PartialEq)]
 enum ConnectionState
@@ -95,43 +285,76 @@ enum ConnectionState
 impl Connection
 {
 	/// Create a new connection from the ACK in a SYN-SYN,ACK-ACK
-	pub(super) fn new_inbound(hdr: &super::PktHeader) -> Self
+	pub(super) fn new_inbound(hdr: &super::PktHeader, opts: &Options, options: Negotiated) -> Self
 	{
-		Connection {
+		let mut rv = Connection {
 			state: ConnectionState::Established,
 			next_rx_seq: hdr.sequence_number,
 			last_rx_ack: hdr.sequence_number,
 			rx_buffer_seq: hdr.sequence_number,
+			rx_highest_seq: hdr.sequence_number,
 			rx_buffer: RxBuffer::new(2*DEF_RX_WINDOW_SIZE as usize),
 
 			rx_window_size_max: MAX_WINDOW_SIZE,	// Can be updated by the user
 			rx_window_size: DEF_RX_WINDOW_SIZE,
 
-			tx_state: ConnectionTxState::new(hdr.acknowledgement_number, hdr.window_size as u32),
-			}
+			rx_waiters: ::kernel::user_async::Queue::new(),
+			abort_error: None,
+
+			options: options,
+			ts_recent: opts.timestamp.map(|ts| ts.value).unwrap_or(0),
+
+			tx_state: ConnectionTxState::new(hdr.acknowledgement_number, (hdr.window_size as u32) << options.send_window_scale, &options),
+			};
+		rv.apply_window_scale();
+		rv
 	}
 
 	pub(super) fn new_outbound(quad: &Quad, sequence_number: u32) -> Self
 	{
 		log_trace!("Connection::new_outbound({:?}, {:#x})", quad, sequence_number);
+		// Nothing is known about the peer until the SYN-ACK
+		let options = Negotiated::from_syn(&Options::default(), LOCAL_MSS, LOCAL_WINDOW_SCALE);
 		let mut rv = Connection {
 			state: ConnectionState::SynSent,
 			next_rx_seq: 0,
 			last_rx_ack: 0,
 			rx_buffer_seq: 0,
+			rx_highest_seq: 0,
 			rx_buffer: RxBuffer::new(2*DEF_RX_WINDOW_SIZE as usize),
 
 			rx_window_size_max: MAX_WINDOW_SIZE,	// Can be updated by the user
 			rx_window_size: DEF_RX_WINDOW_SIZE,
 
-			tx_state: ConnectionTxState::new(sequence_number, DEF_TX_WINDOW_SIZE),
+			rx_waiters: ::kernel::user_async::Queue::new(),
+			abort_error: None,
+
+			options: options,
+			ts_recent: 0,
+
+			tx_state: ConnectionTxState::new(sequence_number, DEF_TX_WINDOW_SIZE, &options),
 			};
 		rv.send_empty_packet(quad, FLAG_SYN);
+		// SYN counts as a byte
+		rv.tx_state.next_tx_seq = sequence_number.wrapping_add(1);
+		rv.tx_state.max_tx_seq = rv.tx_state.next_tx_seq;
+		rv.tx_state.rto.start_timing(rv.tx_state.next_tx_seq);
+		rv.tx_state.retransmit_timer.reset(rv.tx_state.rto.timeout as u64);
 		rv
 	}
 
+	/// Enlarge the receive window if the peer accepted window scaling
+	fn apply_window_scale(&mut self)
+	{
+		if self.options.recv_window_scale > 0
+		{
+			self.rx_window_size = ::core::cmp::min(SCALED_RX_WINDOW_SIZE, self.rx_window_size_max);
+			self.rx_buffer.resize(2*self.rx_window_size as usize);
+		}
+	}
+
 	/// Handle an inbound packet
-	pub(super) fn handle(&mut self, quad: &Quad, hdr: &super::PktHeader, mut pkt: crate::nic::PacketReader)
+	pub(super) fn handle(&mut self, quad: &Quad, hdr: &super::PktHeader, opts: &Options, mut pkt: crate::nic::PacketReader)
 	{
 		match self.state
 		{
@@ -140,6 +363,29 @@ impl Connection
 		_ => {},
 		}
 
+		// Record the peer's timestamp to be echoed, if this segment isn't from after a gap (RFC 7323 4.3)
+		let ts_echo = match opts.timestamp
+			{
+			Some(ts) if self.options.timestamps => {
+				if ts.value.wrapping_sub(self.ts_recent) as i32 >= 0 && self.next_rx_seq.wrapping_sub(hdr.sequence_number) as i32 >= 0 {
+					self.ts_recent = ts.value;
+				}
+				// A zero echo is sent before the peer has seen a timestamp
+				if ts.echo != 0 { Some(ts.echo) } else { None }
+				},
+			_ => None,
+			};
+		if self.options.sack {
+			self.tx_state.peer_sack = opts.sack_blocks;
+		}
+		// Window sizes in SYNs are never scaled
+		let window_size = if hdr.flags & FLAG_SYN != 0 {
+				hdr.window_size as u32
+			}
+			else {
+				(hdr.window_size as u32) << self.options.send_window_scale
+			};
+
 		// Synchronisation request
 		if hdr.flags & FLAG_SYN != 0 {
 			// TODO: Send an ACK of the last recieved byte (should this be conditional?)
@@ -148,40 +394,63 @@ impl Connection
 			//self.next_rx_seq = hdr.sequence_number;
 		}
 		// ACK of sent data
-		if hdr.flags & FLAG_ACK != 0 {
-			let in_flight = self.tx_state.next_tx_seq.wrapping_sub(1).wrapping_sub(hdr.acknowledgement_number) as usize;
-			if in_flight > self.tx_state.buffer.len() {
-				// TODO: Error, something funky has happened
+		if hdr.flags & FLAG_ACK != 0 && self.state != ConnectionState::SynSent {
+			let ack = hdr.acknowledgement_number;
+			let in_flight = self.tx_state.bytes_in_flight();
+			// Number of previously un-acknowledged bytes that this ACK covers
+			let n_bytes = ack.wrapping_sub(self.tx_state.first_unacked_seq()) as usize;
+			if n_bytes == 0 {
+				// Duplicate ACK (RFC 5681 2): Nothing new acknowledged, no data, and no window change
+				if in_flight > 0 && pkt.remain() == 0 && hdr.flags & (FLAG_SYN|FLAG_FIN) == 0
+					&& window_size == self.tx_state.max_tx_window_size
+				{
+					log_trace!("{:?} Duplicate ACK {:#x}", quad, ack);
+					if self.tx_state.cc.on_duplicate_ack(self.tx_state.max_tx_seq, in_flight) {
+						self.tx_state.fast_retransmit = true;
+					}
+					self.tx_state.force_tx = true;
+					WORKER_CV.wake_one();
+				}
+			}
+			else if n_bytes > in_flight {
+				// Old ACK, or an ACK of data we haven't sent
 			}
 			else {
-				let n_bytes = self.tx_state.buffer.len() - in_flight;
 				log_debug!("{:?} ACQ {} bytes", quad, n_bytes);
 				for _ in 0 .. n_bytes {
 					self.tx_state.buffer.pop_front();
-					self.tx_state.sent_bytes -= 1;
 				}
-				// If there are no un-acked bytes, and there's pending bytes. Trigger a re-send
-				if self.tx_state.sent_bytes == 0 && self.tx_state.buffer.len() > 0 {
-					self.tx_state.force_tx = true;
-					super::WORKER_CV.wake_one();
+				if n_bytes < self.tx_state.sent_bytes {
+					self.tx_state.sent_bytes -= n_bytes;
 				}
 				else {
-					// Since we've seen an ACK, reset the retransmit time
-					if self.tx_state.sent_bytes == 0 {
-						self.tx_state.retransmit_timer.clear();
-					}
-					else {
-						// TODO: Maintain a retransmit timer and double it each time we need to retransmit
-						self.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64);
-					}
+					// Covers data being re-sent after a timeout
+					self.tx_state.next_tx_seq = ack;
+					self.tx_state.sent_bytes = 0;
+				}
+				self.tx_state.rto.on_ack(ack, ts_echo);
+				if self.tx_state.cc.on_ack(ack, n_bytes) {
+					self.tx_state.fast_retransmit = true;
+				}
+				// Since we've seen an ACK, reset the retransmit time
+				if n_bytes == in_flight {
+					self.tx_state.retransmit_timer.clear();
+				}
+				else {
+					self.tx_state.arm_retransmit();
+				}
+				// The window has moved, so more data might be sendable
+				if self.tx_state.fast_retransmit || self.tx_state.buffer.len() > self.tx_state.sent_bytes {
+					self.tx_state.force_tx = true;
+					WORKER_CV.wake_one();
 				}
 			}
 		}
 
 		// Update the window size if it changes
-		if self.tx_state.max_tx_window_size != hdr.window_size as u32 {
-			log_debug!("{:?} Max TX window changed: {} -> {}", quad, self.tx_state.max_tx_window_size, hdr.window_size);
-			self.tx_state.max_tx_window_size = hdr.window_size as u32;
+		if self.tx_state.max_tx_window_size != window_size {
+			log_debug!("{:?} Max TX window changed: {} -> {}", quad, self.tx_state.max_tx_window_size, window_size);
+			self.tx_state.max_tx_window_size = window_size;
 		}
 		
 		let new_state = match self.state
@@ -190,11 +459,26 @@ impl Connection
 
 		// SYN sent by local, waiting for SYN-ACK
 		ConnectionState::SynSent => {	
-			if hdr.flags & FLAG_SYN != 0 {
-				self.next_rx_seq += 1;
-				if hdr.flags & FLAG_ACK != 0 {
+			if hdr.flags & FLAG_RST != 0 {
+				// RST in response to our SYN, the remote refused the connection
+				self.abort_error = Some(ConnError::RemoteRefused);
+				ConnectionState::ForceClose
+			}
+			else if hdr.flags & FLAG_SYN != 0 {
+				if hdr.flags & FLAG_ACK != 0 && hdr.acknowledgement_number == self.tx_state.next_tx_seq {
 					// Now established
-					// TODO: Send ACK back
+					// - SYN counts as a byte
+					self.next_rx_seq = hdr.sequence_number.wrapping_add(1);
+					self.last_rx_ack = self.next_rx_seq;
+					self.rx_buffer_seq = self.next_rx_seq;
+					self.rx_highest_seq = self.next_rx_seq;
+					self.options = Negotiated::from_syn(opts, LOCAL_MSS, LOCAL_WINDOW_SCALE);
+					log_debug!("{:?} Negotiated {:?}", quad, self.options);
+					self.tx_state.set_options(&self.options);
+					self.apply_window_scale();
+					let ts_echo = opts.timestamp.map(|ts| { self.ts_recent = ts.value; ts.echo }).filter(|&v| v != 0);
+					self.tx_state.rto.on_ack(hdr.acknowledgement_number, ts_echo);
+					self.tx_state.retransmit_timer.clear();
 					self.send_ack(quad, "SYN-ACK");
 					ConnectionState::Established
 				}
@@ -258,14 +542,20 @@ impl Connection
 						}
 						ofs += 1;
 					}
+					let seg_end = self.next_rx_seq.wrapping_add(ofs as u32);
+					if seg_end.wrapping_sub(self.rx_highest_seq) as i32 > 0 {
+						self.rx_highest_seq = seg_end;
+					}
 					// Better idea: Have an ACQ point, and a window point. Buffer is double the window
 					// Once the window point reaches 25% of the window from the ACK point
 					if start_ofs == 0 {
-						self.next_rx_seq += ofs as u32;
+						// Advance over this data, and any out-of-order data that it joins up with
+						self.next_rx_seq = self.rx_buffer_seq.wrapping_add(self.rx_buffer.valid_len() as u32);
+						self.rx_waiters.wake_all();
 
 						// Calculate a maximum window size based on how much space is left in the buffer
 						let buffered_len = self.next_rx_seq - self.rx_buffer_seq;	// How much data the user has buffered
-						let cur_max_window = 2*self.rx_window_size_max - buffered_len;	// NOTE: 2* for some flex so the window can stay at max size
+						let cur_max_window = self.rx_buffer.size() as u32 - buffered_len;	// NOTE: The buffer is twice the window, so the window can stay at max size
 						if cur_max_window < self.rx_window_size {
 							// Reduce the window size and send an ACQ (with the updated size)
 							while cur_max_window < self.rx_window_size {
@@ -283,6 +573,10 @@ impl Connection
 							self.tx_state.pending_ack = true;
 						}
 					}
+					else {
+						// Out-of-order data, send a duplicate ACK now (with SACK blocks) so the peer notices the loss (RFC 5681 4.2)
+						self.send_ack(quad, "Out of order");
+					}
 
 					if hdr.flags & FLAG_PSH != 0 {
 						// TODO: Prod the user that there's new data?
@@ -343,12 +637,32 @@ impl Connection
 		self.state_update(quad, new_state);
 	}
 
+	/// Handle an ICMP error referencing this connection
+	pub(super) fn handle_icmp_error(&mut self, quad: &Quad, kind: crate::icmp::ErrorKind)
+	{
+		// Only hard errors during the handshake abort the connection, anything else could be transient (or forged)
+		// - RFC 1122 4.2.3.9, matching what most other stacks do
+		if self.state == ConnectionState::SynSent && kind.is_hard()
+		{
+			log_debug!("{:?} Connection refused by ICMP {:?}", quad, kind);
+			self.abort_error = Some(ConnError::RemoteRefused);
+			self.tx_state.retransmit_timer.clear();
+			self.state_update(quad, ConnectionState::ForceClose);
+		}
+		else
+		{
+			log_debug!("{:?} Ignoring ICMP {:?} in state {:?}", quad, kind, self.state);
+		}
+	}
+
 	fn state_update(&mut self, quad: &Quad, new_state: ConnectionState)
 	{
 		if self.state != new_state
 		{
 			log_trace!("{:?} {:?} -> {:?}", quad, self.state, new_state);
 			self.state = new_state;
+			// Any state change could be of interest to a waiting user (e.g. EOF or reset)
+			self.rx_waiters.wake_all();
 
 			// TODO: If transitioning to `Finished`, release the local port?
 			// - Only for client connections.
@@ -363,16 +677,15 @@ impl Connection
 	{
 		match self.state
 		{
-		ConnectionState::SynSent => {
-			todo!("(quad=?) send/recv before established");
-			},
+		// Data sent before the connection is established is buffered until the handshake completes
+		ConnectionState::SynSent => Ok( () ),
 		ConnectionState::Established => Ok( () ),
 		ConnectionState::FinWait1
 		| ConnectionState::FinWait2
 		| ConnectionState::Closing
 		| ConnectionState::TimeWait => Err( ConnError::LocalClosed ),
 
-		ConnectionState::ForceClose => Err( ConnError::RemoteReset ),
+		ConnectionState::ForceClose => Err( self.abort_error.unwrap_or(ConnError::RemoteReset) ),
 		ConnectionState::CloseWait | ConnectionState::LastAck => Err( ConnError::RemoteClosed ),
 
 		ConnectionState::Finished => Err( ConnError::LocalClosed ),
@@ -384,7 +697,7 @@ impl Connection
 		// TODO: Is it valid to send before the connection is fully established?
 		self.state_to_error()?;
 		// 1. Determine how much data we can send (based on the TX window)
-		let max_len = usize::saturating_sub(self.tx_state.cur_tx_window_size as usize, self.tx_state.buffer.len());
+		let max_len = usize::saturating_sub(DEF_TX_WINDOW_SIZE as usize, self.tx_state.buffer.len());
 		let rv = ::core::cmp::min(buf.len(), max_len);
 		log_debug!("{:?} send_data({}/{})", _quad, rv, buf.len());
 		// Add the data to the TX buffer
@@ -396,7 +709,12 @@ impl Connection
 		// Only send if:
 		// - There's no unsent data in the buffer, OR
 		// - There's more than 1MSS unsent in the buffer
-		if self.tx_state.sent_bytes == 0 || self.tx_state.buffer.len() - self.tx_state.sent_bytes >= MSS
+		if self.state == ConnectionState::SynSent
+		{
+			// The data will be sent by the ACK that completes the handshake
+			log_trace!("{:?} waiting for handshake", _quad);
+		}
+		else if self.tx_state.sent_bytes == 0 || self.tx_state.buffer.len() - self.tx_state.sent_bytes >= self.tx_state.mss
 		{
 			log_trace!("{:?} forcing a send", _quad);
 			// Force a TX
@@ -412,13 +730,54 @@ impl Connection
 		Ok(rv)
 	}
 	/// Pull data from the received buffer
-	pub(super) fn recv_data(&mut self, _quad: &Quad, buf: &mut [u8]) -> Result<usize, ConnError>
+	pub(super) fn recv_data(&mut self, quad: &Quad, buf: &mut [u8]) -> Result<usize, ConnError>
 	{
-		self.state_to_error()?;
 		//let valid_len = self.rx_buffer.valid_len();
 		//let acked_len = u32::wrapping_sub(self.next_rx_seq, self.rx_buffer_seq);
 		//let len = usize::min(valid_len, buf.len());
-		Ok( self.rx_buffer.take(buf) )
+		let rv = self.rx_buffer.take(buf);
+		self.rx_buffer_seq = self.rx_buffer_seq.wrapping_add(rv as u32);
+		// Re-open a constrained window once there's space for it again
+		let max_window = self.rx_buffer.size() as u32 / 2;
+		if rv > 0 && self.rx_window_size < max_window
+		{
+			let free_space = self.rx_buffer.size() as u32 - self.next_rx_seq.wrapping_sub(self.rx_buffer_seq);
+			let old_size = self.rx_window_size;
+			while self.rx_window_size < max_window && free_space >= 2*self.rx_window_size {
+				self.rx_window_size *= 2;
+			}
+			if self.rx_window_size != old_size {
+				self.send_ack(quad, "Window update");
+			}
+		}
+		// Only report the connection state once all buffered data has been consumed
+		if rv == 0 {
+			self.state_to_error()?;
+		}
+		Ok( rv )
+	}
+
+	/// Register a sleep object to be woken when there's data to read (or the connection closes)
+	pub(super) fn bind_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject)
+	{
+		self.rx_waiters.wait_upon(obj);
+		if self.has_rx_event() {
+			obj.signal();
+		}
+	}
+	pub(super) fn clear_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject) -> bool
+	{
+		self.rx_waiters.clear_wait(obj);
+		self.has_rx_event()
+	}
+	fn has_rx_event(&self) -> bool
+	{
+		match self.state
+		{
+		ConnectionState::SynSent
+		| ConnectionState::Established => self.rx_buffer.valid_len() > 0,
+		_ => true,
+		}
 	}
 
 	/// Run TX tasks (from the TX worker)
@@ -426,7 +785,7 @@ impl Connection
 	{
 		use ::kernel::futures::block_on;
 
-		let flags = {
+		let mut flags = {
 			let mut flags = 0u8;
 			if ::core::mem::replace(&mut self.tx_state.pending_ack, false) {
 				flags |= FLAG_ACK;
@@ -439,32 +798,96 @@ impl Connection
 			};
 
 		if self.tx_state.retransmit_timer.is_expired() {
-			// Re-send any pending data (and reduce our TX window size?)
-			let len = self.tx_state.buffer.len().min(MSS);
-			log_trace!("{:?} Retransmit {:#x} {} bytes", quad, flags, len);
-			let data = self.tx_state.buffer.get_slices(0..len);
-			// `next_tx_seq` is the sequence number of the next new byte to be sent
-			// - I.e. the byte at `buffer[sent_bytes]`
-			// - So, we want to subtract the number of bytes between `data.len()` and `sent_bytes`
-			let seq_ofs = (self.tx_state.sent_bytes as u32).wrapping_sub(len as u32);
-			let seq = self.tx_state.next_tx_seq.wrapping_sub(seq_ofs);
-			block_on(quad.send_packet(seq, self.next_rx_seq, flags, self.rx_window_size as u16, data.0, data.1));
-			// TODO: Double this timer each time we need to resend (and halve it on successful reception)
-			self.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64);
-		}
-		else if ::core::mem::replace(&mut self.tx_state.force_tx, false) {
-			// Send the new data
-			let nbytes = self.tx_state.buffer.len() - self.tx_state.sent_bytes;
-			let nbytes = nbytes.min(MSS);
-			let data = self.tx_state.buffer.get_slices(self.tx_state.sent_bytes .. self.tx_state.sent_bytes + nbytes);
-			let seq = self.tx_state.next_tx_seq;
-			log_trace!("{:?} TX forced {:#x} {} bytes", quad, flags, nbytes);
-			block_on(quad.send_packet(seq, self.next_rx_seq, flags, self.rx_window_size as u16, data.0, data.1));
-			// TODO: Some flags act as a pseudo-byte if in an empty packet
-			self.tx_state.next_tx_seq = self.tx_state.next_tx_seq.wrapping_add( nbytes as u32 );
+			if !self.tx_state.rto.on_retransmit()
+			{
+				log_notice!("{:?} Connection timed out", quad);
+				self.tx_state.retransmit_timer.clear();
+				if self.state != ConnectionState::SynSent {
+					self.send_empty_packet(quad, FLAG_RST);
+				}
+				self.abort_error = Some(ConnError::TimedOut);
+				self.state_update(quad, ConnectionState::ForceClose);
+				return None;
+			}
+			if self.state == ConnectionState::SynSent
+			{
+				log_trace!("{:?} Retransmit SYN (RTO {}ms)", quad, self.tx_state.rto.timeout);
+				let opts = self.segment_options(FLAG_SYN);
+				block_on(quad.send_packet(self.tx_state.next_tx_seq.wrapping_sub(1), 0, FLAG_SYN, self.advertised_window(), opts.as_bytes(), &[], &[]));
+			}
+			else
+			{
+				log_trace!("{:?} Retransmit timeout, {} bytes in flight (RTO {}ms)", quad, self.tx_state.bytes_in_flight(), self.tx_state.rto.timeout);
+				self.tx_state.cc.on_timeout(self.tx_state.bytes_in_flight());
+				self.tx_state.fast_retransmit = false;
+				// Go back to the first un-acknowledged byte, the send below re-sends as much as the (now reduced)
+				// congestion window allows.
+				self.tx_state.next_tx_seq = self.tx_state.first_unacked_seq();
+				self.tx_state.sent_bytes = 0;
+				self.tx_state.force_tx = true;
+			}
+			self.tx_state.retransmit_timer.reset(self.tx_state.rto.timeout as u64);
 		}
-		else {
-			// Nothing to do.
+		else if ::core::mem::replace(&mut self.tx_state.fast_retransmit, false) && self.tx_state.sent_bytes > 0 {
+			// Re-send the oldest un-acknowledged segment
+			let seq = self.tx_state.first_unacked_seq();
+			let len = self.resend_data(quad, seq, self.tx_state.sent_bytes, flags);
+			log_trace!("{:?} Fast retransmit {:#x} {} bytes", quad, seq, len);
+			self.tx_state.recovery_next_seq = seq.wrapping_add(len as u32);
+			self.tx_state.rto.cancel_timing();
+			flags = 0;
+		}
+		else if self.tx_state.force_tx && self.options.sack && self.tx_state.cc.in_recovery() {
+			// Re-send the next hole reported by SACK (at most one segment for each ACK received)
+			if let Some( (seq, len) ) = self.tx_state.next_sack_hole() {
+				let len = self.resend_data(quad, seq, len, flags);
+				log_trace!("{:?} Retransmit SACK hole {:#x} {} bytes", quad, seq, len);
+				self.tx_state.recovery_next_seq = seq.wrapping_add(len as u32);
+				self.tx_state.rto.cancel_timing();
+				flags = 0;
+			}
+		}
+
+		if ::core::mem::replace(&mut self.tx_state.force_tx, false) {
+			// Send as much new data as the congestion and receive windows allow
+			let window = ::core::cmp::min(self.tx_state.cc.window(), self.tx_state.max_tx_window_size as usize);
+			loop
+			{
+				let opts = self.segment_options(flags);
+				// Options take space from the segment
+				let max_data = self.options.send_mss - opts.padded_len();
+				let unsent = self.tx_state.buffer.len() - self.tx_state.sent_bytes;
+				let mut nbytes = unsent.min(window.saturating_sub(self.tx_state.sent_bytes)).min(max_data);
+				// Nagle: Don't send a short segment while there's un-acknowledged data
+				if nbytes < max_data && self.tx_state.sent_bytes > 0 {
+					nbytes = 0;
+				}
+				// Only send an empty packet if there's flags to send
+				if nbytes == 0 && flags == 0 {
+					break;
+				}
+				let adv_window = self.advertised_window();
+				let data = self.tx_state.buffer.get_slices(self.tx_state.sent_bytes .. self.tx_state.sent_bytes + nbytes);
+				let seq = self.tx_state.next_tx_seq;
+				log_trace!("{:?} TX {:#x} {} bytes", quad, flags, nbytes);
+				block_on(quad.send_packet(seq, self.next_rx_seq, flags, adv_window, opts.as_bytes(), data.0, data.1));
+				// TODO: Some flags act as a pseudo-byte if in an empty packet
+				flags = 0;
+				if nbytes == 0 {
+					break;
+				}
+				self.tx_state.next_tx_seq = self.tx_state.next_tx_seq.wrapping_add( nbytes as u32 );
+				self.tx_state.sent_bytes += nbytes;
+				// Only time new data (Karn's algorithm)
+				if self.tx_state.next_tx_seq.wrapping_sub(self.tx_state.max_tx_seq) as i32 > 0 {
+					self.tx_state.max_tx_seq = self.tx_state.next_tx_seq;
+					self.tx_state.rto.start_timing(self.tx_state.next_tx_seq);
+				}
+				// Start the retransmit timer if it isn't already running (RFC 6298 5.1)
+				if self.tx_state.retransmit_timer.get_expiry().is_none() {
+					self.tx_state.retransmit_timer.reset(self.tx_state.rto.timeout as u64);
+				}
+			}
 		}
 
 		let mut rv = None;
@@ -472,11 +895,54 @@ impl Connection
 		rv
 	}
 
+	/// Re-send up to `len` bytes of previously sent data starting at `seq`, returning the number of bytes sent
+	fn resend_data(&mut self, quad: &Quad, seq: u32, len: usize, flags: u8) -> usize
+	{
+		let opts = self.segment_options(flags);
+		let len = ::core::cmp::min(len, self.options.send_mss - opts.padded_len());
+		let adv_window = self.advertised_window();
+		let ofs = seq.wrapping_sub(self.tx_state.first_unacked_seq()) as usize;
+		let data = self.tx_state.buffer.get_slices(ofs .. ofs + len);
+		::kernel::futures::block_on(quad.send_packet(seq, self.next_rx_seq, flags, adv_window, opts.as_bytes(), data.0, data.1));
+		len
+	}
+
 	fn send_empty_packet(&mut self, quad: &Quad, flags: u8)
 	{
 		log_debug!("{:?} send_packet({:02x})", quad, flags);
+		let opts = self.segment_options(flags);
 		// TODO: Enqueue instead of blocking?
-		::kernel::futures::block_on(quad.send_packet(self.tx_state.next_tx_seq, self.next_rx_seq, flags, self.rx_window_size as u16, &[], &[]));
+		::kernel::futures::block_on(quad.send_packet(self.tx_state.next_tx_seq, self.next_rx_seq, flags, self.advertised_window(), opts.as_bytes(), &[], &[]));
+	}
+
+	/// Options to send in a segment: The offered options for a SYN, otherwise a timestamp and SACK blocks (if enabled)
+	fn segment_options(&self, flags: u8) -> OptionsBuf
+	{
+		if flags & FLAG_SYN != 0 {
+			return OptionsBuf::syn(LOCAL_MSS as u16, Some(LOCAL_WINDOW_SCALE), true, Some(Timestamp { value: super::timestamp_now(), echo: 0 }));
+		}
+		let mut rv = OptionsBuf::new();
+		if self.options.timestamps {
+			rv.push_timestamp(Timestamp { value: super::timestamp_now(), echo: self.ts_recent });
+		}
+		// Report any out-of-order data
+		if self.options.sack && self.rx_highest_seq.wrapping_sub(self.next_rx_seq) as i32 > 0 {
+			let start = self.next_rx_seq.wrapping_sub(self.rx_buffer_seq) as usize;
+			let end = self.rx_highest_seq.wrapping_sub(self.rx_buffer_seq) as usize;
+			let mut ranges = [(0,0); MAX_TX_SACK_BLOCKS];
+			let count = self.rx_buffer.valid_ranges(start .. end, &mut ranges);
+			let mut blocks = [(0,0); MAX_TX_SACK_BLOCKS];
+			for (dst, &(s, e)) in Iterator::zip(blocks.iter_mut(), &ranges[..count]) {
+				*dst = (self.rx_buffer_seq.wrapping_add(s as u32), self.rx_buffer_seq.wrapping_add(e as u32));
+			}
+			rv.push_sack(&blocks[..count]);
+		}
+		rv
+	}
+	/// Receive window to advertise (scaled down if window scaling is in use)
+	fn advertised_window(&self) -> u16
+	{
+		::core::cmp::min(self.rx_window_size >> self.options.recv_window_scale, 0xFFFF) as u16
 	}
 	fn send_ack(&mut self, quad: &Quad, msg: &str)
 	{
@@ -494,7 +960,9 @@ impl Connection
 		let new_state = match self.state
 			{
 			ConnectionState::SynSent => {
-				todo!("{:?} close before established", quad);
+				// Abort the handshake
+				self.send_empty_packet(quad, FLAG_RST);
+				ConnectionState::Finished
 				},
 			ConnectionState::FinWait1
 			| ConnectionState::FinWait2
@@ -0,0 +1,268 @@
+//! TCP header options (MSS, window scaling, timestamps and SACK)
+//!
+//! See RFC 9293 3.2 (MSS), RFC 7323 (window scaling and timestamps) and RFC 2018 (SACK)
+
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+const KIND_SACK: u8 = 5;
+const KIND_TIMESTAMP: u8 = 8;
+
+/// Maximum size of the options area (header length is at most 60 bytes)
+const MAX_OPTIONS_LEN: usize = 40;
+/// Largest permitted window scale shift (RFC 7323 2.3)
+const MAX_WINDOW_SCALE: u8 = 14;
+/// Smallest MSS accepted from the peer (ensures that there's space for data after the options)
+const MIN_MSS: usize = 64;
+/// Maximum number of SACK blocks that can be parsed from a segment
+pub const MAX_SACK_BLOCKS: usize = 4;
+/// Space used by a timestamp option (including the two leading NOPs)
+pub const TIMESTAMP_LEN: usize = 12;
+
+/// Timestamp option value
+#[derive(Copy,Clone,Debug,Default)]
+pub struct Timestamp
+{
+	/// Sender's timestamp clock (TSval)
+	pub value: u32,
+	/// Most recent timestamp seen from the peer (TSecr)
+	pub echo: u32,
+}
+
+/// List of SACK blocks (start and end sequence numbers)
+#[derive(Copy,Clone,Default)]
+pub struct SackBlocks
+{
+	count: usize,
+	blocks: [(u32, u32); MAX_SACK_BLOCKS],
+}
+impl SackBlocks
+{
+	pub fn push(&mut self, start: u32, end: u32) -> bool
+	{
+		if self.count == MAX_SACK_BLOCKS {
+			false
+		}
+		else {
+			self.blocks[self.count] = (start, end);
+			self.count += 1;
+			true
+		}
+	}
+	pub fn iter(&self) -> impl Iterator<Item=&(u32, u32)>
+	{
+		self.blocks[..self.count].iter()
+	}
+}
+impl ::core::fmt::Debug for SackBlocks
+{
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result
+	{
+		f.debug_list().entries(self.iter()).finish()
+	}
+}
+
+/// Options parsed from a received segment
+#[derive(Debug,Default)]
+pub struct Options
+{
+	pub mss: Option<u16>,
+	pub window_scale: Option<u8>,
+	pub sack_permitted: bool,
+	pub timestamp: Option<Timestamp>,
+	pub sack_blocks: SackBlocks,
+}
+impl Options
+{
+	/// Parse `len` bytes of options from the packet
+	///
+	/// Parsing stops at a malformed option, the reader may be left part-way through the options area.
+	pub fn read(reader: &mut crate::nic::PacketReader, len: usize) -> Options
+	{
+		let mut rv = Options::default();
+		let mut remain = len;
+		while remain > 0
+		{
+			let kind = match reader.read_u8()
+				{
+				Ok(v) => v,
+				Err(_) => break,
+				};
+			remain -= 1;
+			match kind
+			{
+			KIND_END => break,
+			KIND_NOP => continue,
+			_ => {},
+			}
+			let opt_len = match reader.read_u8()
+				{
+				Ok(v) if v >= 2 && v as usize - 1 <= remain => v as usize - 2,
+				_ => {
+					log_notice!("Malformed TCP option {} (bad length)", kind);
+					break
+					},
+				};
+			remain -= 1 + opt_len;
+			if let Err(_) = rv.read_one(reader, kind, opt_len) {
+				log_notice!("Malformed TCP option {} (length {})", kind, opt_len);
+				break;
+			}
+		}
+		rv
+	}
+
+	fn read_one(&mut self, reader: &mut crate::nic::PacketReader, kind: u8, len: usize) -> Result<(), ()>
+	{
+		match (kind, len)
+		{
+		(KIND_MSS, 2) => self.mss = Some(reader.read_u16n()?),
+		(KIND_WINDOW_SCALE, 1) => self.window_scale = Some( ::core::cmp::min(reader.read_u8()?, MAX_WINDOW_SCALE) ),
+		(KIND_SACK_PERMITTED, 0) => self.sack_permitted = true,
+		(KIND_TIMESTAMP, 8) => self.timestamp = Some(Timestamp { value: reader.read_u32n()?, echo: reader.read_u32n()? }),
+		(KIND_SACK, _) if len % 8 == 0 => {
+			for _ in 0 .. len / 8
+			{
+				let start = reader.read_u32n()?;
+				let end = reader.read_u32n()?;
+				self.sack_blocks.push(start, end);
+			}
+			},
+		(KIND_MSS, _)
+		| (KIND_WINDOW_SCALE, _)
+		| (KIND_SACK_PERMITTED, _)
+		| (KIND_TIMESTAMP, _)
+		| (KIND_SACK, _) => return Err( () ),
+		_ => {
+			// Unknown option, skip
+			for _ in 0 .. len {
+				reader.read_u8()?;
+			}
+			},
+		}
+		Ok( () )
+	}
+}
+
+/// Options to be sent in a segment
+pub struct OptionsBuf
+{
+	len: usize,
+	data: [u8; MAX_OPTIONS_LEN],
+}
+impl OptionsBuf
+{
+	pub fn new() -> OptionsBuf
+	{
+		OptionsBuf { len: 0, data: [0; MAX_OPTIONS_LEN] }
+	}
+	/// Options for a SYN (or SYN-ACK)
+	pub fn syn(mss: u16, window_scale: Option<u8>, sack_permitted: bool, timestamp: Option<Timestamp>) -> OptionsBuf
+	{
+		let mut rv = OptionsBuf::new();
+		rv.push(&[KIND_MSS, 4]);
+		rv.push(&mss.to_be_bytes());
+		if let Some(shift) = window_scale {
+			rv.push(&[KIND_NOP, KIND_WINDOW_SCALE, 3, shift]);
+		}
+		match (sack_permitted, timestamp)
+		{
+		(true, Some(ts)) => {
+			// Common layout, SACK-permitted takes the place of the timestamp's padding
+			rv.push(&[KIND_SACK_PERMITTED, 2]);
+			rv.push_timestamp_raw(ts);
+			},
+		(true, None) => rv.push(&[KIND_NOP, KIND_NOP, KIND_SACK_PERMITTED, 2]),
+		(false, Some(ts)) => rv.push_timestamp(ts),
+		(false, None) => {},
+		}
+		rv
+	}
+
+	pub fn as_bytes(&self) -> &[u8]
+	{
+		&self.data[..self.len]
+	}
+	/// Length once padded to a multiple of four bytes
+	pub fn padded_len(&self) -> usize
+	{
+		(self.len + 3) / 4 * 4
+	}
+
+	pub fn push_timestamp(&mut self, ts: Timestamp)
+	{
+		self.push(&[KIND_NOP, KIND_NOP]);
+		self.push_timestamp_raw(ts);
+	}
+	fn push_timestamp_raw(&mut self, ts: Timestamp)
+	{
+		self.push(&[KIND_TIMESTAMP, 10]);
+		self.push(&ts.value.to_be_bytes());
+		self.push(&ts.echo.to_be_bytes());
+	}
+	/// Add as many of the SACK blocks as will fit
+	pub fn push_sack(&mut self, blocks: &[(u32, u32)])
+	{
+		let space = (MAX_OPTIONS_LEN - self.len).saturating_sub(4) / 8;
+		let count = ::core::cmp::min(blocks.len(), space);
+		if count > 0
+		{
+			self.push(&[KIND_NOP, KIND_NOP, KIND_SACK, (2 + count * 8) as u8]);
+			for &(start, end) in &blocks[..count]
+			{
+				self.push(&start.to_be_bytes());
+				self.push(&end.to_be_bytes());
+			}
+		}
+	}
+
+	fn push(&mut self, bytes: &[u8])
+	{
+		self.data[self.len ..][..bytes.len()].copy_from_slice(bytes);
+		self.len += bytes.len();
+	}
+}
+
+/// Parameters agreed in the SYN exchange
+#[derive(Copy,Clone,Debug)]
+pub struct Negotiated
+{
+	/// Largest segment (excluding headers and options) the peer accepts
+	pub send_mss: usize,
+	/// Shift applied to window sizes received from the peer
+	pub send_window_scale: u8,
+	/// Shift applied to window sizes sent to the peer
+	pub recv_window_scale: u8,
+	/// Both sides sent SACK-permitted
+	pub sack: bool,
+	/// Both sides sent timestamps, so every segment should carry one
+	pub timestamps: bool,
+}
+impl Negotiated
+{
+	/// Determine the agreed parameters from the peer's SYN options
+	///
+	/// `local_mss` and `local_window_scale` are the values sent (or to be sent) in the local SYN, the local SYN is assumed
+	/// to offer (or echo) SACK and timestamps.
+	pub fn from_syn(peer: &Options, local_mss: usize, local_window_scale: u8) -> Negotiated
+	{
+		let (send_window_scale, recv_window_scale) = match peer.window_scale
+			{
+			Some(s) => (s, local_window_scale),
+			None => (0, 0),
+			};
+		Negotiated {
+			send_mss: match peer.mss
+				{
+				Some(v) => ::core::cmp::min(::core::cmp::max(v as usize, MIN_MSS), local_mss),
+				None => ::core::cmp::min(super::DEFAULT_MSS, local_mss),
+				},
+			send_window_scale,
+			recv_window_scale,
+			sack: peer.sack_permitted,
+			timestamps: peer.timestamp.is_some(),
+		}
+	}
+}
@@ -0,0 +1,394 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/udp.rs
+//! User Datagram Protocol (Layer 4)
+use shared_map::SharedMap;
+use kernel::sync::Mutex;
+use kernel::lib::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use crate::nic::SparsePacket;
+use crate::Address;
+use kernel::futures::block_on;
+
+pub(crate) const IPV4_PROTO_UDP: u8 = 17;
+pub(crate) const IPV6_PROTO_UDP: u8 = 17;
+/// Maximum number of un-read datagrams held against a socket (new datagrams are dropped when full)
+const MAX_RX_QUEUE: usize = 16;
+/// Largest payload that fits in an IPv4 packet (limited by the 16-bit total length)
+const MAX_PAYLOAD_V4: usize = 0xFFFF - 20 - 8;
+/// Largest payload that fits in an unfragmented IPv6 packet (sending fragments isn't supported)
+const MAX_PAYLOAD_V6: usize = 1500 - 40 - 8;
+
+const MIN_DYN_PORT: u16 = 0xC000;
+
+pub fn init()
+{
+	crate::ipv4::register_handler(IPV4_PROTO_UDP, rx_handler_v4).unwrap();
+	crate::ipv6::register_handler(IPV6_PROTO_UDP, rx_handler_v6).unwrap();
+}
+
+static SOCKETS: SharedMap<LocalPair, Socket> = SharedMap::new();
+/// Next dynamic port to try when binding to port zero
+static NEXT_DYN_PORT: AtomicU16 = AtomicU16::new(MIN_DYN_PORT);
+
+fn rx_handler_v4(int: &crate::ipv4::Interface, src_addr: crate::ipv4::Address, pkt: crate::nic::PacketReader) -> crate::ipv4::RxResult
+{
+	rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt)
+}
+fn rx_handler_v6(_int: &crate::ipv6::Interface, src_addr: crate::ipv6::Address, dest_addr: crate::ipv6::Address, _hop_limit: u8, pkt: crate::nic::PacketReader) -> crate::ipv4::RxResult
+{
+	rx_handler(Address::Ipv6(src_addr), Address::Ipv6(dest_addr), pkt)
+}
+fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::PacketReader) -> crate::ipv4::RxResult
+{
+	use crate::ipv4::RxResult;
+	let hdr = match PktHeader::read(&mut pkt)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_error!("Undersized packet: Ran out of data reading header");
+			return RxResult::Handled;
+			},
+		};
+	log_debug!("hdr = {:?}", hdr);
+	// NOTE: The length field covers the header, and the IP layer may have left padding after the end of the datagram
+	if (hdr.length as usize) < 8 || hdr.length as usize - 8 > pkt.remain() {
+		log_error!("Undersized or invalid packet: UDP length is {} but packet length is {}", hdr.length, 8 + pkt.remain());
+		return RxResult::Handled;
+	}
+	let mut data = vec![0; hdr.length as usize - 8];
+	if pkt.read(&mut data).is_err() {
+		return RxResult::Handled;
+	}
+
+	// Validate checksum (a zero checksum means that the sender didn't calculate one, which is only allowed on IPv4)
+	if hdr.checksum == 0
+	{
+		if let Address::Ipv6(_) = src_addr {
+			log_error!("Zero checksum on IPv6 datagram");
+			return RxResult::Handled;
+		}
+	}
+	else
+	{
+		let sum = calculate_checksum(src_addr, dest_addr, &hdr.as_bytes(), &data);
+		if sum != 0 {
+			log_error!("Incorrect checksum: 0x{:04x} != 0", sum);
+			return RxResult::Handled;
+		}
+	}
+
+	let sock = match Option::or( SOCKETS.get(&LocalPair::fixed(dest_addr, hdr.dest_port)), SOCKETS.get(&LocalPair::any(hdr.dest_port)) )
+		{
+		Some(v) => v,
+		None => {
+			log_debug!("Datagram to closed port: {:?}:{} -> {:?}:{}", src_addr, hdr.source_port, dest_addr, hdr.dest_port);
+			return RxResult::PortUnreachable;
+			},
+		};
+	if !sock.remote_mask.matches(&src_addr, hdr.source_port)
+	{
+		log_debug!("Datagram from {:?}:{} doesn't match socket filter {:?}", src_addr, hdr.source_port, sock.remote_mask);
+		return RxResult::Handled;
+	}
+
+	let mut lh = sock.rx_queue.lock();
+	if lh.len() >= MAX_RX_QUEUE
+	{
+		log_debug!("Datagram to {:?}:{} dropped, queue full", dest_addr, hdr.dest_port);
+		return RxResult::Handled;
+	}
+	lh.push(Datagram {
+		remote_addr: src_addr,
+		remote_port: hdr.source_port,
+		data: data,
+		});
+	sock.waiters.wake_all();
+	RxResult::Handled
+}
+
+/// Handle an ICMP error against a datagram sent from the given local address
+pub(crate) fn handle_icmp_error(local: (Address, u16), remote: (Address, u16), kind: crate::icmp::ErrorKind)
+{
+	let sock = match Option::or( SOCKETS.get(&LocalPair::fixed(local.0, local.1)), SOCKETS.get(&LocalPair::any(local.1)) )
+		{
+		Some(v) => v,
+		None => return,
+		};
+	if !sock.remote_mask.matches(&remote.0, remote.1) {
+		return ;
+	}
+	log_debug!("{:?}:{} ICMP error {:?} from {:?}:{}", local.0, local.1, kind, remote.0, remote.1);
+	*sock.pending_error.lock() = Some(kind);
+	sock.waiters.wake_all();
+}
+
+/// Calculate the checksum of a datagram (including the pseudo-header)
+///
+/// When called on a received datagram (with the checksum field populated), this returns zero if the checksum is valid
+pub(crate) fn calculate_checksum(src_addr: Address, dest_addr: Address, hdr: &[u8; 8], data: &[u8]) -> u16
+{
+	use crate::ipv4::calculate_checksum;
+	// NOTE: UDP has the same protocol number on IPv4 and IPv6
+	let sum_pseudo = crate::pseudo_header_checksum(&src_addr, &dest_addr, IPV4_PROTO_UDP, hdr.len() + data.len());
+	let sum_header = calculate_checksum( hdr.chunks(2).map(|v| (v[0] as u16) << 8 | v[1] as u16) );
+	// Final byte is summed as if there was a zero after it (so as 0x??00)
+	let sum_data = calculate_checksum( data.chunks(2).map(|v| (v[0] as u16) << 8 | v.get(1).copied().unwrap_or(0) as u16) );
+	calculate_checksum([ !sum_pseudo, !sum_header, !sum_data ].iter().copied())
+}
+
+#[derive(Copy,Clone,PartialEq,PartialOrd,Eq,Ord,Debug)]
+struct LocalPair(Option<Address>, u16);
+impl LocalPair
+{
+	fn any(port: u16) -> LocalPair {
+		LocalPair(None, port)
+	}
+	fn fixed(addr: Address, port: u16) -> LocalPair {
+		LocalPair(Some(addr), port)
+	}
+}
+
+/// Filter applied to the source of incoming datagrams
+#[derive(Copy,Clone,Debug)]
+pub struct RemoteMask
+{
+	/// Remote address (only the first `mask_bits` bits are checked)
+	pub addr: Address,
+	pub mask_bits: u8,
+	/// Remote port, zero matches any port
+	pub port: u16,
+}
+impl RemoteMask
+{
+	fn matches(&self, addr: &Address, port: u16) -> bool
+	{
+		if self.port != 0 && self.port != port {
+			return false;
+		}
+		match (self.addr, *addr)
+		{
+		(Address::Ipv4(m), Address::Ipv4(a)) => m.mask(self.mask_bits) == a.mask(self.mask_bits),
+		(Address::Ipv6(m), Address::Ipv6(a)) => m.mask(self.mask_bits) == a.mask(self.mask_bits),
+		_ => false,
+		}
+	}
+}
+
+struct Datagram
+{
+	remote_addr: Address,
+	remote_port: u16,
+	data: Vec<u8>,
+}
+
+struct Socket
+{
+	remote_mask: RemoteMask,
+	rx_queue: Mutex<Vec<Datagram>>,
+	/// ICMP error received since the last read (reported once)
+	pending_error: Mutex<Option<crate::icmp::ErrorKind>>,
+	// Userland waiters for an incoming datagram
+	waiters: ::kernel::user_async::Queue,
+}
+
+impl Socket
+{
+	fn has_rx_event(&self) -> bool
+	{
+		self.pending_error.lock().is_some() || !self.rx_queue.lock().is_empty()
+	}
+}
+
+#[derive(Debug)]
+pub enum BindError
+{
+	/// Another socket is already bound to this address/port
+	AddressInUse,
+	/// No free dynamic ports to allocate
+	NoPortAvailable,
+}
+#[derive(Debug)]
+pub enum SendError
+{
+	/// No route to the destination (or it's not reachable from the bound address)
+	NoRoute,
+	/// Datagram is too large to send
+	TooLarge,
+}
+#[derive(Debug)]
+pub enum RecvError
+{
+	/// No datagrams waiting
+	NoData,
+	/// An ICMP error was received in response to a previously sent datagram
+	Icmp(crate::icmp::ErrorKind),
+}
+
+/// Handle to a bound UDP socket
+pub struct SocketHandle(LocalPair);
+impl SocketHandle
+{
+	/// Bind a socket to a local port (optionally restricted to a single local address)
+	///
+	/// A port of zero allocates a port from the dynamic range
+	pub fn bind(local_addr: Option<Address>, port: u16, remote_mask: RemoteMask) -> Result<SocketHandle, BindError>
+	{
+		let new_socket = || Socket {
+			remote_mask: remote_mask,
+			rx_queue: Mutex::new(Vec::new()),
+			pending_error: Mutex::new(None),
+			waiters: ::kernel::user_async::Queue::new(),
+			};
+		if port != 0
+		{
+			let p = LocalPair(local_addr, port);
+			SOCKETS.insert(p, new_socket()).map_err(|_| BindError::AddressInUse)?;
+			Ok( SocketHandle(p) )
+		}
+		else
+		{
+			// Strategy: Linear from the last allocated port
+			for _ in MIN_DYN_PORT ..= 0xFFFF
+			{
+				let port = NEXT_DYN_PORT.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some(if v == 0xFFFF { MIN_DYN_PORT } else { v + 1 })).unwrap();
+				let p = LocalPair(local_addr, port);
+				if SOCKETS.insert(p, new_socket()).is_ok() {
+					return Ok( SocketHandle(p) );
+				}
+			}
+			Err(BindError::NoPortAvailable)
+		}
+	}
+
+	/// Local address (`None` if bound to all addresses) and port of this socket
+	pub fn local_addr(&self) -> (Option<Address>, u16)
+	{
+		(self.0 .0, self.0 .1)
+	}
+
+	/// Send a single datagram to the specified remote
+	pub fn send_to(&self, addr: Address, port: u16, data: &[u8]) -> Result<usize, SendError>
+	{
+		let max_payload = match addr
+			{
+			Address::Ipv4(_) => MAX_PAYLOAD_V4,
+			Address::Ipv6(_) => MAX_PAYLOAD_V6,
+			};
+		if data.len() > max_payload {
+			return Err(SendError::TooLarge);
+		}
+		let source = match (self.0 .0, addr)
+			{
+			(Some(Address::Ipv4(l)), Address::Ipv4(r)) => crate::ipv4::route_lookup(l, r).map(|_| Address::Ipv4(l)),
+			(None, Address::Ipv4(r)) => crate::ipv4::route_lookup(crate::ipv4::Address::zero(), r).map(|(l, _, _)| Address::Ipv4(l)),
+			(Some(Address::Ipv6(l)), Address::Ipv6(r)) => crate::ipv6::route_lookup(l, r).map(|_| Address::Ipv6(l)),
+			(None, Address::Ipv6(r)) => crate::ipv6::route_lookup(crate::ipv6::Address::zero(), r).map(|(l, _, _)| Address::Ipv6(l)),
+			// Bound to an address of the other family
+			_ => None,
+			};
+		let source = source.ok_or(SendError::NoRoute)?;
+
+		let mut hdr = PktHeader {
+			source_port: self.0 .1,
+			dest_port: port,
+			length: (8 + data.len()) as u16,
+			checksum: 0,
+			};
+		hdr.checksum = match calculate_checksum(source, addr, &hdr.as_bytes(), data)
+			{
+			// A calculated checksum of zero is sent as all ones (zero means no checksum)
+			0 => 0xFFFF,
+			v => v,
+			};
+		let hdr_bytes = hdr.as_bytes();
+		let data_pkt = SparsePacket::new_root(data);
+		let hdr_pkt = SparsePacket::new_chained(&hdr_bytes, &data_pkt);
+		match source
+		{
+		Address::Ipv4(s) => block_on(crate::ipv4::send_packet(s, addr.unwrap_ipv4(), IPV4_PROTO_UDP, hdr_pkt)),
+		Address::Ipv6(s) => block_on(crate::ipv6::send_packet(s, addr.unwrap_ipv6(), IPV6_PROTO_UDP, hdr_pkt)),
+		}
+		Ok( data.len() )
+	}
+
+	/// Pop a datagram from the receive queue, returning the datagram's length and source
+	///
+	/// If the buffer is smaller than the datagram, the remainder of the datagram is discarded.
+	/// A pending ICMP error is reported (once) before any queued datagrams.
+	pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Address, u16), RecvError>
+	{
+		let s = SOCKETS.get(&self.0).expect("Socket entry missing while handle still exists");
+		if let Some(e) = s.pending_error.lock().take() {
+			return Err(RecvError::Icmp(e));
+		}
+		let mut lh = s.rx_queue.lock();
+		if lh.is_empty() {
+			return Err(RecvError::NoData);
+		}
+		let dg = lh.remove(0);
+		let len = ::core::cmp::min(buf.len(), dg.data.len());
+		buf[..len].copy_from_slice(&dg.data[..len]);
+		Ok( (len, dg.remote_addr, dg.remote_port) )
+	}
+
+	/// Register a sleep object to be woken when a datagram is received
+	pub fn bind_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject)
+	{
+		let s = SOCKETS.get(&self.0).expect("Socket entry missing while handle still exists");
+		s.waiters.wait_upon(obj);
+		if s.has_rx_event() {
+			obj.signal();
+		}
+	}
+	/// Unregister a sleep object, returning `true` if there's a datagram (or error) waiting
+	pub fn clear_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject) -> bool
+	{
+		let s = SOCKETS.get(&self.0).expect("Socket entry missing while handle still exists");
+		s.waiters.clear_wait(obj);
+		s.has_rx_event()
+	}
+}
+impl ::core::ops::Drop for SocketHandle
+{
+	fn drop(&mut self)
+	{
+		SOCKETS.take(&self.0);
+	}
+}
+
+#[derive(Debug)]
+struct PktHeader
+{
+	source_port: u16,
+	dest_port: u16,
+	/// Length of the header and data
+	length: u16,
+	checksum: u16,
+}
+impl PktHeader
+{
+	fn read(reader: &mut crate::nic::PacketReader) -> Result<Self, ()>
+	{
+		Ok(PktHeader {
+			source_port: reader.read_u16n()?,
+			dest_port: reader.read_u16n()?,
+			length: reader.read_u16n()?,
+			checksum: reader.read_u16n()?,
+			})
+	}
+	fn as_bytes(&self) -> [u8; 8]
+	{
+		[
+			(self.source_port >> 8) as u8,
+			(self.source_port >> 0) as u8,
+			(self.dest_port >> 8) as u8,
+			(self.dest_port >> 0) as u8,
+			(self.length >> 8) as u8,
+			(self.length >> 0) as u8,
+			(self.checksum >> 8) as u8,
+			(self.checksum >> 0) as u8,
+			]
+	}
+}
@@ -411,12 +411,18 @@ impl Port
 	}
 	fn request_ata_lba48(&self, disk: u8, cmd: u8,  n_sectors: u16, lba: u64, data: DataPtr) -> Result<usize, Error>
 	{
-		log_trace!("request_ata_lba48(disk={}, cmd={:#02x}, n_sectors={}, lba={})", disk, cmd, n_sectors, lba);
+		self.request_ata_lba48_features(disk, cmd, 0, n_sectors, lba, data)
+	}
+	fn request_ata_lba48_features(&self, disk: u8, cmd: u8, features: u16, n_sectors: u16, lba: u64, data: DataPtr) -> Result<usize, Error>
+	{
+		log_trace!("request_ata_lba48(disk={}, cmd={:#02x}, features={:#x}, n_sectors={}, lba={})", disk, cmd, features, n_sectors, lba);
 		assert!(lba < (1<<48));
 		let cmd_data = hw::sata::FisHost2DevReg {
 			ty: hw::sata::FisType::H2DRegister as u8,
 			flags: 0x80,
 			command: cmd,
+			features: features as u8,
+			features_exp: (features >> 8) as u8,
 			sector_num: lba as u8,
 			cyl_low: (lba >> 8) as u8,
 			cyl_high: (lba >> 16) as u8,
@@ -489,7 +495,9 @@ impl Port
 
 			n_prdt_ents += 1;
 		}
-		slot.data.prdt[n_prdt_ents-1].dbc |= 1 << 31;	// set IOC
+		if n_prdt_ents > 0 {
+			slot.data.prdt[n_prdt_ents-1].dbc |= 1 << 31;	// set IOC
+		}
 		slot.hdr.prdtl = n_prdt_ents as u16;
 		slot.hdr.prdbc = 0;
 		slot.hdr.flags = (cmd.len() / 4) as u16
@@ -683,6 +691,22 @@ impl ::storage_ata::volume::Interface for Interface
 		Err(_) => Err(From::from(0)),
 		}
 	}
+	fn dma_lba_48_features(&self, cmd: u8, features: u16, count: u16, addr: u64, data: DataPtr) -> Result<usize,::storage_ata::volume::Error> {
+		match self.port().request_ata_lba48_features(0, cmd, features, count, addr, data)
+		{
+		Ok(bc) => Ok( bc / 512 ),
+		Err(Error::Ata{err, ..}) => Err(From::from(err)),
+		Err(_) => Err(From::from(0)),
+		}
+	}
+	fn non_data(&self, cmd: u8) -> Result<(),::storage_ata::volume::Error> {
+		match self.port().request_ata_lba48(0, cmd, 0, 0, DataPtr::Send(&[]))
+		{
+		Ok(_) => Ok( () ),
+		Err(Error::Ata{err, ..}) => Err(From::from(err)),
+		Err(_) => Err(From::from(0)),
+		}
+	}
 }
 
 impl ::storage_scsi::ScsiInterface for Interface
@@ -22,6 +22,9 @@ const HDD_DMA_R28: u8 = 0xC8;
 const HDD_DMA_W28: u8 = 0xCA;
 const HDD_DMA_R48: u8 = 0x25;
 const HDD_DMA_W48: u8 = 0x35;
+const HDD_DSM: u8 = 0x06;
+/// DATA SET MANAGEMENT feature: TRIM
+const HDD_DSM_TRIM: u16 = 0x0001;
 
 pub struct DmaController
 {
@@ -88,6 +91,22 @@ impl DmaController
 		let dst = if count > MAX_DMA_SECTORS { &dst[.. MAX_DMA_SECTORS * SECTOR_SIZE] } else { dst };
 		self.do_dma(blockidx, DMABuffer::new(dst, 32), disk, true)
 	}
+	/// Issue DATA SET MANAGEMENT (TRIM) with the provided range blocks
+	pub fn do_trim<'a>(&'a self, ranges: &'a [u8], disk: u8) -> storage::AsyncIoResult<'a,()> {
+		assert!(disk < 4);
+		assert!(ranges.len() % SECTOR_SIZE == 0);
+		let bus = (disk >> 1) & 1;
+		let ctrlr = &self.ata_controllers[bus as usize];
+		let bm_regs = self.borrow_regs(bus == 1);
+		Box::pin(ctrlr.do_dsm(DMABuffer::new(ranges, 32), disk & 1, bm_regs))
+	}
+	/// Flush the disk's write cache, using the passed FLUSH CACHE command
+	pub fn do_flush<'a>(&'a self, cmd: u8, disk: u8) -> storage::AsyncIoResult<'a,()> {
+		assert!(disk < 4);
+		let bus = (disk >> 1) & 1;
+		let ctrlr = &self.ata_controllers[bus as usize];
+		Box::pin(ctrlr.do_non_data(cmd, disk & 1))
+	}
 	fn do_dma<'a>(&'a self, blockidx: u64, dst: DMABuffer<'a>, disk: u8, is_write: bool) -> storage::AsyncIoResult<'a,usize>
 	{
 		log_trace!("do_dma(blockidx={}, dst={:?}, disk={})", blockidx, dst, disk);
@@ -281,6 +300,18 @@ impl AtaRegs
 		log_debug!("start_dma(disk={},blockidx={},is_write={},dma_buffer={{len={}}})",
 			disk, blockidx, is_write, dma_buffer.len());
 		let count = dma_buffer.len() / SECTOR_SIZE;
+		// - Only use LBA48 if needed
+		let use_48 = blockidx >= (1 << 28) || count >= 256;
+		let cmd = if use_48 {
+				if is_write { HDD_DMA_W48 } else { HDD_DMA_R48 }	// LBA 48
+			} else {
+				if is_write { HDD_DMA_W28 } else { HDD_DMA_R28 }	// LBA 28
+			};
+		self.start_dma_cmd(disk, cmd, 0, blockidx, use_48, dma_buffer, is_write, bm);
+	}
+	fn start_dma_cmd(&mut self, disk: u8, cmd: u8, features: u16, blockidx: u64, use_48: bool, dma_buffer: &DMABuffer, is_write: bool, bm: &DmaRegBorrow)
+	{
+		let count = dma_buffer.len() / SECTOR_SIZE;
 		
 		self.fill_prdt(dma_buffer);
 		
@@ -288,10 +319,10 @@ impl AtaRegs
 		// SAFE: Unique access and valid IO accesses
 		unsafe
 		{
-			// - Only use LBA48 if needed
-			if blockidx >= (1 << 28) || count >= 256
+			if use_48
 			{
 				self.out_8(6, 0x40 | (disk << 4));
+				self.out_8(1, (features >> 8) as u8);
 				self.out_8(2, (count >> 8) as u8);
 				self.out_8(3, (blockidx >> 24) as u8);
 				self.out_8(4, (blockidx >> 32) as u8);
@@ -301,6 +332,7 @@ impl AtaRegs
 			{
 				self.out_8(6, 0xE0 | (disk << 4) | ((blockidx >> 24) & 0x0F) as u8);
 			}
+			self.out_8(1, features as u8);
 			self.out_8(2, count as u8);
 			self.out_8(3, (blockidx >>  0) as u8);
 			self.out_8(4, (blockidx >>  8) as u8);
@@ -310,12 +342,7 @@ impl AtaRegs
 			bm.out_32(4, ::kernel::memory::virt::get_phys(&self.prdts[0]) as u32);
 			bm.out_8(0, 0x04);	// Reset IRQ
 			
-			self.out_8(7,
-				if blockidx >= (1 << 48) {
-					if is_write { HDD_DMA_W48 } else { HDD_DMA_R48 }	// LBA 48
-				} else {
-					if is_write { HDD_DMA_W28 } else { HDD_DMA_R28 }	// LBA 28
-				});
+			self.out_8(7, cmd);
 			
 			// Start IO
 			bm.out_8(0, if is_write { 0 } else { 8 } | 1);
@@ -335,22 +362,28 @@ impl AtaRegs
 		//	cmd[5] & 0xFF, cmd[5] >> 8
 		//	);
 		
-		self.fill_prdt(dma_buffer);
+		// Non-data commands (e.g. SYNCHRONIZE CACHE) don't touch the bus master
+		let has_data = dma_buffer.len() > 0;
+		if has_data {
+			self.fill_prdt(dma_buffer);
+		}
 		
 		// Commence the IO and return a wait handle for the operation
 		// SAFE: Locked (unique self) and checked access
 		unsafe
 		{
-			// - Set PRDT
-			bm.out_32(4, ::kernel::memory::virt::get_phys(&self.prdts[0]) as u32);
-			bm.out_8(0, 0x04);	// Reset IRQ
-			// Start IO
-			bm.out_8(0, if is_write { 0 } else { 8 } | 1);
+			if has_data {
+				// - Set PRDT
+				bm.out_32(4, ::kernel::memory::virt::get_phys(&self.prdts[0]) as u32);
+				bm.out_8(0, 0x04);	// Reset IRQ
+				// Start IO
+				bm.out_8(0, if is_write { 0 } else { 8 } | 1);
+			}
 
 			// Select channel
 			self.out_8(6, disk << 4);
 			// Set DMA enable
-			self.out_8(1, 0x01);
+			self.out_8(1, if has_data { 0x01 } else { 0x00 });
 			// Max byte count
 			self.out_8(4, (dma_buffer.len() >> 0) as u8);
 			self.out_8(5, (dma_buffer.len() >> 8) as u8);
@@ -597,6 +630,42 @@ impl AtaController
 
 		Ok( dst.len() / 512 )
 	}
+	async fn do_dsm(&self, ranges: DMABuffer<'_>, disk: u8, dma_regs: DmaRegBorrow<'_>) -> Result<(),storage::IoError>
+	{
+		let mut lh = self.regs.async_lock().await;
+		lh.start_dma_cmd( disk, HDD_DSM, HDD_DSM_TRIM, 0, true, &ranges, true, &dma_regs );
+		self.interrupt.handle.get_event().wait().await;
+
+		// SAFE: Holding the register lock
+		unsafe {
+			dma_regs.out_8(0, 0);	// Stop transfer
+			log_trace!("DSM: BM Status = {:?}, ATA Status = {:?}", DmaStatusVal(dma_regs.in_8(2)), AtaStatusVal(lh.in_8(7)));
+		}
+		lh.last_result(false)
+	}
+	/// Issue a command that has no data stage (e.g. FLUSH CACHE)
+	async fn do_non_data(&self, cmd: u8, disk: u8) -> Result<(),storage::IoError>
+	{
+		let mut lh = self.regs.async_lock().await;
+		log_debug!("do_non_data(cmd={:#x},disk={})", cmd, disk);
+		// SAFE: Holding the register lock, valid IO accesses
+		unsafe {
+			lh.out_8(6, 0xE0 | (disk << 4));
+			lh.out_8(7, cmd);
+		}
+		loop
+		{
+			self.interrupt.handle.get_event().wait().await;
+			// Flushing can take a while, ensure that the command is actually complete
+			if lh.in_sts() & AtaStatusVal::BSY != 0 {
+				continue ;
+			}
+			break ;
+		}
+		// SAFE: Holding the register lock (reading status clears the interrupt)
+		let _ = unsafe { lh.in_8(7) };
+		lh.last_result(false)
+	}
 	fn do_atapi<'a>(&'a self, disk: u8, dma_regs: DmaRegBorrow<'a>, cmd: &[u8], dst: DMABuffer<'a>, is_write: bool)
 		-> impl ::core::future::Future<Output=Result<(), storage::IoError>> + 'a
 	{
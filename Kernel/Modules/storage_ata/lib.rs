@@ -29,6 +29,10 @@ struct AtaVolume
 	controller: Arc<io::DmaController>,
 	
 	size: u64,
+	/// Command used to flush the write cache (if supported)
+	flush_cmd: Option<u8>,
+	/// Maximum number of blocks of ranges per TRIM (zero if TRIM isn't supported)
+	trim_max_blocks: u16,
 }
 
 struct AtapiVolume
@@ -76,16 +80,39 @@ pub struct AtaIdentifyData
 	pub size_of_rw_multiple: u16,
 	/// LBA 28 sector count (if zero, use 48)
 	pub sector_count_28: u32,
-	_unused6: [u16; 100-62],
+	_unused6: [u16; 82-62],
+	/// Supported command sets (words 82-84)
+	pub command_sets_supported: [u16; 3],
+	_unused6b: [u16; 100-85],
 	/// LBA 48 sector count
 	pub sector_count_48: u64,
-	_unused7: [u16; 2],
+	_unused7: u16,
+	/// Maximum number of 512 byte blocks of LBA ranges per DATA SET MANAGEMENT command (0 = not reported)
+	pub max_dsm_blocks: u16,
 	/// [0:3] Physical sector size (in logical sectors
 	pub physical_sector_size: u16,
 	_unused8: [u16; 9],
 	/// Number of words per logical sector
 	pub words_per_logical_sector: u32,
-	_unusedz: [u16; 256-119],
+	_unused9: [u16; 169-118],
+	/// DATA SET MANAGEMENT support, [0]: TRIM supported
+	pub data_set_management: u16,
+	_unusedz: [u16; 256-170],
+}
+impl AtaIdentifyData
+{
+	/// FLUSH CACHE supported (word 83 bit 12)
+	pub fn supports_flush(&self) -> bool {
+		self.command_sets_supported[1] & (1 << 12) != 0
+	}
+	/// FLUSH CACHE EXT supported (word 83 bit 13)
+	pub fn supports_flush_ext(&self) -> bool {
+		self.command_sets_supported[1] & (1 << 13) != 0
+	}
+	/// DATA SET MANAGEMENT with the TRIM bit supported
+	pub fn supports_trim(&self) -> bool {
+		self.data_set_management & 1 != 0
+	}
 }
 impl Default for AtaIdentifyData {
 	fn default() -> AtaIdentifyData {
@@ -108,6 +135,8 @@ impl ::core::fmt::Debug for AtaIdentifyData {
 		write!(f, " sector_count_28: {:#x}", self.sector_count_28)?;
 		write!(f, " sector_count_48: {:#x}", self.sector_count_48)?;
 		write!(f, " words_per_logical_sector: {}", self.words_per_logical_sector)?;
+		write!(f, " command_sets_supported: [{:#x},{:#x},{:#x}]", self.command_sets_supported[0], self.command_sets_supported[1], self.command_sets_supported[2])?;
+		write!(f, " data_set_management: {:#x}", self.data_set_management)?;
 		write!(f, "}}")?;
 		Ok( () )
 	}
@@ -120,13 +149,15 @@ fn init()
 
 impl AtaVolume
 {
-	fn new_boxed(dma_controller: Arc<io::DmaController>, disk: u8, sectors: u64) -> Box<AtaVolume>
+	fn new_boxed(dma_controller: Arc<io::DmaController>, disk: u8, sectors: u64, ident: &AtaIdentifyData) -> Box<AtaVolume>
 	{
 		Box::new( AtaVolume {
 			name: format!("{}-{}", dma_controller.name, disk),
 			disk: disk,
 			controller: dma_controller,
 			size: sectors,
+			flush_cmd: volume::flush_command(ident),
+			trim_max_blocks: volume::trim_max_blocks(ident),
 			} )
 	}
 }
@@ -149,10 +180,32 @@ impl ::kernel::metadevs::storage::PhysicalVolume for AtaVolume
 		ctrlr.do_dma_wr(idx, num, src, self.disk)
 	}
 	
-	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()>
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> storage::AsyncIoResult<'a,()>
+	{
+		Box::pin(async move {
+			if self.trim_max_blocks == 0 {
+				// Do nothing, no support for TRIM
+				return Ok( () );
+			}
+			let mut buf = vec![0u8; self.trim_max_blocks as usize * io::SECTOR_SIZE];
+			let mut ofs = 0;
+			while ofs < count
+			{
+				let (n, nblocks) = volume::fill_trim_block(&mut buf, blockidx + ofs as u64, count - ofs);
+				self.controller.do_trim(&buf[..nblocks * io::SECTOR_SIZE], self.disk).await?;
+				ofs += n;
+			}
+			Ok( () )
+		})
+	}
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
 	{
-		// Do nothing, no support for TRIM
-		Box::pin(async move { Ok(()) })
+		match self.flush_cmd
+		{
+		Some(cmd) => self.controller.do_flush(cmd, self.disk),
+		// No support for flushing, assume that there's no write-back cache
+		None => Box::pin(async move { Ok(()) }),
+		}
 	}
 	
 }
@@ -254,7 +307,7 @@ impl ControllerRoot
 				AtaClass::Native => {
 					let sectors = if ident.sector_count_48 == 0 { ident.sector_count_28 as u64 } else { ident.sector_count_48 };
 					log_log!("ATA{}: Hard Disk, {} sectors, {}", disk, sectors, storage::SizePrinter(sectors * io::SECTOR_SIZE as u64));
-					volumes.push( storage::register_pv( AtaVolume::new_boxed(dma_controller.clone(), disk, sectors) ) );
+					volumes.push( storage::register_pv( AtaVolume::new_boxed(dma_controller.clone(), disk, sectors, ident) ) );
 					},
 				AtaClass::ATAPI => {
 					log_log!("ATA{}: ATAPI", disk);
@@ -26,6 +26,18 @@ const ATA_READ_DMA: u8 = 0xC8;
 const ATA_WRITE_DMA: u8 = 0xCA;
 const ATA_READ_DMA_EXT: u8 = 0x25;
 const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE: u8 = 0xE7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_DATA_SET_MANAGEMENT: u8 = 0x06;
+/// DATA SET MANAGEMENT feature: TRIM
+const ATA_DSM_TRIM: u16 = 0x0001;
+
+/// Maximum sectors in a single DSM range entry
+const DSM_RANGE_MAX: usize = 0xFFFF;
+/// Number of range entries in a 512 byte DSM block
+const DSM_RANGES_PER_BLOCK: usize = 512 / 8;
+/// Limit on the DSM blocks sent in one TRIM (devices can report up to 65535, but 8 blocks already cover 16GiB of sectors)
+const DSM_BLOCKS_MAX: u16 = 8;
 
 pub trait Interface: 'static + Send
 {
@@ -34,6 +46,10 @@ pub trait Interface: 'static + Send
 	fn ata_identify(&self) -> Result<super::AtaIdentifyData, Error>;
 	fn dma_lba_28(&self, cmd: u8, count: u8 , addr: u32, data: DataPtr) -> Result<usize,Error>;
 	fn dma_lba_48(&self, cmd: u8, count: u16, addr: u64, data: DataPtr) -> Result<usize,Error>;
+	/// LBA48 DMA command with a features value (e.g. DATA SET MANAGEMENT)
+	fn dma_lba_48_features(&self, cmd: u8, features: u16, count: u16, addr: u64, data: DataPtr) -> Result<usize,Error>;
+	/// Command with no data transfer (e.g. FLUSH CACHE)
+	fn non_data(&self, cmd: u8) -> Result<(),Error>;
 }
 
 pub struct AtaVolume<I: Interface>
@@ -41,6 +57,60 @@ pub struct AtaVolume<I: Interface>
 	int: I,
 	block_size: u32,
 	block_count: u64,
+	/// Command used to flush the write cache (if supported)
+	flush_cmd: Option<u8>,
+	/// Maximum number of 512 byte blocks of ranges per TRIM (zero if TRIM isn't supported)
+	trim_max_blocks: u16,
+}
+
+/// Select the cache flush command supported by a device
+pub fn flush_command(ident: &super::AtaIdentifyData) -> Option<u8>
+{
+	if ident.supports_flush_ext() {
+		Some(ATA_FLUSH_CACHE_EXT)
+	}
+	else if ident.supports_flush() {
+		Some(ATA_FLUSH_CACHE)
+	}
+	else {
+		None
+	}
+}
+/// Maximum number of 512 byte range blocks per TRIM, zero if TRIM isn't supported
+pub fn trim_max_blocks(ident: &super::AtaIdentifyData) -> u16
+{
+	if !ident.supports_trim() {
+		0
+	}
+	else if ident.max_dsm_blocks == 0 {
+		// Not reported, one block is always allowed
+		1
+	}
+	else {
+		// Capped, as the range buffer is allocated for each wipe
+		ident.max_dsm_blocks.min(DSM_BLOCKS_MAX)
+	}
+}
+/// Fill DATA SET MANAGEMENT blocks with LBA ranges
+///
+/// Returns the number of sectors covered, and the number of 512 byte blocks used.
+pub fn fill_trim_block(buf: &mut [u8], mut blockidx: u64, count: usize) -> (usize, usize)
+{
+	let mut rem = count;
+	let mut n_ents = 0;
+	for ent in buf.chunks_mut(8)
+	{
+		let n = ::core::cmp::min(rem, DSM_RANGE_MAX);
+		// Entry: [0:47] LBA, [48:63] count (zero for unused)
+		let v = if n == 0 { 0 } else { (blockidx & 0xFFFF_FFFF_FFFF) | ((n as u64) << 48) };
+		ent.copy_from_slice(&v.to_le_bytes());
+		if n > 0 {
+			n_ents += 1;
+		}
+		blockidx += n as u64;
+		rem -= n;
+	}
+	(count - rem, ::kernel::lib::num::div_up(n_ents, DSM_RANGES_PER_BLOCK))
 }
 
 impl<I: Interface> AtaVolume<I>
@@ -54,10 +124,16 @@ impl<I: Interface> AtaVolume<I>
 		
 		log_log!("{}: Hard Disk, {} sectors of {}b each, {}", int.name(), block_count, block_size, storage::SizePrinter(block_count * block_size as u64));
 				
+		let flush_cmd = flush_command(&ident_data);
+		let trim_max_blocks = trim_max_blocks(&ident_data);
+		log_debug!("{}: flush_cmd={:?} trim_max_blocks={}", int.name(), flush_cmd, trim_max_blocks);
+				
 		Ok(Box::new(AtaVolume {
 			int: int,
 			block_size: block_size,
 			block_count: block_count,
+			flush_cmd: flush_cmd,
+			trim_max_blocks: trim_max_blocks,
 			}))
 	}
 }
@@ -102,10 +178,36 @@ impl<I: Interface + Send + 'static> storage::PhysicalVolume for AtaVolume<I>
 		Box::pin(async move { ret })
 	}
 	
-	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()>
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> storage::AsyncIoResult<'a,()>
 	{
-		// Do nothing, no support for TRIM
-		Box::pin(async move { Ok(()) })
+		if self.trim_max_blocks == 0 {
+			// Do nothing, no support for TRIM
+			return Box::pin(async move { Ok(()) });
+		}
+		// DSM ranges are in logical sectors
+		let mut buf = vec![0u8; self.trim_max_blocks as usize * 512];
+		let mut ret = Ok( () );
+		let mut ofs = 0;
+		while ofs < count
+		{
+			let (n, nblocks) = fill_trim_block(&mut buf, blockidx + ofs as u64, count - ofs);
+			if let Err(e) = self.int.dma_lba_48_features(ATA_DATA_SET_MANAGEMENT, ATA_DSM_TRIM, nblocks as u16, 0, DataPtr::Send(&buf[..nblocks * 512])) {
+				ret = Err(e.into());
+				break ;
+			}
+			ofs += n;
+		}
+		Box::pin(async move { ret })
+	}
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		let ret = match self.flush_cmd
+			{
+			Some(cmd) => self.int.non_data(cmd).map_err(|e| e.into()),
+			// No support for flushing, assume that there's no write-back cache
+			None => Ok( () ),
+			};
+		Box::pin(async move { ret })
 	}
 	
 }
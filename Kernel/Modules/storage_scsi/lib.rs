@@ -9,6 +9,7 @@
 use kernel::prelude::*;
 
 use kernel::metadevs::storage;
+use core::sync::atomic::{AtomicBool,Ordering};
 
 pub mod proto;
 
@@ -34,6 +35,8 @@ pub struct Volume<I: ScsiInterface>
 	class: VolumeClass,
 	// block size, number of blocks
 	size: Option< (usize, u64) >,
+	/// Cleared if the device rejects UNMAP
+	unmap_supported: AtomicBool,
 }
 
 impl<I: ScsiInterface> Volume<I>
@@ -101,6 +104,7 @@ impl<I: ScsiInterface> Volume<I>
 			int: int,
 			class: class,
 			size: size,
+			unmap_supported: AtomicBool::new(true),
 			} ))
 	}
 }
@@ -156,9 +160,47 @@ impl<I: ScsiInterface> storage::PhysicalVolume for Volume<I>
 		})
 	}
 	
-	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()>
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> storage::AsyncIoResult<'a,()>
 	{
-		todo!("Volume::wipe");
+		Box::pin(async move {
+			match self.class
+			{
+			VolumeClass::DirectAccessBlock => {},
+			_ => return Ok( () ),
+			}
+			
+			let mut ofs = 0;
+			while ofs < count && self.unmap_supported.load(Ordering::Relaxed)
+			{
+				let n = ::core::cmp::min(count - ofs, u32::max_value() as usize);
+				let params = proto::UnmapParams::new(blockidx + ofs as u64, n as u32);
+				match self.int.send(proto::Unmap::new(params.len() as u16).as_ref(), params.as_ref()).await
+				{
+				Ok( () ) => {},
+				Err(e) => {
+					// Discarding is only advisory, so just stop trying
+					log_notice!("{}: UNMAP failed ({:?}), disabling discard", self.int.name(), e);
+					self.unmap_supported.store(false, Ordering::Relaxed);
+					},
+				}
+				ofs += n;
+			}
+			Ok( () )
+		})
+	}
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		Box::pin(async move {
+			match self.class
+			{
+			VolumeClass::DirectAccessBlock => {
+				log_trace!("SCSI SynchronizeCache10");
+				self.int.send(proto::SynchronizeCache10::new().as_ref(), &[]).await
+				},
+			// Read-only (or unwritable) devices have nothing to flush
+			_ => Ok( () ),
+			}
+		})
 	}
 	
 }
@@ -210,3 +210,36 @@ impl GetConfiguration
 	}
 }
 
+def_cmd!{ SynchronizeCache10[10] 0x35,
+	() => [
+		0,	// 1: flags (IMMED)
+		0,0,0,0,	// LBA (zero, with count zero = whole device)
+		0,	// 6: group number
+		0,0,	// count
+		0	// 9: control
+	] }
+
+def_cmd!{ Unmap[10] 0x42,
+	(param_len: u16) => [
+		0,	// 1: anchor
+		0,0,0,0,	// reserved
+		0,	// 6: group number
+		((param_len >> 8) & 0xFF) as u8,
+		((param_len >> 0) & 0xFF) as u8,
+		0	// 9: control
+	] }
+/// UNMAP parameter list, with a single block descriptor
+pub struct UnmapParams([u8; 8+16]);
+impl AsRef<[u8]> for UnmapParams { fn as_ref(&self) -> &[u8] { &self.0 } }
+impl UnmapParams
+{
+	pub fn new(lba: u64, count: u32) -> Self {
+		let mut rv = [0; 8+16];
+		BigEndian::write_u16(&mut rv[0..], (rv.len() - 2) as u16);	// Data length (excluding this field)
+		BigEndian::write_u16(&mut rv[2..], 16);	// Block descriptor data length
+		BigEndian::write_u64(&mut rv[8..], lba);
+		BigEndian::write_u32(&mut rv[16..], count);
+		UnmapParams(rv)
+	}
+	pub fn len(&self) -> usize { self.0.len() }
+}
@@ -285,24 +285,30 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			},
 		// === 4: Networking
 		NET_CONNECT => {
-			todo!("NET_CONNECT");
+			let remote: crate::values::SocketAddress = { let p: Freeze<_> = args.get()?; *p };
+			from_result(network_calls::new_client(remote))
 			},
 		NET_LISTEN => {
 			let local: crate::values::SocketAddress = { let p: Freeze<_> = args.get()?; *p };
-			match network_calls::new_server(local)
-			{
-			Ok(v) => v as u64,
-			Err(e) => e as u8 as u64,
-			}
+			from_result(network_calls::new_server(local))
 			},
 		NET_BIND => {
 			let local: crate::values::SocketAddress = { let p: Freeze<_> = args.get()?; *p };
 			let remote: crate::values::MaskedSocketAddress = { let p: Freeze<_> = args.get()?; *p };
-			match network_calls::new_free_socket(local, remote)
-			{
-			Ok(v) => v as u64,
-			Err(e) => e as u8 as u64,
-			}
+			from_result(network_calls::new_free_socket(local, remote))
+			},
+		NET_ROUTE_ADD => {
+			let route: crate::values::NetworkRoute = { let p: Freeze<_> = args.get()?; *p };
+			from_result(network_calls::route_add(route))
+			},
+		NET_ROUTE_DEL => {
+			let route: crate::values::NetworkRoute = { let p: Freeze<_> = args.get()?; *p };
+			from_result(network_calls::route_del(route))
+			},
+		NET_ROUTE_GET => {
+			let index: usize = args.get()?;
+			let mut route: FreezeMut<crate::values::NetworkRoute> = args.get()?;
+			from_result(network_calls::route_get(index, &mut route))
 			},
 		// === *: Default
 		_ => {
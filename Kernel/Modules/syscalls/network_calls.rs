@@ -5,32 +5,230 @@
 //! Userland interface to the network stack
 use crate::args::Args;
 use kernel::memory::freeze::{Freeze,FreezeMut};
+use kernel::sync::Mutex;
+use core::sync::atomic::{AtomicBool,Ordering};
+use crate::values::{SocketAddress,SocketAddressType,SocketPortType,SocketError,SocketShutdownSide};
+use crate::values::NetworkRoute;
 
 unsafe impl crate::args::Pod for crate::values::SocketAddress { }
 unsafe impl crate::args::Pod for crate::values::MaskedSocketAddress { }
+unsafe impl crate::args::Pod for crate::values::NetworkRoute { }
 
-pub fn new_server(local_address: crate::values::SocketAddress) -> Result<u32, crate::values::SocketError>
+impl ::core::convert::From<SocketError> for u32 {
+	fn from(v: SocketError) -> u32 {
+		let v: u8 = v.into();
+		v as u32
+	}
+}
+
+/// Convert a userland socket address into a network stack address
+fn get_address(addr: &SocketAddress) -> Result<::network::Address, SocketError>
+{
+	match SocketAddressType::try_from(addr.addr_ty)
+	{
+	Ok(SocketAddressType::Ipv4) => Ok( ::network::Address::Ipv4(::network::ipv4::Address([addr.addr[0], addr.addr[1], addr.addr[2], addr.addr[3]])) ),
+	Ok(SocketAddressType::Ipv6) => Ok( ::network::Address::Ipv6(::network::ipv6::Address(addr.addr)) ),
+	_ => Err(SocketError::InvalidValue),
+	}
+}
+/// Convert a network stack address (and port) into a userland socket address
+fn make_socket_address(port_ty: SocketPortType, addr: ::network::Address, port: u16) -> SocketAddress
+{
+	let mut rv = SocketAddress {
+		port_ty: port_ty as u8,
+		port: port,
+		..Default::default()
+		};
+	match addr
+	{
+	::network::Address::Ipv4(a) => {
+		rv.addr_ty = SocketAddressType::Ipv4 as u8;
+		rv.addr[..4].copy_from_slice(&a.0);
+		},
+	::network::Address::Ipv6(a) => {
+		rv.addr_ty = SocketAddressType::Ipv6 as u8;
+		rv.addr = a.0;
+		},
+	}
+	rv
+}
+fn conn_error(e: ::network::tcp::ConnError) -> SocketError
+{
+	use ::network::tcp::ConnError;
+	match e
+	{
+	ConnError::NoRoute => SocketError::NoRoute,
+	ConnError::LocalClosed => SocketError::ConnectionClosed,
+	ConnError::RemoteRefused => SocketError::ConnectionRefused,
+	ConnError::RemoteClosed => SocketError::ConnectionClosed,
+	ConnError::RemoteReset => SocketError::ConnectionReset,
+	ConnError::NoPortAvailable => SocketError::NoPortAvailable,
+	ConnError::TimedOut => SocketError::TimedOut,
+	}
+}
+
+fn icmp_error(e: ::network::icmp::ErrorKind) -> SocketError
+{
+	use ::network::icmp::ErrorKind;
+	match e
+	{
+	ErrorKind::ProtocolUnreachable
+	| ErrorKind::PortUnreachable => SocketError::ConnectionRefused,
+	_ => SocketError::NoRoute,
+	}
+}
+
+fn route_error(e: ::network::ipv4::routes::Error) -> SocketError
+{
+	use ::network::ipv4::routes::Error;
+	match e
+	{
+	Error::InvalidMask => SocketError::InvalidValue,
+	Error::UnknownInterface => SocketError::NoRoute,
+	Error::Exists => SocketError::AlreadyInUse,
+	Error::NotFound => SocketError::InvalidValue,
+	}
+}
+fn get_route_v4_addr(a: &[u8; 16]) -> ::network::ipv4::Address
+{
+	::network::ipv4::Address([a[0], a[1], a[2], a[3]])
+}
+
+pub fn route_add(route: NetworkRoute) -> Result<u32, SocketError>
+{
+	if !::vfs::Credentials::current().is_superuser() {
+		log_notice!("NET_ROUTE_ADD: Non-root process attempted to change the routing table");
+		return Err(SocketError::PermissionDenied);
+	}
+	match SocketAddressType::try_from(route.addr_ty)
+	{
+	Ok(SocketAddressType::Ipv4) => {
+		::network::ipv4::routes::add(::network::ipv4::routes::Route {
+			network: get_route_v4_addr(&route.network),
+			mask: route.mask,
+			gateway: get_route_v4_addr(&route.gateway),
+			source: get_route_v4_addr(&route.source),
+			metric: route.metric,
+			}).map_err(route_error)?;
+		Ok(0)
+		},
+	_ => Err(SocketError::InvalidValue),
+	}
+}
+pub fn route_del(route: NetworkRoute) -> Result<u32, SocketError>
 {
-	todo!("new_server({:?}", local_address);
+	if !::vfs::Credentials::current().is_superuser() {
+		log_notice!("NET_ROUTE_DEL: Non-root process attempted to change the routing table");
+		return Err(SocketError::PermissionDenied);
+	}
+	match SocketAddressType::try_from(route.addr_ty)
+	{
+	Ok(SocketAddressType::Ipv4) => {
+		::network::ipv4::routes::remove(get_route_v4_addr(&route.network), route.mask, get_route_v4_addr(&route.gateway)).map_err(route_error)?;
+		Ok(0)
+		},
+	_ => Err(SocketError::InvalidValue),
+	}
+}
+pub fn route_get(index: usize, dst: &mut NetworkRoute) -> Result<u32, SocketError>
+{
+	let r = ::network::ipv4::routes::get(index).ok_or(SocketError::NoData)?;
+	*dst = NetworkRoute {
+		addr_ty: SocketAddressType::Ipv4 as u8,
+		mask: r.mask,
+		metric: r.metric,
+		..Default::default()
+		};
+	dst.network[..4].copy_from_slice(&r.network.0);
+	dst.gateway[..4].copy_from_slice(&r.gateway.0);
+	dst.source[..4].copy_from_slice(&r.source.0);
+	Ok(0)
+}
+
+pub fn new_client(remote_address: SocketAddress) -> Result<u32, SocketError>
+{
+	match SocketPortType::try_from(remote_address.port_ty)
+	{
+	Ok(SocketPortType::Tcp) => {
+		let addr = get_address(&remote_address)?;
+		let conn = ::network::tcp::ConnectionHandle::connect(addr, remote_address.port).map_err(conn_error)?;
+		Ok( crate::objects::new_object(ConnSocket::new(conn)) )
+		},
+	_ => Err(SocketError::InvalidValue),
+	}
+}
+
+pub fn new_server(local_address: SocketAddress) -> Result<u32, SocketError>
+{
+	match SocketPortType::try_from(local_address.port_ty)
+	{
+	Ok(SocketPortType::Tcp) => {
+		// An all-zero address listens on every local address
+		let addr = if local_address.addr.iter().all(|&v| v == 0) {
+				None
+			}
+			else {
+				Some( get_address(&local_address)? )
+			};
+		// TODO: Check that the current process is allowed to listen on this port
+		let server = ::network::tcp::ServerHandle::listen_on(addr, local_address.port)
+			.map_err(|e| match e
+				{
+				::network::tcp::ListenError::SocketInUse => SocketError::AlreadyInUse,
+				})?;
+		Ok( crate::objects::new_object(ConnServer { inner: Mutex::new(server) }) )
+		},
+	_ => Err(SocketError::InvalidValue),
+	}
 }
 
-pub fn new_free_socket(local_address: crate::values::SocketAddress, remote_mask: crate::values::MaskedSocketAddress) -> Result<u32, crate::values::SocketError>
+pub fn new_free_socket(local_address: SocketAddress, remote_mask: crate::values::MaskedSocketAddress) -> Result<u32, SocketError>
 {
 	if local_address.port_ty != remote_mask.addr.port_ty {
-		return Err(crate::values::SocketError::InvalidValue);
+		return Err(SocketError::InvalidValue);
 	}
 	if local_address.addr_ty != remote_mask.addr.addr_ty {
-		return Err(crate::values::SocketError::InvalidValue);
+		return Err(SocketError::InvalidValue);
 	}
 	// TODO: Check that the current process is allowed to use the specified combination of port/type
-	//crate::objects::new_object(ConnSocket {
-	//	inner: ::network::tcp::ConnectionHandle::connect(remote_mask, remote_mask)?,
-	//	});
-	todo!("new_free_socket");
+	match SocketPortType::try_from(local_address.port_ty)
+	{
+	Ok(SocketPortType::Udp) => {
+		let local_addr = if local_address.addr.iter().all(|&v| v == 0) {
+				None
+			}
+			else {
+				Some( get_address(&local_address)? )
+			};
+		let remote_addr = get_address(&remote_mask.addr)?;
+		let max_mask_bits = match remote_addr
+			{
+			::network::Address::Ipv4(_) => 32,
+			::network::Address::Ipv6(_) => 128,
+			};
+		if remote_mask.mask > max_mask_bits {
+			return Err(SocketError::InvalidValue);
+		}
+		let mask = ::network::udp::RemoteMask {
+			addr: remote_addr,
+			mask_bits: remote_mask.mask,
+			port: remote_mask.addr.port,
+			};
+		let sock = ::network::udp::SocketHandle::bind(local_addr, local_address.port, mask)
+			.map_err(|e| match e
+				{
+				::network::udp::BindError::AddressInUse => SocketError::AlreadyInUse,
+				::network::udp::BindError::NoPortAvailable => SocketError::NoPortAvailable,
+				})?;
+		Ok( crate::objects::new_object(FreeSocket { inner: sock }) )
+		},
+	_ => Err(SocketError::InvalidValue),
+	}
 }
 
 struct ConnServer
 {
+	inner: Mutex<::network::tcp::ServerHandle>,
 }
 impl crate::objects::Object for ConnServer
 {
@@ -44,8 +242,16 @@ impl crate::objects::Object for ConnServer
 		match call
 		{
 		crate::values::NET_SERVER_ACCEPT => {
-			let addr_ptr: FreezeMut<crate::values::SocketAddress> = args.get()?;
-			todo!("NET_SERVER_ACCEPT({:p})", &*addr_ptr);
+			let mut addr_ptr: FreezeMut<SocketAddress> = args.get()?;
+			Ok(match self.inner.lock().accept()
+			{
+			Some(conn) => {
+				let (addr, port) = conn.remote_addr();
+				*addr_ptr = make_socket_address(SocketPortType::Tcp, addr, port);
+				crate::objects::new_object(ConnSocket::new(conn)) as u64
+				},
+			None => crate::from_result::<u32,_>(Err(SocketError::NoData)),
+			})
 			},
 		_ => crate::objects::object_has_no_such_method_ref("network_calls::ConnServer", call),
 		}
@@ -55,17 +261,40 @@ impl crate::objects::Object for ConnServer
 		let _ = unsafe { ::core::ptr::read(self) };
 		crate::objects::object_has_no_such_method_val("network_calls::ConnServer", call)
 	}
-	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_SERVER_ACCEPT != 0 {
+			self.inner.lock().bind_wait_accept(obj);
+			ret += 1;
+		}
+		ret
 	}
-	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_SERVER_ACCEPT != 0 {
+			if self.inner.lock().clear_wait_accept(obj) {
+				ret |= crate::values::EV_NET_SERVER_ACCEPT;
+			}
+		}
+		ret
 	}
 }
 
 struct ConnSocket
 {
-	_inner: ::network::tcp::ConnectionHandle,
+	inner: ::network::tcp::ConnectionHandle,
+	/// Set once the user has requested that the receive side be shut down
+	rx_shutdown: AtomicBool,
+}
+impl ConnSocket
+{
+	fn new(inner: ::network::tcp::ConnectionHandle) -> ConnSocket
+	{
+		ConnSocket {
+			inner: inner,
+			rx_shutdown: AtomicBool::new(false),
+		}
+	}
 }
 impl crate::objects::Object for ConnSocket
 {
@@ -79,16 +308,37 @@ impl crate::objects::Object for ConnSocket
 		match call
 		{
 		crate::values::NET_CONNSOCK_SHUTDOWN => {
-			let what = crate::values::SocketShutdownSide::try_from(args.get::<u8>()?).map_err(|_| crate::Error::BadValue)?;
-			todo!("NET_CONNSOCK_SHUTDOWN({:?})", what);
+			let what = SocketShutdownSide::try_from(args.get::<u8>()?).map_err(|_| crate::Error::BadValue)?;
+			log_trace!("NET_CONNSOCK_SHUTDOWN({:?})", what);
+			Ok(match what
+			{
+			SocketShutdownSide::Transmit => crate::from_result(self.inner.close().map(|_| 0u32).map_err(conn_error)),
+			SocketShutdownSide::Receive => {
+				self.rx_shutdown.store(true, Ordering::SeqCst);
+				0
+				},
+			})
 			},
 		crate::values::NET_CONNSOCK_SEND => {
 			let data: Freeze<[u8]> = args.get()?;
-			todo!("NET_CONNSOCK_SEND({:p})", &*data);
+			Ok(crate::from_result(self.inner.send_data(&data)
+				.map(|len| len as u32)
+				.map_err(conn_error)
+				))
 			},
 		crate::values::NET_CONNSOCK_RECV => {
-			let data: FreezeMut<[u8]> = args.get()?;
-			todo!("NET_CONNSOCK_RECV({:p})", &*data);
+			let mut data: FreezeMut<[u8]> = args.get()?;
+			if self.rx_shutdown.load(Ordering::SeqCst) {
+				return Ok(crate::from_result::<u32,_>(Err(SocketError::ConnectionClosed)));
+			}
+			Ok(crate::from_result(match self.inner.recv_data(&mut data)
+				{
+				Ok(0) => Err(SocketError::NoData),
+				Ok(len) => Ok(len as u32),
+				// A clean close by the remote is reported as end-of-stream
+				Err(::network::tcp::ConnError::RemoteClosed) => Ok(0),
+				Err(e) => Err(conn_error(e)),
+				}))
 			},
 		_ => crate::objects::object_has_no_such_method_ref("network_calls::ConnSocket", call),
 		}
@@ -98,16 +348,28 @@ impl crate::objects::Object for ConnSocket
 		let _ = unsafe { ::core::ptr::read(self) };
 		crate::objects::object_has_no_such_method_val("network_calls::ConnSocket", call)
 	}
-	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_CONNSOCK_RECV != 0 {
+			self.inner.bind_wait_recv(obj);
+			ret += 1;
+		}
+		ret
 	}
-	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_CONNSOCK_RECV != 0 {
+			if self.inner.clear_wait_recv(obj) {
+				ret |= crate::values::EV_NET_CONNSOCK_RECV;
+			}
+		}
+		ret
 	}
 }
 
 struct FreeSocket
 {
+	inner: ::network::udp::SocketHandle,
 }
 
 impl crate::objects::Object for FreeSocket
@@ -123,11 +385,38 @@ impl crate::objects::Object for FreeSocket
 		{
 		crate::values::NET_FREESOCK_SEND => {
 			let data: Freeze<[u8]> = args.get()?;
-			todo!("NET_FREESOCK_SEND({:p})", &*data);
+			let remote: Freeze<SocketAddress> = args.get()?;
+			match SocketPortType::try_from(remote.port_ty)
+			{
+			Ok(SocketPortType::Udp) => {},
+			_ => return Ok(crate::from_result::<u32,_>(Err(SocketError::InvalidValue))),
+			}
+			let addr = match get_address(&remote)
+				{
+				Ok(v) => v,
+				Err(e) => return Ok(crate::from_result::<u32,_>(Err(e))),
+				};
+			Ok(crate::from_result(self.inner.send_to(addr, remote.port, &data)
+				.map(|len| len as u32)
+				.map_err(|e| match e
+					{
+					::network::udp::SendError::NoRoute => SocketError::NoRoute,
+					::network::udp::SendError::TooLarge => SocketError::TooLarge,
+					})
+				))
 			},
 		crate::values::NET_FREESOCK_RECV => {
-			let data: FreezeMut<[u8]> = args.get()?;
-			todo!("NET_FREESOCK_RECV({:p})", &*data);
+			let mut data: FreezeMut<[u8]> = args.get()?;
+			let mut addr_ptr: FreezeMut<SocketAddress> = args.get()?;
+			Ok(match self.inner.recv_from(&mut data)
+			{
+			Ok((len, addr, port)) => {
+				*addr_ptr = make_socket_address(SocketPortType::Udp, addr, port);
+				len as u64
+				},
+			Err(::network::udp::RecvError::NoData) => crate::from_result::<u32,_>(Err(SocketError::NoData)),
+			Err(::network::udp::RecvError::Icmp(e)) => crate::from_result::<u32,_>(Err(icmp_error(e))),
+			})
 			},
 		_ => crate::objects::object_has_no_such_method_ref("network_calls::FreeSocket", call),
 		}
@@ -137,11 +426,21 @@ impl crate::objects::Object for FreeSocket
 		let _ = unsafe { ::core::ptr::read(self) };
 		crate::objects::object_has_no_such_method_val("network_calls::FreeSocket", call)
 	}
-	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_FREESOCK_RECV != 0 {
+			self.inner.bind_wait_recv(obj);
+			ret += 1;
+		}
+		ret
 	}
-	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_FREESOCK_RECV != 0 {
+			if self.inner.clear_wait_recv(obj) {
+				ret |= crate::values::EV_NET_FREESOCK_RECV;
+			}
+		}
+		ret
 	}
 }
-
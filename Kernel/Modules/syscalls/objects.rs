@@ -339,6 +339,19 @@ pub fn clear_wait(handle: u32, mask: u32, sleeper: &mut ::kernel::threads::Sleep
 		})
 }
 
+/// Borrow another of this process's objects (e.g. one passed as a syscall argument)
+///
+/// Returns `Error::BadValue` if the object isn't of type `T`
+pub fn with_object_ref<T: Object+'static, O>(handle: u32, fcn: impl FnOnce(&T)->Result<O,super::Error>) -> Result<O,super::Error> {
+	get_process_local::<ProcessObjects>().with_object(handle, |obj| {
+		match obj.as_any().downcast_ref::<T>()
+		{
+		Some(v) => fcn(v),
+		None => Err( super::Error::BadValue ),
+		}
+		})
+}
+
 /// Give the target process the object specified by `handle`
 pub fn give_object(target: &::kernel::threads::ProcessHandle, tag: &str, handle: u32) -> Result<(),super::Error> {
 	log_trace!("give_object(target={:?}, handle={:?})", target, handle);
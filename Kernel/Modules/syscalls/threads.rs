@@ -60,6 +60,9 @@ pub fn newthread(sp: usize, ip: usize) -> ObjectHandle {
 pub fn newprocess(name: &str,  clone_start: usize, clone_end: usize) -> ObjectHandle {
 	// 1. Create a new process image (virtual address space)
 	let process = ::kernel::threads::ProcessHandle::new(name, clone_start, clone_end);
+	// 2. Inherit the current identity (can be changed with CORE_PROTOPROCESS_SETCREDS before starting)
+	let creds = ::vfs::Credentials::current();
+	::vfs::Credentials::for_process(&process).set(creds.uid(), creds.gid());
 	
 	crate::objects::new_object( ProtoProcess(process) )
 }
@@ -115,6 +118,19 @@ impl crate::objects::Object for ProtoProcess
 			let handle: u32 = args.get()?;
 			crate::objects::give_object(&self.0, &tag, handle).map(|_| 0)
 			}
+		// Set the identity the process runs as
+		values::CORE_PROTOPROCESS_SETCREDS => {
+			let uid: u32 = args.get()?;
+			let gid: u32 = args.get()?;
+			Ok(super::from_result::<u32,_>(if ::vfs::Credentials::current().is_superuser() {
+				::vfs::Credentials::for_process(&self.0).set(uid, gid);
+				Ok(0)
+			}
+			else {
+				log_notice!("CORE_PROTOPROCESS_SETCREDS: Non-root process attempted to set credentials to {}:{}", uid, gid);
+				Err(values::VFSError::PermissionDenied)
+			}))
+			},
 		_ => crate::objects::object_has_no_such_method_ref("threads::ProtoProcess", call),
 		}
 	}
@@ -14,6 +14,8 @@ use ::vfs::handle;
 use ::vfs::node_cache::NodeClass;
 use ::vfs::Path;
 
+unsafe impl crate::args::Pod for values::VFSNodeInfo { }
+
 
 macro_rules! map_enums {
 	( ($a:ident, $b:ident) match ($v:expr) { $( ($l:ident $($extra:tt)*), )* } ) => {
@@ -38,8 +40,25 @@ impl_from! {
 		Error::PermissionDenied => VFSError::PermissionDenied,
 		Error::Locked => VFSError::FileLocked,
 		Error::MalformedPath => VFSError::MalformedPath,
-		Error::Unknown(reason) => todo!("VFS Error Unknown - '{}'", reason),
-		_ => todo!("VFS Error - {:?}", v),
+		Error::AlreadyExists => VFSError::AlreadyExists,
+		Error::DirectoryNotEmpty => VFSError::DirectoryNotEmpty,
+		Error::CrossFilesystem => VFSError::CrossFilesystem,
+		Error::ReadOnlyFilesystem => VFSError::ReadOnlyFilesystem,
+		Error::InvalidParameter => VFSError::InvalidParameter,
+		Error::NonDirComponent => VFSError::NonDirComponent,
+		Error::RecursionDepthExceeded => VFSError::RecursionDepthExceeded,
+		Error::BlockIoError(e) => {
+			log_notice!("VFS IO Error - {:?}", e);
+			VFSError::IoError
+			},
+		Error::InconsistentFilesystem => VFSError::InconsistentFilesystem,
+		Error::OutOfSpace => VFSError::OutOfSpace,
+		Error::OutOfMemory => VFSError::OutOfMemory,
+		Error::TransientError => VFSError::TransientError,
+		Error::Unknown(reason) => {
+			log_notice!("VFS Error Unknown - '{}'", reason);
+			VFSError::Unknown
+			},
 		}
 	}}
 	From<NodeClass>(v) for values::VFSNodeType {
@@ -101,7 +120,7 @@ impl objects::Object for Node
 	fn try_clone(&self) -> Option<u32> {
 		Some( objects::new_object( Node(self.0.clone()) ) )
 	}
-	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_NODE_GETTYPE => {
@@ -109,6 +128,26 @@ impl objects::Object for Node
 			let v32: u32 = values::VFSNodeType::from( self.0.get_class() ).into();
 			Ok( v32 as u64 )
 			},
+		values::VFS_NODE_GETINFO => {
+			let mut info: FreezeMut<values::VFSNodeInfo> = args.get()?;
+			log_debug!("VFS_NODE_GETINFO()");
+			let class: u32 = values::VFSNodeType::from( self.0.get_class() ).into();
+			Ok( super::from_result(to_result(self.0.get_metadata()).map(|m| {
+				*info = values::VFSNodeInfo {
+					size: m.size,
+					created: m.created,
+					modified: m.modified,
+					accessed: m.accessed,
+					link_count: m.link_count,
+					owner: m.owner,
+					group: m.group,
+					mode: m.mode,
+					class: class as u8,
+					_pad: 0,
+					};
+				0u32
+				})) )
+			},
 		_ => objects::object_has_no_such_method_ref("vfs::Node", call),
 		}
 	}
@@ -282,6 +321,64 @@ impl objects::Object for Dir
 		values::VFS_DIR_ENUMERATE => {
 			objects::new_object( DirIter::new( self.handle.clone() ) ) as u64
 			},
+		values::VFS_DIR_MKDIR => {
+			let name: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			log_debug!("VFS_DIR_MKDIR({:?})", name);
+			super::from_result(
+				to_result( self.handle.mkdir(name) )
+					.map( |h| objects::new_object(Dir::new(h)) )
+				)
+			},
+		values::VFS_DIR_CREATEFILE => {
+			let name: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			log_debug!("VFS_DIR_CREATEFILE({:?})", name);
+			super::from_result(
+				to_result( self.handle.create_file(name) )
+					.map( |h| objects::new_object(File(h)) )
+				)
+			},
+		values::VFS_DIR_UNLINK => {
+			let name: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			log_debug!("VFS_DIR_UNLINK({:?})", name);
+			super::from_result( to_result( self.handle.unlink(name) ).map(|_| 0u32) )
+			},
+		values::VFS_DIR_SYMLINK => {
+			let name: Freeze<[u8]> = args.get()?;
+			let target: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			let target = Path::new(&target);
+			log_debug!("VFS_DIR_SYMLINK({:?}, {:?})", name, target);
+			super::from_result( to_result( self.handle.symlink(name, target) ).map(|_| 0u32) )
+			},
+		values::VFS_DIR_LINK => {
+			let name: Freeze<[u8]> = args.get()?;
+			let node: u32 = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			log_debug!("VFS_DIR_LINK({:?}, #{})", name, node);
+			objects::with_object_ref(node, |node: &Node| {
+				Ok( super::from_result( to_result( self.handle.link(name, &node.0) ).map(|_| 0u32) ) )
+				})?
+			},
+		values::VFS_DIR_RENAME => {
+			let old_name: Freeze<[u8]> = args.get()?;
+			let new_dir: u32 = args.get()?;
+			let new_name: Freeze<[u8]> = args.get()?;
+
+			let old_name = ::kernel::lib::byte_str::ByteStr::new(&*old_name);
+			let new_name = ::kernel::lib::byte_str::ByteStr::new(&*new_name);
+			log_debug!("VFS_DIR_RENAME({:?}, #{}, {:?})", old_name, new_dir, new_name);
+			objects::with_object_ref(new_dir, |new_dir: &Dir| {
+				Ok( super::from_result( to_result( self.handle.rename(old_name, &new_dir.handle, new_name) ).map(|_| 0u32) ) )
+				})?
+			},
 		_ => return crate::objects::object_has_no_such_method_ref("vfs::Dir", call),
 		})
 	}
@@ -163,7 +163,10 @@ impl ScsiInterfaceInner
 		let cbw_bytes = cbw.to_bytes();
 		self.ep_out.send(&cbw_bytes).await;
 		// Receive data (would be nice if this allowed multiple in-flight requests)
-		self.ep_in.recv(buf).await;
+		// - Non-data commands (e.g. SYNCHRONIZE CACHE) skip the data stage
+		if buf.len() > 0 {
+			self.ep_in.recv(buf).await;
+		}
 		// Receive CSW
 		let mut csw_bytes = [0; 12+1];
 		self.ep_in.recv(&mut csw_bytes).await;
@@ -199,8 +202,10 @@ impl ScsiInterfaceInner
 			};
 		let cbw_bytes = cbw.to_bytes();
 		self.ep_out.send(&cbw_bytes).await;
-		// Send data
-		self.ep_out.send(buf).await;
+		// Send data (skipped for non-data commands)
+		if buf.len() > 0 {
+			self.ep_out.send(buf).await;
+		}
 		// Receive CSW
 		let mut csw_bytes = [0; 12+1];
 		self.ep_in.recv(&mut csw_bytes).await;
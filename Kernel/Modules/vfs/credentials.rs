@@ -0,0 +1,122 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/credentials.rs
+//! Per-process identity used for access checks
+use ::kernel::lib::mem::aref::ArefBorrow;
+use ::core::sync::atomic::{AtomicU32,Ordering};
+use super::node::Metadata;
+
+/// User/group identity of a process
+///
+/// Stored as process-local data, defaults to root (uid/gid zero) so kernel-spawned processes
+/// have full access until a login process sets otherwise.
+#[derive(Default)]
+pub struct Credentials
+{
+	uid: AtomicU32,
+	gid: AtomicU32,
+}
+
+/// "Sticky" directory mode bit, restricts removing/renaming entries to their owners
+pub const S_ISVTX: u16 = 0o1000;
+
+/// Type of access requested from `check_access`
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Access
+{
+	Read,
+	Write,
+	Execute,
+}
+impl Access
+{
+	/// Permission bit for this access (in the "other" position)
+	fn bit(&self) -> u16 {
+		match *self
+		{
+		Access::Read    => 4,
+		Access::Write   => 2,
+		Access::Execute => 1,
+		}
+	}
+}
+
+impl Credentials
+{
+	/// Credentials of the current process
+	pub fn current() -> ArefBorrow<Credentials> {
+		::kernel::threads::get_process_local::<Credentials>()
+	}
+	/// Credentials of another process (e.g. one that hasn't been started yet)
+	pub fn for_process(process: &::kernel::threads::ProcessHandle) -> ArefBorrow<Credentials> {
+		process.get_process_local_alloc::<Credentials>()
+	}
+
+	pub fn uid(&self) -> u32 {
+		self.uid.load(Ordering::Relaxed)
+	}
+	pub fn gid(&self) -> u32 {
+		self.gid.load(Ordering::Relaxed)
+	}
+	/// Returns true if this identity bypasses mode checks
+	pub fn is_superuser(&self) -> bool {
+		self.uid() == 0
+	}
+
+	/// Change the identity (callers must check that this is allowed)
+	pub fn set(&self, uid: u32, gid: u32) {
+		self.uid.store(uid, Ordering::Relaxed);
+		self.gid.store(gid, Ordering::Relaxed);
+	}
+
+	/// Check the requested access against a node's owner and mode bits
+	pub fn check_access(&self, meta: &Metadata, access: Access) -> super::Result<()> {
+		let allowed = if self.is_superuser() {
+				// Root can read/write anything, but can only execute if there's an execute bit set
+				access != Access::Execute || meta.mode & 0o111 != 0
+			}
+			else {
+				let shift = if meta.owner == self.uid() {
+						6
+					}
+					else if meta.group == self.gid() {
+						3
+					}
+					else {
+						0
+					};
+				(meta.mode >> shift) & access.bit() != 0
+			};
+		if allowed {
+			Ok( () )
+		}
+		else {
+			log_debug!("check_access: {:?} denied for {}:{} (node {}:{} {:#o})",
+				access, self.uid(), self.gid(), meta.owner, meta.group, meta.mode);
+			Err(super::Error::PermissionDenied)
+		}
+	}
+	/// Check that an entry (`node`) can be removed from or renamed within a directory (`dir`)
+	///
+	/// If the directory has the sticky bit set, only the entry's owner, the directory's owner, or root can do so.
+	pub fn check_sticky(&self, dir: &Metadata, node: &Metadata) -> super::Result<()> {
+		if dir.mode & S_ISVTX == 0 || self.is_superuser() || node.owner == self.uid() || dir.owner == self.uid() {
+			Ok( () )
+		}
+		else {
+			log_debug!("check_sticky: {}:{} can't remove entry owned by {} (dir {}:{} {:#o})",
+				self.uid(), self.gid(), node.owner, dir.owner, dir.group, dir.mode);
+			Err(super::Error::PermissionDenied)
+		}
+	}
+}
+
+/// Check that the current process can perform `access` on a node with the given metadata
+pub fn check_access(meta: &Metadata, access: Access) -> super::Result<()> {
+	Credentials::current().check_access(meta, access)
+}
+/// Check that the current process can remove/rename `node` from a directory with metadata `dir`
+pub fn check_sticky(dir: &Metadata, node: &Metadata) -> super::Result<()> {
+	Credentials::current().check_sticky(dir, node)
+}
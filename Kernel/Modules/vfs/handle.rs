@@ -8,6 +8,7 @@ use ::kernel::prelude::*;
 use ::kernel::lib::byte_str::{ByteStr,ByteString};
 use ::kernel::PAGE_SIZE;
 use super::node::{NodeType};
+use super::credentials::Access;
 use super::node_cache::{CacheHandle};
 use super::Path;
 
@@ -95,6 +96,11 @@ impl Any
 	pub fn get_class(&self) -> super::node_cache::NodeClass {
 		self.node.get_class()
 	}
+
+	/// Get the node's metadata (size, times, permissions)
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		self.node.get_metadata()
+	}
 	
 	/// Upgrade the handle to a directory handle
 	pub fn into_dir(self) -> super::Result<Dir> {
@@ -133,16 +139,24 @@ impl File
 	}
 
 	fn from_node(node: super::node_cache::CacheHandleFile, mode: FileOpenMode) -> super::Result<File> {
+		// Check permissions before taking the lock (so a failed open doesn't need to unlock)
+		match mode
+		{
+		FileOpenMode::NoDataAccess => {},
+		FileOpenMode::SharedRO => node.check_access(Access::Read)?,
+		FileOpenMode::Execute => node.check_access(Access::Execute)?,
+		FileOpenMode::Append
+		| FileOpenMode::ExclRW
+		| FileOpenMode::Unsynch => node.check_access(Access::Write)?,
+		// NOTE: UniqueRW only needs read access (writes go to a private copy)
+		FileOpenMode::UniqueRW => node.check_access(Access::Read)?,
+		}
 		match mode
 		{
 		FileOpenMode::NoDataAccess => {},
-		// TODO: Check permissions (must be readable in current context)
 		FileOpenMode::SharedRO => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be writable in current context)
 		FileOpenMode::Append => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be executable in current context)
 		FileOpenMode::Execute => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be writable in current context)
 		FileOpenMode::ExclRW => { node.file_lock_exclusive()?; }
 		FileOpenMode::Unsynch => { node.file_lock_unsynch()?; }
 		FileOpenMode::UniqueRW => todo!("UniqueRW - CoW"),
@@ -153,8 +167,17 @@ impl File
 	pub fn size(&self) -> u64 {
 		self.node.get_valid_size()
 	}
-	pub fn truncate(&self) {
-		todo!("Handle::truncate()");
+	/// Discard the file's contents (setting the size to zero)
+	pub fn truncate(&self) -> super::Result<()> {
+		match self.mode
+		{
+		FileOpenMode::ExclRW
+		|FileOpenMode::Unsynch => {
+			self.node.truncate(0)?;
+			Ok( () )
+			},
+		_ => Err(super::Error::PermissionDenied),
+		}
 	}
 
 	/// Read data from the file at the specified offset
@@ -345,6 +368,18 @@ impl Dir
 		let node = self.node.create(name.as_ref(), NodeType::File)?;
 		File::from_node(node.into_file()?, FileOpenMode::ExclRW)
 	}
+	/// Create a new name (hard link) for an existing node on the same mount
+	pub fn link(&self, name: impl AsRef<ByteStr>, node: &Any) -> super::Result<()> {
+		self.node.link(name.as_ref(), &node.node)
+	}
+	/// Remove a name from this directory
+	pub fn unlink(&self, name: impl AsRef<ByteStr>) -> super::Result<()> {
+		self.node.unlink(name.as_ref())
+	}
+	/// Atomically move an entry to `new_dir` (which must be on the same mount)
+	pub fn rename(&self, old_name: impl AsRef<ByteStr>, new_dir: &Dir, new_name: impl AsRef<ByteStr>) -> super::Result<()> {
+		self.node.rename(old_name.as_ref(), &new_dir.node, new_name.as_ref())
+	}
 
 	/// Open a child of this node
 	pub fn open_child(&self, name: &ByteStr) -> super::Result<Any> {
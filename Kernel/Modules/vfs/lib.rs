@@ -27,6 +27,10 @@ pub enum Error
 	Locked,
 	/// The item already exists
 	AlreadyExists,
+	/// Directory still has entries (can't be removed)
+	DirectoryNotEmpty,
+	/// Operation would span two filesystems (e.g. renaming to another mount)
+	CrossFilesystem,
 
 	/// Path was malformed (too long, not absolute, not normalised, ... depends)
 	MalformedPath,
@@ -74,11 +78,13 @@ impl From<::kernel::metadevs::storage::IoError> for Error {
 //}
 
 pub use self::path::{Path,PathBuf};
+pub use self::credentials::Credentials;
 
 pub mod node;
 pub mod node_cache;
 pub mod mount;
 pub mod handle;
+pub mod credentials;
 mod path;
 mod ramfs;
 
@@ -98,6 +104,8 @@ fn init()
 		};
 	root.mkdir("system").unwrap();
 	root.mkdir("volumes").unwrap();
-	root.mkdir("temp").unwrap();
+	root.mkdir("tmp").unwrap();
+	// 4. Mount a tmpfs on /tmp (world-writable, so there's always somewhere to write even if booted from read-only media)
+	mount::mount("/tmp".as_ref(), VolumeHandle::new_ramdisk(0), "ramfs", &["mode=1777"]).expect("Unable to mount /tmp");
 }
 
@@ -9,6 +9,7 @@ use super::node::{InodeId,Node};
 use super::node_cache::{CacheHandle};
 use ::kernel::sync::RwLock;
 use ::kernel::lib::{LazyStatic,SparseVec,VecMap};
+use ::core::sync::atomic::{AtomicBool,Ordering};
 
 use ::kernel::metadevs::storage::VolumeHandle;
 
@@ -26,9 +27,54 @@ pub struct SelfHandle(usize);
 struct MountedVolume
 {
 	mountpoint_node: super::node_cache::CacheHandleDir,
+	inner: MountedFs,
+}
+/// A filesystem instance, along with VFS-level mount state
+struct MountedFs
+{
 	fs: Box<dyn Filesystem>,
+	/// Set when mounted `ro` (modifications are rejected by the VFS)
+	readonly: AtomicBool,
 }
 
+/// Options passed to `mount`/`remount`, parsed from a list of `key` or `key=value` strings
+#[derive(Debug,Default,Clone)]
+pub struct MountOptions
+{
+	/// `ro` (cleared by `rw`)
+	pub readonly: bool,
+	/// Options not handled by the VFS, left for the driver
+	pub extra: Vec<(String, Option<String>)>,
+}
+impl MountOptions
+{
+	pub fn parse(options: &[&str]) -> MountOptions {
+		let mut rv = MountOptions::default();
+		for opt in options
+		{
+			match *opt
+			{
+			"" => {},
+			"ro" => rv.readonly = true,
+			"rw" => rv.readonly = false,
+			_ => {
+				let mut it = opt.splitn(2, '=');
+				let key = it.next().unwrap();
+				rv.extra.push( (String::from(key), it.next().map(String::from)) );
+				},
+			}
+		}
+		rv
+	}
+	/// Returns `true` if the option `key` was passed (with or without a value)
+	pub fn has(&self, key: &str) -> bool {
+		self.extra.iter().any(|(k,_)| k == key)
+	}
+	/// Get the value of a `key=value` option
+	pub fn get(&self, key: &str) -> Option<&str> {
+		self.extra.iter().filter(|(k,_)| k == key).filter_map(|(_,v)| v.as_ref()).map(|v| &v[..]).last()
+	}
+}
 
 /// Filesystem instance trait (i.e. the instance)
 pub trait Filesystem:
@@ -36,12 +82,19 @@ pub trait Filesystem:
 {
 	fn root_inode(&self) -> InodeId;
 	fn get_node_by_inode(&self, _: InodeId) -> Option<Node>;
+
+	/// Write all dirty data/metadata to the volume (called before unmount)
+	fn flush(&self) -> super::Result<()>;
+	/// Apply new mount options to a mounted instance
+	fn remount(&self, options: &MountOptions) -> super::Result<()>;
 }
 
 struct NullFs;
 impl Filesystem for NullFs {
 	fn root_inode(&self) -> InodeId { 0 }
 	fn get_node_by_inode(&self, _: InodeId) -> Option<Node> { None }
+	fn flush(&self) -> super::Result<()> { Ok( () ) }
+	fn remount(&self, _: &MountOptions) -> super::Result<()> { Ok( () ) }
 }
 
 /// Filesystem instance trait
@@ -56,7 +109,7 @@ pub trait Driver:
 	/// Mount the provided volume as this filesystem
 	///
 	/// NOTE: `handle` isn't actually usable until after this function returns
-	fn mount(&self, vol: VolumeHandle, handle: SelfHandle) -> super::Result<Box<dyn Filesystem>>;
+	fn mount(&self, vol: VolumeHandle, handle: SelfHandle, options: &MountOptions) -> super::Result<Box<dyn Filesystem>>;
 }
 
 pub struct DriverRegistration(&'static str);
@@ -66,7 +119,7 @@ static S_DRIVERS: LazyStatic<RwLock< VecMap<&'static str, &'static dyn Driver> >
 /// Mounted volumes
 static S_VOLUMES: LazyStatic<RwLock< SparseVec<MountedVolume> >> = lazystatic_init!();
 /// Root mount
-static S_ROOT_VOLUME: RwLock<Option<Box<dyn Filesystem>>> = RwLock::new(None);
+static S_ROOT_VOLUME: RwLock<Option<MountedFs>> = RwLock::new(None);
 
 pub fn init()
 {
@@ -75,9 +128,9 @@ pub fn init()
 }
 
 /// Mount a volume at the provided location
-// TODO: Parse options
-pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) -> Result<(),MountError>
+pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, options: &[&str]) -> Result<(),MountError>
 {
+	let options = MountOptions::parse(options);
 	let drivers = S_DRIVERS.read();
 	// 1. (maybe) detect filesystem
 	let driver = if fs == "" {
@@ -103,17 +156,17 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 	
 	if location == Path::new("/")
 	{
-		let fs: Box<_> = match driver.mount(vol, SelfHandle(0))
+		let fs: Box<_> = match driver.mount(vol, SelfHandle(0), &options)
 			{
 			Ok(v) => v,
 			Err(_) => return Err(MountError::CallFailed),
 			};
 		let mut lh = S_ROOT_VOLUME.write();
 		if lh.is_some() {
-			log_warning!("TODO: Support remounting /");
+			log_warning!("/ is already mounted (use `remount` to change options)");
 			return Err(MountError::MountpointUsed);
 		}
-		*lh = Some(fs);
+		*lh = Some(MountedFs { fs, readonly: AtomicBool::new(options.readonly) });
 	}
 	else
 	{
@@ -130,19 +183,25 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 		
 		// 3. Reserve the mountpoint ID (using a placeholder instance)
 		// NOTE: Nothing should know of this index until after mount is completed
-		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs) });
+		let vidx = S_VOLUMES.write().insert(MountedVolume {
+			mountpoint_node: nh,
+			inner: MountedFs { fs: Box::new(NullFs), readonly: AtomicBool::new(options.readonly) },
+			});
 
 		// 4. Mount and register volume
-		let fs = match driver.mount(vol, SelfHandle(vidx))
+		let fs = match driver.mount(vol, SelfHandle(vidx), &options)
 			{
 			Ok(v) => v,
-			Err(_) => return Err(MountError::CallFailed),
+			Err(_) => {
+				S_VOLUMES.write().remove(vidx);
+				return Err(MountError::CallFailed);
+				},
 			};
 
 		// 5. Store and bind to mountpoint
 		{
 			let mut lh = S_VOLUMES.write();
-			lh[vidx].fs = fs;
+			lh[vidx].inner.fs = fs;
 			if lh[vidx].mountpoint_node.mount(vidx + 1) == false {
 				lh.remove(vidx);
 				return Err(MountError::MountpointUsed);
@@ -152,6 +211,74 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 
 	Ok( () )
 }
+
+/// Unmount the volume mounted at the provided location
+///
+/// Fails with `MountError::Busy` if any node on the volume is still open (or has a volume mounted on it)
+pub fn unmount(location: &Path) -> Result<(),MountError>
+{
+	let id = mount_id_for(location)?;
+	if id == 0 {
+		// The root volume can't be detached, there would be nothing left
+		return Err(MountError::Busy);
+	}
+	let vidx = id - 1;
+
+	// 1. Detach from the mountpoint, provided that nothing still references the volume
+	// - This purges the volume's nodes from the cache, which lets drivers write back per-node state
+	let mountpoint_node = S_VOLUMES.read()[vidx].mountpoint_node.clone();
+	if ! super::node_cache::release_mount(id, &mountpoint_node) {
+		return Err(MountError::Busy);
+	}
+
+	// 2. Write back all dirty data, then release the instance
+	let rv = Handle(id).with_fs(|fs| fs.flush());
+	S_VOLUMES.write().remove(vidx);
+	match rv
+	{
+	Ok(_) => Ok( () ),
+	Err(e) => {
+		log_error!("unmount({:?}): Flush failed, data may have been lost - {:?}", location, e);
+		Err(MountError::CallFailed)
+		},
+	}
+}
+
+/// Change the options of an existing mount
+pub fn remount(location: &Path, options: &[&str]) -> Result<(),MountError>
+{
+	let options = MountOptions::parse(options);
+	let h = Handle(mount_id_for(location)?);
+	h.with_mount(|m| {
+		// Ensure that nothing is left dirty when switching to read-only
+		if options.readonly && !m.readonly.load(Ordering::Relaxed) {
+			m.fs.flush()?;
+		}
+		m.fs.remount(&options)?;
+		m.readonly.store(options.readonly, Ordering::Relaxed);
+		Ok( () )
+		})
+		.map_err(|e: super::Error| {
+			log_warning!("remount({:?}): Driver refused - {:?}", location, e);
+			MountError::CallFailed
+			})
+}
+
+/// Get the mount ID for a path, which must be the root of a mounted volume
+fn mount_id_for(location: &Path) -> Result<usize,MountError>
+{
+	let nh = match CacheHandle::from_path(location)
+		{
+		Ok(nh) => nh,
+		Err(_) => return Err(MountError::InvalidMountpoint),
+		};
+	let (id, inode) = nh.get_ids();
+	if inode != Handle(id).root_inode() {
+		return Err(MountError::NotMounted);
+	}
+	Ok(id)
+}
+
 #[derive(Debug)]
 pub enum MountError
 {
@@ -160,6 +287,8 @@ pub enum MountError
 	InvalidMountpoint,
 	MountpointUsed,
 	CallFailed,
+	NotMounted,
+	Busy,
 }
 impl_fmt! {
 	Display(self,f) for MountError {
@@ -170,6 +299,8 @@ impl_fmt! {
 			&MountError::InvalidMountpoint => "The specified mountpoint was invalid",
 			&MountError::MountpointUsed => "The specified mountpoint was already used",
 			&MountError::CallFailed => "Driver's mount call failed",
+			&MountError::NotMounted => "The specified path is not the root of a mounted volume",
+			&MountError::Busy => "The volume is still in use",
 			})
 	}
 }
@@ -214,13 +345,20 @@ impl Handle
 	pub fn get_node(&self, id: InodeId) -> Option<Node> {
 		self.with_fs(|fs| fs.get_node_by_inode(id))
 	}
+	/// Returns `true` if the volume was mounted read-only
+	pub fn is_readonly(&self) -> bool {
+		self.with_mount(|m| m.readonly.load(Ordering::Relaxed))
+	}
 
 	fn with_fs<R, F: FnOnce(&dyn Filesystem)->R>(&self, f: F) -> R {
+		self.with_mount(|m| f(&*m.fs))
+	}
+	fn with_mount<R, F: FnOnce(&MountedFs)->R>(&self, f: F) -> R {
 		if self.0 == 0 {
-			f(&**S_ROOT_VOLUME.read().as_ref().unwrap())
+			f(S_ROOT_VOLUME.read().as_ref().unwrap())
 		}
 		else {
-			f(&*S_VOLUMES.read().get(self.0 - 1).unwrap().fs)
+			f(&S_VOLUMES.read().get(self.0 - 1).unwrap().inner)
 		}
 	}
 }
@@ -17,12 +17,49 @@ pub enum NodeType<'a> {
 	Symlink(&'a super::Path),
 }
 
+/// Timestamp in seconds since 1970-01-01 00:00 UTC (zero if not recorded)
+pub type Timestamp = i64;
+
+/// Convert a calendar date/time (UTC) into a `Timestamp`
+pub fn timestamp_from_date(year: i64, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> Timestamp {
+	// Days since the epoch, using a year that starts in March (so the leap day is at the end)
+	let (y, m) = if month <= 2 { (year - 1, month as i64 + 9) } else { (year, month as i64 - 3) };
+	let era = y.div_euclid(400);
+	let yoe = y - era * 400;
+	let doy = (153 * m + 2) / 5 + day as i64 - 1;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	let days = era * 146097 + doe - 719468;
+	days * 86400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64
+}
+
+/// Node metadata, as returned by `NodeBase::get_metadata`
+///
+/// Fields that the filesystem doesn't record are left at their defaults (zero)
+#[derive(Debug,Default,Clone)]
+pub struct Metadata {
+	/// Size in bytes (file data, directory storage, or symlink target)
+	pub size: u64,
+	/// Number of directory entries referencing this node
+	pub link_count: u32,
+	/// Unix-style permission bits (`0o7777` mask)
+	pub mode: u16,
+	/// Owning user ID
+	pub owner: u32,
+	/// Owning group ID
+	pub group: u32,
+	pub created: Timestamp,
+	pub modified: Timestamp,
+	pub accessed: Timestamp,
+}
+
 /// Base trait for a VFS node, defines common operation on nodes
 pub trait NodeBase: Send {
 	/// Return the volume's inode number
 	fn get_id(&self) -> InodeId;
 	/// Return an &Any associated with this node (not nessesarily same as `self`, up to the driver)
 	fn get_any(&self) -> &dyn Any;
+	/// Obtain the node's metadata (times, permissions, ownership)
+	fn get_metadata(&self) -> Result<Metadata>;
 }
 /// Trait for "File" nodes
 pub trait File: NodeBase {
@@ -63,6 +100,10 @@ pub trait Dir: NodeBase {
 	fn link(&self, name: &ByteStr, inode: &dyn NodeBase) -> Result<()>;
 	/// Remove the specified name
 	fn unlink(&self, name: &ByteStr) -> Result<()>;
+	/// Move the entry `old_name` to `new_name` in `new_dir` (which can be `self`)
+	///
+	/// `new_dir` is always on the same volume, and the destination name must not exist.
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn Dir, new_name: &ByteStr) -> Result<()>;
 }
 /// Trait for symbolic link nodes.
 pub trait Symlink: NodeBase {
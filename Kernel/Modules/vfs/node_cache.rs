@@ -8,7 +8,7 @@ use super::Path;
 use super::node::InodeId;
 use ::kernel::sync::mutex::LazyMutex;
 use ::kernel::lib::byte_str::{ByteStr,ByteString};
-use ::core::sync::atomic::{self,AtomicUsize};
+use ::core::sync::atomic::{self,AtomicBool,AtomicUsize};
 
 static S_NODE_CACHE: LazyMutex<::kernel::lib::VecMap<(usize,InodeId),Box<CachedNode>>> = lazymutex_init!();
 
@@ -58,6 +58,8 @@ struct CachedNode
 {
 	/// Number of outstanding references to this node
 	refcount: AtomicUsize,
+	/// A name for this node has been removed, so it's dropped from the cache once unreferenced
+	unlinked: AtomicBool,
 	/// Per-class info
 	node: CacheNodeInfo,
 	// TODO: Append lock (held while the size is being updated)
@@ -103,6 +105,26 @@ impl Clone for CacheHandle
 			}
 	}
 }
+impl Drop for CacheHandle
+{
+	fn drop(&mut self) {
+		// NOTE: The node is left in the cache (it's only purged when the volume is unmounted), unless it has been unlinked
+		// - The cache lock is held so the release can't race with `mark_unlinked`
+		let purged = {
+			let mut lh = S_NODE_CACHE.lock();
+			// SAFE: self.ptr is valid until the reference is released (and the lock prevents a concurrent purge)
+			let cn = unsafe { &*self.ptr };
+			if cn.refcount.fetch_sub(1, atomic::Ordering::Release) == 1 && cn.unlinked.load(atomic::Ordering::Relaxed) {
+				lh.remove( &(self.mountpt, self.inode) )
+			}
+			else {
+				None
+			}
+			};
+		// Drop the driver's node handle outside of the lock
+		drop(purged);
+	}
+}
 
 impl CacheHandle
 {
@@ -121,7 +143,7 @@ impl CacheHandle
 			Entry::Vacant(e) =>
 				match super::mount::Handle::from_id(mountpoint).get_node(inode)
 				{
-				Some(node) => e.insert(Box::new(CachedNode { node: node.into(), refcount: AtomicUsize::new(1) })),
+				Some(node) => e.insert(Box::new(CachedNode { node: node.into(), refcount: AtomicUsize::new(1), unlinked: AtomicBool::new(false) })),
 				None => return Err( super::Error::NotFound ),
 				},
 			};
@@ -154,6 +176,21 @@ impl CacheHandle
 	}
 	
 	
+	/// Obtain a handle to a node looked up in the directory `parent`
+	///
+	/// Records the parent of directories, so renames can check for loops.
+	fn from_child_ids(parent: &CacheHandle, inode: InodeId) -> super::Result<CacheHandle>
+	{
+		let rv = CacheHandle::from_ids(parent.mountpt, inode)?;
+		// - If the child was a mountpoint, then `rv` is the root of another mount (and has no parent there)
+		if rv.mountpt == parent.mountpt {
+			if let CacheNodeInfo::Dir(ref info) = *rv.as_ref() {
+				info.set_parent(parent.inode);
+			}
+		}
+		Ok(rv)
+	}
+	
 	/// Obtain a node handle using a parent directory node and a relative path
 	pub fn from_path_at_node(node_h: CacheHandleDir, path: &Path) -> super::Result<CacheHandle>
 	{
@@ -209,7 +246,7 @@ impl CacheHandle
 						Ok(v) => v,
 						Err(_) => return Err(super::Error::NotFound),
 						};
-					CacheHandle::from_ids( node_h.mountpt, next_id )?
+					CacheHandle::from_child_ids( &node_h, next_id )?
 					},
 				_ => return Err(super::Error::NonDirComponent),
 				};
@@ -238,6 +275,23 @@ impl CacheHandle
 		CacheHandle::from_path_at_node(node_h.into_dir()?, path)
 	}
 	
+	/// Get the (mount ID, inode number) pair for this node
+	pub fn get_ids(&self) -> (usize, InodeId) {
+		(self.mountpt, self.inode)
+	}
+	/// Returns an error if the node's volume is mounted read-only
+	fn check_writable(&self) -> super::Result<()> {
+		if super::mount::Handle::from_id(self.mountpt).is_readonly() {
+			Err( super::Error::ReadOnlyFilesystem )
+		}
+		else {
+			Ok( () )
+		}
+	}
+	/// Check the current process's credentials against the node's mode bits
+	pub fn check_access(&self, access: super::credentials::Access) -> super::Result<()> {
+		super::credentials::check_access(&self.get_metadata()?, access)
+	}
 	pub fn get_class(&self) -> NodeClass {
 		match self.as_ref()
 		{
@@ -278,6 +332,15 @@ impl CacheHandle
 		&CacheNodeInfo::Symlink { ref fsnode, .. } => fsnode.get_any(),
 		}
 	}
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		match self.as_ref()
+		{
+		&CacheNodeInfo::Dir(ref inner) => inner.fsnode.get_metadata(),
+		&CacheNodeInfo::File(ref inner) => inner.fsnode.get_metadata(),
+		&CacheNodeInfo::Special { ref fsnode, .. } => fsnode.get_metadata(),
+		&CacheNodeInfo::Symlink { ref fsnode, .. } => fsnode.get_metadata(),
+		}
+	}
 }
 
 
@@ -293,6 +356,103 @@ impl CacheHandle
 	}
 }
 
+/// Returns `true` if the specified node is a directory with a filesystem mounted on it
+fn is_mounted_on(mountpt: usize, inode: InodeId) -> bool {
+	match S_NODE_CACHE.lock().get( &(mountpt, inode) )
+	{
+	Some(cn) => match cn.node
+		{
+		CacheNodeInfo::Dir(ref info) => info.mountpoint.load(atomic::Ordering::Relaxed) != 0,
+		_ => false,
+		},
+	None => false,
+	}
+}
+
+/// Returns the recorded parent of a cached directory (if known)
+fn cached_parent(mountpt: usize, inode: InodeId) -> Option<InodeId> {
+	match S_NODE_CACHE.lock().get( &(mountpt, inode) )
+	{
+	Some(cn) => match cn.node
+		{
+		CacheNodeInfo::Dir(ref info) => info.get_parent(),
+		_ => None,
+		},
+	None => None,
+	}
+}
+/// Update the recorded parent of a cached directory (after it has been renamed)
+fn set_cached_parent(mountpt: usize, inode: InodeId, parent: InodeId) {
+	if let Some(cn) = S_NODE_CACHE.lock().get( &(mountpt, inode) ) {
+		if let CacheNodeInfo::Dir(ref info) = cn.node {
+			info.set_parent(parent);
+		}
+	}
+}
+
+/// Flag a cached node as having had a name removed, purging it now if it's unreferenced
+///
+/// This lets the filesystem free the node once the last handle is closed (if that was its last name)
+fn mark_unlinked(mountpt: usize, inode: InodeId) {
+	let purged = {
+		let mut lh = S_NODE_CACHE.lock();
+		let key = (mountpt, inode);
+		let in_use = match lh.get(&key)
+			{
+			Some(cn) => {
+				cn.unlinked.store(true, atomic::Ordering::Relaxed);
+				cn.refcount.load(atomic::Ordering::Acquire) > 0
+				},
+			None => true,
+			};
+		if in_use { None } else { lh.remove(&key) }
+		};
+	// Drop the driver's node handle outside of the lock
+	drop(purged);
+}
+
+/// Detach a mount from its mountpoint, and purge its nodes from the cache
+///
+/// Returns `false` (leaving the mount untouched) if any node on the mount is still referenced
+pub fn release_mount(mount_id: usize, mountpoint: &CacheHandleDir) -> bool {
+	let purged: Vec<Box<CachedNode>> = {
+		let mut lh = S_NODE_CACHE.lock();
+		if lh.iter().any(|(k,v)| k.0 == mount_id && v.refcount.load(atomic::Ordering::Acquire) > 0) {
+			return false;
+		}
+		// SAFE: The handle keeps the node in the cache, and the info isn't mutated
+		match unsafe { &(*mountpoint.0.ptr).node }
+		{
+		CacheNodeInfo::Dir(ref info) => {
+			if info.mountpoint.compare_exchange(mount_id, 0, atomic::Ordering::Relaxed, atomic::Ordering::Relaxed).is_err() {
+				return false;
+			}
+			},
+		_ => return false,
+		}
+		let keys: Vec<_> = lh.iter().filter(|(k,_)| k.0 == mount_id).map(|(k,_)| *k).collect();
+		keys.iter().filter_map(|k| lh.remove(k)).collect()
+		};
+	// Drop the driver's node handles outside of the lock
+	drop(purged);
+	true
+}
+
+/// Adapter used to pass a cached node to `node::Dir::link`
+struct LinkSource<'a>(&'a CacheHandle);
+impl<'a> super::node::NodeBase for LinkSource<'a>
+{
+	fn get_id(&self) -> InodeId {
+		self.0.inode
+	}
+	fn get_any(&self) -> &dyn Any {
+		self.0.get_node_any()
+	}
+	fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		self.0.get_metadata()
+	}
+}
+
 impl CacheHandle
 {
 	fn as_ref(&self) -> &CacheNodeInfo {
@@ -4,6 +4,7 @@ use super::{CacheHandleDir};
 use crate as vfs;
 use ::core::sync::atomic::{self,AtomicUsize};
 use ::kernel::lib::byte_str::{ByteStr};
+use crate::credentials::{self,Access};
 
 pub struct CacheNodeInfoDir
 {
@@ -11,14 +12,23 @@ pub struct CacheNodeInfoDir
 	pub fsnode: Box<dyn vfs::node::Dir>,
 
 	pub mountpoint: AtomicUsize,	// 0 is invalid (that's root), so means "no mount"
+	/// Parent directory on the same mount (if this node was reached via a lookup)
+	parent: ::kernel::sync::Mutex<Option<vfs::node::InodeId>>,
 }
 impl CacheNodeInfoDir {
 	pub fn new(fsnode: Box<dyn vfs::node::Dir>) -> Self {
 		CacheNodeInfoDir {
 			fsnode,
 			mountpoint: AtomicUsize::new(0),
+			parent: ::kernel::sync::Mutex::new(None),
 		}
 	}
+	pub fn get_parent(&self) -> Option<vfs::node::InodeId> {
+		*self.parent.lock()
+	}
+	pub fn set_parent(&self, parent: vfs::node::InodeId) {
+		*self.parent.lock() = Some(parent);
+	}
 }
 
 /// Directory methods
@@ -31,16 +41,101 @@ impl CacheHandleDir
 		_ => Err( vfs::Error::Unknown("BUG: CacheHandleDir for non-directory") ),
 		}
 	}
+	/// Check that entries can be added/removed (volume writable, and caller has write+search access)
+	fn check_modify(&self) -> vfs::Result<()> {
+		self.0.check_writable()?;
+		self.0.check_access(Access::Write)?;
+		self.0.check_access(Access::Execute)?;
+		Ok( () )
+	}
+	/// Check that the sticky bit (if set on this directory) allows the current process to remove/rename `inode`
+	fn check_sticky(&self, inode: vfs::node::InodeId) -> vfs::Result<()> {
+		let dir_meta = self.0.get_metadata()?;
+		if dir_meta.mode & credentials::S_ISVTX == 0 {
+			return Ok( () );
+		}
+		let node = super::CacheHandle::from_child_ids(&self.0, inode)?;
+		credentials::check_sticky(&dir_meta, &node.get_metadata()?)
+	}
 	pub fn create(&self, name: &ByteStr, ty: vfs::node::NodeType) -> vfs::Result<super::CacheHandle> {
+		self.check_modify()?;
 		let inode = self.get_info()?.fsnode.create(name, ty)?;
-		Ok( super::CacheHandle::from_ids(self.0.mountpt, inode)? )
+		Ok( super::CacheHandle::from_child_ids(&self.0, inode)? )
 	}
 	pub fn read_dir(&self, ofs: usize, items: &mut vfs::node::ReadDirCallback) -> vfs::Result<usize> {
 		Ok( self.get_info()?.fsnode.read(ofs, items)? )
 	}
 	pub fn open_child(&self, name: &ByteStr) -> vfs::Result<super::CacheHandle> {
 		let inode = self.get_info()?.fsnode.lookup(name)?;
-		Ok( super::CacheHandle::from_ids(self.0.mountpt, inode)? )
+		Ok( super::CacheHandle::from_child_ids(&self.0, inode)? )
+	}
+	/// Add a new name for an existing (non-directory) node on the same mount
+	pub fn link(&self, name: &ByteStr, node: &super::CacheHandle) -> vfs::Result<()> {
+		if node.mountpt != self.0.mountpt {
+			return Err( vfs::Error::CrossFilesystem );
+		}
+		if node.is_dir() {
+			return Err( vfs::Error::TypeMismatch );
+		}
+		self.check_modify()?;
+		self.get_info()?.fsnode.link(name, &super::LinkSource(node))
+	}
+	pub fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
+		self.check_modify()?;
+		let info = self.get_info()?;
+		let inode = info.fsnode.lookup(name)?;
+		if super::is_mounted_on(self.0.mountpt, inode) {
+			return Err( vfs::Error::Locked );
+		}
+		self.check_sticky(inode)?;
+		info.fsnode.unlink(name)?;
+		super::mark_unlinked(self.0.mountpt, inode);
+		Ok( () )
+	}
+	/// Move an entry to another directory on the same mount
+	pub fn rename(&self, old_name: &ByteStr, new_dir: &CacheHandleDir, new_name: &ByteStr) -> vfs::Result<()> {
+		if new_dir.0.mountpt != self.0.mountpt {
+			return Err( vfs::Error::CrossFilesystem );
+		}
+		self.check_modify()?;
+		new_dir.check_modify()?;
+		let info = self.get_info()?;
+		let inode = info.fsnode.lookup(old_name)?;
+		if super::is_mounted_on(self.0.mountpt, inode) {
+			return Err( vfs::Error::Locked );
+		}
+		self.check_sticky(inode)?;
+		// Prevent a directory being moved into its own sub-tree
+		if new_dir.0.inode != self.0.inode {
+			new_dir.check_not_within(inode, self.0.inode)?;
+		}
+		info.fsnode.rename(old_name, &*new_dir.get_info()?.fsnode, new_name)?;
+		super::set_cached_parent(self.0.mountpt, inode, new_dir.0.inode);
+		Ok( () )
+	}
+	/// Returns an error if `node` is this directory or one of its parents
+	///
+	/// The search stops at the mount root, or at `stop` (a known ancestor that isn't `node`)
+	fn check_not_within(&self, node: vfs::node::InodeId, stop: vfs::node::InodeId) -> vfs::Result<()> {
+		let root = vfs::mount::Handle::from_id(self.0.mountpt).root_inode();
+		let mut cur = self.0.inode;
+		loop
+		{
+			if cur == node {
+				return Err( vfs::Error::InvalidParameter );
+			}
+			if cur == stop || cur == root {
+				return Ok( () );
+			}
+			cur = match super::cached_parent(self.0.mountpt, cur)
+				{
+				Some(v) => v,
+				None => {
+					log_notice!("check_not_within: Parent of {}:{:#x} unknown", self.0.mountpt, cur);
+					return Err( vfs::Error::Unknown("Directory parent unknown") );
+					},
+				};
+		}
 	}
 }
 /// Directory methods (mountpoint)
@@ -42,6 +42,10 @@ impl CacheHandleFile
 		_ => Err(vfs::Error::InvalidParameter),
 		}
 	}
+	/// Check the current process's credentials against the file's mode bits
+	pub fn check_access(&self, access: vfs::credentials::Access) -> vfs::Result<()> {
+		self.0.check_access(access)
+	}
 	/// Take out a sharable lock on the file
 	pub fn file_lock_shared(&self) -> vfs::Result<()> {
 		let info = self.get_info()?;
@@ -135,9 +139,15 @@ impl CacheHandleFile
 	}
 	pub fn write(&self, ofs: u64, src: &[u8]) -> vfs::Result<usize> {
 		// TODO: Ensure that the handle is writable?
+		self.0.check_writable()?;
 		Ok( self.get_info()?.fsnode.write(ofs, src)? )
 	}
+	pub fn truncate(&self, newsize: u64) -> vfs::Result<u64> {
+		self.0.check_writable()?;
+		Ok( self.get_info()?.fsnode.truncate(newsize)? )
+	}
 	pub fn append(&self, data: &[u8]) -> vfs::Result<usize> {
+		self.0.check_writable()?;
 		let info = self.get_info()?;
 		let _lh = info.append_lock.lock();
 		let ofs = info.fsnode.size();
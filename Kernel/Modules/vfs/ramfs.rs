@@ -1,8 +1,8 @@
 // "Tifflin" Kernel
 // - By John Hodge (thePowersGang)
 //
-// Core/vfs/mod.rs
-//! Virtual File System
+// Core/vfs/ramfs.rs
+//! In-memory filesystem (used for the root and tmpfs mounts)
 use ::kernel::prelude::*;
 use crate as vfs;
 use super::{mount, node};
@@ -10,13 +10,29 @@ use ::kernel::metadevs::storage::VolumeHandle;
 use ::kernel::lib::{VecMap,SparseVec};
 use ::kernel::lib::byte_str::{ByteStr,ByteString};
 use ::kernel::lib::mem::aref::{Aref,ArefInner,ArefBorrow};
+use ::kernel::memory::phys::FrameHandle;
+use ::kernel::memory::page_cache::{S_PAGE_CACHE,CachedPage};
+use ::kernel::PAGE_SIZE;
+use ::core::sync::atomic::{AtomicU32,AtomicUsize,Ordering};
+use ::core::mem::ManuallyDrop;
 
 pub struct Driver;
 pub static S_DRIVER: Driver = Driver;
 
+/// A node, with the metadata shared by all node types
+struct RamNode
+{
+	owner: u32,
+	group: u32,
+	mode: u16,
+	link_count: AtomicU32,
+	/// Number of live `FileRef`s (updated with the node list locked)
+	open_count: AtomicU32,
+	data: RamFile,
+}
 enum RamFile
 {
-	//File(RamFileFile),
+	File(RamFileFile),
 	Dir(RamFileDir),
 	Symlink(RamFileSymlink),
 }
@@ -30,13 +46,20 @@ struct RamFileSymlink
 {
 	target: super::PathBuf,
 }
-//#[derive(Default)]
-//struct RamFileFile
-//{
-//	ofs: usize,
-//	size: usize,
-//}
-struct FileRef(ArefBorrow<RamFSInner>,ArefBorrow<RamFile>);
+#[derive(Default)]
+struct RamFileFile
+{
+	data: ::kernel::sync::RwLock<RamFileData>,
+}
+#[derive(Default)]
+struct RamFileData
+{
+	size: u64,
+	/// Backing frames, `None` for pages that have never been written (these read as zero)
+	pages: Vec<Option<FrameHandle>>,
+}
+/// Handle to a node, the node is freed when the last handle is dropped after the last name is removed
+struct FileRef(ArefBorrow<RamFSInner>,ManuallyDrop<ArefBorrow<RamNode>>,node::InodeId);
 
 struct RamFS
 {
@@ -47,7 +70,11 @@ struct RamFSInner
 	_vh: VolumeHandle,
 	// TODO: Store as much data (and metadata) as possible on the volume
 	// - Possibly by using an allocation pool backed onto the volume
-	nodes: ::kernel::sync::Mutex< SparseVec<Aref<RamFile>> >,
+	nodes: ::kernel::sync::Mutex< SparseVec<Aref<RamNode>> >,
+	/// Maximum number of file pages (from the `size` mount option, `!0` if unlimited)
+	page_limit: AtomicUsize,
+	/// Number of pages currently allocated to file data
+	pages_used: AtomicUsize,
 }
 
 pub fn init()
@@ -56,21 +83,67 @@ pub fn init()
 	::core::mem::forget(h);
 }
 
+/// Parse the `size` mount option (bytes, with an optional K/M/G suffix) into a page count
+fn parse_size_option(options: &mount::MountOptions) -> super::Result<Option<usize>> {
+	let v = match options.get("size")
+		{
+		Some(v) => v,
+		None => return Ok(None),
+		};
+	let (digits, shift) = match v.as_bytes().last()
+		{
+		Some(&b'K') | Some(&b'k') => (&v[..v.len()-1], 10),
+		Some(&b'M') | Some(&b'm') => (&v[..v.len()-1], 20),
+		Some(&b'G') | Some(&b'g') => (&v[..v.len()-1], 30),
+		_ => (v, 0),
+		};
+	match digits.parse::<usize>().ok().and_then(|n| n.checked_mul(1 << shift))
+	{
+	Some(n) => Ok(Some( ::kernel::lib::num::div_up(n, PAGE_SIZE) )),
+	None => {
+		log_warning!("ramfs: Invalid size option {:?}", v);
+		Err(vfs::Error::InvalidParameter)
+		},
+	}
+}
+
 impl mount::Driver for Driver
 {
 	fn detect(&self, _vol: &VolumeHandle) -> super::Result<usize> {
 		// RAMFS should never bind to an arbitary volume
 		Ok(0)
 	}
-	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle, options: &mount::MountOptions) -> super::Result<Box<dyn mount::Filesystem>> {
+		let page_limit = parse_size_option(options)?.unwrap_or(!0);
+		let root_mode = match options.get("mode")
+			{
+			Some(v) => match u16::from_str_radix(v, 8)
+				{
+				Ok(v) => v & 0o7777,
+				Err(_) => {
+					log_warning!("ramfs: Invalid mode option {:?}", v);
+					return Err(vfs::Error::InvalidParameter);
+					},
+				},
+			None => 0o755,
+			};
 		let rv = Box::new(RamFS {
 			// SAFE: ArefInner must not change addresses, but because you can't move out of a boxed trait, we're good
 			inner: unsafe { ArefInner::new( RamFSInner {
 				_vh: vol,
 				nodes: Default::default(),
+				page_limit: AtomicUsize::new(page_limit),
+				pages_used: AtomicUsize::new(0),
 				}) },
 			});
-		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamFile::Dir(Default::default())) );
+		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamNode {
+			owner: 0,
+			group: 0,
+			mode: root_mode,
+			link_count: AtomicU32::new(1),
+			open_count: AtomicU32::new(0),
+			data: RamFile::Dir(Default::default()),
+			}) );
 		assert_eq!(root_inode, 0);
 		Ok(rv)
 	}
@@ -84,48 +157,250 @@ impl mount::Filesystem for RamFS
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		log_trace!("RamFS::get_node_by_inode({})", id);
 		let nodes = self.inner.nodes.lock();
-		if id >= nodes.len() as node::InodeId {
-			log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
-			None
+		let n = match nodes.get(id as usize)
+			{
+			Some(v) => v,
+			None => {
+				log_log!("RamFile::get_node_by_inode - Inode {} out of range or freed", id);
+				return None;
+				},
+			};
+		n.open_count.fetch_add(1, Ordering::Relaxed);
+		let fr = Box::new(FileRef(
+			self.inner.borrow(),
+			ManuallyDrop::new(n.borrow()),
+			id
+			));
+		match n.data
+		{
+		RamFile::Dir(_) => Some(node::Node::Dir(fr)),
+		RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
+		RamFile::File(_) => Some(node::Node::File(fr)),
 		}
-		else {
-			let fr = Box::new(FileRef(
-				self.inner.borrow(),
-				nodes[id as usize].borrow()
-				));
-			match *nodes[id as usize]
+	}
+	fn flush(&self) -> super::Result<()> {
+		// Nothing is stored on the volume
+		Ok( () )
+	}
+	fn remount(&self, options: &mount::MountOptions) -> super::Result<()> {
+		if let Some(limit) = parse_size_option(options)? {
+			// NOTE: Shrinking below the current usage only prevents new allocations
+			self.inner.page_limit.store(limit, Ordering::Relaxed);
+		}
+		Ok( () )
+	}
+}
+
+impl RamFSInner
+{
+	/// Allocate a zeroed page for file data, checking against the size limit
+	fn alloc_page(&self) -> vfs::Result<FrameHandle> {
+		let limit = self.page_limit.load(Ordering::Relaxed);
+		if self.pages_used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| if v < limit { Some(v + 1) } else { None }).is_err() {
+			return Err(vfs::Error::OutOfSpace);
+		}
+		let mut page = match S_PAGE_CACHE.create()
 			{
-			RamFile::Dir(_) => Some(node::Node::Dir(fr)),
-			RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
-			//RamFile::File(_) => todo!("normal files"),
+			Ok(v) => v,
+			Err(_) => {
+				self.pages_used.fetch_sub(1, Ordering::Relaxed);
+				return Err(vfs::Error::OutOfMemory);
+				},
+			};
+		page.data_mut().fill(0);
+		Ok( page.get_frame_handle() )
+	}
+	fn free_page(&self, frame: FrameHandle) {
+		drop(frame);
+		self.pages_used.fetch_sub(1, Ordering::Relaxed);
+	}
+	/// Free a node that has no names and no open handles
+	fn release_node(&self, nodes: &mut SparseVec<Aref<RamNode>>, inode: usize) {
+		if let RamFile::File(ref f) = nodes[inode].data {
+			// Shrinking never fails (no pages are mapped when truncating to zero)
+			let _ = f.truncate(self, 0);
+		}
+		nodes.remove(inode);
+	}
+}
+
+/// Temporarily map a file page into kernel memory
+fn map_frame(frame: &FrameHandle) -> vfs::Result<CachedPage> {
+	S_PAGE_CACHE.map(frame).map_err(|_| vfs::Error::OutOfMemory)
+}
+
+impl RamFileFile
+{
+	fn truncate(&self, fs: &RamFSInner, newsize: u64) -> vfs::Result<u64> {
+		let mut lh = self.data.write();
+		if newsize < lh.size {
+			// Release frames past the new end
+			let n_pages = ::kernel::lib::num::div_up(newsize, PAGE_SIZE as u64) as usize;
+			if n_pages < lh.pages.len() {
+				for frame in lh.pages.drain(n_pages..).flatten() {
+					fs.free_page(frame);
+				}
+			}
+			// Zero the tail of the last page, so extending the file again reads zeroes
+			let tail = (newsize % PAGE_SIZE as u64) as usize;
+			if tail != 0 {
+				if let Some(&Some(ref frame)) = lh.pages.get(n_pages - 1) {
+					map_frame(frame)?.data_mut()[tail..].fill(0);
+				}
 			}
 		}
+		// NOTE: Extending doesn't allocate, the new pages read as zero until written
+		lh.size = newsize;
+		Ok(newsize)
 	}
 }
 
+impl Drop for FileRef {
+	fn drop(&mut self) {
+		let mut nodes = self.0.nodes.lock();
+		let last = self.1.open_count.fetch_sub(1, Ordering::Relaxed) == 1 && self.1.link_count.load(Ordering::Relaxed) == 0;
+		// SAFE: The borrow isn't used after this point
+		unsafe { ManuallyDrop::drop(&mut self.1); }
+		if last {
+			log_debug!("RamFS: Freeing unlinked inode {}", self.2);
+			self.0.release_node(&mut nodes, self.2 as usize);
+		}
+	}
+}
 impl FileRef {
 	fn dir(&self) -> &RamFileDir {
-		match &*self.1
+		match &self.1.data
 		{
 		&RamFile::Dir(ref e) => e,
 		_ => panic!("Called FileRef::dir() on non-dir"),
 		}
 	}
 	fn symlink(&self) -> &RamFileSymlink {
-		match &*self.1
+		match &self.1.data
 		{
 		&RamFile::Symlink(ref e) => e,
 		_ => panic!("Called FileRef::symlink() on non-symlink"),
 		}
 	}
+	fn file(&self) -> &RamFileFile {
+		match &self.1.data
+		{
+		&RamFile::File(ref e) => e,
+		_ => panic!("Called FileRef::file() on non-file"),
+		}
+	}
 }
 impl node::NodeBase for FileRef {
 	fn get_id(&self) -> node::InodeId {
-		unimplemented!()
+		self.2
 	}
 	fn get_any(&self) -> &dyn (::core::any::Any) {
 		self
 	}
+	fn get_metadata(&self) -> vfs::Result<node::Metadata> {
+		// TODO: Track times
+		Ok(node::Metadata {
+			size: match &self.1.data
+				{
+				&RamFile::File(ref e) => e.data.read().size,
+				&RamFile::Dir(ref e) => e.ents.read().iter().count() as u64,
+				&RamFile::Symlink(ref e) => ByteStr::new(&*e.target).len() as u64,
+				},
+			link_count: self.1.link_count.load(Ordering::Relaxed),
+			mode: self.1.mode,
+			owner: self.1.owner,
+			group: self.1.group,
+			..Default::default()
+			})
+	}
+}
+impl node::File for FileRef {
+	fn size(&self) -> u64 {
+		self.file().data.read().size
+	}
+	fn truncate(&self, newsize: u64) -> vfs::Result<u64> {
+		self.file().truncate(&self.0, newsize)
+	}
+	fn clear(&self, ofs: u64, size: u64) -> vfs::Result<()> {
+		let mut lh = self.file().data.write();
+		let end = ::core::cmp::min(ofs.saturating_add(size), lh.size);
+		let mut pos = ofs;
+		while pos < end
+		{
+			let page = (pos / PAGE_SIZE as u64) as usize;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let len = ::core::cmp::min((PAGE_SIZE - page_ofs) as u64, end - pos) as usize;
+			if let Some(slot) = lh.pages.get_mut(page) {
+				if len == PAGE_SIZE {
+					// Whole page cleared, just release the frame
+					if let Some(frame) = slot.take() {
+						self.0.free_page(frame);
+					}
+				}
+				else if let Some(ref frame) = *slot {
+					map_frame(frame)?.data_mut()[page_ofs..][..len].fill(0);
+				}
+			}
+			pos += len as u64;
+		}
+		Ok( () )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+		let lh = self.file().data.read();
+		if ofs > lh.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let len = ::core::cmp::min(buf.len() as u64, lh.size - ofs) as usize;
+		let mut done = 0;
+		while done < len
+		{
+			let pos = ofs + done as u64;
+			let page = (pos / PAGE_SIZE as u64) as usize;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let n = ::core::cmp::min(PAGE_SIZE - page_ofs, len - done);
+			let dst = &mut buf[done..][..n];
+			match lh.pages.get(page)
+			{
+			Some(&Some(ref frame)) => dst.copy_from_slice( &map_frame(frame)?.data()[page_ofs..][..n] ),
+			_ => dst.fill(0),
+			}
+			done += n;
+		}
+		Ok(len)
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
+		let mut lh = self.file().data.write();
+		if ofs > lh.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let mut done = 0;
+		while done < buf.len()
+		{
+			let pos = ofs + done as u64;
+			let page = (pos / PAGE_SIZE as u64) as usize;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let n = ::core::cmp::min(PAGE_SIZE - page_ofs, buf.len() - done);
+			if lh.pages.len() <= page {
+				lh.pages.resize_with(page + 1, || None);
+			}
+			if lh.pages[page].is_none() {
+				match self.0.alloc_page()
+				{
+				Ok(frame) => lh.pages[page] = Some(frame),
+				// Ran out of space part-way through, return a short write
+				Err(_) if done > 0 => break,
+				Err(e) => return Err(e),
+				}
+			}
+			let frame = lh.pages[page].as_ref().unwrap();
+			map_frame(frame)?.data_mut()[page_ofs..][..n].copy_from_slice(&buf[done..][..n]);
+			done += n;
+			if pos + n as u64 > lh.size {
+				lh.size = pos + n as u64;
+			}
+		}
+		Ok(done)
+	}
 }
 impl node::Dir for FileRef {
 	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
@@ -158,25 +433,100 @@ impl node::Dir for FileRef {
 		{
 		Entry::Occupied(_) => Err(vfs::Error::AlreadyExists),
 		Entry::Vacant(e) => {
-			let nn = match nodetype
+			let (mode, nn) = match nodetype
 				{
-				node::NodeType::Dir  => RamFile::Dir (Default::default()),
-				//node::NodeType::File => RamFile::File(Default::default()),
-				node::NodeType::File => return Err(vfs::Error::Unknown("TODO: Files")),
+				node::NodeType::Dir  => (0o755, RamFile::Dir (Default::default())),
+				node::NodeType::File => (0o644, RamFile::File(Default::default())),
 				node::NodeType::Symlink(v) =>
-					RamFile::Symlink(RamFileSymlink{target: From::from(v)}),
+					(0o777, RamFile::Symlink(RamFileSymlink{target: From::from(v)})),
 				};
-			let inode = self.0.nodes.lock().insert( Aref::new(nn) );
+			// New nodes are owned by the creating process
+			let creds = vfs::Credentials::current();
+			let inode = self.0.nodes.lock().insert( Aref::new(RamNode {
+				owner: creds.uid(),
+				group: creds.gid(),
+				mode: mode,
+				link_count: AtomicU32::new(1),
+				open_count: AtomicU32::new(0),
+				data: nn,
+				}) );
 			e.insert(inode);
 			Ok(inode as node::InodeId)
 			},
 		}
 	}
 	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::link({:?}, inode={})", name, node.get_id())
+		use ::kernel::lib::vec_map::Entry;
+		let mut lh = self.dir().ents.write();
+		match lh.entry(From::from(name))
+		{
+		Entry::Occupied(_) => Err(vfs::Error::AlreadyExists),
+		Entry::Vacant(e) => {
+			let inode = node.get_id() as usize;
+			self.0.nodes.lock()[inode].link_count.fetch_add(1, Ordering::Relaxed);
+			e.insert(inode);
+			Ok( () )
+			},
+		}
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::unlink({:?})", name)
+		let mut lh = self.dir().ents.write();
+		let inode = match lh.get(name)
+			{
+			Some(&v) => v,
+			None => return Err(vfs::Error::NotFound),
+			};
+		{
+			// NOTE: The node list isn't held while checking, as `create` locks it with a directory locked
+			let node = self.0.nodes.lock()[inode].borrow();
+			if let RamFile::Dir(ref d) = node.data {
+				if d.ents.read().iter().next().is_some() {
+					return Err(vfs::Error::DirectoryNotEmpty);
+				}
+			}
+		}
+		lh.remove(&From::from(name));
+		let mut nodes = self.0.nodes.lock();
+		let node = &nodes[inode];
+		if node.link_count.fetch_sub(1, Ordering::Relaxed) == 1 && node.open_count.load(Ordering::Relaxed) == 0 {
+			// Last name removed and nothing has it open, free it now
+			self.0.release_node(&mut nodes, inode);
+		}
+		// Otherwise, the node (and its data) is freed when the last handle is dropped
+		Ok( () )
+	}
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn node::Dir, new_name: &ByteStr) -> vfs::Result<()> {
+		let new_dir = match new_dir.get_any().downcast_ref::<FileRef>()
+			{
+			Some(v) => v.dir(),
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		let src = self.dir();
+		if ::core::ptr::eq(src, new_dir) {
+			let mut lh = src.ents.write();
+			if lh.get(new_name).is_some() {
+				return Err(vfs::Error::AlreadyExists);
+			}
+			let inode = lh.remove(&From::from(old_name)).ok_or(vfs::Error::NotFound)?;
+			lh.insert(From::from(new_name), inode);
+		}
+		else {
+			// Lock in address order, so two opposing renames can't deadlock
+			let (mut src_lh, mut dst_lh) = if (src as *const RamFileDir) < (new_dir as *const RamFileDir) {
+					let a = src.ents.write();
+					(a, new_dir.ents.write())
+				}
+				else {
+					let b = new_dir.ents.write();
+					(src.ents.write(), b)
+				};
+			if dst_lh.get(new_name).is_some() {
+				return Err(vfs::Error::AlreadyExists);
+			}
+			let inode = src_lh.remove(&From::from(old_name)).ok_or(vfs::Error::NotFound)?;
+			dst_lh.insert(From::from(new_name), inode);
+		}
+		Ok( () )
 	}
 }
 impl node::Symlink for FileRef {
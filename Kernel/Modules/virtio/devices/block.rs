@@ -3,19 +3,30 @@ use kernel::prelude::*;
 use kernel::metadevs::storage;
 use crate::interface::Interface;
 use crate::queue::{Queue,Buffer};
+use core::sync::atomic::{AtomicU32,Ordering};
 
 #[allow(dead_code)]
 mod defs {
 pub const VIRTIO_BLK_F_RO	: u32 = 1 << 5;
+pub const VIRTIO_BLK_F_FLUSH	: u32 = 1 << 9;
+pub const VIRTIO_BLK_F_DISCARD	: u32 = 1 << 13;
 // TODO: Other feature flags
 
+/// Config offset of `max_discard_sectors`
+pub const VIRTIO_BLK_CFG_MAX_DISCARD_SECTORS: usize = 36;
+
 pub const VIRTIO_BLK_T_IN    	: u32 = 0;
 pub const VIRTIO_BLK_T_OUT  	: u32 = 1;
 pub const VIRTIO_BLK_T_SCSI_CMD	: u32 = 2;
 pub const VIRTIO_BLK_T_SCSI_CMD_OUT	: u32 = 3;
 pub const VIRTIO_BLK_T_FLUSH	: u32 = 4;
 pub const VIRTIO_BLK_T_FLUSH_OUT: u32 = 5;
+pub const VIRTIO_BLK_T_DISCARD	: u32 = 11;
 pub const VIRTIO_BLK_T_BARRIER	: u32 = 0x8000_0000;
+
+pub const VIRTIO_BLK_S_OK	: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR	: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP	: u8 = 2;
 }
 use self::defs::*;
 
@@ -29,6 +40,10 @@ struct Volume<I: Interface>
 	interface: I,
 	capacity: u64,
 	requestq: Queue,
+	/// Device has a write-back cache (and accepts FLUSH)
+	has_flush: bool,
+	/// Maximum number of sectors in a DISCARD request (zero if unsupported, or cleared if the device rejects it)
+	max_discard_sectors: AtomicU32,
 }
 
 impl BlockDevice
@@ -40,15 +55,24 @@ impl BlockDevice
 
 		let requestq = int.get_queue(0, 0).expect("Queue #0 'requestq' missing on virtio block device");
 	
-		let features = int.negotiate_features( VIRTIO_BLK_F_RO );
+		let features = int.negotiate_features( VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_DISCARD );
 		if features & VIRTIO_BLK_F_RO != 0 {
 			// TODO: Need a way of indicating to the upper layers that a volume is read-only
 		}
+		let max_discard_sectors = if features & VIRTIO_BLK_F_DISCARD != 0 {
+				// SAFE: Readable register, present when DISCARD is negotiated
+				unsafe { int.cfg_read_32(VIRTIO_BLK_CFG_MAX_DISCARD_SECTORS) }
+			}
+			else {
+				0
+			};
 		int.set_driver_ok();
 
 		let mut vol = Box::new(Volume {
 			requestq: requestq,
 			capacity: capacity,
+			has_flush: features & VIRTIO_BLK_F_FLUSH != 0,
+			max_discard_sectors: AtomicU32::new(max_discard_sectors),
 			interface: int,
 			});
 
@@ -71,6 +95,25 @@ struct VirtioBlockReq
 }
 unsafe impl ::kernel::lib::POD for VirtioBlockReq {}
 
+#[repr(C)]
+struct VirtioBlockDiscard
+{
+	sector: u64,
+	num_sectors: u32,
+	flags: u32,
+}
+unsafe impl ::kernel::lib::POD for VirtioBlockDiscard {}
+
+fn status_to_result(status: u8) -> Result<(), storage::IoError>
+{
+	match status
+	{
+	VIRTIO_BLK_S_OK => Ok( () ),
+	VIRTIO_BLK_S_UNSUPP => Err( storage::IoError::Unknown("VirtIO: Unsupported request") ),
+	_ => Err( storage::IoError::Unknown("VirtIO: IO error") ),
+	}
+}
+
 const BLOCK_SIZE: usize = 512;
 impl<I: Interface+Send+'static> storage::PhysicalVolume for Volume<I>
 {
@@ -127,10 +170,73 @@ impl<I: Interface+Send+'static> storage::PhysicalVolume for Volume<I>
 		Box::pin(async move { rv })
 	}
 	
-	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()>
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> storage::AsyncIoResult<'a,()>
 	{
-		// Do nothing, no support for TRIM
-		Box::pin(async move { Ok(()) })
+		let max_discard_sectors = self.max_discard_sectors.load(Ordering::Relaxed);
+		if max_discard_sectors == 0 {
+			// Do nothing, no support for DISCARD
+			return Box::pin(async move { Ok(()) });
+		}
+
+		let cmd = VirtioBlockReq {
+			type_: VIRTIO_BLK_T_DISCARD,
+			ioprio: 0,
+			sector: 0,
+			};
+		let mut rv = Ok( () );
+		let mut ofs = 0;
+		while rv.is_ok() && ofs < count
+		{
+			let n = ::core::cmp::min(count - ofs, max_discard_sectors as usize);
+			let seg = VirtioBlockDiscard {
+				sector: blockidx + ofs as u64,
+				num_sectors: n as u32,
+				flags: 0,
+				};
+			let mut status = 0u8;
+			rv = match self.requestq.send_buffers_blocking(&self.interface, &mut[
+					Buffer::Read( ::kernel::lib::as_byte_slice(&cmd) ),
+					Buffer::Read( ::kernel::lib::as_byte_slice(&seg) ),
+					Buffer::Write( ::kernel::lib::as_byte_slice_mut(&mut status) )
+					])
+				{
+				Ok(_) if status == VIRTIO_BLK_S_OK => Ok( () ),
+				Ok(_) => {
+					// Discarding is only a hint, so don't fail the wipe (and stop sending them)
+					log_notice!("VirtIO block: DISCARD failed ({:?}), disabling", status_to_result(status));
+					self.max_discard_sectors.store(0, Ordering::Relaxed);
+					break ;
+					},
+				Err( () ) => Err( storage::IoError::Unknown("VirtIO") ),
+				};
+			ofs += n;
+		}
+
+		Box::pin(async move { rv })
+	}
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		if !self.has_flush {
+			// No write-back cache, writes are complete once acknowledged
+			return Box::pin(async move { Ok(()) });
+		}
+
+		let cmd = VirtioBlockReq {
+			type_: VIRTIO_BLK_T_FLUSH,
+			ioprio: 0,
+			sector: 0,
+			};
+		let mut status = 0u8;
+		let rv = match self.requestq.send_buffers_blocking(&self.interface, &mut[
+				Buffer::Read( ::kernel::lib::as_byte_slice(&cmd) ),
+				Buffer::Write( ::kernel::lib::as_byte_slice_mut(&mut status) )
+				])
+			{
+			Ok(_) => status_to_result(status),
+			Err( () ) => Err( storage::IoError::Unknown("VirtIO") ),
+			};
+
+		Box::pin(async move { rv })
 	}
 
 }
@@ -15,6 +15,23 @@ mod modules {
 	}
 }
 
+/// Check the result of a command against the (optional) expected error, as named by `vfs::Error`'s Debug output
+///
+/// Returns `None` if the command failed as expected, panics if the outcome wasn't what was expected.
+fn check_result<T>(cmd: &str, path: &::vfs::Path, res: ::vfs::Result<T>, expected_err: Option<&str>) -> Option<T>
+{
+    match (res, expected_err)
+    {
+    (Ok(v), None) => Some(v),
+    (Ok(_), Some(exp)) => panic!("`{}`: {:?} succeeded, expected {}", cmd, path, exp),
+    (Err(e), Some(exp)) if format!("{:?}", e) == exp => {
+        log_log!("`{}`: {:?} failed as expected: {:?}", cmd, path, e);
+        None
+        },
+    (Err(e), _) => panic!("`{}`: {:?} failed: {:?}", cmd, path, e),
+    }
+}
+
 fn main()
 {
     ::kernel::threads::init();
@@ -22,10 +39,14 @@ fn main()
     ::kernel::memory::page_cache::init();
     (::kernel::metadevs::storage::S_MODULE.init)();
     (::kernel::hw::mapper_mbr::S_MODULE.init)();
+    (::kernel::hw::mapper_gpt::S_MODULE.init)();
     (::vfs::S_MODULE.init)();
 
     modules::use_mods();
 
+    // Files held open by `open`, named by slot
+    let mut open_files: ::std::collections::HashMap<String, vfs_handle::File> = Default::default();
+
     let cmd_stream = ::std::io::stdin();
     loop
     {
@@ -54,8 +75,17 @@ fn main()
                 "persistent" => virt_storage::OverlayType::Persistent,
                 _ => panic!("`add_disk`: Invalid `overlay` argument"),
                 };
-            log_log!("CMD add_disk {} := {} {:?}", name, path, overlay);
-            match crate::virt_storage::add_volume(name, path.as_ref(), overlay)
+            let block_size = match args.next()
+                {
+                None => 512,
+                Some(v) => match v.parse::<usize>()
+                    {
+                    Ok(v) if v >= 512 && v.is_power_of_two() => v,
+                    _ => panic!("`add_disk`: Invalid `block_size` argument"),
+                    },
+                };
+            log_log!("CMD add_disk {} := {} {:?} bs={}", name, path, overlay, block_size);
+            match crate::virt_storage::add_volume(name, path.as_ref(), overlay, block_size)
             {
             Ok(()) => {},
             Err(e) => panic!("`add_disk`: Unable to open {} as {}: {:?}", path, name, e),
@@ -79,6 +109,25 @@ fn main()
             Err(e) => panic!("`mount`: Unable to mount {} from {}: {:?}", mountpt, volume, e),
             }
             },
+        "unmount" => {
+            let Some(mountpt) = args.next() else { panic!("`unmount`: missing `mountpt` argument") };
+            log_log!("COMMAND: unmount {mountpt:?}");
+            match ::vfs::mount::unmount(mountpt.as_ref())
+            {
+            Ok(_) => {},
+            Err(e) => panic!("`unmount`: Unable to unmount {}: {:?}", mountpt, e),
+            }
+            },
+        "remount" => {
+            let Some(mountpt) = args.next() else { panic!("`remount`: missing `mountpt` argument") };
+            let options = args.next().map(|v| v.split(",").collect::<Vec<_>>()).unwrap_or_default();
+            log_log!("COMMAND: remount {mountpt:?} options={options:?}");
+            match ::vfs::mount::remount(mountpt.as_ref(), &options)
+            {
+            Ok(_) => {},
+            Err(e) => panic!("`remount`: Unable to remount {}: {:?}", mountpt, e),
+            }
+            },
         // List directory
         "ls" => {
             let dir = ::vfs::Path::new( args.next().expect("ls dir") );
@@ -124,6 +173,7 @@ fn main()
         "store" => {
             let src: &::std::path::Path = args.next().expect("`store` src").as_ref();
             let dst: &::vfs::Path = args.next().expect("`store` dst").as_ref();
+            let expected_err = args.next();
             let (dst_dir,dst_name) = dst.split_off_last().expect("`store` dst invalid");
 
             let mut src_handle = match ::std::fs::File::open(src)
@@ -131,64 +181,114 @@ fn main()
                 Ok(h) => h,
                 Err(e) => panic!("`store`: Cannot open source file {}: {:?}", src.display(), e),
                 };
-            let parent_handle = match ::vfs::handle::Dir::open(dst_dir)
-                {
-                Ok(h) => h,
-                Err(e) => panic!("`store`: Cannot open parent directory of {:?}: {:?}", dst, e),
-                };
-            let dst_handle = match parent_handle.create_file(dst_name)
-                {
-                Ok(h) => h,
-                Err(::vfs::Error::AlreadyExists) => match parent_handle.open_child(dst_name)
+            // NOTE: The expected error can come from either opening or writing the destination
+            let res = (|| -> ::vfs::Result<()> {
+                let parent_handle = ::vfs::handle::Dir::open(dst_dir)?;
+                let dst_handle = match parent_handle.create_file(dst_name)
                     {
-                    Ok(h) => match h.into_file(::vfs::handle::FileOpenMode::ExclRW)
-                        {
-                        Ok(h) => {
-                            h.truncate();
-                            h
-                            },
-                        Err(e) => panic!("`store`: Cannot create {:?}: {:?}", dst, e),
+                    Err(::vfs::Error::AlreadyExists) => {
+                        let h = parent_handle.open_child(dst_name)?.into_file(::vfs::handle::FileOpenMode::ExclRW)?;
+                        h.truncate()?;
+                        h
                         },
-                    Err(e) => panic!("`store`: Cannot open existing {:?}: {:?}", dst, e),
-                    },
-                Err(e) => panic!("`store`: Cannot create {:?}: {:?}", dst, e),
-                };
+                    rv => rv?,
+                    };
 
-            let mut ofs = 0;
-            let mut buf = vec![0; 0x2000];
-            loop
-            {
-                use std::io::Read;
-                match src_handle.read(&mut buf)
+                let mut ofs = 0;
+                let mut buf = vec![0; 0x2000];
+                loop
                 {
-                Ok(0) => break,
-                Ok(l) => {
-                    match dst_handle.write(ofs, &buf[..l])
+                    use std::io::Read;
+                    match src_handle.read(&mut buf)
                     {
-                    Ok(v) if v == l => {},
-                    Ok(v) => panic!("`store`: Failed to write to {:?}: Truncated? {} != exp {}", dst, v, l),
-                    Err(e) => panic!("`store`: Failed to write to {:?}: {:?}", dst, e),
+                    Ok(0) => break,
+                    Ok(l) => {
+                        match dst_handle.write(ofs, &buf[..l])?
+                        {
+                        v if v == l => {},
+                        v => panic!("`store`: Failed to write to {:?}: Truncated? {} != exp {}", dst, v, l),
+                        }
+                        ofs += l as u64;
+                        },
+                    Err(e) => panic!("`store`: IO failure reading from local: {:?}", e),
                     }
-                    ofs += l as u64;
-                    },
-                Err(e) => panic!("`store`: IO failure reading from local: {:?}", e),
                 }
+                Ok( () )
+                })();
+            check_result("store", dst, res, expected_err);
+            },
+        // Hold a file open (in the named slot) until `close`
+        "open" => {
+            let slot = args.next().expect("`open` slot");
+            let path: &::vfs::Path = args.next().expect("`open` path").as_ref();
+            log_log!("COMMAND: open {} := {:?}", slot, path);
+            match vfs_handle::File::open(path, vfs_handle::FileOpenMode::SharedRO)
+            {
+            Ok(h) => { open_files.insert(slot.to_owned(), h); },
+            Err(e) => panic!("`open`: Cannot open {:?}: {:?}", path, e),
             }
             },
+        "close" => {
+            let slot = args.next().expect("`close` slot");
+            log_log!("COMMAND: close {}", slot);
+            if open_files.remove(slot).is_none() {
+                panic!("`close`: No file open in slot {:?}", slot);
+            }
+            },
+        // Remove a directory entry
+        "unlink" => {
+            let path: &::vfs::Path = args.next().expect("`unlink` path").as_ref();
+            let expected_err = args.next();
+            let (dir,name) = path.split_off_last().expect("`unlink` path invalid");
+            log_log!("COMMAND: unlink {:?}", path);
+            let res = ::vfs::handle::Dir::open(dir).and_then(|h| h.unlink(name));
+            check_result("unlink", path, res, expected_err);
+            },
+        // Add a new name for an existing file
+        "link" => {
+            let src: &::vfs::Path = args.next().expect("`link` src").as_ref();
+            let dst: &::vfs::Path = args.next().expect("`link` dst").as_ref();
+            let expected_err = args.next();
+            let (dst_dir,dst_name) = dst.split_off_last().expect("`link` dst invalid");
+            log_log!("COMMAND: link {:?} {:?}", src, dst);
+            let res = vfs_handle::Any::open(src).and_then(|node| vfs_handle::Dir::open(dst_dir)?.link(dst_name, &node));
+            check_result("link", dst, res, expected_err);
+            },
+        // Change the identity used for access checks
+        "creds" => {
+            let uid: u32 = args.next().expect("`creds` uid").parse().expect("`creds` uid invalid");
+            let gid: u32 = args.next().expect("`creds` gid").parse().expect("`creds` gid invalid");
+            log_log!("COMMAND: creds {}:{}", uid, gid);
+            ::vfs::Credentials::current().set(uid, gid);
+            },
         // Read a file and check that it's identical to the on-system version
+        // - The remote can be `@slot` to read from a file held by `open`
         "readback" => {
             let local: &::std::path::Path = args.next().expect("`readback` local").as_ref();
-            let remote: &::vfs::Path = args.next().expect("`readback` remote").as_ref();
+            let remote = args.next().expect("`readback` remote");
 
             let mut local_handle = match ::std::fs::File::open(local)
                 {
                 Ok(h) => h,
                 Err(e) => panic!("`readback`: Cannot open local file {}: {:?}", local.display(), e),
                 };
-            let remote_handle = match vfs_handle::File::open(remote, vfs_handle::FileOpenMode::SharedRO)
+            let owned;
+            let remote_handle = match remote.strip_prefix("@")
                 {
-                Ok(h) => h,
-                Err(e) => panic!("`readback`: Cannot open remote file {:?}: {:?}", remote, e),
+                Some(slot) => match open_files.get(slot)
+                    {
+                    Some(h) => h,
+                    None => panic!("`readback`: No file open in slot {:?}", slot),
+                    },
+                None => {
+                    let remote: &::vfs::Path = remote.as_ref();
+                    owned = match vfs_handle::File::open(remote, vfs_handle::FileOpenMode::SharedRO)
+                        {
+                        Ok(h) => h,
+                        Err(e) => panic!("`readback`: Cannot open remote file {:?}: {:?}", remote, e),
+                        };
+                    &owned
+                    },
                 };
             let mut ofs = 0;
             let mut buf_l = vec![0; 0x2000];
@@ -216,6 +316,7 @@ fn main()
             },
         "crc32" => {
             let remote: &::vfs::Path = args.next().expect("`crc32` remote").as_ref();
+            let expected = args.next().map(|v| u32::from_str_radix(v, 16).expect("`crc32` expected value invalid"));
 
             let remote_handle = match vfs_handle::File::open(remote, vfs_handle::FileOpenMode::SharedRO)
                 {
@@ -242,6 +343,9 @@ fn main()
             }
             let crc = digest.finalize();
             println!("{:?}: {} bytes, CRC32={:08x}", remote, ofs, crc);
+            if let Some(exp) = expected {
+                assert!(crc == exp, "`crc32`: {:?} CRC32 mismatch: {:08x} != exp {:08x}", remote, crc, exp);
+            }
             },
 		"hexdump" => {
             let remote: &::vfs::Path = args.next().expect("`hexdump` remote").as_ref();
@@ -16,10 +16,9 @@ pub enum OverlayType
     Persistent,
 }
 
-pub fn add_volume(name: &str, path: &::std::path::Path, overlay_ty: OverlayType) -> Result<()/*::kernel::metadevs::storage::PhysicalVolumeReg*/, ::std::io::Error>
+pub fn add_volume(name: &str, path: &::std::path::Path, overlay_ty: OverlayType, block_size: usize) -> Result<()/*::kernel::metadevs::storage::PhysicalVolumeReg*/, ::std::io::Error>
 {
     use ::std::io::{Seek};
-    let block_size = 512;
 
     let name = name.to_owned();
 
@@ -206,5 +205,15 @@ impl storage::PhysicalVolume for Volume
         let ret = Ok(());
         Box::pin( ::core::future::ready(ret) )
     }
+    fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+    {
+        // Writes go to the overlay if present, so that's the file to sync
+        let ret = match self.write_overlay
+            {
+            Some(ref overlay) => overlay.fp.lock().unwrap().sync_data(),
+            None => self.fp.lock().unwrap().sync_data(),
+            };
+        Box::pin( ::core::future::ready(ret.map_err(cvt_err)) )
+    }
     
 }
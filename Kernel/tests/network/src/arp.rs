@@ -123,3 +123,19 @@ impl super::PacketHandler for ArpHandler
         true
     }
 }
+
+/// Wait for an ARP request, returning the requested address
+#[track_caller]
+pub fn wait_request(fw: &super::TestFramework) -> crate::ipv4::Addr
+{
+    let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
+        {
+        Some(v) => v,
+        None => panic!("No packet recieved"),
+        };
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&data_handle[..]);
+    assert_eq!(ether_hdr.proto, 0x0806, "Expected an ARP packet, got EtherTy {:04x}", ether_hdr.proto);
+    let (pkt, _) = ArpPacket::parse(tail);
+    assert_eq!(pkt.op, 1, "Expected an ARP request");
+    crate::ipv4::Addr(pkt.dst_swaddr)
+}
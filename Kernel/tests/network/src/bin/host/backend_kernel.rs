@@ -32,7 +32,10 @@ pub fn init() {
 pub fn create_interface(stream: Arc<::std::net::UdpSocket>, number: u32, mac: [u8; 6], addr: IpAddr) -> &'static mut ::network::nic::Registration<TestNic> {
     let nic_handle = network::nic::register(mac, TestNic::new(number, stream));
 	// TODO: Make this a command instead
-    network::ipv4::add_interface(mac, addr, 24);
+    // - A zero address leaves the interface to be configured by DHCP
+    if !addr.is_zero() {
+        network::ipv4::add_interface(mac, addr, 24);
+    }
     Box::leak( Box::new(nic_handle) )
 }
 
@@ -44,12 +47,47 @@ pub fn run_blocking<T>(f: impl FnOnce()->T) -> T {
     ::kernel::arch::imp::threads::test_pause_thread(f)
 }
 
+pub fn ipv4_route_add(network: IpAddr, mask: u8, gateway: IpAddr, metric: u16) -> Result<(), String> {
+    ::network::ipv4::routes::add(::network::ipv4::routes::Route {
+        network, mask, gateway, metric,
+        source: ::network::ipv4::Address::zero(),
+        }).map_err(|e| format!("{:?}", e))
+}
+
 pub fn tcp_connect(ip: IpAddr, port: u16) -> ::network::tcp::ConnectionHandle {
     ::network::tcp::ConnectionHandle::connect(::network::Address::Ipv4(ip), port).unwrap()
 }
 pub fn tcp_listen(port: u16) -> ::network::tcp::ServerHandle {
     ::network::tcp::ServerHandle::listen(port).unwrap()
 }
+/// Bind a UDP socket to `port` on all local addresses, accepting datagrams from anywhere
+pub fn udp_bind(port: u16) -> Result<UdpSocket, String> {
+    let any = ::network::udp::RemoteMask {
+        addr: ::network::Address::Ipv4(::network::ipv4::Address::zero()),
+        mask_bits: 0,
+        port: 0,
+        };
+    ::network::udp::SocketHandle::bind(None, port, any)
+        .map(UdpSocket)
+        .map_err(|e| format!("{:?}", e))
+}
+
+pub struct UdpSocket(::network::udp::SocketHandle);
+impl UdpSocket
+{
+    pub fn send_to(&self, ip: IpAddr, port: u16, data: &[u8]) -> Result<usize, String> {
+        self.0.send_to(::network::Address::Ipv4(ip), port, data).map_err(|e| format!("{:?}", e))
+    }
+    /// Receive a datagram, returning its length and source (formatted as `ip:port`)
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, String), String> {
+        match self.0.recv_from(buf)
+        {
+        Ok((len, ::network::Address::Ipv4(a), port)) => Ok( (len, format!("{}:{}", a, port)) ),
+        Ok((len, a, port)) => Ok( (len, format!("{:?}:{}", a, port)) ),
+        Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+}
 
 
 pub struct TestNic
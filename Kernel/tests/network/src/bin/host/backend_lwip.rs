@@ -45,12 +45,32 @@ pub fn run_blocking<T>(f: impl FnOnce()->T) -> T {
     f()
 }
 
+pub fn ipv4_route_add(_network: IpAddr, _mask: u8, _gateway: IpAddr, _metric: u16) -> Result<(), String> {
+    // lwIP routes using the interface netmasks (and a single default gateway), there's no table to add to
+    Err("lwIP doesn't support static routes".to_owned())
+}
+
 pub fn tcp_connect(ip: IpAddr, port: u16) -> client_socket::ClientSocket {
     client_socket::ClientSocket::connect(ip, port).unwrap()
 }
 pub fn tcp_listen(port: u16) -> Server {
     Server( ::lwip::netconn::TcpServer::listen_with_backlog(port, 2).unwrap() )
 }
+pub fn udp_bind(_port: u16) -> Result<UdpSocket, String> {
+    Err("UDP isn't wrapped for lwIP".to_owned())
+}
+
+/// Placeholder (UDP sockets can't be created with lwIP)
+pub enum UdpSocket {}
+impl UdpSocket
+{
+    pub fn send_to(&self, _ip: IpAddr, _port: u16, _data: &[u8]) -> Result<usize, String> {
+        match *self {}
+    }
+    pub fn recv_from(&self, _buf: &mut [u8]) -> Result<(usize, String), String> {
+        match *self {}
+    }
+}
 
 
 pub struct Server(::lwip::netconn::TcpServer);
@@ -108,6 +108,7 @@ fn main()
 	// Monitor stdin for commands
 	let mut tcp_conn_handles = ::std::collections::HashMap::new();
 	let mut tcp_server_handles = ::std::collections::HashMap::new();
+	let mut udp_handles = ::std::collections::HashMap::new();
 	
     loop
     {
@@ -131,6 +132,19 @@ fn main()
 			},
 		"ipv4-add" => {
 			},
+		// Add a route: network, prefix length, gateway, metric
+		"ipv4-route-add" => {
+			let network = backend::parse_addr(it.next().expect("Missing network")).unwrap();
+			let mask: u8 = it.next().unwrap().parse().unwrap();
+			let gateway = backend::parse_addr(it.next().expect("Missing gateway")).unwrap();
+			let metric: u16 = it.next().unwrap().parse().unwrap();
+			log_notice!("ipv4-route-add {:?}/{} via {:?} metric {}", network, mask, gateway, metric);
+			match backend::ipv4_route_add(network, mask, gateway, metric)
+			{
+			Ok(()) => println!("OK"),
+			Err(e) => println!("ERROR: ipv4-route-add failed: {}", e),
+			}
+			},
 		// Listen on a port/interface
 		"tcp-listen" => {
 			let index: usize = it.next().unwrap().parse().unwrap();
@@ -170,6 +184,17 @@ fn main()
 			h.send_data(&bytes).unwrap();
 			println!("OK");
 			},
+		// Send `len` bytes of generated data (byte `i` is `i as u8`)
+		"tcp-send-fill" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let len: usize = it.next().unwrap().parse().unwrap();
+			let bytes: Vec<u8> = (0 .. len).map(|i| i as u8).collect();
+			let h = &tcp_conn_handles[&index];
+			log_notice!("tcp-send-fill {} {}", index, len);
+			let sent = h.send_data(&bytes).unwrap();
+			assert_eq!(sent, len, "Not all data fit in the TX buffer");
+			println!("OK");
+			},
 		"tcp-recv-assert" => {
 			let index: usize = it.next().unwrap().parse().unwrap();
 			let read_size: usize = it.next().unwrap().parse().unwrap();
@@ -184,6 +209,44 @@ fn main()
 			assert_eq!(&buf[..len], &exp_bytes[..]);
 			println!("OK");
 			},
+		// Bind a UDP socket to a local port
+		"udp-bind" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let port : u16   = it.next().unwrap().parse().unwrap();
+			log_notice!("udp-bind {} = *:{}", index, port);
+			match backend::udp_bind(port)
+			{
+			Ok(h) => {
+				udp_handles.insert(index, h);
+				println!("OK");
+				},
+			Err(e) => println!("ERROR: udp-bind failed: {}", e),
+			}
+			},
+		"udp-send" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let ip = backend::parse_addr(it.next().expect("Missing IP")).unwrap();
+			let port: u16 = it.next().unwrap().parse().unwrap();
+			let bytes = parse_hex_bytes(it.next().unwrap()).unwrap();
+			log_notice!("udp-send {} {:?}", index, bytes);
+			let sent = udp_handles[&index].send_to(ip, port, &bytes).unwrap();
+			assert_eq!(sent, bytes.len());
+			println!("OK");
+			},
+		"udp-recv-assert" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let src_ip = it.next().expect("Missing IP");
+			let src_port: u16 = it.next().unwrap().parse().unwrap();
+			let exp_bytes = parse_hex_bytes(it.next().unwrap()).unwrap();
+			// - Receive a datagram, check that its source and contents equal the expected values
+			// NOTE: No wait
+			log_notice!("udp-recv-assert {} {}:{} == {:?}", index, src_ip, src_port, exp_bytes);
+			let mut buf = vec![0; 1500];
+			let (len, src) = udp_handles[&index].recv_from(&mut buf).unwrap();
+			assert_eq!(src, format!("{}:{}", src_ip, src_port));
+			assert_eq!(&buf[..len], &exp_bytes[..]);
+			println!("OK");
+			},
 		_ => panic!("ERROR: Unknown command '{}'", cmd),
 		}
     }
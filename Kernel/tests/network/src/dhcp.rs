@@ -0,0 +1,128 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/dhcp.rs
+//! DHCP infrastructure (the framework acts as the server)
+use crate::ipv4::Addr as IpAddr4;
+
+#[cfg(test)]
+mod tests;
+
+pub const PORT_SERVER: u16 = 67;
+pub const PORT_CLIENT: u16 = 68;
+
+pub const OPT_SUBNET_MASK: u8 = 1;
+pub const OPT_ROUTER: u8 = 3;
+pub const OPT_REQUESTED_ADDR: u8 = 50;
+pub const OPT_LEASE_TIME: u8 = 51;
+pub const OPT_MESSAGE_TYPE: u8 = 53;
+pub const OPT_SERVER_ID: u8 = 54;
+
+pub const DHCPDISCOVER: u8 = 1;
+pub const DHCPOFFER: u8 = 2;
+pub const DHCPREQUEST: u8 = 3;
+pub const DHCPACK: u8 = 5;
+pub const DHCPNAK: u8 = 6;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// A client message
+#[derive(Debug)]
+pub struct Message
+{
+    pub op: u8,
+    pub xid: u32,
+    pub flags: u16,
+    pub ciaddr: IpAddr4,
+    pub chaddr: [u8; 6],
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+impl Message
+{
+    pub fn parse(buf: &[u8]) -> Message
+    {
+        assert!(buf.len() >= 240, "DHCP message too short ({} bytes)", buf.len());
+        assert_eq!(buf[236..240], MAGIC_COOKIE, "Bad magic cookie");
+        let mut options = Vec::new();
+        let mut opts = &buf[240..];
+        loop
+        {
+            match opts
+            {
+            [] | [255, ..] => break,
+            [0, rest @ ..] => opts = rest,
+            [ty, len, rest @ ..] => {
+                let len = *len as usize;
+                assert!(rest.len() >= len, "Option {} overruns the message", ty);
+                options.push( (*ty, rest[..len].to_vec()) );
+                opts = &rest[len..];
+                },
+            [_] => panic!("Truncated option"),
+            }
+        }
+        Message {
+            op: buf[0],
+            xid: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            flags: u16::from_be_bytes([buf[10], buf[11]]),
+            ciaddr: IpAddr4([buf[12], buf[13], buf[14], buf[15]]),
+            chaddr: [buf[28], buf[29], buf[30], buf[31], buf[32], buf[33]],
+            options,
+            }
+    }
+
+    pub fn option(&self, ty: u8) -> Option<&[u8]>
+    {
+        self.options.iter().find(|v| v.0 == ty).map(|v| &v.1[..])
+    }
+    pub fn msg_type(&self) -> u8
+    {
+        self.option(OPT_MESSAGE_TYPE).expect("No message type option")[0]
+    }
+}
+
+/// Wait for a message from the client, returning the destination IP and the message
+#[track_caller]
+pub fn wait_client_message(fw: &crate::TestFramework, timeout: std::time::Duration) -> (IpAddr4, Message)
+{
+    let data_handle = match fw.wait_packet(timeout)
+        {
+        Some(v) => v,
+        None => panic!("No packet recieved"),
+        };
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&data_handle[..]);
+    assert_eq!(ether_hdr.proto, 0x0800, "Incorrect ethernet protocol value: {:04x}", ether_hdr.proto);
+    let (ip_hdr, _, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, 17, "Expected a UDP packet");
+    let tail = &tail[..ip_hdr.total_legnth as usize - 20];
+    let (udp_hdr, data) = crate::udp::Header::parse(tail);
+    assert_eq!(udp_hdr.calculate_checksum_v4(IpAddr4(ip_hdr.src_addr), IpAddr4(ip_hdr.dst_addr), data), 0, "UDP checksum incorrect");
+    assert_eq!(udp_hdr.src_port, PORT_CLIENT);
+    assert_eq!(udp_hdr.dst_port, PORT_SERVER);
+    let msg = Message::parse(data);
+    assert_eq!(msg.op, 1, "Expected a BOOTREQUEST");
+    assert_eq!(msg.chaddr, crate::REMOTE_MAC, "Incorrect client hardware address");
+    (IpAddr4(ip_hdr.dst_addr), msg)
+}
+
+/// Send a reply to the client (broadcast from `server`)
+pub fn send_reply(fw: &crate::TestFramework, server: IpAddr4, xid: u32, yiaddr: IpAddr4, msg_type: u8, options: &[(u8, &[u8])])
+{
+    let mut msg = vec![0; 240];
+    msg[0] = 2; // BOOTREPLY
+    msg[1] = 1; // Ethernet
+    msg[2] = 6;
+    msg[4..8].copy_from_slice(&xid.to_be_bytes());
+    msg[16..20].copy_from_slice(&yiaddr.0);
+    msg[20..24].copy_from_slice(&server.0);
+    msg[28..34].copy_from_slice(&crate::REMOTE_MAC);
+    msg[236..240].copy_from_slice(&MAGIC_COOKIE);
+    msg.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, msg_type]);
+    for (ty, val) in options
+    {
+        msg.push(*ty);
+        msg.push(val.len() as u8);
+        msg.extend_from_slice(val);
+    }
+    msg.push(255);
+    crate::udp::send_packet_raw(fw, server, IpAddr4([255,255,255,255]), PORT_SERVER, PORT_CLIENT, &msg);
+}
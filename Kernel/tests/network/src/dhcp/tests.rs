@@ -0,0 +1,101 @@
+//! DHCP client tests
+use super::*;
+use std::time::Duration;
+
+/// Address handed out by the server
+const CLIENT_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+/// The framework's address, which also acts as the server
+const SERVER_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+const ROUTER_ADDR: IpAddr4 = IpAddr4([192,168,1,254]);
+
+/// Run the DISCOVER/OFFER/REQUEST/ACK exchange, returning the REQUEST's transaction ID
+#[track_caller]
+fn acquire_lease(fw: &crate::TestFramework, lease_time: u32) -> u32
+{
+    // The client waits a short time before starting
+    let (dst, discover) = wait_client_message(fw, Duration::from_millis(3000));
+    assert_eq!(dst, IpAddr4([255,255,255,255]), "DISCOVER should be broadcast");
+    assert_eq!(discover.msg_type(), DHCPDISCOVER);
+    assert_eq!(discover.ciaddr, IpAddr4([0,0,0,0]));
+    assert!(discover.flags & 0x8000 != 0, "Broadcast flag should be set");
+
+    let options: &[(u8, &[u8])] = &[
+        (OPT_SERVER_ID, &SERVER_ADDR.0),
+        (OPT_SUBNET_MASK, &[255,255,255,0]),
+        (OPT_ROUTER, &ROUTER_ADDR.0),
+        (OPT_LEASE_TIME, &lease_time.to_be_bytes()),
+        ];
+    send_reply(fw, SERVER_ADDR, discover.xid, CLIENT_ADDR, DHCPOFFER, options);
+
+    let (dst, request) = wait_client_message(fw, Duration::from_millis(1000));
+    assert_eq!(dst, IpAddr4([255,255,255,255]), "REQUEST should be broadcast");
+    assert_eq!(request.msg_type(), DHCPREQUEST);
+    assert_eq!(request.xid, discover.xid, "REQUEST should use the DISCOVER's transaction ID");
+    assert_eq!(request.option(OPT_REQUESTED_ADDR), Some(&CLIENT_ADDR.0[..]), "Requested address mismatch");
+    assert_eq!(request.option(OPT_SERVER_ID), Some(&SERVER_ADDR.0[..]), "Server identifier mismatch");
+
+    send_reply(fw, SERVER_ADDR, request.xid, CLIENT_ADDR, DHCPACK, options);
+    request.xid
+}
+
+/// A lease configures the address and the default route
+#[test]
+fn lease()
+{
+    let fw = crate::TestFramework::new_unconfigured("dhcp_lease");
+    acquire_lease(&fw, 3600);
+
+    // The address is usable
+    let testblob = b"Hello DHCP";
+    crate::icmp::send_packet_raw(&fw, SERVER_ADDR, CLIENT_ADDR, crate::icmp::TYPE_ECHO_REQUEST, 0, [0x12,0x34, 0x00,0x01], testblob);
+    let (_, data) = crate::icmp::wait_rx_check(&fw, CLIENT_ADDR, SERVER_ADDR, crate::icmp::TYPE_ECHO_REPLY, 0);
+    assert_eq!(&data[..], &testblob[..], "Data mismatch");
+
+    // Remote traffic goes via the router
+    fw.send_command("tcp-connect 0 10.1.2.3 80");
+    assert_eq!(crate::arp::wait_request(&fw), ROUTER_ADDR, "Should resolve the router");
+}
+
+/// The lease is renewed (by unicast to the server) once T1 passes
+#[test]
+fn renew()
+{
+    let fw = crate::TestFramework::new_unconfigured("dhcp_renew");
+    let xid = acquire_lease(&fw, 4);
+
+    // T1 is half of the lease time
+    let (dst, request) = wait_client_message(&fw, Duration::from_millis(3000));
+    assert_eq!(dst, SERVER_ADDR, "Renewal should be sent to the server");
+    assert_eq!(request.msg_type(), DHCPREQUEST);
+    assert_eq!(request.ciaddr, CLIENT_ADDR, "Renewal should include the client address");
+    assert_eq!(request.option(OPT_REQUESTED_ADDR), None, "Renewal must not include a requested address");
+    assert_ne!(request.xid, xid, "Renewal should be a new transaction");
+
+    send_reply(&fw, SERVER_ADDR, request.xid, CLIENT_ADDR, DHCPACK, &[
+        (OPT_SERVER_ID, &SERVER_ADDR.0),
+        (OPT_SUBNET_MASK, &[255,255,255,0]),
+        (OPT_LEASE_TIME, &3600u32.to_be_bytes()),
+        ]);
+
+    // Still configured
+    crate::icmp::send_packet_raw(&fw, SERVER_ADDR, CLIENT_ADDR, crate::icmp::TYPE_ECHO_REQUEST, 0, [0x12,0x34, 0x00,0x02], b"");
+    crate::icmp::wait_rx_check(&fw, CLIENT_ADDR, SERVER_ADDR, crate::icmp::TYPE_ECHO_REPLY, 0);
+}
+
+/// A NAK restarts discovery
+#[test]
+fn nak()
+{
+    let fw = crate::TestFramework::new_unconfigured("dhcp_nak");
+
+    let (_, discover) = wait_client_message(&fw, Duration::from_millis(3000));
+    assert_eq!(discover.msg_type(), DHCPDISCOVER);
+    send_reply(&fw, SERVER_ADDR, discover.xid, CLIENT_ADDR, DHCPOFFER, &[(OPT_SERVER_ID, &SERVER_ADDR.0)]);
+    let (_, request) = wait_client_message(&fw, Duration::from_millis(1000));
+    assert_eq!(request.msg_type(), DHCPREQUEST);
+    send_reply(&fw, SERVER_ADDR, request.xid, IpAddr4([0,0,0,0]), DHCPNAK, &[(OPT_SERVER_ID, &SERVER_ADDR.0)]);
+
+    let (_, discover2) = wait_client_message(&fw, Duration::from_millis(1000));
+    assert_eq!(discover2.msg_type(), DHCPDISCOVER, "Should restart with a DISCOVER");
+    assert_ne!(discover2.xid, discover.xid, "Should be a new transaction");
+}
@@ -0,0 +1,94 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/icmp.rs
+//! ICMP tests and infrastructure
+use crate::ipv4::Addr as IpAddr4;
+
+#[cfg(test)]
+mod tests;
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DEST_UNREACHABLE: u8 = 3;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+pub const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+
+#[derive(Copy,Clone)]
+#[derive(Debug)]
+#[derive(serde_derive::Deserialize,serde_derive::Serialize)]
+pub struct Header
+{
+    pub ty: u8,
+    pub code: u8,
+    pub checksum: u16,
+    /// Type-specific data (e.g. identifier and sequence for echo)
+    pub rest: [u8; 4],
+}
+impl Header
+{
+    /// Parse an ICMP header, returning the data
+    pub fn parse(mut buf: &[u8]) -> (Self, &[u8]) {
+        let rv: Self = crate::des_be(&mut buf).expect("Failed to parse ICMP header");
+        (rv, buf)
+    }
+    pub fn encode(&self) -> [u8; 8]
+    {
+        let mut rv = [0; 8];
+        crate::ser_be(&mut std::io::Cursor::new(&mut rv[..]), self);
+        rv
+    }
+    pub fn calculate_checksum(&self, data: &[u8]) -> u16
+    {
+        fn u16be(a: u8, b: u8) -> u16 {
+            (a as u16) << 8 | (b as u16)
+        }
+        let hdr_enc = self.encode();
+        let it_header = hdr_enc.chunks(2).map(|v| u16be(v[0], v[1]));
+        // Odd trailing byte is padded with zero
+        let it_data = data.chunks(2).map(|v| u16be(v[0], *v.get(1).unwrap_or(&0)));
+        crate::ipv4::calculate_ip_checksum(it_header.chain(it_data))
+    }
+}
+
+pub fn send_packet_raw(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, ty: u8, code: u8, rest: [u8; 4], data: &[u8])
+{
+    let mut header = Header { ty, code, checksum: 0, rest, };
+    header.checksum = header.calculate_checksum(data);
+    let icmp_hdr = header.encode();
+    let ip_hdr = {
+        let mut h = crate::ipv4::Header::new_simple(src, dst, 1, icmp_hdr.len() + data.len());
+        h.set_checksum();
+        h.encode()
+        };
+    fw.send_ethernet_direct(0x0800, &[&ip_hdr, &icmp_hdr, data]);
+}
+
+/// Wait for an ICMP message from `src` to `dst` and check its type and code, returning the type-specific header data and body
+#[track_caller]
+pub fn wait_rx_check(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, ty: u8, code: u8) -> ([u8; 4], Vec<u8>)
+{
+    let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
+        {
+        Some(v) => v,
+        None => panic!("No packet recieved"),
+        };
+    let tail = &data_handle[..];
+    // 1. Check the ethernet header
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(tail);
+    assert_eq!(ether_hdr.proto, 0x0800, "Incorrect ethernet protocol value: {:04x}", ether_hdr.proto);
+    // 2. Check the IPv4 header
+    let (ip_hdr, ip_options, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, 1);
+    assert_eq!(crate::ipv4::Addr(ip_hdr.src_addr), src);
+    assert_eq!(crate::ipv4::Addr(ip_hdr.dst_addr), dst);
+    assert_eq!(ip_options.len(), 0);
+    let tail = &tail[..ip_hdr.total_legnth as usize - 20];
+    // 3. Check the ICMP header
+    let (icmp_hdr, tail) = Header::parse(tail);
+    assert_eq!(icmp_hdr.calculate_checksum(tail), 0, "ICMP checksum incorrect");
+    assert!(icmp_hdr.ty == ty, "ICMP type mismatch: Expected {} got {}", ty, icmp_hdr.ty);
+    assert!(icmp_hdr.code == code, "ICMP code mismatch: Expected {} got {}", code, icmp_hdr.code);
+    (icmp_hdr.rest, tail.to_owned())
+}
@@ -0,0 +1,110 @@
+//! ICMP tests
+use crate::ipv4::Addr as IpAddr4;
+use super::*;
+
+const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+/// Echo requests are answered with the same identifier/sequence and data
+#[test]
+fn echo()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("icmp_echo");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    let testblob = b"Ping data, with an odd length!";
+    send_packet_raw(&fw, LOCAL_ADDR, REMOTE_ADDR, TYPE_ECHO_REQUEST, 0, [0x12,0x34, 0x00,0x01], testblob);
+    let (rest, data) = wait_rx_check(&fw, REMOTE_ADDR, LOCAL_ADDR, TYPE_ECHO_REPLY, 0);
+    assert_eq!(rest, [0x12,0x34, 0x00,0x01], "Identifier/sequence mismatch");
+    assert_eq!(&data[..], &testblob[..], "Data mismatch");
+}
+
+/// Packets for an unknown IP protocol generate a protocol unreachable
+#[test]
+fn protocol_unreachable()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("icmp_protocol_unreachable");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    let body = [1,2,3,4,5,6,7,8,9,10];
+    let ip_hdr = {
+        let mut h = crate::ipv4::Header::new_simple(LOCAL_ADDR, REMOTE_ADDR, 0xFD, body.len());
+        h.set_checksum();
+        h.encode()
+        };
+    fw.send_ethernet_direct(0x0800, &[&ip_hdr, &body]);
+
+    let (_, data) = wait_rx_check(&fw, REMOTE_ADDR, LOCAL_ADDR, TYPE_DEST_UNREACHABLE, CODE_PROTOCOL_UNREACHABLE);
+    // Quotes the IP header and the first 8 bytes of the body
+    assert_eq!(&data[..20], &ip_hdr[..], "Quoted header mismatch");
+    assert_eq!(&data[20..], &body[..8], "Quoted data mismatch");
+}
+
+/// UDP datagrams to a closed port generate a port unreachable
+#[test]
+fn udp_port_unreachable()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("icmp_udp_port_unreachable");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    crate::udp::send_packet_raw(&fw, LOCAL_ADDR, REMOTE_ADDR, 11200, 9999, b"Hello");
+
+    let (_, data) = wait_rx_check(&fw, REMOTE_ADDR, LOCAL_ADDR, TYPE_DEST_UNREACHABLE, CODE_PORT_UNREACHABLE);
+    let (ip_hdr, _, tail) = crate::ipv4::Header::parse(&data);
+    assert_eq!(ip_hdr.protocol, 17);
+    assert_eq!(IpAddr4(ip_hdr.src_addr), LOCAL_ADDR);
+    assert_eq!(IpAddr4(ip_hdr.dst_addr), REMOTE_ADDR);
+    assert_eq!(tail.len(), 8, "Expected exactly the UDP header to be quoted");
+    assert_eq!(&tail[..4], &[0x2B,0xC0, 0x27,0x0F], "Quoted ports mismatch");
+}
+
+/// A port unreachable in response to a SYN aborts the connection attempt
+#[test]
+fn tcp_connect_refused()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("icmp_tcp_connect_refused");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    fw.send_command(&format!("tcp-connect 0 {LOCAL_ADDR} 80"));
+
+    // Get the SYN, and quote it back in an ICMP error
+    let syn_pkt = fw.wait_packet(std::time::Duration::from_millis(1000)).expect("No SYN recieved");
+    let (_, ip_pkt) = crate::ethernet::EthernetHeader::parse(&syn_pkt);
+    let (ip_hdr, _, tcp_pkt) = crate::ipv4::Header::parse(ip_pkt);
+    assert_eq!(ip_hdr.protocol, 6);
+    let (tcp_hdr, _, _) = crate::tcp::Header::parse(tcp_pkt);
+    assert_eq!(tcp_hdr.flags, crate::tcp::TCP_SYN);
+    send_packet_raw(&fw, LOCAL_ADDR, REMOTE_ADDR, TYPE_DEST_UNREACHABLE, CODE_PORT_UNREACHABLE, [0; 4], &ip_pkt[..20 + 8]);
+    ::std::thread::sleep(::std::time::Duration::from_millis(50));
+
+    // A late SYN,ACK should now be ignored (the connection has been aborted)
+    let hdr = crate::tcp::Header {
+        src_port: tcp_hdr.dst_port,
+        dst_port: tcp_hdr.src_port,
+        seq: 0x1000,
+        ack: tcp_hdr.seq + 1,
+        data_ofs: (20/4) << 4,
+        flags: crate::tcp::TCP_SYN|crate::tcp::TCP_ACK,
+        window: 0x1000,
+        checksum: 0,
+        urg_ptr: 0,
+        };
+    crate::tcp::send_packet_raw(&fw, LOCAL_ADDR, REMOTE_ADDR, hdr, &[], &[]);
+    match fw.wait_packet(std::time::Duration::from_millis(100))
+    {
+    Some(_) => panic!("Unexpected packet"),
+    None => {},
+    }
+}
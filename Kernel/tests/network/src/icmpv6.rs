@@ -0,0 +1,87 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/icmpv6.rs
+//! ICMPv6 (and NDP) tests and infrastructure
+use crate::ipv6::Addr as IpAddr6;
+use crate::icmp::Header;
+
+#[cfg(test)]
+mod tests;
+
+pub const TYPE_DEST_UNREACHABLE: u8 = 1;
+pub const TYPE_PARAMETER_PROBLEM: u8 = 4;
+pub const TYPE_ECHO_REQUEST: u8 = 128;
+pub const TYPE_ECHO_REPLY: u8 = 129;
+pub const TYPE_NEIGHBOUR_SOLICITATION: u8 = 135;
+pub const TYPE_NEIGHBOUR_ADVERTISEMENT: u8 = 136;
+
+pub const CODE_PORT_UNREACHABLE: u8 = 4;
+pub const CODE_UNKNOWN_NEXT_HEADER: u8 = 1;
+
+pub fn calculate_checksum(header: &Header, src: IpAddr6, dst: IpAddr6, data: &[u8]) -> u16
+{
+    fn u16be(a: u8, b: u8) -> u16 {
+        (a as u16) << 8 | (b as u16)
+    }
+    let pseudo = crate::ipv6::pseudo_header(src, dst, 58, 8 + data.len());
+    let hdr_enc = header.encode();
+    let it_header = hdr_enc.chunks(2).map(|v| u16be(v[0], v[1]));
+    // Odd trailing byte is padded with zero
+    let it_data = data.chunks(2).map(|v| u16be(v[0], *v.get(1).unwrap_or(&0)));
+    crate::ipv4::calculate_ip_checksum(pseudo.into_iter().chain(it_header).chain(it_data))
+}
+
+pub fn send_packet_raw(fw: &crate::TestFramework, src: IpAddr6, dst: IpAddr6, ty: u8, code: u8, rest: [u8; 4], data: &[u8])
+{
+    send_packet_raw_hop_limit(fw, 255, src, dst, Header { ty, code, checksum: 0, rest, }, data);
+}
+/// Send a message with a specific IPv6 hop limit (NDP requires 255)
+pub fn send_packet_raw_hop_limit(fw: &crate::TestFramework, hop_limit: u8, src: IpAddr6, dst: IpAddr6, mut header: Header, data: &[u8])
+{
+    header.checksum = calculate_checksum(&header, src, dst, data);
+    let icmp_hdr = header.encode();
+    let mut ip_hdr = crate::ipv6::Header::new_simple(src, dst, 58, icmp_hdr.len() + data.len());
+    ip_hdr.hop_limit = hop_limit;
+    fw.send_ethernet_direct(0x86DD, &[&ip_hdr.encode(), &icmp_hdr, data]);
+}
+
+/// Send a neighbour solicitation for `target` (with our MAC as the source link-layer address)
+pub fn send_solicitation(fw: &crate::TestFramework, src: IpAddr6, target: IpAddr6)
+{
+    send_solicitation_hop_limit(fw, 255, src, target);
+}
+pub fn send_solicitation_hop_limit(fw: &crate::TestFramework, hop_limit: u8, src: IpAddr6, target: IpAddr6)
+{
+    let mut data = target.0.to_vec();
+    data.extend_from_slice(&[1, 1]);
+    data.extend_from_slice(&crate::LOCAL_MAC);
+    let header = Header { ty: TYPE_NEIGHBOUR_SOLICITATION, code: 0, checksum: 0, rest: [0; 4], };
+    send_packet_raw_hop_limit(fw, hop_limit, src, target.solicited_node(), header, &data);
+}
+
+/// Wait for an ICMPv6 message from `src` to `dst` and check its type and code, returning the type-specific header data and body
+#[track_caller]
+pub fn wait_rx_check(fw: &crate::TestFramework, src: IpAddr6, dst: IpAddr6, ty: u8, code: u8) -> ([u8; 4], Vec<u8>)
+{
+    let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
+        {
+        Some(v) => v,
+        None => panic!("No packet recieved"),
+        };
+    let tail = &data_handle[..];
+    // 1. Check the ethernet header
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(tail);
+    assert_eq!(ether_hdr.proto, 0x86DD, "Incorrect ethernet protocol value: {:04x}", ether_hdr.proto);
+    // 2. Check the IPv6 header
+    let (ip_hdr, tail) = crate::ipv6::Header::parse(tail);
+    assert_eq!(ip_hdr.next_header, 58);
+    assert_eq!(IpAddr6(ip_hdr.src_addr), src);
+    assert_eq!(IpAddr6(ip_hdr.dst_addr), dst);
+    // 3. Check the ICMPv6 header
+    let (icmp_hdr, tail) = Header::parse(tail);
+    assert_eq!(calculate_checksum(&icmp_hdr, src, dst, tail), 0, "ICMPv6 checksum incorrect");
+    assert!(icmp_hdr.ty == ty, "ICMPv6 type mismatch: Expected {} got {}", ty, icmp_hdr.ty);
+    assert!(icmp_hdr.code == code, "ICMPv6 code mismatch: Expected {} got {}", code, icmp_hdr.code);
+    (icmp_hdr.rest, tail.to_owned())
+}
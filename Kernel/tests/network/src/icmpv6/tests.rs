@@ -0,0 +1,79 @@
+//! ICMPv6 and NDP tests
+use crate::ipv6::Addr as IpAddr6;
+use super::*;
+
+fn remote_addr() -> IpAddr6 {
+    IpAddr6::link_local(crate::REMOTE_MAC)
+}
+fn local_addr() -> IpAddr6 {
+    IpAddr6::link_local(crate::LOCAL_MAC)
+}
+
+/// Solicitations for the link-local address (derived from the MAC) are answered
+#[test]
+fn neighbour_solicitation()
+{
+    let fw = crate::TestFramework::new("icmpv6_neighbour_solicitation");
+
+    send_solicitation(&fw, local_addr(), remote_addr());
+    let (rest, data) = wait_rx_check(&fw, remote_addr(), local_addr(), TYPE_NEIGHBOUR_ADVERTISEMENT, 0);
+    assert_eq!(rest[0] & 0x40, 0x40, "Solicited flag not set");
+    assert_eq!(&data[..16], &remote_addr().0[..], "Target address mismatch");
+    // Target link-layer address option
+    assert_eq!(&data[16..18], &[2, 1], "Missing target link-layer address");
+    assert_eq!(&data[18..24], &crate::REMOTE_MAC[..], "Target link-layer address mismatch");
+}
+
+/// Solicitations that could have been forwarded by a router (hop limit below 255) are ignored
+#[test]
+fn neighbour_solicitation_hop_limit()
+{
+    let fw = crate::TestFramework::new("icmpv6_neighbour_solicitation_hop_limit");
+
+    send_solicitation_hop_limit(&fw, 64, local_addr(), remote_addr());
+    match fw.wait_packet(std::time::Duration::from_millis(100))
+    {
+    Some(_) => panic!("Unexpected packet"),
+    None => {},
+    }
+
+    // The same solicitation with the correct hop limit is still answered
+    send_solicitation(&fw, local_addr(), remote_addr());
+    wait_rx_check(&fw, remote_addr(), local_addr(), TYPE_NEIGHBOUR_ADVERTISEMENT, 0);
+}
+
+/// Echo requests are answered with the same identifier/sequence and data
+#[test]
+fn echo()
+{
+    let fw = crate::TestFramework::new("icmpv6_echo");
+
+    // Populate the neighbour cache
+    send_solicitation(&fw, local_addr(), remote_addr());
+    wait_rx_check(&fw, remote_addr(), local_addr(), TYPE_NEIGHBOUR_ADVERTISEMENT, 0);
+
+    let testblob = b"Ping data, with an odd length!";
+    send_packet_raw(&fw, local_addr(), remote_addr(), TYPE_ECHO_REQUEST, 0, [0x12,0x34, 0x00,0x01], testblob);
+    let (rest, data) = wait_rx_check(&fw, remote_addr(), local_addr(), TYPE_ECHO_REPLY, 0);
+    assert_eq!(rest, [0x12,0x34, 0x00,0x01], "Identifier/sequence mismatch");
+    assert_eq!(&data[..], &testblob[..], "Data mismatch");
+}
+
+/// Packets with an unknown next header generate a parameter problem
+#[test]
+fn unknown_next_header()
+{
+    let fw = crate::TestFramework::new("icmpv6_unknown_next_header");
+
+    send_solicitation(&fw, local_addr(), remote_addr());
+    wait_rx_check(&fw, remote_addr(), local_addr(), TYPE_NEIGHBOUR_ADVERTISEMENT, 0);
+
+    let body = [1,2,3,4,5,6,7,8,9,10];
+    let ip_hdr = crate::ipv6::Header::new_simple(local_addr(), remote_addr(), 0xFD, body.len()).encode();
+    fw.send_ethernet_direct(0x86DD, &[&ip_hdr, &body]);
+
+    let (rest, data) = wait_rx_check(&fw, remote_addr(), local_addr(), TYPE_PARAMETER_PROBLEM, CODE_UNKNOWN_NEXT_HEADER);
+    assert_eq!(rest, [0,0,0,6], "Pointer should refer to the next header field");
+    assert_eq!(&data[..40], &ip_hdr[..], "Quoted header mismatch");
+    assert_eq!(&data[40..], &body[..], "Quoted data mismatch");
+}
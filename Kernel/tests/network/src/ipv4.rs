@@ -2,6 +2,9 @@
 use std::io::Cursor;
 use std::mem::size_of;
 
+#[cfg(test)]
+mod tests;
+
 #[derive(Copy,Clone,PartialEq)]
 pub struct Addr(pub [u8; 4]);
 impl ::core::fmt::Debug for Addr {
@@ -101,3 +104,17 @@ pub fn prime_arp(fw: &crate::TestFramework, dst: Addr, src: Addr)
     // Short sleep for processing
     ::std::thread::sleep(::std::time::Duration::new(0,250*1000));
 }
+
+/// Send a single fragment of an IPv4 packet (`ofs` is in bytes, and must be a multiple of 8)
+pub fn send_fragment(fw: &crate::TestFramework, src: Addr, dst: Addr, proto: u8, identification: u16, ofs: usize, more_fragments: bool, data: &[u8])
+{
+    assert!(ofs % 8 == 0);
+    let ip_hdr = {
+        let mut h = Header::new_simple(src, dst, proto, data.len());
+        h.identification = identification;
+        h.fragment_info = (if more_fragments { 1 << 13 } else { 0 }) | (ofs / 8) as u16;
+        h.set_checksum();
+        h.encode()
+        };
+    fw.send_ethernet_direct(0x0800, &[&ip_hdr, data]);
+}
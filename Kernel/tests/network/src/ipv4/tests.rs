@@ -0,0 +1,150 @@
+//! IPv4 tests (fragmentation and reassembly)
+use super::*;
+use crate::icmp;
+
+const REMOTE_ADDR: Addr = Addr([192,168,1,1]);
+const LOCAL_ADDR: Addr = Addr([192,168,1,2]);
+
+/// Build an ICMP echo request (header and data) ready to be split into fragments
+fn make_echo_request(rest: [u8; 4], data: &[u8]) -> Vec<u8>
+{
+    let mut header = icmp::Header { ty: icmp::TYPE_ECHO_REQUEST, code: 0, checksum: 0, rest, };
+    header.checksum = header.calculate_checksum(data);
+    let mut rv = header.encode().to_vec();
+    rv.extend_from_slice(data);
+    rv
+}
+
+/// A fragmented echo request (sent out of order) is reassembled and answered
+#[test]
+fn reassembly()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_reassembly");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    let testblob: Vec<u8> = (0 .. 100u8).collect();
+    let msg = make_echo_request([0x43,0x21, 0x00,0x01], &testblob);
+    // Last fragment first, then the first
+    send_fragment(&fw, LOCAL_ADDR, REMOTE_ADDR, 1, 0x1234, 48, false, &msg[48..]);
+    send_fragment(&fw, LOCAL_ADDR, REMOTE_ADDR, 1, 0x1234, 0, true, &msg[..48]);
+
+    let (rest, data) = icmp::wait_rx_check(&fw, REMOTE_ADDR, LOCAL_ADDR, icmp::TYPE_ECHO_REPLY, 0);
+    assert_eq!(rest, [0x43,0x21, 0x00,0x01], "Identifier/sequence mismatch");
+    assert_eq!(&data[..], &testblob[..], "Data mismatch");
+}
+
+/// An incomplete datagram is not delivered
+#[test]
+fn reassembly_incomplete()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_reassembly_incomplete");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    let testblob: Vec<u8> = (0 .. 100u8).collect();
+    let msg = make_echo_request([0x43,0x21, 0x00,0x02], &testblob);
+    // Missing the middle fragment
+    send_fragment(&fw, LOCAL_ADDR, REMOTE_ADDR, 1, 0x1235, 0, true, &msg[..32]);
+    send_fragment(&fw, LOCAL_ADDR, REMOTE_ADDR, 1, 0x1235, 64, false, &msg[64..]);
+
+    if let Some(_) = fw.wait_packet(std::time::Duration::from_millis(500)) {
+        panic!("Unexpected packet for an incomplete datagram");
+    }
+}
+
+/// A reply larger than the MTU is sent as fragments
+#[test]
+fn fragmentation()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_fragmentation");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    let testblob: Vec<u8> = (0 .. 2000).map(|v| v as u8).collect();
+    let msg = make_echo_request([0x43,0x21, 0x00,0x03], &testblob);
+    send_fragment(&fw, LOCAL_ADDR, REMOTE_ADDR, 1, 0x1236, 0, true, &msg[..1400]);
+    send_fragment(&fw, LOCAL_ADDR, REMOTE_ADDR, 1, 0x1236, 1400, false, &msg[1400..]);
+
+    // Collect the fragments of the reply
+    let mut reply = vec![0u8; msg.len()];
+    let mut seen_bytes = 0;
+    let mut seen_last = false;
+    let mut identification = None;
+    while !(seen_last && seen_bytes == reply.len())
+    {
+        let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
+            {
+            Some(v) => v,
+            None => panic!("Missing reply fragment ({} of {} bytes seen)", seen_bytes, reply.len()),
+            };
+        let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&data_handle[..]);
+        assert_eq!(ether_hdr.proto, 0x0800);
+        let (ip_hdr, _, tail) = Header::parse(tail);
+        assert_eq!(ip_hdr.protocol, 1);
+        assert_eq!(Addr(ip_hdr.src_addr), REMOTE_ADDR);
+        assert_eq!(Addr(ip_hdr.dst_addr), LOCAL_ADDR);
+        assert!(ip_hdr.total_legnth as usize <= 1500, "Fragment exceeds MTU");
+        let tail = &tail[..ip_hdr.total_legnth as usize - 20];
+        match identification
+        {
+        None => identification = Some(ip_hdr.identification),
+        Some(v) => assert_eq!(v, ip_hdr.identification, "Fragments have differing identification values"),
+        }
+
+        let ofs = (ip_hdr.fragment_info & 0x1FFF) as usize * 8;
+        let more_fragments = ip_hdr.fragment_info & (1 << 13) != 0;
+        assert!(ofs + tail.len() <= reply.len(), "Fragment past the end of the datagram");
+        reply[ofs..][..tail.len()].copy_from_slice(tail);
+        seen_bytes += tail.len();
+        if !more_fragments {
+            seen_last = true;
+        }
+    }
+
+    let (icmp_hdr, data) = icmp::Header::parse(&reply);
+    assert_eq!(icmp_hdr.calculate_checksum(data), 0, "ICMP checksum incorrect");
+    assert_eq!(icmp_hdr.ty, icmp::TYPE_ECHO_REPLY);
+    assert_eq!(icmp_hdr.rest, [0x43,0x21, 0x00,0x03], "Identifier/sequence mismatch");
+    assert_eq!(data, &testblob[..], "Data mismatch");
+}
+
+/// Traffic for a remote network is sent via the default route's gateway
+#[test]
+fn route_default_gateway()
+{
+    let fw = crate::TestFramework::new("ipv4_route_default_gateway");
+
+    fw.send_command("ipv4-route-add 0.0.0.0 0 192.168.1.254 10");
+    fw.send_command("tcp-connect 0 10.1.2.3 80");
+    assert_eq!(crate::arp::wait_request(&fw), Addr([192,168,1,254]), "Should resolve the gateway");
+}
+
+/// The most specific route is used, even if a less specific route has a lower metric
+#[test]
+fn route_longest_prefix()
+{
+    let fw = crate::TestFramework::new("ipv4_route_longest_prefix");
+
+    fw.send_command("ipv4-route-add 0.0.0.0 0 192.168.1.254 0");
+    fw.send_command("ipv4-route-add 10.0.0.0 8 192.168.1.253 10");
+    fw.send_command("tcp-connect 0 10.1.2.3 80");
+    assert_eq!(crate::arp::wait_request(&fw), Addr([192,168,1,253]), "Should use the /8 route");
+}
+
+/// Directly attached addresses don't go via the gateway
+#[test]
+fn route_direct()
+{
+    let fw = crate::TestFramework::new("ipv4_route_direct");
+
+    fw.send_command("ipv4-route-add 0.0.0.0 0 192.168.1.254 0");
+    fw.send_command("tcp-connect 0 192.168.1.20 80");
+    assert_eq!(crate::arp::wait_request(&fw), Addr([192,168,1,20]), "Should resolve the destination directly");
+}
@@ -0,0 +1,84 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/ipv6.rs
+//! IPv6 infrastructure
+use std::io::Cursor;
+
+#[derive(Copy,Clone,PartialEq)]
+pub struct Addr(pub [u8; 16]);
+impl ::core::fmt::Debug for Addr {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        for i in 0 .. 8 {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:x}", self.word(i))?;
+        }
+        Ok( () )
+    }
+}
+impl Addr
+{
+    pub fn word(&self, i: usize) -> u16 {
+        (self.0[i*2] as u16) << 8 | (self.0[i*2+1] as u16)
+    }
+    /// Link-local address derived from a MAC (modified EUI-64)
+    pub fn link_local(mac: [u8; 6]) -> Addr {
+        Addr([
+            0xfe,0x80, 0,0, 0,0, 0,0,
+            mac[0] ^ 0x02, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5],
+            ])
+    }
+    pub fn solicited_node(&self) -> Addr {
+        Addr([0xff,0x02, 0,0, 0,0, 0,0, 0,0, 0,1, 0xff, self.0[13], self.0[14], self.0[15]])
+    }
+}
+
+#[derive(Debug)]
+#[derive(serde_derive::Deserialize,serde_derive::Serialize)]
+pub struct Header
+{
+    pub ver_tc_flow: u32,
+    pub payload_length: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src_addr: [u8; 16],
+    pub dst_addr: [u8; 16],
+}
+impl Header
+{
+    pub fn parse(mut buf: &[u8]) -> (Self, &[u8]) {
+        let rv: Self = crate::des_be(&mut buf).expect("Failed to parse IPv6 header");
+        assert_eq!(rv.ver_tc_flow >> 28, 6, "Bad IP version");
+        assert!(rv.payload_length as usize <= buf.len(), "IPv6 payload length past end of packet");
+        let len = rv.payload_length as usize;
+        (rv, &buf[..len])
+    }
+    pub fn new_simple(src: Addr, dst: Addr, next_header: u8, data_len: usize) -> Self
+    {
+        Header {
+            ver_tc_flow: 6 << 28,
+            payload_length: data_len as u16,
+            next_header: next_header,
+            hop_limit: 255,
+            src_addr: src.0,
+            dst_addr: dst.0,
+        }
+    }
+    pub fn encode(&self) -> [u8; 40]
+    {
+        let mut buf = [0; 40];
+        crate::ser_be(&mut Cursor::new(&mut buf[..]), self);
+        buf
+    }
+}
+
+/// Upper-layer pseudo-header, as big-endian words
+pub fn pseudo_header(src: Addr, dst: Addr, next_header: u8, len: usize) -> Vec<u16>
+{
+    (0 .. 8).map(|i| src.word(i))
+        .chain((0 .. 8).map(|i| dst.word(i)))
+        .chain([(len >> 16) as u16, len as u16, 0, next_header as u16].iter().copied())
+        .collect()
+}
@@ -80,6 +80,65 @@ pub const TCP_RST: u8 = 0x04;
 pub const TCP_PSH: u8 = 0x08;
 pub const TCP_ACK: u8 = 0x10;
 
+pub const OPT_MSS: u8 = 2;
+pub const OPT_WINDOW_SCALE: u8 = 3;
+pub const OPT_SACK_PERMITTED: u8 = 4;
+pub const OPT_SACK: u8 = 5;
+pub const OPT_TIMESTAMP: u8 = 8;
+
+/// Parse TCP options into kind and value pairs
+pub fn parse_options(mut opts: &[u8]) -> Vec<(u8, Vec<u8>)>
+{
+    let mut rv = Vec::new();
+    loop
+    {
+        match opts
+        {
+        [] | [0, ..] => break,
+        [1, rest @ ..] => opts = rest,
+        [ty, len, rest @ ..] => {
+            let len = *len as usize;
+            assert!(len >= 2 && rest.len() >= len - 2, "Option {} has a bad length ({})", ty, len);
+            rv.push( (*ty, rest[..len-2].to_vec()) );
+            opts = &rest[len-2..];
+            },
+        [_] => panic!("Truncated option"),
+        }
+    }
+    rv
+}
+/// Get the value of an option
+pub fn find_option(opts: &[(u8, Vec<u8>)], ty: u8) -> Option<&[u8]>
+{
+    opts.iter().find(|v| v.0 == ty).map(|v| &v.1[..])
+}
+/// Get the value and echo from a timestamp option
+pub fn find_timestamp(opts: &[(u8, Vec<u8>)]) -> Option<(u32, u32)>
+{
+    let v = find_option(opts, OPT_TIMESTAMP)?;
+    assert_eq!(v.len(), 8, "Bad timestamp option length");
+    Some( (u32::from_be_bytes([v[0], v[1], v[2], v[3]]), u32::from_be_bytes([v[4], v[5], v[6], v[7]])) )
+}
+/// MSS option
+pub fn opt_mss(mss: u16) -> [u8; 4]
+{
+    let b = mss.to_be_bytes();
+    [OPT_MSS, 4, b[0], b[1]]
+}
+/// Window scale option (with padding)
+pub fn opt_window_scale(shift: u8) -> [u8; 4]
+{
+    [1, OPT_WINDOW_SCALE, 3, shift]
+}
+/// Timestamp option (with padding)
+pub fn opt_timestamp(value: u32, echo: u32) -> [u8; 12]
+{
+    let mut rv = [1, 1, OPT_TIMESTAMP, 10, 0,0,0,0, 0,0,0,0];
+    rv[4..8].copy_from_slice(&value.to_be_bytes());
+    rv[8..12].copy_from_slice(&echo.to_be_bytes());
+    rv
+}
+
 pub fn send_packet_raw(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, mut header: Header, options: &[u8], data: &[u8])
 {
     assert!(options.len() % 4 == 0);
@@ -130,7 +189,23 @@ impl TcpConn<'_>
     #[track_caller]
     pub fn wait_rx_check(&self, flags: u8, data: &[u8]) -> Header
     {
-        let data_handle = match self.fw.wait_packet(std::time::Duration::from_millis(1000))
+        self.wait_rx_check_timeout(std::time::Duration::from_millis(1000), flags, data)
+    }
+    #[track_caller]
+    pub fn wait_rx_check_timeout(&self, timeout: std::time::Duration, flags: u8, data: &[u8]) -> Header
+    {
+        self.wait_rx_check_options_timeout(timeout, flags, data).0
+    }
+    /// Wait for a packet, returning the header and parsed options
+    #[track_caller]
+    pub fn wait_rx_check_options(&self, flags: u8, data: &[u8]) -> (Header, Vec<(u8, Vec<u8>)>)
+    {
+        self.wait_rx_check_options_timeout(std::time::Duration::from_millis(1000), flags, data)
+    }
+    #[track_caller]
+    pub fn wait_rx_check_options_timeout(&self, timeout: std::time::Duration, flags: u8, data: &[u8]) -> (Header, Vec<(u8, Vec<u8>)>)
+    {
+        let data_handle = match self.fw.wait_packet(timeout)
             {
             Some(v) => v,
             None => panic!("No packet recieved"),
@@ -149,12 +224,10 @@ impl TcpConn<'_>
         let (tcp_hdr,tcp_options, tail) = Header::parse(tail);
         assert!(tcp_hdr.dst_port == self.local_port, "TCP destination port mismatch: Exp {} got {}", tcp_hdr.dst_port, self.local_port);
         assert!(tcp_hdr.src_port == self.remote_port, "TCP source port mismatch: Exp {} got {}", tcp_hdr.src_port, self.remote_port);
-        //assert!(tcp_options.len() == 0, "Expected no TCP options, got {:?}", tcp_options);
-        let _ = tcp_options;
         assert!(tcp_hdr.flags == flags, "Header flags mismatch: Expected {:#x} got {:#x}", flags, tcp_hdr.flags);
         // 4. Check the data
         assert_eq!(tail, data, "Data mismatch");
-        tcp_hdr
+        (tcp_hdr, parse_options(tcp_options))
     }
     #[track_caller]
     pub fn wait_rx_none(&self)
@@ -167,6 +240,11 @@ impl TcpConn<'_>
     }
 
     pub fn from_rx_conn(fw: &crate::TestFramework, lport: u16, laddr: crate::ipv4::Addr) -> TcpConn
+    {
+        Self::from_rx_conn_options(fw, lport, laddr).0
+    }
+    /// Wait for a SYN, returning the connection and the SYN's options
+    pub fn from_rx_conn_options(fw: &crate::TestFramework, lport: u16, laddr: crate::ipv4::Addr) -> (TcpConn, Vec<(u8, Vec<u8>)>)
     {
         let t = std::time::Instant::now();
         let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
@@ -186,13 +264,11 @@ impl TcpConn<'_>
         assert_eq!(ip_options.len(), 0);
         // 3. Check the TCP header (incl flags)
         let (tcp_hdr,tcp_options, tail) = Header::parse(tail);
-        //assert_eq!(tcp_options.len(), 0);
-        let _ = tcp_options;
         assert_eq!(tcp_hdr.flags, TCP_SYN);
         assert_eq!(tcp_hdr.dst_port, lport);
         // 4. Check the data
         assert_eq!(tail, &[], "Data mismatch");
-        TcpConn {
+        (TcpConn {
             fw: fw,
             addrs: (laddr, crate::ipv4::Addr(ip_hdr.src_addr)),
             remote_port: tcp_hdr.src_port, 
@@ -202,6 +278,6 @@ impl TcpConn<'_>
 
             local_seq: 0x10000,
             remote_seq: tcp_hdr.seq + 1,
-            }
+            }, parse_options(tcp_options))
     }
 }
@@ -1,6 +1,7 @@
 //! TCP tests
 use crate::ipv4::Addr as IpAddr4;
 use super::*;
+use crate::HexString;
 
 /// TCP State CLOSED
 /// 
@@ -137,13 +138,341 @@ fn client()
     conn.wait_rx_check(if cfg!(feature="lwip") { TCP_ACK|TCP_PSH } else { 0 }, &[0,1,2,3]);
 }
 
-/// Helper to create a string of hex-encoded bytes
-struct HexString<'a>(&'a [u8]);
-impl ::std::fmt::Display for HexString<'_> {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        for b in self.0 {
-            write!(f, "{:02x}", b)?;
-        }
-        Ok(())
+/// An unanswered SYN is retransmitted, with the timeout doubling each time
+#[test]
+fn client_syn_retransmit()
+{
+    use std::time::{Duration,Instant};
+    let my_ip = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_client_syn_retransmit");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let mut conn = TcpConn::from_rx_conn(&fw, 80, my_ip);
+    // Ignore the SYN, the first retransmit should be after the initial timeout (1s) and the second after double that
+    let t = Instant::now();
+    conn.wait_rx_check_timeout(Duration::from_millis(1500), TCP_SYN, &[]);
+    let first = t.elapsed();
+    let t = Instant::now();
+    conn.wait_rx_check_timeout(Duration::from_millis(3000), TCP_SYN, &[]);
+    let second = t.elapsed();
+    assert!(first >= Duration::from_millis(800), "Retransmitted too early: {:?}", first);
+    assert!(second > first * 3 / 2, "Timeout should back off: {:?} then {:?}", first, second);
+
+    // Answering the retransmitted SYN completes the handshake
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &[], &[]);
+    conn.local_seq += 1;
+    conn.wait_rx_check(TCP_ACK, &[]);
+}
+
+/// Un-acknowledged data is retransmitted (and stops once acknowledged)
+#[test]
+fn client_data_retransmit()
+{
+    use std::time::Duration;
+    let my_ip = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_client_data_retransmit");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let mut conn = TcpConn::from_rx_conn(&fw, 80, my_ip);
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &[], &[]);
+    conn.local_seq += 1;
+    conn.wait_rx_check(TCP_ACK, &[]);
+
+    fw.send_command("tcp-send 0 \"00 01 02 03\"");
+    let hdr = conn.wait_rx_check(0, &[0,1,2,3]);
+    assert_eq!(hdr.seq, conn.remote_seq, "Data sent with the wrong sequence number");
+    // Don't ACK, the same data should be sent again
+    let hdr = conn.wait_rx_check_timeout(Duration::from_millis(1500), 0, &[0,1,2,3]);
+    assert_eq!(hdr.seq, conn.remote_seq, "Retransmit has the wrong sequence number");
+
+    conn.remote_seq += 4;
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    if let Some(_) = fw.wait_packet(Duration::from_millis(1000)) {
+        panic!("Unexpected packet after the data was acknowledged");
+    }
+}
+
+/// Data generated by `tcp-send-fill`
+fn fill_data(range: ::std::ops::Range<usize>) -> Vec<u8>
+{
+    range.map(|i| i as u8).collect()
+}
+
+/// Three duplicate ACKs trigger a retransmit of the lost segment before the retransmit timer expires
+#[test]
+fn client_fast_retransmit()
+{
+    use std::time::{Duration,Instant};
+    const MSS: usize = 1400;
+    let my_ip = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_client_fast_retransmit");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let mut conn = TcpConn::from_rx_conn(&fw, 80, my_ip);
+    // Large receive window, so only the congestion window limits sending
+    conn.rx_window = 0xFFFF;
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &opt_mss(MSS as u16), &[]);
+    conn.local_seq += 1;
+    conn.wait_rx_check(TCP_ACK, &[]);
+    let base_seq = conn.remote_seq;
+
+    fw.send_command("tcp-send-fill 0 9800");
+    // Initial window is three segments
+    for i in 0 .. 3 {
+        let hdr = conn.wait_rx_check(0, &fill_data(i*MSS .. (i+1)*MSS));
+        assert_eq!(hdr.seq, base_seq.wrapping_add((i*MSS) as u32));
+    }
+    conn.wait_rx_none();
+
+    // ACK all of it, slow start grows the window by one segment
+    conn.remote_seq = base_seq.wrapping_add((3*MSS) as u32);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    for i in 3 .. 7 {
+        let hdr = conn.wait_rx_check(0, &fill_data(i*MSS .. (i+1)*MSS));
+        assert_eq!(hdr.seq, base_seq.wrapping_add((i*MSS) as u32));
+    }
+
+    // The first of those was "lost", send duplicate ACKs as if the rest were received
+    let t = Instant::now();
+    for _ in 0 .. 3 {
+        conn.raw_send_packet(TCP_ACK, &[], &[]);
+    }
+    let hdr = conn.wait_rx_check(0, &fill_data(3*MSS .. 4*MSS));
+    assert_eq!(hdr.seq, conn.remote_seq, "Retransmitted the wrong segment");
+    assert!(t.elapsed() < Duration::from_millis(150), "Retransmit took too long ({:?}), not a fast retransmit", t.elapsed());
+
+    // ACK everything, nothing should be sent after
+    conn.remote_seq = base_seq.wrapping_add(9800);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    if let Some(_) = fw.wait_packet(Duration::from_millis(1000)) {
+        panic!("Unexpected packet after the data was acknowledged");
+    }
+}
+
+/// After a retransmit timeout only one segment is re-sent, and the window then grows from there
+#[test]
+fn client_rto_slow_start()
+{
+    use std::time::Duration;
+    const MSS: usize = 1400;
+    let my_ip = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_client_rto_slow_start");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let mut conn = TcpConn::from_rx_conn(&fw, 80, my_ip);
+    conn.rx_window = 0xFFFF;
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &opt_mss(MSS as u16), &[]);
+    conn.local_seq += 1;
+    conn.wait_rx_check(TCP_ACK, &[]);
+    let base_seq = conn.remote_seq;
+
+    fw.send_command("tcp-send-fill 0 4200");
+    for i in 0 .. 3 {
+        conn.wait_rx_check(0, &fill_data(i*MSS .. (i+1)*MSS));
+    }
+
+    // Lose all three, the timeout re-sends only the first
+    let hdr = conn.wait_rx_check_timeout(Duration::from_millis(1500), 0, &fill_data(0 .. MSS));
+    assert_eq!(hdr.seq, base_seq, "Retransmitted the wrong segment");
+    conn.wait_rx_none();
+
+    // ACK it, slow start allows two more segments
+    conn.remote_seq = base_seq.wrapping_add(MSS as u32);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    for i in 1 .. 3 {
+        let hdr = conn.wait_rx_check(0, &fill_data(i*MSS .. (i+1)*MSS));
+        assert_eq!(hdr.seq, base_seq.wrapping_add((i*MSS) as u32));
     }
-}
\ No newline at end of file
+
+    conn.remote_seq = base_seq.wrapping_add(4200);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    if let Some(_) = fw.wait_packet(Duration::from_millis(1000)) {
+        panic!("Unexpected packet after the data was acknowledged");
+    }
+}
+
+/// The SYN offers all supported options, and the peer's MSS limits segment sizes
+#[test]
+fn client_syn_options()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_client_syn_options");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let (mut conn, options) = TcpConn::from_rx_conn_options(&fw, 80, my_ip);
+    assert_eq!(find_option(&options, OPT_MSS), Some(&[0x05,0x78][..]), "Expected an MSS of 1400");
+    assert!(find_option(&options, OPT_WINDOW_SCALE).is_some(), "Window scale not offered");
+    assert!(find_option(&options, OPT_SACK_PERMITTED).is_some(), "SACK not offered");
+    let (_, echo) = find_timestamp(&options).expect("Timestamps not offered");
+    assert_eq!(echo, 0, "SYN shouldn't echo a timestamp");
+
+    // Only reply with a small MSS, so scaling/SACK/timestamps aren't used
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &opt_mss(500), &[]);
+    conn.local_seq += 1;
+    let (_, options) = conn.wait_rx_check_options(TCP_ACK, &[]);
+    assert_eq!(options, vec![], "Options sent when none were negotiated");
+
+    // Segments are limited to the peer's MSS
+    fw.send_command("tcp-send-fill 0 1200");
+    conn.wait_rx_check(0, &fill_data(0 .. 500));
+    conn.wait_rx_check(0, &fill_data(500 .. 1000));
+    // Remainder is held back (Nagle) until the full segments are acknowledged
+    conn.wait_rx_none();
+    conn.remote_seq = conn.remote_seq.wrapping_add(1000);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_check(0, &fill_data(1000 .. 1200));
+}
+
+/// Negotiated timestamps are sent (and echoed) on every segment, and take space from the MSS
+#[test]
+fn client_timestamps()
+{
+    const MSS: usize = 1400;
+    let my_ip = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_client_timestamps");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let (mut conn, options) = TcpConn::from_rx_conn_options(&fw, 80, my_ip);
+    let (syn_ts, _) = find_timestamp(&options).expect("Timestamps not offered");
+    conn.rx_window = 0xFFFF;
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &[&opt_mss(MSS as u16)[..], &opt_timestamp(1234, syn_ts)].concat(), &[]);
+    conn.local_seq += 1;
+    let (_, options) = conn.wait_rx_check_options(TCP_ACK, &[]);
+    let (ack_ts, echo) = find_timestamp(&options).expect("No timestamp on the ACK");
+    assert_eq!(echo, 1234, "Peer's timestamp not echoed");
+    assert!(ack_ts.wrapping_sub(syn_ts) as i32 >= 0, "Timestamp went backwards");
+
+    // The timestamp uses 12 bytes of each segment
+    fw.send_command("tcp-send-fill 0 2000");
+    let (_, options) = conn.wait_rx_check_options(0, &fill_data(0 .. MSS - 12));
+    assert_eq!(find_timestamp(&options).map(|v| v.1), Some(1234));
+    conn.wait_rx_none();
+
+    // A newer timestamp from the peer is echoed from then on
+    conn.remote_seq = conn.remote_seq.wrapping_add((MSS - 12) as u32);
+    conn.raw_send_packet(TCP_ACK, &opt_timestamp(1300, ack_ts), &[]);
+    let (_, options) = conn.wait_rx_check_options(0, &fill_data(MSS - 12 .. 2000));
+    assert_eq!(find_timestamp(&options).map(|v| v.1), Some(1300));
+}
+
+/// Window sizes are scaled after both sides offer window scaling
+#[test]
+fn client_window_scale()
+{
+    const MSS: usize = 1400;
+    let my_ip = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_client_window_scale");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let (mut conn, options) = TcpConn::from_rx_conn_options(&fw, 80, my_ip);
+    let shift = find_option(&options, OPT_WINDOW_SCALE).expect("Window scale not offered")[0];
+    // The SYN's window is never scaled
+    conn.rx_window = 0xFFFF;
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &[&opt_mss(MSS as u16)[..], &opt_window_scale(2)].concat(), &[]);
+    conn.local_seq += 1;
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert!(hdr.window as u32 * (1 << shift) > 0xFFFF, "Receive window not enlarged ({:#x} << {})", hdr.window, shift);
+
+    // Advertise 1000<<2 bytes, enough for two full segments (a third partial segment is held back by Nagle)
+    conn.rx_window = 1000;
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+    fw.send_command("tcp-send-fill 0 4200");
+    conn.wait_rx_check(0, &fill_data(0 .. MSS));
+    conn.wait_rx_check(0, &fill_data(MSS .. 2*MSS));
+    conn.wait_rx_none();
+}
+
+/// Out-of-order data is reported with SACK blocks
+#[test]
+fn server_sack()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_server_sack");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("tcp-listen 0 80");
+
+    let mut conn = TcpConn {
+        fw: &fw,
+        addrs: (LOCAL_ADDR, REMOTE_ADDR),
+        remote_port: 80,
+        local_port: 11200,
+
+        rx_window: 0x1000,
+
+        local_seq: 0x1000,
+        remote_seq: 0x1000,
+        };
+
+    // Only offer MSS and SACK, the reply should only include those
+    conn.raw_send_packet(TCP_SYN, &[&opt_mss(1400)[..], &[1, 1, OPT_SACK_PERMITTED, 2]].concat(), &[]);
+    conn.local_seq = conn.local_seq.wrapping_add(1);
+    let (hdr, options) = conn.wait_rx_check_options(TCP_SYN|TCP_ACK, &[]);
+    assert_eq!(find_option(&options, OPT_MSS), Some(&[0x05,0x78][..]));
+    assert!(find_option(&options, OPT_SACK_PERMITTED).is_some(), "SACK-permitted not echoed");
+    assert!(find_option(&options, OPT_WINDOW_SCALE).is_none(), "Window scale sent when not offered");
+    assert!(find_option(&options, OPT_TIMESTAMP).is_none(), "Timestamp sent when not offered");
+    conn.remote_seq = hdr.seq.wrapping_add(1);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+    fw.send_command("tcp-accept 0 0");
+
+    // Skip 100 bytes, should get an immediate (duplicate) ACK with a SACK block for the received data
+    let data = fill_data(0 .. 150);
+    let base_seq = conn.local_seq;
+    conn.local_seq = base_seq.wrapping_add(100);
+    conn.raw_send_packet(TCP_ACK, &[], &data[100..]);
+    let (hdr, options) = conn.wait_rx_check_options(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, base_seq, "Out-of-order data acknowledged");
+    let sack = find_option(&options, OPT_SACK).expect("No SACK block");
+    let mut exp = Vec::new();
+    exp.extend_from_slice(&base_seq.wrapping_add(100).to_be_bytes());
+    exp.extend_from_slice(&base_seq.wrapping_add(150).to_be_bytes());
+    assert_eq!(sack, &exp[..], "Incorrect SACK block");
+
+    // Fill the hole, all of the data should now be readable
+    conn.local_seq = base_seq;
+    conn.raw_send_packet(TCP_ACK, &[], &data[..100]);
+    conn.local_seq = base_seq.wrapping_add(150);
+    fw.send_command( &format!("tcp-recv-assert 0 150 {}", HexString(&data)) );
+}
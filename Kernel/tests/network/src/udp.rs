@@ -0,0 +1,97 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/udp.rs
+//! UDP tests and infrastructure
+use crate::ipv4::Addr as IpAddr4;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Copy,Clone)]
+#[derive(Debug)]
+#[derive(serde_derive::Deserialize,serde_derive::Serialize)]
+pub struct Header
+{
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub length: u16,
+    pub checksum: u16,
+}
+impl Header
+{
+    /// Parse a UDP header, returning the data
+    pub fn parse(mut buf: &[u8]) -> (Self, &[u8]) {
+        let rv: Self = crate::des_be(&mut buf).expect("Failed to parse UDP header");
+        assert!(rv.length as usize >= 8, "Bad UDP length");
+        assert!(rv.length as usize - 8 <= buf.len(), "UDP length past end of packet: 8+{} > {}", buf.len(), rv.length);
+        (rv, &buf[..rv.length as usize - 8])
+    }
+    pub fn encode(&self) -> [u8; 8]
+    {
+        let mut rv = [0; 8];
+        crate::ser_be(&mut std::io::Cursor::new(&mut rv[..]), self);
+        rv
+    }
+    pub fn calculate_checksum_v4(&self, src: IpAddr4, dst: IpAddr4, data: &[u8]) -> u16
+    {
+        fn u16be(a: u8, b: u8) -> u16 {
+            (a as u16) << 8 | (b as u16)
+        }
+        let pseudo_enc = [
+            u16be(src.0[0], src.0[1]), u16be(src.0[2], src.0[3]),
+            u16be(dst.0[0], dst.0[1]), u16be(dst.0[2], dst.0[3]),
+            17, self.length,
+            ];
+        let hdr_enc = self.encode();
+        let it_header = hdr_enc.chunks(2).map(|v| u16be(v[0], v[1]));
+        // Odd trailing byte is padded with zero
+        let it_data = data.chunks(2).map(|v| u16be(v[0], *v.get(1).unwrap_or(&0)));
+        crate::ipv4::calculate_ip_checksum(pseudo_enc.iter().copied().chain(it_header).chain(it_data))
+    }
+}
+
+pub fn send_packet_raw(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, src_port: u16, dst_port: u16, data: &[u8])
+{
+    let mut header = Header {
+        src_port,
+        dst_port,
+        length: (8 + data.len()) as u16,
+        checksum: 0,
+        };
+    header.checksum = header.calculate_checksum_v4(src, dst, data);
+    let udp_hdr = header.encode();
+    let ip_hdr = {
+        let mut h = crate::ipv4::Header::new_simple(src, dst, 17, udp_hdr.len() + data.len());
+        h.set_checksum();
+        h.encode()
+        };
+    fw.send_ethernet_direct(0x0800, &[&ip_hdr, &udp_hdr, data]);
+}
+
+/// Wait for a UDP datagram from `src` to `dst` (checking the ports and checksum), returning the data
+#[track_caller]
+pub fn wait_rx_check(fw: &crate::TestFramework, src: (IpAddr4, u16), dst: (IpAddr4, u16)) -> Vec<u8>
+{
+    let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
+        {
+        Some(v) => v,
+        None => panic!("No packet recieved"),
+        };
+    // 1. Check the ethernet header
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&data_handle[..]);
+    assert_eq!(ether_hdr.proto, 0x0800, "Incorrect ethernet protocol value: {:04x}", ether_hdr.proto);
+    // 2. Check the IPv4 header
+    let (ip_hdr, ip_options, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, 17, "Expected a UDP packet");
+    assert_eq!(IpAddr4(ip_hdr.src_addr), src.0);
+    assert_eq!(IpAddr4(ip_hdr.dst_addr), dst.0);
+    assert_eq!(ip_options.len(), 0);
+    let tail = &tail[..ip_hdr.total_legnth as usize - 20];
+    // 3. Check the UDP header
+    let (udp_hdr, data) = Header::parse(tail);
+    assert_eq!(udp_hdr.calculate_checksum_v4(src.0, dst.0, data), 0, "UDP checksum incorrect");
+    assert!(udp_hdr.src_port == src.1, "UDP source port mismatch: Exp {} got {}", src.1, udp_hdr.src_port);
+    assert!(udp_hdr.dst_port == dst.1, "UDP destination port mismatch: Exp {} got {}", dst.1, udp_hdr.dst_port);
+    data.to_vec()
+}
@@ -0,0 +1,94 @@
+//! UDP tests
+use crate::ipv4::Addr as IpAddr4;
+use crate::HexString;
+use super::*;
+
+const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+/// Datagrams sent from a bound socket come from its port, with a valid checksum
+#[test]
+fn send()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("udp_send");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("udp-bind 0 1234");
+
+    let testblob = b"Some UDP test data, with an odd length";
+    fw.send_command(&format!("udp-send 0 {} 4321 {}", LOCAL_ADDR, HexString(testblob)));
+    let data = wait_rx_check(&fw, (REMOTE_ADDR, 1234), (LOCAL_ADDR, 4321));
+    assert_eq!(&data[..], &testblob[..], "Data mismatch");
+}
+
+/// Datagrams to a bound port are queued for the socket (in order, and without an ICMP error)
+#[test]
+fn recv()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("udp_recv");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("udp-bind 0 1234");
+
+    let testblob1 = b"First datagram";
+    let testblob2 = b"Second datagram, from another port";
+    send_packet_raw(&fw, LOCAL_ADDR, REMOTE_ADDR, 4321, 1234, testblob1);
+    send_packet_raw(&fw, LOCAL_ADDR, REMOTE_ADDR, 4322, 1234, testblob2);
+    match fw.wait_packet(std::time::Duration::from_millis(100))
+    {
+    Some(_) => panic!("Unexpected packet"),
+    None => {},
+    }
+    fw.send_command(&format!("udp-recv-assert 0 {} 4321 {}", LOCAL_ADDR, HexString(testblob1)));
+    fw.send_command(&format!("udp-recv-assert 0 {} 4322 {}", LOCAL_ADDR, HexString(testblob2)));
+
+    // Reply, which also checks that the host didn't fail the above assertions
+    fw.send_command(&format!("udp-send 0 {} 4321 {}", LOCAL_ADDR, HexString(testblob1)));
+    let data = wait_rx_check(&fw, (REMOTE_ADDR, 1234), (LOCAL_ADDR, 4321));
+    assert_eq!(&data[..], &testblob1[..], "Data mismatch");
+}
+
+/// Datagrams with a bad checksum are dropped
+#[test]
+fn bad_checksum()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("udp_bad_checksum");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("udp-bind 0 1234");
+
+    let testblob = b"Corrupted";
+    let mut header = Header {
+        src_port: 4321,
+        dst_port: 1234,
+        length: (8 + testblob.len()) as u16,
+        checksum: 0,
+        };
+    header.checksum = header.calculate_checksum_v4(LOCAL_ADDR, REMOTE_ADDR, testblob) ^ 0x5555;
+    let udp_hdr = header.encode();
+    let ip_hdr = {
+        let mut h = crate::ipv4::Header::new_simple(LOCAL_ADDR, REMOTE_ADDR, 17, udp_hdr.len() + testblob.len());
+        h.set_checksum();
+        h.encode()
+        };
+    fw.send_ethernet_direct(0x0800, &[&ip_hdr, &udp_hdr, testblob]);
+    match fw.wait_packet(std::time::Duration::from_millis(100))
+    {
+    Some(_) => panic!("Unexpected packet"),
+    None => {},
+    }
+
+    // Only the valid datagram is received
+    let testblob = b"Valid";
+    send_packet_raw(&fw, LOCAL_ADDR, REMOTE_ADDR, 4321, 1234, testblob);
+    fw.send_command(&format!("udp-recv-assert 0 {} 4321 {}", LOCAL_ADDR, HexString(testblob)));
+    fw.send_command(&format!("udp-send 0 {} 4321 {}", LOCAL_ADDR, HexString(testblob)));
+    let data = wait_rx_check(&fw, (REMOTE_ADDR, 1234), (LOCAL_ADDR, 4321));
+    assert_eq!(&data[..], &testblob[..], "Data mismatch");
+}
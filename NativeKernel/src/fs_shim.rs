@@ -38,7 +38,7 @@ impl mount::Driver for NativeFsDriver
 	fn detect(&self, _vol: &VolumeHandle) -> vfs::Result<usize> {
         Ok(0)
     }
-	fn mount(&self, _vol: VolumeHandle, _handle: mount::SelfHandle) -> vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, _vol: VolumeHandle, _handle: mount::SelfHandle, _options: &mount::MountOptions) -> vfs::Result<Box<dyn mount::Filesystem>> {
         // TODO: Can this get the path from the volume handle?
         let root_path: PathBuf = ".native_fs".into();
         let mut rv = NativeFs::default();
@@ -84,6 +84,13 @@ impl mount::Filesystem for FsInstance
         EntData::File(_r) => vfs::node::Node::File(Box::new(FileNodeRef( self.0.borrow(), n ))),
         })
     }
+	fn flush(&self) -> vfs::Result<()> {
+        // All writes go directly to the host filesystem
+        Ok( () )
+    }
+	fn remount(&self, _options: &mount::MountOptions) -> vfs::Result<()> {
+        Ok( () )
+    }
 }
 #[derive(Clone)]
 struct DirNodeRef(ArefBorrow<NativeFs>, InodeId);
@@ -97,6 +104,10 @@ impl vfs::node::NodeBase for DirNodeRef
 	fn get_any(&self) -> &dyn ::std::any::Any {
         self
     }
+	/// Obtain the node's metadata (times, permissions, ownership)
+	fn get_metadata(&self) -> vfs::node::Result<vfs::node::Metadata> {
+        native_metadata(&self.0.get_dir(self.1).path)
+    }
 }
 impl vfs::node::Dir for DirNodeRef
 {
@@ -174,15 +185,57 @@ impl vfs::node::Dir for DirNodeRef
 	/// 
 	/// Returns the newly created node
 	fn create(&self, name: &ByteStr, nodetype: vfs::node::NodeType) -> vfs::node::Result<InodeId> {
-        todo!("create({:?}, {:?})", name, nodetype)
+        let path = self.child_path(name)?;
+        let data = match nodetype
+            {
+            vfs::node::NodeType::File => {
+                ::std::fs::OpenOptions::new().write(true).create_new(true).open(&path).map_err(map_err)?;
+                EntData::File(FileData { path })
+                },
+            vfs::node::NodeType::Dir => {
+                ::std::fs::create_dir(&path).map_err(map_err)?;
+                EntData::Dir(DirData { path })
+                },
+            vfs::node::NodeType::Symlink(_) => return Err(vfs::Error::Unknown("Symbolic links aren't supported by the native filesystem")),
+            };
+        Ok(self.0.allocate_inode(data))
     }
 	/// Create a new name for the provided inode
 	fn link(&self, name: &ByteStr, inode: &dyn vfs::node::NodeBase) -> vfs::node::Result<()> {
-        todo!("link({:?}, {:?})", name, inode.get_id())
+        // Only files can have multiple names
+        let src = match inode.get_any().downcast_ref::<FileNodeRef>()
+            {
+            Some(f) => f.0.get_file(f.1).path.clone(),
+            None => return Err(vfs::Error::TypeMismatch),
+            };
+        ::std::fs::hard_link(src, self.child_path(name)?).map_err(map_err)
     }
 	/// Remove the specified name
 	fn unlink(&self, name: &ByteStr) -> vfs::node::Result<()> {
-        todo!("unlink({:?})", name)
+        let path = self.child_path(name)?;
+        if ::std::fs::symlink_metadata(&path).map_err(map_err)?.is_dir() {
+            ::std::fs::remove_dir(&path).map_err(map_err)
+        }
+        else {
+            ::std::fs::remove_file(&path).map_err(map_err)
+        }
+    }
+	/// Move an entry to another directory
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn vfs::node::Dir, new_name: &ByteStr) -> vfs::node::Result<()> {
+        let new_dir = match new_dir.get_any().downcast_ref::<DirNodeRef>()
+            {
+            Some(v) => v,
+            None => return Err(vfs::Error::CrossFilesystem),
+            };
+        ::std::fs::rename(self.child_path(old_name)?, new_dir.child_path(new_name)?).map_err(map_err)
+    }
+}
+impl DirNodeRef
+{
+    /// Host path of an entry in this directory
+    fn child_path(&self, name: &ByteStr) -> vfs::node::Result<PathBuf> {
+        let name = ::std::str::from_utf8(name.as_bytes()).map_err(|_| vfs::Error::InvalidParameter)?;
+        Ok(self.0.get_dir(self.1).path.join(name))
     }
 }
 
@@ -198,6 +251,10 @@ impl vfs::node::NodeBase for FileNodeRef
 	fn get_any(&self) -> &dyn ::std::any::Any {
         self
     }
+	/// Obtain the node's metadata (times, permissions, ownership)
+	fn get_metadata(&self) -> vfs::node::Result<vfs::node::Metadata> {
+        native_metadata(&self.0.get_file(self.1).path)
+    }
 }
 impl vfs::node::File for FileNodeRef
 {
@@ -228,6 +285,37 @@ impl vfs::node::File for FileNodeRef
     }
 }
 
+fn native_metadata(path: &Path) -> vfs::node::Result<vfs::node::Metadata> {
+    fn to_ts(t: ::std::io::Result<::std::time::SystemTime>) -> vfs::node::Timestamp {
+        t.ok()
+            .and_then(|t| t.duration_since(::std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as vfs::node::Timestamp)
+            .unwrap_or(0)
+    }
+    let m = ::std::fs::metadata(path).map_err(map_err)?;
+    Ok(vfs::node::Metadata {
+        size: m.len(),
+        link_count: 1,
+        mode: if m.permissions().readonly() { 0o555 } else { 0o755 },
+        owner: 0,
+        group: 0,
+        created: to_ts(m.created()),
+        modified: to_ts(m.modified()),
+        accessed: to_ts(m.accessed()),
+    })
+}
+
 fn map_err(e: ::std::io::Error) -> vfs::Error {
-    todo!("Transform error {:?}", e)
+    use ::std::io::ErrorKind;
+    match e.kind()
+    {
+    ErrorKind::NotFound => vfs::Error::NotFound,
+    ErrorKind::PermissionDenied => vfs::Error::PermissionDenied,
+    ErrorKind::AlreadyExists => vfs::Error::AlreadyExists,
+    ErrorKind::DirectoryNotEmpty => vfs::Error::DirectoryNotEmpty,
+    _ => {
+        log_warning!("Unhandled native IO error: {:?}", e);
+        vfs::Error::Unknown("Native IO error")
+        },
+    }
 }
\ No newline at end of file
@@ -35,8 +35,11 @@ impl ::Object for Server
 		&self.0
 	}
 
-	type Waits = ();
+	type Waits = ServerWaits;
 }
+define_waits!{ ServerWaits => (
+	accept:has_accept = ::values::EV_NET_SERVER_ACCEPT,
+)}
 impl Server
 {
 	pub fn open(addr: impl Into<SocketAddress>) -> Result<Server, Error> {
@@ -54,6 +57,11 @@ impl Server
 			.map_err(|e| Error::try_from(e as u8).unwrap() )
 			.map( |v| (ConnectedSocket(v), sa,) )
 	}
+
+	/// Wait item that fires when there's a connection waiting to be accepted
+	pub fn wait_accept(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_NET_SERVER_ACCEPT)
+	}
 }
 // --------------------------------------------------------------------
 impl ::Object for ConnectedSocket
@@ -70,8 +78,11 @@ impl ::Object for ConnectedSocket
 		&self.0
 	}
 
-	type Waits = ();
+	type Waits = ConnectedSocketWaits;
 }
+define_waits!{ ConnectedSocketWaits => (
+	rx:has_rx = ::values::EV_NET_CONNSOCK_RECV,
+)}
 impl ConnectedSocket
 {
 	pub fn connect(addr: impl Into<SocketAddress>) -> Result<ConnectedSocket, Error> {
@@ -103,12 +114,20 @@ impl ConnectedSocket
 		to_result(unsafe { self.0.call_2(::values::NET_CONNSOCK_SEND, data.as_ptr() as usize, data.len()) as usize })
 			.map(|v| v as usize)
 	}
+	/// Read data from the connection
+	///
+	/// Returns `Err(Error::NoData)` if nothing is available yet, and `Ok(0)` once the remote has closed the connection
 	pub fn recv(&mut self, data: &mut [u8]) -> Result<usize, Error> {
 		// SAFE: Syscall
 		to_result(unsafe { self.0.call_2(::values::NET_CONNSOCK_RECV, data.as_ptr() as usize, data.len()) as usize })
 			.map(|v| v as usize)
 	}
 
+	/// Wait item that fires when there's data to read (or the connection has closed)
+	pub fn wait_rx(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_NET_CONNSOCK_RECV)
+	}
+
 	// TODO: Async IO using registered buffers (which minimises the problems with borrowing)
 }
 // --------------------------------------------------------------------
@@ -126,8 +145,11 @@ impl ::Object for FreeSocket
 		&self.0
 	}
 
-	type Waits = ();
+	type Waits = FreeSocketWaits;
 }
+define_waits!{ FreeSocketWaits => (
+	rx:has_rx = ::values::EV_NET_FREESOCK_RECV,
+)}
 impl FreeSocket
 {
 	/// Create a free socket using the specified local and remote addresses.
@@ -151,5 +173,32 @@ impl FreeSocket
 		to_result( unsafe { self.0.call_3(::values::NET_FREESOCK_RECV, data.as_ptr() as usize, data.len(), &mut sa as *mut _ as usize) as usize } )
 			.map(|v| (v as usize, sa))
 	}
+
+	/// Wait item that fires when there's a datagram to read
+	pub fn wait_rx(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_NET_FREESOCK_RECV)
+	}
 }
 
+// --------------------------------------------------------------------
+pub use ::values::NetworkRoute as Route;
+
+/// Add an entry to the routing table
+pub fn route_add(route: &Route) -> Result<(), Error> {
+	// SAFE: Syscall
+	to_result(unsafe { syscall!(NET_ROUTE_ADD, route as *const _ as usize) as usize })
+		.map(|_| ())
+}
+/// Remove an entry from the routing table (matching the network, mask, and gateway)
+pub fn route_del(route: &Route) -> Result<(), Error> {
+	// SAFE: Syscall
+	to_result(unsafe { syscall!(NET_ROUTE_DEL, route as *const _ as usize) as usize })
+		.map(|_| ())
+}
+/// Read an entry from the routing table, returns `Err(Error::NoData)` once `index` is past the end
+pub fn route_get(index: usize) -> Result<Route, Error> {
+	let mut rv = Route::default();
+	// SAFE: Syscall
+	to_result(unsafe { syscall!(NET_ROUTE_GET, index, &mut rv as *mut _ as usize) as usize })
+		.map(|_| rv)
+}
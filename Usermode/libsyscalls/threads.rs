@@ -132,6 +132,16 @@ impl ProtoProcess
 		// SAFE: Syscall
 		unsafe { self.0.call_2l(::values::CORE_PROTOPROCESS_SENDOBJ, ::values::FixedStr8::from(tag).into(), oh as usize); }
 	}
+
+	#[inline]
+	/// Set the user and group IDs that the process will run as (only allowed if the caller is root)
+	pub fn set_credentials(&self, uid: u32, gid: u32) -> Result<(), ::values::VFSError> {
+		// SAFE: Syscall
+		let rv = unsafe { self.0.call_2(::values::CORE_PROTOPROCESS_SETCREDS, uid as usize, gid as usize) };
+		::to_result(rv as usize)
+			.map(|_| ())
+			.map_err(|code| ::values::VFSError::try_from(code).expect("Bad VFS Error"))
+	}
  
  	#[inline]
 	pub fn start(self, entry: usize, stack: usize) -> Process {
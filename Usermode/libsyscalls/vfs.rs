@@ -18,6 +18,7 @@ pub use ::values::VFSError as Error;
 pub use ::values::VFSNodeType as NodeType;
 pub use ::values::VFSFileOpenMode as FileOpenMode;
 pub use ::values::VFSMemoryMapMode as MemoryMapMode;
+pub use ::values::VFSNodeInfo as NodeInfo;
 
 pub fn root() -> &'static Dir {
 	use ::core::sync::atomic::{Ordering,AtomicBool};
@@ -51,6 +52,14 @@ impl Node
 		// SAFE: Syscall with no side-effects
 		NodeType::try_from( unsafe { self.0.call_0(::values::VFS_NODE_GETTYPE) } as u32 ).expect("Bad VFS Node Type")
 	}
+	/// Query the node's metadata (size, times, ownership and permissions)
+	#[inline]
+	pub fn get_info(&self) -> Result<NodeInfo,Error> {
+		let mut rv = NodeInfo::default();
+		// SAFE: Syscall, kernel only writes to the provided struct
+		to_result( unsafe { self.0.call_1(::values::VFS_NODE_GETINFO, &mut rv as *mut _ as usize) } as usize )?;
+		Ok(rv)
+	}
 
 	/// Convert handle to a directory handle
 	#[inline]
@@ -182,6 +191,63 @@ impl Dir
 		Err(code) => Err( Error::try_from(code).expect("Bad VFS Error") ),
 		}
 	}
+
+	/// Create a new sub-directory
+	#[inline]
+	pub fn mkdir<P: ?Sized+AsRef<[u8]>>(&self, name: &P) -> Result<Dir, Error> {
+		let name = name.as_ref();
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_2(::values::VFS_DIR_MKDIR, name.as_ptr() as usize, name.len()) } as usize )
+			.map(|h| Dir(h))
+	}
+	/// Create a new file (opened for exclusive read-write)
+	#[inline]
+	pub fn create_file<P: ?Sized+AsRef<[u8]>>(&self, name: &P) -> Result<File, Error> {
+		let name = name.as_ref();
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_2(::values::VFS_DIR_CREATEFILE, name.as_ptr() as usize, name.len()) } as usize )
+			.map(|h| File(h, 0))
+	}
+	/// Remove an entry from this directory (directories must be empty)
+	#[inline]
+	pub fn unlink<P: ?Sized+AsRef<[u8]>>(&self, name: &P) -> Result<(), Error> {
+		let name = name.as_ref();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_2(::values::VFS_DIR_UNLINK, name.as_ptr() as usize, name.len()) } as usize )
+			.map(|_| ())
+	}
+	/// Create a symbolic link pointing at `target`
+	#[inline]
+	pub fn symlink<P: ?Sized+AsRef<[u8]>, T: ?Sized+AsRef<[u8]>>(&self, name: &P, target: &T) -> Result<(), Error> {
+		let name = name.as_ref();
+		let target = target.as_ref();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_4(::values::VFS_DIR_SYMLINK, name.as_ptr() as usize, name.len(), target.as_ptr() as usize, target.len()) } as usize )
+			.map(|_| ())
+	}
+	/// Add a new name for an existing node (must be on the same mount, and not a directory)
+	#[inline]
+	pub fn link<P: ?Sized+AsRef<[u8]>>(&self, name: &P, node: &Node) -> Result<(), Error> {
+		let name = name.as_ref();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_3(::values::VFS_DIR_LINK, name.as_ptr() as usize, name.len(), (node.0).0 as usize) } as usize )
+			.map(|_| ())
+	}
+	/// Atomically move the entry `old_name` to `new_name` in `new_dir` (which can be this directory)
+	///
+	/// Fails if `new_dir` is on a different mount, or if `new_name` already exists
+	#[inline]
+	pub fn rename<P: ?Sized+AsRef<[u8]>, Q: ?Sized+AsRef<[u8]>>(&self, old_name: &P, new_dir: &Dir, new_name: &Q) -> Result<(), Error> {
+		let old_name = old_name.as_ref();
+		let new_name = new_name.as_ref();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_5(::values::VFS_DIR_RENAME,
+			old_name.as_ptr() as usize, old_name.len(),
+			(new_dir.0).0 as usize,
+			new_name.as_ptr() as usize, new_name.len()
+			) } as usize )
+			.map(|_| ())
+	}
 }
 impl ::Object for Dir {
 	const CLASS: u16 = ::values::CLASS_VFS_DIR;
@@ -7,6 +7,8 @@ pub enum Error
 
 pub struct UserInfo
 {
+	uid: u32,
+	gid: u32,
 }
 
 pub fn try_login(username: &str, password: &str) -> Result<UserInfo, Error>
@@ -14,7 +16,7 @@ pub fn try_login(username: &str, password: &str) -> Result<UserInfo, Error>
 	// TODO: Use a proper auth infrastructure, something PAM-esque
 	if username == "root" && password == "password"
 	{
-		Ok(UserInfo {})
+		Ok(UserInfo { uid: 0, gid: 0 })
 	}
 	else if username == "guest"
 	{
@@ -33,5 +35,11 @@ impl UserInfo
 	{
 		"/sysroot/bin/shell"
 	}
+
+	/// User and group IDs that the session's processes run as
+	pub fn get_ids(&self) -> (u32, u32)
+	{
+		(self.uid, self.gid)
+	}
 }
 
@@ -128,7 +128,7 @@ fn try_login(username: &str, password: &str) -> Result<(), &'static str>
 	Ok(i) => {
 		// Spawn console, and wait for it to terminate
 		// - This also spawns the handle server for the session
-		spawn_console_and_wait( i.get_shell() );
+		spawn_console_and_wait( &i );
 		Ok( () )
 		},
 	Err(auth::Error::InvalidAuthentication) => Err("Invalid username or password"),
@@ -144,8 +144,10 @@ fn open_exe(path: &str) -> Result<::syscalls::vfs::File, ::syscalls::vfs::Error>
 	}
 }
 
-fn spawn_console_and_wait(path: &str)
+fn spawn_console_and_wait(user: &auth::UserInfo)
 {
+	let path = user.get_shell();
+	let (uid, gid) = user.get_ids();
 	let (hs_svr_chan, hs_clt_chan) = ::syscalls::ipc::RpcChannel::new_pair().expect("Coudn't create new RPC Channel");
 
 	// Spawn a session leader handled server
@@ -153,6 +155,7 @@ fn spawn_console_and_wait(path: &str)
 		let path = "/sysroot/bin/handle_server";
 		let fh = open_exe(path).unwrap_or_else(|e| panic!("Couldn't open handle server - {:?}", e));
 		let pp = loader::new_process(fh, path.as_bytes(), &[]).expect("Could not spawn handle server");
+		pp.set_credentials(uid, gid).expect("Could not set handle server credentials");
 		pp.send_obj( "RwRoot", VFS_ROOT.clone() );
 		pp.send_obj( "HsChan", hs_svr_chan );
 		pp.start()
@@ -165,6 +168,7 @@ fn spawn_console_and_wait(path: &str)
 			Err(e) => panic!("Couldn't open executable '{}' - {:?}", path, e),
 			};
 		let pp = loader::new_process(fh, path.as_bytes(), &[]).expect("Could not spawn shell");
+		pp.set_credentials(uid, gid).expect("Could not set shell credentials");
 		pp.send_obj( "guigrp", ::syscalls::gui::clone_group_handle() );
 		pp.send_obj( "HsChan", hs_clt_chan );
 		pp.start()
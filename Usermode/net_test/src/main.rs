@@ -0,0 +1,171 @@
+// Tifflin OS - Network Syscall Testing Application
+// - By John Hodge (thePowersGang)
+//
+//! Checks the socket syscalls (NET_LISTEN, NET_CONNECT, NET_BIND, and the socket object methods)
+//!
+//! Usage: `net_test [<echo server IPv4 address> <port>]`
+//! - Without arguments, only the checks that don't need a remote host are run.
+//! - With an echo server (e.g. `ncat -l -k -e /bin/cat`), a TCP connection is also made and checked.
+
+#[macro_use]
+extern crate syscalls;
+
+use syscalls::net::{Server,ConnectedSocket,FreeSocket,ShutdownSide};
+use syscalls::net::{Error,SocketAddress,MaskedSocketAddress};
+use syscalls::values::{SocketAddressType,SocketPortType};
+
+const TEST_PORT: u16 = 7000;
+
+fn main()
+{
+	tcp_server();
+	udp_socket();
+	bad_arguments();
+
+	let mut args = ::std::env::args_os().skip(1);
+	match (args.next(), args.next())
+	{
+	(Some(addr), Some(port)) => {
+		let addr = parse_ipv4(addr.as_ref()).expect("Malformed IPv4 address");
+		let port = parse_u16(port.as_ref()).expect("Malformed port number");
+		tcp_echo(make_addr(SocketPortType::Tcp, addr, port));
+		},
+	_ => kernel_log!("No echo server given, skipping connection tests"),
+	}
+	kernel_log!("net_test: All tests passed");
+}
+
+fn make_addr(port_ty: SocketPortType, addr: [u8; 4], port: u16) -> SocketAddress
+{
+	let mut rv = SocketAddress {
+		port_ty: port_ty as u8,
+		addr_ty: SocketAddressType::Ipv4 as u8,
+		port: port,
+		..Default::default()
+		};
+	rv.addr[..4].copy_from_slice(&addr);
+	rv
+}
+
+/// Check that a call failed with the expected error
+fn expect_err<T>(name: &str, res: Result<T, Error>, expected: Error)
+{
+	let expected_code: u8 = expected.into();
+	match res
+	{
+	Ok(_) => panic!("{}: Unexpected success, expected {}", name, expected_code),
+	Err(e) => {
+		let code: u8 = e.into();
+		assert_eq!(code, expected_code, "{}: Wrong error", name);
+		},
+	}
+}
+
+/// Listening, duplicate ports, and accept with nothing waiting
+fn tcp_server()
+{
+	kernel_log!("tcp_server");
+	let addr = make_addr(SocketPortType::Tcp, [0; 4], TEST_PORT);
+	let server = Server::open(addr).expect("NET_LISTEN failed");
+	expect_err("NET_LISTEN (duplicate)", Server::open(addr), Error::AlreadyInUse);
+	expect_err("NET_SERVER_ACCEPT (empty)", server.accept(), Error::NoData);
+	// Closing the server releases the port
+	::std::mem::drop(server);
+	let server = Server::open(addr).expect("NET_LISTEN after close failed");
+	::std::mem::drop(server);
+}
+
+/// Binding, duplicate binds, and receive with nothing waiting
+fn udp_socket()
+{
+	kernel_log!("udp_socket");
+	let local = make_addr(SocketPortType::Udp, [0; 4], TEST_PORT);
+	let remote = MaskedSocketAddress { addr: make_addr(SocketPortType::Udp, [0; 4], 0), mask: 0, };
+	let mut sock = FreeSocket::create(local, remote).expect("NET_BIND failed");
+	expect_err("NET_BIND (duplicate)", FreeSocket::create(local, remote), Error::AlreadyInUse);
+	let mut buf = [0; 64];
+	expect_err("NET_FREESOCK_RECV (empty)", sock.recv_from(&mut buf), Error::NoData);
+	::std::mem::drop(sock);
+	let sock = FreeSocket::create(local, remote).expect("NET_BIND after close failed");
+	::std::mem::drop(sock);
+}
+
+/// Mismatched or invalid addresses are rejected
+fn bad_arguments()
+{
+	kernel_log!("bad_arguments");
+	let udp_addr = make_addr(SocketPortType::Udp, [0; 4], TEST_PORT);
+	let tcp_addr = make_addr(SocketPortType::Tcp, [0; 4], TEST_PORT);
+	expect_err("NET_LISTEN (UDP)", Server::open(udp_addr), Error::InvalidValue);
+	expect_err("NET_CONNECT (UDP)", ConnectedSocket::connect(udp_addr), Error::InvalidValue);
+	let mut bad_ty = tcp_addr;
+	bad_ty.addr_ty = 0xFF;
+	expect_err("NET_CONNECT (bad address type)", ConnectedSocket::connect(bad_ty), Error::InvalidValue);
+
+	let remote = MaskedSocketAddress { addr: tcp_addr, mask: 0, };
+	expect_err("NET_BIND (port type mismatch)", FreeSocket::create(udp_addr, remote), Error::InvalidValue);
+	let remote = MaskedSocketAddress { addr: udp_addr, mask: 33, };
+	expect_err("NET_BIND (mask too long)", FreeSocket::create(udp_addr, remote), Error::InvalidValue);
+}
+
+/// Connect to an echo server, send data and wait for it to come back, then shut down
+fn tcp_echo(remote: SocketAddress)
+{
+	kernel_log!("tcp_echo");
+	const DATA: &[u8] = b"Hello, network";
+	let mut sock = ConnectedSocket::connect(remote).expect("NET_CONNECT failed");
+	assert_eq!(sock.send(DATA).expect("NET_CONNSOCK_SEND failed"), DATA.len());
+
+	let mut buf = [0; 64];
+	let mut len = 0;
+	while len < DATA.len()
+	{
+		match sock.recv(&mut buf[len..])
+		{
+		Ok(0) => panic!("tcp_echo: Connection closed early"),
+		Ok(v) => len += v,
+		Err(Error::NoData) => { ::syscalls::threads::wait(&mut [sock.wait_rx()], !0); },
+		Err(e) => panic!("tcp_echo: NET_CONNSOCK_RECV failed: {:?}", e),
+		}
+	}
+	assert_eq!(&buf[..len], DATA, "tcp_echo: Data mismatch");
+
+	// Once the receive side is shut down, reads fail
+	sock.shutdown(ShutdownSide::Receive).expect("NET_CONNSOCK_SHUTDOWN(Receive) failed");
+	expect_err("NET_CONNSOCK_RECV after shutdown", sock.recv(&mut buf), Error::ConnectionClosed);
+	sock.shutdown(ShutdownSide::Transmit).expect("NET_CONNSOCK_SHUTDOWN(Transmit) failed");
+	expect_err("NET_CONNSOCK_SEND after shutdown", sock.send(DATA), Error::ConnectionClosed);
+}
+
+fn parse_u16(s: &::std::ffi::OsStr) -> Option<u16>
+{
+	let mut rv: u16 = 0;
+	if s.as_bytes().is_empty() {
+		return None;
+	}
+	for &b in s.as_bytes()
+	{
+		if b < b'0' || b > b'9' {
+			return None;
+		}
+		rv = rv.checked_mul(10)?.checked_add((b - b'0') as u16)?;
+	}
+	Some(rv)
+}
+fn parse_ipv4(s: &::std::ffi::OsStr) -> Option<[u8; 4]>
+{
+	let mut rv = [0; 4];
+	let mut it = s.as_bytes().split(|&b| b == b'.');
+	for v in rv.iter_mut()
+	{
+		let n = parse_u16(::std::ffi::OsStr::new(it.next()?))?;
+		if n > 255 {
+			return None;
+		}
+		*v = n as u8;
+	}
+	if it.next().is_some() {
+		return None;
+	}
+	Some(rv)
+}
@@ -2,6 +2,8 @@
 // - By John Hodge (thePowersGang)
 //
 //! Stress-tests the VFS by enumerating all directories and checksumming all files
+//!
+//! Also checks that each node's metadata (`VFS_NODE_GETINFO`) agrees with its type and contents
 
 #[macro_use]
 extern crate syscalls;
@@ -51,12 +53,17 @@ fn dump_dir(level: usize, handle: Dir, buffer: &mut [u8])
 		
 		match node_res
 		{
-		Ok(node) =>
+		Ok(node) => {
+			let info = node.get_info().unwrap();
+			let class: u32 = node.class().into();
+			assert_eq!(info.class as u32, class, "VFS_NODE_GETINFO class mismatch");
+			assert_eq!(info._pad, 0, "VFS_NODE_GETINFO padding not cleared");
 			match node.class()
 			{
-			NodeType::File => dump_file(level+1, node.into_file(FileOpenMode::ReadOnly).unwrap()),
+			NodeType::File => dump_file(level+1, node.into_file(FileOpenMode::ReadOnly).unwrap(), info.size),
 			NodeType::Dir => dump_dir(level+1, node.into_dir().unwrap(), buffer),
 			_ => {},
+			}
 			},
 		Err(e) => {
 			kernel_log!(">> ERROR {:?}", e);
@@ -67,11 +74,12 @@ fn dump_dir(level: usize, handle: Dir, buffer: &mut [u8])
 }
 
 // Reads and applies a CRC32 to the file
-fn dump_file(level: usize, mut handle: File)
+fn dump_file(level: usize, mut handle: File, size: u64)
 {
 	let mut buffer = [0; 8*4096];
 
 	let mut crc = ::crc::Crc32::new();
+	let mut total = 0;
 	loop
 	{
 		let len = match handle.read(&mut buffer)
@@ -85,7 +93,9 @@ fn dump_file(level: usize, mut handle: File)
 			};
 
 		crc.update( &buffer[..len] );
+		total += len as u64;
 	}
+	assert_eq!(total, size, "VFS_NODE_GETINFO size doesn't match the file contents");
 	kernel_log!("{}> CRC32={:#x}", Repeat(level," "), crc.finalise());
 }
 
@@ -102,6 +102,12 @@ def_groups! {
 		=1: NET_LISTEN,
 		/// Open a free-form datagram 'socket'
 		=2: NET_BIND,
+		/// Add an entry to the routing table (`NetworkRoute`)
+		=3: NET_ROUTE_ADD,
+		/// Remove an entry from the routing table (matched on network, mask, and gateway)
+		=4: NET_ROUTE_DEL,
+		/// Read an entry from the routing table by index (returns `SocketError::NoData` past the end)
+		=5: NET_ROUTE_GET,
 	}
 }
 
@@ -178,6 +184,8 @@ def_classes! {
 		/// Give the process one of this process's objects
 		/// This method blocks if the child process hasn't popped the previous object
 		=0: CORE_PROTOPROCESS_SENDOBJ,
+		/// Set the user/group IDs the process will run as (caller must be running as root)
+		=1: CORE_PROTOPROCESS_SETCREDS,
 		--
 		/// Start the process executing
 		=0: CORE_PROTOPROCESS_START,
@@ -202,6 +210,8 @@ def_classes! {
 	/// Opened node
 	=3: CLASS_VFS_NODE = {
 		=0: VFS_NODE_GETTYPE,
+		/// Read node metadata into a `VFSNodeInfo`
+		=1: VFS_NODE_GETINFO,
 		--
 		=0: VFS_NODE_TOFILE,
 		=1: VFS_NODE_TODIR,
@@ -229,6 +239,18 @@ def_classes! {
 		=1: VFS_DIR_OPENCHILD,
 		/// Open a sub-path
 		=2: VFS_DIR_OPENPATH,
+		/// Create a new directory (returns a directory handle)
+		=3: VFS_DIR_MKDIR,
+		/// Create a new file (returns a file handle, opened exclusively)
+		=4: VFS_DIR_CREATEFILE,
+		/// Remove an entry (directories must be empty)
+		=5: VFS_DIR_UNLINK,
+		/// Create a symbolic link (name, target path)
+		=6: VFS_DIR_SYMLINK,
+		/// Add a new name for a node (name, node handle), the node must be on the same mount
+		=7: VFS_DIR_LINK,
+		/// Atomically move an entry (old name, destination directory handle, new name) within a mount
+		=8: VFS_DIR_RENAME,
 		--
 	}|{
 	},
@@ -325,6 +347,8 @@ def_classes! {
 		=0: NET_SERVER_ACCEPT,
 	--
 	}|{
+		/// Fires when there is a new client waiting to be accepted
+		=0: EV_NET_SERVER_ACCEPT,
 	},
 	/// Socket connection
 	=12: CLASS_SOCKET = {
@@ -332,10 +356,12 @@ def_classes! {
 		=0: NET_CONNSOCK_RECV,
 		/// Send data
 		=1: NET_CONNSOCK_SEND,
-		///
+		/// Close one (or both) directions of the connection
 		=2: NET_CONNSOCK_SHUTDOWN,
 	--
 	}|{
+		/// Fires when there is data to read (or the connection has been closed)
+		=0: EV_NET_CONNSOCK_RECV,
 	},
 	/// Free-bind socket
 	=13: CLASS_FREESOCKET = {
@@ -345,6 +371,8 @@ def_classes! {
 		=1: NET_FREESOCK_SEND,
 	--
 	}|{
+		/// Fires when there is a datagram to read
+		=0: EV_NET_FREESOCK_RECV,
 	},
 /*
 	/// A registered read/write buffer
@@ -404,6 +432,32 @@ enum_to_from!{ VFSError => u32:
 	PermissionDenied = 2,
 	FileLocked = 3,
 	MalformedPath = 4,
+	/// The destination name already exists
+	AlreadyExists = 5,
+	/// Directory can't be removed while it has entries
+	DirectoryNotEmpty = 6,
+	/// Operation would span two mounts
+	CrossFilesystem = 7,
+	/// The filesystem does not allow modification
+	ReadOnlyFilesystem = 8,
+	/// A parameter was invalid (e.g. an empty name)
+	InvalidParameter = 9,
+	/// A component of the path was not a directory
+	NonDirComponent = 10,
+	/// Too many levels of symbolic links
+	RecursionDepthExceeded = 11,
+	/// The underlying storage reported an error
+	IoError = 12,
+	/// The filesystem's on-disk structures are corrupt
+	InconsistentFilesystem = 13,
+	/// The volume is full
+	OutOfSpace = 14,
+	/// The kernel ran out of memory
+	OutOfMemory = 15,
+	/// A transient failure, the operation can be retried
+	TransientError = 16,
+	/// Any other error
+	Unknown = 17,
 }
 enum_to_from!{ VFSNodeType => u32:
 	File = 0,
@@ -411,6 +465,32 @@ enum_to_from!{ VFSNodeType => u32:
 	Symlink = 2,
 	Special = 3,
 }
+/// Node metadata returned by `VFS_NODE_GETINFO`
+#[derive(Default,Copy,Clone,Debug)]
+#[repr(C)]
+pub struct VFSNodeInfo
+{
+	/// Size in bytes
+	pub size: u64,
+	/// Creation time (seconds since 1970, zero if unknown)
+	pub created: i64,
+	/// Last modification time
+	pub modified: i64,
+	/// Last access time
+	pub accessed: i64,
+	/// Number of names referencing the node
+	pub link_count: u32,
+	/// Owning user ID
+	pub owner: u32,
+	/// Owning group ID
+	pub group: u32,
+	/// Unix permission bits
+	pub mode: u16,
+	/// Node type (`VFSNodeType`)
+	pub class: u8,
+	/// Explicit padding (always zero), so no uninitialised bytes are copied to userland
+	pub _pad: u8,
+}
 enum_to_from!{ VFSFileOpenMode => u8:
 	ReadOnly = 1,
 	Execute  = 2,
@@ -552,6 +632,22 @@ enum_to_from!{ SocketError => u8:
 	InvalidValue = 1,
 	/// The specified address was already in use
 	AlreadyInUse = 2,
+	/// No route to the specified address
+	NoRoute = 3,
+	/// No free local ports to allocate
+	NoPortAvailable = 4,
+	/// The remote end refused the connection
+	ConnectionRefused = 5,
+	/// The connection was reset by the remote end
+	ConnectionReset = 6,
+	/// The connection has been closed
+	ConnectionClosed = 7,
+	/// The data is too large to send as a single datagram
+	TooLarge = 8,
+	/// The remote end stopped responding
+	TimedOut = 9,
+	/// The calling process isn't allowed to perform this operation
+	PermissionDenied = 10,
 }
 enum_to_from!{ SocketShutdownSide => u8:
 	Transmit = 0,
@@ -592,4 +688,22 @@ pub struct MaskedSocketAddress
 	pub addr: SocketAddress,
 	pub mask: u8,
 }
+/// Routing table entry (used by the `NET_ROUTE_*` calls)
+#[derive(Default,Copy,Clone,Debug)]
+#[repr(C)]
+pub struct NetworkRoute
+{
+	/// Address type (`SocketAddressType`) of all of the addresses
+	pub addr_ty: u8,
+	/// Prefix length of `network`
+	pub mask: u8,
+	/// Lower metrics are preferred when prefix lengths are equal
+	pub metric: u16,
+	/// Destination network
+	pub network: [u8; 16],
+	/// Next hop (zero for directly attached networks)
+	pub gateway: [u8; 16],
+	/// Local address to send from (zero to select from the gateway when adding)
+	pub source: [u8; 16],
+}
 